@@ -0,0 +1,109 @@
+//! Benchmarks of the graph operations that run on every build, on synthetic graphs up to the size of a large install.
+//!
+//! Run with `cargo bench --bench graph`. Criterion compares every run with the previous one, which catches performance regressions.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use libside::{
+    builder::fs::CreateDirectory,
+    graph::{Applied, Graph, Pending},
+    requirements,
+    system::LocalSystem,
+};
+use std::path::PathBuf;
+
+requirements!(R = CreateDirectory);
+
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+/// A graph of `size` directories, where every directory depends on the previous one and on one halfway back.
+/// Every tenth directory is named after `generation`, so that graphs of different generations differ in a tenth of their nodes.
+fn synthetic(size: usize, generation: usize) -> Graph<R, Pending> {
+    let mut graph = Graph::new();
+    let mut nodes = Vec::with_capacity(size);
+    for index in 0..size {
+        let path = if index % 10 == 0 {
+            format!("/srv/bench/{}-{}", index, generation)
+        } else {
+            format!("/srv/bench/{}", index)
+        };
+        let dependencies = match index {
+            0 => Vec::new(),
+            _ => vec![nodes[index - 1], nodes[index / 2]],
+        };
+        nodes.push(graph.add(CreateDirectory::new(PathBuf::from(path)), &dependencies));
+    }
+
+    graph
+}
+
+/// The graph as it is loaded from the database of an install.
+fn applied(graph: &Graph<R, Pending>) -> Graph<R, Applied> {
+    serde_json::from_str(&serde_json::to_string(graph).unwrap()).unwrap()
+}
+
+fn invert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("invert");
+    for size in SIZES {
+        let graph = synthetic(size, 0);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &graph, |b, graph| {
+            b.iter(|| graph.invert())
+        });
+    }
+}
+
+fn retain(c: &mut Criterion) {
+    let mut group = c.benchmark_group("retain");
+    for size in SIZES {
+        let graph = synthetic(size, 0);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &graph, |b, graph| {
+            b.iter_batched(
+                || graph.clone(),
+                |mut graph| graph.retain(|index, _| index % 10 != 0),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+}
+
+fn compare_with(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compare_with");
+    group.sample_size(10);
+    for size in SIZES {
+        let prev = applied(&synthetic(size, 0));
+        let next = synthetic(size, 1);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(size),
+            &(prev, next),
+            |b, (prev, next)| b.iter(|| next.compare_with(&mut LocalSystem::new(), prev).unwrap()),
+        );
+    }
+}
+
+fn generate_application_sequence(c: &mut Criterion) {
+    let mut group = c.benchmark_group("generate_application_sequence");
+    group.sample_size(10);
+    for size in SIZES {
+        let prev = applied(&synthetic(size, 0));
+        let next = synthetic(size, 1);
+        let compared = next.compare_with(&mut LocalSystem::new(), &prev).unwrap();
+        group.bench_with_input(
+            BenchmarkId::from_parameter(size),
+            &compared,
+            |b, compared| {
+                b.iter(|| {
+                    compared
+                        .generate_application_sequence(&mut LocalSystem::new())
+                        .unwrap()
+                })
+            },
+        );
+    }
+}
+
+criterion_group!(
+    benches,
+    invert,
+    retain,
+    compare_with,
+    generate_application_sequence
+);
+criterion_main!(benches);
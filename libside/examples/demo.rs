@@ -6,15 +6,17 @@ use std::time::Duration;
 use libside::builder::apt::{Apt, AptInstall, AptPackage, AptUpdate};
 use libside::builder::fs::*;
 use libside::builder::mysql::*;
-use libside::builder::nginx::Nginx;
+use libside::builder::nginx::{Location, Nginx, NginxConfigTest, ServerBlock, SiteConfig};
 use libside::builder::path::{Bindable, Existing, Exposed, Path, SharedConfig};
 use libside::builder::php_fpm::*;
+use libside::builder::ssh::KnownHost;
 use libside::builder::systemd::*;
 use libside::builder::users::*;
 use libside::builder::{AsParam, Builder, Context};
 use libside::config::systemd::*;
 use libside::graph::GraphNodeReference;
 use libside::requirements::{Requirement, Supports};
+use libside::scaffold::{PackageTemplate, ScaffoldFile};
 use libside::secrets::keys::AsymmetricKey;
 use libside::secrets::password::{Alphanumeric, Password};
 use libside::system::LocalSystem;
@@ -78,7 +80,9 @@ struct Backup {
 struct BackupRemote {
     host: String,
     path: String,
-    known_host: String,
+    /// The host key of the remote host. If it is not configured, the key is trusted on first use
+    #[serde(default)]
+    known_host: Option<String>,
     user: String,
 }
 
@@ -154,11 +158,14 @@ requirements!(
     EnableService,
     Chown,
     Chmod,
+    KnownHost,
+    NginxConfigTest,
 );
 
 impl Builder for Demo {
     type PackageConfig = Config;
     type Data = DemoData;
+    type Prepared = ();
     type Requirement = R;
     type BuildError = BuildError;
 
@@ -195,9 +202,17 @@ impl Builder for Demo {
         })
     }
 
+    fn prepare_package(
+        &self,
+        _package: &libside::builder::Package<Self::PackageConfig>,
+    ) -> Result<Self::Prepared, Self::BuildError> {
+        Ok(())
+    }
+
     fn build_package(
         &self,
         package: &libside::builder::Package<Self::PackageConfig>,
+        _prepared: Self::Prepared,
         context: &mut libside::builder::Context<Self::Requirement>,
         data: &mut Self::Data,
     ) -> Result<(), Self::BuildError> {
@@ -375,32 +390,46 @@ impl Builder for Demo {
                     }
 
                     let started = ServiceRunning::restart(context, &service);
-                    let site_file = data.nginx_sites.make_file(context, config_file!("demo-data/nginx/php-site"
-                        document_root: document_root.clone(),
-                        // php-fpm is configured to chroot into /php-data, so we need to strip /php-data from the path we're going to pass to php-fpm
-                        php_root: PathBuf::from("/").join(PathBuf::from(php_files.to_string()).strip_prefix("/php-data").unwrap()).display().to_string(),
-                        server_name: &www.hostname,
-                        fpm_socket: &listen_sock,
-                    ).rename(package.name()));
-
-                    data.nginx
-                        .default_service()
-                        .add_start_dependencies(site_file.graph_node());
+                    // php-fpm is configured to chroot into /php-data, so we need to strip /php-data from the path we're going to pass to php-fpm
+                    let php_root = PathBuf::from("/")
+                        .join(
+                            PathBuf::from(php_files.to_string())
+                                .strip_prefix("/php-data")
+                                .unwrap(),
+                        )
+                        .display()
+                        .to_string();
+                    let server = site_server(&www.hostname, &document_root)
+                        .index("index.php")
+                        .index("index.html")
+                        .location(
+                            Location::new(r"~ \.php")
+                                .try_files(&["$uri", "/index.php", "=404"])
+                                .directive("include", "fastcgi_params")
+                                .fastcgi_pass(&listen_sock)
+                                .directive("fastcgi_split_path_info", r"^(.+\.php)(/.*)$")
+                                .fastcgi_param("HTTPS", "off")
+                                .fastcgi_param(
+                                    "SCRIPT_FILENAME",
+                                    &format!("{}$fastcgi_script_name", php_root),
+                                ),
+                        );
+                    SiteConfig::new().server(server).install(
+                        context,
+                        &mut data.nginx,
+                        &data.nginx_sites,
+                        package.name(),
+                    );
                     data.sites
                         .push((document_root, Some((listen_sock, started))));
                 } else {
-                    let site_file = data.nginx_sites.make_file(
+                    let server = site_server(&www.hostname, &document_root).index("index.html");
+                    SiteConfig::new().server(server).install(
                         context,
-                        config_file!("demo-data/nginx/html-site"
-                            document_root: document_root.clone(),
-                            server_name: &www.hostname,
-                        )
-                        .rename(package.name()),
+                        &mut data.nginx,
+                        &data.nginx_sites,
+                        package.name(),
                     );
-
-                    data.nginx
-                        .default_service()
-                        .add_start_dependencies(site_file.graph_node());
                     data.sites.push((document_root, None));
                 }
             }
@@ -544,7 +573,7 @@ impl Builder for Demo {
         nginx_service.add_start_dependencies(fastcgi_params.graph_node());
         nginx_service.add_start_dependencies(deps);
 
-        ServiceRunning::restart(context, nginx_service);
+        nginx.restart(context, &nginx_conf_file);
 
         if let Some(backup) = data.backup {
             let mysql_group = data.mysql.as_ref().map(|m| m.mysql.mysql_group());
@@ -654,19 +683,11 @@ impl Builder for Demo {
 
             if let Some(rsync) = backup.sync_to {
                 let root = context.create_chroot("backup-sync");
-                let keypair: AsymmetricKey<4096> = context.secret("backup-sync-ssh-key");
+                // The private key is delivered as a systemd credential, so it never ends up in the config tree
+                let (keypair, private_key): (AsymmetricKey<4096>, _) =
+                    context.credential("backup-sync-ssh-key");
 
                 let dir = context.config_root().make_dir(context, "ssh");
-                let private_key_file = dir.make_file(
-                    context,
-                    ConfigFileData {
-                        path: PathBuf::from("id_rsa"),
-                        contents: keypair.private_key_data().as_bytes().to_vec(),
-                        path_dependency: dir.graph_node(),
-                        extra_dependencies: Vec::new(),
-                    },
-                );
-
                 let public_key_file = dir.make_file(
                     context,
                     ConfigFileData {
@@ -677,33 +698,29 @@ impl Builder for Demo {
                     },
                 );
 
-                let known_hosts_file = dir.make_file(
+                public_key_file.chown(context, &backup_user, &backup_group);
+                public_key_file.chmod(context, 0o600);
+
+                Rsync::install(context);
+                let ssh = Ssh::install(context);
+                let known_hosts = KnownHost::add(
                     context,
-                    ConfigFileData {
-                        path: PathBuf::from("known_hosts"),
-                        contents: rsync.known_host.into_bytes(),
-                        path_dependency: dir.graph_node(),
-                        extra_dependencies: Vec::new(),
-                    },
+                    &rsync.host,
+                    22,
+                    rsync.known_host.as_deref(),
+                    &[ssh.graph_node()],
                 );
 
-                for file in [private_key_file, public_key_file, known_hosts_file] {
-                    file.chown(context, &backup_user, &backup_group);
-                    file.chmod(context, 0o600);
-                }
-
                 let mut sb = SandboxBuilder::new(&root);
-                let mounted = sb.bind_read_only_path(dir.bind());
-
-                let key_file = mounted.join("id_rsa");
-                let known_hosts_file = mounted.join("known_hosts");
+                sb.bind_read_only_path(dir.bind());
+                let known_hosts_file = sb.bind_read_only_path(known_hosts.bind());
 
                 let mut script = String::new();
                 writeln!(&mut script, "#! /bin/bash").unwrap();
                 writeln!(
                     &mut script,
-                    "rsync -rtv -e 'ssh -i {} -o \"UserKnownHostsFile={}\" -l {}' {}/ {}:{}",
-                    key_file,
+                    "rsync -rtv -e \"ssh -i {} -o UserKnownHostsFile={} -l {}\" {}/ {}:{}",
+                    private_key.env_path(),
                     known_hosts_file,
                     rsync.user,
                     context.shared_backup_root(),
@@ -714,9 +731,6 @@ impl Builder for Demo {
 
                 sb.bind_read_only_path(context.shared_backup_root().bind());
 
-                Rsync::install(context);
-                Ssh::install(context);
-
                 let runfile = context.config_root().make_file(
                     context,
                     ConfigFileData {
@@ -746,7 +760,8 @@ impl Builder for Demo {
                         .temporary_file_system_push("/var")
                         .restrict_address_families_push("AF_INET AF_INET6")
                         .private_network(false)
-                        .private_users(false),
+                        .private_users(false)
+                        .credential(&private_key),
                     resource_control: ResourceControl::new().device_policy(DevicePolicy::Strict),
                 }
                 .install(context, "backup-sync");
@@ -770,10 +785,85 @@ impl Builder for Demo {
 
         Ok(())
     }
+
+    fn package_template(&self, template: PackageTemplate, name: &str) -> Option<Vec<ScaffoldFile>> {
+        Some(match template {
+            PackageTemplate::Www => vec![
+                ScaffoldFile::new(
+                    "package.toml",
+                    format!(
+                        r#"[www]
+# The directory in this package with the files of the site
+path = "www"
+hostname = "{name}"
+
+# Serve .php files with php-fpm
+php = false
+
+# A MySQL database for the site
+# [www.database]
+# name = "{database}"
+# user = "{database}"
+# backup = true
+"#,
+                        name = name,
+                        database = name.replace(['.', '-'], "_"),
+                    ),
+                ),
+                ScaffoldFile::new("www/index.html", format!("<h1>{}</h1>\n", name)),
+            ],
+            PackageTemplate::Binary => vec![
+                ScaffoldFile::new(
+                    "package.toml",
+                    format!(
+                        r#"[binary]
+# The directory in this package with the executable
+path = "bin"
+executable = "{name}"
+arguments = ""
+
+# Allow the service to use the network
+network_access = false
+"#,
+                        name = name,
+                    ),
+                ),
+                ScaffoldFile::new(
+                    PathBuf::from("bin").join(name),
+                    "#!/bin/sh\n# Replace this with the executable of the service\nexec sleep infinity\n",
+                ),
+            ],
+            PackageTemplate::Backup => vec![ScaffoldFile::new(
+                "package.toml",
+                r#"[backup]
+
+# Copy the backups of the databases to another host with rsync
+# [backup.rsync]
+# host = "backup.example.com"
+# path = "/srv/backups"
+# The host key is trusted on first use if it is not configured
+# known_host = "ssh-ed25519 AAAA..."
+# user = "backup"
+"#,
+            )],
+        })
+    }
+}
+
+/// A server block that serves the files in `document_root` for `hostname` on port 80.
+fn site_server(hostname: &str, document_root: &Path<Exposed>) -> ServerBlock {
+    ServerBlock::new()
+        .listen("80")
+        .listen("[::]:80")
+        .server_name(hostname)
+        .root(document_root)
+        .access_log(&format!("/var/log/nginx/access_{}.log", hostname))
+        .error_log(&format!("/var/log/nginx/error_{}.log", hostname))
+        .location(Location::new("/").try_files(&["$uri", "$uri/", "=404"]))
 }
 
 fn main() {
-    match SiDe::run(&mut LocalSystem, Demo) {
+    match SiDe::run(&mut LocalSystem::new(), Demo) {
         Ok(_) => (),
         Err(e) => {
             eprintln!("{}", e);
@@ -0,0 +1,519 @@
+//! Running `side` as a long-running, pull-based configuration agent, for `side agent`.
+//!
+//! The agent periodically pulls the packages directory from a [`Source`], builds and applies the packages when they changed,
+//! and verifies the current install on a separate schedule.
+//! Everything that happens is reported as an [`AgentEvent`], and the outcome is recorded in an [`AgentStatus`] file in the base directory.
+//! `side agent --install-unit` writes a systemd unit that runs the agent with the same options, and enables it.
+use crate::config::systemd::{Install, Restart, Service, ServiceType, Unit};
+use crate::fleet::ApplyLock;
+use crate::system::{CommandResult, System};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+/// The name of the systemd unit that `side agent --install-unit` generates.
+pub const UNIT_NAME: &str = "side-agent.service";
+
+/// Where the packages are pulled from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A git repository, and optionally the branch to check out. The default branch of the repository is used if omitted.
+    Git { url: String, branch: Option<String> },
+
+    /// A directory on the system, which is mirrored with `rsync`. This can be a mount or a directory that is kept up to date by other tools.
+    Directory(PathBuf),
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("git", url)) if !url.is_empty() => Ok(match url.rsplit_once('#') {
+                Some((url, branch)) if !branch.is_empty() => Source::Git {
+                    url: url.to_owned(),
+                    branch: Some(branch.to_owned()),
+                },
+                _ => Source::Git {
+                    url: url.to_owned(),
+                    branch: None,
+                },
+            }),
+            Some(("dir", path)) if !path.is_empty() => Ok(Source::Directory(PathBuf::from(path))),
+            _ => Err(format!(
+                "invalid source {:?}, expected 'git:<url>[#<branch>]' or 'dir:<path>'",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Git { url, branch: None } => write!(f, "git:{}", url),
+            Source::Git {
+                url,
+                branch: Some(branch),
+            } => write!(f, "git:{}#{}", url, branch),
+            Source::Directory(path) => write!(f, "dir:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AgentError<S: System> {
+    #[error("unable to execute {}: {}", .0, .1)]
+    FailedToStart(&'static str, S::CommandError),
+
+    #[error("{} failed: {} {}", .0, .1, .2)]
+    Unsuccessful(&'static str, String, String),
+
+    #[error("unable to access {}: {}", .0.display(), .1)]
+    UnableToAccess(PathBuf, S::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+}
+
+fn run<S: System>(
+    system: &mut S,
+    command: &'static str,
+    args: &[&str],
+) -> Result<CommandResult, AgentError<S>> {
+    let result = system
+        .execute_command(command, args)
+        .map_err(|e| AgentError::FailedToStart(command, e))?;
+    result.successful().map_err(|(stdout, stderr)| {
+        AgentError::Unsuccessful(command, stdout.into(), stderr.into())
+    })?;
+
+    Ok(result)
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().expect("paths must be valid UTF-8")
+}
+
+impl Source {
+    /// Replaces the contents of `packages` with the latest version from the source.
+    /// Local changes to the packages directory are discarded.
+    pub fn pull<S: System>(&self, system: &mut S, packages: &Path) -> Result<(), AgentError<S>> {
+        let dir = path_str(packages);
+        match self {
+            Source::Git { url, branch } => {
+                let git_dir = packages.join(".git");
+                let exists = system
+                    .path_exists(&git_dir)
+                    .map_err(|e| AgentError::UnableToAccess(git_dir, e))?;
+                if !exists {
+                    run(system, "git", &["init", "--quiet", dir])?;
+                }
+
+                let refspec = branch.as_deref().unwrap_or("HEAD");
+                run(
+                    system,
+                    "git",
+                    &["-C", dir, "fetch", "--quiet", url, refspec],
+                )?;
+                run(
+                    system,
+                    "git",
+                    &["-C", dir, "reset", "--quiet", "--hard", "FETCH_HEAD"],
+                )?;
+                run(system, "git", &["-C", dir, "clean", "--quiet", "-fd"])?;
+            }
+            Source::Directory(path) => {
+                let from = format!("{}/", path_str(path));
+                let to = format!("{}/", dir);
+                run(system, "rsync", &["-a", "--delete", &from, &to])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The options of `side agent`.
+#[derive(Debug, Clone, clap::Args)]
+pub struct AgentOptions {
+    /// Where to pull the packages from: `git:<url>[#<branch>]` or `dir:<path>`
+    #[arg(long = "source")]
+    pub source: Source,
+
+    /// The number of seconds between pulls
+    #[arg(long = "interval", default_value = "300", value_parser = clap::value_parser!(u64).range(1..))]
+    pub interval: u64,
+
+    /// The number of seconds between verifications of the current install
+    #[arg(long = "verify-interval", default_value = "3600", value_parser = clap::value_parser!(u64).range(1..))]
+    pub verify_interval: u64,
+
+    /// Cancel and revert an apply if it takes longer than this many seconds
+    #[arg(long = "timeout")]
+    pub timeout: Option<u64>,
+
+    /// Cancel and revert an apply if a single requirement takes longer than this many seconds
+    #[arg(long = "node-timeout")]
+    pub node_timeout: Option<u64>,
+
+    /// Apply the new requirements before undoing the old ones
+    #[arg(long = "create-first")]
+    pub create_first: bool,
+
+    /// Delay every pull by a random number of seconds up to this value, so that many hosts do not apply at the same time
+    #[arg(long = "splay", default_value = "0")]
+    pub splay: u64,
+
+    /// Limit the number of hosts that apply at the same time with a lock: `file:<dir>` on shared storage, or the URL of a lock service
+    #[arg(long = "apply-lock")]
+    pub apply_lock: Option<ApplyLock>,
+
+    /// The number of hosts that may hold the apply lock at the same time
+    #[arg(long = "max-concurrent", default_value = "1", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_concurrent: usize,
+
+    /// Write a systemd unit that runs the agent with these options and enable it, instead of running the agent
+    #[arg(long = "install-unit")]
+    pub install_unit: bool,
+}
+
+impl AgentOptions {
+    /// The arguments that run the agent with these options, starting with the `agent` subcommand.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            String::from("agent"),
+            String::from("--source"),
+            self.source.to_string(),
+            String::from("--interval"),
+            self.interval.to_string(),
+            String::from("--verify-interval"),
+            self.verify_interval.to_string(),
+        ];
+        if let Some(timeout) = self.timeout {
+            args.push(String::from("--timeout"));
+            args.push(timeout.to_string());
+        }
+
+        if let Some(node_timeout) = self.node_timeout {
+            args.push(String::from("--node-timeout"));
+            args.push(node_timeout.to_string());
+        }
+
+        if self.create_first {
+            args.push(String::from("--create-first"));
+        }
+
+        if self.splay > 0 {
+            args.push(String::from("--splay"));
+            args.push(self.splay.to_string());
+        }
+
+        if let Some(apply_lock) = &self.apply_lock {
+            args.push(String::from("--apply-lock"));
+            args.push(apply_lock.to_string());
+            args.push(String::from("--max-concurrent"));
+            args.push(self.max_concurrent.to_string());
+        }
+
+        args
+    }
+}
+
+/// Quotes an argument for a systemd `ExecStart=` line if it contains whitespace or quotes.
+fn quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg.to_owned()
+    }
+}
+
+/// Generates the systemd unit that runs `exe` as an agent for the base directory `base`.
+/// The agent reverts an apply that is in progress when it is stopped, so it is given plenty of time to stop.
+pub fn unit(exe: &Path, base: &Path, options: &AgentOptions) -> String {
+    let command = [path_str(exe).to_owned(), path_str(base).to_owned()]
+        .into_iter()
+        .chain(options.args())
+        .map(|arg| quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let unit = Unit::new()
+        .description("libside configuration agent")
+        .wants_push("network-online.target")
+        .after_push("network-online.target");
+    let service = Service::new()
+        .service_type(ServiceType::Simple)
+        .exec_start_push(command)
+        .restart(Restart::OnFailure)
+        .restart_sec("30")
+        .timeout_stop_sec("900");
+    let install = Install::new().wanted_by_push("multi-user.target");
+
+    format!(
+        "[Unit]\n{}\n[Service]\n{}\n[Install]\n{}",
+        unit, service, install
+    )
+}
+
+/// Writes the unit for the agent to `/etc/systemd/system`, and enables and starts it.
+pub fn install_unit<S: System>(
+    system: &mut S,
+    exe: &Path,
+    base: &Path,
+    options: &AgentOptions,
+) -> Result<(), AgentError<S>> {
+    let path = Path::new("/etc/systemd/system").join(UNIT_NAME);
+    system
+        .put_file_contents(&path, unit(exe, base, options).as_bytes())
+        .map_err(|e| AgentError::UnableToWrite(path.clone(), e))?;
+    info!("Wrote {}", path.display());
+
+    run(system, "systemctl", &["daemon-reload"])?;
+    run(system, "systemctl", &["enable", "--now", UNIT_NAME])?;
+    info!("Enabled and started {}", UNIT_NAME);
+
+    Ok(())
+}
+
+/// Something that happened while the agent was running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentEvent {
+    /// The packages were pulled, and `changes` files were added, removed or modified.
+    Pulled {
+        changes: usize,
+    },
+    PullFailed(String),
+
+    /// The changed packages were built and applied as install `install`.
+    Applied {
+        install: u64,
+    },
+    ApplyFailed(String),
+
+    /// The changed packages were not applied, because the current install did not pass verification or the apply lock could not be acquired.
+    /// The apply is tried again after the next pull.
+    ApplySkipped(String),
+
+    Verified,
+    VerificationFailed(String),
+}
+
+impl AgentEvent {
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self,
+            AgentEvent::PullFailed(_)
+                | AgentEvent::ApplyFailed(_)
+                | AgentEvent::ApplySkipped(_)
+                | AgentEvent::VerificationFailed(_)
+        )
+    }
+
+    /// Logs the event.
+    pub fn report(&self) {
+        match self {
+            AgentEvent::Pulled { changes: 0 } => info!("{}", self),
+            AgentEvent::ApplySkipped(_) => warn!("{}", self),
+            _ if self.is_failure() => error!("{}", self),
+            _ => info!("{}", self),
+        }
+    }
+}
+
+impl Display for AgentEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AgentEvent::Pulled { changes: 0 } => write!(f, "Pulled the packages, no changes"),
+            AgentEvent::Pulled { changes } => {
+                write!(f, "Pulled the packages, {} files changed", changes)
+            }
+            AgentEvent::PullFailed(err) => write!(f, "Unable to pull the packages: {}", err),
+            AgentEvent::Applied { install } => write!(f, "Applied install {}", install),
+            AgentEvent::ApplyFailed(err) => write!(f, "Apply failed: {}", err),
+            AgentEvent::ApplySkipped(err) => write!(f, "Not applying the changes: {}", err),
+            AgentEvent::Verified => write!(f, "Verification OK"),
+            AgentEvent::VerificationFailed(err) => write!(f, "Verification failed:\n{}", err),
+        }
+    }
+}
+
+/// The outcome of the most recent pull, apply and verification of the agent, so that it can be inspected while the agent is running.
+/// Times are in seconds since the Unix epoch.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentStatus {
+    pub last_pull: Option<u64>,
+    pub last_apply: Option<u64>,
+    pub last_verification: Option<u64>,
+
+    /// The install that was applied most recently by the agent.
+    pub applied_install: Option<u64>,
+
+    /// Whether the most recent verification succeeded.
+    pub verified: Option<bool>,
+
+    /// The most recent failure, which is cleared once the same step succeeds again.
+    pub last_error: Option<String>,
+}
+
+impl AgentStatus {
+    /// Loads the status, or returns an empty status if it does not exist or cannot be read.
+    pub fn load<S: System>(system: &S, path: &Path) -> AgentStatus {
+        system
+            .file_contents(path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save<S: System>(&self, system: &mut S, path: &Path) -> Result<(), AgentError<S>> {
+        let contents = serde_json::to_string_pretty(self).unwrap();
+        system
+            .put_file_contents(path, contents.as_bytes())
+            .map_err(|e| AgentError::UnableToWrite(path.to_owned(), e))
+    }
+
+    /// Updates the status with an event that happened at `time`.
+    pub fn record(&mut self, event: &AgentEvent, time: u64) {
+        match event {
+            AgentEvent::Pulled { .. } | AgentEvent::PullFailed(_) => self.last_pull = Some(time),
+            AgentEvent::Applied { install } => {
+                self.last_apply = Some(time);
+                self.applied_install = Some(*install);
+            }
+            AgentEvent::ApplyFailed(_) | AgentEvent::ApplySkipped(_) => {
+                self.last_apply = Some(time)
+            }
+            AgentEvent::Verified | AgentEvent::VerificationFailed(_) => {
+                self.last_verification = Some(time);
+                self.verified = Some(*event == AgentEvent::Verified);
+            }
+        }
+
+        if event.is_failure() {
+            self.last_error = Some(event.to_string());
+        } else if !matches!(event, AgentEvent::Pulled { .. }) {
+            self.last_error = None;
+        }
+    }
+}
+
+/// The current time in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unit, AgentEvent, AgentOptions, AgentStatus, Source};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    pub fn parse_sources() {
+        let sources = [
+            (
+                "git:https://example.com/packages.git",
+                Source::Git {
+                    url: String::from("https://example.com/packages.git"),
+                    branch: None,
+                },
+            ),
+            (
+                "git:git@example.com:ops/packages.git#production",
+                Source::Git {
+                    url: String::from("git@example.com:ops/packages.git"),
+                    branch: Some(String::from("production")),
+                },
+            ),
+            (
+                "dir:/mnt/packages",
+                Source::Directory(PathBuf::from("/mnt/packages")),
+            ),
+        ];
+
+        for (s, source) in sources {
+            assert_eq!(s.parse::<Source>().unwrap(), source);
+            assert_eq!(source.to_string(), s);
+        }
+
+        assert!("git:".parse::<Source>().is_err());
+        assert!("/mnt/packages".parse::<Source>().is_err());
+    }
+
+    #[test]
+    pub fn generate_unit() {
+        let options = AgentOptions {
+            source: "git:https://example.com/packages.git#main".parse().unwrap(),
+            interval: 60,
+            verify_interval: 600,
+            timeout: Some(1800),
+            node_timeout: None,
+            create_first: true,
+            splay: 120,
+            apply_lock: Some("file:/mnt/shared/locks".parse().unwrap()),
+            max_concurrent: 5,
+            install_unit: true,
+        };
+        let unit = unit(
+            Path::new("/usr/local/bin/side"),
+            Path::new("/srv/my dir"),
+            &options,
+        );
+
+        assert!(unit.starts_with("[Unit]\nDescription=libside configuration agent\n"));
+        assert!(unit.contains("\nExecStart=/usr/local/bin/side \"/srv/my dir\" agent --source git:https://example.com/packages.git#main --interval 60 --verify-interval 600 --timeout 1800 --create-first --splay 120 --apply-lock file:/mnt/shared/locks --max-concurrent 5\n"));
+        assert!(unit.contains("\nRestart=on-failure\n"));
+        assert!(unit.contains("[Install]\nWantedBy=multi-user.target\n"));
+        assert!(!unit.contains("--install-unit"));
+    }
+
+    #[test]
+    pub fn record_events() {
+        let mut status = AgentStatus::default();
+        status.record(&AgentEvent::Pulled { changes: 2 }, 10);
+        status.record(&AgentEvent::ApplyFailed(String::from("no space left")), 11);
+        assert_eq!(status.last_pull, Some(10));
+        assert_eq!(status.last_apply, Some(11));
+        assert_eq!(status.applied_install, None);
+        assert_eq!(
+            status.last_error.as_deref(),
+            Some("Apply failed: no space left")
+        );
+
+        status.record(&AgentEvent::Pulled { changes: 0 }, 20);
+        assert!(status.last_error.is_some());
+
+        status.record(&AgentEvent::Applied { install: 4 }, 21);
+        status.record(&AgentEvent::Verified, 22);
+        assert_eq!(
+            status,
+            AgentStatus {
+                last_pull: Some(20),
+                last_apply: Some(21),
+                last_verification: Some(22),
+                applied_install: Some(4),
+                verified: Some(true),
+                last_error: None,
+            }
+        );
+    }
+
+    #[test]
+    pub fn serialize_deserialize_agent_status() {
+        let status = AgentStatus {
+            last_pull: Some(1700000000),
+            verified: Some(false),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&status).unwrap();
+
+        assert_eq!(status, serde_json::from_str(&json).unwrap());
+    }
+}
@@ -1,5 +1,5 @@
 use crate::graph::{Applied, Graph, VerificationState};
-use crate::requirements::Requirement;
+use crate::requirements::{Requirement, Supports};
 use crate::system::System;
 use std::fmt::Debug;
 use std::fmt::Display;
@@ -17,6 +17,61 @@ impl<R: Requirement> Default for SystemState<R> {
     }
 }
 
+/// A read-only view of the install that was current when a build started.
+pub struct PreviousInstall<'a, R> {
+    version: u64,
+    state: &'a SystemState<R>,
+}
+
+impl<'a, R> Clone for PreviousInstall<'a, R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, R> Copy for PreviousInstall<'a, R> {}
+
+impl<'a, R: Requirement> PreviousInstall<'a, R> {
+    pub fn new(version: u64, state: &'a SystemState<R>) -> Self {
+        PreviousInstall { version, state }
+    }
+
+    /// The version number of the previous install. Version 0 is the empty install created by `init`.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns true if there has never been a build before this one.
+    pub fn is_initial(&self) -> bool {
+        self.version == 0
+    }
+
+    pub fn state(&self) -> &'a SystemState<R> {
+        self.state
+    }
+
+    pub fn requirements(&self) -> impl Iterator<Item = &'a R> {
+        self.state.graph.requirements()
+    }
+
+    /// Returns true if the previous install contained a requirement that affects the same thing as `requirement`.
+    pub fn contains<T>(&self, requirement: T) -> bool
+    where
+        R: Supports<T>,
+    {
+        let requirement = R::create_from(requirement);
+        self.requirements().any(|r| r.affects(&requirement))
+    }
+
+    /// Returns true if the package `name` added at least one requirement to the previous install.
+    pub fn has_package(&self, name: &str) -> bool {
+        self.state
+            .graph
+            .packages()
+            .any(|package| package == Some(name))
+    }
+}
+
 impl<R: Requirement + Display> SystemState<R> {
     pub fn verify_system_state<'r, S: System>(
         &'r self,
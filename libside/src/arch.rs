@@ -0,0 +1,157 @@
+//! The CPU architectures that builders can target.
+//!
+//! The architecture of a system is detected with `uname -m` when a build starts, and is available to builders with `Context::arch`.
+//! Every install records the architecture it was built for, so that an install that was built on one host is never applied to a host with a different architecture.
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    Amd64,
+    Arm64,
+}
+
+/// Systems with an architecture that is not recognized are assumed to be amd64, which is what `side` has always targeted.
+impl Default for Arch {
+    fn default() -> Self {
+        Arch::Amd64
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchError<S: System> {
+    #[error("unable to execute uname: {}", .0)]
+    FailedToStart(S::CommandError),
+
+    #[error("uname failed: {} {}", .0, .1)]
+    Unsuccessful(String, String),
+
+    #[error("unable to read the architecture from {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("invalid architecture in {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, serde_json::Error),
+
+    #[error("unable to write the architecture to {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+}
+
+impl Arch {
+    /// Parses the machine hardware name, as printed by `uname -m`.
+    /// The Debian names are accepted as well, because some kernels report those.
+    pub fn from_machine(machine: &str) -> Option<Arch> {
+        match machine.trim() {
+            "x86_64" | "amd64" => Some(Arch::Amd64),
+            "aarch64" | "arm64" | "armv8l" => Some(Arch::Arm64),
+            _ => None,
+        }
+    }
+
+    /// Detects the architecture of `system`.
+    /// Returns `None` if `system` runs on an architecture that is not supported.
+    pub fn detect<S: System>(system: &mut S) -> Result<Option<Arch>, ArchError<S>> {
+        let result = system
+            .execute_command("uname", &["-m"])
+            .map_err(ArchError::FailedToStart)?;
+        result.successful().map_err(|(stdout, stderr)| {
+            ArchError::Unsuccessful(stdout.to_owned(), stderr.to_owned())
+        })?;
+
+        Ok(Arch::from_machine(result.stdout_as_str()))
+    }
+
+    /// The name that Debian and Ubuntu use for the architecture, for example in package names and `dpkg --print-architecture`.
+    pub fn debian(&self) -> &'static str {
+        match self {
+            Arch::Amd64 => "amd64",
+            Arch::Arm64 => "arm64",
+        }
+    }
+
+    /// The name that the kernel uses for the architecture, which is also used by Alpine and by most release binaries.
+    pub fn machine(&self) -> &'static str {
+        match self {
+            Arch::Amd64 => "x86_64",
+            Arch::Arm64 => "aarch64",
+        }
+    }
+
+    /// Loads the architecture that an install was built for from `path`.
+    /// Installs that were created before the architecture was recorded have none.
+    pub fn load<S: System>(path: &Path, system: &S) -> Result<Option<Arch>, ArchError<S>> {
+        if !system
+            .path_exists(path)
+            .map_err(|e| ArchError::UnableToRead(path.to_owned(), e))?
+        {
+            return Ok(None);
+        }
+
+        let contents = system
+            .file_contents(path)
+            .map_err(|e| ArchError::UnableToRead(path.to_owned(), e))?;
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| ArchError::Invalid(path.to_owned(), e))
+    }
+
+    pub fn save<S: System>(&self, path: &Path, system: &S) -> Result<(), ArchError<S>> {
+        let contents = serde_json::to_string(self).unwrap();
+        system
+            .put_file_contents(path, contents.as_bytes())
+            .map_err(|e| ArchError::UnableToWrite(path.to_owned(), e))
+    }
+}
+
+impl Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.debian())
+    }
+}
+
+/// A value for each architecture, such as the download URL and checksum of a release binary.
+/// Package configurations can contain it as `{"amd64": ..., "arm64": ...}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PerArch<T> {
+    pub amd64: T,
+    pub arm64: T,
+}
+
+impl<T> PerArch<T> {
+    pub fn get(&self, arch: Arch) -> &T {
+        match arch {
+            Arch::Amd64 => &self.amd64,
+            Arch::Arm64 => &self.arm64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Arch, PerArch};
+
+    #[test]
+    pub fn detect_from_machine() {
+        assert_eq!(Arch::from_machine("x86_64\n"), Some(Arch::Amd64));
+        assert_eq!(Arch::from_machine("aarch64\n"), Some(Arch::Arm64));
+        assert_eq!(Arch::from_machine("arm64"), Some(Arch::Arm64));
+        assert_eq!(Arch::from_machine("riscv64\n"), None);
+        assert_eq!(Arch::Arm64.debian(), "arm64");
+        assert_eq!(Arch::Arm64.machine(), "aarch64");
+    }
+
+    #[test]
+    pub fn serialize_deserialize_per_arch() {
+        let urls = PerArch {
+            amd64: String::from("https://example.com/tool-x86_64.tar.gz"),
+            arm64: String::from("https://example.com/tool-aarch64.tar.gz"),
+        };
+        let json = r#"{"amd64":"https://example.com/tool-x86_64.tar.gz","arm64":"https://example.com/tool-aarch64.tar.gz"}"#;
+
+        assert_eq!(serde_json::to_string(&urls).unwrap(), json);
+        assert_eq!(urls, serde_json::from_str(json).unwrap());
+        assert_eq!(urls.get(Arch::Arm64), &urls.arm64);
+    }
+}
@@ -0,0 +1,840 @@
+//! An append-only log of the commands that were executed during applies, for `side history`.
+//!
+//! Commands are recorded by a [`Recorder`] that is attached to the system with `System::set_recorder`.
+//! The systems that start processes (`LocalSystem` and `LxcInstance`) report every command they run to their recorder.
+//! Systems that wrap another system, such as `ChrootSystem` and `BatchedSystem`, delegate to it, so their commands are recorded as well.
+//! Input that is passed to a command on stdin is never recorded, and arguments that look like secrets are redacted.
+//!
+//! Next to the commands, every build, apply and verification is recorded as an [`Invocation`] in a separate log: who ran it, which installs it went from and to,
+//! which requirements it applied and undid, and how it ended. The requirements are tracked by the same recorder, with `Recorder::start_tracking`.
+use crate::graph::NodeId;
+use crate::notify::Outcome;
+use crate::system::{CommandResult, System};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Replaces the values of arguments that look like secrets.
+pub const REDACTED: &str = "<redacted>";
+
+/// Arguments with a name that contains one of these words are redacted.
+const SENSITIVE: &[&str] = &["password", "passwd", "secret", "token"];
+
+/// Records the commands that a system executes during an apply, and the requirements that are applied and undone during an invocation.
+///
+/// A recorder is attached to a system with [`System::set_recorder`], and is shared by its clones.
+/// Systems that wrap another system attach it to the system they wrap, so that their commands are recorded as well.
+#[derive(Debug, Clone, Default)]
+pub struct Recorder(Arc<Mutex<RecorderState>>);
+
+#[derive(Debug, Default)]
+struct RecorderState {
+    recording: Option<Recording>,
+    tracking: Option<Tracking>,
+}
+
+#[derive(Debug, Default)]
+struct Tracking {
+    apply: Option<u64>,
+    changes: Vec<RequirementChange>,
+}
+
+#[derive(Debug)]
+struct Recording {
+    apply: u64,
+    node: Option<NodeId>,
+    entries: Vec<AuditEntry>,
+}
+
+/// A single command that was executed during an apply.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// The apply during which the command was executed.
+    pub apply: u64,
+
+    /// When the command was started, in seconds since the Unix epoch.
+    pub started: u64,
+
+    pub command: String,
+    pub args: Vec<String>,
+
+    /// `None` if the command could not be started or was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+
+    /// The requirement that executed the command, if the command was executed while applying or undoing a requirement.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<NodeId>,
+
+    /// Set on the entry that records an apply of an install that is older than the current install.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downgrade: Option<Downgrade>,
+}
+
+/// An apply that replaced the current install with an older one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Downgrade {
+    pub from: u64,
+    pub to: u64,
+}
+
+impl Display for Downgrade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "downgrade from {} to {}", self.from, self.to)
+    }
+}
+
+impl Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let exit_code = match self.exit_code {
+            Some(code) => code.to_string(),
+            None => String::from("-"),
+        };
+        write!(
+            f,
+            "{} {:>3} {:>6}ms {}",
+            self.started, exit_code, self.duration_ms, self.command
+        )?;
+        for arg in self.args.iter() {
+            write!(f, " {}", arg)?;
+        }
+
+        if let Some(node) = &self.node {
+            write!(f, " [{}]", node)?;
+        }
+
+        if let Some(downgrade) = &self.downgrade {
+            write!(f, " [{}]", downgrade)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuditError<S: System> {
+    #[error("unable to read the audit log {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("invalid entry in the audit log {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, serde_json::Error),
+
+    #[error("unable to write the audit log {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("the invocation counter {} does not contain a number", .0.display())]
+    InvalidCounter(PathBuf),
+}
+
+impl Recorder {
+    fn state(&self) -> MutexGuard<'_, RecorderState> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds `entry` to the recording, if commands are being recorded. `entry` is called with the id of the apply.
+    fn record(&self, entry: impl FnOnce(u64, Option<NodeId>) -> AuditEntry) {
+        if let Some(recording) = self.state().recording.as_mut() {
+            let entry = entry(recording.apply, recording.node.clone());
+            recording.entries.push(entry);
+        }
+    }
+
+    /// Starts recording the commands that are executed as part of apply `apply`.
+    pub fn start_recording(&self, apply: u64) {
+        let mut state = self.state();
+        if let Some(tracking) = state.tracking.as_mut() {
+            tracking.apply = Some(apply);
+        }
+
+        state.recording = Some(Recording {
+            apply,
+            node: None,
+            entries: Vec::new(),
+        });
+    }
+
+    /// Stops recording, and returns the commands that have been executed since `start_recording`.
+    pub fn stop_recording(&self) -> Vec<AuditEntry> {
+        self.state()
+            .recording
+            .take()
+            .map(|recording| recording.entries)
+            .unwrap_or_default()
+    }
+
+    /// The id of the apply that is being recorded, if any.
+    pub fn recording_apply(&self) -> Option<u64> {
+        self.state()
+            .recording
+            .as_ref()
+            .map(|recording| recording.apply)
+    }
+
+    /// Attributes the commands that are executed from now on to the requirement `node`, or to no requirement if `node` is `None`.
+    pub fn set_node(&self, node: Option<NodeId>) {
+        if let Some(recording) = self.state().recording.as_mut() {
+            recording.node = node;
+        }
+    }
+
+    /// Records a command that was started at `started`. `result` is `None` if the command could not be started.
+    pub fn command_executed(
+        &self,
+        path: &str,
+        args: &[&str],
+        result: Option<&CommandResult>,
+        started: Instant,
+    ) {
+        self.record(|apply, node| AuditEntry {
+            apply,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.saturating_sub(started.elapsed()).as_secs())
+                .unwrap_or(0),
+            command: path.to_owned(),
+            args: redact(args),
+            exit_code: result.and_then(CommandResult::exit_code),
+            duration_ms: started.elapsed().as_millis() as u64,
+            node,
+            downgrade: None,
+        });
+    }
+
+    /// Records that the apply that is being recorded downgrades install `from` to install `to`.
+    /// The downgrade is recorded as a `side apply` entry, so that it shows up in `side history` between the commands of the apply.
+    pub fn downgrade_started(&self, from: u64, to: u64) {
+        self.record(|apply, _| AuditEntry {
+            apply,
+            started: now(),
+            command: String::from("side"),
+            args: vec![
+                String::from("apply"),
+                to.to_string(),
+                String::from("--allow-downgrade"),
+            ],
+            exit_code: None,
+            duration_ms: 0,
+            node: None,
+            downgrade: Some(Downgrade { from, to }),
+        });
+    }
+
+    /// Records that the apply that is being recorded is a fix by `side verify --fix`.
+    /// Like a downgrade, the fix is recorded as a `side` entry, so that it shows up in `side history` even if the fix executed no commands.
+    pub fn fix_started(&self) {
+        self.record(|apply, _| AuditEntry {
+            apply,
+            started: now(),
+            command: String::from("side"),
+            args: vec![String::from("verify"), String::from("--fix")],
+            exit_code: None,
+            duration_ms: 0,
+            node: None,
+            downgrade: None,
+        });
+    }
+
+    /// Records that `host` presented different host keys than the keys that were pinned in `pinned`.
+    /// The change is recorded as a `side` entry of the requirement that detected it, so that `side history` shows when a host key changed even though the apply fails.
+    pub fn host_key_changed(&self, host: &str, pinned: &Path) {
+        self.record(|apply, node| AuditEntry {
+            apply,
+            started: now(),
+            command: String::from("side"),
+            args: vec![
+                String::from("host-key-changed"),
+                host.to_owned(),
+                pinned.display().to_string(),
+            ],
+            exit_code: None,
+            duration_ms: 0,
+            node,
+            downgrade: None,
+        });
+    }
+
+    /// Starts tracking the requirements that are applied and undone, and the apply id of the commands that are recorded meanwhile.
+    pub fn start_tracking(&self) {
+        self.state().tracking = Some(Tracking::default());
+    }
+
+    /// Records that `requirement` was applied, undone or reverted, if changes are being tracked.
+    pub fn requirement_changed(&self, action: ChangeAction, requirement: &impl Display) {
+        if let Some(tracking) = self.state().tracking.as_mut() {
+            tracking.changes.push(RequirementChange {
+                action,
+                requirement: requirement.to_string(),
+            });
+        }
+    }
+
+    /// Stops tracking, and returns the apply id that was recorded, if any, and the changes since `start_tracking`.
+    pub fn stop_tracking(&self) -> (Option<u64>, Vec<RequirementChange>) {
+        self.state()
+            .tracking
+            .take()
+            .map(|tracking| (tracking.apply, tracking.changes))
+            .unwrap_or_default()
+    }
+}
+
+/// Attributes the commands that `system` executes from now on to the requirement `node`, if a recorder is attached to it.
+pub fn set_node<S: System>(system: &S, node: Option<NodeId>) {
+    if let Some(recorder) = system.recorder() {
+        recorder.set_node(node);
+    }
+}
+
+/// Records that `requirement` was applied, undone or reverted, if a recorder is attached to `system`.
+pub fn requirement_changed<S: System>(
+    system: &S,
+    action: ChangeAction,
+    requirement: &impl Display,
+) {
+    if let Some(recorder) = system.recorder() {
+        recorder.requirement_changed(action, requirement);
+    }
+}
+
+/// Records that the host key of `host` has changed, if a recorder is attached to `system`.
+pub fn host_key_changed<S: System>(system: &S, host: &str, pinned: &Path) {
+    if let Some(recorder) = system.recorder() {
+        recorder.host_key_changed(host, pinned);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// What happened to a requirement during an invocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeAction {
+    /// The requirement was created, or modified because it changed.
+    Applied,
+    Undone,
+
+    /// The requirement was undone because the apply failed.
+    Reverted,
+}
+
+impl Display for ChangeAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ChangeAction::Applied => "applied",
+            ChangeAction::Undone => "undone",
+            ChangeAction::Reverted => "reverted",
+        })
+    }
+}
+
+/// A requirement that an invocation applied or undid, in the order in which it happened.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequirementChange {
+    pub action: ChangeAction,
+    pub requirement: String,
+}
+
+/// A single build, apply or verification, as recorded in the invocation log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invocation {
+    pub id: u64,
+
+    /// When the invocation started, in seconds since the Unix epoch.
+    pub started: u64,
+    pub duration_ms: u64,
+
+    /// The user that ran side. For `sudo`, this is the user that ran `sudo`.
+    pub user: String,
+
+    /// The command, such as `apply` or `verify --fix`.
+    pub command: String,
+
+    /// The current install before and after the invocation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<u64>,
+
+    /// The apply under which `side history show` lists the commands that the invocation executed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apply: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<RequirementChange>,
+
+    pub outcome: Outcome,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Invocation {
+    /// A single line that summarizes the invocation, for `side history invocations`.
+    pub fn summary(&self) -> String {
+        let count = |action| {
+            self.changes
+                .iter()
+                .filter(|change| change.action == action)
+                .count()
+        };
+        let installs = match (self.from, self.to) {
+            (Some(from), Some(to)) if from != to => format!(" {} -> {}", from, to),
+            (_, Some(to)) => format!(" on {}", to),
+            _ => String::new(),
+        };
+        format!(
+            "{} {} {} {}{} {}: {} applied, {} undone, {} reverted",
+            self.id,
+            self.started,
+            self.user,
+            self.command,
+            installs,
+            self.outcome,
+            count(ChangeAction::Applied),
+            count(ChangeAction::Undone),
+            count(ChangeAction::Reverted)
+        )
+    }
+}
+
+impl Display for Invocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.summary())?;
+        if let Some(apply) = self.apply {
+            writeln!(f, "commands: side history show {}", apply)?;
+        }
+
+        if let Some(error) = &self.error {
+            writeln!(f, "error: {}", error)?;
+        }
+
+        for change in self.changes.iter() {
+            writeln!(f, "  {}: {}", change.action, change.requirement)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The user that runs side: the user that ran `sudo` if side runs through sudo, otherwise the user of the process.
+pub fn current_user() -> String {
+    std::env::var("SUDO_USER")
+        .or_else(|_| std::env::var("USER"))
+        .unwrap_or_else(|_| format!("uid {}", unsafe { libc::getuid() }))
+}
+
+/// The log of invocations in the base directory. It contains one JSON entry per line.
+///
+/// The id of the next invocation is kept in a counter file next to the log, so that appending an invocation does not read the log.
+pub struct InvocationLog<'p> {
+    path: &'p Path,
+}
+
+impl<'p> InvocationLog<'p> {
+    pub fn new(path: &'p Path) -> InvocationLog<'p> {
+        InvocationLog { path }
+    }
+
+    /// Loads all invocations. A log that does not exist yet is empty.
+    pub fn load<S: System>(&self, system: &S) -> Result<Vec<Invocation>, AuditError<S>> {
+        let contents = read_log(system, self.path)?;
+        String::from_utf8_lossy(&contents)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line).map_err(|e| AuditError::Invalid(self.path.to_owned(), e))
+            })
+            .collect()
+    }
+
+    /// Adds `invocation` to the end of the log with the next id, and returns that id.
+    /// The log is opened in append mode, so existing entries are never changed.
+    pub fn append<S: System>(
+        &self,
+        system: &mut S,
+        mut invocation: Invocation,
+    ) -> Result<u64, AuditError<S>> {
+        if let Some(parent) = self.path.parent() {
+            system
+                .make_dir_all(parent)
+                .map_err(|e| AuditError::UnableToWrite(self.path.to_owned(), e))?;
+        }
+
+        // The counter is updated first: if side is interrupted before the entry is written, an id is skipped rather than used twice.
+        invocation.id = self.next_id(system)?;
+        let counter = self.counter();
+        system
+            .put_file_contents(&counter, (invocation.id + 1).to_string().as_bytes())
+            .map_err(|e| AuditError::UnableToWrite(counter, e))?;
+
+        let mut line = serde_json::to_string(&invocation).unwrap().into_bytes();
+        line.push(b'\n');
+        system
+            .append_file_contents(self.path, &line)
+            .map_err(|e| AuditError::UnableToWrite(self.path.to_owned(), e))?;
+        Ok(invocation.id)
+    }
+
+    fn counter(&self) -> PathBuf {
+        self.path.with_extension("next")
+    }
+
+    /// The id for the next invocation. Logs that were written before the counter existed are counted once.
+    fn next_id<S: System>(&self, system: &S) -> Result<u64, AuditError<S>> {
+        let counter = self.counter();
+        let exists = system
+            .path_exists(&counter)
+            .map_err(|e| AuditError::UnableToRead(counter.clone(), e))?;
+        if exists {
+            let contents = system
+                .file_contents(&counter)
+                .map_err(|e| AuditError::UnableToRead(counter.clone(), e))?;
+            return String::from_utf8_lossy(&contents)
+                .trim()
+                .parse()
+                .map_err(|_| AuditError::InvalidCounter(counter));
+        }
+
+        let contents = read_log(system, self.path)?;
+        Ok(String::from_utf8_lossy(&contents)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .count() as u64
+            + 1)
+    }
+}
+
+fn read_log<S: System>(system: &S, path: &Path) -> Result<Vec<u8>, AuditError<S>> {
+    let exists = system
+        .path_exists(path)
+        .map_err(|e| AuditError::UnableToRead(path.to_owned(), e))?;
+    if !exists {
+        return Ok(Vec::new());
+    }
+
+    system
+        .file_contents(path)
+        .map_err(|e| AuditError::UnableToRead(path.to_owned(), e))
+}
+
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SENSITIVE.iter().any(|word| name.contains(word))
+}
+
+/// Redacts the values of arguments that look like secrets: `name=value` and `--name=value` where the name is sensitive,
+/// and the argument that follows a sensitive flag such as `--password`.
+pub fn redact(args: &[&str]) -> Vec<String> {
+    let mut result = Vec::with_capacity(args.len());
+    let mut redact_next = false;
+    for arg in args {
+        if redact_next {
+            result.push(REDACTED.to_owned());
+            redact_next = false;
+        } else if let Some((name, _)) = arg.split_once('=').filter(|(name, _)| is_sensitive(name)) {
+            result.push(format!("{}={}", name, REDACTED));
+        } else {
+            redact_next = arg.starts_with('-') && is_sensitive(arg);
+            result.push((*arg).to_owned());
+        }
+    }
+
+    result
+}
+
+/// The audit log in the base directory. It contains one JSON entry per line.
+pub struct AuditLog<'p> {
+    path: &'p Path,
+}
+
+impl<'p> AuditLog<'p> {
+    pub fn new(path: &'p Path) -> AuditLog<'p> {
+        AuditLog { path }
+    }
+
+    /// Loads all entries. A log that does not exist yet is empty.
+    pub fn load<S: System>(&self, system: &S) -> Result<Vec<AuditEntry>, AuditError<S>> {
+        let contents = self.contents(system)?;
+        String::from_utf8_lossy(&contents)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line).map_err(|e| AuditError::Invalid(self.path.to_owned(), e))
+            })
+            .collect()
+    }
+
+    /// The id for the next apply.
+    pub fn next_apply<S: System>(&self, system: &S) -> Result<u64, AuditError<S>> {
+        Ok(self
+            .load(system)?
+            .iter()
+            .map(|entry| entry.apply + 1)
+            .max()
+            .unwrap_or(1))
+    }
+
+    /// Adds `entries` to the end of the log. The log is opened in append mode, so existing entries are never changed.
+    pub fn append<S: System>(
+        &self,
+        system: &S,
+        entries: &[AuditEntry],
+    ) -> Result<(), AuditError<S>> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut lines = Vec::new();
+        for entry in entries {
+            lines.extend_from_slice(serde_json::to_string(entry).unwrap().as_bytes());
+            lines.push(b'\n');
+        }
+
+        system
+            .append_file_contents(self.path, &lines)
+            .map_err(|e| AuditError::UnableToWrite(self.path.to_owned(), e))
+    }
+
+    fn contents<S: System>(&self, system: &S) -> Result<Vec<u8>, AuditError<S>> {
+        read_log(system, self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        redact, requirement_changed, AuditEntry, AuditLog, ChangeAction, Downgrade, Invocation,
+        InvocationLog, Recorder, RequirementChange, REDACTED,
+    };
+    use crate::notify::Outcome;
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+    use std::path::Path;
+
+    fn invocation(command: &str, outcome: Outcome) -> Invocation {
+        Invocation {
+            id: 0,
+            started: 1700000000,
+            duration_ms: 2500,
+            user: String::from("alice"),
+            command: command.to_owned(),
+            from: Some(3),
+            to: Some(4),
+            apply: Some(9),
+            changes: vec![
+                RequirementChange {
+                    action: ChangeAction::Applied,
+                    requirement: String::from("file(/etc/motd)"),
+                },
+                RequirementChange {
+                    action: ChangeAction::Undone,
+                    requirement: String::from("dir(/srv/old)"),
+                },
+            ],
+            outcome,
+            error: None,
+        }
+    }
+
+    #[test]
+    pub fn serialize_deserialize_audit_entry() {
+        let entry = AuditEntry {
+            apply: 3,
+            started: 1700000000,
+            command: String::from("systemctl"),
+            args: vec![String::from("restart"), String::from("nginx")],
+            exit_code: Some(0),
+            duration_ms: 120,
+            node: None,
+            downgrade: None,
+        };
+        let json = r#"{"apply":3,"started":1700000000,"command":"systemctl","args":["restart","nginx"],"exit_code":0,"duration_ms":120}"#;
+
+        assert_eq!(serde_json::to_string(&entry).unwrap(), json);
+        assert_eq!(entry, serde_json::from_str(json).unwrap());
+        assert_eq!(
+            entry.to_string(),
+            "1700000000   0    120ms systemctl restart nginx"
+        );
+    }
+
+    #[test]
+    pub fn redact_secrets() {
+        assert_eq!(
+            redact(&["--user", "root", "--password", "hunter2", "-v"]),
+            vec!["--user", "root", "--password", REDACTED, "-v"]
+        );
+        assert_eq!(
+            redact(&["--api-token=abc", "DB_PASSWORD=abc", "name=www"]),
+            vec![
+                format!("--api-token={}", REDACTED),
+                format!("DB_PASSWORD={}", REDACTED),
+                String::from("name=www")
+            ]
+        );
+    }
+
+    #[test]
+    pub fn record_and_append_commands() {
+        let dir = TempDir::new("audit");
+        let path = dir.join("audit.jsonl");
+        let log = AuditLog::new(&path);
+        let mut system = LocalSystem::new();
+        let recorder = Recorder::default();
+        system.set_recorder(Some(recorder.clone()));
+
+        let first = log.next_apply(&system).unwrap();
+        recorder.start_recording(first);
+        system.execute_command("true", &[]).unwrap();
+        system.execute_command("false", &[]).unwrap();
+        LocalSystem::new().execute_command("true", &[]).unwrap();
+        let entries = recorder.stop_recording();
+        log.append(&system, &entries).unwrap();
+
+        system.execute_command("true", &[]).unwrap();
+        let second = log.next_apply(&system).unwrap();
+        let loaded = log.load(&system).unwrap();
+
+        recorder.start_recording(second);
+        system.execute_command("true", &[]).unwrap();
+        let more = recorder.stop_recording();
+        log.append(&system, &more).unwrap();
+        let appended = log.load(&system).unwrap();
+
+        assert_eq!(first, 1);
+        assert_eq!(second, 2);
+        assert_eq!(loaded, entries);
+        assert_eq!(appended, [entries.clone(), more].concat());
+        assert_eq!(
+            loaded
+                .iter()
+                .map(|entry| (entry.command.as_str(), entry.exit_code))
+                .collect::<Vec<_>>(),
+            vec![("true", Some(0)), ("false", Some(1))]
+        );
+    }
+
+    #[test]
+    pub fn serialize_deserialize_invocation() {
+        let invocation = invocation("apply", Outcome::Succeeded);
+        let json = r#"{"id":0,"started":1700000000,"duration_ms":2500,"user":"alice","command":"apply","from":3,"to":4,"apply":9,"changes":[{"action":"applied","requirement":"file(/etc/motd)"},{"action":"undone","requirement":"dir(/srv/old)"}],"outcome":"succeeded"}"#;
+
+        assert_eq!(serde_json::to_string(&invocation).unwrap(), json);
+        assert_eq!(invocation, serde_json::from_str(json).unwrap());
+        assert_eq!(
+            invocation.summary(),
+            "0 1700000000 alice apply 3 -> 4 succeeded: 1 applied, 1 undone, 0 reverted"
+        );
+        assert_eq!(
+            invocation.to_string(),
+            "0 1700000000 alice apply 3 -> 4 succeeded: 1 applied, 1 undone, 0 reverted\n\
+            commands: side history show 9\n  \
+            applied: file(/etc/motd)\n  \
+            undone: dir(/srv/old)\n"
+        );
+    }
+
+    #[test]
+    pub fn track_changes_and_apply() {
+        let mut system = LocalSystem::new();
+        let recorder = Recorder::default();
+        requirement_changed(&system, ChangeAction::Applied, &"not attached");
+        system.set_recorder(Some(recorder.clone()));
+        requirement_changed(&system, ChangeAction::Applied, &"not tracked");
+
+        // A recorder of another system does not see the changes
+        let other = Recorder::default();
+        other.start_tracking();
+
+        recorder.start_tracking();
+        recorder.start_recording(5);
+        requirement_changed(&system, ChangeAction::Reverted, &"file(/etc/motd)");
+        recorder.stop_recording();
+
+        assert_eq!(other.stop_tracking(), (None, Vec::new()));
+        assert_eq!(
+            recorder.stop_tracking(),
+            (
+                Some(5),
+                vec![RequirementChange {
+                    action: ChangeAction::Reverted,
+                    requirement: String::from("file(/etc/motd)"),
+                }]
+            )
+        );
+        assert_eq!(recorder.stop_tracking(), (None, Vec::new()));
+    }
+
+    #[test]
+    pub fn append_invocations() {
+        let dir = TempDir::new("invocations");
+        let path = dir.join("audit/invocations.jsonl");
+        let log = InvocationLog::new(&path);
+        let mut system = LocalSystem::new();
+
+        assert!(log.load(&system).unwrap().is_empty());
+        let first = log
+            .append(&mut system, invocation("apply", Outcome::Succeeded))
+            .unwrap();
+        let second = log
+            .append(&mut system, invocation("verify", Outcome::Failed))
+            .unwrap();
+        let loaded = log.load(&system).unwrap();
+        let counter = std::fs::read_to_string(dir.join("audit/invocations.next")).unwrap();
+
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(counter, "3");
+        assert_eq!(
+            loaded
+                .iter()
+                .map(|invocation| (
+                    invocation.id,
+                    invocation.command.as_str(),
+                    invocation.outcome
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, "apply", Outcome::Succeeded),
+                (2, "verify", Outcome::Failed)
+            ]
+        );
+    }
+
+    #[test]
+    pub fn record_downgrade() {
+        let recorder = Recorder::default();
+        recorder.start_recording(4);
+        recorder.downgrade_started(7, 5);
+        let entries = recorder.stop_recording();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].apply, 4);
+        assert_eq!(entries[0].downgrade, Some(Downgrade { from: 7, to: 5 }));
+        assert!(entries[0]
+            .to_string()
+            .ends_with("side apply 5 --allow-downgrade [downgrade from 7 to 5]"));
+    }
+
+    #[test]
+    pub fn record_host_key_change() {
+        let recorder = Recorder::default();
+        recorder.host_key_changed("backup.example.com", Path::new("/keys/backup"));
+        recorder.start_recording(2);
+        recorder.host_key_changed("backup.example.com", Path::new("/keys/backup"));
+        let entries = recorder.stop_recording();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].command, "side");
+        assert_eq!(
+            entries[0].args,
+            vec!["host-key-changed", "backup.example.com", "/keys/backup"]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+//! Batching of read-only commands, to amortize the cost of starting commands on remote systems.
+//!
+//! Every command that is executed in an LXC instance or over SSH needs its own round-trip.
+//! [`script`] combines several commands into a single shell script, and [`parse_output`] splits the output of that script back into separate results.
+//! [`BatchedSystem`] prefetches the results of [`Probe`]s, for example all checks that are needed to verify a graph, and answers them from a cache.
+use crate::audit::Recorder;
+use crate::bootstrap::quote;
+use crate::builder::fs::Sha3;
+use crate::system::{CommandResult, System, SystemLock};
+use crate::transfer::TransferLimits;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const HEADER: &str = "side-batch";
+
+/// A read-only check whose result can be prefetched.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Probe {
+    /// Checks whether a path exists, like `System::path_exists`.
+    PathExists(PathBuf),
+
+    /// Executes a command without input, like `System::execute_command`.
+    Command(String, Vec<String>),
+}
+
+impl Probe {
+    pub fn command(path: &str, args: &[&str]) -> Probe {
+        Probe::Command(
+            path.to_owned(),
+            args.iter().map(|&arg| arg.to_owned()).collect(),
+        )
+    }
+}
+
+/// Generates a shell script that runs all commands in order, without input.
+/// For each command, the script prints a header line with the exit code and the length of stdout and stderr, followed by stdout and stderr themselves.
+pub fn script(commands: &[(&str, &[&str])]) -> String {
+    let mut script = String::from("out=$(mktemp)\nerr=$(mktemp)\n");
+    for (path, args) in commands {
+        script.push_str(&quote(path));
+        for arg in args.iter() {
+            script.push(' ');
+            script.push_str(&quote(arg));
+        }
+
+        script.push_str(" </dev/null >\"$out\" 2>\"$err\"\ncode=$?\n");
+        script.push_str(&format!(
+            "printf '{} %s %s %s\\n' \"$code\" \"$(wc -c <\"$out\")\" \"$(wc -c <\"$err\")\"\n",
+            HEADER
+        ));
+        script.push_str("cat \"$out\" \"$err\"\n");
+    }
+
+    script.push_str("rm -f \"$out\" \"$err\"\n");
+    script
+}
+
+/// Parses the output of a script generated by [`script`] with `count` commands.
+/// Returns `None` if the output is incomplete or malformed.
+pub fn parse_output(mut output: &[u8], count: usize) -> Option<Vec<CommandResult>> {
+    let mut results = Vec::with_capacity(count);
+    for _ in 0..count {
+        let end = output.iter().position(|&b| b == b'\n')?;
+        let header = std::str::from_utf8(&output[..end]).ok()?;
+        let mut fields = header.split_whitespace();
+        if fields.next()? != HEADER {
+            return None;
+        }
+
+        let exit_code = fields.next()?.parse().ok()?;
+        let stdout_len: usize = fields.next()?.parse().ok()?;
+        let stderr_len: usize = fields.next()?.parse().ok()?;
+        output = &output[end + 1..];
+        if output.len() < stdout_len + stderr_len {
+            return None;
+        }
+
+        let (stdout, rest) = output.split_at(stdout_len);
+        let (stderr, rest) = rest.split_at(stderr_len);
+        results.push(CommandResult::new(
+            stdout.to_vec(),
+            stderr.to_vec(),
+            Some(exit_code),
+        ));
+        output = rest;
+    }
+
+    Some(results)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PrefetchError<S: System> {
+    #[error("unable to check whether paths exist: {}", .0)]
+    Paths(S::Error),
+
+    #[error("unable to execute commands: {}", .0)]
+    Commands(S::CommandError),
+}
+
+/// Wraps a system and answers prefetched probes from a cache.
+/// The cache is cleared whenever the system might be changed, that is on any modification and on any command that was not prefetched.
+#[derive(Debug)]
+pub struct BatchedSystem<'s, S: System> {
+    inner: &'s mut S,
+    paths: RefCell<HashMap<PathBuf, bool>>,
+    commands: RefCell<HashMap<(String, Vec<String>), CommandResult>>,
+}
+
+impl<'s, S: System> BatchedSystem<'s, S> {
+    pub fn new(inner: &'s mut S) -> BatchedSystem<'s, S> {
+        BatchedSystem {
+            inner,
+            paths: RefCell::new(HashMap::new()),
+            commands: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Determines the results of all probes in at most two batches, and caches them.
+    pub fn prefetch(&mut self, probes: &[Probe]) -> Result<(), PrefetchError<S>> {
+        let mut paths = Vec::new();
+        let mut commands = Vec::new();
+        for probe in probes {
+            match probe {
+                Probe::PathExists(path) => paths.push(path.as_path()),
+                Probe::Command(path, args) => commands.push((path, args)),
+            }
+        }
+
+        if !paths.is_empty() {
+            let exist = self
+                .inner
+                .paths_exist(&paths)
+                .map_err(PrefetchError::Paths)?;
+            self.paths
+                .get_mut()
+                .extend(paths.into_iter().map(Path::to_path_buf).zip(exist));
+        }
+
+        if !commands.is_empty() {
+            let args = commands
+                .iter()
+                .map(|(_, args)| args.iter().map(String::as_str).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            let batch = commands
+                .iter()
+                .zip(args.iter())
+                .map(|((path, _), args)| (path.as_str(), args.as_slice()))
+                .collect::<Vec<_>>();
+            let results = self
+                .inner
+                .execute_batch(&batch)
+                .map_err(PrefetchError::Commands)?;
+            self.commands.get_mut().extend(
+                commands
+                    .into_iter()
+                    .map(|(path, args)| (path.clone(), args.clone()))
+                    .zip(results),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn invalidate(&self) {
+        self.paths.borrow_mut().clear();
+        self.commands.borrow_mut().clear();
+    }
+}
+
+impl<'s, S: System> System for BatchedSystem<'s, S> {
+    type Error = S::Error;
+    type CommandError = S::CommandError;
+
+    fn path_exists(&self, path: &Path) -> Result<bool, Self::Error> {
+        match self.paths.borrow().get(path) {
+            Some(&exists) => Ok(exists),
+            None => self.inner.path_exists(path),
+        }
+    }
+
+    fn path_is_dir(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.inner.path_is_dir(path)
+    }
+
+    fn file_contents(&self, path: &Path) -> Result<Vec<u8>, Self::Error> {
+        self.inner.file_contents(path)
+    }
+
+    fn put_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.put_file_contents(path, contents)
+    }
+
+    fn append_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.append_file_contents(path, contents)
+    }
+
+    fn replace_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.replace_file_contents(path, contents)
+    }
+
+    fn file_sha3(&self, path: &Path) -> Result<Sha3, Self::Error> {
+        self.inner.file_sha3(path)
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64, Self::Error> {
+        self.inner.file_size(path)
+    }
+
+    fn file_mtime(&self, path: &Path) -> Result<Option<SystemTime>, Self::Error> {
+        self.inner.file_mtime(path)
+    }
+
+    fn execute_command(
+        &self,
+        path: &str,
+        args: &[&str],
+    ) -> Result<CommandResult, Self::CommandError> {
+        let key = (
+            path.to_owned(),
+            args.iter().map(|&arg| arg.to_owned()).collect::<Vec<_>>(),
+        );
+        if let Some(result) = self.commands.borrow().get(&key) {
+            return Ok(result.clone());
+        }
+
+        self.invalidate();
+        self.inner.execute_command(path, args)
+    }
+
+    fn execute_command_with_input(
+        &self,
+        path: &str,
+        args: &[&str],
+        input: &[u8],
+    ) -> Result<CommandResult, Self::CommandError> {
+        self.invalidate();
+        self.inner.execute_command_with_input(path, args, input)
+    }
+
+    fn execute_command_streaming(
+        &self,
+        path: &str,
+        args: &[&str],
+        output: &mut dyn FnMut(&[u8]),
+    ) -> Result<CommandResult, Self::CommandError> {
+        self.invalidate();
+        self.inner.execute_command_streaming(path, args, output)
+    }
+
+    fn execute_batch(
+        &self,
+        commands: &[(&str, &[&str])],
+    ) -> Result<Vec<CommandResult>, Self::CommandError> {
+        self.invalidate();
+        self.inner.execute_batch(commands)
+    }
+
+    fn copy_file(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.copy_file(from, to)
+    }
+
+    fn copy_file_with_progress(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.copy_file_with_progress(from, to, progress)
+    }
+
+    fn copy_file_resumable(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        limits: &TransferLimits,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.copy_file_resumable(from, to, limits, progress)
+    }
+
+    fn symlink(&mut self, target: &Path, link: &Path) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.symlink(target, link)
+    }
+
+    fn make_dir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.make_dir(path)
+    }
+
+    fn make_dir_all(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.make_dir_all(path)
+    }
+
+    fn dir_is_empty(&mut self, path: &Path) -> Result<bool, Self::Error> {
+        self.inner.dir_is_empty(path)
+    }
+
+    fn read_dir(&mut self, path: &Path) -> Result<Vec<String>, Self::Error> {
+        self.inner.read_dir(path)
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.remove_dir(path)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.remove_file(path)
+    }
+
+    fn get_user(&mut self, name: &str) -> Result<Option<()>, Self::Error> {
+        self.inner.get_user(name)
+    }
+
+    fn chmod(&mut self, path: &Path, mode: u32) -> Result<(), Self::Error> {
+        self.invalidate();
+        self.inner.chmod(path, mode)
+    }
+
+    fn lock(&mut self, path: &Path, wait: bool) -> Result<Option<SystemLock>, Self::Error> {
+        self.inner.lock(path, wait)
+    }
+
+    fn recorder(&self) -> Option<&Recorder> {
+        self.inner.recorder()
+    }
+
+    fn set_recorder(&mut self, recorder: Option<Recorder>) -> Option<Recorder> {
+        self.inner.set_recorder(recorder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_output, script, BatchedSystem, Probe};
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+    use std::path::Path;
+
+    #[test]
+    pub fn script_round_trip() {
+        let commands: [(&str, &[&str]); 3] = [
+            ("printf", &["it's\\nbinary\\000safe"]),
+            ("sh", &["-c", "echo oops >&2; exit 3"]),
+            ("true", &[]),
+        ];
+        let output = LocalSystem::new()
+            .execute_command_with_input("sh", &[], script(&commands).as_bytes())
+            .unwrap();
+        let results = parse_output(output.stdout(), commands.len()).unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].stdout(), b"it's\nbinary\0safe");
+        assert!(results[0].is_success());
+        assert_eq!(results[1].stderr(), b"oops\n");
+        assert!(!results[1].is_success());
+        assert!(results[2].is_success());
+        assert!(parse_output(output.stdout(), 4).is_none());
+    }
+
+    #[test]
+    pub fn default_execute_batch() {
+        let results = LocalSystem::new()
+            .execute_batch(&[("echo", &["a"]), ("false", &[])])
+            .unwrap();
+
+        assert_eq!(results[0].stdout(), b"a\n");
+        assert!(!results[1].is_success());
+    }
+
+    #[test]
+    pub fn prefetched_probes_are_cached_until_modified() {
+        let temp = TempDir::new("batch");
+        let dir = temp.join("probed");
+        let mut local = LocalSystem::new();
+        let mut system = BatchedSystem::new(&mut local);
+        system
+            .prefetch(&[
+                Probe::PathExists(dir.clone()),
+                Probe::command("echo", &["cached"]),
+            ])
+            .unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let cached = system.path_exists(&dir).unwrap();
+        let output = system.execute_command("echo", &["cached"]).unwrap();
+        system.remove_dir(Path::new(&dir)).unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        let fresh = system.path_exists(&dir).unwrap();
+
+        assert!(!cached);
+        assert_eq!(output.stdout(), b"cached\n");
+        assert!(fresh);
+    }
+}
@@ -0,0 +1,231 @@
+//! Generating first-boot artifacts from an install.
+//!
+//! The graph of an install is converted into a standalone shell script, or into cloud-init user-data that runs that script.
+//! This allows a new machine to be provisioned without running libside on it.
+//! Only requirements that implement [`Requirement::to_shell`] can be represented. All other nodes are listed in the report
+//! returned by [`Bootstrap::unsupported`], and are skipped in the generated script.
+use crate::graph::{Graph, GraphWalker};
+use crate::requirements::Requirement;
+use crate::system::System;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// The location that the bootstrap script is written to by the cloud-init user-data.
+pub const CLOUD_INIT_SCRIPT_PATH: &str = "/var/lib/side-bootstrap.sh";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BootstrapFormat {
+    /// A POSIX shell script.
+    #[default]
+    Shell,
+
+    /// cloud-init user-data (`#cloud-config`) that writes and runs the shell script on first boot.
+    CloudInit,
+}
+
+impl FromStr for BootstrapFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shell" => Ok(BootstrapFormat::Shell),
+            "cloud-init" => Ok(BootstrapFormat::CloudInit),
+            _ => Err(format!(
+                "invalid bootstrap format {:?}, expected 'shell' or 'cloud-init'",
+                s
+            )),
+        }
+    }
+}
+
+/// A node that could not be represented in the bootstrap script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported {
+    pub node: usize,
+    pub package: Option<String>,
+    pub requirement: String,
+}
+
+impl Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "node {}: {}", self.node, self.requirement)?;
+        if let Some(package) = &self.package {
+            write!(f, " (package {})", package)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Bootstrap {
+    script: String,
+    unsupported: Vec<Unsupported>,
+}
+
+impl Bootstrap {
+    /// Converts `graph` into a shell script. The requirements are emitted in an order that satisfies all dependencies.
+    pub fn generate<R: Requirement, State, S: System>(
+        graph: &Graph<R, State>,
+        system: &mut S,
+    ) -> Bootstrap {
+        let mut script = String::from("#!/bin/sh\nset -eu\n");
+        let mut unsupported = Vec::new();
+        let mut walker =
+            GraphWalker::new(graph).expect("the graph of an install never contains a cycle");
+        while let Some((index, node)) = walker.next() {
+            let requirement = node.requirement();
+            script.push('\n');
+            match requirement.to_shell(system) {
+                Some(commands) => {
+                    script.push_str(&format!("# {}\n", requirement));
+                    script.push_str(&commands);
+                    if !commands.ends_with('\n') {
+                        script.push('\n');
+                    }
+                }
+                None => {
+                    script.push_str(&format!(
+                        "# skipped, cannot be represented: {}\n",
+                        requirement
+                    ));
+                    unsupported.push(Unsupported {
+                        node: index,
+                        package: node.package().map(str::to_owned),
+                        requirement: requirement.to_string(),
+                    });
+                }
+            }
+        }
+
+        Bootstrap {
+            script,
+            unsupported,
+        }
+    }
+
+    pub fn script(&self) -> &str {
+        &self.script
+    }
+
+    /// The nodes that were skipped because they cannot be represented in a shell script.
+    pub fn unsupported(&self) -> &[Unsupported] {
+        &self.unsupported
+    }
+
+    /// Wraps the script in cloud-init user-data.
+    pub fn cloud_config(&self) -> String {
+        format!(
+            "#cloud-config\nwrite_files:\n  - path: {}\n    permissions: '0700'\n    encoding: b64\n    content: {}\nruncmd:\n  - [{}]\n",
+            CLOUD_INIT_SCRIPT_PATH,
+            base64::encode(&self.script),
+            CLOUD_INIT_SCRIPT_PATH,
+        )
+    }
+
+    pub fn render(&self, format: BootstrapFormat) -> String {
+        match format {
+            BootstrapFormat::Shell => self.script.clone(),
+            BootstrapFormat::CloudInit => self.cloud_config(),
+        }
+    }
+}
+
+/// Quotes `s` so that it is interpreted as a single word by the shell.
+/// Words that only contain characters without a special meaning are returned unchanged.
+pub fn quote(s: &str) -> String {
+    let plain = s
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+    if plain && !s.is_empty() {
+        s.to_owned()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+/// Formats a single command line, quoting all arguments.
+pub fn command(program: &str, args: &[&str]) -> String {
+    let mut line = program.to_owned();
+    for arg in args {
+        line.push(' ');
+        line.push_str(&quote(arg));
+    }
+
+    line.push('\n');
+    line
+}
+
+/// Formats commands that write `contents` to `path`. The contents are base64-encoded, so binary files are preserved.
+pub fn write_file(path: &str, contents: &[u8]) -> String {
+    let encoded = base64::encode(contents);
+    let mut result = format!("base64 -d > {} <<'EOF'\n", quote(path));
+    for line in encoded.as_bytes().chunks(76) {
+        result.push_str(std::str::from_utf8(line).unwrap());
+        result.push('\n');
+    }
+
+    result.push_str("EOF\n");
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quote, Bootstrap, BootstrapFormat};
+    use crate::builder::fs::CreateDirectory;
+    use crate::builder::mysql::CreateMySqlDatabase;
+    use crate::graph::{Graph, Pending};
+    use crate::system::LocalSystem;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn parse_bootstrap_format() {
+        assert_eq!(
+            "shell".parse::<BootstrapFormat>().unwrap(),
+            BootstrapFormat::Shell
+        );
+        assert_eq!(
+            "cloud-init".parse::<BootstrapFormat>().unwrap(),
+            BootstrapFormat::CloudInit
+        );
+        assert!("ignition".parse::<BootstrapFormat>().is_err());
+    }
+
+    #[test]
+    pub fn quoting() {
+        assert_eq!(quote("--shell"), "--shell");
+        assert_eq!(quote("/srv/my app"), "'/srv/my app'");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    pub fn generate_script() {
+        let mut graph = Graph::<CreateDirectory, Pending>::new();
+        let parent = graph.add(CreateDirectory::new(PathBuf::from("/srv")), &[]);
+        graph.add(CreateDirectory::new(PathBuf::from("/srv/app")), &[parent]);
+
+        let bootstrap = Bootstrap::generate(&graph, &mut LocalSystem::new());
+        let script = bootstrap.script();
+        let parent = script.find("mkdir -p /srv\n").unwrap();
+        let child = script.find("mkdir -p /srv/app\n").unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(parent < child);
+        assert!(bootstrap.unsupported().is_empty());
+
+        let cloud_config = bootstrap.render(BootstrapFormat::CloudInit);
+        assert!(cloud_config.starts_with("#cloud-config\n"));
+        assert!(cloud_config.contains(&base64::encode(script)));
+    }
+
+    #[test]
+    pub fn report_unsupported() {
+        let mut graph = Graph::<CreateMySqlDatabase, Pending>::new();
+        graph.add(CreateMySqlDatabase::new("app"), &[]);
+
+        let bootstrap = Bootstrap::generate(&graph, &mut LocalSystem::new());
+        assert_eq!(bootstrap.unsupported().len(), 1);
+        assert_eq!(bootstrap.unsupported()[0].node, 0);
+        assert!(bootstrap.script().contains("# skipped"));
+    }
+}
@@ -0,0 +1,443 @@
+use crate::bootstrap;
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, FailureHint, Requirement, Supports};
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use super::Context;
+
+const UPDATE_ALTERNATIVES: &str = "update-alternatives";
+
+/// Selects `path` as the alternative for a generic name with `update-alternatives`, such as `/usr/bin/php8.2` for `php`.
+///
+/// The alternative is registered with `--install` if it has not been registered yet, for example by the package that ships it.
+/// The selection that it replaces is saved in the backups directory, so that undoing the requirement restores it,
+/// and an alternative that was registered by the requirement is removed again.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Alternative {
+    name: String,
+    link: PathBuf,
+    path: PathBuf,
+    priority: i32,
+
+    /// Where the replaced selection is saved.
+    saved: PathBuf,
+}
+
+/// The selection of a link group before the requirement was applied.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct Saved {
+    /// Whether the link group was in automatic mode, where the alternative with the highest priority is selected.
+    auto: bool,
+    value: Option<PathBuf>,
+
+    /// Whether the alternative was already registered, in which case it is not removed on undo.
+    registered: bool,
+}
+
+/// The parts of the output of `update-alternatives --query` that are used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Query {
+    auto: bool,
+    value: Option<PathBuf>,
+    alternatives: Vec<PathBuf>,
+}
+
+impl Query {
+    fn parse(output: &str) -> Query {
+        let mut query = Query {
+            auto: false,
+            value: None,
+            alternatives: Vec::new(),
+        };
+        for line in output.lines() {
+            match line.split_once(':') {
+                Some(("Status", status)) => query.auto = status.trim() == "auto",
+                Some(("Value", value)) if value.trim() != "none" => {
+                    query.value = Some(PathBuf::from(value.trim()))
+                }
+                Some(("Alternative", path)) => query.alternatives.push(PathBuf::from(path.trim())),
+                _ => (),
+            }
+        }
+
+        query
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AlternativeError<S: System> {
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("the saved selection in {} is invalid: {}", .0.display(), .1)]
+    InvalidSaved(PathBuf, serde_json::Error),
+
+    #[error("unable to execute update-alternatives: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("update-alternatives failed: {0} {1}")]
+    Unsuccessful(String, String),
+}
+
+impl<S: System> From<(&str, &str)> for AlternativeError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        AlternativeError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+impl Alternative {
+    /// `name` is the name of the link group, for example `php`, and `link` is the generic path, for example `/usr/bin/php`.
+    pub fn new<L: Into<PathBuf>, P: Into<PathBuf>>(name: &str, link: L, path: P) -> Alternative {
+        assert!(
+            !name.is_empty() && !name.contains('/'),
+            "invalid alternatives name {:?}",
+            name
+        );
+
+        Alternative {
+            name: name.to_owned(),
+            link: link.into(),
+            path: path.into(),
+            priority: 0,
+            saved: PathBuf::new(),
+        }
+    }
+
+    /// The priority with which the alternative is registered, if it has not been registered yet.
+    /// The priority only matters when the link group returns to automatic mode.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Adds the alternative to the graph, applied after `dependencies`, such as the package that installs `path`.
+    pub fn add<'r, R, I>(mut self, context: &mut Context<R>, dependencies: I) -> GraphNodeReference
+    where
+        R: Requirement + Supports<Alternative>,
+        I: IntoIterator<Item = &'r GraphNodeReference>,
+    {
+        self.saved = context.alternatives_path.join(&self.name);
+        context.add_node(self, dependencies)
+    }
+
+    /// Returns `None` if the link group does not exist.
+    fn query<S: System>(&self, system: &S) -> Result<Option<Query>, AlternativeError<S>> {
+        let result = system
+            .execute_command(UPDATE_ALTERNATIVES, &["--query", &self.name])
+            .map_err(AlternativeError::FailedToStart)?;
+        if result.is_success() {
+            Ok(Some(Query::parse(result.stdout_as_str())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn run<S: System>(system: &S, args: &[&str]) -> Result<(), AlternativeError<S>> {
+        let result = system
+            .execute_command(UPDATE_ALTERNATIVES, args)
+            .map_err(AlternativeError::FailedToStart)?;
+        result.successful()?;
+
+        Ok(())
+    }
+
+    fn install_args(&self) -> Vec<String> {
+        vec![
+            String::from("--install"),
+            self.link.display().to_string(),
+            self.name.clone(),
+            self.path.display().to_string(),
+            self.priority.to_string(),
+        ]
+    }
+
+    fn select<S: System>(
+        &self,
+        system: &S,
+        query: Option<&Query>,
+    ) -> Result<(), AlternativeError<S>> {
+        let registered = query
+            .map(|query| query.alternatives.contains(&self.path))
+            .unwrap_or(false);
+        if !registered {
+            let args = self.install_args();
+            Self::run(system, &args.iter().map(String::as_str).collect::<Vec<_>>())?;
+        }
+
+        Self::run(system, &["--set", &self.name, path_str(&self.path)])
+    }
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+impl Requirement for Alternative {
+    const NAME: &'static str = "alternative";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A path selected as the alternative for a generic name with `update-alternatives`.",
+        create: "Saves the current selection in the backups directory, registers the alternative with `--install` if needed, and selects it with `--set`.",
+        verify: "Checks that `update-alternatives --query` reports the path as the selected value.",
+        undo: "Restores the saved selection with `--set` or `--auto`. An alternative that the requirement registered is removed again.",
+    };
+    const DOCS_URL: Option<&'static str> =
+        Some("https://manpages.debian.org/update-alternatives.1");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "doesn't exist",
+            hint: "The path of the alternative does not exist. Install the package that provides it first.",
+        },
+    ];
+
+    type CreateError<S: System> = AlternativeError<S>;
+    type ModifyError<S: System> = AlternativeError<S>;
+    type DeleteError<S: System> = AlternativeError<S>;
+    type HasBeenCreatedError<S: System> = AlternativeError<S>;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        let query = self.query(system)?;
+
+        // A selection that was saved by an interrupted apply is the original one, and is kept
+        let exists = system
+            .path_exists(&self.saved)
+            .map_err(|e| AlternativeError::UnableToRead(self.saved.clone(), e))?;
+        if !exists {
+            let saved = Saved {
+                auto: query.as_ref().map(|query| query.auto).unwrap_or(true),
+                value: query.as_ref().and_then(|query| query.value.clone()),
+                registered: query
+                    .as_ref()
+                    .map(|query| query.alternatives.contains(&self.path))
+                    .unwrap_or(false),
+            };
+            if let Some(dir) = self.saved.parent() {
+                system
+                    .make_dir_all(dir)
+                    .map_err(|e| AlternativeError::UnableToWrite(dir.to_owned(), e))?;
+            }
+            system
+                .put_file_contents(
+                    &self.saved,
+                    serde_json::to_string(&saved).unwrap().as_bytes(),
+                )
+                .map_err(|e| AlternativeError::UnableToWrite(self.saved.clone(), e))?;
+        }
+
+        self.select(system, query.as_ref())
+    }
+
+    /// Selects the alternative again after someone else changed the selection or removed the alternative.
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        let query = self.query(system)?;
+        self.select(system, query.as_ref())
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        let contents = system
+            .file_contents(&self.saved)
+            .map_err(|e| AlternativeError::UnableToRead(self.saved.clone(), e))?;
+        let saved: Saved = serde_json::from_slice(&contents)
+            .map_err(|e| AlternativeError::InvalidSaved(self.saved.clone(), e))?;
+
+        if !saved.registered {
+            Self::run(system, &["--remove", &self.name, path_str(&self.path)])?;
+        }
+
+        // Removing the last alternative removes the link group, in which case there is nothing left to restore
+        if self.query(system)?.is_some() {
+            match (saved.auto, &saved.value) {
+                (false, Some(value)) => Self::run(system, &["--set", &self.name, path_str(value)])?,
+                _ => Self::run(system, &["--auto", &self.name])?,
+            }
+        }
+
+        system
+            .remove_file(&self.saved)
+            .map_err(|e| AlternativeError::UnableToWrite(self.saved.clone(), e))
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        system
+            .path_exists(&self.saved)
+            .map_err(|e| AlternativeError::UnableToRead(self.saved.clone(), e))
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        true
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(match self.query(system) {
+            Ok(Some(query)) => {
+                query.value.as_ref() == Some(&self.path) && query.alternatives.contains(&self.path)
+            }
+            _ => false,
+        })
+    }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let args = self.install_args();
+        let mut script = bootstrap::command(
+            UPDATE_ALTERNATIVES,
+            &args.iter().map(String::as_str).collect::<Vec<_>>(),
+        );
+        script.push_str(&bootstrap::command(
+            UPDATE_ALTERNATIVES,
+            &["--set", &self.name, self.path.to_str()?],
+        ));
+        Some(script)
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec![UPDATE_ALTERNATIVES]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::File,
+            self.link.display().to_string(),
+            format!("alternative {} selected", self.path.display()),
+        )
+    }
+}
+
+impl Display for Alternative {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "alternative({} = {})", self.name, self.path.display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Alternative, Query};
+    use crate::{requirements::Requirement, system::System, testing::LxcInstance};
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn serialize_deserialize_alternative() {
+        let mut r = Alternative::new("php", "/usr/bin/php", "/usr/bin/php8.2").priority(82);
+        r.saved = PathBuf::from("/srv/backups/_alternatives/php");
+        let json = r#"{"name":"php","link":"/usr/bin/php","path":"/usr/bin/php8.2","priority":82,"saved":"/srv/backups/_alternatives/php"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn parse_query() {
+        let output = "Name: php
+Link: /usr/bin/php
+Slaves:
+ php.1.gz /usr/share/man/man1/php.1.gz
+Status: manual
+Best: /usr/bin/php8.2
+Value: /usr/bin/php8.1
+
+Alternative: /usr/bin/php8.1
+Priority: 81
+Slaves:
+ php.1.gz /usr/share/man/man1/php8.1.1.gz
+
+Alternative: /usr/bin/php8.2
+Priority: 82
+";
+
+        assert_eq!(
+            Query::parse(output),
+            Query {
+                auto: false,
+                value: Some(PathBuf::from("/usr/bin/php8.1")),
+                alternatives: vec![
+                    PathBuf::from("/usr/bin/php8.1"),
+                    PathBuf::from("/usr/bin/php8.2")
+                ],
+            }
+        );
+        assert_eq!(
+            Query::parse("Name: editor\nStatus: auto\nValue: none\n"),
+            Query {
+                auto: true,
+                value: None,
+                alternatives: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn alternative_name_is_checked() {
+        Alternative::new("../php", "/usr/bin/php", "/usr/bin/php8.2");
+    }
+
+    #[test]
+    #[ignore]
+    pub fn lxc_alternative() {
+        let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
+        sys.put_file_contents("/usr/local/bin/side-editor".as_ref(), b"#!/bin/sh\n")
+            .unwrap();
+        sys.chmod("/usr/local/bin/side-editor".as_ref(), 0o755)
+            .unwrap();
+        let before = sys
+            .execute_command("update-alternatives", &["--query", "editor"])
+            .unwrap()
+            .stdout_as_str()
+            .to_owned();
+
+        let mut r = Alternative::new("editor", "/usr/bin/editor", "/usr/local/bin/side-editor");
+        r.saved = PathBuf::from("/root/side-alternative-editor");
+
+        assert!(!r.has_been_created(&mut sys).unwrap());
+        assert!(!r.verify(&mut sys).unwrap());
+
+        r.create(&mut sys).unwrap();
+
+        assert!(r.has_been_created(&mut sys).unwrap());
+        assert!(r.verify(&mut sys).unwrap());
+
+        sys.execute_command("update-alternatives", &["--auto", "editor"])
+            .unwrap();
+        assert!(!r.verify(&mut sys).unwrap());
+
+        r.modify(&mut sys).unwrap();
+        assert!(r.verify(&mut sys).unwrap());
+
+        r.delete(&mut sys).unwrap();
+
+        assert!(!r.has_been_created(&mut sys).unwrap());
+        assert!(!r.verify(&mut sys).unwrap());
+        assert_eq!(
+            Query::parse(
+                sys.execute_command("update-alternatives", &["--query", "editor"])
+                    .unwrap()
+                    .stdout_as_str()
+            ),
+            Query::parse(&before)
+        );
+    }
+}
@@ -1,7 +1,11 @@
-use super::MinimalContext;
+use super::{ExposeMode, MinimalContext};
 use crate::apply::SystemState;
+use crate::arch::Arch;
+use crate::deleted::DeletedFiles;
+use crate::outputs::Outputs;
 use crate::requirements::Requirement;
 use crate::system::System;
+use crate::transfer::TransferLimits;
 use crate::{
     graph::{ApplyResult, Graph, Pending},
     StateDirs,
@@ -12,6 +16,8 @@ pub struct PreparedBuild<'d, R> {
     contexts: Vec<MinimalContext>,
     install: &'d StateDirs,
     target_graph: Graph<R, Pending>,
+    transfer: TransferLimits,
+    arch: Arch,
 }
 
 impl<'d, R: Requirement> PreparedBuild<'d, R> {
@@ -19,26 +25,108 @@ impl<'d, R: Requirement> PreparedBuild<'d, R> {
         install: &'d StateDirs,
         contexts: Vec<MinimalContext>,
         graph: Graph<R, Pending>,
+        arch: Arch,
     ) -> Self {
         PreparedBuild {
             contexts,
             install,
             target_graph: graph,
+            transfer: TransferLimits::default(),
+            arch,
         }
     }
 
+    /// Copies exposed files into the install with `limits`, on systems that support resumable copies.
+    pub fn with_transfer_limits(mut self, limits: TransferLimits) -> Self {
+        self.transfer = limits;
+        self
+    }
+
+    /// The outputs that all packages declared.
+    pub fn outputs(&self) -> Outputs {
+        let mut outputs = Outputs::default();
+        for context in self.contexts.iter() {
+            outputs.extend(context.outputs.clone());
+        }
+
+        outputs
+    }
+
+    /// The system files that all packages delete.
+    pub fn deleted(&self) -> DeletedFiles {
+        let mut deleted = DeletedFiles::default();
+        for context in self.contexts.iter() {
+            deleted.extend(context.deleted.clone());
+        }
+
+        deleted
+    }
+
+    /// The configuration files that the packages generated, with the paths that they are installed at.
+    pub fn generated_files(&self) -> impl Iterator<Item = (PathBuf, &[u8])> {
+        self.contexts
+            .iter()
+            .flat_map(|c| c.files.iter())
+            .map(|file| {
+                // The generated files of every package mirror the paths that they are installed at
+                let relative = file
+                    .source
+                    .strip_prefix(&self.install.generated)
+                    .unwrap()
+                    .components()
+                    .skip(1);
+                (
+                    Path::new("/").join(relative.collect::<PathBuf>()),
+                    file.contents.as_slice(),
+                )
+            })
+    }
+
+    /// The total size in bytes of the files that will be copied into the install by exposing them.
+    pub fn exposed_size(&self) -> u64 {
+        self.contexts
+            .iter()
+            .flat_map(|c| c.exposed.iter())
+            .filter(|e| e.mode == ExposeMode::Copy)
+            .map(|e| e.size)
+            .sum()
+    }
+
     pub fn generate_files<'r, S: System>(
         &self,
         system: &mut S,
-        _prev: &SystemState<R>,
+        prev: &SystemState<R>,
     ) -> Result<&Graph<R, Pending>, ()> {
+        let mut reported = 0;
+        Ok(self.generate_files_with_progress(system, prev, |progress| {
+            // Log every 10%, so that copying large files does not look like a hang
+            let percentage = progress.copied * 100 / progress.total.max(1);
+            if percentage >= reported + 10 {
+                reported = percentage - percentage % 10;
+                tracing::info!(
+                    "  expose: {}% ({} of {} bytes)",
+                    reported,
+                    progress.copied,
+                    progress.total
+                );
+            }
+        }))
+    }
+
+    /// Like [`PreparedBuild::generate_files`], but calls `progress` while exposed files are being copied instead of logging the progress.
+    pub fn generate_files_with_progress<S: System>(
+        &self,
+        system: &mut S,
+        _prev: &SystemState<R>,
+        mut progress: impl FnMut(&ExposeProgress),
+    ) -> &Graph<R, Pending> {
         // TODO: Use system to create the files
         self.install.create_dirs(system).unwrap();
 
         // Generate the config files, because we need them for the install
         for config in self.contexts.iter().map(|c| c.files.iter()).flatten() {
             let path = config.source.parent().unwrap();
-            println!("  prep : {}", config.source.display());
+            tracing::info!("  prep : {}", config.source.display());
             system.make_dir_all(&path).unwrap();
             system
                 .put_file_contents(&config.source, &config.contents)
@@ -52,26 +140,66 @@ impl<'d, R: Requirement> PreparedBuild<'d, R> {
             .flatten()
         {
             let path = deleted.save_to.parent().unwrap();
-            println!("  prep : {}", path.display());
+            tracing::info!("  prep : {}", path.display());
             system.make_dir_all(&path).unwrap();
         }
 
         // Create the main application files
         // TODO: Should we allow custom owners for exposed files, or should we keep everything owned by root? Does it even matter if we don't need the files to ever be writeable?
+        let mut copier = Copier {
+            copied: 0,
+            total: self.exposed_size(),
+            limits: self.transfer,
+            progress: &mut progress,
+        };
+        tracing::info!("  expose: {} bytes to copy", copier.total);
         for context in self.contexts.iter() {
             for exposed in context.exposed.iter() {
-                println!("  expose: {:?}", exposed.source);
+                tracing::info!("  expose: {:?}", exposed.source);
                 let metadata = exposed.source.symlink_metadata().unwrap();
-                if metadata.file_type().is_dir() {
+                if exposed.mode == ExposeMode::Link {
+                    system
+                        .make_dir_all(exposed.target.parent().unwrap())
+                        .unwrap();
+                    system.symlink(&exposed.source, &exposed.target).unwrap();
+                } else if metadata.file_type().is_dir() {
+                    // Only the files that were found while scanning the package are copied, so excluded files are never exposed.
                     system.make_dir_all(&exposed.target).unwrap();
-                    copy(system, &exposed.source, &exposed.target).unwrap();
+                    for file in exposed.files.iter() {
+                        let target = exposed
+                            .target
+                            .join(file.strip_prefix(&exposed.source).unwrap());
+                        if system.path_is_dir(file).unwrap() {
+                            system.make_dir_all(&target).unwrap();
+                        } else {
+                            copier.copy(system, file, &target).unwrap();
+                        }
+                    }
                 } else {
-                    copy_file(system, &exposed.source, &exposed.target).unwrap();
+                    copier
+                        .copy(system, &exposed.source, &exposed.target)
+                        .unwrap();
                 }
             }
         }
 
-        Ok(&self.target_graph)
+        &self.target_graph
+    }
+
+    /// Writes the install before it is applied, with the database as a pending database, so that an interrupted apply can be resumed.
+    /// [`PreparedBuild::save`] writes the install again once the apply has finished.
+    pub fn save_pending<S: System>(&self, system: &mut S) {
+        let state = SystemState {
+            graph: self
+                .target_graph
+                .clone()
+                .apply_execution_results(ApplyResult::default()),
+        };
+
+        self.install.write_pending_db(system, &state).unwrap();
+        self.install.write_outputs(system, &self.outputs()).unwrap();
+        self.install.write_deleted(system, &self.deleted()).unwrap();
+        self.install.write_arch(system, self.arch).unwrap();
     }
 
     pub fn save<S: System>(
@@ -79,15 +207,62 @@ impl<'d, R: Requirement> PreparedBuild<'d, R> {
         system: &mut S,
         result: ApplyResult,
     ) -> Result<SystemState<R>, ()> {
+        let outputs = self.outputs();
+        let deleted = self.deleted();
         let state = SystemState {
             graph: self.target_graph.apply_execution_results(result),
         };
 
         self.install.write_dbs(system, &state).unwrap();
+        self.install.write_outputs(system, &outputs).unwrap();
+        self.install.write_deleted(system, &deleted).unwrap();
+        self.install.write_arch(system, self.arch).unwrap();
         Ok(state)
     }
 }
 
+/// The progress of copying exposed files into the install.
+#[derive(Debug, Clone, Copy)]
+pub struct ExposeProgress<'a> {
+    /// The file that is being copied.
+    pub file: &'a Path,
+
+    /// The number of bytes of all exposed files that have been copied so far.
+    pub copied: u64,
+
+    /// The total number of bytes of all exposed files.
+    pub total: u64,
+}
+
+struct Copier<'p, F: FnMut(&ExposeProgress)> {
+    copied: u64,
+    total: u64,
+    limits: TransferLimits,
+    progress: &'p mut F,
+}
+
+impl<'p, F: FnMut(&ExposeProgress)> Copier<'p, F> {
+    fn copy<S: System>(&mut self, system: &mut S, from: &Path, to: &Path) -> Result<(), S::Error> {
+        let (copied, total, progress) = (self.copied, self.total, &mut self.progress);
+        system.copy_file_resumable(from, to, &self.limits, &mut |file_copied| {
+            progress(&ExposeProgress {
+                file: from,
+                copied: copied + file_copied,
+                total,
+            })
+        })?;
+
+        self.copied += from.metadata().map(|m| m.len()).unwrap_or(0);
+        (self.progress)(&ExposeProgress {
+            file: from,
+            copied: self.copied,
+            total,
+        });
+
+        Ok(())
+    }
+}
+
 pub fn copy<S: System, U: AsRef<Path>, V: AsRef<Path>>(
     system: &mut S,
     from: U,
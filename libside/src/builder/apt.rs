@@ -1,10 +1,20 @@
+use super::fs::{ConfigFileData, CreateDirectory, FileWithContents};
 use super::Context;
+use crate::arch::Arch;
+use crate::batch::Probe;
+use crate::bootstrap;
 pub use crate::generic_apt_package;
 use crate::graph::GraphNodeReference;
-use crate::requirements::{Requirement, Supports};
+use crate::report::{Category, Description, Fact};
+use crate::requirements::{Explanation, FailureHint, Requirement, Supports};
+use crate::retry::RetryPolicy;
 use crate::system::{NeverError, System};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fmt::Display;
+use std::io::Write;
+use std::path::{Path as StdPath, PathBuf};
+use tracing::warn;
 
 #[macro_export]
 macro_rules! generic_apt_package {
@@ -18,29 +28,238 @@ macro_rules! generic_apt_package {
                 Self(node)
             }
 
-            fn graph_node(&self) -> GraphNodeReference {
+            fn graph_node(&self) -> $crate::graph::GraphNodeReference {
                 self.0
             }
         }
-    }
+    };
+    ($vis:vis $struct:ident => { amd64: $amd64:literal, arm64: $arm64:literal }) => {
+        $vis struct $struct($crate::graph::GraphNodeReference);
+
+        impl $crate::builder::apt::AptPackage for $struct {
+            const NAME: &'static str = $amd64;
+
+            fn name(arch: $crate::arch::Arch) -> &'static str {
+                match arch {
+                    $crate::arch::Arch::Amd64 => $amd64,
+                    $crate::arch::Arch::Arm64 => $arm64,
+                }
+            }
+
+            fn create(node: $crate::graph::GraphNodeReference) -> Self {
+                Self(node)
+            }
+
+            fn graph_node(&self) -> $crate::graph::GraphNodeReference {
+                self.0
+            }
+        }
+    };
 }
 
 #[derive(Default)]
 pub struct Apt {
     update: Option<GraphNodeReference>,
     global_preconditions: Vec<GraphNodeReference>,
+    bundle: Option<PathBuf>,
 }
 
 impl Apt {
+    /// Installs all packages from a directory of `.deb` files instead of the configured repositories, for hosts without internet access.
+    /// The directory must contain the packages and all dependencies that are not installed yet.
+    /// Packages that have been installed before this is called are still installed from the repositories.
+    pub fn offline<R: Requirement>(context: &mut Context<R>, bundle: impl Into<PathBuf>) {
+        context.state::<Apt>().bundle = Some(bundle.into());
+    }
+
+    /// Makes all apt operations, including updating the package lists, depend on `node`.
+    /// Packages that have been installed before this is called do not depend on `node`.
     pub fn global_precondition<R: Requirement>(context: &mut Context<R>, node: GraphNodeReference) {
         let state = context.state::<Apt>();
         state.global_preconditions.push(node);
     }
+
+    /// Updates the package lists once per build, and returns the nodes that apt operations need to depend on.
+    pub fn update<R>(context: &mut Context<R>) -> Vec<GraphNodeReference>
+    where
+        R: Requirement + Supports<AptUpdate>,
+    {
+        if context.state::<Apt>().update.is_none() {
+            let preconditions = context.state::<Apt>().global_preconditions.clone();
+            context.state::<Apt>().update = Some(context.add_node(AptUpdate, &preconditions));
+        }
+
+        let state = context.state::<Apt>();
+        state
+            .update
+            .iter()
+            .chain(state.global_preconditions.iter())
+            .copied()
+            .collect()
+    }
+}
+
+/// Proxy settings for apt in `/etc/apt/apt.conf.d`.
+///
+/// Installing the proxy makes it a global precondition of all apt operations.
+/// Verification checks that apt reports the configured proxies, using `apt-config`.
+pub struct AptProxy {
+    settings: Vec<(String, String)>,
+}
+
+impl AptProxy {
+    pub fn new() -> AptProxy {
+        AptProxy {
+            settings: Vec::new(),
+        }
+    }
+
+    fn set(mut self, name: String, value: &str) -> Self {
+        self.settings.push((name, value.to_owned()));
+        self
+    }
+
+    /// Uses `proxy` for HTTP repositories.
+    pub fn http(self, proxy: &str) -> Self {
+        self.set(String::from("Acquire::http::Proxy"), proxy)
+    }
+
+    /// Uses `proxy` for HTTPS repositories.
+    pub fn https(self, proxy: &str) -> Self {
+        self.set(String::from("Acquire::https::Proxy"), proxy)
+    }
+
+    /// Connects to `host` without a proxy.
+    pub fn direct(self, host: &str) -> Self {
+        self.set(format!("Acquire::http::Proxy::{}", host), "DIRECT")
+            .set(format!("Acquire::https::Proxy::{}", host), "DIRECT")
+    }
+
+    fn to_vec(&self) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for (name, value) in self.settings.iter() {
+            writeln!(&mut data, "{} \"{}\";", name, value)?;
+        }
+
+        Ok(data)
+    }
+
+    pub fn install<R>(self, context: &mut Context<R>) -> GraphNodeReference
+    where
+        R: Requirement
+            + Supports<CreateDirectory>
+            + Supports<FileWithContents>
+            + Supports<AptConfigValue>,
+    {
+        let dir = context.existing("/etc/apt/apt.conf.d/");
+        let file = ConfigFileData {
+            path: dir.join("90side-proxy").full_path(),
+            contents: self.to_vec().unwrap(),
+            path_dependency: dir.node,
+            extra_dependencies: Vec::new(),
+        }
+        .create(context)
+        .graph_node()
+        .unwrap();
+
+        for (name, value) in self.settings {
+            let node = context.add_node(AptConfigValue { name, value }, &[file]);
+            Apt::global_precondition(context, node);
+        }
+
+        Apt::global_precondition(context, file);
+        file
+    }
+}
+
+impl Default for AptProxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A repository in `/etc/apt/sources.list.d`, for example a local mirror in an air-gapped network.
+///
+/// Installing the mirror makes it a global precondition of all apt operations.
+/// Verification checks that apt has picked up the repository, using `apt-get indextargets`.
+pub struct AptMirror {
+    uri: String,
+    suite: String,
+    components: Vec<String>,
+    signed_by: Option<String>,
+}
+
+impl AptMirror {
+    pub fn new(uri: &str, suite: &str) -> AptMirror {
+        AptMirror {
+            uri: uri.to_owned(),
+            suite: suite.to_owned(),
+            components: Vec::new(),
+            signed_by: None,
+        }
+    }
+
+    pub fn component(mut self, component: &str) -> Self {
+        self.components.push(component.to_owned());
+        self
+    }
+
+    /// Only accepts packages signed by the keys in `keyring`, rather than the keys that are trusted globally.
+    pub fn signed_by(mut self, keyring: &str) -> Self {
+        self.signed_by = Some(keyring.to_owned());
+        self
+    }
+
+    fn to_vec(&self) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        write!(&mut data, "deb ")?;
+        if let Some(keyring) = &self.signed_by {
+            write!(&mut data, "[signed-by={}] ", keyring)?;
+        }
+
+        write!(&mut data, "{} {}", self.uri, self.suite)?;
+        for component in self.components.iter() {
+            write!(&mut data, " {}", component)?;
+        }
+
+        writeln!(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn install<R>(self, context: &mut Context<R>, name: &str) -> GraphNodeReference
+    where
+        R: Requirement
+            + Supports<CreateDirectory>
+            + Supports<FileWithContents>
+            + Supports<AptSource>,
+    {
+        let dir = context.existing("/etc/apt/sources.list.d/");
+        let file = ConfigFileData {
+            path: dir.join(format!("{}.list", name)).full_path(),
+            contents: self.to_vec().unwrap(),
+            path_dependency: dir.node,
+            extra_dependencies: Vec::new(),
+        }
+        .create(context)
+        .graph_node()
+        .unwrap();
+
+        let node = context.add_node(AptSource { uri: self.uri }, &[file]);
+        Apt::global_precondition(context, node);
+        file
+    }
 }
 
 pub trait AptPackage {
+    /// The name of the package on amd64.
     const NAME: &'static str;
 
+    /// The name of the package on `arch`. Most packages have the same name on all architectures;
+    /// packages such as kernels and bootloaders are declared with `generic_apt_package!(Name => { amd64: "...", arm64: "..." })`.
+    fn name(_arch: Arch) -> &'static str {
+        Self::NAME
+    }
+
     fn create(node: GraphNodeReference) -> Self;
 
     fn graph_node(&self) -> GraphNodeReference;
@@ -51,31 +270,283 @@ pub trait AptPackage {
     where
         Self: Sized,
     {
-        if context.state::<Apt>().update.is_none() {
-            context.state::<Apt>().update = Some(context.add_node(AptUpdate, &[]));
-        }
-        let state = context.state::<Apt>();
-        let updated = state.update;
-        let dependencies = updated
-            .iter()
-            .chain(state.global_preconditions.iter())
-            .copied()
-            .collect::<Vec<_>>();
-        Self::create(context.add_node(AptInstall::new(Self::NAME), dependencies.iter()))
+        let name = Self::name(context.arch());
+        Self::create(add_install(context, AptInstall::new(name)))
+    }
+
+    /// Like [`AptPackage::install`], but installs exactly `version` of the package, such as `1.18.0-0ubuntu1`.
+    /// Verification fails if another version is installed, and applying the requirement upgrades or downgrades the package.
+    fn install_version<R: Requirement + Supports<AptInstall> + Supports<AptUpdate>>(
+        context: &mut Context<R>,
+        version: &str,
+    ) -> Self
+    where
+        Self: Sized,
+    {
+        let name = Self::name(context.arch());
+        Self::create(add_install(
+            context,
+            AptInstall::new(name).with_version(version),
+        ))
+    }
+}
+
+fn add_install<R: Requirement + Supports<AptInstall> + Supports<AptUpdate>>(
+    context: &mut Context<R>,
+    install: AptInstall,
+) -> GraphNodeReference {
+    if let Some(bundle) = context.state::<Apt>().bundle.clone() {
+        // Package lists cannot be updated without internet access
+        let dependencies = context.state::<Apt>().global_preconditions.clone();
+        return context.add_node(install.from_bundle(bundle), dependencies.iter());
     }
+
+    let dependencies = Apt::update(context);
+    context.add_node(install, dependencies.iter())
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct AptInstall {
     name: String,
+
+    /// A directory of `.deb` files to install from with `dpkg`, instead of the repositories.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bundle: Option<PathBuf>,
+
+    /// The exact version to install. Any version is accepted if this is `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
 }
 
 impl AptInstall {
     pub fn new(name: &str) -> AptInstall {
         AptInstall {
             name: name.to_string(),
+            bundle: None,
+            version: None,
+        }
+    }
+
+    /// Pins the package to `version`, such as `1.18.0-0ubuntu1`.
+    pub fn with_version(self, version: &str) -> AptInstall {
+        AptInstall {
+            version: Some(version.to_owned()),
+            ..self
         }
     }
+
+    /// The argument of `apt-get install`: the name of the package, followed by `=<version>` if the version is pinned.
+    fn target(&self) -> String {
+        match &self.version {
+            Some(version) => format!("{}={}", self.name, version),
+            None => self.name.clone(),
+        }
+    }
+
+    /// The arguments of `apt-get` that install the package.
+    fn install_args(&self) -> Vec<String> {
+        let mut args = vec!["install", "-y", "-q", "--no-install-recommends"]
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        if self.version.is_some() {
+            args.push(String::from("--allow-downgrades"));
+        }
+
+        args.push(self.target());
+        args
+    }
+
+    /// The version of the package that is installed, if any.
+    fn installed_version<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<Option<String>, CheckError<S>> {
+        let result = system
+            .execute_command("dpkg-query", &["-W", "-f=${Version}", &self.name])
+            .map_err(CheckError)?;
+        let version = result.stdout_as_str().trim();
+        Ok(if result.is_success() && !version.is_empty() {
+            Some(version.to_owned())
+        } else {
+            None
+        })
+    }
+
+    fn install<S: System>(&self, system: &mut S) -> Result<(), InstallError<S>> {
+        if let Some(bundle) = &self.bundle {
+            let debs = self.bundled_debs(system, bundle)?;
+            let mut args = vec!["-i"];
+            args.extend(debs.iter().map(|path| path.to_str().unwrap()));
+            let result = system
+                .execute_command("dpkg", &args)
+                .map_err(InstallError::FailedToStart)?;
+            result.successful()?;
+
+            return Ok(());
+        }
+
+        let args = self.install_args();
+        let result = system
+            .execute_command(
+                "apt-get",
+                &args.iter().map(String::as_str).collect::<Vec<_>>(),
+            )
+            .map_err(InstallError::FailedToStart)?;
+        result.successful()?;
+
+        Ok(())
+    }
+
+    pub fn from_bundle(self, bundle: PathBuf) -> AptInstall {
+        AptInstall {
+            bundle: Some(bundle),
+            ..self
+        }
+    }
+
+    /// Determines the `.deb` files in `bundle` that need to be installed, dependencies first.
+    fn bundled_debs<S: System>(
+        &self,
+        system: &mut S,
+        bundle: &StdPath,
+    ) -> Result<Vec<PathBuf>, InstallError<S>> {
+        let debs = system
+            .read_dir(bundle)
+            .map_err(|e| InstallError::UnableToReadBundle(bundle.to_owned(), e))?
+            .into_iter()
+            .filter(|name| name.ends_with(".deb"))
+            .map(|name| bundle.join(name))
+            .collect::<Vec<_>>();
+        let paths = debs
+            .iter()
+            .map(|path| path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        let args = paths
+            .iter()
+            .map(|&path| ["-f", path, "Package", "Pre-Depends", "Depends"])
+            .collect::<Vec<_>>();
+        let commands = args
+            .iter()
+            .map(|args| ("dpkg-deb", &args[..]))
+            .collect::<Vec<_>>();
+        let results = system
+            .execute_batch(&commands)
+            .map_err(InstallError::FailedToStart)?;
+
+        let mut packages = Vec::new();
+        for (path, result) in debs.into_iter().zip(results) {
+            result.successful()?;
+            let package = BundledPackage::parse(path.clone(), result.stdout_as_str())
+                .ok_or(InstallError::InvalidDeb(path))?;
+            let installed = AptInstall::new(&package.name)
+                .has_been_created(system)
+                .map_err(|CheckError(e)| InstallError::FailedToStart(e))?;
+            if !installed || package.name == self.name {
+                packages.push(package);
+            }
+        }
+
+        install_order(&packages, &self.name)
+            .ok_or_else(|| InstallError::NotInBundle(self.name.clone(), bundle.to_owned()))
+    }
+}
+
+/// A `.deb` file in a bundle, with the names of the packages it depends on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BundledPackage {
+    path: PathBuf,
+    name: String,
+    depends: Vec<Vec<String>>,
+}
+
+impl BundledPackage {
+    /// Parses the output of `dpkg-deb -f <path> Package Pre-Depends Depends`.
+    fn parse(path: PathBuf, fields: &str) -> Option<BundledPackage> {
+        let mut name = None;
+        let mut depends = Vec::new();
+        let mut current = String::new();
+        // Long fields are continued on lines that start with whitespace
+        for line in fields.lines() {
+            if line.starts_with(char::is_whitespace) {
+                current.push_str(line);
+                continue;
+            }
+
+            Self::field(&current, &mut name, &mut depends);
+            current = line.to_owned();
+        }
+
+        Self::field(&current, &mut name, &mut depends);
+        Some(BundledPackage {
+            path,
+            name: name?,
+            depends,
+        })
+    }
+
+    fn field(line: &str, name: &mut Option<String>, depends: &mut Vec<Vec<String>>) {
+        match line.split_once(':') {
+            Some(("Package", value)) => *name = Some(value.trim().to_owned()),
+            Some(("Pre-Depends" | "Depends", value)) => {
+                depends.extend(value.split(',').map(|alternatives| {
+                    alternatives
+                        .split('|')
+                        .filter_map(|dependency| {
+                            // Strip version constraints such as `(>= 1.0)` and architecture qualifiers such as `:any`
+                            let name = dependency.split_whitespace().next()?;
+                            Some(name.split(':').next().unwrap().to_owned())
+                        })
+                        .collect()
+                }))
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Orders the bundled packages that `name` needs so that every package comes after its dependencies.
+/// Dependencies that are not in the bundle are assumed to be installed already.
+/// Returns `None` if `name` is not in the bundle.
+fn install_order(packages: &[BundledPackage], name: &str) -> Option<Vec<PathBuf>> {
+    fn visit<'a>(
+        packages: &'a [BundledPackage],
+        package: &'a BundledPackage,
+        visited: &mut HashSet<&'a str>,
+        order: &mut Vec<PathBuf>,
+    ) {
+        if !visited.insert(&package.name) {
+            return;
+        }
+
+        for alternatives in package.depends.iter() {
+            // Use the first alternative that is available, like apt does
+            let dependency = alternatives
+                .iter()
+                .find_map(|name| packages.iter().find(|p| &p.name == name));
+            if let Some(dependency) = dependency {
+                visit(packages, dependency, visited, order);
+            }
+        }
+
+        order.push(package.path.clone());
+    }
+
+    let package = packages.iter().find(|p| p.name == name)?;
+    let mut order = Vec::new();
+    visit(packages, package, &mut HashSet::new(), &mut order);
+    Some(order)
+}
+
+/// Returns true if the output of `apt-cache policy` contains a version that can be installed.
+/// The output is empty for packages that apt does not know at all.
+fn has_candidate(policy: &str) -> bool {
+    policy.lines().any(|line| {
+        line.trim()
+            .strip_prefix("Candidate:")
+            .map(|candidate| candidate.trim() != "(none)")
+            .unwrap_or(false)
+    })
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -85,6 +556,15 @@ pub enum InstallError<S: System> {
 
     #[error("apt-get failed: {0} {1}")]
     Unsuccessful(String, String),
+
+    #[error("unable to read the package bundle {}: {}", .0.display(), .1)]
+    UnableToReadBundle(PathBuf, S::Error),
+
+    #[error("{} is not a valid package", .0.display())]
+    InvalidDeb(PathBuf),
+
+    #[error("package {} is not in the bundle {}", .0, .1.display())]
+    NotInBundle(String, PathBuf),
 }
 
 impl<S: System> From<(&str, &str)> for InstallError<S> {
@@ -93,38 +573,50 @@ impl<S: System> From<(&str, &str)> for InstallError<S> {
     }
 }
 
+/// Returns true if `version` is in the version table in the output of `apt-cache policy`.
+/// Versions are indented less than the sources of each version, and the installed version is marked with `***`.
+fn has_version(policy: &str, version: &str) -> bool {
+    policy
+        .lines()
+        .skip_while(|line| line.trim() != "Version table:")
+        .skip(1)
+        .filter(|line| line.len() - line.trim_start().len() <= 5)
+        .any(|line| {
+            line.trim()
+                .trim_start_matches("***")
+                .split_whitespace()
+                .next()
+                == Some(version)
+        })
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error("unable to execute apt-get: {0}")]
 pub struct CheckError<S: System>(S::CommandError);
 
 impl Requirement for AptInstall {
     type CreateError<S: System> = InstallError<S>;
-    type ModifyError<S: System> = NeverError;
+    type ModifyError<S: System> = InstallError<S>;
     type DeleteError<S: System> = InstallError<S>;
     type HasBeenCreatedError<S: System> = CheckError<S>;
 
     fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
-        let result = system
-            .execute_command(
-                "apt-get",
-                &[
-                    "install",
-                    "-y",
-                    "-q",
-                    "--no-install-recommends",
-                    self.name.as_str(),
-                ],
-            )
-            .map_err(InstallError::FailedToStart)?;
-        result.successful()?;
-
-        Ok(())
+        self.install(system)
     }
 
-    fn modify<S: crate::system::System>(
-        &self,
-        _system: &mut S,
-    ) -> Result<(), Self::ModifyError<S>> {
+    /// Upgrades or downgrades the package if another version than the pinned version is installed.
+    fn modify<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        let Some(version) = &self.version else {
+            return Ok(());
+        };
+
+        let installed = self
+            .installed_version(system)
+            .map_err(|CheckError(e)| InstallError::FailedToStart(e))?;
+        if installed.as_ref() != Some(version) {
+            self.install(system)?;
+        }
+
         Ok(())
     }
 
@@ -155,8 +647,12 @@ impl Requirement for AptInstall {
         self.name == other.name
     }
 
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
     fn supports_modifications(&self) -> bool {
-        false
+        self.version.is_some()
     }
     fn can_undo(&self) -> bool {
         true
@@ -166,15 +662,153 @@ impl Requirement for AptInstall {
     }
 
     fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
-        Ok(self.has_been_created(system).unwrap())
+        if !self.has_been_created(system).unwrap() {
+            return Ok(false);
+        }
+
+        match &self.version {
+            Some(version) => {
+                let installed = self.installed_version(system).unwrap();
+                if installed.as_ref() != Some(version) {
+                    warn!(
+                        "{} is installed in version {}, but version {} is pinned",
+                        self.name,
+                        installed.as_deref().unwrap_or("(none)"),
+                        version
+                    );
+                    return Ok(false);
+                }
+
+                Ok(true)
+            }
+            None => Ok(true),
+        }
+    }
+
+    fn verify_probes(&self) -> Vec<Probe> {
+        let mut probes = vec![Probe::command(
+            "dpkg-query",
+            &["-W", "-f=${Status}", &self.name],
+        )];
+        if self.version.is_some() {
+            probes.push(Probe::command(
+                "dpkg-query",
+                &["-W", "-f=${Version}", &self.name],
+            ));
+        }
+
+        probes
+    }
+
+    fn to_shell<S: System>(&self, system: &mut S) -> Option<String> {
+        if let Some(bundle) = &self.bundle {
+            let debs = self.bundled_debs(system, bundle).ok()?;
+            let mut args = vec!["-i"];
+            args.extend(debs.iter().map(|path| path.to_str().unwrap()));
+            return Some(bootstrap::command(
+                "DEBIAN_FRONTEND=noninteractive dpkg",
+                &args,
+            ));
+        }
+
+        let args = self.install_args();
+        Some(bootstrap::command(
+            "DEBIAN_FRONTEND=noninteractive apt-get",
+            &args.iter().map(String::as_str).collect::<Vec<_>>(),
+        ))
+    }
+
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        vec![Fact::AptPackage(self.name.clone())]
+    }
+
+    fn preflight<S: System>(&self, system: &mut S) -> Vec<String> {
+        if let Ok(true) = self.has_been_created(system) {
+            if self.version.is_none()
+                || self.installed_version(system).ok().flatten() == self.version
+            {
+                return Vec::new();
+            }
+        }
+
+        if let Some(bundle) = &self.bundle {
+            return match self.bundled_debs(system, bundle) {
+                Ok(_) => Vec::new(),
+                Err(e) => vec![e.to_string()],
+            };
+        }
+
+        match system.execute_command("apt-cache", &["policy", &self.name]) {
+            Ok(result) if result.is_success() && !has_candidate(result.stdout_as_str()) => {
+                vec![format!(
+                    "package {} is not available from the configured sources",
+                    self.name
+                )]
+            }
+            Ok(result) if result.is_success() => match &self.version {
+                Some(version) if !has_version(result.stdout_as_str(), version) => {
+                    vec![format!(
+                        "version {} of package {} is not available from the configured sources",
+                        version, self.name
+                    )]
+                }
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// apt fails immediately when another apt or dpkg process, such as unattended-upgrades, holds the dpkg lock.
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        Some(RetryPolicy::new(6, 2000))
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        if self.bundle.is_some() {
+            vec!["apt-get", "dpkg", "dpkg-deb", "dpkg-query"]
+        } else {
+            vec!["apt-get", "dpkg-query"]
+        }
+    }
+
+    fn describe(&self) -> Description {
+        match &self.version {
+            Some(version) => Description::new(
+                Category::Package,
+                &self.name,
+                format!("version {} installed with apt", version),
+            ),
+            None => Description::new(Category::Package, &self.name, "installed with apt"),
+        }
     }
 
     const NAME: &'static str = "apt_package";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A package installed with apt, optionally pinned to an exact version.",
+        create: "Runs `apt-get install` without recommended packages, or installs the `.deb` files from a bundle with `dpkg -i`. A pinned version is installed with `apt-get install <package>=<version>`, which may downgrade the package.",
+        verify: "Checks that `dpkg-query` reports the package as installed, in the pinned version if there is one.",
+        undo: "Runs `apt-get remove`.",
+    };
+    const DOCS_URL: Option<&'static str> = Some("https://manpages.debian.org/apt-get.8");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "Unable to locate package",
+            hint: "apt does not know the package. Check its name, and that the repository that provides it is configured and the package lists are up to date.",
+        },
+        FailureHint {
+            pattern: "Could not get lock",
+            hint: "Another apt or dpkg process is running, such as unattended-upgrades. Wait for it to finish.",
+        },
+        FailureHint {
+            pattern: "dpkg was interrupted",
+            hint: "A previous install was interrupted. Run `dpkg --configure -a` to finish it.",
+        },
+    ];
 }
 
 impl Display for AptInstall {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "apt({})", self.name)
+        write!(f, "apt({})", self.target())
     }
 }
 
@@ -187,6 +821,12 @@ pub struct UpdateError<S: System>(S::CommandError);
 
 impl Requirement for AptUpdate {
     const NAME: &'static str = "apt_update";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "Up-to-date package lists.",
+        create: "Runs `apt-get update`.",
+        verify: "Always passes.",
+        undo: "Nothing.",
+    };
 
     type CreateError<S: System> = UpdateError<S>;
     type ModifyError<S: System> = UpdateError<S>;
@@ -219,6 +859,10 @@ impl Requirement for AptUpdate {
         true
     }
 
+    fn identity(&self) -> String {
+        String::new()
+    }
+
     fn supports_modifications(&self) -> bool {
         true
     }
@@ -234,6 +878,18 @@ impl Requirement for AptUpdate {
     fn verify<S: System>(&self, _system: &mut S) -> Result<bool, ()> {
         Ok(true)
     }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        Some(bootstrap::command("apt-get", &["update"]))
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["apt-get"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(Category::Package, "apt", "package lists updated")
+    }
 }
 
 impl Display for AptUpdate {
@@ -242,18 +898,279 @@ impl Display for AptUpdate {
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum AptConfigError<S: System> {
+    #[error("unable to execute command: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("command failed: {0} {1}")]
+    Unsuccessful(String, String),
+
+    #[error("apt reports {actual:?}")]
+    NotEffective { actual: String },
+}
+
+impl<S: System> From<(&str, &str)> for AptConfigError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        AptConfigError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+/// Checks that apt uses the configured value for an option.
+/// This requirement does not change anything on the system; it is added by [`AptProxy::install`] to verify the configuration.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AptConfigValue {
+    name: String,
+    value: String,
+}
+
+impl AptConfigValue {
+    fn current<S: System>(&self, system: &mut S) -> Result<String, AptConfigError<S>> {
+        let result = system
+            .execute_command("apt-config", &["shell", "V", &self.name])
+            .map_err(AptConfigError::FailedToStart)?;
+        result.successful()?;
+
+        Ok(parse_config_shell(result.stdout_as_str()))
+    }
+
+    fn check<S: System>(&self, system: &mut S) -> Result<(), AptConfigError<S>> {
+        let actual = self.current(system)?;
+        if actual == self.value {
+            Ok(())
+        } else {
+            Err(AptConfigError::NotEffective { actual })
+        }
+    }
+}
+
+/// Extracts the value from the output of `apt-config shell V <name>`, which is either empty or `V='value'`.
+fn parse_config_shell(output: &str) -> String {
+    let output = output.trim();
+    output
+        .strip_prefix("V='")
+        .and_then(|value| value.strip_suffix('\''))
+        .map(|value| value.replace(r"'\''", "'"))
+        .unwrap_or_default()
+}
+
+impl Requirement for AptConfigValue {
+    type CreateError<S: System> = AptConfigError<S>;
+    type ModifyError<S: System> = AptConfigError<S>;
+    type DeleteError<S: System> = NeverError;
+    type HasBeenCreatedError<S: System> = NeverError;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.check(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.check(system)
+    }
+
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self.check(system).is_ok())
+    }
+
+    fn verify_probes(&self) -> Vec<Probe> {
+        vec![Probe::command("apt-config", &["shell", "V", &self.name])]
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["apt-config"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Package,
+            "apt",
+            format!("{} is {}", self.name, self.value),
+        )
+    }
+
+    const NAME: &'static str = "apt_config_value";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A value of an apt option, such as a proxy.",
+        create: "Checks the value with `apt-config`, and fails if it differs. Nothing on the system is changed.",
+        verify: "Checks the value again.",
+        undo: "Nothing.",
+    };
+}
+
+impl Display for AptConfigValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "apt-config({} = {})", self.name, self.value)
+    }
+}
+
+/// Checks that apt fetches package lists from a repository.
+/// This requirement does not change anything on the system; it is added by [`AptMirror::install`] to verify the configuration.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AptSource {
+    uri: String,
+}
+
+impl AptSource {
+    fn check<S: System>(&self, system: &mut S) -> Result<(), AptConfigError<S>> {
+        let result = system
+            .execute_command("apt-get", &["indextargets", "--format", "$(REPO_URI)"])
+            .map_err(AptConfigError::FailedToStart)?;
+        result.successful()?;
+
+        let uri = self.uri.trim_end_matches('/');
+        if result
+            .stdout_as_str()
+            .lines()
+            .any(|line| line.trim().trim_end_matches('/') == uri)
+        {
+            Ok(())
+        } else {
+            Err(AptConfigError::NotEffective {
+                actual: String::from("no package lists for the repository"),
+            })
+        }
+    }
+}
+
+impl Requirement for AptSource {
+    type CreateError<S: System> = AptConfigError<S>;
+    type ModifyError<S: System> = AptConfigError<S>;
+    type DeleteError<S: System> = NeverError;
+    type HasBeenCreatedError<S: System> = NeverError;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.check(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.check(system)
+    }
+
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.uri == other.uri
+    }
+
+    fn identity(&self) -> String {
+        self.uri.clone()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self.check(system).is_ok())
+    }
+
+    fn verify_probes(&self) -> Vec<Probe> {
+        vec![Probe::command(
+            "apt-get",
+            &["indextargets", "--format", "$(REPO_URI)"],
+        )]
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["apt-get"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(Category::Package, &self.uri, "used as apt repository")
+    }
+
+    const NAME: &'static str = "apt_source";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A repository that apt fetches package lists from.",
+        create: "Checks that `apt-get indextargets` lists the repository, and fails otherwise. Nothing on the system is changed.",
+        verify: "Checks the repository again.",
+        undo: "Nothing.",
+    };
+}
+
+impl Display for AptSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "apt-source({})", self.uri)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        builder::apt::{AptInstall, AptUpdate},
+        arch::Arch,
+        builder::apt::{
+            has_candidate, has_version, install_order, parse_config_shell, AptConfigValue,
+            AptInstall, AptMirror, AptPackage, AptProxy, AptSource, AptUpdate, BundledPackage,
+        },
         requirements::Requirement,
         testing::LxcInstance,
     };
+    use std::path::PathBuf;
+
+    generic_apt_package!(Nginx => "nginx");
+    generic_apt_package!(LinuxImage => { amd64: "linux-image-amd64", arm64: "linux-image-arm64" });
+
+    #[test]
+    pub fn apt_package_names_per_arch() {
+        assert_eq!(Nginx::name(Arch::Arm64), "nginx");
+        assert_eq!(LinuxImage::name(Arch::Amd64), "linux-image-amd64");
+        assert_eq!(LinuxImage::name(Arch::Arm64), "linux-image-arm64");
+    }
 
     #[test]
     pub fn serialize_deserialize_apt_install() {
         let r = AptInstall {
             name: "test".to_string(),
+            bundle: None,
+            version: None,
         };
         let json = r#"{"name":"test"}"#;
 
@@ -261,6 +1178,83 @@ mod tests {
         assert_eq!(r, serde_json::from_str(json).unwrap());
     }
 
+    #[test]
+    pub fn serialize_deserialize_apt_install_from_bundle() {
+        let r = AptInstall::new("nginx").from_bundle(PathBuf::from("/srv/debs"));
+        let json = r#"{"name":"nginx","bundle":"/srv/debs"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn serialize_deserialize_pinned_apt_install() {
+        let r = AptInstall::new("nginx").with_version("1.18.0-0ubuntu1");
+        let json = r#"{"name":"nginx","version":"1.18.0-0ubuntu1"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+        assert_eq!(r.to_string(), "apt(nginx=1.18.0-0ubuntu1)");
+        assert_eq!(
+            r.install_args(),
+            vec![
+                "install",
+                "-y",
+                "-q",
+                "--no-install-recommends",
+                "--allow-downgrades",
+                "nginx=1.18.0-0ubuntu1"
+            ]
+        );
+        assert!(r.affects(&AptInstall::new("nginx")));
+    }
+
+    #[test]
+    pub fn parse_bundled_package() {
+        let fields = "Package: nginx\nPre-Depends: dpkg (>= 1.17)\nDepends: libc6:any (>= 2.34), nginx-common (= 1.22),\n libssl3 | libssl1.1\n";
+        let package = BundledPackage::parse(PathBuf::from("/srv/debs/nginx.deb"), fields).unwrap();
+
+        assert_eq!(package.name, "nginx");
+        assert_eq!(
+            package.depends,
+            vec![
+                vec!["dpkg".to_string()],
+                vec!["libc6".to_string()],
+                vec!["nginx-common".to_string()],
+                vec!["libssl3".to_string(), "libssl1.1".to_string()],
+            ]
+        );
+        assert!(BundledPackage::parse(PathBuf::from("/srv/debs/x.deb"), "").is_none());
+    }
+
+    #[test]
+    pub fn bundled_packages_are_installed_after_dependencies() {
+        let package = |name: &str, depends: &[&[&str]]| BundledPackage {
+            path: PathBuf::from(format!("/srv/debs/{}.deb", name)),
+            name: name.to_string(),
+            depends: depends
+                .iter()
+                .map(|alternatives| alternatives.iter().map(|s| s.to_string()).collect())
+                .collect(),
+        };
+        let packages = [
+            package("nginx", &[&["libc6"], &["nginx-common"], &["libssl3"]]),
+            package("nginx-common", &[&["lsb-base", "sysvinit-utils"]]),
+            package("sysvinit-utils", &[&["nginx"]]),
+            package("unrelated", &[]),
+        ];
+
+        assert_eq!(
+            install_order(&packages, "nginx").unwrap(),
+            vec![
+                PathBuf::from("/srv/debs/sysvinit-utils.deb"),
+                PathBuf::from("/srv/debs/nginx-common.deb"),
+                PathBuf::from("/srv/debs/nginx.deb"),
+            ]
+        );
+        assert!(install_order(&packages, "php").is_none());
+    }
+
     #[test]
     pub fn serialize_deserialize_apt_update() {
         let r = AptUpdate;
@@ -270,12 +1264,93 @@ mod tests {
         assert_eq!(r, serde_json::from_str(json).unwrap());
     }
 
+    #[test]
+    pub fn serialize_deserialize_apt_config_value() {
+        let r = AptConfigValue {
+            name: "Acquire::http::Proxy".to_string(),
+            value: "http://proxy:3128".to_string(),
+        };
+        let json = r#"{"name":"Acquire::http::Proxy","value":"http://proxy:3128"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn serialize_deserialize_apt_source() {
+        let r = AptSource {
+            uri: "http://mirror.local/debian".to_string(),
+        };
+        let json = r#"{"uri":"http://mirror.local/debian"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn apt_proxy_and_mirror_files() {
+        let proxy = AptProxy::new()
+            .http("http://proxy:3128")
+            .direct("mirror.local");
+        let mirror = AptMirror::new("http://mirror.local/debian", "bookworm")
+            .component("main")
+            .component("contrib")
+            .signed_by("/usr/share/keyrings/local.gpg");
+
+        assert_eq!(
+            String::from_utf8(proxy.to_vec().unwrap()).unwrap(),
+            "Acquire::http::Proxy \"http://proxy:3128\";\nAcquire::http::Proxy::mirror.local \"DIRECT\";\nAcquire::https::Proxy::mirror.local \"DIRECT\";\n"
+        );
+        assert_eq!(
+            String::from_utf8(mirror.to_vec().unwrap()).unwrap(),
+            "deb [signed-by=/usr/share/keyrings/local.gpg] http://mirror.local/debian bookworm main contrib\n"
+        );
+    }
+
+    #[test]
+    pub fn parse_apt_config_shell() {
+        assert_eq!(
+            parse_config_shell("V='http://proxy:3128'\n"),
+            "http://proxy:3128"
+        );
+        assert_eq!(parse_config_shell(""), "");
+    }
+
+    #[test]
+    pub fn parse_apt_cache_policy() {
+        let available = "nginx:\n  Installed: (none)\n  Candidate: 1.22.1-9\n  Version table:\n     1.22.1-9 500\n";
+        let virtual_package =
+            "mail-transport-agent:\n  Installed: (none)\n  Candidate: (none)\n  Version table:\n";
+
+        assert!(has_candidate(available));
+        assert!(!has_candidate(virtual_package));
+        assert!(!has_candidate(""));
+
+        let installed = "nginx:\n  Installed: 1.22.1-9\n  Candidate: 1.22.1-9+deb12u1\n  Version table:\n     1.22.1-9+deb12u1 500\n        500 http://deb.debian.org/debian bookworm-updates/main amd64 Packages\n *** 1.22.1-9 500\n        100 /var/lib/dpkg/status\n";
+        assert!(has_version(installed, "1.22.1-9"));
+        assert!(has_version(installed, "1.22.1-9+deb12u1"));
+        assert!(!has_version(installed, "1.18.0"));
+        assert!(!has_version(installed, "500"));
+    }
+
+    #[test]
+    #[ignore]
+    pub fn lxc_apt_install_preflight() {
+        let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
+        AptUpdate.create(&mut sys).unwrap();
+
+        assert!(AptInstall::new("nginx").preflight(&mut sys).is_empty());
+        assert_eq!(AptInstall::new("ngnix").preflight(&mut sys).len(), 1);
+    }
+
     #[test]
     #[ignore]
     pub fn lxc_apt_install() {
         let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
         let p = AptInstall {
             name: "nginx".to_string(),
+            bundle: None,
+            version: None,
         };
 
         assert!(!p.has_been_created(&mut sys).unwrap());
@@ -292,6 +1367,25 @@ mod tests {
         assert!(!p.verify(&mut sys).unwrap());
     }
 
+    #[test]
+    #[ignore]
+    pub fn lxc_apt_install_pinned_version() {
+        let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
+        AptUpdate.create(&mut sys).unwrap();
+        let latest = AptInstall::new("nginx");
+        latest.create(&mut sys).unwrap();
+        let version = latest.installed_version(&mut sys).unwrap().unwrap();
+
+        let pinned = AptInstall::new("nginx").with_version(&version);
+        assert!(pinned.verify(&mut sys).unwrap());
+
+        let other = AptInstall::new("nginx").with_version("0.0.1");
+        assert!(!other.verify(&mut sys).unwrap());
+        assert_eq!(other.preflight(&mut sys).len(), 1);
+        assert!(other.modify(&mut sys).is_err());
+        assert!(pinned.modify(&mut sys).is_ok());
+    }
+
     #[test]
     #[ignore]
     pub fn lxc_apt_update() {
@@ -0,0 +1,381 @@
+//! One-off steps that run a command, such as `composer install` or the migrations of a database.
+//!
+//! A [`RunCommand`] declares the effects of its command, so that it can be used without defining a new [`Requirement`]:
+//! the paths that the command creates and removes, a check command that succeeds once the command has run,
+//! and a command that undoes it. The declared paths are checked after the command has run.
+//! Without a check command, the command is run again on every apply in which it changed or has not been created, so it should be idempotent.
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use crate::bootstrap;
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, Requirement, Resource, Supports};
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+
+use super::Context;
+
+/// Runs a command with declared effects, see the [module documentation](self).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RunCommand {
+    name: String,
+    command: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    check: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    undo: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    creates: Vec<PathBuf>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    removes: Vec<PathBuf>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RunCommandError<S: System> {
+    #[error("unable to execute {command}: {inner}")]
+    FailedToStart {
+        command: String,
+        inner: S::CommandError,
+    },
+
+    #[error("{command} failed: {stderr}")]
+    Unsuccessful { command: String, stderr: String },
+
+    #[error("{command} did not create {path}")]
+    NotCreated { command: String, path: PathBuf },
+
+    #[error("{command} did not remove {path}")]
+    NotRemoved { command: String, path: PathBuf },
+
+    #[error("unable to check whether {path} exists: {inner}")]
+    UnableToCheck { path: PathBuf, inner: S::Error },
+}
+
+fn to_owned(command: &[&str]) -> Vec<String> {
+    assert!(!command.is_empty(), "a command needs a program");
+    command.iter().map(|&arg| arg.to_owned()).collect()
+}
+
+impl RunCommand {
+    /// Runs `command`, of which the first element is the program. `name` identifies the step across builds, and must be unique.
+    pub fn new(name: &str, command: &[&str]) -> RunCommand {
+        RunCommand {
+            name: name.to_owned(),
+            command: to_owned(command),
+            check: None,
+            undo: None,
+            creates: Vec::new(),
+            removes: Vec::new(),
+        }
+    }
+
+    /// Only runs the command if `command` fails. It should succeed once the command has run.
+    pub fn check(mut self, command: &[&str]) -> Self {
+        self.check = Some(to_owned(command));
+        self
+    }
+
+    /// Runs `command` when the step is undone. Without an undo command, the effects of the command are kept.
+    pub fn undo(mut self, command: &[&str]) -> Self {
+        self.undo = Some(to_owned(command));
+        self
+    }
+
+    /// Declares that the command creates `path`. The path must exist after the command has run, and no other requirement may hold it.
+    pub fn creates(mut self, path: PathBuf) -> Self {
+        self.creates.push(path);
+        self
+    }
+
+    /// Declares that the command removes `path`. The path must not exist after the command has run.
+    pub fn removes(mut self, path: PathBuf) -> Self {
+        self.removes.push(path);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Adds the step to the graph, after `dependencies`.
+    pub fn add<R: Requirement + Supports<RunCommand>>(
+        self,
+        context: &mut Context<R>,
+        dependencies: &[GraphNodeReference],
+    ) -> GraphNodeReference {
+        // A duplicate name fails the build after the current package
+        let _ = context.claim_unique("command", &self.name);
+        context.add_node(self, dependencies)
+    }
+
+    fn execute<S: System>(
+        system: &mut S,
+        command: &[String],
+    ) -> Result<crate::system::CommandResult, RunCommandError<S>> {
+        let args = command[1..].iter().map(String::as_str).collect::<Vec<_>>();
+        system
+            .execute_command(&command[0], &args)
+            .map_err(|inner| RunCommandError::FailedToStart {
+                command: command.join(" "),
+                inner,
+            })
+    }
+
+    fn run<S: System>(system: &mut S, command: &[String]) -> Result<(), RunCommandError<S>> {
+        let result = Self::execute(system, command)?;
+        if result.is_success() {
+            Ok(())
+        } else {
+            Err(RunCommandError::Unsuccessful {
+                command: command.join(" "),
+                stderr: result.stderr_as_str().trim().to_owned(),
+            })
+        }
+    }
+
+    /// Returns the first declared effect that is not present on the system.
+    fn missing_effect<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<Option<RunCommandError<S>>, RunCommandError<S>> {
+        let exists = |system: &mut S, path: &PathBuf| {
+            system
+                .path_exists(path)
+                .map_err(|inner| RunCommandError::UnableToCheck {
+                    path: path.clone(),
+                    inner,
+                })
+        };
+
+        let command = self.command.join(" ");
+        for path in self.creates.iter() {
+            if !exists(system, path)? {
+                return Ok(Some(RunCommandError::NotCreated {
+                    command,
+                    path: path.clone(),
+                }));
+            }
+        }
+
+        for path in self.removes.iter() {
+            if exists(system, path)? {
+                return Ok(Some(RunCommandError::NotRemoved {
+                    command,
+                    path: path.clone(),
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Whether the check command succeeds, or `None` if there is no check command.
+    fn check_passes<S: System>(&self, system: &mut S) -> Result<Option<bool>, RunCommandError<S>> {
+        match &self.check {
+            Some(check) => Ok(Some(Self::execute(system, check)?.is_success())),
+            None => Ok(None),
+        }
+    }
+
+    fn run_and_check_effects<S: System>(&self, system: &mut S) -> Result<(), RunCommandError<S>> {
+        Self::run(system, &self.command)?;
+        match self.missing_effect(system)? {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Requirement for RunCommand {
+    type CreateError<S: System> = RunCommandError<S>;
+    type ModifyError<S: System> = RunCommandError<S>;
+    type DeleteError<S: System> = RunCommandError<S>;
+    type HasBeenCreatedError<S: System> = RunCommandError<S>;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.run_and_check_effects(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        if self.check_passes(system)? == Some(true) {
+            return Ok(());
+        }
+
+        self.run_and_check_effects(system)
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        match &self.undo {
+            Some(undo) => Self::run(system, undo),
+            None => Ok(()),
+        }
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        if let Some(passes) = self.check_passes(system)? {
+            Ok(passes)
+        } else if self.creates.is_empty() && self.removes.is_empty() {
+            Ok(false)
+        } else {
+            Ok(self.missing_effect(system)?.is_none())
+        }
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        self.undo.is_some()
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self.diff(system).is_none())
+    }
+
+    fn diff<S: System>(&self, system: &mut S) -> Option<String> {
+        match self.check_passes(system) {
+            Ok(Some(false)) => {
+                return Some(format!("{} failed", self.check.as_ref().unwrap().join(" ")))
+            }
+            Err(e) => return Some(e.to_string()),
+            Ok(_) => {}
+        }
+
+        match self.missing_effect(system) {
+            Ok(missing) => missing.map(|e| e.to_string()),
+            Err(e) => Some(e.to_string()),
+        }
+    }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let line = |command: &[String]| {
+            let args = command[1..].iter().map(String::as_str).collect::<Vec<_>>();
+            bootstrap::command(&command[0], &args)
+        };
+
+        Some(match &self.check {
+            Some(check) => format!(
+                "if ! {}; then\n    {}fi\n",
+                line(check).trim_end(),
+                line(&self.command)
+            ),
+            None => line(&self.command),
+        })
+    }
+
+    fn exclusive_resources(&self) -> Vec<Resource> {
+        self.creates
+            .iter()
+            .map(|path| Resource::Path(path.clone()))
+            .collect()
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(Category::Other, &self.name, self.command.join(" "))
+    }
+
+    const NAME: &'static str = "run_command";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A command that is run once, with the paths it creates and removes declared.",
+        create: "Runs the command unless its check command succeeds, and fails if a declared path was not created or removed.",
+        verify: "Runs the check command, and checks that the declared paths exist or do not exist.",
+        undo: "Runs the undo command, or nothing if there is none.",
+    };
+}
+
+impl Display for RunCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "command({})", self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RunCommand;
+    use crate::requirements::Requirement;
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+
+    #[test]
+    pub fn serialize_deserialize_run_command() {
+        let r = RunCommand::new("migrate", &["php", "artisan", "migrate"]);
+        let json = r#"{"name":"migrate","command":["php","artisan","migrate"]}"#;
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+
+        let r = RunCommand::new("vendor", &["composer", "install"])
+            .check(&["test", "-d", "vendor"])
+            .undo(&["rm", "-r", "vendor"])
+            .creates("/srv/app/vendor".into())
+            .removes("/srv/app/composer.phar".into());
+        let json = r#"{"name":"vendor","command":["composer","install"],"check":["test","-d","vendor"],"undo":["rm","-r","vendor"],"creates":["/srv/app/vendor"],"removes":["/srv/app/composer.phar"]}"#;
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn run_command_with_effects() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("command");
+        let marker = dir.join("marker");
+        let marker_str = marker.to_str().unwrap();
+
+        let r = RunCommand::new("touch", &["touch", marker_str])
+            .check(&["test", "-e", marker_str])
+            .undo(&["rm", marker_str])
+            .creates(marker.clone());
+        assert!(!r.has_been_created(&mut system).unwrap());
+        assert!(!r.verify(&mut system).unwrap());
+
+        r.create(&mut system).unwrap();
+        assert!(r.has_been_created(&mut system).unwrap());
+        assert!(r.verify(&mut system).unwrap());
+
+        // The check passes, so the command is not run again
+        let r = RunCommand::new("touch", &["false"])
+            .check(&["test", "-e", marker_str])
+            .undo(&["rm", marker_str]);
+        r.modify(&mut system).unwrap();
+
+        r.delete(&mut system).unwrap();
+        assert!(!system.path_exists(&marker).unwrap());
+
+        let err = RunCommand::new("nothing", &["true"])
+            .creates(marker.clone())
+            .create(&mut system)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("true did not create {}", marker.display())
+        );
+
+        let err = RunCommand::new("fail", &["sh", "-c", "echo broken >&2; exit 1"])
+            .create(&mut system)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "sh -c echo broken >&2; exit 1 failed: broken"
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! Secrets that are delivered to systemd services as credentials, with `LoadCredential=` or `LoadCredentialEncrypted=`.
+//!
+//! The credential files are written to the credentials directory of the base directory, which only root can read.
+//! systemd copies them into `$CREDENTIALS_DIRECTORY` when the service starts, where only the service can read them.
+//! Unlike config files that are bind-mounted into a chroot, the secret is never part of the config tree.
+//!
+//! An encrypted credential only keeps its credential file until it has been encrypted: [`EncryptCredential`] removes the file afterwards,
+//! and the build only writes it again when the encrypted file is missing or contains an older version of the secret.
+use crate::bootstrap;
+use crate::builder::fs::Sha3;
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, Requirement, Supports};
+use crate::secrets::SecretId;
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use super::{AsParam, Context};
+
+/// A secret that is delivered to a systemd service as a credential, created with [`Context::credential`].
+#[derive(Clone, Debug)]
+pub struct SystemdCredential {
+    name: String,
+    path: PathBuf,
+    sha3: Sha3,
+    encrypted: Option<GraphNodeReference>,
+}
+
+impl SystemdCredential {
+    pub(crate) fn new(name: &str, path: PathBuf, data: &[u8]) -> SystemdCredential {
+        SystemdCredential {
+            name: name.to_owned(),
+            path,
+            sha3: Sha3::hash(data),
+            encrypted: None,
+        }
+    }
+
+    /// The name of the credential, which is also the name of its file in `$CREDENTIALS_DIRECTORY`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Encrypts the credential with `systemd-creds`, so that it can only be decrypted on this system.
+    /// The service then loads it with `LoadCredentialEncrypted=` instead of `LoadCredential=`.
+    /// The credential file is removed once it has been encrypted.
+    pub fn encrypt<R: Requirement + Supports<EncryptCredential>>(
+        self,
+        context: &mut Context<R>,
+    ) -> SystemdCredential {
+        let target = self.path.with_file_name(format!("{}.cred", self.name));
+        context.secrets.encrypt_credential(
+            SecretId::new(context.package_name.clone(), self.name.clone()),
+            target.clone(),
+        );
+        let node = context.add_node(
+            EncryptCredential {
+                name: self.name.clone(),
+                source: self.path,
+                target: target.clone(),
+                sha3: self.sha3,
+            },
+            &[],
+        );
+
+        SystemdCredential {
+            name: self.name,
+            path: target,
+            sha3: self.sha3,
+            encrypted: Some(node),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted.is_some()
+    }
+
+    /// The node that encrypts the credential, if it is encrypted.
+    pub fn graph_node(&self) -> Option<GraphNodeReference> {
+        self.encrypted
+    }
+
+    /// The path of the credential in unit files, such as in `ExecStart=`. systemd replaces `%d` with the credentials directory of the service.
+    pub fn unit_path(&self) -> String {
+        format!("%d/{}", self.name)
+    }
+
+    /// The path of the credential in scripts that the service runs.
+    pub fn env_path(&self) -> String {
+        format!("${{CREDENTIALS_DIRECTORY}}/{}", self.name)
+    }
+}
+
+impl AsParam for SystemdCredential {
+    /// The value of `LoadCredential=` or `LoadCredentialEncrypted=`.
+    fn as_param(&self) -> String {
+        format!("{}:{}", self.name, self.path.display())
+    }
+}
+
+/// A credential file that is encrypted with `systemd-creds encrypt`.
+/// The encrypted file is bound to the host key, or the TPM2 chip if there is one, so a copy of it is useless on another system.
+///
+/// The hash of the secret is part of the requirement, so that a changed secret is encrypted again.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EncryptCredential {
+    name: String,
+    source: PathBuf,
+    target: PathBuf,
+    sha3: Sha3,
+}
+
+impl EncryptCredential {
+    fn encrypt<S: System>(&self, system: &mut S) -> Result<(), CredentialError<S>> {
+        let result = system
+            .execute_command(
+                "systemd-creds",
+                &[
+                    "encrypt",
+                    &format!("--name={}", self.name),
+                    path_str::<S>(&self.source)?,
+                    path_str::<S>(&self.target)?,
+                ],
+            )
+            .map_err(CredentialError::FailedToStart)?;
+        result.successful()?;
+
+        system
+            .remove_file(&self.source)
+            .map_err(CredentialError::UnableToRemovePlaintext)
+    }
+}
+
+/// Decrypts the encrypted credential `name` at `path`, or returns `None` if it cannot be decrypted.
+pub(crate) fn decrypt<S: System>(system: &S, name: &str, path: &Path) -> Option<Vec<u8>> {
+    let result = system
+        .execute_command(
+            "systemd-creds",
+            &["decrypt", &format!("--name={}", name), path.to_str()?, "-"],
+        )
+        .ok()?;
+
+    result.is_success().then(|| result.stdout().to_vec())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CredentialError<S: System> {
+    #[error("unable to remove encrypted credential: {0}")]
+    UnableToRemove(S::Error),
+
+    #[error("unable to remove the credential file after encrypting it: {0}")]
+    UnableToRemovePlaintext(S::Error),
+
+    #[error("unable to execute systemd-creds: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("systemd-creds failed: {0} {1}")]
+    Unsuccessful(String, String),
+
+    #[error("{} is not valid UTF-8", .0.display())]
+    NonUtf8Path(PathBuf),
+}
+
+fn path_str<S: System>(path: &Path) -> Result<&str, CredentialError<S>> {
+    path.to_str()
+        .ok_or_else(|| CredentialError::NonUtf8Path(path.to_owned()))
+}
+
+impl<S: System> From<(&str, &str)> for CredentialError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        CredentialError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+impl Requirement for EncryptCredential {
+    const NAME: &'static str = "encrypt_credential";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A credential for a systemd service, encrypted so that it can only be decrypted on this system.",
+        create: "Encrypts the credential file from the credentials directory with `systemd-creds encrypt`, and removes the credential file.",
+        verify: "Decrypts the encrypted file with `systemd-creds decrypt`, and compares it with the hash of the secret.",
+        undo: "Removes the encrypted file. The secret itself is kept with the other secrets.",
+    };
+    const DOCS_URL: Option<&'static str> =
+        Some("https://www.freedesktop.org/software/systemd/man/systemd-creds.html");
+
+    type CreateError<S: System> = CredentialError<S>;
+    type ModifyError<S: System> = CredentialError<S>;
+    type DeleteError<S: System> = CredentialError<S>;
+    type HasBeenCreatedError<S: System> = S::Error;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.encrypt(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.encrypt(system)
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        system
+            .remove_file(&self.target)
+            .map_err(CredentialError::UnableToRemove)
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        system.path_exists(&self.target)
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.target == other.target
+    }
+
+    fn identity(&self) -> String {
+        self.target.display().to_string()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        true
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(decrypt(system, &self.name, &self.target)
+            .map(|data| Sha3::hash(&data) == self.sha3)
+            .unwrap_or(false))
+    }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let mut script = bootstrap::command(
+            "systemd-creds",
+            &[
+                "encrypt",
+                &format!("--name={}", self.name),
+                self.source.to_str()?,
+                self.target.to_str()?,
+            ],
+        );
+        script.push_str(&bootstrap::command("rm", &[self.source.to_str()?]));
+        Some(script)
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["systemd-creds"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::File,
+            self.target.display().to_string(),
+            format!("encrypted credential {}", self.name),
+        )
+    }
+}
+
+impl Display for EncryptCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "encrypt-credential({})", self.target.display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EncryptCredential, SystemdCredential};
+    use crate::builder::fs::Sha3;
+    use crate::builder::AsParam;
+    use crate::config::systemd::Exec;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn serialize_deserialize_encrypt_credential() {
+        let r = EncryptCredential {
+            name: "db-password".to_string(),
+            source: PathBuf::from("/srv/credentials/app/db-password"),
+            target: PathBuf::from("/srv/credentials/app/db-password.cred"),
+            sha3: Sha3::hash(b"hunter2"),
+        };
+        let json = serde_json::to_string(&r).unwrap();
+        assert!(json.starts_with(r#"{"name":"db-password","source":"/srv/credentials/app/db-password","target":"/srv/credentials/app/db-password.cred","sha3":["#));
+        assert_eq!(r, serde_json::from_str(&json).unwrap());
+
+        // A rotated secret changes the requirement, so that it is encrypted again
+        let rotated = EncryptCredential {
+            sha3: Sha3::hash(b"correct horse"),
+            ..r.clone()
+        };
+        assert_ne!(r, rotated);
+    }
+
+    #[test]
+    pub fn load_credential() {
+        let credential = SystemdCredential::new(
+            "db-password",
+            PathBuf::from("/srv/credentials/app/db-password"),
+            b"hunter2",
+        );
+        assert_eq!(
+            credential.as_param(),
+            "db-password:/srv/credentials/app/db-password"
+        );
+        assert_eq!(credential.unit_path(), "%d/db-password");
+
+        let exec = Exec::new().credential(&credential);
+        assert_eq!(
+            exec.to_string(),
+            "LoadCredential=db-password:/srv/credentials/app/db-password\n"
+        );
+    }
+}
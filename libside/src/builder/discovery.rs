@@ -0,0 +1,212 @@
+//! Service discovery for services that run on the same server.
+//!
+//! Packages register the endpoints of their services with [`Discovery::register`], which writes an environment file for each endpoint.
+//! Consumers load the file with `EnvironmentFile=` and depend on the registration, so they never hardcode socket paths or ports.
+//! In the finish phase, [`Discovery::install`] writes all endpoints into a single JSON file for tools that need the full picture.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use super::fs::{ConfigFileData, CreateDirectory, FileWithContents};
+use super::path::{Path, WillBeCreated};
+use super::Context;
+use crate::graph::GraphNodeReference;
+use crate::requirements::{Requirement, Supports};
+
+/// Where a service can be reached.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Address {
+    Port { host: String, port: u16 },
+    Socket(PathBuf),
+}
+
+/// A service endpoint, such as the FastCGI socket of a php-fpm pool or the HTTP port of an API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Endpoint {
+    /// The package that registered the endpoint.
+    pub package: String,
+
+    /// The protocol that is spoken on the endpoint, for example `http` or `fastcgi`.
+    pub protocol: String,
+
+    pub address: Address,
+}
+
+impl Endpoint {
+    /// The variables of the environment file for the endpoint `name`, prefixed with `SERVICE_<NAME>_`.
+    pub fn environment(&self, name: &str) -> String {
+        let prefix = format!("SERVICE_{}_", name.to_uppercase().replace('-', "_"));
+        let mut result = format!("{}PROTOCOL={}\n", prefix, self.protocol);
+        match &self.address {
+            Address::Port { host, port } => {
+                result.push_str(&format!("{}HOST={}\n", prefix, host));
+                result.push_str(&format!("{}PORT={}\n", prefix, port));
+            }
+            Address::Socket(path) => {
+                result.push_str(&format!("{}SOCKET={}\n", prefix, path.display()));
+            }
+        }
+
+        result
+    }
+}
+
+/// A registered endpoint.
+pub struct RegisteredEndpoint {
+    env_file: Path<WillBeCreated>,
+}
+
+impl RegisteredEndpoint {
+    /// The environment file with the address of the endpoint, for `EnvironmentFile=`.
+    pub fn env_file(&self) -> &Path<WillBeCreated> {
+        &self.env_file
+    }
+
+    pub fn graph_node(&self) -> GraphNodeReference {
+        self.env_file.graph_node().unwrap()
+    }
+}
+
+/// The endpoints that have been registered by all packages so far.
+#[derive(Default)]
+pub struct Discovery {
+    dir: Option<Path<WillBeCreated>>,
+    endpoints: BTreeMap<String, Endpoint>,
+    nodes: Vec<GraphNodeReference>,
+}
+
+impl Discovery {
+    /// The directory that contains the discovery files.
+    pub const DIR: &'static str = "side-discovery";
+
+    fn dir<R>(context: &mut Context<R>) -> Path<WillBeCreated>
+    where
+        R: Requirement + Supports<CreateDirectory>,
+    {
+        match context.state::<Discovery>().dir.clone() {
+            Some(dir) => dir,
+            None => {
+                let dir = context.existing("/etc/").make_dir(context, Self::DIR);
+                context.state::<Discovery>().dir = Some(dir.clone());
+                dir
+            }
+        }
+    }
+
+    /// Registers the endpoint `name` of the current package, which is available once `dependencies` have been applied.
+    /// Names are global, so two packages cannot register the same name.
+    pub fn register<R>(
+        context: &mut Context<R>,
+        name: &str,
+        protocol: &str,
+        address: Address,
+        dependencies: &[GraphNodeReference],
+    ) -> RegisteredEndpoint
+    where
+        R: Requirement + Supports<CreateDirectory> + Supports<FileWithContents>,
+    {
+        assert!(
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "invalid endpoint name {:?}",
+            name
+        );
+
+        let endpoint = Endpoint {
+            package: context.package_name.clone(),
+            protocol: protocol.to_owned(),
+            address,
+        };
+        if let Some(existing) = context.state::<Discovery>().endpoints.get(name) {
+            panic!(
+                "The endpoint {} is registered by both {} and {}",
+                name, existing.package, endpoint.package
+            );
+        }
+
+        let dir = Self::dir(context);
+        let env_file = ConfigFileData {
+            path: dir.join(format!("{}.env", name)).full_path(),
+            contents: endpoint.environment(name).into_bytes(),
+            path_dependency: dir.graph_node(),
+            extra_dependencies: dependencies.to_vec(),
+        }
+        .create(context);
+
+        let discovery = context.state::<Discovery>();
+        discovery.endpoints.insert(name.to_owned(), endpoint);
+        discovery.nodes.extend(env_file.graph_node());
+
+        RegisteredEndpoint { env_file }
+    }
+
+    /// Writes all registered endpoints to `services.json`, keyed by name. Call this in the finish phase, after all packages have been built.
+    pub fn install<R>(context: &mut Context<R>) -> Path<WillBeCreated>
+    where
+        R: Requirement + Supports<CreateDirectory> + Supports<FileWithContents>,
+    {
+        let dir = Self::dir(context);
+        let discovery = context.state::<Discovery>();
+        let contents = serde_json::to_vec_pretty(&discovery.endpoints).unwrap();
+        let dependencies = discovery.nodes.clone();
+
+        ConfigFileData {
+            path: dir.join("services.json").full_path(),
+            contents,
+            path_dependency: dir.graph_node(),
+            extra_dependencies: dependencies,
+        }
+        .create(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Address, Endpoint};
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn serialize_deserialize_endpoint() {
+        let e = Endpoint {
+            package: String::from("api"),
+            protocol: String::from("http"),
+            address: Address::Port {
+                host: String::from("127.0.0.1"),
+                port: 8080,
+            },
+        };
+        let json = r#"{"package":"api","protocol":"http","address":{"port":{"host":"127.0.0.1","port":8080}}}"#;
+
+        assert_eq!(serde_json::to_string(&e).unwrap(), json);
+        assert_eq!(e, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn endpoint_environment() {
+        let socket = Endpoint {
+            package: String::from("www"),
+            protocol: String::from("fastcgi"),
+            address: Address::Socket(PathBuf::from("/run/www-fpm/fpm.sock")),
+        };
+        let port = Endpoint {
+            package: String::from("api"),
+            protocol: String::from("http"),
+            address: Address::Port {
+                host: String::from("127.0.0.1"),
+                port: 8080,
+            },
+        };
+
+        assert_eq!(
+            socket.environment("www-fpm"),
+            "SERVICE_WWW_FPM_PROTOCOL=fastcgi\nSERVICE_WWW_FPM_SOCKET=/run/www-fpm/fpm.sock\n"
+        );
+        assert_eq!(
+            port.environment("api"),
+            "SERVICE_API_PROTOCOL=http\nSERVICE_API_HOST=127.0.0.1\nSERVICE_API_PORT=8080\n"
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use super::fs::Sha3;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, Requirement};
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+/// The number of files that are hashed with a single `sha3sum` command.
+const BATCH_SIZE: usize = 256;
+
+/// Records the checksums of exposed files in the graph, so that verification detects changes to deployed application code.
+/// The files are copied into the install before the apply; the requirement itself only checks that the copies are intact.
+/// See [`super::Context::expose_verified`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExposedFiles {
+    /// The exposed file or directory in the install.
+    path: PathBuf,
+
+    /// The checksums of the exposed files, relative to `path`. A single exposed file is stored with an empty path.
+    files: BTreeMap<PathBuf, Sha3>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("exposed files in {} do not match the package: {}", .0.display(), .1.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "))]
+pub struct ExposedFilesError(PathBuf, Vec<PathBuf>);
+
+impl ExposedFiles {
+    pub fn new(path: PathBuf, files: BTreeMap<PathBuf, Sha3>) -> ExposedFiles {
+        ExposedFiles { path, files }
+    }
+
+    /// Hashes the files below `source`, or `source` itself if `files` is empty, for an exposure at `path`.
+    pub fn hash(path: PathBuf, source: &Path, files: &[PathBuf]) -> std::io::Result<ExposedFiles> {
+        let mut hashes = BTreeMap::new();
+        if files.is_empty() {
+            hashes.insert(
+                PathBuf::new(),
+                Sha3::hash_reader(std::fs::File::open(source)?)?,
+            );
+        } else {
+            for file in files.iter().filter(|file| !file.is_dir()) {
+                let relative = file.strip_prefix(source).unwrap().to_path_buf();
+                hashes.insert(relative, Sha3::hash_reader(std::fs::File::open(file)?)?);
+            }
+        }
+
+        Ok(ExposedFiles::new(path, hashes))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn full_path(&self, relative: &Path) -> PathBuf {
+        if relative.as_os_str().is_empty() {
+            self.path.clone()
+        } else {
+            self.path.join(relative)
+        }
+    }
+
+    /// Hashes many files with a single `sha3sum` command on the system, so that large trees do not need a command per file.
+    /// Files that could not be hashed this way are missing from the result.
+    fn hash_in_batches<S: System>(system: &S, paths: &[PathBuf]) -> HashMap<PathBuf, Sha3> {
+        let mut hashes = HashMap::new();
+        for batch in paths.chunks(BATCH_SIZE) {
+            let mut args = vec!["-a", "256"];
+            args.extend(batch.iter().filter_map(|path| path.to_str()));
+
+            // sha3sum exits with an error if a file is missing, but still prints the hashes of the other files.
+            if let Ok(result) = system.execute_command("sha3sum", &args) {
+                for line in result.stdout_as_str().lines() {
+                    if let Some((hash, path)) = line.split_once("  ") {
+                        if let Some(hash) = Sha3::from_hex(hash) {
+                            hashes.insert(PathBuf::from(path), hash);
+                        }
+                    }
+                }
+            }
+        }
+
+        hashes
+    }
+
+    /// Returns the exposed files that are missing or whose contents differ from the package, relative to `path`.
+    pub fn changed_files<S: System>(&self, system: &S) -> Vec<&Path> {
+        let paths = self
+            .files
+            .keys()
+            .map(|relative| self.full_path(relative))
+            .collect::<Vec<_>>();
+        let hashes = Self::hash_in_batches(system, &paths);
+
+        self.files
+            .iter()
+            .zip(paths.iter())
+            .filter(|((_, expected), path)| {
+                let actual = hashes
+                    .get(*path)
+                    .copied()
+                    .or_else(|| system.file_sha3(path).ok());
+                actual != Some(**expected)
+            })
+            .map(|((relative, _), _)| relative.as_path())
+            .collect()
+    }
+}
+
+impl Requirement for ExposedFiles {
+    const NAME: &'static str = "exposed_files";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "The checksums of the files that a package exposes, such as the code of an application.",
+        create: "Nothing: the files are copied into the install before the apply. The checksums are recorded in the graph.",
+        verify: "Hashes the copies in the install with `sha3sum`, and fails if any file has changed.",
+        undo: "Nothing: the files are removed along with the install.",
+    };
+
+    type CreateError<S: System> = ExposedFilesError;
+    type ModifyError<S: System> = ExposedFilesError;
+    type DeleteError<S: System> = ExposedFilesError;
+    type HasBeenCreatedError<S: System> = ExposedFilesError;
+
+    /// Checks that the files were copied into the install intact.
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        let changed = self.changed_files(system);
+        if changed.is_empty() {
+            Ok(())
+        } else {
+            Err(ExposedFilesError(
+                self.path.clone(),
+                changed.into_iter().map(Path::to_path_buf).collect(),
+            ))
+        }
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.create(system)
+    }
+
+    /// The files are removed along with the install.
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(self.changed_files(system).is_empty())
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+
+    fn identity(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        true
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self.changed_files(system).is_empty())
+    }
+
+    fn diff<S: System>(&self, system: &mut S) -> Option<String> {
+        let changed = self.changed_files(system);
+        Some(if changed.is_empty() {
+            format!("all {} files are identical", self.files.len())
+        } else {
+            format!(
+                "{} of {} files are missing or changed: {}",
+                changed.len(),
+                self.files.len(),
+                changed
+                    .iter()
+                    .map(|path| self.full_path(path).display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::File,
+            self.path.display().to_string(),
+            format!("{} exposed files with recorded checksums", self.files.len()),
+        )
+    }
+}
+
+impl Display for ExposedFiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "exposed_files({}, {} files)",
+            self.path.display(),
+            self.files.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExposedFiles;
+    use crate::builder::fs::Sha3;
+    use crate::requirements::Requirement;
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    pub fn serialize_deserialize_exposed_files() {
+        let r = ExposedFiles::new(
+            PathBuf::from("/srv/installed/3/files/exposed/www/public"),
+            BTreeMap::from([(PathBuf::from("index.html"), Sha3::default())]),
+        );
+        let json = r#"{"path":"/srv/installed/3/files/exposed/www/public","files":{"index.html":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn detect_changed_exposed_files() {
+        let dir = TempDir::new("exposed");
+        std::fs::create_dir_all(dir.join("public/css")).unwrap();
+        std::fs::write(dir.join("public/index.html"), "<h1>hello</h1>").unwrap();
+        std::fs::write(dir.join("public/css/site.css"), "h1 { color: red }").unwrap();
+        let files = vec![
+            dir.join("public/css"),
+            dir.join("public/css/site.css"),
+            dir.join("public/index.html"),
+        ];
+        let exposed = ExposedFiles::hash(dir.join("public"), &dir.join("public"), &files).unwrap();
+        let single = ExposedFiles::hash(
+            dir.join("public/index.html"),
+            &dir.join("public/index.html"),
+            &[],
+        )
+        .unwrap();
+
+        let mut system = LocalSystem::new();
+        let unchanged = exposed.verify(&mut system).unwrap() && single.verify(&mut system).unwrap();
+
+        std::fs::write(dir.join("public/index.html"), "<h1>tampered</h1>").unwrap();
+        std::fs::remove_file(dir.join("public/css/site.css")).unwrap();
+        let changed = exposed
+            .changed_files(&system)
+            .into_iter()
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        let single_ok = single.verify(&mut system).unwrap();
+
+        assert!(unchanged);
+        assert!(!single_ok);
+        assert_eq!(
+            changed,
+            vec![PathBuf::from("css/site.css"), PathBuf::from("index.html")]
+        );
+    }
+}
@@ -0,0 +1,657 @@
+use crate::bootstrap;
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, FailureHint, Requirement, Resource, Supports};
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use super::Context;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NftFamily {
+    Inet,
+    Ip,
+    Ip6,
+}
+
+impl Display for NftFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NftFamily::Inet => "inet",
+            NftFamily::Ip => "ip",
+            NftFamily::Ip6 => "ip6",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NftHook {
+    Input,
+    Forward,
+    Output,
+}
+
+impl Display for NftHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NftHook::Input => "input",
+            NftHook::Forward => "forward",
+            NftHook::Output => "output",
+        })
+    }
+}
+
+/// What happens to packets that reach the end of a base chain without matching a rule.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NftPolicy {
+    Accept,
+    Drop,
+}
+
+impl Display for NftPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NftPolicy::Accept => "accept",
+            NftPolicy::Drop => "drop",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        })
+    }
+}
+
+/// A single rule in nftables syntax, such as `tcp dport 22 accept`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NftRule(String);
+
+impl NftRule {
+    pub fn new(rule: &str) -> NftRule {
+        assert!(
+            !rule.trim().is_empty() && !rule.contains('\n'),
+            "invalid nftables rule {:?}",
+            rule
+        );
+
+        NftRule(rule.trim().to_owned())
+    }
+
+    /// Accepts new connections to `port`.
+    pub fn allow_port(protocol: Protocol, port: u16) -> NftRule {
+        NftRule(format!("{} dport {} accept", protocol, port))
+    }
+
+    /// Accepts packets that belong to connections that have already been accepted.
+    pub fn allow_established() -> NftRule {
+        NftRule(String::from("ct state established,related accept"))
+    }
+
+    pub fn allow_loopback() -> NftRule {
+        NftRule(String::from("iifname \"lo\" accept"))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct BaseChain {
+    hook: NftHook,
+    priority: i32,
+    policy: NftPolicy,
+}
+
+/// A chain in an [`NftTable`].
+/// Base chains are attached to a hook and see the packets that pass through it, other chains are only reached by jumping to them.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NftChain {
+    name: String,
+    base: Option<BaseChain>,
+    rules: Vec<NftRule>,
+}
+
+impl NftChain {
+    pub fn new(name: &str) -> NftChain {
+        check_name("chain", name);
+        NftChain {
+            name: name.to_owned(),
+            base: None,
+            rules: Vec::new(),
+        }
+    }
+
+    /// A filter chain on `hook` with priority 0.
+    pub fn base(name: &str, hook: NftHook, policy: NftPolicy) -> NftChain {
+        NftChain {
+            base: Some(BaseChain {
+                hook,
+                priority: 0,
+                policy,
+            }),
+            ..NftChain::new(name)
+        }
+    }
+
+    /// An `input` chain that drops everything except loopback traffic, established connections and the ports opened with [`NftChain::allow_port`].
+    pub fn deny_all_input() -> NftChain {
+        NftChain::base("input", NftHook::Input, NftPolicy::Drop)
+            .rule(NftRule::allow_loopback())
+            .rule(NftRule::allow_established())
+    }
+
+    /// Sets the priority of a base chain. Chains with a lower priority see packets first.
+    pub fn priority(mut self, priority: i32) -> NftChain {
+        self.base
+            .as_mut()
+            .expect("only base chains have a priority")
+            .priority = priority;
+        self
+    }
+
+    pub fn rule(mut self, rule: NftRule) -> NftChain {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn allow_port(self, protocol: Protocol, port: u16) -> NftChain {
+        self.rule(NftRule::allow_port(protocol, port))
+    }
+
+    fn render(&self, out: &mut String) {
+        out.push_str(&format!("\tchain {} {{\n", self.name));
+        if let Some(base) = &self.base {
+            out.push_str(&format!(
+                "\t\ttype filter hook {} priority {}; policy {};\n",
+                base.hook, base.priority, base.policy
+            ));
+        }
+
+        for rule in self.rules.iter() {
+            out.push_str(&format!("\t\t{}\n", rule.0));
+        }
+
+        out.push_str("\t}\n");
+    }
+}
+
+/// An nftables table, which is written to a rules file in `/etc/nftables.d` and loaded with `nft -f`.
+/// The rules file replaces the entire table atomically, so that the table never contains a mix of old and new rules.
+///
+/// The table does not survive a reboot unless `/etc/nftables.conf` includes the rules files: `include "/etc/nftables.d/*.nft"`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NftTable {
+    family: NftFamily,
+    name: String,
+    chains: Vec<NftChain>,
+}
+
+impl NftTable {
+    pub const RULES_DIR: &'static str = "/etc/nftables.d";
+
+    pub fn new(family: NftFamily, name: &str) -> NftTable {
+        check_name("table", name);
+        NftTable {
+            family,
+            name: name.to_owned(),
+            chains: Vec::new(),
+        }
+    }
+
+    pub fn chain(mut self, chain: NftChain) -> NftTable {
+        assert!(
+            self.chains.iter().all(|other| other.name != chain.name),
+            "duplicate nftables chain {:?}",
+            chain.name
+        );
+
+        self.chains.push(chain);
+        self
+    }
+
+    pub fn install<R: Requirement + Supports<NftTable>>(
+        self,
+        context: &mut Context<R>,
+        dependencies: &[GraphNodeReference],
+    ) -> GraphNodeReference {
+        context.add_node(self, dependencies)
+    }
+
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(Self::RULES_DIR).join(format!("{}-{}.nft", self.family, self.name))
+    }
+
+    /// The contents of the rules file.
+    pub fn render(&self) -> String {
+        let mut out = String::from("#!/usr/sbin/nft -f\n");
+        // Declaring the table first makes deleting it succeed even if it does not exist yet
+        out.push_str(&format!("table {} {}\n", self.family, self.name));
+        out.push_str(&format!("delete table {} {}\n\n", self.family, self.name));
+        out.push_str(&format!("table {} {} {{\n", self.family, self.name));
+        for chain in self.chains.iter() {
+            chain.render(&mut out);
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    /// The chains that `nft list table` should show, with their policy and number of rules.
+    fn expected_chains(&self) -> Vec<LiveChain> {
+        self.chains
+            .iter()
+            .map(|chain| LiveChain {
+                name: chain.name.clone(),
+                policy: chain.base.as_ref().map(|base| base.policy.to_string()),
+                rules: chain.rules.len(),
+            })
+            .collect()
+    }
+
+    fn load<S: System>(&self, system: &mut S) -> Result<(), FirewallError<S>> {
+        let path = self.path();
+        system
+            .make_dir_all(Path::new(Self::RULES_DIR))
+            .map_err(FirewallError::UnableToWrite)?;
+        system
+            .put_file_contents(&path, self.render().as_bytes())
+            .map_err(FirewallError::UnableToWrite)?;
+
+        let result = system
+            .execute_command("nft", &["-f", path.to_str().unwrap()])
+            .map_err(FirewallError::FailedToStart)?;
+        result.successful()?;
+
+        Ok(())
+    }
+
+    /// Returns the chains in the live ruleset, or `None` if the table does not exist.
+    fn live_chains<S: System>(&self, system: &mut S) -> Option<Vec<LiveChain>> {
+        let family = self.family.to_string();
+        let result = system
+            .execute_command("nft", &["list", "table", &family, &self.name])
+            .ok()?;
+        if result.is_success() {
+            Some(parse_listing(result.stdout_as_str()))
+        } else {
+            None
+        }
+    }
+}
+
+/// The parts of a chain in the output of `nft list table` that are compared during verification.
+/// `nft` normalizes rules when it lists them, so only the number of rules is compared and not their text.
+#[derive(Debug, PartialEq, Eq)]
+struct LiveChain {
+    name: String,
+    policy: Option<String>,
+    rules: usize,
+}
+
+fn parse_listing(listing: &str) -> Vec<LiveChain> {
+    let mut chains = Vec::new();
+    let mut current: Option<LiveChain> = None;
+    let mut depth = 0;
+    for line in listing.lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+
+        if line == "}" {
+            depth -= 1;
+            if depth == 1 {
+                chains.extend(current.take());
+            }
+        } else if line.ends_with('{') {
+            depth += 1;
+            if depth == 2 {
+                current = line
+                    .strip_prefix("chain ")
+                    .and_then(|rest| rest.strip_suffix('{'))
+                    .map(|name| LiveChain {
+                        name: name.trim().to_owned(),
+                        policy: None,
+                        rules: 0,
+                    });
+            }
+        } else if let (2, Some(chain)) = (depth, current.as_mut()) {
+            if line.starts_with("type ") {
+                chain.policy = line
+                    .split(';')
+                    .filter_map(|part| part.trim().strip_prefix("policy "))
+                    .map(str::to_owned)
+                    .next();
+            } else if !line.starts_with("comment ") {
+                chain.rules += 1;
+            }
+        }
+    }
+
+    chains
+}
+
+fn check_name(kind: &str, name: &str) {
+    assert!(
+        name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        "invalid nftables {} name {:?}",
+        kind,
+        name
+    );
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FirewallError<S: System> {
+    #[error("unable to write rules file: {0}")]
+    UnableToWrite(S::Error),
+
+    #[error("unable to remove rules file: {0}")]
+    UnableToRemove(S::Error),
+
+    #[error("unable to execute nft: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("nft failed: {0} {1}")]
+    Unsuccessful(String, String),
+}
+
+impl<S: System> From<(&str, &str)> for FirewallError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        FirewallError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+impl Requirement for NftTable {
+    type CreateError<S: System> = FirewallError<S>;
+    type ModifyError<S: System> = FirewallError<S>;
+    type DeleteError<S: System> = FirewallError<S>;
+    type HasBeenCreatedError<S: System> = S::Error;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.load(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.load(system)
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        system
+            .remove_file(&self.path())
+            .map_err(FirewallError::UnableToRemove)?;
+
+        if self.live_chains(system).is_some() {
+            let family = self.family.to_string();
+            let result = system
+                .execute_command("nft", &["delete", "table", &family, &self.name])
+                .map_err(FirewallError::FailedToStart)?;
+            result.successful()?;
+        }
+
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        system.path_exists(&self.path())
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.family == other.family && self.name == other.name
+    }
+
+    fn identity(&self) -> String {
+        format!("{} {}", self.family, self.name)
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        true
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        let file_matches = match system.file_contents(&self.path()) {
+            Ok(contents) => contents == self.render().as_bytes(),
+            Err(_) => false,
+        };
+
+        Ok(file_matches && self.live_chains(system) == Some(self.expected_chains()))
+    }
+
+    fn preflight<S: System>(&self, system: &mut S) -> Vec<String> {
+        match system.execute_command_with_input("nft", &["-c", "-f", "-"], self.render().as_bytes())
+        {
+            Ok(result) if result.is_success() => Vec::new(),
+            Ok(result) => vec![format!(
+                "nftables table {} {} is invalid: {}",
+                self.family,
+                self.name,
+                result.stderr_as_str().trim()
+            )],
+            Err(e) => vec![format!("unable to execute nft: {}", e)],
+        }
+    }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let path = self.path();
+        let path = path.to_str()?;
+        let mut script = bootstrap::command("mkdir", &["-p", Self::RULES_DIR]);
+        script.push_str(&bootstrap::write_file(path, self.render().as_bytes()));
+        script.push_str(&bootstrap::command("nft", &["-f", path]));
+        Some(script)
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["nft"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Firewall,
+            format!("{} {}", self.family, self.name),
+            format!(
+                "{} chains, {} rules",
+                self.chains.len(),
+                self.chains
+                    .iter()
+                    .map(|chain| chain.rules.len())
+                    .sum::<usize>()
+            ),
+        )
+    }
+
+    fn exclusive_resources(&self) -> Vec<Resource> {
+        vec![Resource::Path(self.path())]
+    }
+
+    const NAME: &'static str = "nft_table";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "An nftables table, in a rules file in `/etc/nftables.d`.",
+        create: "Writes the rules file and loads it with `nft -f`. The file replaces the entire table atomically.",
+        verify: "Compares the rules file, and checks the chains, policies and number of rules reported by `nft list table`.",
+        undo: "Removes the rules file and deletes the table.",
+    };
+    const DOCS_URL: Option<&'static str> = Some("https://manpages.debian.org/nft.8");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "Operation not permitted",
+            hint: "Loading rules needs root and `CAP_NET_ADMIN`. Unprivileged containers cannot load nftables rules.",
+        },
+        FailureHint {
+            pattern: "Could not process rule",
+            hint: "nft rejected a rule. Check the syntax of the rules, and whether the kernel supports the expressions they use.",
+        },
+    ];
+}
+
+impl Display for NftTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "nft-table({} {})", self.family, self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_listing, LiveChain, NftChain, NftFamily, NftHook, NftPolicy, NftRule, NftTable,
+        Protocol,
+    };
+    use crate::{requirements::Requirement, system::System, testing::LxcInstance};
+
+    fn web_server() -> NftTable {
+        NftTable::new(NftFamily::Inet, "libside").chain(
+            NftChain::deny_all_input()
+                .allow_port(Protocol::Tcp, 22)
+                .allow_port(Protocol::Tcp, 443),
+        )
+    }
+
+    #[test]
+    pub fn serialize_deserialize_nft_table() {
+        let t = NftTable::new(NftFamily::Inet, "web").chain(
+            NftChain::base("input", NftHook::Input, NftPolicy::Accept)
+                .rule(NftRule::new("tcp dport 80 drop")),
+        );
+        let json = r#"{"family":"inet","name":"web","chains":[{"name":"input","base":{"hook":"input","priority":0,"policy":"accept"},"rules":["tcp dport 80 drop"]}]}"#;
+
+        assert_eq!(serde_json::to_string(&t).unwrap(), json);
+        assert_eq!(t, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn render_nft_table() {
+        let t = web_server().chain(
+            NftChain::base("output", NftHook::Output, NftPolicy::Accept)
+                .priority(-10)
+                .rule(NftRule::new("ip daddr 192.0.2.1 drop")),
+        );
+
+        assert_eq!(
+            t.path().to_str().unwrap(),
+            "/etc/nftables.d/inet-libside.nft"
+        );
+        assert_eq!(
+            t.render(),
+            "#!/usr/sbin/nft -f
+table inet libside
+delete table inet libside
+
+table inet libside {
+\tchain input {
+\t\ttype filter hook input priority 0; policy drop;
+\t\tiifname \"lo\" accept
+\t\tct state established,related accept
+\t\ttcp dport 22 accept
+\t\ttcp dport 443 accept
+\t}
+\tchain output {
+\t\ttype filter hook output priority -10; policy accept;
+\t\tip daddr 192.0.2.1 drop
+\t}
+}
+"
+        );
+    }
+
+    #[test]
+    pub fn parse_live_ruleset() {
+        let listing = "table inet libside {
+\tchain input {
+\t\ttype filter hook input priority filter; policy drop;
+\t\tiifname \"lo\" accept
+\t\tct state established,related accept
+\t\ttcp dport 22 accept
+\t\ttcp dport 443 accept
+\t}
+
+\tchain log {
+\t\tcomment \"added by hand\"
+\t\tlog prefix \"dropped: \"
+\t}
+}
+";
+
+        assert_eq!(
+            parse_listing(listing),
+            vec![
+                LiveChain {
+                    name: String::from("input"),
+                    policy: Some(String::from("drop")),
+                    rules: 4,
+                },
+                LiveChain {
+                    name: String::from("log"),
+                    policy: None,
+                    rules: 1,
+                },
+            ]
+        );
+        assert_ne!(parse_listing(listing), web_server().expected_chains());
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn nft_table_name_is_checked() {
+        NftTable::new(NftFamily::Inet, "libside; flush ruleset");
+    }
+
+    #[test]
+    #[ignore]
+    pub fn lxc_nft_table() {
+        let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
+        sys.execute_command("apt-get", &["install", "-y", "nftables"])
+            .unwrap()
+            .successful()
+            .unwrap();
+        let t = web_server();
+
+        assert!(!t.has_been_created(&mut sys).unwrap());
+        assert!(!t.verify(&mut sys).unwrap());
+        assert!(t.preflight(&mut sys).is_empty());
+
+        t.create(&mut sys).unwrap();
+
+        assert!(t.has_been_created(&mut sys).unwrap());
+        assert!(t.verify(&mut sys).unwrap());
+
+        sys.execute_command("nft", &["flush", "chain", "inet", "libside", "input"])
+            .unwrap()
+            .successful()
+            .unwrap();
+        assert!(!t.verify(&mut sys).unwrap());
+
+        t.modify(&mut sys).unwrap();
+        assert!(t.verify(&mut sys).unwrap());
+
+        t.delete(&mut sys).unwrap();
+
+        assert!(!t.has_been_created(&mut sys).unwrap());
+        assert!(!t.verify(&mut sys).unwrap());
+        assert!(!sys
+            .execute_command("nft", &["list", "table", "inet", "libside"])
+            .unwrap()
+            .is_success());
+    }
+}
@@ -1,6 +1,14 @@
+use crate::batch::Probe;
+use crate::bootstrap;
 use crate::builder::GeneratedFile;
+use crate::conffile::{self, ConffilePolicy, PackageChange, ThreeWay};
+use crate::conflict::Resolution;
 use crate::graph::GraphNodeReference;
-use crate::requirements::{Requirement, Supports};
+use crate::report::{self, Category, Description, Fact};
+use crate::requirements::{
+    BackupError, Explanation, FailureHint, Requirement, Resource, Supports, VerificationFailure,
+};
+use crate::review::unified_diff;
 use crate::system::{NeverError, System};
 use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_256};
@@ -10,6 +18,9 @@ use std::{fmt::Display, path::PathBuf};
 use super::path::{CanWritePath, Path, WillBeCreated};
 use super::Context;
 
+/// Files larger than this are only compared by their checksum when showing the changes of a plan.
+const MAX_DIFF_SIZE: u64 = 256 * 1024;
+
 pub struct ConfigFileData {
     pub path: PathBuf,
     pub contents: Vec<u8>,
@@ -66,23 +77,46 @@ impl ConfigFileData {
         }
     }
 
-    pub fn create<R: Requirement + Supports<FileWithContents>>(
+    pub fn create<R>(self, context: &mut Context<R>) -> Path<WillBeCreated>
+    where
+        R: Requirement + Supports<CreateDirectory> + Supports<FileWithContents>,
+    {
+        self.create_with_conffile_policy(context, ConffilePolicy::KeepOurs)
+    }
+
+    /// Like `create`, for a file that is also a conffile of a distribution package.
+    /// `policy` decides what happens when a package upgrade ships a new version of the file.
+    pub fn create_with_conffile_policy<R>(
         self,
         context: &mut Context<R>,
-    ) -> Path<WillBeCreated> {
+        policy: ConffilePolicy,
+    ) -> Path<WillBeCreated>
+    where
+        R: Requirement + Supports<CreateDirectory> + Supports<FileWithContents>,
+    {
         let path = self.path().to_path_buf();
         assert!(path.is_absolute());
         let source = context.generated_path.join(path.strip_prefix("/").unwrap());
 
+        // The file always depends on its directory, even if the path dependency is something else
+        let directory = path.parent().and_then(|parent| context.directory(parent));
         let depends_on = self
             .path_dependency()
             .iter()
+            .chain(
+                directory
+                    .iter()
+                    .filter(|&&node| self.path_dependency() != Some(node)),
+            )
             .chain(self.extra_dependencies())
             .copied()
             .collect::<Vec<_>>();
         let contents = self.contents();
+        let backup = context.original_file_path(FileWithContents::NAME, &path);
         let node = context.add_node(
-            FileWithContents::new(source.clone(), path.clone(), Sha3::hash(&contents)),
+            FileWithContents::new(source.clone(), path.clone(), Sha3::hash(&contents))
+                .with_backup(backup)
+                .with_conffile_policy(policy),
             &depends_on,
         );
 
@@ -107,6 +141,36 @@ impl Sha3 {
 
         Sha3(hasher.finalize().into())
     }
+
+    /// Hashes everything that can be read from `reader`, without reading it into memory all at once.
+    pub fn hash_reader<T: std::io::Read>(mut reader: T) -> std::io::Result<Sha3> {
+        let mut hasher = Sha3_256::new();
+        let mut buf = [0u8; 65536];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            hasher.update(&buf[..n]);
+        }
+
+        Ok(Sha3(hasher.finalize().into()))
+    }
+
+    /// Parses a hash from 64 hexadecimal characters, as printed by `sha3sum -a 256`.
+    pub fn from_hex(s: &str) -> Option<Sha3> {
+        if s.len() != 64 || !s.is_ascii() {
+            return None;
+        }
+
+        let mut result = [0u8; 32];
+        for (b, hex) in result.iter_mut().zip(s.as_bytes().chunks(2)) {
+            *b = u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?;
+        }
+
+        Some(Sha3(result))
+    }
 }
 
 impl Display for Sha3 {
@@ -124,6 +188,14 @@ pub struct FileWithContents {
     local_file: PathBuf,
     to: PathBuf,
     sha3: Sha3,
+
+    /// Where a pre-existing file is saved before it is overwritten, so that it can be restored when the requirement is undone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    backup: Option<PathBuf>,
+
+    /// What to do when a package upgrade ships a new version of the file.
+    #[serde(default, skip_serializing_if = "ConffilePolicy::is_keep_ours")]
+    conffile: ConffilePolicy,
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -134,16 +206,51 @@ pub struct FileCreateError<S: System> {
     inner: S::Error,
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum FileModifyError<S: System> {
+    #[error("{}", .0)]
+    Copy(FileCreateError<S>),
+
+    #[error("Unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("Unable to execute command: {}", .0)]
+    FailedToStart(S::CommandError),
+
+    #[error("Unable to merge the changes of package {} into {}: the changes conflict", .0, .1.display())]
+    MergeConflict(String, PathBuf),
+}
+
 #[derive(Debug, Clone, thiserror::Error)]
-#[error("Unable to delete file {}: {}", path.display(), inner)]
-pub struct FileDeleteError<S: System> {
-    path: PathBuf,
-    inner: S::Error,
+pub enum FileDeleteError<S: System> {
+    #[error("Unable to delete file {}: {}", .0.display(), .1)]
+    UnableToDelete(PathBuf, S::Error),
+
+    #[error("Unable to restore {}: its backup {} does not exist", .0.display(), .1.display())]
+    BackupMissing(PathBuf, PathBuf),
+}
+
+/// Returns a problem if the parent directory of `path` exists but cannot be written to, for example because it is on a read-only filesystem.
+fn check_parent_writable<S: System>(system: &mut S, path: &StdPath) -> Option<String> {
+    let parent = path.parent()?;
+    if !system.path_is_dir(parent).unwrap_or(false) {
+        return None;
+    }
+
+    let writable = system
+        .execute_command("test", &["-w", parent.to_str()?])
+        .map(|result| result.is_success())
+        .unwrap_or(true);
+    if writable {
+        None
+    } else {
+        Some(format!("{} is not writable", parent.display()))
+    }
 }
 
 impl Requirement for FileWithContents {
     type CreateError<S: System> = FileCreateError<S>;
-    type ModifyError<S: System> = FileCreateError<S>;
+    type ModifyError<S: System> = FileModifyError<S>;
     type DeleteError<S: System> = FileDeleteError<S>;
     type HasBeenCreatedError<S: System> = S::Error;
 
@@ -157,23 +264,54 @@ impl Requirement for FileWithContents {
             })
     }
 
+    /// Overwrites the file, unless a package upgrade changed it and the conffile policy says otherwise.
     fn modify<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        if let Some(change) = self.package_change(system) {
+            match self.conffile {
+                ConffilePolicy::KeepOurs => {}
+                ConffilePolicy::TakeTheirs => return self.take_theirs(system, &change),
+                ConffilePolicy::Merge => return self.merge(system, &change),
+            }
+        }
+
         system
             .copy_file(&self.local_file, &self.to)
-            .map_err(|inner| FileCreateError {
-                from: self.local_file.clone(),
-                to: self.to.clone(),
-                inner,
+            .map_err(|inner| {
+                FileModifyError::Copy(FileCreateError {
+                    from: self.local_file.clone(),
+                    to: self.to.clone(),
+                    inner,
+                })
             })
     }
 
     fn delete<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
         system
             .remove_file(&self.to)
-            .map_err(|inner| FileDeleteError {
-                path: self.to.clone(),
-                inner,
-            })
+            .map_err(|inner| FileDeleteError::UnableToDelete(self.to.clone(), inner))
+    }
+
+    /// Restores the original file from the backup that was made before it was overwritten.
+    /// Fails if the backup is missing, instead of leaving the file with our contents.
+    fn pre_existing_delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        let backup = match &self.backup {
+            Some(backup) => backup,
+            None => return Ok(()),
+        };
+        let exists = system
+            .path_exists(backup)
+            .map_err(|inner| FileDeleteError::UnableToDelete(backup.clone(), inner))?;
+        if !exists {
+            return Err(FileDeleteError::BackupMissing(
+                self.to.clone(),
+                backup.clone(),
+            ));
+        }
+
+        system
+            .copy_file(backup, &self.to)
+            .and_then(|_| system.remove_file(backup))
+            .map_err(|inner| FileDeleteError::UnableToDelete(self.to.clone(), inner))
     }
 
     fn has_been_created<S: crate::system::System>(
@@ -183,10 +321,41 @@ impl Requirement for FileWithContents {
         system.path_exists(&self.to)
     }
 
+    /// Files that already have the right contents are adopted without asking.
+    fn resolve_existing<S: System>(&self, system: &mut S) -> Option<Resolution> {
+        match system.file_sha3(&self.to) {
+            Ok(sha3) if sha3 == self.sha3 => Some(Resolution::Adopt),
+            _ => None,
+        }
+    }
+
+    fn backup_existing<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        match &self.backup {
+            Some(backup) => system.copy_file(&self.to, backup).map_err(|inner| {
+                FileModifyError::Copy(FileCreateError {
+                    from: self.to.clone(),
+                    to: backup.clone(),
+                    inner,
+                })
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn saved_original(&self) -> Option<(&StdPath, &StdPath)> {
+        self.backup
+            .as_deref()
+            .map(|backup| (self.to.as_path(), backup))
+    }
+
     fn affects(&self, other: &Self) -> bool {
         self.to == other.to
     }
 
+    fn identity(&self) -> String {
+        self.to.display().to_string()
+    }
+
     fn supports_modifications(&self) -> bool {
         true
     }
@@ -197,16 +366,154 @@ impl Requirement for FileWithContents {
         false
     }
 
+    /// A file that does not match our version is still valid if the conffile policy accepts the package's version, or the merge of both.
     fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
-        Ok(if self.has_been_created(system).unwrap() {
-            let contents = system.file_contents(&self.to).unwrap();
-            Sha3::hash(&contents) == self.sha3
+        if !self.has_been_created(system).unwrap() {
+            return Ok(false);
+        }
+
+        let sha3 = system.file_sha3(&self.to).unwrap();
+        if sha3 == self.sha3 || self.conffile.is_keep_ours() {
+            return Ok(sha3 == self.sha3);
+        }
+
+        Ok(match (self.conffile, self.package_change(system)) {
+            (ConffilePolicy::TakeTheirs, Some(change)) => change.replaced(&self.to),
+            (ConffilePolicy::Merge, Some(change)) if !change.replaced(&self.to) => {
+                match conffile::merge(
+                    system,
+                    &self.local_file,
+                    self.existing_backup(system),
+                    &change.theirs,
+                ) {
+                    Ok(Some(merged)) => Sha3::hash(&merged) == sha3,
+                    _ => false,
+                }
+            }
+            _ => false,
+        })
+    }
+
+    fn verify_probes(&self) -> Vec<Probe> {
+        vec![Probe::PathExists(self.to.clone())]
+    }
+
+    fn diff<S: System>(&self, system: &mut S) -> Option<String> {
+        if system.path_is_dir(&self.to).ok()? {
+            return Some(String::from("a directory exists at this path"));
+        }
+
+        let size = system.file_size(&self.to).ok()?;
+        let sha3 = system.file_sha3(&self.to).ok()?;
+        Some(if sha3 == self.sha3 {
+            format!("{} bytes with identical contents", size)
         } else {
-            false
+            format!(
+                "{} bytes with checksum {}, expected {}",
+                size, sha3, self.sha3
+            )
         })
     }
 
+    fn verification_failure<S: System>(&self, system: &mut S) -> VerificationFailure {
+        match system.path_exists(&self.to) {
+            Ok(false) => VerificationFailure::Missing,
+            _ => match (system.path_is_dir(&self.to), system.file_sha3(&self.to)) {
+                (Ok(false), Ok(sha3)) => VerificationFailure::HashMismatch {
+                    expected: self.sha3.to_string(),
+                    found: sha3.to_string(),
+                },
+                _ => self
+                    .diff(system)
+                    .map(|details| VerificationFailure::Differs { details })
+                    .unwrap_or(VerificationFailure::Unknown),
+            },
+        }
+    }
+
+    fn contents_diff<S: System>(&self, system: &mut S) -> Option<String> {
+        if !system.path_exists(&self.to).ok()? || system.path_is_dir(&self.to).ok()? {
+            return None;
+        }
+
+        let size = system.file_size(&self.to).ok()?;
+        let new_size = system.file_size(&self.local_file).ok()?;
+        if size > MAX_DIFF_SIZE || new_size > MAX_DIFF_SIZE {
+            return if system.file_sha3(&self.to).ok()? == self.sha3 {
+                None
+            } else {
+                Some(format!(
+                    "File {} is too large to diff ({} bytes, will be {} bytes)\n",
+                    self.to.display(),
+                    size,
+                    new_size
+                ))
+            };
+        }
+
+        let old = system.file_contents(&self.to).ok()?;
+        let new = system.file_contents(&self.local_file).ok()?;
+        Some(unified_diff(&self.to, &old, &new)).filter(|diff| !diff.is_empty())
+    }
+
+    fn to_shell<S: System>(&self, system: &mut S) -> Option<String> {
+        let contents = system.file_contents(&self.local_file).ok()?;
+        Some(bootstrap::write_file(self.to.to_str()?, &contents))
+    }
+
+    fn preflight<S: System>(&self, system: &mut S) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !system.path_exists(&self.local_file).unwrap_or(true) {
+            problems.push(format!(
+                "the generated file {} does not exist",
+                self.local_file.display()
+            ));
+        }
+
+        problems.extend(check_parent_writable(system, &self.to));
+        problems
+    }
+
+    fn summarize<S: System>(&self, system: &mut S) -> Vec<Fact> {
+        let mut facts = vec![Fact::File(self.to.clone())];
+        if let Ok(contents) = system.file_contents(&self.local_file) {
+            facts.extend(report::summarize_config(&self.to, &contents));
+        }
+
+        if let Some(three_way) = self.three_way(system) {
+            facts.push(Fact::Conffile(three_way));
+        }
+
+        facts
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        match self.conffile {
+            ConffilePolicy::KeepOurs => Vec::new(),
+            ConffilePolicy::TakeTheirs => vec!["dpkg-query", "md5sum"],
+            ConffilePolicy::Merge => vec!["dpkg-query", "md5sum", "diff3"],
+        }
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::File,
+            self.to.display().to_string(),
+            format!("file with sha3 {}", self.sha3),
+        )
+    }
+
+    fn exclusive_resources(&self) -> Vec<Resource> {
+        vec![Resource::Path(self.to.clone())]
+    }
+
     const NAME: &'static str = "file_with_contents";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A file with contents from the package.",
+        create: "Copies the file into place. An existing file is backed up first if the package allows it. Changes overwrite the file, unless a package upgrade changed it and the conffile policy says otherwise.",
+        verify: "Compares the checksum of the file. Changes from a package upgrade are accepted if the conffile policy takes or merges them.",
+        undo: "Removes the file. A file that existed before the requirement is restored from its backup.",
+    };
 }
 
 impl FileWithContents {
@@ -215,8 +522,98 @@ impl FileWithContents {
             local_file: source,
             to,
             sha3,
+            backup: None,
+            conffile: ConffilePolicy::KeepOurs,
+        }
+    }
+
+    /// Saves a pre-existing file that is overwritten to `backup`.
+    pub fn with_backup(self, backup: PathBuf) -> Self {
+        Self {
+            backup: Some(backup),
+            ..self
         }
     }
+
+    pub fn with_conffile_policy(self, conffile: ConffilePolicy) -> Self {
+        Self { conffile, ..self }
+    }
+
+    /// Returns the new version of the file that a package upgrade shipped, if any.
+    /// Only files in `/etc` can be conffiles, so no other files are checked.
+    fn package_change<S: System>(&self, system: &S) -> Option<PackageChange> {
+        if self.to.starts_with("/etc") {
+            conffile::detect(system, &self.to)
+        } else {
+            None
+        }
+    }
+
+    /// The saved original of the file, which is the common ancestor of our version and the package's version.
+    fn existing_backup<S: System>(&self, system: &S) -> Option<&StdPath> {
+        self.backup
+            .as_deref()
+            .filter(|backup| system.path_exists(backup).unwrap_or(false))
+    }
+
+    fn three_way<S: System>(&self, system: &S) -> Option<ThreeWay> {
+        let change = self.package_change(system)?;
+        Some(ThreeWay {
+            path: self.to.clone(),
+            package: change.package,
+            policy: self.conffile,
+            ours: self.sha3,
+            theirs: system.file_sha3(&change.theirs).ok()?,
+            theirs_path: change.theirs,
+            base: self
+                .existing_backup(system)
+                .and_then(|backup| system.file_sha3(backup).ok()),
+            current: system.file_sha3(&self.to).ok()?,
+        })
+    }
+
+    /// Installs the package's version of the file, if dpkg did not already do so.
+    fn take_theirs<S: System>(
+        &self,
+        system: &mut S,
+        change: &PackageChange,
+    ) -> Result<(), FileModifyError<S>> {
+        if !change.replaced(&self.to) {
+            system
+                .copy_file(&change.theirs, &self.to)
+                .and_then(|_| system.remove_file(&change.theirs))
+                .map_err(|e| FileModifyError::UnableToWrite(self.to.clone(), e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Merges the package's changes into our version. The package's version is kept as `.dpkg-dist`.
+    fn merge<S: System>(
+        &self,
+        system: &mut S,
+        change: &PackageChange,
+    ) -> Result<(), FileModifyError<S>> {
+        let theirs = if change.replaced(&self.to) {
+            let dist = conffile::with_suffix(&self.to, ".dpkg-dist");
+            system
+                .copy_file(&self.to, &dist)
+                .map_err(|e| FileModifyError::UnableToWrite(dist.clone(), e))?;
+            dist
+        } else {
+            change.theirs.clone()
+        };
+
+        let base = self.existing_backup(system);
+        let merged = conffile::merge(system, &self.local_file, base, &theirs)
+            .map_err(FileModifyError::FailedToStart)?
+            .ok_or_else(|| {
+                FileModifyError::MergeConflict(change.package.clone(), self.to.clone())
+            })?;
+        system
+            .put_file_contents(&self.to, &merged)
+            .map_err(|e| FileModifyError::UnableToWrite(self.to.clone(), e))
+    }
 }
 
 impl Display for FileWithContents {
@@ -245,6 +642,10 @@ impl CreateDirectory {
             needs_cleanup: false,
         }
     }
+
+    pub fn path(&self) -> &StdPath {
+        &self.path
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -303,6 +704,10 @@ impl Requirement for CreateDirectory {
         self.path == other.path
     }
 
+    fn identity(&self) -> String {
+        self.path.display().to_string()
+    }
+
     fn supports_modifications(&self) -> bool {
         false
     }
@@ -317,7 +722,90 @@ impl Requirement for CreateDirectory {
         Ok(self.has_been_created(system).unwrap())
     }
 
+    fn verify_probes(&self) -> Vec<Probe> {
+        vec![Probe::PathExists(self.path.clone())]
+    }
+
+    fn verification_failure<S: System>(&self, _system: &mut S) -> VerificationFailure {
+        VerificationFailure::Missing
+    }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        Some(bootstrap::command("mkdir", &["-p", self.path.to_str()?]))
+    }
+
+    fn preflight<S: System>(&self, system: &mut S) -> Vec<String> {
+        check_parent_writable(system, &self.path)
+            .into_iter()
+            .collect()
+    }
+
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        vec![Fact::Directory(self.path.clone())]
+    }
+
+    fn describe(&self) -> Description {
+        let summary = if self.needs_cleanup {
+            "directory"
+        } else {
+            "directory, kept when undone"
+        };
+        Description::new(
+            Category::Directory,
+            self.path.display().to_string(),
+            summary,
+        )
+    }
+
+    fn exclusive_resources(&self) -> Vec<Resource> {
+        vec![Resource::Path(self.path.clone())]
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        if self.needs_cleanup {
+            vec!["tar"]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Files that are still in the directory when it is undone were not created by requirements, such as uploads, so they are saved in a tar archive.
+    fn backup<S: System>(&self, system: &mut S, to: &StdPath) -> Result<bool, BackupError<S>> {
+        let read_error = |e| BackupError::UnableToRead(self.path.clone(), e);
+        if !system.path_exists(&self.path).map_err(read_error)?
+            || system.read_dir(&self.path).map_err(read_error)?.is_empty()
+        {
+            return Ok(false);
+        }
+
+        system
+            .make_dir_all(to)
+            .map_err(|e| BackupError::UnableToWrite(to.to_owned(), e))?;
+        let archive = to.join("contents.tar");
+        system
+            .execute_command(
+                "tar",
+                &[
+                    "-C",
+                    self.path.to_str().unwrap(),
+                    "-cf",
+                    archive.to_str().unwrap(),
+                    ".",
+                ],
+            )
+            .map_err(BackupError::FailedToStart)?
+            .successful()?;
+
+        Ok(true)
+    }
+
     const NAME: &'static str = "directory";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A directory.",
+        create: "Creates the directory.",
+        verify: "Checks that the directory exists.",
+        undo: "Removes the directory, unless it was created to be kept. Files that are still in the directory are first saved in a tar archive in the backups directory.",
+    };
 }
 
 impl Display for CreateDirectory {
@@ -398,10 +886,18 @@ impl Requirement for Delete {
         Ok(!system.path_exists(&self.path)?)
     }
 
+    fn saved_original(&self) -> Option<(&StdPath, &StdPath)> {
+        Some((&self.path, &self.copy_to))
+    }
+
     fn affects(&self, other: &Self) -> bool {
         self.path == other.path
     }
 
+    fn identity(&self) -> String {
+        self.path.display().to_string()
+    }
+
     fn supports_modifications(&self) -> bool {
         false
     }
@@ -416,7 +912,29 @@ impl Requirement for Delete {
         Ok(self.has_been_created(system).unwrap())
     }
 
+    fn verify_probes(&self) -> Vec<Probe> {
+        vec![Probe::PathExists(self.path.clone())]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::File,
+            self.path.display().to_string(),
+            format!("removed, backed up to {}", self.copy_to.display()),
+        )
+    }
+
+    fn exclusive_resources(&self) -> Vec<Resource> {
+        vec![Resource::Path(self.path.clone())]
+    }
+
     const NAME: &'static str = "delete";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A file that must not exist.",
+        create: "Copies the file to a backup, then removes it.",
+        verify: "Checks that the file no longer exists.",
+        undo: "Copies the backup back to where the file was, then removes the backup.",
+    };
 }
 
 impl Display for Delete {
@@ -501,6 +1019,10 @@ impl Requirement for Chown {
         self.path == other.path
     }
 
+    fn identity(&self) -> String {
+        self.path.display().to_string()
+    }
+
     fn supports_modifications(&self) -> bool {
         true
     }
@@ -516,7 +1038,43 @@ impl Requirement for Chown {
         Ok(true)
     }
 
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        Some(bootstrap::command(
+            "chown",
+            &[
+                &format!("{}:{}", self.user, self.group),
+                self.path.to_str()?,
+            ],
+        ))
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Permissions,
+            self.path.display().to_string(),
+            format!("owned by {}:{}", self.user, self.group),
+        )
+    }
+
     const NAME: &'static str = "chown";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "The owner and group of a path.",
+        create: "Runs `chown user:group` on the path.",
+        verify: "Not checked yet: always passes.",
+        undo: "Nothing: the previous owner is not known, so the ownership is kept.",
+    };
+    const DOCS_URL: Option<&'static str> =
+        Some("https://man7.org/linux/man-pages/man1/chown.1.html");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "invalid user",
+            hint: "The user or group does not exist. Make the requirement depend on the requirement that creates it.",
+        },
+        FailureHint {
+            pattern: "invalid group",
+            hint: "The group does not exist. Make the requirement depend on the requirement that creates it.",
+        },
+    ];
 }
 
 impl Display for Chown {
@@ -572,6 +1130,10 @@ impl Requirement for Chmod {
         self.path == other.path
     }
 
+    fn identity(&self) -> String {
+        self.path.display().to_string()
+    }
+
     fn supports_modifications(&self) -> bool {
         true
     }
@@ -587,7 +1149,28 @@ impl Requirement for Chmod {
         Ok(true)
     }
 
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        Some(bootstrap::command(
+            "chmod",
+            &[&format!("{:o}", self.permissions), self.path.to_str()?],
+        ))
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Permissions,
+            self.path.display().to_string(),
+            format!("mode {:o}", self.permissions),
+        )
+    }
+
     const NAME: &'static str = "chmod";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "The permissions of a path.",
+        create: "Sets the permissions of the path.",
+        verify: "Not checked yet: always passes.",
+        undo: "Nothing: the previous permissions are not known, so they are kept.",
+    };
 }
 
 impl Display for Chmod {
@@ -596,22 +1179,212 @@ impl Display for Chmod {
     }
 }
 
+/// A symbolic link at `link` that points to `target`.
+/// Changing the target replaces the link atomically, so there is no moment at which the link does not exist.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Symlink {
+    link: PathBuf,
+    target: PathBuf,
+}
+
+impl Symlink {
+    pub fn new(link: PathBuf, target: PathBuf) -> Symlink {
+        Symlink { link, target }
+    }
+
+    /// Returns the current target of the link, or `None` if there is no link.
+    fn current_target<S: System>(&self, system: &mut S) -> Result<Option<String>, SymlinkError<S>> {
+        let result = system
+            .execute_command("readlink", &[self.link.to_str().unwrap()])
+            .map_err(SymlinkError::FailedToStart)?;
+        Ok(if result.is_success() {
+            Some(result.stdout_as_str().trim_end_matches('\n').to_owned())
+        } else {
+            None
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SymlinkError<S: System> {
+    #[error("unable to create symlink {}: {}", .0.display(), .1)]
+    UnableToCreate(PathBuf, S::Error),
+
+    #[error("unable to remove symlink {}: {}", .0.display(), .1)]
+    UnableToRemove(PathBuf, S::Error),
+
+    #[error("unable to execute command: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("command failed: {0} {1}")]
+    Unsuccessful(String, String),
+}
+
+impl<S: System> From<(&str, &str)> for SymlinkError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        SymlinkError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+impl Requirement for Symlink {
+    type CreateError<S: System> = SymlinkError<S>;
+    type ModifyError<S: System> = SymlinkError<S>;
+    type DeleteError<S: System> = SymlinkError<S>;
+    type HasBeenCreatedError<S: System> = SymlinkError<S>;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        // Create the new link next to the old one, and rename it over the old link
+        let mut temporary = self.link.clone().into_os_string();
+        temporary.push(".side-new");
+        let temporary = PathBuf::from(temporary);
+        // A leftover link from an interrupted apply may be dangling, which `path_exists` does not detect
+        system
+            .execute_command("rm", &["-f", temporary.to_str().unwrap()])
+            .map_err(SymlinkError::FailedToStart)?
+            .successful()?;
+
+        system
+            .symlink(&self.target, &temporary)
+            .map_err(|e| SymlinkError::UnableToCreate(temporary.clone(), e))?;
+        system
+            .execute_command(
+                "mv",
+                &[
+                    "-T",
+                    temporary.to_str().unwrap(),
+                    self.link.to_str().unwrap(),
+                ],
+            )
+            .map_err(SymlinkError::FailedToStart)?
+            .successful()?;
+
+        Ok(())
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.create(system)
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        system
+            .remove_file(&self.link)
+            .map_err(|e| SymlinkError::UnableToRemove(self.link.clone(), e))
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(self.current_target(system)?.is_some())
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.link == other.link
+    }
+
+    fn identity(&self) -> String {
+        self.link.display().to_string()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        true
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self
+            .current_target(system)
+            .map(|target| target.as_deref() == self.target.to_str())
+            .unwrap_or(false))
+    }
+
+    fn verify_probes(&self) -> Vec<Probe> {
+        vec![Probe::command("readlink", &[self.link.to_str().unwrap()])]
+    }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        Some(bootstrap::command(
+            "ln",
+            &["-sfn", self.target.to_str()?, self.link.to_str()?],
+        ))
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["readlink", "rm", "mv"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::File,
+            self.link.display().to_string(),
+            format!("links to {}", self.target.display()),
+        )
+    }
+
+    fn exclusive_resources(&self) -> Vec<Resource> {
+        vec![Resource::Path(self.link.clone())]
+    }
+
+    const NAME: &'static str = "symlink";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A symbolic link to a target.",
+        create: "Creates a new link next to the old one and renames it over the old link, so that the link is replaced atomically.",
+        verify: "Checks that `readlink` returns the target.",
+        undo: "Removes the link.",
+    };
+}
+
+impl Display for Symlink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "symlink({} -> {})",
+            self.link.display(),
+            self.target.display()
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        builder::fs::{Chmod, Chown, CreateDirectory, Delete, FileWithContents, Sha3},
+        builder::fs::{
+            Chmod, Chown, CreateDirectory, Delete, FileDeleteError, FileWithContents, Sha3, Symlink,
+        },
+        conffile::ConffilePolicy,
+        conflict::Resolution,
+        graph::NodeId,
         requirements::Requirement,
-        system::System,
-        testing::LxcInstance,
+        system::{LocalSystem, System},
+        testing::{LxcInstance, TempDir},
     };
     use std::path::PathBuf;
 
+    #[test]
+    pub fn sha3_hex_and_reader() {
+        let hash = Sha3::hash(b"Hello World");
+
+        assert_eq!(Sha3::from_hex(&hash.to_string()), Some(hash));
+        assert_eq!(Sha3::hash_reader(&b"Hello World"[..]).unwrap(), hash);
+        assert_eq!(Sha3::from_hex("abc"), None);
+        assert_eq!(Sha3::from_hex(&"zz".repeat(32)), None);
+    }
+
     #[test]
     pub fn serialize_deserialize_file_with_contents() {
         let r = FileWithContents {
             local_file: PathBuf::from("/foo/bar/baz"),
             to: PathBuf::from("/fizz/buzz"),
             sha3: Sha3::hash("Hello World".as_bytes()),
+            backup: None,
+            conffile: Default::default(),
         };
         let json = r#"{"local_file":"/foo/bar/baz","to":"/fizz/buzz","sha3":[225,103,246,141,101,99,215,91,178,95,58,164,156,41,239,97,45,65,53,45,192,6,6,222,124,189,99,11,178,102,95,81]}"#;
 
@@ -619,6 +1392,127 @@ mod tests {
         assert_eq!(r, serde_json::from_str(json).unwrap());
     }
 
+    #[test]
+    pub fn file_identity_ignores_contents() {
+        let file = |to: &str, contents: &[u8]| {
+            FileWithContents::new(
+                PathBuf::from("/foo"),
+                PathBuf::from(to),
+                Sha3::hash(contents),
+            )
+        };
+        let directory = CreateDirectory::new(PathBuf::from("/etc/app.conf"));
+
+        assert_eq!(
+            NodeId::of(&file("/etc/app.conf", b"a")),
+            NodeId::of(&file("/etc/app.conf", b"b"))
+        );
+        assert_ne!(
+            NodeId::of(&file("/etc/app.conf", b"a")),
+            NodeId::of(&file("/etc/other.conf", b"a"))
+        );
+        assert_ne!(
+            NodeId::of(&file("/etc/app.conf", b"a")),
+            NodeId::of(&directory)
+        );
+    }
+
+    #[test]
+    pub fn serialize_deserialize_file_with_contents_backup() {
+        let r = FileWithContents::new(
+            PathBuf::from("/foo"),
+            PathBuf::from("/bar"),
+            Sha3::default(),
+        )
+        .with_backup(PathBuf::from("/deleted/bar"));
+        let json = r#"{"local_file":"/foo","to":"/bar","sha3":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"backup":"/deleted/bar"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn serialize_deserialize_file_with_contents_conffile() {
+        let r = FileWithContents::new(
+            PathBuf::from("/foo"),
+            PathBuf::from("/etc/bar"),
+            Sha3::default(),
+        )
+        .with_conffile_policy(ConffilePolicy::Merge);
+        let json = r#"{"local_file":"/foo","to":"/etc/bar","sha3":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"conffile":"merge"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn file_with_contents_adopts_and_restores_existing() {
+        let dir = TempDir::new("adopt");
+        std::fs::write(dir.join("source"), b"new").unwrap();
+        std::fs::write(dir.join("target"), b"original").unwrap();
+        let mut sys = LocalSystem::new();
+        let file = |contents: &[u8]| {
+            FileWithContents::new(dir.join("source"), dir.join("target"), Sha3::hash(contents))
+                .with_backup(dir.join("backup"))
+        };
+
+        let identical = file(b"original").resolve_existing(&mut sys);
+        let different = file(b"new").resolve_existing(&mut sys);
+        file(b"new").backup_existing(&mut sys).unwrap();
+        file(b"new").modify(&mut sys).unwrap();
+        let overwritten = std::fs::read(dir.join("target")).unwrap();
+        file(b"new").pre_existing_delete(&mut sys).unwrap();
+        let restored = std::fs::read(dir.join("target")).unwrap();
+        let backup_removed = !dir.join("backup").exists();
+        let missing = file(b"new").pre_existing_delete(&mut sys);
+
+        assert_eq!(identical, Some(Resolution::Adopt));
+        assert_eq!(different, None);
+        assert_eq!(overwritten, b"new");
+        assert_eq!(restored, b"original");
+        assert!(backup_removed);
+        assert!(matches!(missing, Err(FileDeleteError::BackupMissing(..))));
+    }
+
+    #[test]
+    pub fn file_with_contents_diffs_existing() {
+        let dir = TempDir::new("contents-diff");
+        std::fs::write(dir.join("source"), b"user www-data;\nworker_processes 4;\n").unwrap();
+        let mut sys = LocalSystem::new();
+        let file = FileWithContents::new(
+            dir.join("source"),
+            dir.join("target"),
+            Sha3::hash(b"user www-data;\nworker_processes 4;\n"),
+        );
+
+        let missing = file.contents_diff(&mut sys);
+        std::fs::write(dir.join("target"), b"user www-data;\nworker_processes 4;\n").unwrap();
+        let identical = file.contents_diff(&mut sys);
+        std::fs::write(dir.join("target"), b"user www-data;\nworker_processes 2;\n").unwrap();
+        let changed = file.contents_diff(&mut sys);
+        std::fs::write(dir.join("target"), b"\x7fELF\0\0").unwrap();
+        let binary = file.contents_diff(&mut sys);
+
+        let target = dir.join("target");
+        assert_eq!(missing, None);
+        assert_eq!(identical, None);
+        assert_eq!(
+            changed,
+            Some(format!(
+                "--- {}\n+++ {}\n@@ -1,2 +1,2 @@\n user www-data;\n-worker_processes 2;\n+worker_processes 4;\n",
+                target.display(),
+                target.display()
+            ))
+        );
+        assert_eq!(
+            binary,
+            Some(format!(
+                "Binary file {} differs (6 bytes, will be 35 bytes)\n",
+                target.display()
+            ))
+        );
+    }
+
     #[test]
     #[ignore]
     pub fn lxc_file_with_contents() {
@@ -629,6 +1523,8 @@ mod tests {
             local_file: PathBuf::from("/foo"),
             to: PathBuf::from("/bar"),
             sha3: Sha3::hash(data),
+            backup: None,
+            conffile: Default::default(),
         };
 
         assert!(!p.has_been_created(&mut sys).unwrap());
@@ -648,6 +1544,8 @@ mod tests {
             local_file: PathBuf::from("/baz"),
             to: PathBuf::from("/bar"),
             sha3: Sha3::hash(data2),
+            backup: None,
+            conffile: Default::default(),
         };
 
         assert!(p.has_been_created(&mut sys).unwrap());
@@ -755,6 +1653,61 @@ mod tests {
         assert_eq!(r, serde_json::from_str(json).unwrap());
     }
 
+    #[test]
+    pub fn serialize_deserialize_symlink() {
+        let r = Symlink::new(PathBuf::from("/srv/live"), PathBuf::from("/srv/3"));
+        let json = r#"{"link":"/srv/live","target":"/srv/3"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn symlink_is_replaced() {
+        let dir = TempDir::new("symlink");
+        let link = dir.join("live");
+        let blue = Symlink::new(link.clone(), dir.join("1"));
+        let green = Symlink::new(link.clone(), dir.join("2"));
+        let mut system = LocalSystem::new();
+
+        let before = blue.has_been_created(&mut system).unwrap();
+        blue.create(&mut system).unwrap();
+        let blue_ok = blue.verify(&mut system).unwrap();
+        green.modify(&mut system).unwrap();
+        let green_ok = green.verify(&mut system).unwrap();
+        let blue_after = blue.verify(&mut system).unwrap();
+        green.delete(&mut system).unwrap();
+        let after = green.has_been_created(&mut system).unwrap();
+
+        assert!(!before);
+        assert!(blue_ok);
+        assert!(green_ok);
+        assert!(!blue_after);
+        assert!(!after);
+    }
+
+    #[test]
+    pub fn directory_with_files_is_backed_up() {
+        let dir = TempDir::new("backup");
+        let uploads = dir.join("uploads");
+        let backups = dir.join("backups");
+        let r = CreateDirectory::new(uploads.clone());
+        let mut system = LocalSystem::new();
+
+        r.create(&mut system).unwrap();
+        let empty = r.backup(&mut system, &backups.join("empty")).unwrap();
+        std::fs::write(uploads.join("photo.jpg"), b"photo").unwrap();
+        let full = r.backup(&mut system, &backups.join("full")).unwrap();
+        let archive = backups.join("full/contents.tar");
+        let listing = system
+            .execute_command("tar", &["-tf", archive.to_str().unwrap()])
+            .unwrap();
+
+        assert!(!empty);
+        assert!(full);
+        assert!(listing.stdout_as_str().contains("photo.jpg"));
+    }
+
     #[test]
     pub fn serialize_deserialize_chmod() {
         let r = Chmod {
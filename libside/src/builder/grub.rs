@@ -0,0 +1,417 @@
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, Requirement, Supports};
+use crate::system::{NeverError, System};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::fs::{ConfigFileData, CreateDirectory, FileWithContents};
+use super::reboot::RequiresReboot;
+use super::Context;
+
+/// A drop-in for the GRUB defaults in `/etc/default/grub.d`, which are sourced after `/etc/default/grub` by `update-grub`.
+///
+/// Kernel command-line parameters are appended to `GRUB_CMDLINE_LINUX`, so parameters from `/etc/default/grub` and other drop-ins are kept.
+/// Because the parameters only take effect after a reboot, installing them also adds a [`RequiresReboot`] marker.
+pub struct GrubDefaults {
+    settings: Vec<(String, String)>,
+    parameters: Vec<String>,
+}
+
+impl GrubDefaults {
+    pub fn new() -> GrubDefaults {
+        GrubDefaults {
+            settings: Vec::new(),
+            parameters: Vec::new(),
+        }
+    }
+
+    /// Sets a key such as `GRUB_TIMEOUT`.
+    pub fn set<V: Display>(mut self, key: &str, value: V) -> Self {
+        assert!(
+            !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'),
+            "invalid GRUB setting {:?}",
+            key
+        );
+
+        self.settings.push((key.to_owned(), value.to_string()));
+        self
+    }
+
+    /// Adds a kernel command-line parameter, for example `kernel_parameter("mitigations", Some("auto"))` or `kernel_parameter("quiet", None)`.
+    pub fn kernel_parameter(mut self, name: &str, value: Option<&str>) -> Self {
+        let parameter = match value {
+            Some(value) => format!("{}={}", name, value),
+            None => name.to_owned(),
+        };
+        assert!(
+            !parameter.is_empty() && !parameter.contains(char::is_whitespace),
+            "invalid kernel parameter {:?}",
+            parameter
+        );
+
+        self.parameters.push(parameter);
+        self
+    }
+
+    fn to_vec(&self) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for (key, value) in self.settings.iter() {
+            writeln!(&mut data, "{}=\"{}\"", key, escape(value))?;
+        }
+
+        if !self.parameters.is_empty() {
+            writeln!(
+                &mut data,
+                "GRUB_CMDLINE_LINUX=\"$GRUB_CMDLINE_LINUX {}\"",
+                escape(&self.parameters.join(" "))
+            )?;
+        }
+
+        Ok(data)
+    }
+
+    pub fn install<R>(self, context: &mut Context<R>, name: &str) -> GraphNodeReference
+    where
+        R: Requirement
+            + Supports<CreateDirectory>
+            + Supports<FileWithContents>
+            + Supports<UpdateGrub>
+            + Supports<KernelParameter>
+            + Supports<RequiresReboot>,
+    {
+        let dir = PathBuf::from("/etc/default/grub.d");
+        let file = ConfigFileData {
+            path: dir.join(format!("{}.cfg", name)),
+            contents: self.to_vec().unwrap(),
+            path_dependency: context.directory(&dir),
+            extra_dependencies: Vec::new(),
+        }
+        .create(context)
+        .graph_node()
+        .unwrap();
+
+        let update = context.add_node(
+            UpdateGrub {
+                parameters: self.parameters.clone(),
+            },
+            &[file],
+        );
+
+        if !self.parameters.is_empty() {
+            let key = format!("kernel command line {}", self.parameters.join(" "));
+            RequiresReboot::add(
+                context,
+                &key,
+                "kernel command-line parameters changed",
+                &[update],
+            );
+        }
+
+        for parameter in self.parameters {
+            context.add_node(KernelParameter { parameter }, &[update]);
+        }
+
+        update
+    }
+}
+
+impl Default for GrubDefaults {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Escapes a value for use in a double-quoted shell string.
+fn escape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '`') {
+            result.push('\\');
+        }
+
+        result.push(c);
+    }
+
+    result
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GrubError<S: System> {
+    #[error("unable to execute update-grub: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("update-grub failed: {0} {1}")]
+    Unsuccessful(String, String),
+}
+
+impl<S: System> From<(&str, &str)> for GrubError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        GrubError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+/// Regenerates `/boot/grub/grub.cfg` so that changes to the GRUB defaults take effect on the next boot.
+/// Verification checks that every kernel entry in the generated configuration contains the configured parameters.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UpdateGrub {
+    parameters: Vec<String>,
+}
+
+impl UpdateGrub {
+    const CONFIG: &'static str = "/boot/grub/grub.cfg";
+
+    fn exec<S: System>(&self, system: &mut S) -> Result<(), GrubError<S>> {
+        let result = system
+            .execute_command("update-grub", &[])
+            .map_err(GrubError::FailedToStart)?;
+        result.successful()?;
+
+        Ok(())
+    }
+}
+
+/// Returns true if every `linux` line in `config` contains all `parameters`.
+fn config_has_parameters(config: &str, parameters: &[String]) -> bool {
+    let mut kernels = config
+        .lines()
+        .map(str::trim_start)
+        .filter(|line| line.starts_with("linux ") || line.starts_with("linux\t"))
+        .peekable();
+    if kernels.peek().is_none() {
+        return false;
+    }
+
+    kernels.all(|line| {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        parameters
+            .iter()
+            .all(|parameter| words.contains(&parameter.as_str()))
+    })
+}
+
+impl Requirement for UpdateGrub {
+    type CreateError<S: System> = GrubError<S>;
+    type ModifyError<S: System> = GrubError<S>;
+    type DeleteError<S: System> = GrubError<S>;
+    type HasBeenCreatedError<S: System> = NeverError;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.exec(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.exec(system)
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        self.exec(system)
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
+
+    fn affects(&self, _other: &Self) -> bool {
+        false
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        if self.parameters.is_empty() {
+            return Ok(true);
+        }
+
+        Ok(system
+            .file_contents(Path::new(Self::CONFIG))
+            .map(|config| {
+                config_has_parameters(&String::from_utf8_lossy(&config), &self.parameters)
+            })
+            .unwrap_or(false))
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["update-grub"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(Category::Other, "grub", "configuration regenerated")
+    }
+
+    const NAME: &'static str = "update_grub";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "The generated GRUB configuration in `/boot/grub/grub.cfg`.",
+        create: "Runs `update-grub`, so that changes to the GRUB defaults take effect on the next boot.",
+        verify: "Checks that every kernel entry in the generated configuration contains the configured parameters.",
+        undo: "Runs `update-grub` again, without the removed defaults.",
+    };
+}
+
+impl Display for UpdateGrub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "update-grub({})", self.parameters.join(" "))
+    }
+}
+
+/// Checks that the running kernel has been booted with a command-line parameter, by reading `/proc/cmdline`.
+/// This requirement does not change anything on the system; it is added by [`GrubDefaults::install`].
+/// Verification fails until the system has been rebooted after the parameter was added.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KernelParameter {
+    parameter: String,
+}
+
+impl KernelParameter {
+    const CMDLINE: &'static str = "/proc/cmdline";
+}
+
+impl Requirement for KernelParameter {
+    type CreateError<S: System> = NeverError;
+    type ModifyError<S: System> = NeverError;
+    type DeleteError<S: System> = NeverError;
+    type HasBeenCreatedError<S: System> = NeverError;
+
+    fn create<S: System>(&self, _system: &mut S) -> Result<(), Self::CreateError<S>> {
+        // The parameter only takes effect after a reboot, so there is nothing to check yet
+        Ok(())
+    }
+
+    fn modify<S: System>(&self, _system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        Ok(())
+    }
+
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.parameter == other.parameter
+    }
+
+    fn identity(&self) -> String {
+        self.parameter.clone()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(system
+            .file_contents(Path::new(Self::CMDLINE))
+            .map(|cmdline| {
+                String::from_utf8_lossy(&cmdline)
+                    .split_whitespace()
+                    .any(|parameter| parameter == self.parameter)
+            })
+            .unwrap_or(false))
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Other,
+            &self.parameter,
+            "on the kernel command line",
+        )
+    }
+
+    const NAME: &'static str = "kernel_parameter";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A command-line parameter that the running kernel was booted with.",
+        create: "Nothing: the parameter only takes effect after a reboot.",
+        verify: "Checks that `/proc/cmdline` contains the parameter, which fails until the system has been rebooted.",
+        undo: "Nothing.",
+    };
+}
+
+impl Display for KernelParameter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "kernel-parameter({})", self.parameter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{config_has_parameters, GrubDefaults, KernelParameter, UpdateGrub};
+
+    #[test]
+    pub fn grub_defaults_contents() {
+        let defaults = GrubDefaults::new()
+            .set("GRUB_TIMEOUT", 2)
+            .set("GRUB_DISTRIBUTOR", "\"side\"")
+            .kernel_parameter("mitigations", Some("auto"))
+            .kernel_parameter("systemd.unified_cgroup_hierarchy", Some("1"));
+
+        assert_eq!(
+            std::str::from_utf8(&defaults.to_vec().unwrap()).unwrap(),
+            "GRUB_TIMEOUT=\"2\"\nGRUB_DISTRIBUTOR=\"\\\"side\\\"\"\nGRUB_CMDLINE_LINUX=\"$GRUB_CMDLINE_LINUX mitigations=auto systemd.unified_cgroup_hierarchy=1\"\n"
+        );
+    }
+
+    #[test]
+    pub fn generated_config_parameters() {
+        let parameters = vec![String::from("mitigations=auto")];
+        let config = "menuentry 'Debian' {\n\tlinux /vmlinuz root=/dev/sda1 ro mitigations=auto quiet\n}\nmenuentry 'Debian (recovery)' {\n\tlinux /vmlinuz root=/dev/sda1 ro single mitigations=auto\n}\n";
+        let old = "menuentry 'Debian' {\n\tlinux /vmlinuz root=/dev/sda1 ro mitigations=off\n}\n";
+
+        assert!(config_has_parameters(config, &parameters));
+        assert!(!config_has_parameters(old, &parameters));
+        assert!(!config_has_parameters("", &parameters));
+    }
+
+    #[test]
+    pub fn serialize_deserialize_update_grub() {
+        let r = UpdateGrub {
+            parameters: vec![String::from("mitigations=auto")],
+        };
+        let json = r#"{"parameters":["mitigations=auto"]}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn serialize_deserialize_kernel_parameter() {
+        let r = KernelParameter {
+            parameter: String::from("mitigations=auto"),
+        };
+        let json = r#"{"parameter":"mitigations=auto"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+}
@@ -0,0 +1,315 @@
+//! Checking that a service works, not only that it is running.
+//!
+//! A [`HealthCheck`] does not change anything on the system. It is added after the node that starts a service, such as [`ServiceRunning::restart`](super::systemd::ServiceRunning::restart),
+//! and fails the apply if the service does not respond as expected, which reverts the apply.
+//! Services take a moment to start, so a failed check is attempted again a few times, see [`Requirement::retry_policy`].
+use std::fmt::Display;
+
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, FailureHint, Requirement, Supports, VerificationFailure};
+use crate::retry::RetryPolicy;
+use crate::system::{NeverError, System};
+use serde::{Deserialize, Serialize};
+
+use super::Context;
+
+/// How long a single attempt of a check may take.
+const TIMEOUT_SECS: &str = "10";
+
+/// What a [`HealthCheck`] checks.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthProbe {
+    /// An HTTP GET request to `url` that responds with `status`.
+    Http { url: String, status: u16 },
+
+    /// A TCP connection to `port` on `host` that can be established.
+    Tcp { host: String, port: u16 },
+
+    /// A command that exits with `exit_code`.
+    Command {
+        command: Vec<String>,
+        exit_code: i32,
+    },
+}
+
+impl Display for HealthProbe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthProbe::Http { url, .. } => write!(f, "{}", url),
+            HealthProbe::Tcp { host, port } => write!(f, "tcp://{}:{}", host, port),
+            HealthProbe::Command { command, .. } => write!(f, "{}", command.join(" ")),
+        }
+    }
+}
+
+/// Checks that a service responds, see the [module documentation](self).
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HealthCheck {
+    probe: HealthProbe,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HealthCheckError<S: System> {
+    #[error("unable to execute the health check: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("health check of {probe} failed: {reason}")]
+    Unhealthy { probe: String, reason: String },
+}
+
+impl HealthCheck {
+    /// Checks that a GET request to `url` responds with `status`.
+    pub fn http(url: &str, status: u16) -> HealthCheck {
+        HealthCheck {
+            probe: HealthProbe::Http {
+                url: url.to_owned(),
+                status,
+            },
+        }
+    }
+
+    /// Checks that a TCP connection to `port` on `host` can be established.
+    pub fn tcp(host: &str, port: u16) -> HealthCheck {
+        HealthCheck {
+            probe: HealthProbe::Tcp {
+                host: host.to_owned(),
+                port,
+            },
+        }
+    }
+
+    /// Checks that `command` exits with `exit_code`.
+    pub fn command(command: &[&str], exit_code: i32) -> HealthCheck {
+        assert!(!command.is_empty(), "a health check needs a command");
+        HealthCheck {
+            probe: HealthProbe::Command {
+                command: command.iter().map(|&arg| arg.to_owned()).collect(),
+                exit_code,
+            },
+        }
+    }
+
+    pub fn probe(&self) -> &HealthProbe {
+        &self.probe
+    }
+
+    /// Adds the check to the graph after `service`, the node that starts the service that is checked.
+    pub fn after<R: Requirement + Supports<HealthCheck>>(
+        self,
+        context: &mut Context<R>,
+        service: GraphNodeReference,
+    ) -> GraphNodeReference {
+        context.add_node(self, &[service])
+    }
+
+    fn check<S: System>(&self, system: &mut S) -> Result<(), HealthCheckError<S>> {
+        let unhealthy = |reason: String| HealthCheckError::Unhealthy {
+            probe: self.probe.to_string(),
+            reason,
+        };
+
+        match &self.probe {
+            HealthProbe::Http { url, status } => {
+                let result = system
+                    .execute_command(
+                        "curl",
+                        &[
+                            "--silent",
+                            "--output",
+                            "/dev/null",
+                            "--max-time",
+                            TIMEOUT_SECS,
+                            "--write-out",
+                            "%{http_code}",
+                            url,
+                        ],
+                    )
+                    .map_err(HealthCheckError::FailedToStart)?;
+                let actual = result.stdout_as_str().trim();
+                if actual == status.to_string() {
+                    Ok(())
+                } else {
+                    Err(unhealthy(format!(
+                        "responded with status {}, expected {}",
+                        actual, status
+                    )))
+                }
+            }
+            HealthProbe::Tcp { host, port } => {
+                let result = system
+                    .execute_command("nc", &["-z", "-w", TIMEOUT_SECS, host, &port.to_string()])
+                    .map_err(HealthCheckError::FailedToStart)?;
+                if result.is_success() {
+                    Ok(())
+                } else {
+                    Err(unhealthy(String::from("unable to connect")))
+                }
+            }
+            HealthProbe::Command { command, exit_code } => {
+                let mut args = vec![TIMEOUT_SECS];
+                args.extend(command.iter().map(String::as_str));
+                let result = system
+                    .execute_command("timeout", &args)
+                    .map_err(HealthCheckError::FailedToStart)?;
+                if result.exit_code() == Some(*exit_code) {
+                    Ok(())
+                } else {
+                    Err(unhealthy(format!(
+                        "exited with {}, expected {}: {}",
+                        result
+                            .exit_code()
+                            .map(|code| code.to_string())
+                            .unwrap_or_else(|| String::from("a signal")),
+                        exit_code,
+                        result.stderr_as_str().trim()
+                    )))
+                }
+            }
+        }
+    }
+}
+
+impl Requirement for HealthCheck {
+    type CreateError<S: System> = HealthCheckError<S>;
+    type ModifyError<S: System> = HealthCheckError<S>;
+    type DeleteError<S: System> = NeverError;
+    type HasBeenCreatedError<S: System> = NeverError;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.check(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.check(system)
+    }
+
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.probe == other.probe
+    }
+
+    fn identity(&self) -> String {
+        self.probe.to_string()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self.check(system).is_ok())
+    }
+
+    fn verification_failure<S: System>(&self, system: &mut S) -> VerificationFailure {
+        match self.check(system) {
+            Ok(()) => VerificationFailure::Unknown,
+            Err(e) => VerificationFailure::Differs {
+                details: e.to_string(),
+            },
+        }
+    }
+
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        Some(RetryPolicy::new(5, 1000))
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        match &self.probe {
+            HealthProbe::Http { .. } => vec!["curl"],
+            HealthProbe::Tcp { .. } => vec!["nc"],
+            HealthProbe::Command { .. } => vec!["timeout"],
+        }
+    }
+
+    fn describe(&self) -> Description {
+        let expected = match &self.probe {
+            HealthProbe::Http { status, .. } => format!("responds with status {}", status),
+            HealthProbe::Tcp { .. } => String::from("accepts connections"),
+            HealthProbe::Command { exit_code, .. } => format!("exits with {}", exit_code),
+        };
+        Description::new(Category::Service, self.probe.to_string(), expected)
+    }
+
+    const NAME: &'static str = "health_check";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A service that responds to an HTTP request, a TCP connection or a command as expected.",
+        create: "Sends the request, connects or runs the command, and fails if the result differs. A failed check is attempted 5 times. Nothing on the system is changed.",
+        verify: "Checks again.",
+        undo: "Nothing.",
+    };
+    const FAILURE_HINTS: &'static [FailureHint] = &[FailureHint {
+        pattern: "responded with status 000",
+        hint: "nothing answered the request; check that the service listens on the address of the check",
+    }];
+}
+
+impl Display for HealthCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "health-check({})", self.probe)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HealthCheck;
+    use crate::requirements::Requirement;
+    use crate::system::LocalSystem;
+
+    #[test]
+    pub fn serialize_deserialize_health_check() {
+        let r = HealthCheck::http("http://127.0.0.1:8080/health", 204);
+        let json = r#"{"probe":{"http":{"url":"http://127.0.0.1:8080/health","status":204}}}"#;
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+
+        let r = HealthCheck::tcp("127.0.0.1", 5432);
+        let json = r#"{"probe":{"tcp":{"host":"127.0.0.1","port":5432}}}"#;
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+
+        let r = HealthCheck::command(&["redis-cli", "ping"], 0);
+        let json = r#"{"probe":{"command":{"command":["redis-cli","ping"],"exit_code":0}}}"#;
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn command_exit_code() {
+        let mut system = LocalSystem::new();
+
+        assert!(HealthCheck::command(&["true"], 0)
+            .create(&mut system)
+            .is_ok());
+        assert!(HealthCheck::command(&["sh", "-c", "exit 3"], 3)
+            .verify(&mut system)
+            .unwrap());
+
+        let err = HealthCheck::command(&["sh", "-c", "echo down >&2; exit 1"], 0)
+            .create(&mut system)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "health check of sh -c echo down >&2; exit 1 failed: exited with 1, expected 0: down"
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path};
+
+/// Patterns that are always excluded from packages.
+pub const DEFAULT_EXCLUDE: &[&str] = &[".git", ".DS_Store", "*.swp"];
+
+/// Decides which files in a package are visible to the builder and can be exposed.
+/// Configured in the `[files]` section of `package.toml`:
+///
+/// ```toml
+/// [files]
+/// include = ["public", "*.php"]
+/// exclude = ["*.log", "public/uploads"]
+/// ```
+///
+/// Patterns support `*` and `?`, which never match a `/`.
+/// A pattern without a `/` matches any single path component, a pattern with a `/` matches a path relative to the package root.
+/// Anything below an excluded path is excluded as well, and anything below an included path is included as well.
+/// If `include` is empty, all files that are not excluded are included.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileFilter {
+    #[serde(default)]
+    include: Vec<String>,
+
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+impl FileFilter {
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> FileFilter {
+        FileFilter { include, exclude }
+    }
+
+    /// Returns true if `relative`, or one of its parents, matches one of the default or configured exclude patterns.
+    pub fn is_excluded(&self, relative: &Path) -> bool {
+        DEFAULT_EXCLUDE
+            .iter()
+            .copied()
+            .chain(self.exclude.iter().map(String::as_str))
+            .any(|pattern| matches(pattern, relative))
+    }
+
+    /// Returns true if `relative` is not excluded, and is included by the include patterns.
+    pub fn is_included(&self, relative: &Path) -> bool {
+        !self.is_excluded(relative)
+            && (self.include.is_empty()
+                || self
+                    .include
+                    .iter()
+                    .any(|pattern| matches(pattern, relative)))
+    }
+}
+
+/// Returns true if `pattern` matches `relative` or one of its parents.
+fn matches(pattern: &str, relative: &Path) -> bool {
+    let components = relative
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if pattern.contains('/') {
+        let pattern = pattern.trim_matches('/');
+        (1..=components.len()).any(|n| glob(pattern, &components[..n].join("/")))
+    } else {
+        components.iter().any(|c| glob(pattern, c))
+    }
+}
+
+pub(crate) fn glob(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = text.as_bytes();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(b'?') if text[t] != b'/' => {
+                p += 1;
+                t += 1;
+            }
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` match one more character, as long as that character is not a separator
+                Some((star, start)) if text[start] != b'/' => {
+                    backtrack = Some((star, start + 1));
+                    p = star + 1;
+                    t = start + 1;
+                }
+                _ => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob, FileFilter};
+    use std::path::Path;
+
+    #[test]
+    pub fn glob_patterns() {
+        assert!(glob("*.swp", ".index.php.swp"));
+        assert!(glob("file?.txt", "file1.txt"));
+        assert!(glob("public/*/index.html", "public/blog/index.html"));
+        assert!(!glob("public/*", "public/blog/index.html"));
+        assert!(!glob("*.swp", "index.php"));
+    }
+
+    #[test]
+    pub fn default_exclude() {
+        let filter = FileFilter::default();
+
+        assert!(filter.is_excluded(Path::new(".git")));
+        assert!(filter.is_excluded(Path::new("public/.git/config")));
+        assert!(filter.is_excluded(Path::new("src/.main.rs.swp")));
+        assert!(filter.is_included(Path::new("public/index.html")));
+    }
+
+    #[test]
+    pub fn include_and_exclude() {
+        let filter = FileFilter::new(
+            vec![String::from("public"), String::from("*.php")],
+            vec![String::from("public/uploads"), String::from("*.log")],
+        );
+
+        assert!(filter.is_included(Path::new("public/css/site.css")));
+        assert!(filter.is_included(Path::new("src/index.php")));
+        assert!(!filter.is_included(Path::new("src/main.rs")));
+        assert!(!filter.is_included(Path::new("public/uploads/a.png")));
+        assert!(!filter.is_included(Path::new("public/error.log")));
+        assert!(!filter.is_included(Path::new("uploads/a.png")));
+    }
+
+    #[test]
+    pub fn parse_file_filter() {
+        let filter: FileFilter = toml::from_str(r#"exclude = ["*.log"]"#).unwrap();
+
+        assert_eq!(
+            filter,
+            FileFilter::new(Vec::new(), vec![String::from("*.log")])
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! A small key-value store for facts that builds share across applies, such as the last allocated port, a migration level or a previously chosen PHP version.
+//!
+//! Builders access it with [`super::Context::kv`]. The store is loaded from `kv.json` in the base directory when a build starts, and saved when the build has assembled its graph.
+//! Values are kept until they are removed, even if a later build does not read them.
+//! The store is saved with a revision number: a build whose store was changed on disk by another run while it was building fails instead of overwriting the other run's values.
+use crate::system::System;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KvError<S: System> {
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("invalid key-value store in {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, serde_json::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("{} was changed by another run during this build (revision {} instead of {})", .path.display(), .found, .expected)]
+    Conflict {
+        path: PathBuf,
+        expected: u64,
+        found: u64,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Stored {
+    revision: u64,
+    values: BTreeMap<String, Value>,
+}
+
+/// The values in the store, see the [module documentation](self).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KvStore {
+    stored: Stored,
+    changed: bool,
+}
+
+impl KvStore {
+    /// Loads the store from `path`. A store that does not exist yet is empty.
+    pub fn load<S: System>(path: &Path, system: &S) -> Result<KvStore, KvError<S>> {
+        Ok(KvStore {
+            stored: Self::read(path, system)?,
+            changed: false,
+        })
+    }
+
+    fn read<S: System>(path: &Path, system: &S) -> Result<Stored, KvError<S>> {
+        if !system
+            .path_exists(path)
+            .map_err(|e| KvError::UnableToRead(path.to_owned(), e))?
+        {
+            return Ok(Stored::default());
+        }
+
+        let contents = system
+            .file_contents(path)
+            .map_err(|e| KvError::UnableToRead(path.to_owned(), e))?;
+        serde_json::from_slice(&contents).map_err(|e| KvError::Invalid(path.to_owned(), e))
+    }
+
+    /// Saves the store to `path` if any value was changed.
+    /// Fails if the store on disk has a different revision than when it was loaded.
+    pub fn save<S: System>(&self, path: &Path, system: &S) -> Result<(), KvError<S>> {
+        if !self.changed {
+            return Ok(());
+        }
+
+        let found = Self::read(path, system)?.revision;
+        if found != self.stored.revision {
+            return Err(KvError::Conflict {
+                path: path.to_owned(),
+                expected: self.stored.revision,
+                found,
+            });
+        }
+
+        let stored = Stored {
+            revision: self.stored.revision + 1,
+            values: self.stored.values.clone(),
+        };
+        system
+            .put_file_contents(path, &serde_json::to_vec_pretty(&stored).unwrap())
+            .map_err(|e| KvError::UnableToWrite(path.to_owned(), e))
+    }
+
+    /// Returns the value of `key`, or `None` if the store does not contain it.
+    /// Fails if the value cannot be deserialized as `T`.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, serde_json::Error> {
+        self.stored
+            .values
+            .get(key)
+            .map(|value| T::deserialize(value))
+            .transpose()
+    }
+
+    /// Sets `key` to `value`.
+    pub fn set<T: Serialize>(&mut self, key: &str, value: T) {
+        let value = serde_json::to_value(value).expect("values must be serializable to JSON");
+        if self.stored.values.get(key) != Some(&value) {
+            self.stored.values.insert(key.to_owned(), value);
+            self.changed = true;
+        }
+    }
+
+    /// Returns the value of `key`, or sets it to the value returned by `f` if the store does not contain it yet.
+    pub fn get_or_insert_with<T: Serialize + DeserializeOwned>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce() -> T,
+    ) -> Result<T, serde_json::Error> {
+        match self.get(key)? {
+            Some(value) => Ok(value),
+            None => {
+                let value = f();
+                self.set(key, &value);
+                Ok(value)
+            }
+        }
+    }
+
+    /// Removes `key`, and returns true if the store contained it.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let removed = self.stored.values.remove(key).is_some();
+        self.changed |= removed;
+        removed
+    }
+
+    /// The keys in the store, in alphabetical order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.stored.values.keys().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KvError, KvStore};
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+
+    #[test]
+    pub fn store_values() {
+        let system = LocalSystem::new();
+        let dir = TempDir::new("kv");
+        let path = dir.join("kv.json");
+
+        let mut kv = KvStore::load(&path, &system).unwrap();
+        assert_eq!(kv.get::<u16>("last_port").unwrap(), None);
+        assert_eq!(
+            kv.get_or_insert_with("php", || String::from("8.2"))
+                .unwrap(),
+            "8.2"
+        );
+        kv.set("last_port", 20003u16);
+        kv.set("migration", 4);
+        assert!(kv.remove("migration"));
+        assert!(!kv.remove("migration"));
+        assert!(kv.get::<String>("last_port").is_err());
+        kv.save(&path, &system).unwrap();
+
+        let mut kv = KvStore::load(&path, &system).unwrap();
+        assert_eq!(kv.keys().collect::<Vec<_>>(), vec!["last_port", "php"]);
+        assert_eq!(kv.get::<u16>("last_port").unwrap(), Some(20003));
+        assert_eq!(
+            kv.get_or_insert_with("php", || String::from("8.3"))
+                .unwrap(),
+            "8.2"
+        );
+
+        // Another run saves its changes first
+        let mut other = KvStore::load(&path, &system).unwrap();
+        other.set("last_port", 20004u16);
+        other.save(&path, &system).unwrap();
+
+        kv.set("php", "8.3");
+        assert!(matches!(
+            kv.save(&path, &system),
+            Err(KvError::Conflict {
+                expected: 1,
+                found: 2,
+                ..
+            })
+        ));
+    }
+}
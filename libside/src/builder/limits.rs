@@ -0,0 +1,601 @@
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, Requirement, Supports};
+use crate::system::{NeverError, System};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io::Write;
+use std::path::PathBuf;
+
+use super::fs::{ConfigFileData, CreateDirectory, FileWithContents};
+use super::Context;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LimitType {
+    Soft,
+    Hard,
+    Both,
+}
+
+impl LimitType {
+    fn keyword(&self) -> &'static str {
+        match self {
+            LimitType::Soft => "soft",
+            LimitType::Hard => "hard",
+            LimitType::Both => "-",
+        }
+    }
+}
+
+struct LimitEntry {
+    domain: String,
+    kind: LimitType,
+    item: String,
+    value: String,
+}
+
+/// A file in `/etc/security/limits.d`.
+///
+/// Entries for a single user (i.e. not `*` or `@group`) are verified by checking the limits of a new session for that user.
+pub struct LimitsConf {
+    entries: Vec<LimitEntry>,
+    dependencies: Vec<GraphNodeReference>,
+}
+
+impl LimitsConf {
+    pub fn new() -> LimitsConf {
+        LimitsConf {
+            entries: Vec::new(),
+            dependencies: Vec::new(),
+        }
+    }
+
+    pub fn entry<V: Display>(
+        mut self,
+        domain: &str,
+        kind: LimitType,
+        item: &str,
+        value: V,
+    ) -> Self {
+        self.entries.push(LimitEntry {
+            domain: domain.to_owned(),
+            kind,
+            item: item.to_owned(),
+            value: value.to_string(),
+        });
+
+        self
+    }
+
+    /// Adds a dependency on the node that creates the user or group that the limits apply to.
+    pub fn depends_on(mut self, node: GraphNodeReference) -> Self {
+        self.dependencies.push(node);
+        self
+    }
+
+    fn to_vec(&self) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for entry in self.entries.iter() {
+            writeln!(
+                &mut data,
+                "{} {} {} {}",
+                entry.domain,
+                entry.kind.keyword(),
+                entry.item,
+                entry.value
+            )?;
+        }
+
+        Ok(data)
+    }
+
+    pub fn install<R>(self, context: &mut Context<R>, name: &str) -> GraphNodeReference
+    where
+        R: Requirement
+            + Supports<CreateDirectory>
+            + Supports<FileWithContents>
+            + Supports<EffectiveLimit>,
+    {
+        let dir = context.existing("/etc/security/limits.d/");
+        let file = ConfigFileData {
+            path: dir.join(format!("{}.conf", name)).full_path(),
+            contents: self.to_vec().unwrap(),
+            path_dependency: dir.node,
+            extra_dependencies: self.dependencies.clone(),
+        }
+        .create(context)
+        .graph_node()
+        .unwrap();
+
+        for entry in self.entries.iter() {
+            if entry.domain.starts_with('@') || entry.domain.starts_with('%') || entry.domain == "*"
+            {
+                continue;
+            }
+
+            if let Some(flag) = ulimit_flag(&entry.item) {
+                let hard = match entry.kind {
+                    LimitType::Soft => &[false][..],
+                    LimitType::Hard => &[true][..],
+                    LimitType::Both => &[false, true][..],
+                };
+
+                for &hard in hard {
+                    context.add_node(
+                        EffectiveLimit {
+                            user: entry.domain.clone(),
+                            flag,
+                            hard,
+                            value: entry.value.clone(),
+                        },
+                        &[file],
+                    );
+                }
+            }
+        }
+
+        file
+    }
+}
+
+impl Default for LimitsConf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Maps a limits.conf item to the corresponding flag of bash's `ulimit` builtin.
+fn ulimit_flag(item: &str) -> Option<char> {
+    Some(match item {
+        "core" => 'c',
+        "data" => 'd',
+        "fsize" => 'f',
+        "memlock" => 'l',
+        "nofile" => 'n',
+        "rss" => 'm',
+        "stack" => 's',
+        "cpu" => 't',
+        "nproc" => 'u',
+        "as" => 'v',
+        "locks" => 'x',
+        "sigpending" => 'i',
+        "msgqueue" => 'q',
+        "nice" => 'e',
+        "rtprio" => 'r',
+        _ => return None,
+    })
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LimitsError<S: System> {
+    #[error("unable to execute command: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("command failed: {0} {1}")]
+    Unsuccessful(String, String),
+
+    #[error("the effective value is {actual}")]
+    NotEffective { actual: String },
+}
+
+impl<S: System> From<(&str, &str)> for LimitsError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        LimitsError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+/// Checks that a new session of `user` gets the configured limit.
+/// This requirement does not change anything on the system; it is added by [`LimitsConf::install`] to verify that the limits are actually applied.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EffectiveLimit {
+    user: String,
+    flag: char,
+    hard: bool,
+    value: String,
+}
+
+impl EffectiveLimit {
+    fn current<S: System>(&self, system: &mut S) -> Result<String, LimitsError<S>> {
+        let command = format!(
+            "ulimit -{} -{}",
+            if self.hard { 'H' } else { 'S' },
+            self.flag
+        );
+        let result = system
+            .execute_command("su", &["-s", "/bin/bash", "-c", &command, &self.user])
+            .map_err(LimitsError::FailedToStart)?;
+        result.successful()?;
+
+        Ok(result.stdout_as_str().trim().to_owned())
+    }
+
+    fn check<S: System>(&self, system: &mut S) -> Result<(), LimitsError<S>> {
+        let actual = self.current(system)?;
+        if actual == self.value {
+            Ok(())
+        } else {
+            Err(LimitsError::NotEffective { actual })
+        }
+    }
+}
+
+impl Requirement for EffectiveLimit {
+    type CreateError<S: System> = LimitsError<S>;
+    type ModifyError<S: System> = LimitsError<S>;
+    type DeleteError<S: System> = NeverError;
+    type HasBeenCreatedError<S: System> = NeverError;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.check(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.check(system)
+    }
+
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.user == other.user && self.flag == other.flag && self.hard == other.hard
+    }
+
+    fn identity(&self) -> String {
+        format!("{}/{}/{}", self.user, self.flag, self.hard)
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self.check(system).is_ok())
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["su"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Limits,
+            &self.user,
+            format!(
+                "{} limit -{} is {}",
+                if self.hard { "hard" } else { "soft" },
+                self.flag,
+                self.value
+            ),
+        )
+    }
+
+    const NAME: &'static str = "effective_limit";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A resource limit that a new session of a user gets.",
+        create: "Checks the limit with `ulimit` in a new session of the user, and fails if it differs. Nothing on the system is changed.",
+        verify: "Checks the limit again.",
+        undo: "Nothing.",
+    };
+}
+
+impl Display for EffectiveLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "limit({} -{}{} = {})",
+            self.user,
+            if self.hard { 'H' } else { 'S' },
+            self.flag,
+            self.value
+        )
+    }
+}
+
+/// A drop-in for the systemd manager configuration in `/etc/systemd/system.conf.d`, for example to raise `DefaultLimitNOFILE` or `DefaultTasksMax`.
+pub struct ManagerDefaults {
+    properties: Vec<(String, String)>,
+}
+
+impl ManagerDefaults {
+    pub fn new() -> ManagerDefaults {
+        ManagerDefaults {
+            properties: Vec::new(),
+        }
+    }
+
+    pub fn set<V: Display>(mut self, property: &str, value: V) -> Self {
+        self.properties
+            .push((property.to_owned(), value.to_string()));
+        self
+    }
+
+    pub fn default_limit_nofile<V: Display>(self, value: V) -> Self {
+        self.set("DefaultLimitNOFILE", value)
+    }
+
+    pub fn default_tasks_max<V: Display>(self, value: V) -> Self {
+        self.set("DefaultTasksMax", value)
+    }
+
+    fn to_vec(&self) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        writeln!(&mut data, "[Manager]")?;
+        for (property, value) in self.properties.iter() {
+            writeln!(&mut data, "{}={}", property, value)?;
+        }
+
+        Ok(data)
+    }
+
+    pub fn install<R>(self, context: &mut Context<R>, name: &str) -> GraphNodeReference
+    where
+        R: Requirement
+            + Supports<CreateDirectory>
+            + Supports<FileWithContents>
+            + Supports<ReloadManager>,
+    {
+        let dir = PathBuf::from("/etc/systemd/system.conf.d");
+        let file = ConfigFileData {
+            path: dir.join(format!("{}.conf", name)),
+            contents: self.to_vec().unwrap(),
+            path_dependency: context.directory(&dir),
+            extra_dependencies: Vec::new(),
+        }
+        .create(context)
+        .graph_node()
+        .unwrap();
+
+        context.add_node(
+            ReloadManager {
+                properties: self.properties,
+            },
+            &[file],
+        )
+    }
+}
+
+impl Default for ManagerDefaults {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Re-executes the systemd manager so that changes to its configuration take effect.
+/// Verification checks that the manager reports the expected values for the configured properties.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReloadManager {
+    properties: Vec<(String, String)>,
+}
+
+impl ReloadManager {
+    fn exec<S: System>(&self, system: &mut S) -> Result<(), LimitsError<S>> {
+        let result = system
+            .execute_command("systemctl", &["daemon-reexec"])
+            .map_err(LimitsError::FailedToStart)?;
+        result.successful()?;
+
+        Ok(())
+    }
+
+    fn is_effective<S: System>(&self, system: &mut S) -> Result<bool, LimitsError<S>> {
+        for (property, value) in self.properties.iter() {
+            let result = system
+                .execute_command(
+                    "systemctl",
+                    &["show", "--property", property.as_str(), "--value"],
+                )
+                .map_err(LimitsError::FailedToStart)?;
+            result.successful()?;
+
+            if result.stdout_as_str().trim() != value {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Requirement for ReloadManager {
+    type CreateError<S: System> = LimitsError<S>;
+    type ModifyError<S: System> = LimitsError<S>;
+    type DeleteError<S: System> = LimitsError<S>;
+    type HasBeenCreatedError<S: System> = NeverError;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.exec(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.exec(system)
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        self.exec(system)
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
+
+    fn affects(&self, _other: &Self) -> bool {
+        false
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self.is_effective(system).unwrap_or(false))
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["systemctl"]
+    }
+
+    fn describe(&self) -> Description {
+        let properties = self
+            .properties
+            .iter()
+            .map(|(property, value)| format!("{}={}", property, value))
+            .collect::<Vec<_>>();
+        Description::new(
+            Category::Limits,
+            "systemd",
+            format!("manager defaults {}", properties.join(", ")),
+        )
+    }
+
+    const NAME: &'static str = "reload_systemd_manager";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "Makes the systemd manager pick up changes to its configuration.",
+        create: "Runs `systemctl daemon-reexec`.",
+        verify:
+            "Checks that the manager reports the expected values for the configured properties.",
+        undo: "Runs `systemctl daemon-reexec` again, without the removed configuration.",
+    };
+}
+
+impl Display for ReloadManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reload-systemd-manager(")?;
+        for (index, (property, value)) in self.properties.iter().enumerate() {
+            if index != 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}={}", property, value)?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EffectiveLimit, LimitType, LimitsConf, ManagerDefaults, ReloadManager};
+    use crate::{requirements::Requirement, system::System, testing::LxcInstance};
+
+    #[test]
+    pub fn limits_conf_contents() {
+        let conf = LimitsConf::new()
+            .entry("www-data", LimitType::Both, "nofile", 65536)
+            .entry("@staff", LimitType::Soft, "nproc", 4096);
+
+        assert_eq!(
+            std::str::from_utf8(&conf.to_vec().unwrap()).unwrap(),
+            "www-data - nofile 65536\n@staff soft nproc 4096\n"
+        );
+    }
+
+    #[test]
+    pub fn manager_defaults_contents() {
+        let defaults = ManagerDefaults::new()
+            .default_limit_nofile(65536)
+            .default_tasks_max("80%");
+
+        assert_eq!(
+            std::str::from_utf8(&defaults.to_vec().unwrap()).unwrap(),
+            "[Manager]\nDefaultLimitNOFILE=65536\nDefaultTasksMax=80%\n"
+        );
+    }
+
+    #[test]
+    pub fn serialize_deserialize_effective_limit() {
+        let r = EffectiveLimit {
+            user: String::from("www-data"),
+            flag: 'n',
+            hard: true,
+            value: String::from("65536"),
+        };
+        let json = r#"{"user":"www-data","flag":"n","hard":true,"value":"65536"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn serialize_deserialize_reload_manager() {
+        let r = ReloadManager {
+            properties: vec![(String::from("DefaultTasksMax"), String::from("4096"))],
+        };
+        let json = r#"{"properties":[["DefaultTasksMax","4096"]]}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    #[ignore]
+    pub fn lxc_effective_limit() {
+        let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
+        let p = EffectiveLimit {
+            user: String::from("www-data"),
+            flag: 'n',
+            hard: true,
+            value: String::from("12345"),
+        };
+
+        assert!(!p.verify(&mut sys).unwrap());
+        assert!(p.create(&mut sys).is_err());
+
+        sys.put_file_contents(
+            "/etc/security/limits.d/test.conf".as_ref(),
+            b"www-data hard nofile 12345\n",
+        )
+        .unwrap();
+
+        assert!(p.verify(&mut sys).unwrap());
+        p.create(&mut sys).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    pub fn lxc_reload_manager() {
+        let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
+        let p = ReloadManager {
+            properties: vec![(String::from("DefaultTasksMax"), String::from("4321"))],
+        };
+
+        assert!(!p.verify(&mut sys).unwrap());
+
+        sys.make_dir_all("/etc/systemd/system.conf.d".as_ref())
+            .unwrap();
+        sys.put_file_contents(
+            "/etc/systemd/system.conf.d/test.conf".as_ref(),
+            b"[Manager]\nDefaultTasksMax=4321\n",
+        )
+        .unwrap();
+        p.create(&mut sys).unwrap();
+
+        assert!(p.verify(&mut sys).unwrap());
+    }
+}
@@ -1,34 +1,81 @@
 use self::apply::PreparedBuild;
-use self::fs::{CreateDirectory, Delete};
+use self::credentials::SystemdCredential;
+use self::exposed::ExposedFiles;
+use self::fs::{CreateDirectory, Delete, Sha3};
+use self::ignore::FileFilter;
+use self::kv::{KvError, KvStore};
+use self::ports::{Port, PortError, PortRegistry};
+use self::source::{PackageSource, SourceConfig, SourceError};
 use self::users::{Group, User};
+use self::version::PackageVersion;
+use crate::arch::{Arch, ArchError};
+use crate::db::Migrations;
+use crate::deleted::{self, DeletedFiles};
+use crate::distro::{Distro, ServiceManager};
+use crate::outputs::Outputs;
 use crate::requirements::{Requirement, Supports};
+use crate::scaffold::{PackageTemplate, ScaffoldFile};
 use crate::system::System;
 use crate::{
-    graph::{Graph, GraphNodeReference, Pending},
-    secrets::{Secret, SecretId, Secrets},
+    apply::PreviousInstall,
+    graph::{ApplySequence, Graph, GraphNodeReference, Pending},
+    secrets::{credential_path, Credential, Secret, SecretId, Secrets},
     Dirs, StateDirs, VersionedPath,
 };
 use path::*;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::path::Path as StdPath;
 use std::{
+    collections::{BTreeSet, HashMap},
     fmt::{Debug, Display},
     path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 use typemap::{Key, TypeMap};
 
+pub mod alternatives;
 pub mod apply;
 pub mod apt;
+pub mod command;
+pub mod credentials;
+pub mod discovery;
+pub mod exposed;
+pub mod firewall;
 pub mod fs;
+pub mod grub;
+pub mod health;
+pub mod ignore;
+pub mod kv;
+pub mod limits;
+pub mod mount;
 pub mod mysql;
 pub mod nginx;
+pub mod packages;
+pub mod patch;
 pub mod path;
 pub mod php_fpm;
+pub mod ports;
+pub mod reboot;
+pub mod service;
+pub mod sftp;
+pub mod source;
+pub mod ssh;
 pub mod systemd;
+pub mod udev;
 pub mod users;
+pub mod version;
+pub mod workers;
 
 #[derive(Serialize, Deserialize)]
 pub struct PackageConfig<C> {
+    /// The `[files]` section, which determines which files in the package can be used.
+    #[serde(default)]
+    files: FileFilter,
+
+    /// The `[source]` section, which fetches the files of the package from elsewhere instead of the package directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<SourceConfig>,
+
     #[serde(flatten)]
     config: C,
 }
@@ -37,6 +84,8 @@ pub struct PackageInfo {
     name: String,
     path: PathBuf,
     files: Vec<PathBuf>,
+    filter: FileFilter,
+    checksum: Sha3,
 }
 
 pub struct Package<C> {
@@ -53,6 +102,11 @@ impl<C> Package<C> {
         &self.config.config
     }
 
+    /// A checksum of the names and contents of all files in the package.
+    pub fn checksum(&self) -> Sha3 {
+        self.info.checksum
+    }
+
     pub fn root<'a>(&'a self) -> Path<Source<'a>> {
         Path {
             base: self.info.path.clone(),
@@ -63,24 +117,52 @@ impl<C> Package<C> {
     }
 }
 
+/// How exposed files are made available in the install.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExposeMode {
+    /// The files are copied into the install, so that later changes to the package do not affect the install.
+    #[default]
+    Copy,
+
+    /// A symlink to the package is created instead of copying the files.
+    /// This avoids copying large read-only assets, but changes to the package are immediately visible to all installs.
+    Link,
+}
+
 struct ExposedPath {
     source: PathBuf,
     target: PathBuf,
+    mode: ExposeMode,
+
+    /// The files and directories below `source` that should be copied.
+    files: Vec<PathBuf>,
+
+    /// The total size of the exposed files, in bytes.
+    size: u64,
 }
 
-pub trait Builder {
-    type PackageConfig: DeserializeOwned;
+pub trait Builder: Sync {
+    type PackageConfig: DeserializeOwned + Sync;
     type Data;
+    type Prepared: Send;
     type Requirement: Requirement + Display;
-    type BuildError: std::error::Error;
+    type BuildError: std::error::Error + Send;
 
     fn start_build(
         &self,
         context: &mut Context<Self::Requirement>,
     ) -> Result<Self::Data, Self::BuildError>;
+
+    /// Does the work for a single package that does not need access to the graph, such as rendering templates.
+    /// Packages are prepared in parallel, before any package is built. The result is passed to `build_package`.
+    fn prepare_package(
+        &self,
+        package: &Package<Self::PackageConfig>,
+    ) -> Result<Self::Prepared, Self::BuildError>;
     fn build_package(
         &self,
         package: &Package<Self::PackageConfig>,
+        prepared: Self::Prepared,
         context: &mut Context<Self::Requirement>,
         data: &mut Self::Data,
     ) -> Result<(), Self::BuildError>;
@@ -89,6 +171,51 @@ pub trait Builder {
         context: &mut Context<Self::Requirement>,
         data: Self::Data,
     ) -> Result<(), Self::BuildError>;
+
+    /// Returns the files of a new package named `name`, for `side new-package`. Paths are relative to the package directory.
+    /// The files must include a `package.toml` that can be loaded as `PackageConfig`. Returns `None` if the builder has no such template.
+    fn package_template(
+        &self,
+        _template: PackageTemplate,
+        _name: &str,
+    ) -> Option<Vec<ScaffoldFile>> {
+        None
+    }
+
+    /// Returns the conversions from older formats of `Requirement`, in order. Databases of installs are written with the schema version of these migrations,
+    /// and databases with an older schema version are migrated when they are read. Register a migration whenever the serialization of a requirement changes.
+    fn migrations() -> Migrations {
+        Migrations::new()
+    }
+
+    /// Runs before `sequence` is applied to `system`, after the preflight checks and the review, for example to put up a maintenance page
+    /// or to drain the system from a load balancer. An error aborts the apply before anything has been changed.
+    fn pre_apply<S: System>(
+        &self,
+        _system: &mut S,
+        _sequence: &ApplySequence<Self::Requirement>,
+    ) -> Result<(), Self::BuildError> {
+        Ok(())
+    }
+
+    /// Runs after `sequence` has been applied, also when some subtrees failed with `--isolate-failures`.
+    /// An error fails the command after the install has been saved and made current, but does not revert the apply.
+    fn post_apply<S: System>(
+        &self,
+        _system: &mut S,
+        _sequence: &ApplySequence<Self::Requirement>,
+    ) -> Result<(), Self::BuildError> {
+        Ok(())
+    }
+
+    /// Runs after a failed apply of `sequence` has been reverted, instead of `post_apply`. An error is logged, and the apply fails with its original error.
+    fn post_revert<S: System>(
+        &self,
+        _system: &mut S,
+        _sequence: &ApplySequence<Self::Requirement>,
+    ) -> Result<(), Self::BuildError> {
+        Ok(())
+    }
 }
 
 pub struct GeneratedFile {
@@ -127,11 +254,15 @@ pub struct Context<'a, R> {
     source_root: Path<Source<'a>>,
     files: Vec<GeneratedFile>,
     deleted_files: Vec<DeletedFile>,
+    deleted: DeletedFiles,
     exposed: Vec<ExposedPath>,
+    outputs: Outputs,
     package_name: String,
+    package_version: Option<GraphNodeReference>,
 
     info: &'a PackageInfo,
     install: &'a StateDirs,
+    previous: PreviousInstall<'a, R>,
     secrets: &'a mut Secrets,
 
     graph: &'a mut Graph<R, Pending>,
@@ -142,7 +273,12 @@ pub struct Context<'a, R> {
     exposed_files_path: VersionedPath,
     userdata_path: Option<Path<Userdata>>,
     backup_path: Option<Path<Backup>>,
-    deleted_path: PathBuf,
+    originals_path: PathBuf,
+    patches_path: PathBuf,
+    alternatives_path: PathBuf,
+    reboots_path: PathBuf,
+    credentials_path: PathBuf,
+    host_keys_path: PathBuf,
 
     state: &'a mut TypeMap,
 }
@@ -150,7 +286,9 @@ pub struct Context<'a, R> {
 pub struct MinimalContext {
     files: Vec<GeneratedFile>,
     deleted_files: Vec<DeletedFile>,
+    deleted: DeletedFiles,
     exposed: Vec<ExposedPath>,
+    outputs: Outputs,
 }
 
 impl<'a, R: Requirement> Context<'a, R> {
@@ -158,6 +296,7 @@ impl<'a, R: Requirement> Context<'a, R> {
         info: &'a PackageInfo,
         dirs: &Dirs,
         install: &'a StateDirs,
+        previous: PreviousInstall<'a, R>,
         secrets: &'a mut Secrets,
         graph: &'a mut Graph<R, Pending>,
         state: &'a mut TypeMap,
@@ -165,6 +304,7 @@ impl<'a, R: Requirement> Context<'a, R> {
         let p = Context {
             info,
             install,
+            previous,
             source_root: Path::<Source> {
                 base: info.path.to_owned(),
                 path: PathBuf::new(),
@@ -175,15 +315,23 @@ impl<'a, R: Requirement> Context<'a, R> {
             graph,
             files: Vec::new(),
             deleted_files: Vec::new(),
+            deleted: DeletedFiles::default(),
             exposed: Default::default(),
+            outputs: Outputs::default(),
             package_name: info.name.to_string(),
+            package_version: None,
             generated_path: install.generated_path(&info.name),
             chroots_path: None,
             config_files_path: None,
             exposed_files_path: install.exposed_path(&info.name),
             userdata_path: None,
             backup_path: None,
-            deleted_path: dirs.deleted.clone(),
+            originals_path: dirs.originals_path(install.version),
+            patches_path: dirs.patches_path(),
+            alternatives_path: dirs.alternatives_path(),
+            reboots_path: dirs.reboots.clone(),
+            credentials_path: dirs.credentials.clone(),
+            host_keys_path: dirs.host_keys.clone(),
             state,
         };
 
@@ -201,19 +349,86 @@ impl<'a, R: Requirement> Context<'a, R> {
         self.graph.add(node, deps)
     }
 
+    /// Marks `nodes` as part of the checkpoint `name`, such as `"all sites deployed"`.
+    /// `side apply --until <name>` only applies the nodes of the checkpoint and their dependencies, so that large changes can be rolled out in stages.
+    pub fn checkpoint<'r, I: IntoIterator<Item = &'r GraphNodeReference>>(
+        &mut self,
+        name: &str,
+        nodes: I,
+    ) {
+        self.graph.add_checkpoint(name, nodes)
+    }
+
+    /// Adds a node that creates a directory, and registers it as the node that files in the directory depend on.
+    fn add_directory<'r, I: IntoIterator<Item = &'r GraphNodeReference>>(
+        &mut self,
+        directory: CreateDirectory,
+        deps: I,
+    ) -> GraphNodeReference
+    where
+        R: Supports<CreateDirectory>,
+    {
+        add_directory(self.graph, self.state, directory, deps)
+    }
+
+    /// Returns the node that creates the directory `path`, or `None` for `/`.
+    /// Directories that have not been created by any package are added along with their parents.
+    /// They are kept when undone, because they may already exist.
+    pub fn directory(&mut self, path: &StdPath) -> Option<GraphNodeReference>
+    where
+        R: Supports<CreateDirectory>,
+    {
+        directory(self.graph, self.state, path)
+    }
+
+    /// The install that was current when this build started.
+    /// Can be used to make decisions based on what was deployed before, for example to only run a migration when upgrading from an older version.
+    pub fn previous(&self) -> PreviousInstall<'a, R> {
+        self.previous
+    }
+
+    /// Returns a node that records the checksum of the source files of the current package.
+    /// Requirements that are derived from the source files, but whose own parameters do not change when the files change,
+    /// should depend on this node so that they are considered changed whenever the sources change.
+    pub fn package_version(&mut self) -> GraphNodeReference
+    where
+        R: Supports<PackageVersion>,
+    {
+        let info = self.info;
+        let graph = &mut self.graph;
+        *self
+            .package_version
+            .get_or_insert_with(|| graph.add(PackageVersion::new(&info.name, info.checksum), []))
+    }
+
     pub fn into_minimal(self) -> MinimalContext {
         MinimalContext {
             files: self.files,
             deleted_files: self.deleted_files,
+            deleted: self.deleted,
             exposed: self.exposed,
+            outputs: self.outputs,
         }
     }
 
+    /// Declares an output named `<package>.<name>`, which is saved with the install and can be read with `side output`.
+    /// Panics if the package already declared an output with the same name.
+    pub fn output<T: Serialize>(&mut self, name: &str, value: T) {
+        let name = format!("{}.{}", self.package_name, name);
+        let value = serde_json::to_value(value).expect("outputs must be serializable to JSON");
+        let previous = self.outputs.insert(name.clone(), value);
+        assert!(previous.is_none(), "output {} is declared twice", name);
+    }
+
     pub fn package_root(&self) -> Path<Source<'a>> {
         self.source_root.clone()
     }
 
     pub fn expose(&mut self, path: &Path<Source>) -> Path<Exposed> {
+        self.expose_with(path, ExposeMode::Copy)
+    }
+
+    pub fn expose_with(&mut self, path: &Path<Source>, mode: ExposeMode) -> Path<Exposed> {
         let full_path = path.full_path();
         if !full_path.exists() {
             panic!("Exposed path does not exist: {:?}", full_path);
@@ -235,9 +450,24 @@ impl<'a, R: Requirement> Context<'a, R> {
             }
         }
 
+        let files = self
+            .info
+            .files
+            .iter()
+            .filter(|file| file.starts_with(&full_path) && **file != full_path)
+            .cloned()
+            .collect::<Vec<_>>();
+        let size = if files.is_empty() {
+            file_size(&full_path)
+        } else {
+            files.iter().map(|file| file_size(file)).sum()
+        };
         self.exposed.push(ExposedPath {
             source: full_path,
             target: target.full_path(),
+            mode,
+            files,
+            size,
         });
 
         Path {
@@ -248,6 +478,25 @@ impl<'a, R: Requirement> Context<'a, R> {
         }
     }
 
+    /// Like [`Context::expose`], but also records the checksums of the exposed files in the graph, so that `side verify` detects changes to them.
+    /// Requirements that use the returned path depend on the check, so they are only applied once the files have been copied intact.
+    pub fn expose_verified(&mut self, path: &Path<Source>) -> Path<Exposed>
+    where
+        R: Supports<ExposedFiles>,
+    {
+        let exposed = self.expose(path);
+        let hashed = {
+            let added = self.exposed.last().unwrap();
+            ExposedFiles::hash(added.target.clone(), &added.source, &added.files)
+        };
+        let files = hashed.unwrap_or_else(|e| {
+            panic!("Unable to hash exposed path {:?}: {}", path.full_path(), e)
+        });
+        let node = self.graph.add(files, []);
+
+        exposed.with_node(node)
+    }
+
     pub fn create_chroot<P: AsRef<StdPath>>(&mut self, name: P) -> Path<Chroot>
     where
         R: Supports<CreateDirectory>,
@@ -257,7 +506,7 @@ impl<'a, R: Requirement> Context<'a, R> {
 
         let chroots_root = self.chroots_root();
         let path = chroots_root.join(name).full_path();
-        let node = self.graph.add(
+        let node = self.add_directory(
             CreateDirectory::new_without_cleanup(path.clone()),
             chroots_root.node.as_ref(),
         );
@@ -279,7 +528,7 @@ impl<'a, R: Requirement> Context<'a, R> {
 
         let userdata_root = self.userdata_root();
         let path = userdata_root.join_unchecked(name).unwrap().full_path();
-        let node = self.graph.add(
+        let node = self.add_directory(
             CreateDirectory::new_without_cleanup(path.clone()),
             userdata_root.node.as_ref(),
         );
@@ -299,10 +548,13 @@ impl<'a, R: Requirement> Context<'a, R> {
         let install = self.install;
         let info = self.info;
         let graph = &mut self.graph;
+        let state = &mut self.state;
         self.chroots_path
             .get_or_insert_with(|| {
                 let chroots_path = install.chroot_path(&info.name);
-                let chroots_ref = graph.add(
+                let chroots_ref = add_directory(
+                    graph,
+                    state,
                     CreateDirectory::new_without_cleanup(chroots_path.clone()),
                     [],
                 );
@@ -323,16 +575,21 @@ impl<'a, R: Requirement> Context<'a, R> {
         let install = self.install;
         let info = self.info;
         let graph = &mut self.graph;
+        let state = &mut self.state;
         self.userdata_path
             .get_or_insert_with(|| {
                 let userdata_path = install.userdata_path(&info.name);
-                let parent_ref = graph.add(
+                let parent_ref = add_directory(
+                    graph,
+                    state,
                     CreateDirectory::new_without_cleanup(
                         userdata_path.clone().parent().unwrap().to_path_buf(),
                     ),
                     [],
                 );
-                let userdata_ref = graph.add(
+                let userdata_ref = add_directory(
+                    graph,
+                    state,
                     CreateDirectory::new_without_cleanup(userdata_path.clone()),
                     &[parent_ref],
                 );
@@ -353,10 +610,13 @@ impl<'a, R: Requirement> Context<'a, R> {
         let install = self.install;
         let info = self.info;
         let graph = &mut self.graph;
+        let state = &mut self.state;
         self.config_files_path
             .get_or_insert_with(|| {
                 let config_files_path = install.config_path(&info.name);
-                let config_files_ref = graph.add(
+                let config_files_ref = add_directory(
+                    graph,
+                    state,
                     CreateDirectory::new_without_cleanup(config_files_path.clone()),
                     &[],
                 );
@@ -377,10 +637,13 @@ impl<'a, R: Requirement> Context<'a, R> {
         let install = self.install;
         let info = self.info;
         let graph = &mut self.graph;
+        let state = &mut self.state;
         self.backup_path
             .get_or_insert_with(|| {
                 let backup_path = install.backup_path(&info.name);
-                let backup_ref = graph.add(
+                let backup_ref = add_directory(
+                    graph,
+                    state,
                     CreateDirectory::new_without_cleanup(backup_path.clone()),
                     &[],
                 );
@@ -411,19 +674,38 @@ impl<'a, R: Requirement> Context<'a, R> {
         let full_path = path.full_path();
         assert!(full_path.is_absolute());
 
-        let backup_path = self
-            .deleted_path
-            .join(&full_path.strip_prefix("/").unwrap());
-        self.deleted_files.push(DeletedFile {
-            save_to: backup_path.clone(),
+        let backup_path = self.original_file_path(Delete::NAME, &full_path);
+        self.deleted.push(deleted::DeletedFile {
+            path: full_path.clone(),
+            backup: backup_path.clone(),
         });
         self.graph
             .add(Delete::new(full_path, backup_path), path.node.iter())
     }
 
+    /// Returns the path where the original of the system file `path` is saved before a requirement of kind `kind` overwrites or deletes it.
+    /// Paths are versioned, so that an original that is saved by one install is never overwritten by a later install.
+    /// If the previous install already had such a requirement, its original was saved by an earlier install and is kept where it is.
+    fn original_file_path(&mut self, kind: &'static str, path: &StdPath) -> PathBuf {
+        let previous = self.previous;
+        let saved = self
+            .state
+            .entry::<SimpleKv<SavedOriginals>>()
+            .or_insert_with(|| SavedOriginals::of(previous));
+        let backup = match saved.0.get(&(kind, path.to_path_buf())) {
+            Some(backup) => backup.clone(),
+            None => self.originals_path.join(path.strip_prefix("/").unwrap()),
+        };
+        self.deleted_files.push(DeletedFile {
+            save_to: backup.clone(),
+        });
+
+        backup
+    }
+
     pub fn existing<P: AsRef<StdPath>>(&mut self, path: P) -> Path<Existing> {
         let path = path.as_ref();
-        println!("TODO: Verify must_exist {}", path.display());
+        tracing::debug!("TODO: Verify must_exist {}", path.display());
         // self.must_exist.push(path.to_path_buf());
         Path {
             base: path.to_path_buf(),
@@ -433,12 +715,69 @@ impl<'a, R: Requirement> Context<'a, R> {
         }
     }
 
+    /// Allocates a TCP port for `name` from [`ports::DEFAULT_RANGE`]. The package keeps the same port in later builds, unless another package claims it first.
+    pub fn port(&mut self, name: &str) -> Result<Port, PortError> {
+        let package = self.package_name.clone();
+        self.state::<PortRegistry>().claim(&package, name, None)
+    }
+
+    /// Claims a fixed TCP port for `name`. Fails if another package uses the port in this build.
+    pub fn fixed_port(&mut self, name: &str, port: u16) -> Result<Port, PortError> {
+        let package = self.package_name.clone();
+        self.state::<PortRegistry>()
+            .claim(&package, name, Some(port))
+    }
+
+    /// Claims `key` for the current package, for values that must be unique across all packages, such as user names, unit names and nginx server names.
+    /// `kind` describes the values, for example `"user"`. A conflict fails the build after the current package, even if the error is ignored.
+    pub fn claim_unique(&mut self, kind: &'static str, key: &str) -> Result<(), ConflictError> {
+        let package = self.package_name.clone();
+        self.state::<Claims>().claim(kind, key, package)
+    }
+
+    /// The distribution that is being built for, which is detected from the system when the build starts.
+    pub fn distro(&mut self) -> Distro {
+        *self.state::<Distro>()
+    }
+
+    /// The architecture that is being built for, which is detected from the system when the build starts.
+    /// Use it to select architecture-specific downloads, for example with [`crate::arch::PerArch`].
+    pub fn arch(&mut self) -> Arch {
+        *self.state::<Arch>()
+    }
+
+    /// The service manager that runs the services, which is detected from the system when the build starts.
+    /// Services are always described as systemd units, see [`service`] for how other service managers run them.
+    pub fn service_manager(&mut self) -> ServiceManager {
+        *self.state::<ServiceManager>()
+    }
+
     pub fn secret<T: Secret + std::fmt::Debug>(&mut self, name: &str) -> T {
         self.secrets
             .get_or_create(SecretId::new(self.package_name.clone(), name.to_string()))
             .unwrap()
     }
 
+    /// Like [`Context::secret`], but also delivers the secret to services as a systemd credential.
+    /// The secret is written to the credentials directory, which only root can read, rather than to the config tree.
+    /// Add the returned [`SystemdCredential`] to a service with [`crate::config::systemd::Exec::credential`].
+    pub fn credential<T: Credential + std::fmt::Debug>(
+        &mut self,
+        name: &str,
+    ) -> (T, SystemdCredential) {
+        let id = SecretId::new(self.package_name.clone(), name.to_string());
+        let path = credential_path(&self.credentials_path, &id);
+        let secret: T = self.secrets.credential(id).unwrap();
+        let credential = SystemdCredential::new(name, path, &secret.credential_data());
+
+        (secret, credential)
+    }
+
+    /// The key-value store for small facts that are kept between builds, see [`kv`].
+    pub fn kv(&mut self) -> &mut KvStore {
+        self.state::<KvStore>()
+    }
+
     pub fn state<T: Default + 'static>(&mut self) -> &mut T {
         self.state
             .entry::<SimpleKv<T>>()
@@ -446,29 +785,277 @@ impl<'a, R: Requirement> Context<'a, R> {
     }
 }
 
+/// The size of the file at `path`, or 0 for directories.
+fn file_size(path: &StdPath) -> u64 {
+    match path.metadata() {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => 0,
+    }
+}
+
 struct SimpleKv<T>(T);
 
+/// The nodes that create directories, by path.
+#[derive(Default)]
+struct Directories(HashMap<PathBuf, GraphNodeReference>);
+
+/// Where the previous install saved the originals of system files, by the kind of the requirement and the path of the file.
+struct SavedOriginals(HashMap<(&'static str, PathBuf), PathBuf>);
+
+impl SavedOriginals {
+    fn of<R: Requirement>(previous: PreviousInstall<R>) -> Self {
+        SavedOriginals(
+            previous
+                .requirements()
+                .flat_map(|requirement| {
+                    requirement.saved_original().map(|(path, backup)| {
+                        (
+                            (requirement.kind(), path.to_path_buf()),
+                            backup.to_path_buf(),
+                        )
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+fn add_directory<'r, R, I>(
+    graph: &mut Graph<R, Pending>,
+    state: &mut TypeMap,
+    directory: CreateDirectory,
+    deps: I,
+) -> GraphNodeReference
+where
+    R: Requirement + Supports<CreateDirectory>,
+    I: IntoIterator<Item = &'r GraphNodeReference>,
+{
+    let path = directory.path().to_path_buf();
+    let node = graph.add(directory, deps);
+    state
+        .entry::<SimpleKv<Directories>>()
+        .or_insert_with(Default::default)
+        .0
+        .insert(path, node);
+
+    node
+}
+
+fn directory<R>(
+    graph: &mut Graph<R, Pending>,
+    state: &mut TypeMap,
+    path: &StdPath,
+) -> Option<GraphNodeReference>
+where
+    R: Requirement + Supports<CreateDirectory>,
+{
+    assert!(path.is_absolute());
+    let directories = state
+        .entry::<SimpleKv<Directories>>()
+        .or_insert_with(Default::default);
+    if let Some(node) = directories.0.get(path) {
+        return Some(*node);
+    }
+
+    let parent = directory(graph, state, path.parent()?);
+    Some(add_directory(
+        graph,
+        state,
+        CreateDirectory::new_without_cleanup(path.to_path_buf()),
+        parent.iter(),
+    ))
+}
+
+/// The values that have been claimed with [`Context::claim_unique`], and the package that claimed them.
+#[derive(Default)]
+struct Claims {
+    claimed: HashMap<(&'static str, String), String>,
+    conflicts: Vec<ConflictError>,
+}
+
+impl Claims {
+    fn claim(
+        &mut self,
+        kind: &'static str,
+        key: &str,
+        package: String,
+    ) -> Result<(), ConflictError> {
+        match self.claimed.get(&(kind, key.to_owned())) {
+            Some(first) => {
+                let conflict = ConflictError {
+                    kind,
+                    key: key.to_owned(),
+                    first: first.clone(),
+                    second: package,
+                };
+                self.conflicts.push(conflict.clone());
+                Err(conflict)
+            }
+            None => {
+                self.claimed.insert((kind, key.to_owned()), package);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Returns the first conflict that was found by [`Context::claim_unique`] since the last call.
+fn take_conflict(state: &mut TypeMap) -> Option<ConflictError> {
+    let claims = state.get_mut::<SimpleKv<Claims>>()?;
+    let conflict = claims.conflicts.first().cloned();
+    claims.conflicts.clear();
+    conflict
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{} {:?} is declared by both {} and {}", .kind, .key, .first, .second)]
+pub struct ConflictError {
+    pub kind: &'static str,
+    pub key: String,
+    pub first: String,
+    pub second: String,
+}
+
 impl<T: 'static> Key for SimpleKv<T> {
     type Value = T;
 }
 
-fn scan_files<S: System>(path: &StdPath, system: &mut S) -> Result<Vec<PathBuf>, S::Error> {
-    let mut result = Vec::new();
+/// Returns all files and directories below `root` that are included by `filter`.
+/// Excluded directories are not scanned. Directories that are not included themselves are returned if they contain an included file.
+pub(crate) fn scan_files<S: System>(
+    root: &StdPath,
+    filter: &FileFilter,
+    system: &mut S,
+) -> Result<Vec<PathBuf>, S::Error> {
+    let mut result = BTreeSet::new();
     let mut stack = Vec::new();
-    stack.push(path.to_path_buf());
+    stack.push(root.to_path_buf());
 
     while let Some(working_path) = stack.pop() {
         for entry in system.read_dir(&working_path)? {
             let path = working_path.join(entry);
+            let relative = path.strip_prefix(root).unwrap();
+            if filter.is_excluded(relative) {
+                continue;
+            }
+
             if system.path_is_dir(&path)? {
                 stack.push(path.clone());
             }
 
-            result.push(path);
+            if filter.is_included(relative) {
+                result.extend(
+                    path.ancestors()
+                        .take_while(|p| *p != root)
+                        .map(StdPath::to_path_buf),
+                );
+            }
+        }
+    }
+
+    Ok(result.into_iter().collect())
+}
+
+/// Computes a checksum over the paths (relative to `root`) and contents of `files`, independent of the order of `files`.
+fn checksum_files<S: System>(
+    root: &StdPath,
+    files: &[PathBuf],
+    system: &mut S,
+) -> Result<Sha3, S::Error> {
+    let mut entries = Vec::new();
+    for path in files {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let contents = if system.path_is_dir(path)? {
+            None
+        } else {
+            Some(system.file_sha3(path)?)
+        };
+
+        entries.push((relative.to_path_buf(), contents));
+    }
+
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut data = Vec::new();
+    for (path, contents) in entries {
+        data.extend_from_slice(path.to_string_lossy().as_bytes());
+        data.push(0);
+        if let Some(contents) = contents {
+            data.extend_from_slice(contents.to_string().as_bytes());
         }
+
+        data.push(b'\n');
     }
 
-    Ok(result)
+    Ok(Sha3::hash(&data))
+}
+
+/// An error that occurs while loading packages or assembling the graph of a build.
+/// `E` is the error type of the [`Builder`].
+#[derive(Debug, thiserror::Error)]
+pub enum BuildPhaseError<S: System, E: std::error::Error> {
+    #[error("unable to load secrets from {}: {}", .0.display(), .1)]
+    UnableToLoadSecrets(PathBuf, S::Error),
+
+    #[error("unable to save secrets to {}: {}", .0.display(), .1)]
+    UnableToSaveSecrets(PathBuf, S::Error),
+
+    #[error("unable to save credentials to {}: {}", .0.display(), .1)]
+    UnableToSaveCredentials(PathBuf, S::Error),
+
+    #[error("unable to load port allocations from {}: {}", .0.display(), .1)]
+    UnableToLoadPorts(PathBuf, S::Error),
+
+    #[error("unable to save port allocations to {}: {}", .0.display(), .1)]
+    UnableToSavePorts(PathBuf, S::Error),
+
+    #[error("{}", .0)]
+    KvStoreFailed(KvError<S>),
+
+    #[error("unable to scan {}: {}", .0.display(), .1)]
+    UnableToScan(PathBuf, S::Error),
+
+    #[error("{} is not a directory, but the packages directory may only contain packages", .0.display())]
+    NotAPackage(PathBuf),
+
+    #[error("{:?} is reserved and cannot be used as a package name", .0)]
+    ReservedPackageName(String),
+
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToReadConfig(PathBuf, S::Error),
+
+    #[error("invalid package configuration in {}: {}", .0.display(), .1)]
+    InvalidConfig(PathBuf, toml::de::Error),
+
+    #[error("unable to fetch the source of package {}: {}", .0, .1)]
+    SourceFailed(String, SourceError<S>),
+
+    #[error("unable to prepare package {}: {}", .0, .1)]
+    PrepareFailed(String, E),
+
+    #[error("unable to build package {}: {}", .0, .1)]
+    BuildFailed(String, E),
+
+    #[error("{}", .0)]
+    Conflict(ConflictError),
+
+    #[error("unable to detect the distribution: {}", .0)]
+    UnableToDetectDistro(S::Error),
+
+    #[error("unable to detect the architecture: {}", .0)]
+    UnableToDetectArch(ArchError<S>),
+
+    #[error("unable to detect the service manager: {}", .0)]
+    UnableToDetectServiceManager(S::Error),
+}
+
+/// Returns true if `name` cannot be used for a package, because the name is used for the graph nodes or backups of libside itself.
+pub fn is_reserved_package_name(name: &str) -> bool {
+    name == "_start"
+        || name == "_finish"
+        || name == crate::ORIGINALS
+        || name == crate::PATCHES
+        || name == crate::ALTERNATIVES
+        || name == crate::UNDONE
 }
 
 pub struct Packages<C> {
@@ -476,105 +1063,511 @@ pub struct Packages<C> {
 }
 
 impl<C: DeserializeOwned> Packages<C> {
-    pub fn load<S: System>(dirs: &Dirs, system: &mut S) -> Result<Packages<C>, S::Error> {
+    pub fn load<S: System, E: std::error::Error>(
+        dirs: &Dirs,
+        system: &mut S,
+    ) -> Result<Packages<C>, BuildPhaseError<S, E>> {
         let package_dir = &dirs.packages;
         let mut packages = Vec::new();
-        for package_path in system.read_dir(package_dir)? {
+        let mut package_paths = system
+            .read_dir(package_dir)
+            .map_err(|e| BuildPhaseError::UnableToScan(package_dir.clone(), e))?;
+
+        // The order of the packages determines the order of the nodes in the graph, which should not depend on the order of `read_dir`
+        package_paths.sort();
+        for package_path in package_paths {
             let path = PathBuf::from(&package_path);
             let path = package_dir.join(path);
-            if system.path_is_dir(&path)? {
-                let config =
-                    toml::from_slice(&system.file_contents(&path.join("package.toml"))?).unwrap();
-                let name = path.file_name().unwrap().to_string_lossy().to_string();
-
-                if name == "_start" || name == "_finish" {
-                    panic!("Invalid package name: {}", name);
-                }
-
-                let info = PackageInfo {
-                    name,
-                    files: scan_files(&path, system)?,
-                    path,
-                };
+            let scan_error = |e| BuildPhaseError::UnableToScan(path.clone(), e);
+            if !system.path_is_dir(&path).map_err(scan_error)? {
+                return Err(BuildPhaseError::NotAPackage(path));
+            }
 
-                packages.push(Package { info, config });
-            } else {
-                panic!("There are files in the packages directory: {:?}", path);
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if is_reserved_package_name(&name) {
+                return Err(BuildPhaseError::ReservedPackageName(name));
             }
+
+            let config_path = path.join("package.toml");
+            let contents = system
+                .file_contents(&config_path)
+                .map_err(|e| BuildPhaseError::UnableToReadConfig(config_path.clone(), e))?;
+            let config: PackageConfig<C> = toml::from_slice(&contents)
+                .map_err(|e| BuildPhaseError::InvalidConfig(config_path, e))?;
+
+            let path = match &config.source {
+                Some(source) => {
+                    let _span = tracing::info_span!("source", package = %name).entered();
+                    source
+                        .fetch(&path, &dirs.sources, system)
+                        .map_err(|e| BuildPhaseError::SourceFailed(name.clone(), e))?
+                }
+                None => path,
+            };
+
+            let scan_error = |e| BuildPhaseError::UnableToScan(path.clone(), e);
+            let filter = config.files.clone();
+            let files = scan_files(&path, &filter, system).map_err(scan_error)?;
+            let info = PackageInfo {
+                name,
+                checksum: checksum_files(&path, &files, system).map_err(scan_error)?,
+                files,
+                filter,
+                path,
+            };
+
+            packages.push(Package { info, config });
         }
 
         Ok(Packages { packages })
     }
 }
 
+/// Calls [`Builder::prepare_package`] for all packages, spread over as many threads as there are CPUs.
+/// The results are returned in the same order as `packages`.
+fn prepare_packages<S: System, B: Builder>(
+    builder: &B,
+    packages: &[Package<B::PackageConfig>],
+) -> Result<Vec<B::Prepared>, BuildPhaseError<S, B::BuildError>> {
+    let threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(packages.len());
+    let next = AtomicUsize::new(0);
+    let mut results = std::thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(package) = packages.get(index) else {
+                            break results;
+                        };
+
+                        let _span = tracing::info_span!(
+                            "package",
+                            name = %package.info.name,
+                            phase = "prepare"
+                        )
+                        .entered();
+                        tracing::info!("Preparing package {}..", package.info.name);
+                        results.push((index, builder.prepare_package(package)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| match worker.join() {
+                Ok(results) => results,
+                Err(panic) => std::panic::resume_unwind(panic),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    results.sort_by_key(|(index, _)| *index);
+    results
+        .into_iter()
+        .map(|(index, result)| {
+            result.map_err(|e| BuildPhaseError::PrepareFailed(packages[index].name().to_owned(), e))
+        })
+        .collect()
+}
+
 pub fn run<'d, K, B: Builder<PackageConfig = K>, S: System>(
     dirs: &Dirs,
     system: &mut S,
     packages: Packages<K>,
     install: &'d StateDirs,
-    builder: B,
-) -> Result<PreparedBuild<'d, B::Requirement>, B::BuildError>
+    previous: PreviousInstall<'_, B::Requirement>,
+    builder: &B,
+) -> Result<PreparedBuild<'d, B::Requirement>, BuildPhaseError<S, B::BuildError>>
 where
     B::Requirement: Supports<CreateDirectory>,
 {
     let packages = packages.packages;
+    let prepared = prepare_packages(builder, &packages)?;
     let mut graph = Graph::new();
     let mut contexts = Vec::new();
 
-    let mut secrets = Secrets::load(&dirs.secrets, system).unwrap();
+    let mut secrets = Secrets::load(&dirs.secrets, system)
+        .map_err(|e| BuildPhaseError::UnableToLoadSecrets(dirs.secrets.clone(), e))?;
+    let ports = PortRegistry::load(&dirs.ports, system)
+        .map_err(|e| BuildPhaseError::UnableToLoadPorts(dirs.ports.clone(), e))?;
+    let kv = KvStore::load(&dirs.kv, system).map_err(BuildPhaseError::KvStoreFailed)?;
 
     let start = PackageInfo {
         name: String::from("_start"),
         path: PathBuf::new(),
         files: Vec::new(),
+        filter: FileFilter::default(),
+        checksum: Sha3::default(),
     };
 
-    let mut state = TypeMap::new();
+    let distro = match Distro::detect(system).map_err(BuildPhaseError::UnableToDetectDistro)? {
+        Some(distro) => distro,
+        None => {
+            tracing::warn!(
+                "The distribution in {} is not supported, assuming {}",
+                crate::distro::OS_RELEASE,
+                Distro::default()
+            );
+            Distro::default()
+        }
+    };
+    let arch = match Arch::detect(system).map_err(BuildPhaseError::UnableToDetectArch)? {
+        Some(arch) => arch,
+        None => {
+            tracing::warn!(
+                "The architecture of the system is not supported, assuming {}",
+                Arch::default()
+            );
+            Arch::default()
+        }
+    };
 
-    println!("Preparing global..");
+    let service_manager = ServiceManager::detect(system, distro)
+        .map_err(BuildPhaseError::UnableToDetectServiceManager)?;
+    if !service_manager.is_systemd() {
+        tracing::info!("Running services with {}", service_manager);
+    }
+
+    let mut state = TypeMap::new();
+    state.insert::<SimpleKv<PortRegistry>>(ports);
+    state.insert::<SimpleKv<KvStore>>(kv);
+    state.insert::<SimpleKv<Distro>>(distro);
+    state.insert::<SimpleKv<Arch>>(arch);
+    state.insert::<SimpleKv<ServiceManager>>(service_manager);
+
+    let span = tracing::info_span!("package", name = %start.name, phase = "start").entered();
+    tracing::info!("Preparing global..");
     let mut context = Context::new(
         &start,
         &dirs,
         &install,
+        previous,
         &mut secrets,
         &mut graph,
         &mut state,
     );
-    let mut data = builder.start_build(&mut context)?;
+    let mut data = builder
+        .start_build(&mut context)
+        .map_err(|e| BuildPhaseError::BuildFailed(start.name.clone(), e))?;
     contexts.push(context.into_minimal());
-
-    for package in packages.iter() {
-        println!("Preparing package {}..", package.info.name);
+    if let Some(conflict) = take_conflict(&mut state) {
+        return Err(BuildPhaseError::Conflict(conflict));
+    }
+    graph.assign_package(0..graph.len(), &start.name);
+    drop(span);
+
+    for (package, prepared) in packages.iter().zip(prepared) {
+        let _span =
+            tracing::info_span!("package", name = %package.info.name, phase = "build").entered();
+        tracing::info!("Building package {}..", package.info.name);
+        let first_node = graph.len();
         let mut context = Context::new(
             &package.info,
             &dirs,
             &install,
+            previous,
             &mut secrets,
             &mut graph,
             &mut state,
         );
-        builder.build_package(&package, &mut context, &mut data)?;
+        builder
+            .build_package(&package, prepared, &mut context, &mut data)
+            .map_err(|e| BuildPhaseError::BuildFailed(package.info.name.clone(), e))?;
 
         contexts.push(context.into_minimal());
+        if let Some(conflict) = take_conflict(&mut state) {
+            return Err(BuildPhaseError::Conflict(conflict));
+        }
+        graph.assign_package(first_node..graph.len(), &package.info.name);
     }
 
     let finish = PackageInfo {
         name: String::from("_finish"),
         path: PathBuf::new(),
         files: Vec::new(),
+        filter: FileFilter::default(),
+        checksum: Sha3::default(),
     };
+    let _span = tracing::info_span!("package", name = %finish.name, phase = "finish").entered();
+    let first_node = graph.len();
     let mut context = Context::new(
         &finish,
         &dirs,
         &install,
+        previous,
         &mut secrets,
         &mut graph,
         &mut state,
     );
-    builder.finish_build(&mut context, data)?;
+    builder
+        .finish_build(&mut context, data)
+        .map_err(|e| BuildPhaseError::BuildFailed(finish.name.clone(), e))?;
     contexts.push(context.into_minimal());
+    if let Some(conflict) = take_conflict(&mut state) {
+        return Err(BuildPhaseError::Conflict(conflict));
+    }
+    graph.assign_package(first_node..graph.len(), &finish.name);
+
+    secrets
+        .save(&dirs.secrets, system)
+        .map_err(|e| BuildPhaseError::UnableToSaveSecrets(dirs.secrets.clone(), e))?;
+    secrets
+        .save_credentials(&dirs.credentials, system)
+        .map_err(|e| BuildPhaseError::UnableToSaveCredentials(dirs.credentials.clone(), e))?;
+    let ports = state.remove::<SimpleKv<PortRegistry>>().unwrap_or_default();
+    ports
+        .save(&dirs.ports, system)
+        .map_err(|e| BuildPhaseError::UnableToSavePorts(dirs.ports.clone(), e))?;
+    let kv = state.remove::<SimpleKv<KvStore>>().unwrap_or_default();
+    kv.save(&dirs.kv, system)
+        .map_err(BuildPhaseError::KvStoreFailed)?;
+
+    Ok(PreparedBuild::new(install, contexts, graph, arch))
+}
 
-    secrets.save(&dirs.secrets, system).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_directory, directory,
+        fs::{CreateDirectory, Delete, Sha3},
+        ignore::FileFilter,
+        prepare_packages, scan_files, take_conflict, BuildPhaseError, Builder, Claims, Context,
+        Package, PackageConfig, PackageInfo, SimpleKv,
+    };
+    use crate::apply::{PreviousInstall, SystemState};
+    use crate::graph::{Applied, ApplyResult, Graph, Pending};
+    use crate::requirements::Requirement;
+    use crate::secrets::Secrets;
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+    use crate::Dirs;
+    use std::path::{Path, PathBuf};
+    use typemap::TypeMap;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("cannot prepare {0}")]
+    struct PrepareError(String);
+
+    struct NameBuilder;
+
+    impl Builder for NameBuilder {
+        type PackageConfig = ();
+        type Data = ();
+        type Prepared = String;
+        type Requirement = CreateDirectory;
+        type BuildError = PrepareError;
+
+        fn start_build(
+            &self,
+            _context: &mut Context<Self::Requirement>,
+        ) -> Result<Self::Data, Self::BuildError> {
+            Ok(())
+        }
+
+        fn prepare_package(
+            &self,
+            package: &Package<Self::PackageConfig>,
+        ) -> Result<Self::Prepared, Self::BuildError> {
+            if package.name() == "broken" {
+                Err(PrepareError(package.name().to_owned()))
+            } else {
+                Ok(package.name().to_uppercase())
+            }
+        }
+
+        fn build_package(
+            &self,
+            _package: &Package<Self::PackageConfig>,
+            _prepared: Self::Prepared,
+            _context: &mut Context<Self::Requirement>,
+            _data: &mut Self::Data,
+        ) -> Result<(), Self::BuildError> {
+            Ok(())
+        }
+
+        fn finish_build(
+            &self,
+            _context: &mut Context<Self::Requirement>,
+            _data: Self::Data,
+        ) -> Result<(), Self::BuildError> {
+            Ok(())
+        }
+    }
+
+    fn package(name: &str) -> Package<()> {
+        Package {
+            info: PackageInfo {
+                name: name.to_owned(),
+                path: PathBuf::from("/packages").join(name),
+                files: Vec::new(),
+                filter: FileFilter::default(),
+                checksum: Sha3::default(),
+            },
+            config: PackageConfig {
+                files: FileFilter::default(),
+                source: None,
+                config: (),
+            },
+        }
+    }
+
+    #[test]
+    pub fn prepare_packages_in_order() {
+        let packages = (0..50)
+            .map(|n| package(&format!("p{}", n)))
+            .collect::<Vec<_>>();
+        let prepared = prepare_packages::<LocalSystem, _>(&NameBuilder, &packages).unwrap();
+
+        assert_eq!(
+            prepared,
+            (0..50).map(|n| format!("P{}", n)).collect::<Vec<_>>()
+        );
+        assert!(prepare_packages::<LocalSystem, _>(&NameBuilder, &[])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    pub fn scan_files_skips_excluded() {
+        let root = TempDir::new("scan");
+        for dir in [".git/objects", "public/css", "notes"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            ".git/config",
+            "public/index.html",
+            "public/.index.html.swp",
+            "notes/todo.txt",
+        ] {
+            std::fs::write(root.join(file), b"").unwrap();
+        }
+
+        let filter = FileFilter::new(vec![String::from("*.html")], Vec::new());
+        let files = scan_files(&root, &filter, &mut LocalSystem::new());
+
+        assert_eq!(
+            files.unwrap(),
+            vec![root.join("public"), root.join("public/index.html")]
+        );
+    }
+
+    #[test]
+    pub fn prepare_packages_fails() {
+        let packages = vec![package("a"), package("broken"), package("c")];
+
+        assert!(matches!(
+            prepare_packages::<LocalSystem, _>(&NameBuilder, &packages),
+            Err(BuildPhaseError::PrepareFailed(name, _)) if name == "broken"
+        ));
+    }
 
-    Ok(PreparedBuild::new(install, contexts, graph))
+    /// Builds install `version`, which deletes `file`, on top of the install `previous`.
+    fn build_deletion(
+        dirs: &Dirs,
+        version: u64,
+        previous: &SystemState<Delete>,
+        file: &Path,
+    ) -> Graph<Delete, Applied> {
+        let info = package("motd").info;
+        let install = dirs.get_install(version);
+        let mut system = LocalSystem::new();
+        system.make_dir_all(&dirs.secrets).unwrap();
+        let mut secrets = Secrets::load(&dirs.secrets, &mut system).unwrap();
+        let mut graph = Graph::new();
+        let mut state = TypeMap::new();
+        let mut context = Context::new(
+            &info,
+            dirs,
+            &install,
+            PreviousInstall::new(version - 1, previous),
+            &mut secrets,
+            &mut graph,
+            &mut state,
+        );
+        let existing = context.existing(file);
+        context.delete_default_system_file(existing);
+
+        graph.apply_execution_results(ApplyResult::default())
+    }
+
+    #[test]
+    pub fn undo_deletion_after_later_builds() {
+        let dir = TempDir::new("undo-deletion");
+        let dirs = Dirs::new(dir.join("base"));
+        let file = dir.join("motd");
+        let mut system = LocalSystem::new();
+        system.put_file_contents(&file, b"welcome").unwrap();
+
+        let first = SystemState {
+            graph: build_deletion(&dirs, 1, &SystemState::default(), &file),
+        };
+        let delete = first.graph.requirements().next().unwrap();
+        let (_, backup) = delete.saved_original().unwrap();
+        system.make_dir_all(backup.parent().unwrap()).unwrap();
+        delete.create(&mut system).unwrap();
+
+        let second = SystemState {
+            graph: build_deletion(&dirs, 2, &first, &file),
+        };
+        let third = build_deletion(&dirs, 3, &second, &file);
+        let delete = third.requirements().next().unwrap();
+        assert_eq!(delete.saved_original().unwrap().1, backup);
+
+        delete.delete(&mut system).unwrap();
+        assert_eq!(system.file_contents(&file).unwrap(), b"welcome");
+        assert!(!system.path_exists(backup).unwrap());
+    }
+
+    #[test]
+    pub fn detect_conflicting_claims() {
+        let mut state = TypeMap::new();
+        state.insert::<SimpleKv<Claims>>(Claims::default());
+        let claims = &mut state.get_mut::<SimpleKv<Claims>>().unwrap();
+
+        claims.claim("user", "www", String::from("a")).unwrap();
+        claims.claim("group", "www", String::from("a")).unwrap();
+        let conflict = claims.claim("user", "www", String::from("b")).unwrap_err();
+        claims.claim("user", "www", String::from("c")).unwrap_err();
+
+        assert_eq!(
+            conflict.to_string(),
+            "user \"www\" is declared by both a and b"
+        );
+        assert_eq!(take_conflict(&mut state).unwrap().second, "b");
+        assert!(take_conflict(&mut state).is_none());
+    }
+
+    #[test]
+    pub fn create_missing_directories() {
+        let mut graph = Graph::<CreateDirectory, Pending>::new();
+        let mut state = TypeMap::new();
+        let app = add_directory(
+            &mut graph,
+            &mut state,
+            CreateDirectory::new(PathBuf::from("/srv/app")),
+            [],
+        );
+
+        let config = directory(&mut graph, &mut state, Path::new("/srv/app/config")).unwrap();
+        assert_ne!(config, app);
+        assert_eq!(
+            directory(&mut graph, &mut state, Path::new("/srv/app/config/")),
+            Some(config)
+        );
+        assert_eq!(
+            directory(&mut graph, &mut state, Path::new("/srv/app")),
+            Some(app)
+        );
+        assert_eq!(directory(&mut graph, &mut state, Path::new("/")), None);
+        assert_eq!(graph.len(), 2);
+
+        directory(&mut graph, &mut state, Path::new("/etc/app")).unwrap();
+        assert_eq!(graph.len(), 4);
+    }
 }
@@ -0,0 +1,404 @@
+use crate::batch::Probe;
+use crate::bootstrap;
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, FailureHint, Requirement, Supports};
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path as StdPath, PathBuf};
+
+use super::path::Path;
+use super::Context;
+
+const FSTAB: &str = "/etc/fstab";
+const MOUNTS: &str = "/proc/mounts";
+
+/// A system-wide bind mount of `source` at `target`, with an entry in `/etc/fstab` so that it is restored on boot.
+/// Unlike the bind paths of systemd units, the mount is visible to all processes, for example to a chrooted FTP server.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BindMount {
+    source: PathBuf,
+    target: PathBuf,
+    read_only: bool,
+}
+
+/// Escapes the characters that separate the fields of `/etc/fstab` and `/proc/mounts`.
+fn escape(path: &StdPath) -> String {
+    let mut result = String::new();
+    for c in path.to_str().expect("paths must be valid UTF-8").chars() {
+        match c {
+            ' ' => result.push_str("\\040"),
+            '\t' => result.push_str("\\011"),
+            '\n' => result.push_str("\\012"),
+            '\\' => result.push_str("\\134"),
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+impl BindMount {
+    pub fn new(source: PathBuf, target: PathBuf, read_only: bool) -> BindMount {
+        assert!(
+            source.is_absolute() && target.is_absolute(),
+            "bind mounts need absolute paths: {:?} -> {:?}",
+            source,
+            target
+        );
+
+        BindMount {
+            source,
+            target,
+            read_only,
+        }
+    }
+
+    /// Mounts `source` at `target` after both have been created, and unmounts it before either of them is removed.
+    pub fn add<R: Requirement + Supports<BindMount>, L: Clone, M: Clone>(
+        context: &mut Context<R>,
+        source: &Path<L>,
+        target: &Path<M>,
+        read_only: bool,
+    ) -> GraphNodeReference {
+        context.add_node(
+            BindMount::new(source.full_path(), target.full_path(), read_only),
+            source.graph_node().iter().chain(target.graph_node().iter()),
+        )
+    }
+
+    pub fn target(&self) -> &StdPath {
+        &self.target
+    }
+
+    /// The line in `/etc/fstab` for this mount.
+    pub fn fstab_entry(&self) -> String {
+        format!(
+            "{} {} none {} 0 0",
+            escape(&self.source),
+            escape(&self.target),
+            if self.read_only { "bind,ro" } else { "bind" }
+        )
+    }
+
+    /// Returns `fstab` with the entry for this mount, replacing any other entry for the same target.
+    fn with_entry(&self, fstab: &str) -> String {
+        let mut result = self.without_entry(fstab);
+        result.push_str(&self.fstab_entry());
+        result.push('\n');
+        result
+    }
+
+    /// Returns `fstab` without the entries for the target of this mount.
+    fn without_entry(&self, fstab: &str) -> String {
+        let target = escape(&self.target);
+        let mut result = String::new();
+        for line in fstab.lines() {
+            if line.split_whitespace().nth(1) != Some(target.as_str())
+                || line.trim_start().starts_with('#')
+            {
+                result.push_str(line);
+                result.push('\n');
+            }
+        }
+
+        result
+    }
+
+    /// Returns the mount options of the target in the contents of `/proc/mounts`, or `None` if nothing is mounted there.
+    /// If several filesystems are mounted at the target, the last one is visible.
+    fn mount_options<'a>(&self, mounts: &'a str) -> Option<&'a str> {
+        let target = escape(&self.target);
+        mounts
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .rfind(|fields| fields.len() >= 4 && fields[1] == target)
+            .map(|fields| fields[3])
+    }
+
+    fn read<S: System>(system: &S, path: &str) -> Result<String, BindMountError<S>> {
+        let result = system
+            .execute_command("cat", &[path])
+            .map_err(BindMountError::FailedToStart)?;
+        result.successful()?;
+
+        Ok(result.stdout_as_str().to_owned())
+    }
+
+    fn write_fstab<S: System>(&self, system: &S, fstab: &str) -> Result<(), BindMountError<S>> {
+        system
+            .put_file_contents(StdPath::new(FSTAB), fstab.as_bytes())
+            .map_err(BindMountError::UnableToWriteFstab)
+    }
+
+    fn is_mounted<S: System>(&self, system: &S) -> Result<bool, BindMountError<S>> {
+        Ok(self.mount_options(&Self::read(system, MOUNTS)?).is_some())
+    }
+
+    fn unmount<S: System>(&self, system: &S) -> Result<(), BindMountError<S>> {
+        if self.is_mounted(system)? {
+            system
+                .execute_command("umount", &[self.target.to_str().unwrap()])
+                .map_err(BindMountError::FailedToStart)?
+                .successful()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BindMountError<S: System> {
+    #[error("unable to write /etc/fstab: {0}")]
+    UnableToWriteFstab(S::Error),
+
+    #[error("unable to execute command: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("command failed: {0} {1}")]
+    Unsuccessful(String, String),
+}
+
+impl<S: System> From<(&str, &str)> for BindMountError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        BindMountError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+impl Requirement for BindMount {
+    type CreateError<S: System> = BindMountError<S>;
+    type ModifyError<S: System> = BindMountError<S>;
+    type DeleteError<S: System> = BindMountError<S>;
+    type HasBeenCreatedError<S: System> = BindMountError<S>;
+
+    /// Adds the entry to `/etc/fstab` and mounts the target from it, so that the mount is the same as after a reboot.
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        let fstab = Self::read(system, FSTAB)?;
+        self.write_fstab(system, &self.with_entry(&fstab))?;
+
+        system
+            .execute_command("mount", &[self.target.to_str().unwrap()])
+            .map_err(BindMountError::FailedToStart)?
+            .successful()?;
+
+        Ok(())
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.unmount(system)?;
+        self.create(system)
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        self.unmount(system)?;
+
+        let fstab = Self::read(system, FSTAB)?;
+        self.write_fstab(system, &self.without_entry(&fstab))
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        let target = escape(&self.target);
+        let in_fstab = Self::read(system, FSTAB)?
+            .lines()
+            .any(|line| line.split_whitespace().nth(1) == Some(target.as_str()));
+
+        Ok(in_fstab || self.is_mounted(system)?)
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.target == other.target
+    }
+
+    fn identity(&self) -> String {
+        self.target.display().to_string()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        true
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    /// Checks both the entry in `/etc/fstab` and the mount in `/proc/mounts`, including whether it is read-only.
+    /// `/proc/mounts` does not show the source of a bind mount, so the source is only checked in `/etc/fstab`.
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        let entry = self.fstab_entry();
+        let in_fstab = Self::read(system, FSTAB)
+            .map(|fstab| fstab.lines().any(|line| line.trim() == entry))
+            .unwrap_or(false);
+        let mounted = Self::read(system, MOUNTS)
+            .ok()
+            .and_then(|mounts| {
+                self.mount_options(&mounts)
+                    .map(|options| options.split(',').any(|option| option == "ro"))
+            })
+            .map(|read_only| read_only == self.read_only)
+            .unwrap_or(false);
+
+        Ok(in_fstab && mounted)
+    }
+
+    fn verify_probes(&self) -> Vec<Probe> {
+        vec![
+            Probe::command("cat", &[FSTAB]),
+            Probe::command("cat", &[MOUNTS]),
+        ]
+    }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let target = self.target.to_str()?;
+        let mut script = format!(
+            "echo {} >> {}\n",
+            bootstrap::quote(&self.fstab_entry()),
+            FSTAB
+        );
+        script.push_str(&bootstrap::command("mount", &[target]));
+        Some(script)
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["mount", "umount"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Directory,
+            self.target.display().to_string(),
+            format!(
+                "bind mount of {}{}",
+                self.source.display(),
+                if self.read_only { " (read-only)" } else { "" }
+            ),
+        )
+    }
+
+    const NAME: &'static str = "bind_mount";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A system-wide bind mount, with an entry in `/etc/fstab` so that it is restored on boot.",
+        create: "Adds the entry to `/etc/fstab` and mounts the source at the target. Changes unmount first and mount again.",
+        verify: "Checks the entry in `/etc/fstab` and the mount in `/proc/mounts`, including whether it is read-only.",
+        undo: "Unmounts the target and removes the entry from `/etc/fstab`.",
+    };
+    const DOCS_URL: Option<&'static str> =
+        Some("https://man7.org/linux/man-pages/man8/mount.8.html");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "does not exist",
+            hint: "The source or the target of the bind mount does not exist. Make the requirement depend on the requirements that create them.",
+        },
+        FailureHint {
+            pattern: "must be superuser",
+            hint: "Mounting needs root.",
+        },
+    ];
+}
+
+impl Display for BindMount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "bind-mount({} -> {}{})",
+            self.source.display(),
+            self.target.display(),
+            if self.read_only { ", ro" } else { "" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BindMount;
+    use crate::{requirements::Requirement, system::System, testing::LxcInstance};
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn serialize_deserialize_bind_mount() {
+        let r = BindMount::new(
+            PathBuf::from("/srv/userdata/ftp"),
+            PathBuf::from("/srv/chroots/ftp/data"),
+            true,
+        );
+        let json =
+            r#"{"source":"/srv/userdata/ftp","target":"/srv/chroots/ftp/data","read_only":true}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn update_fstab_and_mounts() {
+        let r = BindMount::new(
+            PathBuf::from("/srv/user data"),
+            PathBuf::from("/srv/chroots/ftp/data"),
+            false,
+        );
+        let fstab = "# /srv/chroots/ftp/data is managed by side\nUUID=1234 / ext4 defaults 0 1\n/old /srv/chroots/ftp/data none bind 0 0\n";
+
+        let updated = r.with_entry(fstab);
+        assert_eq!(updated, "# /srv/chroots/ftp/data is managed by side\nUUID=1234 / ext4 defaults 0 1\n/srv/user\\040data /srv/chroots/ftp/data none bind 0 0\n");
+        assert_eq!(
+            r.without_entry(&updated),
+            "# /srv/chroots/ftp/data is managed by side\nUUID=1234 / ext4 defaults 0 1\n"
+        );
+
+        let mounts = "/dev/sda1 / ext4 rw,relatime 0 0\n/dev/sda1 /srv/chroots/ftp/data ext4 ro,relatime 0 0\n/dev/sda1 /srv/chroots/ftp/data ext4 rw,relatime 0 0\n";
+        assert_eq!(r.mount_options(mounts), Some("rw,relatime"));
+        assert_eq!(r.mount_options("/dev/sda1 / ext4 rw 0 0\n"), None);
+    }
+
+    #[test]
+    #[ignore]
+    pub fn lxc_bind_mount() {
+        let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
+        sys.make_dir(&PathBuf::from("/srv")).unwrap();
+        sys.make_dir(&PathBuf::from("/srv/source")).unwrap();
+        sys.make_dir(&PathBuf::from("/srv/target")).unwrap();
+        sys.put_file_contents(&PathBuf::from("/srv/source/hello"), b"hello")
+            .unwrap();
+        let p = BindMount::new(
+            PathBuf::from("/srv/source"),
+            PathBuf::from("/srv/target"),
+            false,
+        );
+
+        assert!(!p.has_been_created(&mut sys).unwrap());
+        assert!(!p.verify(&mut sys).unwrap());
+
+        p.create(&mut sys).unwrap();
+
+        assert!(p.has_been_created(&mut sys).unwrap());
+        assert!(p.verify(&mut sys).unwrap());
+        assert_eq!(
+            sys.file_contents(&PathBuf::from("/srv/target/hello"))
+                .unwrap(),
+            b"hello"
+        );
+
+        let ro = BindMount::new(
+            PathBuf::from("/srv/source"),
+            PathBuf::from("/srv/target"),
+            true,
+        );
+        assert!(!ro.verify(&mut sys).unwrap());
+        ro.modify(&mut sys).unwrap();
+        assert!(ro.verify(&mut sys).unwrap());
+
+        ro.delete(&mut sys).unwrap();
+
+        assert!(!p.has_been_created(&mut sys).unwrap());
+        assert!(!p.verify(&mut sys).unwrap());
+        assert!(!sys
+            .path_exists(&PathBuf::from("/srv/target/hello"))
+            .unwrap());
+    }
+}
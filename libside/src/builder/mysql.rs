@@ -1,6 +1,9 @@
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use std::path::Path as StdPath;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{collections::HashSet, fmt::Display, path::PathBuf};
+use tracing::info;
 
 use super::apt::AptPackage;
 use super::systemd::ServiceRunning;
@@ -10,8 +13,43 @@ use super::{
     Context, Group, User,
 };
 use crate::graph::GraphNodeReference;
-use crate::requirements::{Requirement, Supports};
-use crate::system::{NeverError, System};
+use crate::report::{Category, Description, Fact};
+use crate::requirements::{Explanation, FailureHint, Requirement, Supports};
+use crate::system::{CommandResult, NeverError, System};
+
+/// Quotes `name` as an identifier, such as the name of a database, by enclosing it in backticks and doubling the backticks it contains.
+/// MySQL does not allow NUL characters in identifiers, so a name that contains them is rejected by the server.
+pub fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+/// Quotes `value` as a string literal, such as a user name or a password, escaping the characters that `mysql_real_escape_string` escapes.
+/// This assumes the server does not run with the `NO_BACKSLASH_ESCAPES` SQL mode, which is not enabled by default.
+pub fn quote_string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('\'');
+    for c in value.chars() {
+        match c {
+            '\0' => result.push_str("\\0"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\x1a' => result.push_str("\\Z"),
+            '\\' | '\'' | '"' => {
+                result.push('\\');
+                result.push(c);
+            }
+            c => result.push(c),
+        }
+    }
+
+    result.push('\'');
+    result
+}
+
+/// The account of `user` on localhost, as used by `CREATE USER` and `GRANT`.
+fn account(user: &str) -> String {
+    format!("{}@'localhost'", quote_string(user))
+}
 
 pub struct Database {
     name: String,
@@ -120,16 +158,34 @@ impl MySqlService {
 }
 
 impl<'a> RunningMySqlService<'a> {
+    /// Creates a database. When it is removed from the configuration, it is dumped to the backup directory and dropped.
     pub fn create_database<R: Requirement>(&self, context: &mut Context<R>, name: &str) -> Database
     where
         R: Supports<CreateMySqlDatabase>,
+    {
+        let dump_to = context
+            .shared_backup_root()
+            .full_path()
+            .join(DROPPED_DATABASES_DIR);
+        self.add_database(context, CreateMySqlDatabase::new(name).dump_to(dump_to))
+    }
+
+    /// Creates a database that is never dropped automatically, not even when it is removed from the configuration.
+    pub fn create_protected_database<R>(&self, context: &mut Context<R>, name: &str) -> Database
+    where
+        R: Requirement + Supports<CreateMySqlDatabase>,
+    {
+        self.add_database(context, CreateMySqlDatabase::new(name).protected())
+    }
+
+    fn add_database<R>(&self, context: &mut Context<R>, database: CreateMySqlDatabase) -> Database
+    where
+        R: Requirement + Supports<CreateMySqlDatabase>,
     {
         let deps = [self.0];
-        let node = context.add_node(CreateMySqlDatabase::new(name), &deps);
-        Database {
-            name: name.to_string(),
-            node,
-        }
+        let name = database.name.clone();
+        let node = context.add_node(database, &deps);
+        Database { name, node }
     }
 
     pub fn create_user<R: Requirement>(
@@ -225,17 +281,81 @@ impl MySqlUser {
     }
 }
 
+/// The directory in the shared backup directory to which databases are dumped before they are dropped.
+const DROPPED_DATABASES_DIR: &str = "dropped-databases";
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct CreateMySqlDatabase {
     name: String,
+
+    /// Protected databases are never dropped.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    protected: bool,
+
+    /// The directory that the database is dumped to before it is dropped.
+    /// Databases without a directory, such as those in graphs saved by older versions, are never dropped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dump_to: Option<PathBuf>,
 }
 
 impl CreateMySqlDatabase {
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_string(),
+            protected: false,
+            dump_to: None,
         }
     }
+
+    /// Dumps the database to a file in `dir` before it is dropped, which allows it to be dropped.
+    pub fn dump_to(mut self, dir: PathBuf) -> Self {
+        self.dump_to = Some(dir);
+        self
+    }
+
+    /// Marks the database as protected, so that it is never dropped automatically.
+    pub fn protected(mut self) -> Self {
+        self.protected = true;
+        self
+    }
+
+    /// Runs `mysqldump` for the database, and returns the dump.
+    fn dump<S: System>(&self, system: &mut S) -> Result<CommandResult, S::CommandError> {
+        system.execute_command(
+            "mysqldump",
+            &["--single-transaction", "--databases", "--", &self.name],
+        )
+    }
+
+    /// Dumps the database to a new file in `dir`, and returns the path of the file.
+    fn dump_before_drop<S: System>(
+        &self,
+        system: &mut S,
+        dir: &StdPath,
+    ) -> Result<PathBuf, MySqlError<S>> {
+        let result = self.dump(system).map_err(MySqlError::FailedToStart)?;
+        result
+            .successful()
+            .map_err(|(_, stderr)| MySqlError::DumpFailed {
+                database: self.name.clone(),
+                stderr: stderr.to_string(),
+            })?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or(0);
+        let path = dir.join(format!("{}-{}.sql", self.name, now));
+        system
+            .make_dir_all(dir)
+            .and_then(|_| system.put_file_contents(&path, result.stdout()))
+            .map_err(|inner| MySqlError::UnableToWriteDump {
+                path: path.clone(),
+                inner,
+            })?;
+
+        Ok(path)
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -249,16 +369,31 @@ pub enum MySqlError<S: System> {
         stdout: String,
         stderr: String,
     },
+
+    #[error("invalid privileges {0:?}, expected keywords such as 'SELECT, INSERT'")]
+    InvalidPrivileges(String),
+
+    #[error("database {0} is protected, and is never dropped automatically")]
+    Protected(String),
+
+    #[error("database {0} has no directory to dump it to before it is dropped")]
+    NoDumpDirectory(String),
+
+    #[error("unable to dump database {database} before dropping it: {stderr}")]
+    DumpFailed { database: String, stderr: String },
+
+    #[error("unable to write the dump to {path}: {inner}")]
+    UnableToWriteDump { path: PathBuf, inner: S::Error },
 }
 
 impl Requirement for CreateMySqlDatabase {
     type CreateError<S: System> = MySqlError<S>;
     type ModifyError<S: System> = NeverError;
-    type DeleteError<S: System> = NeverError;
+    type DeleteError<S: System> = MySqlError<S>;
     type HasBeenCreatedError<S: System> = MySqlError<S>;
 
     fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
-        let query = format!("CREATE DATABASE `{}`;", self.name);
+        let query = format!("CREATE DATABASE {};", quote_identifier(&self.name));
         let result = system
             .execute_command_with_input("mysql", &[], query.as_bytes())
             .map_err(MySqlError::FailedToStart)?;
@@ -280,20 +415,52 @@ impl Requirement for CreateMySqlDatabase {
         Ok(())
     }
 
-    fn delete<S: crate::system::System>(
-        &self,
-        _system: &mut S,
-    ) -> Result<(), Self::DeleteError<S>> {
-        unimplemented!()
+    /// Dumps the database to a new file in `dump_to`, and drops it once the dump has been written.
+    /// The database is not dumped again by [`Requirement::backup`] before it is undone, because this dump already preserves it.
+    fn delete<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        if self.protected {
+            return Err(MySqlError::Protected(self.name.clone()));
+        }
+
+        let dir = self
+            .dump_to
+            .as_ref()
+            .ok_or_else(|| MySqlError::NoDumpDirectory(self.name.clone()))?;
+        if self.has_been_created(system)? {
+            let path = self.dump_before_drop(system, dir)?;
+            info!("Dumped database {} to {}", self.name, path.display());
+        }
+
+        let query = format!("DROP DATABASE IF EXISTS {};", quote_identifier(&self.name));
+        let result = system
+            .execute_command_with_input("mysql", &[], query.as_bytes())
+            .map_err(MySqlError::FailedToStart)?;
+        result
+            .successful()
+            .map_err(|(stdout, stderr)| MySqlError::Unsuccessful {
+                query,
+                stdout: stdout.to_string(),
+                stderr: stderr.to_string(),
+            })?;
+
+        Ok(())
     }
 
     fn has_been_created<S: crate::system::System>(
         &self,
         system: &mut S,
     ) -> Result<bool, Self::HasBeenCreatedError<S>> {
-        let query = format!("SHOW DATABASES LIKE '{}';", self.name);
+        // Unlike `SHOW DATABASES LIKE`, this does not treat `_` and `%` in the name as wildcards
+        let query = format!(
+            "SELECT SCHEMA_NAME FROM information_schema.SCHEMATA WHERE SCHEMA_NAME = {};",
+            quote_string(&self.name)
+        );
         let result = system
-            .execute_command_with_input("mysql", &["--column-names=false"], query.as_bytes())
+            .execute_command_with_input(
+                "mysql",
+                &["--column-names=false", "--raw"],
+                query.as_bytes(),
+            )
             .map_err(MySqlError::FailedToStart)?;
         result
             .successful()
@@ -303,18 +470,22 @@ impl Requirement for CreateMySqlDatabase {
                 stderr: stderr.to_string(),
             })?;
 
-        return Ok(result.stdout_as_str().trim() == self.name);
+        return Ok(result.stdout_as_str().strip_suffix('\n') == Some(self.name.as_str()));
     }
 
     fn affects(&self, other: &Self) -> bool {
         self.name == other.name
     }
 
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
     fn supports_modifications(&self) -> bool {
         false
     }
     fn can_undo(&self) -> bool {
-        false
+        !self.protected && self.dump_to.is_some()
     }
     fn may_pre_exist(&self) -> bool {
         true
@@ -324,7 +495,37 @@ impl Requirement for CreateMySqlDatabase {
         Ok(self.has_been_created(system).unwrap())
     }
 
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        vec![Fact::Database(self.name.clone())]
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["mysql", "mysqldump"]
+    }
+
+    /// Databases contain data that was not created by the requirement, so they are dumped with `mysqldump`.
+    fn describe(&self) -> Description {
+        Description::new(Category::Database, &self.name, "MySQL database")
+    }
+
     const NAME: &'static str = "mysql_database";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A MySQL or MariaDB database.",
+        create: "Runs `CREATE DATABASE`.",
+        verify: "Checks that `SHOW DATABASES` lists the database.",
+        undo: "Dumps the database with `mysqldump` to `dropped-databases` in the backup directory, then runs `DROP DATABASE`. Protected databases are kept.",
+    };
+    const DOCS_URL: Option<&'static str> = Some("https://mariadb.com/kb/en/create-database/");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "Can't connect to local",
+            hint: "The database server is not running, or its socket is not in the default location.",
+        },
+        FailureHint {
+            pattern: "Access denied",
+            hint: "side connects to the database server as root over its socket. Check that `mysql` can log in as root without a password.",
+        },
+    ];
 }
 
 impl Display for CreateMySqlDatabase {
@@ -355,10 +556,10 @@ impl Requirement for CreateMySqlUser {
     type HasBeenCreatedError<S: System> = MySqlError<S>;
 
     fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
-        // TODO: Escape username & password
         let query = format!(
-            "CREATE USER '{}'@'localhost' IDENTIFIED BY '{}'; FLUSH PRIVILEGES;",
-            self.name, self.pass
+            "CREATE USER {} IDENTIFIED BY {}; FLUSH PRIVILEGES;",
+            account(&self.name),
+            quote_string(&self.pass)
         );
         let result = system
             .execute_command_with_input("mysql", &[], query.as_bytes())
@@ -375,10 +576,10 @@ impl Requirement for CreateMySqlUser {
     }
 
     fn modify<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
-        // TODO: Escape username & password
         let query = format!(
-            "ALTER USER '{}'@'localhost' IDENTIFIED BY '{}'; FLUSH PRIVILEGES;",
-            self.name, self.pass
+            "ALTER USER {} IDENTIFIED BY {}; FLUSH PRIVILEGES;",
+            account(&self.name),
+            quote_string(&self.pass)
         );
         let result = system
             .execute_command_with_input("mysql", &[], query.as_bytes())
@@ -395,7 +596,7 @@ impl Requirement for CreateMySqlUser {
     }
 
     fn delete<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
-        let query = format!("DROP USER '{}'@'localhost'; FLUSH PRIVILEGES;", self.name);
+        let query = format!("DROP USER {}; FLUSH PRIVILEGES;", account(&self.name));
         let result = system
             .execute_command_with_input("mysql", &[], query.as_bytes())
             .map_err(MySqlError::FailedToStart)?;
@@ -415,11 +616,15 @@ impl Requirement for CreateMySqlUser {
         system: &mut S,
     ) -> Result<bool, Self::HasBeenCreatedError<S>> {
         let query = format!(
-            "SELECT User FROM mysql.user WHERE User = '{}' AND Host = 'localhost';",
-            self.name
+            "SELECT User FROM mysql.user WHERE User = {} AND Host = 'localhost';",
+            quote_string(&self.name)
         );
         let result = system
-            .execute_command_with_input("mysql", &["--column-names=false"], query.as_bytes())
+            .execute_command_with_input(
+                "mysql",
+                &["--column-names=false", "--raw"],
+                query.as_bytes(),
+            )
             .map_err(MySqlError::FailedToStart)?;
         result
             .successful()
@@ -429,13 +634,17 @@ impl Requirement for CreateMySqlUser {
                 stderr: stderr.to_string(),
             })?;
 
-        return Ok(result.stdout_as_str().trim() == self.name);
+        return Ok(result.stdout_as_str().strip_suffix('\n') == Some(self.name.as_str()));
     }
 
     fn affects(&self, other: &Self) -> bool {
         self.name == other.name
     }
 
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
     fn supports_modifications(&self) -> bool {
         true
     }
@@ -450,7 +659,40 @@ impl Requirement for CreateMySqlUser {
         Ok(self.has_been_created(system).unwrap())
     }
 
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        vec![Fact::DatabaseUser(self.name.clone())]
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["mysql"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(Category::Database, &self.name, "MySQL user")
+    }
+
     const NAME: &'static str = "mysql_user";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A MySQL or MariaDB user on localhost, with a password.",
+        create: "Runs `CREATE USER`. Changes set the new password with `ALTER USER`.",
+        verify: "Checks that the user exists in `mysql.user`.",
+        undo: "Runs `DROP USER`.",
+    };
+    const DOCS_URL: Option<&'static str> = Some("https://mariadb.com/kb/en/create-user/");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "Can't connect to local",
+            hint: "The database server is not running, or its socket is not in the default location.",
+        },
+        FailureHint {
+            pattern: "Access denied",
+            hint: "side connects to the database server as root over its socket. Check that `mysql` can log in as root without a password.",
+        },
+        FailureHint {
+            pattern: "ERROR 1396",
+            hint: "The user already exists, but was not created by side.",
+        },
+    ];
 }
 
 impl Display for CreateMySqlUser {
@@ -474,6 +716,20 @@ impl CreateMySqlGrant {
             privileges: privileges.to_string(),
         }
     }
+
+    /// Returns the privileges, if they are a list of keywords such as `SELECT, SHOW VIEW`. Privileges are keywords, so they cannot be quoted.
+    fn checked_privileges<S: System>(&self) -> Result<&str, MySqlError<S>> {
+        if !self.privileges.is_empty()
+            && self
+                .privileges
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c == ' ' || c == ',')
+        {
+            Ok(&self.privileges)
+        } else {
+            Err(MySqlError::InvalidPrivileges(self.privileges.clone()))
+        }
+    }
 }
 
 impl Requirement for CreateMySqlGrant {
@@ -484,10 +740,10 @@ impl Requirement for CreateMySqlGrant {
 
     fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
         let query = format!(
-            "GRANT {p} ON `{db}`.* TO '{u}'@'localhost'; FLUSH PRIVILEGES;",
-            p = self.privileges,
-            db = self.database,
-            u = self.user
+            "GRANT {p} ON {db}.* TO {u}; FLUSH PRIVILEGES;",
+            p = self.checked_privileges()?,
+            db = quote_identifier(&self.database),
+            u = account(&self.user)
         );
         let result = system
             .execute_command_with_input("mysql", &[], query.as_bytes())
@@ -504,7 +760,12 @@ impl Requirement for CreateMySqlGrant {
     }
 
     fn modify<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
-        let query = format!("REVOKE ALL PRIVILEGES ON `{db}`.* FROM '{u}'@'localhost'; GRANT {p} ON `{db}`.* TO '{u}'@'localhost'; FLUSH PRIVILEGES; FLUSH PRIVILEGES;", p = self.privileges, db = self.database, u = self.user);
+        let query = format!(
+            "REVOKE ALL PRIVILEGES ON {db}.* FROM {u}; GRANT {p} ON {db}.* TO {u}; FLUSH PRIVILEGES;",
+            p = self.checked_privileges()?,
+            db = quote_identifier(&self.database),
+            u = account(&self.user)
+        );
         let result = system
             .execute_command_with_input("mysql", &[], query.as_bytes())
             .map_err(MySqlError::FailedToStart)?;
@@ -521,9 +782,9 @@ impl Requirement for CreateMySqlGrant {
 
     fn delete<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
         let query = format!(
-            "REVOKE ALL PRIVILEGES ON `{db}`.* FROM '{u}'@'localhost'; FLUSH PRIVILEGES;",
-            db = self.database,
-            u = self.user
+            "REVOKE ALL PRIVILEGES ON {db}.* FROM {u}; FLUSH PRIVILEGES;",
+            db = quote_identifier(&self.database),
+            u = account(&self.user)
         );
         let result = system
             .execute_command_with_input("mysql", &[], query.as_bytes())
@@ -543,7 +804,7 @@ impl Requirement for CreateMySqlGrant {
         &self,
         system: &mut S,
     ) -> Result<bool, Self::HasBeenCreatedError<S>> {
-        let query = format!("SHOW GRANTS FOR {}@'localhost'", self.user);
+        let query = format!("SHOW GRANTS FOR {}", account(&self.user));
         let result = system
             .execute_command_with_input("mysql", &["--column-names=false"], query.as_bytes())
             .unwrap();
@@ -555,11 +816,12 @@ impl Requirement for CreateMySqlGrant {
         assert!(result.is_success()); // TODO
 
         let grants = result.stdout_as_str();
-        println!("Grants: {}", grants);
+        tracing::trace!("Grants: {}", grants);
 
         Ok(grants.contains(&format!(
-            "ON `{}`.* TO `{}`@`localhost`",
-            self.database, self.user
+            "ON {}.* TO {}@`localhost`",
+            quote_identifier(&self.database),
+            quote_identifier(&self.user)
         )))
     }
 
@@ -567,6 +829,10 @@ impl Requirement for CreateMySqlGrant {
         self.user == other.user
     }
 
+    fn identity(&self) -> String {
+        self.user.clone()
+    }
+
     fn supports_modifications(&self) -> bool {
         true
     }
@@ -581,7 +847,44 @@ impl Requirement for CreateMySqlGrant {
         Ok(self.has_been_created(system).unwrap())
     }
 
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        vec![Fact::DatabaseGrant {
+            user: self.user.clone(),
+            database: self.database.clone(),
+            privileges: self.privileges.clone(),
+        }]
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["mysql"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Database,
+            &self.user,
+            format!("granted {} on {}", self.privileges, self.database),
+        )
+    }
+
     const NAME: &'static str = "mysql_grant";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "Privileges of a MySQL or MariaDB user on a database.",
+        create: "Runs `GRANT`. Changes revoke all privileges on the database first.",
+        verify: "Checks the privileges reported by `SHOW GRANTS`.",
+        undo: "Revokes all privileges of the user on the database.",
+    };
+    const DOCS_URL: Option<&'static str> = Some("https://mariadb.com/kb/en/grant/");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "Can't connect to local",
+            hint: "The database server is not running, or its socket is not in the default location.",
+        },
+        FailureHint {
+            pattern: "Access denied",
+            hint: "side connects to the database server as root over its socket. Check that `mysql` can log in as root without a password.",
+        },
+    ];
 }
 
 impl Display for CreateMySqlGrant {
@@ -593,30 +896,168 @@ impl Display for CreateMySqlGrant {
 #[cfg(test)]
 mod tests {
     use crate::{
-        builder::mysql::{CreateMySqlDatabase, CreateMySqlGrant, CreateMySqlUser},
+        builder::mysql::{
+            account, quote_identifier, quote_string, CreateMySqlDatabase, CreateMySqlGrant,
+            CreateMySqlUser, MySqlError,
+        },
         requirements::Requirement,
-        system::System,
+        system::{LocalSystem, System},
         testing::LxcInstance,
     };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::path::PathBuf;
+
+    /// Parses a quoted string literal or identifier at the start of `query` the way MySQL does, and returns its value and the rest of the query.
+    fn parse_quoted(query: &str, quote: char, backslash_escapes: bool) -> Option<(String, &str)> {
+        let mut chars = query.char_indices();
+        if chars.next()?.1 != quote {
+            return None;
+        }
+
+        let mut value = String::new();
+        while let Some((index, c)) = chars.next() {
+            if c == quote {
+                if query[index + 1..].starts_with(quote) {
+                    chars.next();
+                    value.push(quote);
+                } else {
+                    return Some((value, &query[index + 1..]));
+                }
+            } else if c == '\\' && backslash_escapes {
+                value.push(match chars.next()?.1 {
+                    '0' => '\0',
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'Z' => '\x1a',
+                    other => other,
+                });
+            } else {
+                value.push(c);
+            }
+        }
+
+        None
+    }
+
+    /// Strings that contain quotes, escape characters and SQL syntax.
+    fn hostile_strings() -> impl Iterator<Item = String> {
+        const ALPHABET: &[char] = &[
+            '\'', '"', '`', '\\', '\0', '\n', '\r', '\t', '\x1a', ';', '-', '#', '/', '*', '%',
+            '_', '@', ' ', 'a', 'Z', '0', 'é', '😀',
+        ];
+        let mut rng = StdRng::seed_from_u64(539);
+        (0..2000).map(move |_| {
+            let len = rng.gen_range(0..24);
+            (0..len)
+                .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())])
+                .collect()
+        })
+    }
+
+    #[test]
+    pub fn quote_hostile_strings() {
+        for value in hostile_strings() {
+            assert_eq!(
+                parse_quoted(&quote_string(&value), '\'', true),
+                Some((value.clone(), "")),
+                "{:?} escapes its string literal",
+                value
+            );
+            assert_eq!(
+                parse_quoted(&quote_identifier(&value), '`', false),
+                Some((value.clone(), "")),
+                "{:?} escapes its identifier",
+                value
+            );
+
+            let account = account(&value);
+            let (user, rest) = parse_quoted(&account, '\'', true).unwrap();
+            assert_eq!((user.as_str(), rest), (value.as_str(), "@'localhost'"));
+        }
+    }
+
+    #[test]
+    pub fn quote_examples() {
+        assert_eq!(quote_string("it's"), r"'it\'s'");
+        assert_eq!(quote_string("a\\b\n"), r"'a\\b\n'");
+        assert_eq!(quote_identifier("my`db"), "`my``db`");
+        assert_eq!(account("foo"), "'foo'@'localhost'");
+    }
+
+    #[test]
+    pub fn reject_invalid_privileges() {
+        let grant = CreateMySqlGrant::new("foo", String::from("bar"), "SELECT, SHOW VIEW");
+        assert_eq!(
+            grant.checked_privileges::<LocalSystem>().unwrap(),
+            "SELECT, SHOW VIEW"
+        );
+
+        for privileges in [
+            "",
+            "ALL; DROP DATABASE bar",
+            "select",
+            "SELECT ON *.* TO 'x'",
+        ] {
+            let grant = CreateMySqlGrant::new("foo", String::from("bar"), privileges);
+            assert!(matches!(
+                grant.checked_privileges::<LocalSystem>(),
+                Err(MySqlError::InvalidPrivileges(_))
+            ));
+        }
+    }
 
     #[test]
     pub fn serialize_deserialize_create_mysql_database() {
-        let r = CreateMySqlDatabase {
-            name: String::from("foo"),
-        };
+        let r = CreateMySqlDatabase::new("foo");
         let json = r#"{"name":"foo"}"#;
 
         assert_eq!(serde_json::to_string(&r).unwrap(), json);
         assert_eq!(r, serde_json::from_str(json).unwrap());
+        assert!(!r.can_undo());
+
+        let r = CreateMySqlDatabase::new("foo").dump_to(PathBuf::from("/srv/backup/dropped"));
+        let json = r#"{"name":"foo","dump_to":"/srv/backup/dropped"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+        assert!(r.can_undo());
+
+        let r = CreateMySqlDatabase::new("foo")
+            .dump_to(PathBuf::from("/srv/backup/dropped"))
+            .protected();
+        let json = r#"{"name":"foo","protected":true,"dump_to":"/srv/backup/dropped"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+        assert!(!r.can_undo());
+    }
+
+    #[test]
+    pub fn protected_database_is_not_dropped() {
+        let r = CreateMySqlDatabase::new("foo")
+            .dump_to(PathBuf::from("/srv/backup/dropped"))
+            .protected();
+        let err = r.delete(&mut LocalSystem::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "database foo is protected, and is never dropped automatically"
+        );
+
+        let err = CreateMySqlDatabase::new("foo")
+            .delete(&mut LocalSystem::new())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "database foo has no directory to dump it to before it is dropped"
+        );
     }
 
     #[test]
     #[ignore]
     pub fn lxc_create_mysql_database() {
         let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
-        let p = CreateMySqlDatabase {
-            name: String::from("foo"),
-        };
+        let p = CreateMySqlDatabase::new("foo").dump_to(PathBuf::from("/root/dropped"));
 
         sys.execute_command("apt-get", &["install", "-y", "mariadb-server"])
             .unwrap();
@@ -629,10 +1070,14 @@ mod tests {
         assert!(p.has_been_created(&mut sys).unwrap());
         assert!(p.verify(&mut sys).unwrap());
 
-        // p.delete(&mut sys).unwrap();
+        p.delete(&mut sys).unwrap();
 
-        // assert!(!p.has_been_created(&mut sys).unwrap());
-        // assert!(!p.verify(&mut sys).unwrap());
+        assert!(!p.has_been_created(&mut sys).unwrap());
+        assert!(!p.verify(&mut sys).unwrap());
+        assert_eq!(
+            sys.read_dir(&PathBuf::from("/root/dropped")).unwrap().len(),
+            1
+        );
     }
 
     #[test]
@@ -701,9 +1146,7 @@ mod tests {
             name: String::from("foo"),
             pass: String::from("bar"),
         };
-        let pre2 = CreateMySqlDatabase {
-            name: String::from("baz"),
-        };
+        let pre2 = CreateMySqlDatabase::new("baz");
         let p = CreateMySqlGrant {
             user: String::from("foo"),
             database: String::from("baz"),
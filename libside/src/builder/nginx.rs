@@ -1,11 +1,17 @@
+use std::fmt::Display;
 use std::path::PathBuf;
 
 use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, FailureHint, Requirement, Supports, VerificationFailure};
+use crate::system::{NeverError, System};
+use serde::{Deserialize, Serialize};
 
 use super::{
-    path::{FromPackage, Path},
-    Group, User,
-    {apt::AptPackage, systemd::SystemdService},
+    fs::{ConfigFileData, CreateDirectory, FileWithContents, Symlink},
+    path::{Exposed, FromPackage, Path, SharedConfig},
+    AsParam, ConflictError, Context, Group, User,
+    {apt::AptPackage, systemd::ServiceRunning, systemd::SystemdService},
 };
 
 pub struct Nginx {
@@ -42,6 +48,39 @@ impl Nginx {
         &mut self.service
     }
 
+    /// Restarts the default service, after testing the configuration in `config` with [`NginxConfigTest`].
+    /// The test runs after all start dependencies of the service, such as the site configuration files,
+    /// so that a broken configuration fails the apply before nginx is restarted and stops serving the other sites.
+    pub fn restart<R, L: Clone>(
+        &mut self,
+        context: &mut Context<R>,
+        config: &Path<L>,
+    ) -> GraphNodeReference
+    where
+        R: Requirement + Supports<NginxConfigTest> + Supports<ServiceRunning>,
+    {
+        let tested = context.add_node(
+            NginxConfigTest {
+                config: config.full_path(),
+            },
+            self.service
+                .start_dependencies
+                .iter()
+                .chain(config.graph_node().iter()),
+        );
+        self.service.add_start_dependencies([tested]);
+
+        self.service.restart(context)
+    }
+
+    /// Claims a `server_name` for the current package, so that two packages cannot serve the same host.
+    pub fn claim_server_name<R: Requirement>(
+        context: &mut Context<R>,
+        server_name: &str,
+    ) -> Result<(), ConflictError> {
+        context.claim_unique("nginx server_name", &server_name.to_ascii_lowercase())
+    }
+
     pub fn www_data_user(&self) -> User {
         User {
             uid: None,
@@ -59,47 +98,1068 @@ impl Nginx {
     }
 }
 
-// pub struct SiteConfig<'a> {
-//     writer: &'a mut String,
-//     indent: usize,
-// }
+/// Quotes `value` for use as a parameter of an nginx directive, if it contains characters that nginx would interpret.
+fn quote(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, ';' | '{' | '}' | '"' | '\'' | '#' | '\\'))
+    {
+        value.to_owned()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// The configuration of a site: the [`Upstream`]s and [`ServerBlock`]s in one file in the `sites` directory of nginx.
+///
+/// Paths that are used in the configuration, such as the document root or the socket of an upstream, are added as dependencies of the file,
+/// so that they exist before nginx loads the configuration.
+#[derive(Clone, Debug, Default)]
+pub struct SiteConfig {
+    upstreams: Vec<Upstream>,
+    servers: Vec<ServerBlock>,
+}
+
+impl SiteConfig {
+    pub fn new() -> SiteConfig {
+        SiteConfig::default()
+    }
+
+    pub fn upstream(mut self, upstream: Upstream) -> Self {
+        self.upstreams.push(upstream);
+        self
+    }
+
+    pub fn server(mut self, server: ServerBlock) -> Self {
+        self.servers.push(server);
+        self
+    }
+
+    fn dependencies(&self) -> impl Iterator<Item = &GraphNodeReference> {
+        self.upstreams
+            .iter()
+            .flat_map(|upstream| upstream.graph_dependencies.iter())
+            .chain(self.servers.iter().flat_map(ServerBlock::dependencies))
+    }
+
+    /// The configuration as a file named `name`, which can be passed to [`Path::make_file`] or [`BlueGreen::install`].
+    pub fn file(&self, name: &str) -> ConfigFileData {
+        let mut extra_dependencies = Vec::new();
+        for &dependency in self.dependencies() {
+            if !extra_dependencies.contains(&dependency) {
+                extra_dependencies.push(dependency);
+            }
+        }
+
+        ConfigFileData {
+            path: PathBuf::from(name),
+            contents: self.to_string().into_bytes(),
+            path_dependency: None,
+            extra_dependencies,
+        }
+    }
+
+    /// Writes the configuration to `<sites>/<name>`, and makes the default service of `nginx` start after the file has been written.
+    /// The server names are claimed for the current package, see [`Nginx::claim_server_name`].
+    pub fn install<R>(
+        &self,
+        context: &mut Context<R>,
+        nginx: &mut Nginx,
+        sites: &Path<SharedConfig>,
+        name: &str,
+    ) -> Path<SharedConfig>
+    where
+        R: Requirement + Supports<CreateDirectory> + Supports<FileWithContents>,
+    {
+        for server_name in self
+            .servers
+            .iter()
+            .flat_map(|server| server.server_names.iter())
+        {
+            // A server name that is already used by another package fails the build after the current package
+            let _ = Nginx::claim_server_name(context, server_name);
+        }
+
+        let file = sites.make_file(context, self.file(name));
+        nginx
+            .default_service()
+            .add_start_dependencies(file.graph_node());
+
+        file
+    }
+}
+
+impl Display for SiteConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for upstream in self.upstreams.iter() {
+            writeln!(f, "{}", upstream)?;
+        }
+
+        for (index, server) in self.servers.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{}", server)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A `server` block.
+#[derive(Clone, Debug, Default)]
+pub struct ServerBlock {
+    listen: Vec<String>,
+    server_names: Vec<String>,
+    root: Option<String>,
+    index: Vec<String>,
+    access_log: Option<String>,
+    error_log: Option<String>,
+    tls: Option<Tls>,
+    directives: Vec<(String, String)>,
+    locations: Vec<Location>,
+    graph_dependencies: Vec<GraphNodeReference>,
+}
+
+impl ServerBlock {
+    pub fn new() -> ServerBlock {
+        ServerBlock::default()
+    }
+
+    /// Adds a `listen` directive, such as `80`, `[::]:80` or `443 ssl`.
+    pub fn listen(mut self, address: &str) -> Self {
+        self.listen.push(address.to_owned());
+        self
+    }
+
+    pub fn server_name(mut self, name: &str) -> Self {
+        self.server_names.push(name.to_owned());
+        self
+    }
+
+    pub fn root<L: Clone>(mut self, path: &Path<L>) -> Self {
+        self.root = Some(path.as_param());
+        self.graph_dependencies.extend(path.graph_node());
+        self
+    }
+
+    pub fn index(mut self, file: &str) -> Self {
+        self.index.push(file.to_owned());
+        self
+    }
+
+    pub fn access_log(mut self, path: &str) -> Self {
+        self.access_log = Some(path.to_owned());
+        self
+    }
+
+    pub fn error_log(mut self, path: &str) -> Self {
+        self.error_log = Some(path.to_owned());
+        self
+    }
+
+    pub fn tls(mut self, tls: Tls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Adds a directive that has no dedicated method. `value` is written as-is.
+    pub fn directive(mut self, name: &str, value: &str) -> Self {
+        self.directives.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn location(mut self, location: Location) -> Self {
+        self.locations.push(location);
+        self
+    }
+
+    fn dependencies(&self) -> impl Iterator<Item = &GraphNodeReference> {
+        self.graph_dependencies
+            .iter()
+            .chain(
+                self.tls
+                    .iter()
+                    .flat_map(|tls| tls.graph_dependencies.iter()),
+            )
+            .chain(
+                self.locations
+                    .iter()
+                    .flat_map(|location| location.dependencies()),
+            )
+    }
+}
+
+impl Display for ServerBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "server {{")?;
+        for address in self.listen.iter() {
+            writeln!(f, "    listen {};", address)?;
+        }
+
+        if !self.server_names.is_empty() {
+            writeln!(f, "    server_name {};", self.server_names.join(" "))?;
+        }
+
+        if let Some(root) = &self.root {
+            writeln!(f, "    root {};", quote(root))?;
+        }
+
+        if !self.index.is_empty() {
+            writeln!(f, "    index {};", self.index.join(" "))?;
+        }
+
+        if let Some(path) = &self.access_log {
+            writeln!(f, "    access_log {};", quote(path))?;
+        }
+
+        if let Some(path) = &self.error_log {
+            writeln!(f, "    error_log {};", quote(path))?;
+        }
+
+        if let Some(tls) = &self.tls {
+            write!(f, "{}", tls)?;
+        }
+
+        for (name, value) in self.directives.iter() {
+            writeln!(f, "    {} {};", name, value)?;
+        }
+
+        for location in self.locations.iter() {
+            writeln!(f)?;
+            write!(f, "{}", location)?;
+        }
+
+        writeln!(f, "}}")
+    }
+}
+
+/// A `location` block of a [`ServerBlock`].
+#[derive(Clone, Debug)]
+pub struct Location {
+    pattern: String,
+    try_files: Vec<String>,
+    proxy_pass: Option<ProxyPass>,
+    fastcgi_pass: Option<String>,
+    fastcgi_params: Vec<(String, String)>,
+    directives: Vec<(String, String)>,
+    graph_dependencies: Vec<GraphNodeReference>,
+}
+
+impl Location {
+    /// A location that matches `pattern`, including the modifier if there is one, such as `/` or `~ \.php$`.
+    pub fn new(pattern: &str) -> Location {
+        Location {
+            pattern: pattern.to_owned(),
+            try_files: Vec::new(),
+            proxy_pass: None,
+            fastcgi_pass: None,
+            fastcgi_params: Vec::new(),
+            directives: Vec::new(),
+            graph_dependencies: Vec::new(),
+        }
+    }
+
+    /// Sets `try_files`, for example to `["$uri", "$uri/", "=404"]`.
+    pub fn try_files(mut self, files: &[&str]) -> Self {
+        self.try_files = files.iter().map(|&file| file.to_owned()).collect();
+        self
+    }
+
+    pub fn proxy_pass(mut self, proxy: ProxyPass) -> Self {
+        self.proxy_pass = Some(proxy);
+        self
+    }
+
+    /// Passes requests to the FastCGI server listening on the unix socket `socket`, such as php-fpm.
+    pub fn fastcgi_pass<L: Clone>(mut self, socket: &Path<L>) -> Self {
+        self.fastcgi_pass = Some(format!("unix:{}", socket.as_param()));
+        self.graph_dependencies.extend(socket.graph_node());
+        self
+    }
+
+    /// Adds a `fastcgi_param`. `value` is written as-is, so that it can contain variables such as `$fastcgi_script_name`.
+    pub fn fastcgi_param(mut self, name: &str, value: &str) -> Self {
+        self.fastcgi_params
+            .push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Adds a directive that has no dedicated method. `value` is written as-is.
+    pub fn directive(mut self, name: &str, value: &str) -> Self {
+        self.directives.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    fn dependencies(&self) -> impl Iterator<Item = &GraphNodeReference> {
+        self.graph_dependencies.iter().chain(
+            self.proxy_pass
+                .iter()
+                .flat_map(|proxy| proxy.graph_dependencies.iter()),
+        )
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "    location {} {{", self.pattern)?;
+        if !self.try_files.is_empty() {
+            let files = self
+                .try_files
+                .iter()
+                .map(|file| quote(file))
+                .collect::<Vec<_>>();
+            writeln!(f, "        try_files {};", files.join(" "))?;
+        }
+
+        for (name, value) in self.directives.iter() {
+            writeln!(f, "        {} {};", name, value)?;
+        }
+
+        if let Some(proxy) = &self.proxy_pass {
+            write!(f, "{}", proxy)?;
+        }
+
+        if let Some(address) = &self.fastcgi_pass {
+            writeln!(f, "        fastcgi_pass {};", quote(address))?;
+        }
+
+        for (name, value) in self.fastcgi_params.iter() {
+            writeln!(f, "        fastcgi_param {} {};", name, value)?;
+        }
+
+        writeln!(f, "    }}")
+    }
+}
+
+/// Where a [`Location`] forwards requests to, with `proxy_pass`.
+#[derive(Clone, Debug)]
+pub struct ProxyPass {
+    target: String,
+    headers: Vec<(String, String)>,
+    graph_dependencies: Vec<GraphNodeReference>,
+}
+
+impl ProxyPass {
+    /// Forwards requests to `url`, such as `http://127.0.0.1:8080`.
+    pub fn url(url: &str) -> ProxyPass {
+        ProxyPass {
+            target: url.to_owned(),
+            headers: Vec::new(),
+            graph_dependencies: Vec::new(),
+        }
+    }
+
+    /// Forwards requests to the servers of `upstream`, which must be part of the same [`SiteConfig`].
+    pub fn upstream(upstream: &Upstream) -> ProxyPass {
+        ProxyPass::url(&format!("http://{}", upstream.name))
+    }
+
+    /// Forwards requests to the HTTP server listening on the unix socket `socket`.
+    pub fn unix<L: Clone>(socket: &Path<L>) -> ProxyPass {
+        let mut proxy = ProxyPass::url(&format!("http://unix:{}:", socket.as_param()));
+        proxy.graph_dependencies.extend(socket.graph_node());
+        proxy
+    }
+
+    /// Adds a `proxy_set_header`. `value` is written as-is, so that it can contain variables such as `$host`.
+    pub fn set_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+}
+
+impl Display for ProxyPass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "        proxy_pass {};", quote(&self.target))?;
+        for (name, value) in self.headers.iter() {
+            writeln!(f, "        proxy_set_header {} {};", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An `upstream` block: a group of servers that a [`ProxyPass::upstream`] forwards requests to.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    name: String,
+    servers: Vec<String>,
+    keepalive: Option<u32>,
+    graph_dependencies: Vec<GraphNodeReference>,
+}
+
+impl Upstream {
+    pub fn new(name: &str) -> Upstream {
+        Upstream {
+            name: name.to_owned(),
+            servers: Vec::new(),
+            keepalive: None,
+            graph_dependencies: Vec::new(),
+        }
+    }
+
+    /// Adds a server at `address`, such as `127.0.0.1:8080`.
+    pub fn server(mut self, address: &str) -> Self {
+        self.servers.push(address.to_owned());
+        self
+    }
+
+    /// Adds a server listening on the unix socket `socket`.
+    pub fn unix_server<L: Clone>(mut self, socket: &Path<L>) -> Self {
+        self.servers.push(format!("unix:{}", socket.as_param()));
+        self.graph_dependencies.extend(socket.graph_node());
+        self
+    }
+
+    /// The number of idle connections to the servers that every worker process keeps open.
+    pub fn keepalive(mut self, connections: u32) -> Self {
+        self.keepalive = Some(connections);
+        self
+    }
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "upstream {} {{", self.name)?;
+        for server in self.servers.iter() {
+            writeln!(f, "    server {};", quote(server))?;
+        }
+
+        if let Some(connections) = self.keepalive {
+            writeln!(f, "    keepalive {};", connections)?;
+        }
+
+        writeln!(f, "}}")
+    }
+}
+
+/// The TLS settings of a [`ServerBlock`]. The server block must also listen with `ssl`, such as `listen 443 ssl`.
+#[derive(Clone, Debug)]
+pub struct Tls {
+    certificate: String,
+    key: String,
+    protocols: String,
+    ciphers: Option<String>,
+    hsts_max_age: Option<u64>,
+    graph_dependencies: Vec<GraphNodeReference>,
+}
+
+impl Tls {
+    /// Serves the certificate (chain) in `certificate` with the private key in `key`, over TLS 1.2 and 1.3.
+    pub fn new<L: Clone, M: Clone>(certificate: &Path<L>, key: &Path<M>) -> Tls {
+        Tls {
+            certificate: certificate.as_param(),
+            key: key.as_param(),
+            protocols: String::from("TLSv1.2 TLSv1.3"),
+            ciphers: None,
+            hsts_max_age: None,
+            graph_dependencies: certificate
+                .graph_node()
+                .into_iter()
+                .chain(key.graph_node())
+                .collect(),
+        }
+    }
+
+    /// Sets `ssl_protocols`, such as `TLSv1.3`.
+    pub fn protocols(mut self, protocols: &str) -> Self {
+        self.protocols = protocols.to_owned();
+        self
+    }
+
+    /// Sets `ssl_ciphers`, in the format of OpenSSL.
+    pub fn ciphers(mut self, ciphers: &str) -> Self {
+        self.ciphers = Some(ciphers.to_owned());
+        self
+    }
+
+    /// Sends a `Strict-Transport-Security` header, which makes browsers only connect over TLS for `max_age` seconds.
+    pub fn hsts(mut self, max_age: u64) -> Self {
+        self.hsts_max_age = Some(max_age);
+        self
+    }
+}
+
+impl Display for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "    ssl_certificate {};", quote(&self.certificate))?;
+        writeln!(f, "    ssl_certificate_key {};", quote(&self.key))?;
+        writeln!(f, "    ssl_protocols {};", self.protocols)?;
+        if let Some(ciphers) = &self.ciphers {
+            writeln!(f, "    ssl_ciphers {};", quote(ciphers))?;
+        }
+
+        if let Some(max_age) = self.hsts_max_age {
+            writeln!(
+                f,
+                "    add_header Strict-Transport-Security \"max-age={}\" always;",
+                max_age
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The parameters for one of the two server blocks of a blue-green site, see [`BlueGreen`].
+pub struct SiteVariant {
+    /// The address to listen on, or `None` for the live site, which uses the listen directives of the template.
+    pub listen: Option<String>,
+    pub document_root: PathBuf,
+}
+
+/// Deploys a site without downtime.
+///
+/// Every install exposes the files of the site in a new, versioned directory.
+/// A staging server block on the loopback interface serves the new directory, and is checked with an HTTP request.
+/// Only when the check succeeds is the live symlink switched to the new directory and nginx reloaded.
+/// If anything fails, reverting the apply switches the symlink back to the previous directory.
+pub struct BlueGreen<'a> {
+    name: String,
+    document_root: &'a Path<Exposed>,
+    staging_port: u16,
+    health_check: String,
+}
+
+/// The graph nodes of a blue-green site.
+pub struct BlueGreenSite {
+    /// The live server block, which serves the files through the live symlink.
+    pub config: Path<SharedConfig>,
+
+    /// The node that reloads nginx after the live symlink has been switched.
+    pub switched: GraphNodeReference,
+}
+
+impl<'a> BlueGreen<'a> {
+    pub fn new(name: &str, document_root: &'a Path<Exposed>) -> BlueGreen<'a> {
+        BlueGreen {
+            name: name.to_owned(),
+            document_root,
+            staging_port: 8080,
+            health_check: String::from("/"),
+        }
+    }
+
+    /// The port of the staging server block on 127.0.0.1. Every blue-green site needs its own port.
+    pub fn staging_port(mut self, port: u16) -> Self {
+        self.staging_port = port;
+        self
+    }
+
+    /// The path that is requested from the staging server block, which must respond with status 200.
+    pub fn health_check(mut self, path: &str) -> Self {
+        assert!(
+            path.starts_with('/'),
+            "invalid health check path {:?}",
+            path
+        );
+        self.health_check = path.to_owned();
+        self
+    }
+
+    /// Adds the staging and live server blocks to `sites`. `site` generates a server block for each variant.
+    pub fn install<R>(
+        self,
+        context: &mut Context<R>,
+        nginx: &Nginx,
+        sites: &Path<SharedConfig>,
+        site: impl Fn(SiteVariant) -> ConfigFileData,
+    ) -> BlueGreenSite
+    where
+        R: Requirement
+            + Supports<CreateDirectory>
+            + Supports<FileWithContents>
+            + Supports<ReloadNginx>
+            + Supports<SiteHealthCheck>
+            + Supports<Symlink>,
+    {
+        let new_root = self.document_root.full_path();
+        let staging = sites.make_file(
+            context,
+            site(SiteVariant {
+                listen: Some(format!("127.0.0.1:{}", self.staging_port)),
+                document_root: new_root,
+            })
+            .rename(format!("{}-staging", self.name)),
+        );
+        let staging_reloaded = context.add_node(
+            ReloadNginx,
+            staging.graph_node().iter().chain([&nginx.graph_node()]),
+        );
+        let healthy = context.add_node(
+            SiteHealthCheck {
+                url: format!(
+                    "http://127.0.0.1:{}{}",
+                    self.staging_port, self.health_check
+                ),
+                status: 200,
+            },
+            &[staging_reloaded],
+        );
+
+        let live = self.document_root.link_version(context, "live", &[healthy]);
+        let switch = live.graph_node().unwrap();
+
+        let config = sites.make_file(
+            context,
+            site(SiteVariant {
+                listen: None,
+                document_root: live.full_path(),
+            })
+            .rename(&self.name),
+        );
+        let switched = context.add_node(ReloadNginx, config.graph_node().iter().chain([&switch]));
+
+        BlueGreenSite { config, switched }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NginxError<S: System> {
+    #[error("unable to execute command: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("command failed: {0} {1}")]
+    Unsuccessful(String, String),
+
+    #[error("{url} responded with status {actual}")]
+    Unhealthy { url: String, actual: String },
+
+    #[error("the configuration in {config} is invalid: {output}")]
+    InvalidConfig { config: String, output: String },
+}
+
+impl<S: System> From<(&str, &str)> for NginxError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        NginxError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+/// Tests the nginx configuration and reloads nginx, or starts it if it is not running.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ReloadNginx;
+
+impl ReloadNginx {
+    fn exec<S: System>(&self, system: &mut S) -> Result<(), NginxError<S>> {
+        system
+            .execute_command("nginx", &["-t", "-q"])
+            .map_err(NginxError::FailedToStart)?
+            .successful()?;
+        system
+            .execute_command("systemctl", &["reload-or-restart", "nginx"])
+            .map_err(NginxError::FailedToStart)?
+            .successful()?;
+
+        Ok(())
+    }
+}
+
+impl Requirement for ReloadNginx {
+    type CreateError<S: System> = NginxError<S>;
+    type ModifyError<S: System> = NginxError<S>;
+    type DeleteError<S: System> = NeverError;
+    type HasBeenCreatedError<S: System> = NeverError;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.exec(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.exec(system)
+    }
+
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
+
+    fn affects(&self, _other: &Self) -> bool {
+        false
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    fn verify<S: System>(&self, _system: &mut S) -> Result<bool, ()> {
+        Ok(true)
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["nginx", "systemctl"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(Category::Service, "nginx", "configuration reloaded")
+    }
 
-// impl SiteConfig<'_> {
-//     pub fn create<T>(f: impl Fn(&mut SiteConfig<'_>), out: impl Fn(FileData<'_>) -> T) -> T {
-//         let mut writer = String::new();
-//         let mut node = SiteConfig {
-//             writer: &mut writer,
-//             indent: 0,
-//         };
+    const NAME: &'static str = "reload_nginx";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "Makes nginx pick up configuration changes.",
+        create: "Tests the configuration with `nginx -t`, then reloads nginx, or starts it if it is not running.",
+        verify: "Always passes.",
+        undo: "Nothing.",
+    };
+}
 
-//         f(&mut node);
+impl Display for ReloadNginx {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reload-nginx")
+    }
+}
+
+/// Tests the nginx configuration in `config` with `nginx -t`, without changing anything on the system.
+/// It is added by [`Nginx::restart`] between the configuration files and the restart of nginx.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NginxConfigTest {
+    config: PathBuf,
+}
 
-//         out(FileData::new("[site configuration]", &writer))
-//     }
+impl NginxConfigTest {
+    fn test<S: System>(&self, system: &mut S) -> Result<(), NginxError<S>> {
+        let config = self.config.display().to_string();
+        let result = system
+            .execute_command("nginx", &["-t", "-q", "-c", &config])
+            .map_err(NginxError::FailedToStart)?;
+        if result.is_success() {
+            Ok(())
+        } else {
+            Err(NginxError::InvalidConfig {
+                config,
+                output: result.stderr_as_str().trim().to_owned(),
+            })
+        }
+    }
+}
 
-//     pub fn block<N: AsRef<str>>(&mut self, name: N, f: impl Fn(&mut SiteConfig<'_>)) -> &mut Self {
-//         writeln!(self.writer, "{}{} {{", " ".repeat(self.indent), name.as_ref()).unwrap();
+impl Requirement for NginxConfigTest {
+    type CreateError<S: System> = NginxError<S>;
+    type ModifyError<S: System> = NginxError<S>;
+    type DeleteError<S: System> = NeverError;
+    type HasBeenCreatedError<S: System> = NeverError;
 
-//         f(&mut SiteConfig {
-//             writer: self.writer,
-//             indent: self.indent + 4,
-//         });
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.test(system)
+    }
 
-//         writeln!(self.writer, "{}}}", " ".repeat(self.indent)).unwrap();
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.test(system)
+    }
 
-//         self
-//     }
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
 
-//     pub fn set<N: AsRef<str>, V: AsRef<str>>(&mut self, name: N, value: V) -> &mut Self {
-//         writeln!(self.writer, "{}{} {};", " ".repeat(self.indent), name.as_ref(), value.as_ref()).unwrap();
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
 
-//         self
-//     }
+    fn affects(&self, other: &Self) -> bool {
+        self.config == other.config
+    }
 
-//     pub fn server(&mut self, f: impl Fn(&mut SiteConfig<'_>)) -> &mut Self { self.block("server", f) }
-//     pub fn directory<T: Tag, N: AsRef<str>>(&mut self, name: N, directory: &ExistingDirectory<T>) -> &mut Self {
-//         self.set(name, directory.path().to_str().unwrap())
-//     }
+    fn identity(&self) -> String {
+        self.config.display().to_string()
+    }
 
-//     pub fn end(&mut self) {}
-// }
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self.test(system).is_ok())
+    }
+
+    fn verification_failure<S: System>(&self, system: &mut S) -> VerificationFailure {
+        match self.test(system) {
+            Ok(()) => VerificationFailure::Unknown,
+            Err(e) => VerificationFailure::Differs {
+                details: e.to_string(),
+            },
+        }
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["nginx"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Service,
+            self.config.display().to_string(),
+            "valid nginx configuration",
+        )
+    }
+
+    const NAME: &'static str = "nginx_config_test";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "An nginx configuration that nginx accepts.",
+        create: "Tests the configuration with `nginx -t -c <config>`, and fails if nginx reports an error. Nothing on the system is changed.",
+        verify: "Tests the configuration again.",
+        undo: "Nothing.",
+    };
+    const FAILURE_HINTS: &'static [FailureHint] = &[FailureHint {
+        pattern: "unknown directive",
+        hint: "a directive is misspelled, or belongs to a module that is not loaded",
+    }];
+}
+
+impl Display for NginxConfigTest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "nginx-config-test({})", self.config.display())
+    }
+}
+
+/// Checks that a site responds to an HTTP request with the expected status.
+/// This requirement does not change anything on the system; it is added by [`BlueGreen::install`] to gate switching to a new version.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SiteHealthCheck {
+    url: String,
+    status: u16,
+}
+
+impl SiteHealthCheck {
+    fn check<S: System>(&self, system: &mut S) -> Result<(), NginxError<S>> {
+        let result = system
+            .execute_command(
+                "curl",
+                &[
+                    "--silent",
+                    "--output",
+                    "/dev/null",
+                    "--max-time",
+                    "10",
+                    "--write-out",
+                    "%{http_code}",
+                    &self.url,
+                ],
+            )
+            .map_err(NginxError::FailedToStart)?;
+        let actual = result.stdout_as_str().trim();
+        if actual == self.status.to_string() {
+            Ok(())
+        } else {
+            Err(NginxError::Unhealthy {
+                url: self.url.clone(),
+                actual: actual.to_owned(),
+            })
+        }
+    }
+}
+
+impl Requirement for SiteHealthCheck {
+    type CreateError<S: System> = NginxError<S>;
+    type ModifyError<S: System> = NginxError<S>;
+    type DeleteError<S: System> = NeverError;
+    type HasBeenCreatedError<S: System> = NeverError;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.check(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.check(system)
+    }
+
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.url == other.url
+    }
+
+    fn identity(&self) -> String {
+        self.url.clone()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self.check(system).is_ok())
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["curl"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Service,
+            &self.url,
+            format!("responds with status {}", self.status),
+        )
+    }
+
+    const NAME: &'static str = "site_health_check";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A site that responds to an HTTP request with the expected status.",
+        create: "Sends the request with `curl`, and fails if the status differs. Nothing on the system is changed.",
+        verify: "Sends the request again.",
+        undo: "Nothing.",
+    };
+}
+
+impl Display for SiteHealthCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "health-check({})", self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Location, NginxConfigTest, ProxyPass, ReloadNginx, ServerBlock, SiteConfig,
+        SiteHealthCheck, Tls, Upstream,
+    };
+    use crate::builder::path::{Existing, Path};
+    use crate::graph::{Graph, GraphNodeReference, Pending};
+    use std::path::PathBuf;
+
+    crate::requirements!(Req = ReloadNginx, SiteHealthCheck);
+
+    #[test]
+    pub fn serialize_deserialize_reload_nginx() {
+        let r = ReloadNginx;
+        let json = r#"null"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn serialize_deserialize_nginx_config_test() {
+        let r = NginxConfigTest {
+            config: PathBuf::from("/etc/nginx/nginx.conf"),
+        };
+        let json = r#"{"config":"/etc/nginx/nginx.conf"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn serialize_deserialize_site_health_check() {
+        let r = SiteHealthCheck {
+            url: String::from("http://127.0.0.1:8080/"),
+            status: 200,
+        };
+        let json = r#"{"url":"http://127.0.0.1:8080/","status":200}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn render_site_config() {
+        let mut graph = Graph::<Req, Pending>::new();
+        let root_node = graph.add(ReloadNginx, &[]);
+        let socket_node = graph.add(ReloadNginx, &[]);
+        let path = |path: &str, node: Option<GraphNodeReference>| Path {
+            base: PathBuf::from(path),
+            path: PathBuf::new(),
+            loc: Existing,
+            node,
+        };
+
+        let app = Upstream::new("app")
+            .unix_server(&path("/run/app.sock", Some(socket_node)))
+            .keepalive(4);
+        let config = SiteConfig::new().upstream(app.clone()).server(
+            ServerBlock::new()
+                .listen("443 ssl")
+                .server_name("example.com")
+                .root(&path("/srv/www/my site", Some(root_node)))
+                .index("index.html")
+                .tls(
+                    Tls::new(
+                        &path("/etc/ssl/example.pem", None),
+                        &path("/etc/ssl/example.key", Some(root_node)),
+                    )
+                    .hsts(31536000),
+                )
+                .location(Location::new("/").try_files(&["$uri", "@app"]))
+                .location(
+                    Location::new("@app")
+                        .proxy_pass(ProxyPass::upstream(&app).set_header("Host", "$host")),
+                ),
+        );
+
+        let file = config.file("example");
+        assert_eq!(file.path(), PathBuf::from("example"));
+        assert_eq!(file.extra_dependencies, vec![socket_node, root_node]);
+        assert_eq!(
+            String::from_utf8(file.contents).unwrap(),
+            r#"upstream app {
+    server unix:/run/app.sock;
+    keepalive 4;
+}
+
+server {
+    listen 443 ssl;
+    server_name example.com;
+    root "/srv/www/my site";
+    index index.html;
+    ssl_certificate /etc/ssl/example.pem;
+    ssl_certificate_key /etc/ssl/example.key;
+    ssl_protocols TLSv1.2 TLSv1.3;
+    add_header Strict-Transport-Security "max-age=31536000" always;
+
+    location / {
+        try_files $uri @app;
+    }
+
+    location @app {
+        proxy_pass http://app;
+        proxy_set_header Host $host;
+    }
+}
+"#
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! Distribution packages that are installed with the package manager of the target distribution.
+//!
+//! Builders that need to run on more than one distribution use [`SystemPackage::install`] instead of `AptPackage::install`.
+//! The package name is usually the same on all distributions; [`SystemPackage::install_named`] handles the packages where it is not.
+use super::apt::{Apt, AptUpdate};
+use super::Context;
+use crate::batch::Probe;
+use crate::bootstrap;
+use crate::distro::{Distro, PackageManager};
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description, Fact};
+use crate::requirements::{Explanation, FailureHint, Requirement, Supports};
+use crate::system::{NeverError, System};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InstallPackage {
+    manager: PackageManager,
+    name: String,
+}
+
+impl InstallPackage {
+    pub fn new(manager: PackageManager, name: &str) -> InstallPackage {
+        InstallPackage {
+            manager,
+            name: name.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PackageError<S: System> {
+    #[error("unable to execute {}: {}", .0, .1)]
+    FailedToStart(PackageManager, S::CommandError),
+
+    #[error("{} failed: {} {}", .0, .1, .2)]
+    Unsuccessful(PackageManager, String, String),
+}
+
+impl Requirement for InstallPackage {
+    type CreateError<S: System> = PackageError<S>;
+    type ModifyError<S: System> = NeverError;
+    type DeleteError<S: System> = PackageError<S>;
+    type HasBeenCreatedError<S: System> = PackageError<S>;
+
+    fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        let (command, args) = self.manager.install_command(&self.name);
+        self.run(system, command, &args)
+    }
+
+    fn modify<S: crate::system::System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<(), Self::ModifyError<S>> {
+        Ok(())
+    }
+
+    fn delete<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        let (command, args) = self.manager.remove_command(&self.name);
+        self.run(system, command, &args)
+    }
+
+    fn has_been_created<S: crate::system::System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        let (command, args) = self.manager.query_command(&self.name);
+        let result = system
+            .execute_command(command, &args)
+            .map_err(|e| PackageError::FailedToStart(self.manager, e))?;
+
+        Ok(result.is_success() && self.manager.is_installed(result.stdout_as_str()))
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.manager == other.manager && self.name == other.name
+    }
+
+    fn identity(&self) -> String {
+        format!("{}:{}", self.manager, self.name)
+    }
+
+    fn supports_modifications(&self) -> bool {
+        false
+    }
+    fn can_undo(&self) -> bool {
+        true
+    }
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(self.has_been_created(system).unwrap())
+    }
+
+    fn verify_probes(&self) -> Vec<Probe> {
+        let (command, args) = self.manager.query_command(&self.name);
+        vec![Probe::command(command, &args)]
+    }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let (command, args) = self.manager.install_command(&self.name);
+        Some(match self.manager {
+            PackageManager::Apt => bootstrap::command(
+                &format!("DEBIAN_FRONTEND=noninteractive {}", command),
+                &args,
+            ),
+            PackageManager::Apk => bootstrap::command(command, &args),
+        })
+    }
+
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        match self.manager {
+            PackageManager::Apt => vec![Fact::AptPackage(self.name.clone())],
+            PackageManager::Apk => Vec::new(),
+        }
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        match self.manager {
+            PackageManager::Apt => vec!["apt-get", "dpkg-query"],
+            PackageManager::Apk => vec!["apk"],
+        }
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Package,
+            &self.name,
+            format!("installed with {}", self.manager),
+        )
+    }
+
+    const NAME: &'static str = "package";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A package installed with the package manager of the target distribution.",
+        create: "Installs the package with apt or apk.",
+        verify: "Asks the package manager whether the package is installed.",
+        undo: "Removes the package.",
+    };
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "Unable to locate package",
+            hint: "apt does not know the package. Check its name, and that the repository that provides it is configured and the package lists are up to date.",
+        },
+        FailureHint {
+            pattern: "Could not get lock",
+            hint: "Another apt or dpkg process is running, such as unattended-upgrades. Wait for it to finish.",
+        },
+        FailureHint {
+            pattern: "unable to select packages",
+            hint: "apk does not know the package. Check its name, and that the repository that provides it is enabled in `/etc/apk/repositories`.",
+        },
+    ];
+}
+
+impl InstallPackage {
+    fn run<S: System>(
+        &self,
+        system: &mut S,
+        command: &str,
+        args: &[&str],
+    ) -> Result<(), PackageError<S>> {
+        let result = system
+            .execute_command(command, args)
+            .map_err(|e| PackageError::FailedToStart(self.manager, e))?;
+        result.successful().map_err(|(stdout, stderr)| {
+            PackageError::Unsuccessful(self.manager, stdout.to_owned(), stderr.to_owned())
+        })
+    }
+}
+
+impl Display for InstallPackage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}({})", self.manager, self.name)
+    }
+}
+
+/// A package that is installed with the package manager of the target distribution.
+pub struct SystemPackage(GraphNodeReference);
+
+impl SystemPackage {
+    /// Installs `name` with the package manager of the distribution that is being built for.
+    pub fn install<R>(context: &mut Context<R>, name: &str) -> SystemPackage
+    where
+        R: Requirement + Supports<InstallPackage> + Supports<AptUpdate>,
+    {
+        Self::install_named(context, |_| name)
+    }
+
+    /// Like [`SystemPackage::install`], for packages that are named differently on some distributions.
+    pub fn install_named<'n, R>(
+        context: &mut Context<R>,
+        name: impl FnOnce(Distro) -> &'n str,
+    ) -> SystemPackage
+    where
+        R: Requirement + Supports<InstallPackage> + Supports<AptUpdate>,
+    {
+        let distro = context.distro();
+        let manager = distro.package_manager();
+        // apk updates its index while installing, apt needs a separate update that is shared with `AptPackage`
+        let dependencies = match manager {
+            PackageManager::Apt => Apt::update(context),
+            PackageManager::Apk => Vec::new(),
+        };
+
+        SystemPackage(context.add_node(InstallPackage::new(manager, name(distro)), &dependencies))
+    }
+
+    pub fn graph_node(&self) -> GraphNodeReference {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InstallPackage;
+    use crate::distro::PackageManager;
+
+    #[test]
+    pub fn serialize_deserialize_install_package() {
+        let r = InstallPackage::new(PackageManager::Apk, "nginx");
+        let json = r#"{"manager":"apk","name":"nginx"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+}
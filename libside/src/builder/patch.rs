@@ -0,0 +1,543 @@
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, Requirement, Supports};
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use super::fs::Sha3;
+use super::Context;
+
+/// A single change to a part of a file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Patch {
+    /// Sets `key = value` in `section` of an ini-style file, such as `php.ini` or `my.cnf`.
+    /// `section` is `None` for keys before the first section. The key is added if it does not exist.
+    /// An empty value is written as a bare key, as used by `my.cnf` for flags like `skip-name-resolve`.
+    IniSet {
+        section: Option<String>,
+        key: String,
+        value: String,
+    },
+
+    /// Replaces the line `old` with `new`. If `old` does not exist, `new` is appended to the file.
+    ReplaceLine { old: String, new: String },
+}
+
+impl Patch {
+    /// Returns the part of `contents` that this patch replaces, or `None` if the patch adds something new.
+    fn original(&self, contents: &str) -> Option<String> {
+        let lines = split_lines(contents);
+        match self {
+            Patch::IniSet { section, key, .. } => {
+                ini_find(&lines, section.as_deref(), key).map(|(_, value)| value)
+            }
+            Patch::ReplaceLine { old, .. } => find_line(&lines, old).map(|_| old.clone()),
+        }
+    }
+
+    fn apply(&self, contents: &str) -> String {
+        let mut lines = split_lines(contents);
+        match self {
+            Patch::IniSet {
+                section,
+                key,
+                value,
+            } => ini_set(&mut lines, section.as_deref(), key, value),
+            Patch::ReplaceLine { old, new } => {
+                if find_line(&lines, new).is_none() {
+                    match find_line(&lines, old) {
+                        Some(index) => lines[index] = new.clone(),
+                        None => lines.push(new.clone()),
+                    }
+                }
+            }
+        }
+
+        join_lines(lines)
+    }
+
+    /// Reverts the patch, given the value that `original` returned before the patch was applied.
+    fn undo(&self, contents: &str, original: Option<&str>) -> String {
+        let mut lines = split_lines(contents);
+        match (self, original) {
+            (Patch::IniSet { section, key, .. }, Some(original)) => {
+                ini_set(&mut lines, section.as_deref(), key, original)
+            }
+            (Patch::IniSet { section, key, .. }, None) => {
+                if let Some((index, _)) = ini_find(&lines, section.as_deref(), key) {
+                    lines.remove(index);
+                }
+            }
+            (Patch::ReplaceLine { new, .. }, original) => {
+                if let Some(index) = find_line(&lines, new) {
+                    match original {
+                        Some(original) => lines[index] = original.to_owned(),
+                        None => {
+                            lines.remove(index);
+                        }
+                    }
+                }
+            }
+        }
+
+        join_lines(lines)
+    }
+
+    fn is_applied(&self, contents: &str) -> bool {
+        let lines = split_lines(contents);
+        match self {
+            Patch::IniSet {
+                section,
+                key,
+                value,
+            } => ini_find(&lines, section.as_deref(), key)
+                .map(|(_, current)| &current == value)
+                .unwrap_or(false),
+            Patch::ReplaceLine { new, .. } => find_line(&lines, new).is_some(),
+        }
+    }
+}
+
+impl Display for Patch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Patch::IniSet {
+                section: Some(section),
+                key,
+                value,
+            } => write!(f, "[{}] {} = {}", section, key, value),
+            Patch::IniSet {
+                section: None,
+                key,
+                value,
+            } => write!(f, "{} = {}", key, value),
+            Patch::ReplaceLine { new, .. } => write!(f, "{}", new),
+        }
+    }
+}
+
+fn split_lines(contents: &str) -> Vec<String> {
+    contents.lines().map(str::to_owned).collect()
+}
+
+fn join_lines(lines: Vec<String>) -> String {
+    let mut result = lines.join("\n");
+    if !result.is_empty() {
+        result.push('\n');
+    }
+
+    result
+}
+
+fn find_line(lines: &[String], line: &str) -> Option<usize> {
+    lines.iter().position(|l| l.trim() == line.trim())
+}
+
+fn section_header(line: &str) -> Option<&str> {
+    let line = line.trim();
+    line.strip_prefix('[')?.strip_suffix(']').map(str::trim)
+}
+
+/// Parses a `key = value` line into the key and the value. Comments, section headers and empty lines are not keys.
+fn ini_entry(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with(';') || line.starts_with('#') || line.starts_with('[') {
+        return None;
+    }
+
+    Some(match line.split_once('=') {
+        Some((key, value)) => (key.trim(), value.trim()),
+        None => (line, ""),
+    })
+}
+
+/// Returns the index and the value of `key` in `section`.
+fn ini_find(lines: &[String], section: Option<&str>, key: &str) -> Option<(usize, String)> {
+    let mut current = None;
+    for (index, line) in lines.iter().enumerate() {
+        if let Some(header) = section_header(line) {
+            current = Some(header);
+        } else if current == section {
+            match ini_entry(line) {
+                Some((k, value)) if k == key => return Some((index, value.to_owned())),
+                _ => {}
+            }
+        }
+    }
+
+    None
+}
+
+fn ini_set(lines: &mut Vec<String>, section: Option<&str>, key: &str, value: &str) {
+    let line = if value.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{} = {}", key, value)
+    };
+
+    if let Some((index, _)) = ini_find(lines, section, key) {
+        lines[index] = line;
+        return;
+    }
+
+    // Add the key after the last non-empty line of the section
+    let mut current = None;
+    let mut end = None;
+    for (index, l) in lines.iter().enumerate() {
+        if let Some(header) = section_header(l) {
+            current = Some(header);
+            if current == section {
+                end = Some(index + 1);
+            }
+        } else if current == section && !l.trim().is_empty() {
+            end = Some(index + 1);
+        }
+    }
+
+    match (end, section) {
+        (Some(end), _) => lines.insert(end, line),
+        (None, None) => lines.insert(0, line),
+        (None, Some(section)) => {
+            if lines.last().map(|l| !l.trim().is_empty()).unwrap_or(false) {
+                lines.push(String::new());
+            }
+
+            lines.push(format!("[{}]", section));
+            lines.push(line);
+        }
+    }
+}
+
+/// Applies a list of patches to a file that is shipped by someone else, such as a distribution package.
+///
+/// Instead of replacing the whole file, only the patched keys and lines are managed, so the rest of the file can still be updated by the package manager.
+/// The values that the patches replace are saved in the backups directory, so that undoing the requirement only reverts its own changes.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PatchFile {
+    path: PathBuf,
+    patches: Vec<Patch>,
+
+    /// Where the replaced values are saved.
+    originals: PathBuf,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError<S: System> {
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("the saved original values in {} are invalid: {}", .0.display(), .1)]
+    InvalidOriginals(PathBuf, serde_json::Error),
+}
+
+impl PatchFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> PatchFile {
+        PatchFile {
+            path: path.into(),
+            patches: Vec::new(),
+            originals: PathBuf::new(),
+        }
+    }
+
+    pub fn ini_set(mut self, section: Option<&str>, key: &str, value: &str) -> Self {
+        self.patches.push(Patch::IniSet {
+            section: section.map(str::to_owned),
+            key: key.to_owned(),
+            value: value.to_owned(),
+        });
+        self
+    }
+
+    pub fn replace_line(mut self, old: &str, new: &str) -> Self {
+        self.patches.push(Patch::ReplaceLine {
+            old: old.to_owned(),
+            new: new.to_owned(),
+        });
+        self
+    }
+
+    /// Adds the patches to the graph, applied after `dependencies`.
+    /// The original values are saved to a path that only depends on the file and the patches, so that an unchanged patch is not re-applied by every install.
+    pub fn add<'r, R, I>(mut self, context: &mut Context<R>, dependencies: I) -> GraphNodeReference
+    where
+        R: Requirement + Supports<PatchFile>,
+        I: IntoIterator<Item = &'r GraphNodeReference>,
+    {
+        let key = serde_json::to_vec(&(&self.path, &self.patches)).unwrap();
+        self.originals = context.patches_path.join(Sha3::hash(&key).to_string());
+        context.add_node(self, dependencies)
+    }
+
+    fn contents<S: System>(&self, system: &S) -> Result<String, PatchError<S>> {
+        let contents = system
+            .file_contents(&self.path)
+            .map_err(|e| PatchError::UnableToRead(self.path.clone(), e))?;
+        Ok(String::from_utf8_lossy(&contents).into_owned())
+    }
+
+    fn write<S: System>(&self, system: &S, contents: &str) -> Result<(), PatchError<S>> {
+        system
+            .put_file_contents(&self.path, contents.as_bytes())
+            .map_err(|e| PatchError::UnableToWrite(self.path.clone(), e))
+    }
+
+    fn apply_all(&self, contents: &str) -> String {
+        self.patches
+            .iter()
+            .fold(contents.to_owned(), |contents, patch| {
+                patch.apply(&contents)
+            })
+    }
+}
+
+impl Requirement for PatchFile {
+    const NAME: &'static str = "patch_file";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "Changes to individual keys and lines of a file that someone else ships, such as a distribution package.",
+        create: "Saves the values that the patches replace in the backups directory, then applies the patches. The rest of the file is left alone.",
+        verify: "Reads the file and checks that every patch is applied.",
+        undo: "Restores the saved values, so that only the changes of the requirement are reverted.",
+    };
+
+    type CreateError<S: System> = PatchError<S>;
+    type ModifyError<S: System> = PatchError<S>;
+    type DeleteError<S: System> = PatchError<S>;
+    type HasBeenCreatedError<S: System> = PatchError<S>;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        let contents = self.contents(system)?;
+
+        // Each patch sees the file as it is after the patches before it have been applied
+        let mut patched = contents.clone();
+        let mut originals = Vec::new();
+        for patch in self.patches.iter() {
+            originals.push(patch.original(&patched));
+            patched = patch.apply(&patched);
+        }
+
+        // The originals are saved first, so that an interrupted apply can still be undone
+        if let Some(dir) = self.originals.parent() {
+            system
+                .make_dir_all(dir)
+                .map_err(|e| PatchError::UnableToWrite(dir.to_owned(), e))?;
+        }
+        system
+            .put_file_contents(
+                &self.originals,
+                serde_json::to_string(&originals).unwrap().as_bytes(),
+            )
+            .map_err(|e| PatchError::UnableToWrite(self.originals.clone(), e))?;
+
+        if patched != contents {
+            self.write(system, &patched)?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-applies patches that have been reverted by someone else, for example by a package upgrade that replaced the file.
+    /// The saved original values are kept.
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        let contents = self.contents(system)?;
+        let patched = self.apply_all(&contents);
+        if patched != contents {
+            self.write(system, &patched)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        let saved = system
+            .file_contents(&self.originals)
+            .map_err(|e| PatchError::UnableToRead(self.originals.clone(), e))?;
+        let originals: Vec<Option<String>> = serde_json::from_slice(&saved)
+            .map_err(|e| PatchError::InvalidOriginals(self.originals.clone(), e))?;
+
+        let contents = self.contents(system)?;
+        let reverted = self
+            .patches
+            .iter()
+            .zip(originals.iter())
+            .rev()
+            .fold(contents.clone(), |contents, (patch, original)| {
+                patch.undo(&contents, original.as_deref())
+            });
+        if reverted != contents {
+            self.write(system, &reverted)?;
+        }
+
+        system
+            .remove_file(&self.originals)
+            .map_err(|e| PatchError::UnableToWrite(self.originals.clone(), e))
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        system
+            .path_exists(&self.originals)
+            .map_err(|e| PatchError::UnableToRead(self.originals.clone(), e))
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self == other
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        true
+    }
+
+    /// The original values may have been saved by an apply that was interrupted. They are kept, and the patches are applied again.
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(match self.contents(system) {
+            Ok(contents) => self.patches.iter().all(|patch| patch.is_applied(&contents)),
+            Err(_) => false,
+        })
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::File,
+            self.path.display().to_string(),
+            format!(
+                "patched: {}",
+                self.patches
+                    .iter()
+                    .map(|patch| patch.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )
+    }
+}
+
+impl Display for PatchFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "patch({})", self.path.display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Patch, PatchFile};
+    use crate::requirements::Requirement;
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use std::path::PathBuf;
+
+    const PHP_INI: &str = "[PHP]\nengine = On\nmemory_limit = 128M\n\n[Date]\n;date.timezone =\n";
+
+    fn ini_set(section: Option<&str>, key: &str, value: &str) -> Patch {
+        Patch::IniSet {
+            section: section.map(str::to_owned),
+            key: key.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    #[test]
+    pub fn serialize_deserialize_patch_file() {
+        let mut r = PatchFile::new("/etc/php.ini")
+            .ini_set(Some("PHP"), "memory_limit", "256M")
+            .replace_line("#Port 22", "Port 2222");
+        r.originals = PathBuf::from("/srv/backups/_patches/abc");
+        let json = r##"{"path":"/etc/php.ini","patches":[{"type":"ini_set","section":"PHP","key":"memory_limit","value":"256M"},{"type":"replace_line","old":"#Port 22","new":"Port 2222"}],"originals":"/srv/backups/_patches/abc"}"##;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn ini_set_replaces_and_adds_keys() {
+        let existing = ini_set(Some("PHP"), "memory_limit", "256M");
+        let new = ini_set(Some("Date"), "date.timezone", "UTC");
+        let section = ini_set(Some("mysqld"), "skip-name-resolve", "");
+
+        assert_eq!(existing.original(PHP_INI), Some(String::from("128M")));
+        assert_eq!(new.original(PHP_INI), None);
+        assert_eq!(
+            existing.apply(PHP_INI),
+            "[PHP]\nengine = On\nmemory_limit = 256M\n\n[Date]\n;date.timezone =\n"
+        );
+        assert_eq!(
+            new.apply(PHP_INI),
+            "[PHP]\nengine = On\nmemory_limit = 128M\n\n[Date]\n;date.timezone =\ndate.timezone = UTC\n"
+        );
+        assert_eq!(
+            section.apply("[client]\nport = 3306\n"),
+            "[client]\nport = 3306\n\n[mysqld]\nskip-name-resolve\n"
+        );
+        assert!(existing.is_applied(&existing.apply(PHP_INI)));
+        assert!(!existing.is_applied(PHP_INI));
+    }
+
+    #[test]
+    pub fn undo_reverts_only_the_patch() {
+        let existing = ini_set(Some("PHP"), "memory_limit", "256M");
+        let new = ini_set(Some("Date"), "date.timezone", "UTC");
+        let line = Patch::ReplaceLine {
+            old: String::from("engine = On"),
+            new: String::from("engine = Off"),
+        };
+
+        // A package upgrade changed another key after the patches were applied
+        let patched = line.apply(&new.apply(&existing.apply(PHP_INI)));
+        let upgraded = patched.replace("[Date]\n", "[Date]\ndate.format = iso\n");
+
+        let reverted = existing.undo(&upgraded, Some("128M"));
+        let reverted = new.undo(&reverted, None);
+        let reverted = line.undo(&reverted, Some("engine = On"));
+
+        assert_eq!(
+            reverted,
+            "[PHP]\nengine = On\nmemory_limit = 128M\n\n[Date]\ndate.format = iso\n;date.timezone =\n"
+        );
+    }
+
+    #[test]
+    pub fn patch_file_is_applied_and_undone() {
+        let dir = TempDir::new("patch");
+        std::fs::write(dir.join("php.ini"), PHP_INI).unwrap();
+        let mut patch = PatchFile::new(dir.join("php.ini"))
+            .ini_set(Some("PHP"), "memory_limit", "256M")
+            .ini_set(Some("Date"), "date.timezone", "UTC");
+        patch.originals = dir.join("originals/php.ini");
+        let mut system = LocalSystem::new();
+
+        let before = patch.has_been_created(&mut system).unwrap();
+        patch.create(&mut system).unwrap();
+        let created = patch.has_been_created(&mut system).unwrap();
+        let verified = patch.verify(&mut system).unwrap();
+        std::fs::write(dir.join("php.ini"), PHP_INI).unwrap();
+        let reverted_externally = patch.verify(&mut system).unwrap();
+        patch.modify(&mut system).unwrap();
+        let fixed = patch.verify(&mut system).unwrap();
+        patch.delete(&mut system).unwrap();
+        let restored = std::fs::read_to_string(dir.join("php.ini")).unwrap();
+        let after = patch.has_been_created(&mut system).unwrap();
+
+        assert!(!before);
+        assert!(created);
+        assert!(verified);
+        assert!(!reverted_externally);
+        assert!(fixed);
+        assert_eq!(restored, PHP_INI);
+        assert!(!after);
+    }
+}
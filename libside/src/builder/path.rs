@@ -10,6 +10,7 @@ use std::path::PathBuf;
 use super::fs::Chmod;
 use super::fs::Chown;
 use super::fs::ConfigFileData;
+use super::fs::Symlink;
 use super::users::Group;
 use super::users::User;
 use super::AsParam;
@@ -25,6 +26,11 @@ pub struct Source<'a>(pub(crate) &'a PackageInfo);
 #[derive(Clone)]
 pub struct Exposed(pub(crate) PathBuf);
 
+/// A path through a symlink next to the versions of an exposed path, created with [`Path::current`].
+/// The link is switched to the new version during the apply, so the path stays the same across versions.
+#[derive(Clone)]
+pub struct Current;
+
 /// A path inside a created chroot directory.
 #[derive(Clone)]
 pub struct Chroot;
@@ -64,6 +70,7 @@ impl SpeculatePath for Chroot {}
 impl SpeculatePath for WillBeCreated {}
 impl SpeculatePath for Existing {}
 impl SpeculatePath for Mounted {}
+impl SpeculatePath for Current {}
 
 pub trait CanWritePath {}
 impl CanWritePath for WillBeCreated {}
@@ -252,7 +259,9 @@ impl<L: Clone> AsParam for Path<L> {
 impl<'a> Path<Source<'a>> {
     pub fn join<P: AsRef<StdPath>>(&self, name: P) -> Result<Path<Source<'a>>, ()> {
         let new = self.join_unchecked(name)?;
-        if self.loc.0.files.contains(&new.full_path()) {
+        if self.loc.0.filter.is_excluded(&new.path) {
+            panic!("Tried to access excluded file in package: {}", new);
+        } else if self.loc.0.files.contains(&new.full_path()) {
             Ok(new)
         } else {
             panic!("Tried to access non-existant file in package: {}", new);
@@ -279,14 +288,48 @@ impl Path<Existing> {
 
         // unwrap is OK because name is a single, normal, component
         let new = self.join_unchecked(name).unwrap();
-        let node = context
-            .graph
-            .add(CreateDirectory::new(new.full_path()), self.node.as_ref());
+        let node = context.add_directory(CreateDirectory::new(new.full_path()), self.node.as_ref());
 
         new.cast_unchecked(WillBeCreated).with_node(node)
     }
 }
 
+impl Path<Exposed> {
+    /// Points the `current` symlink next to the versioned directories of this exposed path at this version.
+    /// The returned path goes through the link, so external scripts and service configs can use it across versions.
+    pub fn current<R>(&self, context: &mut Context<R>) -> Path<Current>
+    where
+        R: Requirement + Supports<Symlink>,
+    {
+        self.link_version(context, "current", self.node.iter())
+    }
+
+    /// Like [`Path::current`], but with a custom name for the link, which is only switched after `after` has been applied.
+    pub fn link_version<'r, R, I: IntoIterator<Item = &'r GraphNodeReference>>(
+        &self,
+        context: &mut Context<R>,
+        name: &str,
+        after: I,
+    ) -> Path<Current>
+    where
+        R: Requirement + Supports<Symlink>,
+    {
+        assert!(path_is_safe(StdPath::new(name)) && StdPath::new(name).components().count() == 1);
+
+        // The link is relative, so that it points into the same directory wherever the state directory is mounted
+        let link = self.loc.0.join(name);
+        let version = self.base.strip_prefix(&self.loc.0).unwrap().to_path_buf();
+        let node = context.add_node(Symlink::new(link.clone(), version), after);
+
+        Path {
+            base: link,
+            path: self.path.clone(),
+            loc: Current,
+            node: Some(node),
+        }
+    }
+}
+
 impl Path<Chroot> {
     pub fn make_dir<P: AsRef<StdPath>, R: Requirement>(
         &self,
@@ -306,7 +349,7 @@ impl Path<Chroot> {
 
         // unwrap is OK because name is a single, normal, component
         let new = self.join_unchecked(name).unwrap();
-        let node = context.graph.add(
+        let node = context.add_directory(
             CreateDirectory::new_without_cleanup(new.full_path()),
             self.node.as_ref(),
         );
@@ -348,7 +391,7 @@ impl Path<Userdata> {
 
         // unwrap is OK because name is a single, normal, component
         let new = self.join_unchecked(name).unwrap();
-        let node = context.graph.add(
+        let node = context.add_directory(
             CreateDirectory::new_without_cleanup(new.full_path()),
             self.node.as_ref(),
         );
@@ -376,7 +419,7 @@ impl Path<Backup> {
 
         // unwrap is OK because name is a single, normal, component
         let new = self.join_unchecked(name).unwrap();
-        let node = context.graph.add(
+        let node = context.add_directory(
             CreateDirectory::new_without_cleanup(new.full_path()),
             self.node.as_ref(),
         );
@@ -404,9 +447,7 @@ impl Path<SharedConfig> {
 
         // unwrap is OK because name is a single, normal, component
         let new = self.join_unchecked(name).unwrap();
-        let node = context
-            .graph
-            .add(CreateDirectory::new(new.full_path()), self.node.as_ref());
+        let node = context.add_directory(CreateDirectory::new(new.full_path()), self.node.as_ref());
 
         new.with_node(node)
     }
@@ -417,7 +458,7 @@ impl Path<SharedConfig> {
         file: ConfigFileData,
     ) -> Path<SharedConfig>
     where
-        R: Supports<FileWithContents>,
+        R: Supports<CreateDirectory> + Supports<FileWithContents>,
     {
         assert!(
             file.path().components().count() == 1
@@ -461,6 +502,17 @@ impl Bindable for Path<Exposed> {
     }
 }
 
+impl Bindable for Path<Current> {
+    fn bind(&self) -> BindPath {
+        BindPath {
+            mount_path: self.full_path(),
+            path_postfix: PathBuf::new(),
+            in_dir: None,
+            nodes: self.node.iter().copied().collect(),
+        }
+    }
+}
+
 impl Bindable for Path<SharedConfig> {
     fn bind(&self) -> BindPath {
         BindPath {
@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use super::AsParam;
+use crate::system::System;
+
+/// The range from which ports are allocated by [`super::Context::port`].
+pub const DEFAULT_RANGE: RangeInclusive<u16> = 20000..=29999;
+
+/// A TCP port that has been allocated to a package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Port(u16);
+
+impl Port {
+    pub fn number(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Display for Port {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl AsParam for Port {
+    fn as_param(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Allocation {
+    package: String,
+    name: String,
+    port: u16,
+}
+
+impl Allocation {
+    fn is(&self, package: &str, name: &str) -> bool {
+        self.package == package && self.name == name
+    }
+}
+
+impl Display for Allocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.package, self.name)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PortError {
+    #[error("port {} for {}/{} is already used by {}", .port, .package, .name, .used_by)]
+    Conflict {
+        port: u16,
+        package: String,
+        name: String,
+        used_by: String,
+    },
+
+    #[error("no free ports left in {}-{} for {}/{}", .range.start(), .range.end(), .package, .name)]
+    Exhausted {
+        range: RangeInclusive<u16>,
+        package: String,
+        name: String,
+    },
+}
+
+/// The ports of all packages.
+/// Allocations are loaded from the previous build, so that a package keeps its ports across versions.
+/// Only the ports that are claimed again in the current build are saved.
+pub struct PortRegistry {
+    previous: Vec<Allocation>,
+    claimed: Vec<Allocation>,
+    range: RangeInclusive<u16>,
+}
+
+impl Default for PortRegistry {
+    fn default() -> Self {
+        PortRegistry {
+            previous: Vec::new(),
+            claimed: Vec::new(),
+            range: DEFAULT_RANGE,
+        }
+    }
+}
+
+impl PortRegistry {
+    pub fn load<S: System>(path: &Path, system: &S) -> Result<PortRegistry, S::Error> {
+        let previous = if system.path_exists(path)? {
+            let contents = system.file_contents(path)?;
+            serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                tracing::warn!(
+                    "Ignoring invalid port allocations in {}: {}",
+                    path.display(),
+                    e
+                );
+                Vec::new()
+            })
+        } else {
+            Vec::new()
+        };
+
+        Ok(PortRegistry {
+            previous,
+            ..Default::default()
+        })
+    }
+
+    pub fn save<S: System>(&self, path: &Path, system: &S) -> Result<(), S::Error> {
+        system.put_file_contents(path, &serde_json::to_vec(&self.claimed).unwrap())
+    }
+
+    fn used_by(&self, port: u16) -> Option<&Allocation> {
+        self.claimed.iter().find(|a| a.port == port)
+    }
+
+    /// Claims `port` for `package`/`name`, or allocates a port if `port` is `None`.
+    /// Claiming the same name twice returns the same port.
+    pub fn claim(
+        &mut self,
+        package: &str,
+        name: &str,
+        port: Option<u16>,
+    ) -> Result<Port, PortError> {
+        if let Some(existing) = self.claimed.iter().find(|a| a.is(package, name)) {
+            if port.map(|port| port == existing.port).unwrap_or(true) {
+                return Ok(Port(existing.port));
+            }
+        }
+
+        let port = match port {
+            Some(port) => {
+                if let Some(other) = self.used_by(port) {
+                    return Err(PortError::Conflict {
+                        port,
+                        package: package.to_owned(),
+                        name: name.to_owned(),
+                        used_by: other.to_string(),
+                    });
+                }
+
+                port
+            }
+            None => {
+                let previous = self
+                    .previous
+                    .iter()
+                    .find(|a| a.is(package, name))
+                    .map(|a| a.port)
+                    .filter(|&port| self.used_by(port).is_none());
+
+                // Ports that other packages used in the previous build are skipped, so that they can keep them
+                match previous.or_else(|| {
+                    self.range.clone().find(|&port| {
+                        self.used_by(port).is_none()
+                            && !self
+                                .previous
+                                .iter()
+                                .any(|a| a.port == port && !a.is(package, name))
+                    })
+                }) {
+                    Some(port) => port,
+                    None => {
+                        return Err(PortError::Exhausted {
+                            range: self.range.clone(),
+                            package: package.to_owned(),
+                            name: name.to_owned(),
+                        })
+                    }
+                }
+            }
+        };
+
+        self.claimed.retain(|a| !a.is(package, name));
+        self.claimed.push(Allocation {
+            package: package.to_owned(),
+            name: name.to_owned(),
+            port,
+        });
+
+        Ok(Port(port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PortError, PortRegistry};
+
+    #[test]
+    pub fn allocate_ports() {
+        let mut ports = PortRegistry {
+            range: 8000..=8002,
+            ..Default::default()
+        };
+
+        assert_eq!(ports.claim("www", "http", None).unwrap().number(), 8000);
+        assert_eq!(ports.claim("www", "http", None).unwrap().number(), 8000);
+        assert_eq!(
+            ports.claim("api", "http", Some(8001)).unwrap().number(),
+            8001
+        );
+        assert_eq!(ports.claim("api", "admin", None).unwrap().number(), 8002);
+        assert!(matches!(
+            ports.claim("blog", "http", None),
+            Err(PortError::Exhausted { .. })
+        ));
+        assert!(matches!(
+            ports.claim("blog", "http", Some(8000)),
+            Err(PortError::Conflict { used_by, .. }) if used_by == "www/http"
+        ));
+    }
+
+    #[test]
+    pub fn keep_ports_across_builds() {
+        let mut first = PortRegistry {
+            range: 8000..=8009,
+            ..Default::default()
+        };
+        first.claim("www", "http", None).unwrap();
+        first.claim("api", "http", None).unwrap();
+
+        // `www` is built after a new package that also needs a port, but keeps its port
+        let mut second = PortRegistry {
+            previous: first.claimed.clone(),
+            range: 8000..=8009,
+            ..Default::default()
+        };
+        assert_eq!(second.claim("new", "http", None).unwrap().number(), 8002);
+        assert_eq!(second.claim("www", "http", None).unwrap().number(), 8000);
+        assert_eq!(second.claimed.len(), 2);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::batch::Probe;
+use crate::graph::GraphNodeReference;
+use crate::reboot::{write_marker, RebootError};
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, Requirement, Supports};
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use super::fs::Sha3;
+use super::Context;
+
+/// Marks a change that only takes effect after the system has been rebooted, such as a kernel upgrade.
+///
+/// The first time the requirement is applied, it records a pending reboot in the reboots directory, which is shown by `side status`.
+/// A change is identified by its `key`, for example the version of the kernel: the reboot is only needed again when the key changes.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RequiresReboot {
+    key: String,
+    reason: String,
+    marker: PathBuf,
+}
+
+impl RequiresReboot {
+    /// Adds a reboot marker that is applied after `dependencies`.
+    pub fn add<'r, R, I>(
+        context: &mut Context<R>,
+        key: &str,
+        reason: &str,
+        dependencies: I,
+    ) -> GraphNodeReference
+    where
+        R: Requirement + Supports<RequiresReboot>,
+        I: IntoIterator<Item = &'r GraphNodeReference>,
+    {
+        let marker = context
+            .reboots_path
+            .join(Sha3::hash(key.as_bytes()).to_string());
+        context.add_node(
+            RequiresReboot {
+                key: key.to_owned(),
+                reason: reason.to_owned(),
+                marker,
+            },
+            dependencies,
+        )
+    }
+}
+
+impl Requirement for RequiresReboot {
+    const NAME: &'static str = "requires_reboot";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A change that only takes effect after a reboot, such as a kernel upgrade.",
+        create: "Records a pending reboot in the reboots directory, which is shown by `side status`. The reboot is only needed again when the key of the change changes.",
+        verify: "Checks that the marker of the pending reboot exists.",
+        undo: "Removes the marker.",
+    };
+
+    type CreateError<S: System> = RebootError<S>;
+    type ModifyError<S: System> = RebootError<S>;
+    type DeleteError<S: System> = RebootError<S>;
+    type HasBeenCreatedError<S: System> = RebootError<S>;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        write_marker(system, &self.marker, &self.reason)
+    }
+
+    fn modify<S: System>(&self, _system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        // The marker already exists, so the change has been recorded before
+        Ok(())
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        system
+            .remove_file(&self.marker)
+            .map_err(|e| RebootError::UnableToWrite(self.marker.clone(), e))
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        system
+            .path_exists(&self.marker)
+            .map_err(|e| RebootError::UnableToRead(self.marker.clone(), e))
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+
+    fn identity(&self) -> String {
+        self.key.clone()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        true
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(system.path_exists(&self.marker).unwrap_or(false))
+    }
+
+    fn verify_probes(&self) -> Vec<Probe> {
+        vec![Probe::PathExists(self.marker.clone())]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Other,
+            &self.key,
+            format!("requires a reboot: {}", self.reason),
+        )
+    }
+}
+
+impl Display for RequiresReboot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reboot({})", self.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequiresReboot;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn serialize_deserialize_requires_reboot() {
+        let r = RequiresReboot {
+            key: "linux-image-6.1.0-13".to_string(),
+            reason: "kernel upgrade".to_string(),
+            marker: PathBuf::from("/srv/reboots/abc"),
+        };
+        let json = r#"{"key":"linux-image-6.1.0-13","reason":"kernel upgrade","marker":"/srv/reboots/abc"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+}
@@ -0,0 +1,637 @@
+//! The service managers that run services: systemd, and OpenRC or SysV init for minimal containers and distributions without systemd.
+//!
+//! Services are described as systemd units with [`ServiceData`](super::systemd::ServiceData), whichever service manager runs them.
+//! The service manager is detected when a build starts, and is available to builders with `Context::service_manager`.
+//! The requirements that start and enable services record their service manager, and run its commands through its [`ServiceBackend`].
+//!
+//! OpenRC and SysV init run every service from an init script in `/etc/init.d`, which is generated from its unit.
+//! The scripts only keep the command, user, group, working directory and environment of the service.
+//! The sandboxing, resource control and restart policies of systemd are not available, and are dropped with a warning.
+//! Drop-in overrides are ignored as well, and timers and template units require systemd.
+use crate::bootstrap::quote;
+use crate::distro::ServiceManager;
+use std::path::PathBuf;
+
+/// A command that manages services.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub program: &'static str,
+    pub args: Vec<String>,
+}
+
+impl Invocation {
+    fn new(program: &'static str, args: &[&str]) -> Invocation {
+        Invocation {
+            program,
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    /// Runs `script` with `sh -c`.
+    fn shell(script: String) -> Invocation {
+        Invocation {
+            program: "sh",
+            args: vec![String::from("-c"), script],
+        }
+    }
+
+    pub fn args(&self) -> Vec<&str> {
+        self.args.iter().map(String::as_str).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Start,
+    Stop,
+    Restart,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Start => "start",
+            Action::Stop => "stop",
+            Action::Restart => "restart",
+        }
+    }
+}
+
+/// The file that defines a service for a service manager.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceFile {
+    pub path: PathBuf,
+    pub contents: Vec<u8>,
+
+    /// Init scripts must be executable.
+    pub executable: bool,
+
+    /// The directives of the unit that the service manager does not support, and that were dropped.
+    pub ignored: Vec<String>,
+}
+
+/// The commands and files of a service manager. Services are always named by their systemd unit, such as `nginx.service`.
+pub trait ServiceBackend {
+    /// The command that starts, stops or restarts `unit`.
+    fn control(&self, action: Action, unit: &str) -> Invocation;
+
+    /// The command that succeeds if `unit` is running.
+    fn is_active(&self, unit: &str) -> Invocation;
+
+    /// The command that enables or disables `unit` at boot.
+    fn set_enabled(&self, unit: &str, enabled: bool) -> Invocation;
+
+    /// The command whose result [`ServiceBackend::parse_enabled`] parses.
+    fn is_enabled(&self, unit: &str) -> Invocation;
+
+    /// Whether `unit` is enabled at boot, given whether [`ServiceBackend::is_enabled`] succeeded and its output.
+    /// Returns `None` if the unit is neither enabled nor disabled, such as a masked systemd unit.
+    fn parse_enabled(&self, unit: &str, success: bool, stdout: &str) -> Option<bool>;
+
+    /// The file that defines `unit`, given the contents of its systemd unit file.
+    fn service_file(&self, unit: &str, contents: &[u8]) -> ServiceFile;
+
+    /// The commands that the invocations run.
+    fn required_commands(&self) -> Vec<&'static str>;
+}
+
+pub struct Systemd;
+
+impl ServiceBackend for Systemd {
+    fn control(&self, action: Action, unit: &str) -> Invocation {
+        Invocation::new("systemctl", &[action.as_str(), unit])
+    }
+
+    fn is_active(&self, unit: &str) -> Invocation {
+        Invocation::new("systemctl", &["is-active", unit])
+    }
+
+    fn set_enabled(&self, unit: &str, enabled: bool) -> Invocation {
+        let keyword = if enabled { "enable" } else { "disable" };
+        Invocation::new("systemctl", &[keyword, unit])
+    }
+
+    fn is_enabled(&self, unit: &str) -> Invocation {
+        Invocation::new("systemctl", &["is-enabled", unit])
+    }
+
+    fn parse_enabled(&self, _unit: &str, success: bool, stdout: &str) -> Option<bool> {
+        if !success {
+            return Some(false);
+        }
+
+        match stdout.trim() {
+            "enabled" => Some(true),
+            "disabled" | "static" => Some(false),
+            _ => None,
+        }
+    }
+
+    fn service_file(&self, unit: &str, contents: &[u8]) -> ServiceFile {
+        ServiceFile {
+            path: PathBuf::from("/etc/systemd/system").join(unit),
+            contents: contents.to_vec(),
+            executable: false,
+            ignored: Vec::new(),
+        }
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["systemctl"]
+    }
+}
+
+pub struct OpenRc;
+
+impl ServiceBackend for OpenRc {
+    fn control(&self, action: Action, unit: &str) -> Invocation {
+        Invocation::new("rc-service", &[script_name(unit), action.as_str()])
+    }
+
+    fn is_active(&self, unit: &str) -> Invocation {
+        Invocation::new("rc-service", &[script_name(unit), "status"])
+    }
+
+    fn set_enabled(&self, unit: &str, enabled: bool) -> Invocation {
+        let name = quote(script_name(unit));
+        if enabled {
+            Invocation::shell(format!("rc-update add {} default", name))
+        } else {
+            // rc-update fails to remove a service that is not in the runlevel
+            Invocation::shell(format!(
+                "if rc-update show default | grep -q {}; then rc-update del {} default; fi",
+                quote(&format!("^ *{} |", script_name(unit))),
+                name
+            ))
+        }
+    }
+
+    fn is_enabled(&self, _unit: &str) -> Invocation {
+        Invocation::new("rc-update", &["show", "default"])
+    }
+
+    fn parse_enabled(&self, unit: &str, success: bool, stdout: &str) -> Option<bool> {
+        // Every line contains a service and its runlevels: `     nginx | default`
+        Some(
+            success
+                && stdout.lines().any(|line| {
+                    line.split_once('|')
+                        .map(|(name, _)| name.trim() == script_name(unit))
+                        .unwrap_or(false)
+                }),
+        )
+    }
+
+    fn service_file(&self, unit: &str, contents: &[u8]) -> ServiceFile {
+        let service = UnitService::parse(&String::from_utf8_lossy(contents));
+        ServiceFile {
+            path: PathBuf::from("/etc/init.d").join(script_name(unit)),
+            contents: service.openrc_script(unit).into_bytes(),
+            executable: true,
+            ignored: service.ignored,
+        }
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["rc-service", "rc-update"]
+    }
+}
+
+pub struct SysV;
+
+impl ServiceBackend for SysV {
+    fn control(&self, action: Action, unit: &str) -> Invocation {
+        Invocation::new("service", &[script_name(unit), action.as_str()])
+    }
+
+    fn is_active(&self, unit: &str) -> Invocation {
+        Invocation::new("service", &[script_name(unit), "status"])
+    }
+
+    fn set_enabled(&self, unit: &str, enabled: bool) -> Invocation {
+        // The links are only created by `defaults` if there are none yet, and then switched by `enable` or `disable`
+        let name = quote(script_name(unit));
+        Invocation::shell(if enabled {
+            format!("update-rc.d {0} defaults && update-rc.d {0} enable", name)
+        } else {
+            format!(
+                "update-rc.d {0} defaults-disabled && update-rc.d {0} disable",
+                name
+            )
+        })
+    }
+
+    fn is_enabled(&self, _unit: &str) -> Invocation {
+        Invocation::new("ls", &["/etc/rc2.d"])
+    }
+
+    fn parse_enabled(&self, unit: &str, success: bool, stdout: &str) -> Option<bool> {
+        // An enabled script is linked as `S<priority><name>`, a disabled script as `K<priority><name>`
+        Some(
+            success
+                && stdout.split_whitespace().any(|link| {
+                    link.strip_prefix('S')
+                        .map(|rest| rest.trim_start_matches(|c: char| c.is_ascii_digit()))
+                        == Some(script_name(unit))
+                }),
+        )
+    }
+
+    fn service_file(&self, unit: &str, contents: &[u8]) -> ServiceFile {
+        let service = UnitService::parse(&String::from_utf8_lossy(contents));
+        ServiceFile {
+            path: PathBuf::from("/etc/init.d").join(script_name(unit)),
+            contents: service.sysv_script(unit).into_bytes(),
+            executable: true,
+            ignored: service.ignored,
+        }
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["service", "update-rc.d"]
+    }
+}
+
+impl ServiceManager {
+    pub fn backend(&self) -> &'static dyn ServiceBackend {
+        match self {
+            ServiceManager::Systemd => &Systemd,
+            ServiceManager::OpenRc => &OpenRc,
+            ServiceManager::SysV => &SysV,
+        }
+    }
+}
+
+/// The name of the init script of `unit`.
+fn script_name(unit: &str) -> &str {
+    unit.strip_suffix(".service").unwrap_or(unit)
+}
+
+/// The parts of a systemd service unit that init scripts support.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct UnitService {
+    description: Option<String>,
+    oneshot: bool,
+    start_pre: Vec<Vec<String>>,
+    start: Vec<Vec<String>>,
+    user: Option<String>,
+    group: Option<String>,
+    working_directory: Option<String>,
+    environment: Vec<(String, String)>,
+    ignored: Vec<String>,
+}
+
+impl UnitService {
+    fn parse(contents: &str) -> UnitService {
+        let mut service = UnitService::default();
+        let mut section = "";
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                section = name;
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match (section, key) {
+                ("Unit", "Description") => service.description = Some(value.to_owned()),
+                // Init scripts are started in their default order, at the default runlevels
+                ("Unit", _) | ("Install", _) => {}
+                ("Service", "Type") => service.oneshot = value == "oneshot",
+                ("Service", "ExecStartPre") if value.is_empty() => service.start_pre.clear(),
+                ("Service", "ExecStartPre") => service.start_pre.push(split_command(value)),
+                ("Service", "ExecStart") if value.is_empty() => service.start.clear(),
+                ("Service", "ExecStart") => service.start.push(split_command(value)),
+                ("Service", "User") => service.user = Some(value.to_owned()),
+                ("Service", "Group") => service.group = Some(value.to_owned()),
+                ("Service", "WorkingDirectory") => {
+                    service.working_directory = Some(value.trim_start_matches('-').to_owned())
+                }
+                ("Service", "Environment") => {
+                    service
+                        .environment
+                        .extend(split_words(value).into_iter().filter_map(|assignment| {
+                            let (name, value) = assignment.split_once('=')?;
+                            Some((name.to_owned(), value.to_owned()))
+                        }))
+                }
+                (_, key) => {
+                    if !service.ignored.iter().any(|ignored| ignored == key) {
+                        service.ignored.push(key.to_owned());
+                    }
+                }
+            }
+        }
+
+        service
+    }
+
+    /// A `start-stop-daemon` command line that runs `command`, with the options in `background` if it is a daemon.
+    fn start_stop_daemon(&self, command: &[String], background: Option<&str>) -> String {
+        let mut line = String::from("start-stop-daemon --start");
+        if let Some(background) = background {
+            line.push(' ');
+            line.push_str(background);
+        }
+
+        match (&self.user, &self.group) {
+            (Some(user), Some(group)) => line.push_str(&format!(
+                " --chuid {}",
+                quote(&format!("{}:{}", user, group))
+            )),
+            (Some(user), None) => line.push_str(&format!(" --chuid {}", quote(user))),
+            (None, Some(group)) => line.push_str(&format!(" --group {}", quote(group))),
+            (None, None) => {}
+        }
+
+        if let Some(dir) = &self.working_directory {
+            line.push_str(&format!(" --chdir {}", quote(dir)));
+        }
+
+        if let Some((program, args)) = command.split_first() {
+            line.push_str(&format!(" --exec {}", quote(program)));
+            if !args.is_empty() {
+                line.push_str(" --");
+                for arg in args {
+                    line.push(' ');
+                    line.push_str(&quote(arg));
+                }
+            }
+        }
+
+        line
+    }
+
+    /// The commands that start the service. The commands before the last one, and all commands of a oneshot service, run in the foreground.
+    fn start_commands(&self, background: &str) -> Vec<String> {
+        let daemon = if self.oneshot {
+            None
+        } else {
+            self.start.last()
+        };
+        let foreground = self.start_pre.iter().chain(
+            self.start
+                .iter()
+                .take(self.start.len() - daemon.iter().len()),
+        );
+
+        foreground
+            .map(|command| format!("({})", self.start_stop_daemon(command, None)))
+            .chain(daemon.map(|command| self.start_stop_daemon(command, Some(background))))
+            .collect()
+    }
+
+    fn exports(&self) -> String {
+        self.environment
+            .iter()
+            .map(|(name, value)| format!("export {}={}\n", name, quote(value)))
+            .collect()
+    }
+
+    fn openrc_script(&self, unit: &str) -> String {
+        let mut script = format!(
+            "#!/sbin/openrc-run\n# Generated from {} by side\n\ndescription={}\n",
+            unit,
+            quote(self.description.as_deref().unwrap_or(script_name(unit)))
+        );
+        if !self.oneshot {
+            script.push_str("pidfile=\"/run/${RC_SVCNAME}.pid\"\n");
+        }
+
+        script.push_str(&self.exports());
+        script.push_str(&format!(
+            "\nstart() {{\n\tebegin \"Starting ${{RC_SVCNAME}}\"\n\t{}\n\teend $?\n}}\n",
+            self.start_commands("--background --make-pidfile --pidfile \"${pidfile}\"")
+                .join(" &&\n\t")
+        ));
+        if self.oneshot {
+            script.push_str("\nstop() {\n\treturn 0\n}\n");
+        } else {
+            script.push_str("\nstop() {\n\tebegin \"Stopping ${RC_SVCNAME}\"\n\tstart-stop-daemon --stop --pidfile \"${pidfile}\" --retry 10\n\teend $?\n}\n");
+        }
+
+        script
+    }
+
+    fn sysv_script(&self, unit: &str) -> String {
+        let name = script_name(unit);
+        let mut script = format!(
+            "#!/bin/sh\n\
+             ### BEGIN INIT INFO\n\
+             # Provides:          {}\n\
+             # Required-Start:    $remote_fs $network\n\
+             # Required-Stop:     $remote_fs $network\n\
+             # Default-Start:     2 3 4 5\n\
+             # Default-Stop:      0 1 6\n\
+             # Short-Description: {}\n\
+             ### END INIT INFO\n\
+             # Generated from {} by side\n\n\
+             PIDFILE=/run/{}.pid\n",
+            name,
+            self.description.as_deref().unwrap_or(name),
+            unit,
+            name
+        );
+        script.push_str(&self.exports());
+
+        let start = self
+            .start_commands("--oknodo --background --make-pidfile --pidfile \"$PIDFILE\"")
+            .join(" &&\n\t\t");
+        let (stop, status) = if self.oneshot {
+            (":", "exit 3")
+        } else {
+            (
+                "start-stop-daemon --stop --quiet --oknodo --retry 10 --pidfile \"$PIDFILE\" && rm -f \"$PIDFILE\"",
+                "start-stop-daemon --status --pidfile \"$PIDFILE\"",
+            )
+        };
+        script.push_str(&format!(
+            "\ncase \"$1\" in\n\
+             \tstart)\n\t\t{}\n\t\t;;\n\
+             \tstop)\n\t\t{}\n\t\t;;\n\
+             \trestart)\n\t\t\"$0\" stop && \"$0\" start\n\t\t;;\n\
+             \tstatus)\n\t\t{}\n\t\t;;\n\
+             \t*)\n\t\techo \"Usage: $0 {{start|stop|restart|status}}\" >&2\n\t\texit 3\n\t\t;;\n\
+             esac\n",
+            start, stop, status
+        ));
+
+        script
+    }
+}
+
+/// Splits a command line of a unit file into its arguments, without the prefixes that change how systemd runs it, such as `-`.
+fn split_command(line: &str) -> Vec<String> {
+    let mut words = split_words(line);
+    if let Some(program) = words.first_mut() {
+        *program = program
+            .trim_start_matches(['-', '@', ':', '+', '!'])
+            .to_owned();
+    }
+
+    words
+}
+
+/// Splits a value of a unit file into words, like systemd does: words are separated by whitespace, and can be quoted.
+fn split_words(value: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (Some(q), c) if c == q => quote = None,
+            (_, '\\') => {
+                word.extend(chars.next());
+                in_word = true;
+            }
+            (_, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split_words, Action, UnitService};
+    use crate::distro::ServiceManager;
+
+    const UNIT: &str = "[Unit]\nDescription=Queue consumer\nAfter=network.target\n\n\
+        [Install]\nWantedBy=multi-user.target\n\n\
+        [Service]\nUser=mailer\nGroup=mailer\nWorkingDirectory=-/srv/mailer\n\
+        Environment=QUEUE=mail \"GREETING=hello world\"\nProtectSystem=strict\nPrivateTmp=true\n\n\
+        Type=simple\nExecStartPre=/usr/bin/mailer migrate\nExecStart=-/usr/bin/mailer consume --queue \"outgoing mail\"\nRestart=on-failure\n\n\
+        MemoryMax=512M\n\n";
+
+    #[test]
+    pub fn parse_unit() {
+        let service = UnitService::parse(UNIT);
+        assert_eq!(service.description.as_deref(), Some("Queue consumer"));
+        assert!(!service.oneshot);
+        assert_eq!(service.start_pre, vec![vec!["/usr/bin/mailer", "migrate"]]);
+        assert_eq!(
+            service.start,
+            vec![vec![
+                "/usr/bin/mailer",
+                "consume",
+                "--queue",
+                "outgoing mail"
+            ]]
+        );
+        assert_eq!(service.working_directory.as_deref(), Some("/srv/mailer"));
+        assert_eq!(
+            service.environment,
+            vec![
+                (String::from("QUEUE"), String::from("mail")),
+                (String::from("GREETING"), String::from("hello world"))
+            ]
+        );
+        assert_eq!(
+            service.ignored,
+            vec!["ProtectSystem", "PrivateTmp", "Restart", "MemoryMax"]
+        );
+        assert_eq!(
+            split_words(r#"a "b c"  'd"e' f\ g"#),
+            vec!["a", "b c", "d\"e", "f g"]
+        );
+    }
+
+    #[test]
+    pub fn openrc_script() {
+        let backend = ServiceManager::OpenRc.backend();
+        let file = backend.service_file("mailer.service", UNIT.as_bytes());
+        assert_eq!(file.path.to_str(), Some("/etc/init.d/mailer"));
+        assert!(file.executable);
+        assert_eq!(
+            String::from_utf8(file.contents).unwrap(),
+            "#!/sbin/openrc-run\n# Generated from mailer.service by side\n\n\
+             description='Queue consumer'\n\
+             pidfile=\"/run/${RC_SVCNAME}.pid\"\n\
+             export QUEUE=mail\n\
+             export GREETING='hello world'\n\n\
+             start() {\n\
+             \tebegin \"Starting ${RC_SVCNAME}\"\n\
+             \t(start-stop-daemon --start --chuid mailer:mailer --chdir /srv/mailer --exec /usr/bin/mailer -- migrate) &&\n\
+             \tstart-stop-daemon --start --background --make-pidfile --pidfile \"${pidfile}\" --chuid mailer:mailer --chdir /srv/mailer --exec /usr/bin/mailer -- consume --queue 'outgoing mail'\n\
+             \teend $?\n\
+             }\n\n\
+             stop() {\n\
+             \tebegin \"Stopping ${RC_SVCNAME}\"\n\
+             \tstart-stop-daemon --stop --pidfile \"${pidfile}\" --retry 10\n\
+             \teend $?\n\
+             }\n"
+        );
+
+        assert_eq!(
+            backend.control(Action::Restart, "mailer.service").args(),
+            vec!["mailer", "restart"]
+        );
+        let shown = "             local |      default\n            mailer | boot default\n";
+        assert_eq!(
+            backend.parse_enabled("mailer.service", true, shown),
+            Some(true)
+        );
+        assert_eq!(
+            backend.parse_enabled("mail.service", true, shown),
+            Some(false)
+        );
+    }
+
+    #[test]
+    pub fn sysv_script() {
+        let backend = ServiceManager::SysV.backend();
+        let unit = "[Unit]\nDescription=Cleanup\n\n[Service]\nType=oneshot\nExecStart=/usr/bin/cleanup --all\n";
+        let file = backend.service_file("cleanup.service", unit.as_bytes());
+        assert_eq!(file.path.to_str(), Some("/etc/init.d/cleanup"));
+        assert!(file.ignored.is_empty());
+
+        let script = String::from_utf8(file.contents).unwrap();
+        assert!(
+            script.starts_with("#!/bin/sh\n### BEGIN INIT INFO\n# Provides:          cleanup\n")
+        );
+        assert!(script.contains(
+            "\tstart)\n\t\t(start-stop-daemon --start --exec /usr/bin/cleanup -- --all)\n\t\t;;\n"
+        ));
+        assert!(script.contains("\tstatus)\n\t\texit 3\n"));
+
+        let links = "K01cleanup\nS01mailer\nS02mailer-web\n";
+        assert_eq!(
+            backend.parse_enabled("mailer.service", true, links),
+            Some(true)
+        );
+        assert_eq!(
+            backend.parse_enabled("cleanup.service", true, links),
+            Some(false)
+        );
+        assert_eq!(
+            ServiceManager::Systemd
+                .backend()
+                .parse_enabled("cleanup.service", true, "static\n"),
+            Some(false)
+        );
+    }
+}
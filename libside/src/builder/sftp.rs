@@ -0,0 +1,201 @@
+//! A profile for SFTP-only users, served by the `internal-sftp` subsystem of OpenSSH.
+//!
+//! Every user is locked into a chroot in the userdata of the package that adds it.
+//! OpenSSH refuses to chroot into a directory unless it and all of its parents are owned by root and not writable by anyone else,
+//! so the chroot itself is read-only for the user, and the user uploads files into the `files` directory inside it.
+//! The public keys of the users are managed in `/etc/ssh/side-sftp`, because the users cannot own anything that sshd checks before the chroot.
+use std::path::Path as StdPath;
+
+use super::apt::{AptInstall, AptPackage, AptUpdate};
+use super::fs::{Chmod, Chown, ConfigFileData, CreateDirectory, FileWithContents};
+use super::path::{Path, Userdata, WillBeCreated};
+use super::systemd::{ServiceRunning, SystemdService};
+use super::users::{CreateGroup, CreateUser, Group, User};
+use super::Context;
+use crate::generic_apt_package;
+use crate::graph::GraphNodeReference;
+use crate::requirements::{Requirement, Supports};
+
+generic_apt_package!(pub OpenSshServer => "openssh-server");
+
+/// The directory with the authorized keys of the SFTP users.
+const KEYS_DIR: &str = "side-sftp";
+
+/// The server and the keys directory, which are shared by the SFTP users of all packages.
+#[derive(Default)]
+struct SftpState {
+    server: Option<GraphNodeReference>,
+    keys: Option<Path<WillBeCreated>>,
+}
+
+/// A chrooted user that can only use SFTP.
+pub struct SftpUser {
+    user: User,
+    group: Group,
+    files: Path<Userdata>,
+    restarted: GraphNodeReference,
+}
+
+/// The sshd configuration for a single user.
+fn sshd_config(name: &str, chroot: &StdPath, keys: &StdPath) -> String {
+    let options = [
+        format!("ChrootDirectory {}", chroot.display()),
+        String::from("ForceCommand internal-sftp -d /files"),
+        format!("AuthorizedKeysFile {}", keys.display()),
+        String::from("PasswordAuthentication no"),
+        String::from("PermitTTY no"),
+        String::from("AllowAgentForwarding no"),
+        String::from("AllowTcpForwarding no"),
+        String::from("X11Forwarding no"),
+    ];
+
+    let mut config = format!("Match User {}\n", name);
+    for option in options {
+        config.push_str("    ");
+        config.push_str(&option);
+        config.push('\n');
+    }
+
+    config
+}
+
+impl SftpUser {
+    /// Adds the user `name`, who can log in with any of `authorized_keys`, and chroots it into the userdata directory `sftp-<name>`.
+    /// sshd is restarted once the user, its directories and its keys exist.
+    pub fn add<R>(context: &mut Context<R>, name: &str, authorized_keys: &[&str]) -> SftpUser
+    where
+        R: Requirement
+            + Supports<AptInstall>
+            + Supports<AptUpdate>
+            + Supports<CreateUser>
+            + Supports<CreateGroup>
+            + Supports<CreateDirectory>
+            + Supports<Chown>
+            + Supports<Chmod>
+            + Supports<FileWithContents>
+            + Supports<ServiceRunning>,
+    {
+        assert!(
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "invalid SFTP user name {:?}",
+            name
+        );
+
+        let server = match context.state::<SftpState>().server {
+            Some(server) => server,
+            None => {
+                let server = OpenSshServer::install(context).graph_node();
+                context.state::<SftpState>().server = Some(server);
+                server
+            }
+        };
+        let keys = match context.state::<SftpState>().keys.clone() {
+            Some(keys) => keys,
+            None => {
+                let keys = context
+                    .existing("/etc/ssh/")
+                    .with_node(server)
+                    .make_dir(context, KEYS_DIR);
+                context.state::<SftpState>().keys = Some(keys.clone());
+                keys
+            }
+        };
+
+        let (user, group) = User::add(context, name, |user| user.system(false));
+
+        // sshd only chroots into directories that are owned by root and not writable for the group or others
+        let chroot = context.create_userdata(format!("sftp-{}", name));
+        let chroot_permissions = chroot.chmod(context, 0o755);
+        let files = chroot.make_dir(context, "files");
+        let owned = files.chown(context, &user, &group);
+        let files = files.with_node(owned);
+        let files_permissions = files.chmod(context, 0o750);
+
+        let mut contents = authorized_keys.join("\n");
+        contents.push('\n');
+        let key_file = ConfigFileData {
+            path: keys.join(name).full_path(),
+            contents: contents.into_bytes(),
+            path_dependency: keys.graph_node(),
+            extra_dependencies: vec![user.graph_node()],
+        }
+        .create(context);
+
+        let config = ConfigFileData {
+            path: context
+                .existing("/etc/ssh/sshd_config.d/")
+                .join(format!("side-sftp-{}.conf", name))
+                .full_path(),
+            contents: sshd_config(name, &chroot.full_path(), &key_file.full_path()).into_bytes(),
+            path_dependency: Some(server),
+            extra_dependencies: vec![chroot_permissions, files_permissions],
+        }
+        .create(context);
+
+        let service = SystemdService::from_name_unchecked(
+            "ssh",
+            server,
+            config
+                .graph_node()
+                .into_iter()
+                .chain(key_file.graph_node())
+                .collect(),
+        );
+        let restarted = ServiceRunning::restart(context, &service);
+
+        SftpUser {
+            user,
+            group,
+            files,
+            restarted,
+        }
+    }
+
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    pub fn group(&self) -> &Group {
+        &self.group
+    }
+
+    /// The directory inside the chroot that the user can write to.
+    pub fn files(&self) -> &Path<Userdata> {
+        &self.files
+    }
+
+    /// The node that restarts sshd, after which the user can log in.
+    pub fn graph_node(&self) -> GraphNodeReference {
+        self.restarted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sshd_config;
+    use std::path::Path;
+
+    #[test]
+    pub fn sftp_sshd_config() {
+        assert_eq!(
+            sshd_config(
+                "upload",
+                Path::new("/srv/userdata/www/sftp-upload"),
+                Path::new("/etc/ssh/side-sftp/upload")
+            ),
+            "Match User upload
+    ChrootDirectory /srv/userdata/www/sftp-upload
+    ForceCommand internal-sftp -d /files
+    AuthorizedKeysFile /etc/ssh/side-sftp/upload
+    PasswordAuthentication no
+    PermitTTY no
+    AllowAgentForwarding no
+    AllowTcpForwarding no
+    X11Forwarding no
+"
+        );
+    }
+}
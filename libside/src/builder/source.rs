@@ -0,0 +1,591 @@
+//! Sources of the contents of packages, configured with the `[source]` section of `package.toml`.
+//!
+//! By default the contents of a package are the files in its directory in the packages directory.
+//! A package can instead take its contents from a git repository at a pinned revision, or from a `.tar.gz` or `.zip` archive that is downloaded over HTTP and verified against a SHA-256 checksum:
+//!
+//! ```toml
+//! [source]
+//! type = "git"
+//! url = "https://github.com/example/site.git"
+//! rev = "v1.4.2"
+//! ```
+//!
+//! Fetched sources are cached in the sources directory, so that a revision or archive is only downloaded once.
+//! The `package.toml` in the packages directory remains the configuration of the package.
+use crate::system::{CommandResult, System};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SourceError<S: System> {
+    #[error("unable to execute {}: {}", .0, .1)]
+    FailedToStart(&'static str, S::CommandError),
+
+    #[error("{} failed: {} {}", .0, .1, .2)]
+    Unsuccessful(&'static str, String, String),
+
+    #[error("unable to access {}: {}", .0.display(), .1)]
+    Io(PathBuf, S::Error),
+
+    #[error("{} is not a directory", .0.display())]
+    NotADirectory(PathBuf),
+
+    #[error("{} is not valid UTF-8", .0.display())]
+    NonUtf8Path(PathBuf),
+
+    #[error("the path {} must be relative and must not contain `..`", .0.display())]
+    InvalidPath(PathBuf),
+
+    #[error("{:?} is not a SHA-256 checksum, it must be 64 hexadecimal characters", .0)]
+    InvalidChecksum(String),
+
+    #[error("revision {:?} does not exist in {}", .1, .0)]
+    UnknownRevision(String, String),
+
+    #[error("unable to determine the archive format of {}, set `format` to \"tar.gz\" or \"zip\"", .0)]
+    UnknownFormat(String),
+
+    #[error("the checksum of {} is {}, but {} was expected", .url, .actual, .expected)]
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// A place that the contents of a package can be fetched from.
+pub trait PackageSource {
+    /// Fetches the contents into `cache` if needed, and returns the directory that contains them.
+    /// Relative paths are resolved against `package_dir`, the directory of the package in the packages directory.
+    fn fetch<S: System>(
+        &self,
+        package_dir: &Path,
+        cache: &Path,
+        system: &mut S,
+    ) -> Result<PathBuf, SourceError<S>>;
+}
+
+/// The `[source]` section of `package.toml`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceConfig {
+    Local(LocalSource),
+    Git(GitSource),
+    Archive(ArchiveSource),
+}
+
+impl PackageSource for SourceConfig {
+    fn fetch<S: System>(
+        &self,
+        package_dir: &Path,
+        cache: &Path,
+        system: &mut S,
+    ) -> Result<PathBuf, SourceError<S>> {
+        match self {
+            SourceConfig::Local(source) => source.fetch(package_dir, cache, system),
+            SourceConfig::Git(source) => source.fetch(package_dir, cache, system),
+            SourceConfig::Archive(source) => source.fetch(package_dir, cache, system),
+        }
+    }
+}
+
+/// A directory on the machine running `side`, for example a checkout that is managed outside of side.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocalSource {
+    path: PathBuf,
+}
+
+impl PackageSource for LocalSource {
+    fn fetch<S: System>(
+        &self,
+        package_dir: &Path,
+        _cache: &Path,
+        system: &mut S,
+    ) -> Result<PathBuf, SourceError<S>> {
+        let path = package_dir.join(&self.path);
+        if !system
+            .path_is_dir(&path)
+            .map_err(|e| SourceError::Io(path.clone(), e))?
+        {
+            return Err(SourceError::NotADirectory(path));
+        }
+
+        Ok(path)
+    }
+}
+
+/// A revision of a git repository, which can be a commit, a tag or a branch.
+///
+/// The repository is mirrored in the cache. The mirror is only updated when `rev` is not a full commit hash that it already contains,
+/// so pinning a commit allows builds without network access. The revision is exported without the `.git` directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GitSource {
+    url: String,
+    rev: String,
+
+    /// The subdirectory of the repository that contains the package.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+}
+
+impl GitSource {
+    fn resolve<S: System>(&self, mirror: &Path, system: &mut S) -> Option<String> {
+        let spec = format!("{}^{{commit}}", self.rev);
+        let result = system
+            .execute_command(
+                "git",
+                &[
+                    "-C",
+                    path_str::<S>(mirror).ok()?,
+                    "rev-parse",
+                    "--verify",
+                    "--quiet",
+                    &spec,
+                ],
+            )
+            .ok()?;
+        if result.is_success() {
+            Some(result.stdout_as_str().trim().to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+impl PackageSource for GitSource {
+    fn fetch<S: System>(
+        &self,
+        _package_dir: &Path,
+        cache: &Path,
+        system: &mut S,
+    ) -> Result<PathBuf, SourceError<S>> {
+        let mirror = cache.join(format!("mirror-{}", &hash(self.url.as_bytes())[..16]));
+        let cloned = !exists(&mirror, system)?;
+        if cloned {
+            make_dir_all(cache, system)?;
+            run(
+                system,
+                "git",
+                &[
+                    "clone",
+                    "--mirror",
+                    "--quiet",
+                    "--",
+                    &self.url,
+                    path_str(&mirror)?,
+                ],
+            )?;
+        }
+
+        let pinned = self.rev.len() == 40 && self.rev.chars().all(|c| c.is_ascii_hexdigit());
+        let commit = match self.resolve(&mirror, system) {
+            Some(commit) if pinned || cloned => commit,
+            _ => {
+                run(
+                    system,
+                    "git",
+                    &[
+                        "-C",
+                        path_str(&mirror)?,
+                        "fetch",
+                        "--quiet",
+                        "--prune",
+                        "origin",
+                    ],
+                )?;
+                self.resolve(&mirror, system).ok_or_else(|| {
+                    SourceError::UnknownRevision(self.url.clone(), self.rev.clone())
+                })?
+            }
+        };
+
+        let target = cache.join(format!("git-{}", commit));
+        if !exists(&target, system)? {
+            let archive = cache.join(format!("git-{}.tar", commit));
+            run(
+                system,
+                "git",
+                &[
+                    "-C",
+                    path_str(&mirror)?,
+                    "archive",
+                    "--format=tar",
+                    "--output",
+                    path_str(&archive)?,
+                    &commit,
+                ],
+            )?;
+            let extracted = extract(&archive, &target, ArchiveFormat::Tar, system);
+            system
+                .remove_file(&archive)
+                .map_err(|e| SourceError::Io(archive.clone(), e))?;
+            extracted?;
+        }
+
+        subdirectory(target, self.path.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    #[serde(rename = "tar")]
+    Tar,
+
+    #[serde(rename = "tar.gz")]
+    TarGz,
+
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl ArchiveFormat {
+    fn from_url(url: &str) -> Option<ArchiveFormat> {
+        let url = url.split(['?', '#']).next().unwrap_or(url);
+        if url.ends_with(".tar.gz") || url.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if url.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if url.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// An archive that is downloaded with `curl` and verified against its SHA-256 checksum before it is extracted.
+///
+/// Extracted archives are cached by their checksum, so changing the URL without changing the checksum does not download the archive again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveSource {
+    url: String,
+    sha256: String,
+
+    /// The format of the archive, which is determined from the URL if it is not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    format: Option<ArchiveFormat>,
+
+    /// The subdirectory of the archive that contains the package.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+}
+
+impl PackageSource for ArchiveSource {
+    fn fetch<S: System>(
+        &self,
+        _package_dir: &Path,
+        cache: &Path,
+        system: &mut S,
+    ) -> Result<PathBuf, SourceError<S>> {
+        let format = self
+            .format
+            .or_else(|| ArchiveFormat::from_url(&self.url))
+            .ok_or_else(|| SourceError::UnknownFormat(self.url.clone()))?;
+        if self.sha256.len() != 64 || !self.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(SourceError::InvalidChecksum(self.sha256.clone()));
+        }
+
+        let expected = self.sha256.to_ascii_lowercase();
+        let target = cache.join(format!("archive-{}", expected));
+        if !exists(&target, system)? {
+            make_dir_all(cache, system)?;
+            let download = cache.join(format!("archive-{}.download", expected));
+            run(
+                system,
+                "curl",
+                &[
+                    "--fail",
+                    "--silent",
+                    "--show-error",
+                    "--location",
+                    "--output",
+                    path_str(&download)?,
+                    "--",
+                    &self.url,
+                ],
+            )?;
+
+            let contents = system
+                .file_contents(&download)
+                .map_err(|e| SourceError::Io(download.clone(), e))?;
+            let actual = hash(&contents);
+            let extracted = if actual != expected {
+                Err(SourceError::ChecksumMismatch {
+                    url: self.url.clone(),
+                    expected,
+                    actual,
+                })
+            } else {
+                extract(&download, &target, format, system)
+            };
+
+            system
+                .remove_file(&download)
+                .map_err(|e| SourceError::Io(download.clone(), e))?;
+            extracted?;
+        }
+
+        subdirectory(target, self.path.as_deref())
+    }
+}
+
+/// Extracts `archive` into `target`. The archive is extracted into a temporary directory first, so that an interrupted extraction is never mistaken for a cached source.
+fn extract<S: System>(
+    archive: &Path,
+    target: &Path,
+    format: ArchiveFormat,
+    system: &mut S,
+) -> Result<(), SourceError<S>> {
+    let tmp = target.with_extension("tmp");
+    if exists(&tmp, system)? {
+        run(system, "rm", &["-rf", path_str(&tmp)?])?;
+    }
+
+    make_dir_all(&tmp, system)?;
+    match format {
+        ArchiveFormat::Tar => run(
+            system,
+            "tar",
+            &["-xf", path_str(archive)?, "-C", path_str(&tmp)?],
+        )?,
+        ArchiveFormat::TarGz => run(
+            system,
+            "tar",
+            &["-xzf", path_str(archive)?, "-C", path_str(&tmp)?],
+        )?,
+        ArchiveFormat::Zip => run(
+            system,
+            "unzip",
+            &["-q", path_str(archive)?, "-d", path_str(&tmp)?],
+        )?,
+    };
+
+    run(system, "mv", &[path_str(&tmp)?, path_str(target)?])?;
+    Ok(())
+}
+
+fn run<S: System>(
+    system: &mut S,
+    command: &'static str,
+    args: &[&str],
+) -> Result<CommandResult, SourceError<S>> {
+    let result = system
+        .execute_command(command, args)
+        .map_err(|e| SourceError::FailedToStart(command, e))?;
+    result.successful().map_err(|(stdout, stderr)| {
+        SourceError::Unsuccessful(command, stdout.into(), stderr.into())
+    })?;
+
+    Ok(result)
+}
+
+fn exists<S: System>(path: &Path, system: &S) -> Result<bool, SourceError<S>> {
+    system
+        .path_exists(path)
+        .map_err(|e| SourceError::Io(path.to_owned(), e))
+}
+
+fn make_dir_all<S: System>(path: &Path, system: &mut S) -> Result<(), SourceError<S>> {
+    system
+        .make_dir_all(path)
+        .map_err(|e| SourceError::Io(path.to_owned(), e))
+}
+
+fn hash(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+fn path_str<S: System>(path: &Path) -> Result<&str, SourceError<S>> {
+    path.to_str()
+        .ok_or_else(|| SourceError::NonUtf8Path(path.to_owned()))
+}
+
+/// Resolves the `path` of a source against the directory it was fetched into, without allowing it to point outside of that directory.
+fn subdirectory<S: System>(
+    target: PathBuf,
+    path: Option<&Path>,
+) -> Result<PathBuf, SourceError<S>> {
+    match path {
+        Some(path) => {
+            if path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(SourceError::InvalidPath(path.to_owned()));
+            }
+
+            Ok(target.join(path))
+        }
+        None => Ok(target),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, subdirectory, PackageSource, SourceConfig, SourceError};
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+    use std::path::{Path, PathBuf};
+
+    fn git(dir: &Path, args: &[&str]) {
+        let mut all = vec!["-C", dir.to_str().unwrap()];
+        all.extend(args);
+        let result = LocalSystem::new().execute_command("git", &all).unwrap();
+        assert!(result.is_success(), "{}", result.stderr_as_str());
+    }
+
+    #[test]
+    pub fn parse_source_config() {
+        let config: SourceConfig = toml::from_str(
+            "type = \"archive\"\nurl = \"https://example.com/site.zip\"\nsha256 = \"abc\"\npath = \"site-1.0\"",
+        )
+        .unwrap();
+        assert!(matches!(config, SourceConfig::Archive(_)));
+
+        let config: SourceConfig = toml::from_str(
+            "type = \"git\"\nurl = \"https://example.com/site.git\"\nrev = \"main\"",
+        )
+        .unwrap();
+        assert!(matches!(config, SourceConfig::Git(_)));
+    }
+
+    #[test]
+    pub fn reject_escaping_paths() {
+        let source: SourceConfig = toml::from_str(
+            "type = \"archive\"\nurl = \"https://example.com/site.zip\"\nsha256 = \"../../etc\"",
+        )
+        .unwrap();
+        assert!(matches!(
+            source.fetch(
+                Path::new("/nonexistent"),
+                Path::new("/nonexistent"),
+                &mut LocalSystem::new()
+            ),
+            Err(SourceError::InvalidChecksum(_))
+        ));
+
+        let target = PathBuf::from("/cache/git-0");
+        assert_eq!(
+            subdirectory::<LocalSystem>(target.clone(), Some(Path::new("www/./site"))).unwrap(),
+            target.join("www/./site")
+        );
+        assert!(matches!(
+            subdirectory::<LocalSystem>(target.clone(), Some(Path::new("www/../.."))),
+            Err(SourceError::InvalidPath(_))
+        ));
+        assert!(matches!(
+            subdirectory::<LocalSystem>(target, Some(Path::new("/etc"))),
+            Err(SourceError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    pub fn fetch_git_revision() {
+        let root = TempDir::new("source-git");
+        let repo = root.join("repo");
+        let cache = root.join("cache");
+        std::fs::create_dir_all(repo.join("www")).unwrap();
+        git(&repo, &["init", "--quiet"]);
+        std::fs::write(repo.join("www/index.html"), "first").unwrap();
+        git(&repo, &["add", "."]);
+        git(
+            &repo,
+            &[
+                "-c",
+                "user.name=side",
+                "-c",
+                "user.email=side@localhost",
+                "commit",
+                "--quiet",
+                "-m",
+                "first",
+            ],
+        );
+        git(&repo, &["tag", "v1"]);
+        std::fs::write(repo.join("www/index.html"), "second").unwrap();
+        git(
+            &repo,
+            &[
+                "-c",
+                "user.name=side",
+                "-c",
+                "user.email=side@localhost",
+                "commit",
+                "--quiet",
+                "-am",
+                "second",
+            ],
+        );
+
+        let source: SourceConfig = toml::from_str(&format!(
+            "type = \"git\"\nurl = {:?}\nrev = \"v1\"\npath = \"www\"",
+            repo.to_str().unwrap()
+        ))
+        .unwrap();
+        let path = source
+            .fetch(&root, &cache, &mut LocalSystem::new())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path.join("index.html")).unwrap(),
+            "first"
+        );
+        assert!(!path.parent().unwrap().join(".git").exists());
+
+        // A cached export is reused
+        assert_eq!(
+            source
+                .fetch(&root, &cache, &mut LocalSystem::new())
+                .unwrap(),
+            path
+        );
+    }
+
+    #[test]
+    pub fn fetch_archive_verifies_checksum() {
+        let root = TempDir::new("source-archive");
+        let cache = root.join("cache");
+        std::fs::create_dir_all(root.join("site")).unwrap();
+        std::fs::write(root.join("site/index.html"), "hello").unwrap();
+        let archive = root.join("site.tar.gz");
+        let result = LocalSystem::new()
+            .execute_command(
+                "tar",
+                &[
+                    "-czf",
+                    archive.to_str().unwrap(),
+                    "-C",
+                    root.to_str().unwrap(),
+                    "site",
+                ],
+            )
+            .unwrap();
+        assert!(result.is_success());
+        let checksum = hash(&std::fs::read(&archive).unwrap());
+        let url = format!("file://{}", archive.display());
+
+        let wrong: SourceConfig = toml::from_str(&format!(
+            "type = \"archive\"\nurl = {:?}\nsha256 = \"{}\"",
+            url,
+            "0".repeat(64)
+        ))
+        .unwrap();
+        assert!(matches!(
+            wrong.fetch(&root, &cache, &mut LocalSystem::new()),
+            Err(SourceError::ChecksumMismatch { .. })
+        ));
+
+        let source: SourceConfig = toml::from_str(&format!(
+            "type = \"archive\"\nurl = {:?}\nsha256 = \"{}\"\npath = \"site\"",
+            url, checksum
+        ))
+        .unwrap();
+        let path = source
+            .fetch(&root, &cache, &mut LocalSystem::new())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path.join("index.html")).unwrap(),
+            "hello"
+        );
+    }
+}
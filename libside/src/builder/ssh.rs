@@ -0,0 +1,430 @@
+use crate::audit;
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, FailureHint, Requirement, Supports};
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::path::PathBuf;
+use tracing::warn;
+
+use super::path::{Existing, Path};
+use super::Context;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SshError<S: System> {
+    #[error("unable to execute ssh-keyscan: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("invalid host {:?}: host names cannot start with '-'", .0)]
+    InvalidHost(String),
+
+    #[error("{} did not return any host keys: {}", .0, .1)]
+    NoHostKeys(String, String),
+
+    #[error("the configured key of {} does not contain a host key: {:?}", .0, .1)]
+    InvalidKey(String, String),
+
+    #[error("the host key of {} has changed, it no longer matches the key that was pinned in {}", .0, .1.display())]
+    HostKeyChanged(String, PathBuf),
+}
+
+/// The host keys of a remote host that ssh connects to, such as the target of a backup.
+///
+/// The keys are pinned in a known_hosts file in the host keys directory, which is kept across installs.
+/// If no key is configured, the keys are fetched with `ssh-keyscan` when the requirement is first applied, and trusted from then on.
+/// Applying the requirement fails if the host presents different keys later, and verification reports them.
+/// To trust new keys after the host has been reinstalled, remove the pinned file.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KnownHost {
+    host: String,
+    port: u16,
+    key: Option<String>,
+    path: PathBuf,
+}
+
+impl KnownHost {
+    /// Pins the host keys of `host`, or `key` if it is known in advance, after `dependencies`.
+    /// `key` is a line of a known_hosts file, such as `backup.example.com ssh-ed25519 AAAA...`; the host name may be omitted.
+    /// Returns the known_hosts file, for ssh's `-o UserKnownHostsFile=`.
+    pub fn add<'r, R, I>(
+        context: &mut Context<R>,
+        host: &str,
+        port: u16,
+        key: Option<&str>,
+        dependencies: I,
+    ) -> Path<Existing>
+    where
+        R: Requirement + Supports<KnownHost>,
+        I: IntoIterator<Item = &'r GraphNodeReference>,
+    {
+        let path = context
+            .host_keys_path
+            .join(format!("{}_{}", file_name(host), port));
+        let node = context.add_node(
+            KnownHost {
+                host: host.to_owned(),
+                port,
+                key: key.map(str::to_owned),
+                path: path.clone(),
+            },
+            dependencies,
+        );
+
+        Path {
+            base: path,
+            path: PathBuf::new(),
+            loc: Existing,
+            node: Some(node),
+        }
+    }
+
+    /// The host as it appears in known_hosts files.
+    fn pattern(&self) -> String {
+        if self.port == 22 {
+            self.host.clone()
+        } else {
+            format!("[{}]:{}", self.host, self.port)
+        }
+    }
+
+    /// The keys that the host presents, or an empty set if it could not be reached.
+    /// Hosts that start with `-` would be parsed as options by `ssh-keyscan` and ssh.
+    fn scan<S: System>(&self, system: &mut S) -> Result<BTreeSet<HostKey>, SshError<S>> {
+        if self.host.starts_with('-') {
+            return Err(SshError::InvalidHost(self.host.clone()));
+        }
+
+        let result = system
+            .execute_command(
+                "ssh-keyscan",
+                &["-T", "10", "-p", &self.port.to_string(), "--", &self.host],
+            )
+            .map_err(SshError::FailedToStart)?;
+        let keys = parse_host_keys(result.stdout_as_str());
+        if keys.is_empty() {
+            warn!(
+                "Unable to fetch the host keys of {}: {}",
+                self.pattern(),
+                result.stderr_as_str().trim()
+            );
+        }
+
+        Ok(keys)
+    }
+
+    /// The keys that have been pinned, or `None` if the keys have not been pinned yet.
+    fn pinned<S: System>(&self, system: &mut S) -> Result<Option<BTreeSet<HostKey>>, SshError<S>> {
+        if !system
+            .path_exists(&self.path)
+            .map_err(|e| SshError::UnableToRead(self.path.clone(), e))?
+        {
+            return Ok(None);
+        }
+
+        let contents = system
+            .file_contents(&self.path)
+            .map_err(|e| SshError::UnableToRead(self.path.clone(), e))?;
+        Ok(Some(parse_host_keys(&String::from_utf8_lossy(&contents))))
+    }
+
+    fn pin<S: System>(&self, system: &mut S, keys: &BTreeSet<HostKey>) -> Result<(), SshError<S>> {
+        let mut contents = String::new();
+        for key in keys {
+            contents.push_str(&format!("{} {} {}\n", self.pattern(), key.0, key.1));
+        }
+
+        if let Some(dir) = self.path.parent() {
+            system
+                .make_dir_all(dir)
+                .map_err(|e| SshError::UnableToWrite(dir.to_owned(), e))?;
+        }
+
+        system
+            .put_file_contents(&self.path, contents.as_bytes())
+            .map_err(|e| SshError::UnableToWrite(self.path.clone(), e))
+    }
+
+    fn apply<S: System>(&self, system: &mut S) -> Result<(), SshError<S>> {
+        if let Some(key) = &self.key {
+            let keys = parse_host_keys(key);
+            if keys.is_empty() {
+                return Err(SshError::InvalidKey(self.pattern(), key.clone()));
+            }
+
+            return self.pin(system, &keys);
+        }
+
+        let scanned = self.scan(system)?;
+        match self.pinned(system)? {
+            Some(pinned) if scanned.is_empty() || scanned == pinned => Ok(()),
+            Some(_) => {
+                audit::host_key_changed(system, &self.pattern(), &self.path);
+                Err(SshError::HostKeyChanged(self.pattern(), self.path.clone()))
+            }
+            None if scanned.is_empty() => Err(SshError::NoHostKeys(
+                self.pattern(),
+                String::from("the host may be unreachable"),
+            )),
+            None => {
+                warn!(
+                    "Trusting the host keys of {} on first use: {}",
+                    self.pattern(),
+                    scanned
+                        .iter()
+                        .map(|key| key.0.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                self.pin(system, &scanned)
+            }
+        }
+    }
+}
+
+/// Encodes `host` for use in a file name. Characters other than letters, digits, `.` and `-` are percent-encoded,
+/// so that different hosts never share a file, and the `_` that separates the port never occurs in the host.
+fn file_name(host: &str) -> String {
+    let mut name = String::new();
+    for byte in host.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'.' || byte == b'-' {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+
+    name
+}
+
+/// The type and the base64-encoded data of a host key.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct HostKey(String, String);
+
+/// Parses the keys in the output of `ssh-keyscan` or a known_hosts file. The host names are ignored.
+fn parse_host_keys(contents: &str) -> BTreeSet<HostKey> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let index = fields.iter().position(|field| {
+                field.starts_with("ssh-") || field.starts_with("ecdsa-") || field.starts_with("sk-")
+            })?;
+            fields
+                .get(index + 1)
+                .map(|data| HostKey(fields[index].to_owned(), data.to_string()))
+        })
+        .collect()
+}
+
+impl Requirement for KnownHost {
+    const NAME: &'static str = "known_host";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "The host keys of a remote host that ssh connects to, pinned in the host keys directory.",
+        create: "Writes the configured key, or fetches the keys of the host with `ssh-keyscan` and trusts them on first use. Fails if the host presents different keys than the keys that have been pinned before.",
+        verify: "Checks that the keys have been pinned, and that the host still presents the same keys. A host that cannot be reached is not reported.",
+        undo: "Nothing: the pinned keys are kept, so that the host is not trusted again without checking its keys if it is added back.",
+    };
+    const FAILURE_HINTS: &'static [FailureHint] = &[FailureHint {
+        pattern: "has changed",
+        hint: "The host may have been reinstalled, or the connection may be intercepted. Once you have checked the new keys, remove the pinned file to trust them.",
+    }];
+
+    type CreateError<S: System> = SshError<S>;
+    type ModifyError<S: System> = SshError<S>;
+    type DeleteError<S: System> = SshError<S>;
+    type HasBeenCreatedError<S: System> = SshError<S>;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.apply(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.apply(system)
+    }
+
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(self.pinned(system)?.is_some())
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+
+    fn identity(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        false
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        true
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        let pinned = match self.pinned(system) {
+            Ok(Some(pinned)) => pinned,
+            _ => return Ok(false),
+        };
+        if let Some(key) = &self.key {
+            return Ok(pinned == parse_host_keys(key));
+        }
+
+        let scanned = self.scan(system).map_err(|_| ())?;
+        if !scanned.is_empty() && scanned != pinned {
+            audit::host_key_changed(system, &self.pattern(), &self.path);
+            warn!(
+                "The host key of {} has changed, it no longer matches {}",
+                self.pattern(),
+                self.path.display()
+            );
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        if self.key.is_some() {
+            Vec::new()
+        } else {
+            vec!["ssh-keyscan"]
+        }
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Other,
+            self.pattern(),
+            format!("host keys pinned in {}", self.path.display()),
+        )
+    }
+}
+
+impl Display for KnownHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "known_host({})", self.pattern())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_name, parse_host_keys, HostKey, KnownHost, SshError};
+    use crate::requirements::Requirement;
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn parse_keys() {
+        let keys = parse_host_keys(
+            "# backup.example.com:22 SSH-2.0-OpenSSH_9.2p1\n\
+             backup.example.com ssh-ed25519 AAAAC3Nz\n\
+             [backup.example.com]:2222 ecdsa-sha2-nistp256 AAAAE2Vj comment\n\
+             ssh-rsa AAAAB3Nz\n\
+             \n\
+             invalid\n",
+        );
+        assert_eq!(
+            keys.into_iter().collect::<Vec<_>>(),
+            vec![
+                HostKey("ecdsa-sha2-nistp256".to_owned(), "AAAAE2Vj".to_owned()),
+                HostKey("ssh-ed25519".to_owned(), "AAAAC3Nz".to_owned()),
+                HostKey("ssh-rsa".to_owned(), "AAAAB3Nz".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn encode_file_names() {
+        assert_eq!(file_name("backup.example.com"), "backup.example.com");
+        assert_eq!(file_name("a:b"), "a%3Ab");
+        assert_eq!(file_name("a_b"), "a%5Fb");
+        assert_eq!(file_name("fe80::1%eth0"), "fe80%3A%3A1%25eth0");
+    }
+
+    #[test]
+    pub fn pin_configured_key() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("host-keys");
+        let host = KnownHost {
+            host: "backup.example.com".to_owned(),
+            port: 2222,
+            key: Some("ssh-ed25519 AAAAC3Nz".to_owned()),
+            path: dir.join("backup.example.com_2222"),
+        };
+
+        assert!(!host.has_been_created(&mut system).unwrap());
+        assert!(!host.verify(&mut system).unwrap());
+        host.create(&mut system).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&host.path).unwrap(),
+            "[backup.example.com]:2222 ssh-ed25519 AAAAC3Nz\n"
+        );
+        assert!(host.verify(&mut system).unwrap());
+        assert!(host.required_commands().is_empty());
+
+        let rotated = KnownHost {
+            key: Some("backup.example.com ssh-ed25519 AAAAOTHER".to_owned()),
+            ..host.clone()
+        };
+        assert!(!rotated.verify(&mut system).unwrap());
+        assert_eq!(host.to_string(), "known_host([backup.example.com]:2222)");
+        assert!(host.affects(&rotated));
+    }
+
+    #[test]
+    pub fn reject_option_hosts() {
+        let mut system = LocalSystem::new();
+        let host = KnownHost {
+            host: "-oProxyCommand=true".to_owned(),
+            port: 22,
+            key: None,
+            path: PathBuf::from("/nonexistent/host-keys/-oProxyCommand=true_22"),
+        };
+
+        assert!(matches!(
+            host.scan(&mut system),
+            Err(SshError::InvalidHost(_))
+        ));
+    }
+
+    #[test]
+    pub fn reject_invalid_key() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("host-keys-invalid");
+        let host = KnownHost {
+            host: "backup.example.com".to_owned(),
+            port: 22,
+            key: Some("backup.example.com AAAAC3Nz".to_owned()),
+            path: dir.join("backup.example.com_22"),
+        };
+
+        assert!(matches!(
+            host.create(&mut system),
+            Err(SshError::InvalidKey(..))
+        ));
+        assert!(!host.path.exists());
+    }
+}
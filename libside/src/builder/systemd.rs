@@ -1,19 +1,134 @@
+use crate::bootstrap;
 use crate::config::systemd::*;
+use crate::distro::ServiceManager;
 use crate::graph::GraphNodeReference;
-use crate::requirements::{Requirement, Supports};
+use crate::report::{Category, Description, Fact};
+use crate::requirements::{Explanation, FailureHint, Requirement, Supports, VerificationFailure};
+use crate::retry::RetryPolicy;
 use crate::system::{NeverError, System};
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::io::{BufRead, BufReader, Cursor, Write};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
 
-use super::fs::{ConfigFileData, CreateDirectory, FileWithContents};
+use super::fs::{Chmod, ConfigFileData, CreateDirectory, FileWithContents};
 use super::path::WillBeCreated;
+use super::service::{Action, Invocation};
 use super::{path::BindPath, Chroot, Context, Mounted, Path};
 
 pub trait SystemdUnit {
     fn name(&self) -> &str;
     fn start_dependencies(&self) -> &[GraphNodeReference];
     fn file_dependency(&self) -> GraphNodeReference;
+
+    /// How the unit is drained before it is stopped or restarted.
+    fn drain(&self) -> Option<&Drain> {
+        None
+    }
+}
+
+/// Gives a service the chance to finish its work before it is stopped or restarted, for example by waiting for open connections to close.
+/// When the timeout expires, the service is stopped anyway.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Drain {
+    /// Runs a command that returns once the service has been drained, such as a graceful shutdown hook.
+    Command {
+        command: Vec<String>,
+        timeout_secs: u64,
+    },
+
+    /// Repeatedly runs a command that prints the number of active connections, until the number is at most `threshold`.
+    Connections {
+        command: Vec<String>,
+        threshold: u64,
+        timeout_secs: u64,
+    },
+}
+
+impl Drain {
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn command(command: &[&str], timeout: Duration) -> Drain {
+        Drain::Command {
+            command: command.iter().map(|&arg| arg.to_owned()).collect(),
+            timeout_secs: timeout.as_secs(),
+        }
+    }
+
+    pub fn connections(command: &[&str], threshold: u64, timeout: Duration) -> Drain {
+        Drain::Connections {
+            command: command.iter().map(|&arg| arg.to_owned()).collect(),
+            threshold,
+            timeout_secs: timeout.as_secs(),
+        }
+    }
+
+    /// Waits until at most `threshold` TCP connections to the local `port` are established, as counted by `ss`.
+    pub fn tcp_connections(port: u16, threshold: u64, timeout: Duration) -> Drain {
+        let count = format!(
+            "ss --no-header --tcp state established '( sport = :{} )' | wc -l",
+            port
+        );
+        Drain::connections(&["sh", "-c", &count], threshold, timeout)
+    }
+
+    /// Drains the service. Failures are logged, because they should never prevent the service from being stopped.
+    fn run<S: System>(&self, system: &mut S, service: &str) {
+        match self {
+            Drain::Command {
+                command,
+                timeout_secs,
+            } => {
+                let timeout = timeout_secs.to_string();
+                let mut args = vec![timeout.as_str()];
+                args.extend(command.iter().map(String::as_str));
+                match system.execute_command("timeout", &args) {
+                    Ok(result) if result.is_success() => {}
+                    Ok(result) => warn!(
+                        "Draining {} was unsuccessful or timed out: {}",
+                        service,
+                        result.stderr_as_str().trim()
+                    ),
+                    Err(e) => warn!("Unable to drain {}: {}", service, e),
+                }
+            }
+            Drain::Connections {
+                command,
+                threshold,
+                timeout_secs,
+            } => {
+                let (path, args) = command.split_first().unwrap();
+                let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+                let start = Instant::now();
+                loop {
+                    let count = system
+                        .execute_command(path, &args)
+                        .ok()
+                        .filter(|result| result.is_success())
+                        .and_then(|result| result.stdout_as_str().trim().parse::<u64>().ok());
+                    match count {
+                        Some(count) if count <= *threshold => break,
+                        Some(count) => {
+                            info!("Waiting for {} connections to {} to close", count, service)
+                        }
+                        None => {
+                            warn!("Unable to count the connections to {}", service);
+                            break;
+                        }
+                    }
+
+                    if start.elapsed() >= Duration::from_secs(*timeout_secs) {
+                        warn!("Draining {} timed out", service);
+                        break;
+                    }
+
+                    std::thread::sleep(Self::POLL_INTERVAL);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -98,6 +213,7 @@ pub struct SystemdService {
     file_dependency: GraphNodeReference,
     pub(crate) start_dependencies: Vec<GraphNodeReference>,
     override_dir: Option<Path<WillBeCreated>>,
+    drain: Option<Drain>,
 }
 
 impl SystemdService {
@@ -112,9 +228,15 @@ impl SystemdService {
             file_dependency,
             start_dependencies,
             override_dir: None,
+            drain: None,
         }
     }
 
+    /// Drains the service before it is stopped or restarted by the `ServiceRunning` requirements that are added afterwards.
+    pub fn set_drain(&mut self, drain: Drain) {
+        self.drain = Some(drain);
+    }
+
     pub fn name(&self) -> &str {
         self.full_name.as_str()
     }
@@ -125,8 +247,19 @@ impl SystemdService {
         data: TimerData,
     ) -> SystemdTimer
     where
-        R: Supports<FileWithContents> + Supports<InstallServices> + Supports<EnableService>,
+        R: Supports<CreateDirectory>
+            + Supports<FileWithContents>
+            + Supports<InstallServices>
+            + Supports<EnableService>,
     {
+        let manager = context.service_manager();
+        if !manager.is_systemd() {
+            panic!(
+                "The timer of {} requires systemd, but services are run with {}",
+                self.full_name, manager
+            );
+        }
+
         let disabled_service = EnableService::disable(context, &self);
         let timer = data.install(context, &self.name, disabled_service);
 
@@ -143,6 +276,15 @@ impl SystemdService {
     where
         R: Supports<CreateDirectory> + Supports<FileWithContents> + Supports<InstallServices>,
     {
+        let manager = context.service_manager();
+        if !manager.is_systemd() {
+            warn!(
+                "{} does not support drop-in overrides, {} of {} is ignored",
+                manager, override_name, self.full_name
+            );
+            return self.file_dependency;
+        }
+
         let override_dir = self.override_dir.get_or_insert_with(|| {
             let dir = context.existing("/etc/systemd/system/");
             dir.make_dir(context, format!("{}.service.d", self.name))
@@ -190,6 +332,10 @@ impl SystemdUnit for SystemdService {
     fn file_dependency(&self) -> GraphNodeReference {
         self.file_dependency
     }
+
+    fn drain(&self) -> Option<&Drain> {
+        self.drain.as_ref()
+    }
 }
 
 pub struct ServiceData {
@@ -229,24 +375,52 @@ impl ServiceData {
             .chain(self.resource_control.graph_dependencies.iter())
     }
 
-    pub fn install<R: Requirement + Supports<FileWithContents>>(
-        self,
-        context: &mut Context<R>,
-        name: &str,
-    ) -> SystemdService {
-        let dir = context.existing("/etc/systemd/system/");
+    /// Installs the unit `<name>.service`, or an init script generated from it if the services are not run with systemd.
+    pub fn install<R>(self, context: &mut Context<R>, name: &str) -> SystemdService
+    where
+        R: Requirement + Supports<CreateDirectory> + Supports<FileWithContents> + Supports<Chmod>,
+    {
+        let unit = format!("{}.service", name);
+        // A duplicate unit fails the build after the current package
+        let _ = context.claim_unique("systemd unit", &unit);
+        let manager = context.service_manager();
+        if !manager.is_systemd() && name.ends_with('@') {
+            panic!(
+                "{} is a template unit, which {} does not support",
+                unit, manager
+            );
+        }
+
+        let file = manager
+            .backend()
+            .service_file(&unit, &self.to_vec().unwrap());
+        if !file.ignored.is_empty() {
+            warn!(
+                "{} does not support {} of {}, they are ignored",
+                manager,
+                file.ignored.join(", "),
+                unit
+            );
+        }
+
+        let dir = context.existing(file.path.parent().unwrap());
         let created_file = ConfigFileData {
-            path: dir.join(&format!("{}.service", name)).full_path(),
-            contents: self.to_vec().unwrap(),
+            path: file.path,
+            contents: file.contents,
             path_dependency: dir.node,
             extra_dependencies: Vec::new(),
         }
         .create(context);
+        let file_dependency = if file.executable {
+            created_file.chmod(context, 0o755)
+        } else {
+            created_file.graph_node().unwrap()
+        };
 
-        let deps = std::iter::once(created_file.node.unwrap())
+        let deps = std::iter::once(file_dependency)
             .chain(self.dependencies().copied())
             .collect();
-        SystemdService::from_name_unchecked(name, created_file.graph_node().unwrap(), deps)
+        SystemdService::from_name_unchecked(name, file_dependency, deps)
     }
 }
 
@@ -326,13 +500,20 @@ impl TimerData {
             .chain(self.timer.graph_dependencies.iter())
     }
 
-    fn install<R: Requirement + Supports<FileWithContents> + Supports<EnableService>>(
+    fn install<R>(
         self,
         context: &mut Context<R>,
         name: &str,
         disabled_service: GraphNodeReference,
-    ) -> SystemdTimer {
+    ) -> SystemdTimer
+    where
+        R: Requirement
+            + Supports<CreateDirectory>
+            + Supports<FileWithContents>
+            + Supports<EnableService>,
+    {
         let full_name = format!("{}.timer", name);
+        let _ = context.claim_unique("systemd unit", &full_name);
         let dir = context.existing("/etc/systemd/system/");
         let created_file = ConfigFileData {
             path: dir.join(&full_name).full_path(),
@@ -367,6 +548,12 @@ pub struct ServiceRunning {
 
     #[serde(default)]
     oneshot: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    drain: Option<Drain>,
+
+    #[serde(default, skip_serializing_if = "ServiceManager::is_systemd")]
+    manager: ServiceManager,
 }
 
 impl ServiceRunning {
@@ -376,11 +563,14 @@ impl ServiceRunning {
         context: &mut Context<R>,
         unit: &U,
     ) -> GraphNodeReference {
+        let manager = context.service_manager();
         context.add_node(
             ServiceRunning {
                 name: unit.name().to_string(),
                 must_restart: true,
                 oneshot: false,
+                drain: unit.drain().cloned(),
+                manager,
             },
             unit.start_dependencies(),
         )
@@ -392,11 +582,14 @@ impl ServiceRunning {
         context: &mut Context<R>,
         unit: &U,
     ) -> GraphNodeReference {
+        let manager = context.service_manager();
         context.add_node(
             ServiceRunning {
                 name: unit.name().to_string(),
                 must_restart: false,
                 oneshot: false,
+                drain: unit.drain().cloned(),
+                manager,
             },
             unit.start_dependencies(),
         )
@@ -410,23 +603,45 @@ impl ServiceRunning {
         context: &mut Context<R>,
         unit: &U,
     ) -> GraphNodeReference {
+        let manager = context.service_manager();
         context.add_node(
             ServiceRunning {
                 name: unit.name().to_string(),
                 must_restart: false,
                 oneshot: true,
+                drain: unit.drain().cloned(),
+                manager,
             },
             unit.start_dependencies(),
         )
     }
 }
 
+impl ServiceRunning {
+    fn action(&self) -> Action {
+        if self.must_restart {
+            Action::Restart
+        } else {
+            // systemd doesn't do anything if we run systemctl start ... on an already running service.
+            // but just in case the service somehow died after we checked its status, we can try to start it.
+            Action::Start
+        }
+    }
+
+    fn drain<S: System>(&self, system: &mut S) {
+        if let Some(drain) = &self.drain {
+            info!("Draining {}", self.name);
+            drain.run(system, &self.name);
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum SystemdError<S: System> {
-    #[error("unable to execute systemctl: {0}")]
+    #[error("unable to execute the service manager: {0}")]
     FailedToStart(S::CommandError),
 
-    #[error("systemctl failed: {0} {1}")]
+    #[error("the service manager failed: {0} {1}")]
     Unsuccessful(String, String),
 }
 
@@ -437,9 +652,19 @@ impl<S: System> From<(&str, &str)> for SystemdError<S> {
 }
 
 #[derive(Debug, thiserror::Error)]
-#[error("unable to execute systemctl: {0}")]
+#[error("unable to execute the service manager: {0}")]
 pub struct CheckError<S: System>(S::CommandError);
 
+/// Runs `invocation`, and fails if it is unsuccessful.
+fn run<S: System>(system: &mut S, invocation: Invocation) -> Result<(), SystemdError<S>> {
+    let result = system
+        .execute_command(invocation.program, &invocation.args())
+        .map_err(SystemdError::FailedToStart)?;
+    result.successful()?;
+
+    Ok(())
+}
+
 impl Requirement for ServiceRunning {
     type CreateError<S: System> = SystemdError<S>;
     type ModifyError<S: System> = SystemdError<S>;
@@ -447,38 +672,29 @@ impl Requirement for ServiceRunning {
     type HasBeenCreatedError<S: System> = CheckError<S>;
 
     fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
-        let result = system
-            .execute_command("systemctl", &["start", &self.name])
-            .map_err(SystemdError::FailedToStart)?;
-        result.successful()?;
-
-        Ok(())
+        run(
+            system,
+            self.manager.backend().control(Action::Start, &self.name),
+        )
     }
 
     fn modify<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
-        let action = if self.must_restart {
-            "restart"
-        } else {
-            // systemd doesn't do anything if we run systemctl start ... on an already running service.
-            // but just in case the service somehow died after we checked its status, we can try to start it.
-            "start"
-        };
-
-        let result = system
-            .execute_command("systemctl", &[action, &self.name])
-            .map_err(SystemdError::FailedToStart)?;
-        result.successful()?;
+        if self.must_restart {
+            self.drain(system);
+        }
 
-        Ok(())
+        run(
+            system,
+            self.manager.backend().control(self.action(), &self.name),
+        )
     }
 
     fn delete<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
-        let result = system
-            .execute_command("systemctl", &["stop", &self.name])
-            .map_err(SystemdError::FailedToStart)?;
-        result.successful()?;
-
-        Ok(())
+        self.drain(system);
+        run(
+            system,
+            self.manager.backend().control(Action::Stop, &self.name),
+        )
     }
 
     fn pre_existing_delete<S: crate::system::System>(
@@ -492,8 +708,9 @@ impl Requirement for ServiceRunning {
         &self,
         system: &mut S,
     ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        let invocation = self.manager.backend().is_active(&self.name);
         let result = system
-            .execute_command("systemctl", &["is-active", &self.name])
+            .execute_command(invocation.program, &invocation.args())
             .map_err(CheckError)?;
         Ok(result.is_success())
     }
@@ -502,6 +719,10 @@ impl Requirement for ServiceRunning {
         self.name == other.name
     }
 
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
     fn supports_modifications(&self) -> bool {
         true
     }
@@ -524,7 +745,67 @@ impl Requirement for ServiceRunning {
         }
     }
 
+    fn verification_failure<S: System>(&self, _system: &mut S) -> VerificationFailure {
+        VerificationFailure::ServiceStopped
+    }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let invocation = self.manager.backend().control(self.action(), &self.name);
+        Some(bootstrap::command(invocation.program, &invocation.args()))
+    }
+
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        if self.oneshot {
+            Vec::new()
+        } else {
+            vec![Fact::ServiceRunning(self.name.clone())]
+        }
+    }
+
+    /// Starting a service fails when it is still stopping after a restart that was triggered by something else, such as a dependency.
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        Some(RetryPolicy::new(3, 1000))
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        let mut commands = self.manager.backend().required_commands();
+        if let Some(Drain::Command { .. }) = &self.drain {
+            commands.push("timeout");
+        }
+
+        commands
+    }
+
+    fn describe(&self) -> Description {
+        let summary = if self.oneshot {
+            "triggered"
+        } else if self.must_restart {
+            "restarted and running"
+        } else {
+            "running"
+        };
+        Description::new(Category::Service, &self.name, summary)
+    }
+
     const NAME: &'static str = "service_status";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A service that is running, or restarted when its configuration changed. Services that are run with OpenRC or SysV init are managed with `rc-service` or `service` instead of `systemctl`.",
+        create: "Starts the service with `systemctl start`, or restarts it when the requirement asks for a restart. A drain command or timeout is run first, so that the service can finish its work.",
+        verify: "Checks that `systemctl is-active` reports the service as active. Oneshot services always pass, because they do not keep running.",
+        undo: "Stops the service with `systemctl stop`, after draining it. A service that was already running before the requirement is stopped as well.",
+    };
+    const DOCS_URL: Option<&'static str> =
+        Some("https://www.freedesktop.org/software/systemd/man/systemctl.html");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "not found",
+            hint: "systemd does not know the unit. Check that its unit file is installed, and that systemd has been reloaded since.",
+        },
+        FailureHint {
+            pattern: "journalctl",
+            hint: "The service failed. Its logs are shown by `journalctl -u` with the name of the service.",
+        },
+    ];
 }
 
 impl Display for ServiceRunning {
@@ -533,18 +814,42 @@ impl Display for ServiceRunning {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub struct InstallServices;
+/// Reloads the unit files. Other service managers read their init scripts whenever a service is started, so there is nothing to reload.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(from = "Option<ServiceManager>", into = "Option<ServiceManager>")]
+pub struct InstallServices {
+    manager: ServiceManager,
+}
+
+/// Serialized as `null` with systemd, like before other service managers were supported.
+impl From<Option<ServiceManager>> for InstallServices {
+    fn from(manager: Option<ServiceManager>) -> Self {
+        InstallServices {
+            manager: manager.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<InstallServices> for Option<ServiceManager> {
+    fn from(install: InstallServices) -> Self {
+        Some(install.manager).filter(|manager| !manager.is_systemd())
+    }
+}
 
 impl InstallServices {
     pub fn run<R: Requirement + Supports<InstallServices>>(
         context: &mut Context<R>,
         dependencies: &[GraphNodeReference],
     ) -> GraphNodeReference {
-        context.add_node(InstallServices, dependencies)
+        let manager = context.service_manager();
+        context.add_node(InstallServices { manager }, dependencies)
     }
 
     fn exec<S: System>(&self, system: &mut S) -> Result<(), SystemdError<S>> {
+        if !self.manager.is_systemd() {
+            return Ok(());
+        }
+
         let result = system
             .execute_command("systemctl", &["daemon-reload"])
             .map_err(SystemdError::FailedToStart)?;
@@ -573,7 +878,7 @@ impl InstallServices {
             let sub = i.next().unwrap();
 
             if load == "not-found" && active == "inactive" && sub == "running" {
-                println!("  not-found: {}", name);
+                tracing::info!("  not-found: {}", name);
                 let result = system
                     .execute_command("systemctl", &["stop", &name])
                     .map_err(SystemdError::FailedToStart)?;
@@ -631,7 +936,37 @@ impl Requirement for InstallServices {
         Ok(true)
     }
 
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        Some(if self.manager.is_systemd() {
+            bootstrap::command("systemctl", &["daemon-reload"])
+        } else {
+            String::new()
+        })
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        if self.manager.is_systemd() {
+            vec!["systemctl"]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Service,
+            self.manager.to_string(),
+            "unit files reloaded",
+        )
+    }
+
     const NAME: &'static str = "install_services";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "Makes systemd pick up new and changed unit files.",
+        create: "Runs `systemctl daemon-reload`. It runs again whenever a unit file changes.",
+        verify: "Always passes.",
+        undo: "Runs `systemctl daemon-reload` again, so that systemd forgets the units that were removed.",
+    };
 }
 
 impl Display for InstallServices {
@@ -644,6 +979,9 @@ impl Display for InstallServices {
 pub struct EnableService {
     name: String,
     disable: bool,
+
+    #[serde(default, skip_serializing_if = "ServiceManager::is_systemd")]
+    manager: ServiceManager,
 }
 
 impl EnableService {
@@ -651,10 +989,12 @@ impl EnableService {
         context: &mut Context<R>,
         unit: &U,
     ) -> GraphNodeReference {
+        let manager = context.service_manager();
         context.add_node(
             EnableService {
                 name: unit.name().to_string(),
                 disable: false,
+                manager,
             },
             &[unit.file_dependency()],
         )
@@ -664,10 +1004,12 @@ impl EnableService {
         context: &mut Context<R>,
         unit: &U,
     ) -> GraphNodeReference {
+        let manager = context.service_manager();
         context.add_node(
             EnableService {
                 name: unit.name().to_string(),
                 disable: true,
+                manager,
             },
             unit.start_dependencies(),
         )
@@ -689,30 +1031,28 @@ impl Requirement for EnableService {
     type HasBeenCreatedError<S: System> = SystemdError<S>;
 
     fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
-        let result = system
-            .execute_command("systemctl", &[Self::keyword(self.disable), &self.name])
-            .map_err(SystemdError::FailedToStart)?;
-        result.successful()?;
-
-        Ok(())
+        run(
+            system,
+            self.manager
+                .backend()
+                .set_enabled(&self.name, !self.disable),
+        )
     }
 
     fn modify<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
-        let result = system
-            .execute_command("systemctl", &[Self::keyword(self.disable), &self.name])
-            .map_err(SystemdError::FailedToStart)?;
-        result.successful()?;
-
-        Ok(())
+        run(
+            system,
+            self.manager
+                .backend()
+                .set_enabled(&self.name, !self.disable),
+        )
     }
 
     fn delete<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
-        let result = system
-            .execute_command("systemctl", &[Self::keyword(!self.disable), &self.name])
-            .map_err(SystemdError::FailedToStart)?;
-        result.successful()?;
-
-        Ok(())
+        run(
+            system,
+            self.manager.backend().set_enabled(&self.name, self.disable),
+        )
     }
 
     fn pre_existing_delete<S: crate::system::System>(
@@ -726,25 +1066,24 @@ impl Requirement for EnableService {
         &self,
         system: &mut S,
     ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        let backend = self.manager.backend();
+        let invocation = backend.is_enabled(&self.name);
         let result = system
-            .execute_command("systemctl", &["is-enabled", &self.name])
+            .execute_command(invocation.program, &invocation.args())
             .map_err(SystemdError::FailedToStart)?;
-        if !result.is_success() {
-            return Ok(self.disable);
-        }
-
-        let s = result.stdout_as_str().trim();
-        Ok(if self.disable {
-            s == "disabled" || s == "static"
-        } else {
-            s == "enabled"
-        })
+        let enabled =
+            backend.parse_enabled(&self.name, result.is_success(), result.stdout_as_str());
+        Ok(enabled == Some(!self.disable))
     }
 
     fn affects(&self, other: &Self) -> bool {
         self.name == other.name
     }
 
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
     fn supports_modifications(&self) -> bool {
         false
     }
@@ -761,7 +1100,45 @@ impl Requirement for EnableService {
         Ok(self.has_been_created(system).unwrap())
     }
 
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let invocation = self
+            .manager
+            .backend()
+            .set_enabled(&self.name, !self.disable);
+        Some(bootstrap::command(invocation.program, &invocation.args()))
+    }
+
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        vec![Fact::ServiceEnabled {
+            name: self.name.clone(),
+            enabled: !self.disable,
+        }]
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        self.manager.backend().required_commands()
+    }
+
+    fn describe(&self) -> Description {
+        let summary = if self.disable { "disabled" } else { "enabled" };
+        Description::new(Category::Service, &self.name, summary)
+    }
+
     const NAME: &'static str = "service_enabled";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A service that is enabled or disabled at boot. With OpenRC or SysV init, its init script is added to or removed from the default runlevel with `rc-update` or `update-rc.d`.",
+        create: "Runs `systemctl enable` or `systemctl disable`.",
+        verify: "Checks the output of `systemctl is-enabled`. A disabled unit may also be `static`.",
+        undo: "Runs the opposite command. A unit that already had the right state before the requirement is left alone.",
+    };
+    const DOCS_URL: Option<&'static str> =
+        Some("https://www.freedesktop.org/software/systemd/man/systemctl.html");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "not found",
+            hint: "systemd does not know the unit. Check that its unit file is installed, and that systemd has been reloaded since.",
+        },
+    ];
 }
 
 impl Display for EnableService {
@@ -773,11 +1150,13 @@ impl Display for EnableService {
 #[cfg(test)]
 mod tests {
     use crate::{
-        builder::systemd::{EnableService, InstallServices, ServiceRunning},
+        builder::systemd::{Drain, EnableService, InstallServices, ServiceRunning},
+        distro::ServiceManager,
         requirements::Requirement,
-        system::System,
-        testing::LxcInstance,
+        system::{LocalSystem, System},
+        testing::{LxcInstance, TempDir},
     };
+    use std::time::Duration;
 
     #[test]
     pub fn serialize_deserialize_service_running() {
@@ -785,6 +1164,8 @@ mod tests {
             name: String::from("foo"),
             must_restart: true,
             oneshot: false,
+            drain: None,
+            manager: ServiceManager::Systemd,
         };
         let json = r#"{"name":"foo","must_restart":true,"oneshot":false}"#;
 
@@ -795,6 +1176,39 @@ mod tests {
         assert_eq!(r, serde_json::from_str(r#"{"name":"foo"}"#).unwrap());
     }
 
+    #[test]
+    pub fn serialize_deserialize_service_running_with_drain() {
+        let r = ServiceRunning {
+            name: String::from("nginx.service"),
+            must_restart: true,
+            oneshot: false,
+            drain: Some(Drain::tcp_connections(80, 0, Duration::from_secs(30))),
+            manager: ServiceManager::Systemd,
+        };
+        let json = r#"{"name":"nginx.service","must_restart":true,"oneshot":false,"drain":{"connections":{"command":["sh","-c","ss --no-header --tcp state established '( sport = :80 )' | wc -l"],"threshold":0,"timeout_secs":30}}}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn drain_waits_for_connections() {
+        let dir = TempDir::new("drain");
+        let counter = dir.join("count");
+        std::fs::write(&counter, "2").unwrap();
+        // Every check closes one connection
+        let script = format!(
+            "n=$(cat {0}); echo $n; echo $((n - 1)) > {0}",
+            counter.display()
+        );
+        let drain = Drain::connections(&["sh", "-c", &script], 0, Duration::from_secs(10));
+
+        drain.run(&mut LocalSystem::new(), "test.service");
+        let remaining = std::fs::read_to_string(&counter).unwrap();
+
+        assert_eq!(remaining.trim(), "-1");
+    }
+
     #[test]
     #[ignore]
     pub fn lxc_service_running() {
@@ -804,6 +1218,8 @@ mod tests {
             name: String::from("nginx"),
             must_restart: true,
             oneshot: false,
+            drain: None,
+            manager: ServiceManager::Systemd,
         };
 
         sys.execute_command("apt-get", &["install", "-y", "nginx"])
@@ -841,6 +1257,8 @@ mod tests {
             name: String::from("nginx"),
             must_restart: false,
             oneshot: false,
+            drain: None,
+            manager: ServiceManager::Systemd,
         };
 
         assert!(p.has_been_created(&mut sys).unwrap());
@@ -875,6 +1293,8 @@ mod tests {
             name: String::from("nginx"),
             must_restart: false,
             oneshot: true,
+            drain: None,
+            manager: ServiceManager::Systemd,
         };
 
         assert!(p.has_been_created(&mut sys).unwrap());
@@ -893,18 +1313,45 @@ mod tests {
 
     #[test]
     pub fn serialize_deserialize_install_services() {
-        let r = InstallServices;
+        let r = InstallServices::default();
         let json = r#"null"#;
 
         assert_eq!(serde_json::to_string(&r).unwrap(), json);
         assert_eq!(r, serde_json::from_str(json).unwrap());
     }
 
+    #[test]
+    pub fn serialize_deserialize_openrc_requirements() {
+        let r = ServiceRunning {
+            name: String::from("mailer.service"),
+            must_restart: false,
+            oneshot: false,
+            drain: None,
+            manager: ServiceManager::OpenRc,
+        };
+        let json =
+            r#"{"name":"mailer.service","must_restart":false,"oneshot":false,"manager":"openrc"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+        assert_eq!(
+            r.to_shell(&mut LocalSystem::new()).unwrap(),
+            "rc-service mailer start\n"
+        );
+
+        let r = InstallServices {
+            manager: ServiceManager::SysV,
+        };
+        assert_eq!(serde_json::to_string(&r).unwrap(), r#""sysv""#);
+        assert_eq!(r, serde_json::from_str(r#""sysv""#).unwrap());
+        assert!(r.required_commands().is_empty());
+    }
+
     #[test]
     #[ignore]
     pub fn lxc_install_services() {
         let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
-        let p = InstallServices;
+        let p = InstallServices::default();
 
         p.create(&mut sys).unwrap();
         p.modify(&mut sys).unwrap();
@@ -916,6 +1363,7 @@ mod tests {
         let r = EnableService {
             name: String::from("foo"),
             disable: false,
+            manager: ServiceManager::Systemd,
         };
         let json = r#"{"name":"foo","disable":false}"#;
 
@@ -931,6 +1379,7 @@ mod tests {
         let p = EnableService {
             name: String::from("nginx"),
             disable: false,
+            manager: ServiceManager::Systemd,
         };
 
         sys.execute_command("apt-get", &["install", "-y", "nginx"])
@@ -953,6 +1402,7 @@ mod tests {
         let p = EnableService {
             name: String::from("nginx"),
             disable: true,
+            manager: ServiceManager::Systemd,
         };
 
         assert!(!p.has_been_created(&mut sys).unwrap());
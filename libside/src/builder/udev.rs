@@ -0,0 +1,242 @@
+use crate::bootstrap;
+use crate::graph::GraphNodeReference;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, Requirement, Supports};
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use super::Context;
+
+/// A rules file in `/etc/udev/rules.d`.
+/// Applying the requirement reloads the udev rules and re-triggers device events, so that the new rules apply to devices that are already present.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UdevRule {
+    name: String,
+    contents: String,
+}
+
+impl UdevRule {
+    pub const RULES_DIR: &'static str = "/etc/udev/rules.d";
+
+    /// `name` is the name of the rules file without the `.rules` extension, for example `70-gpu-access`.
+    pub fn new<I: IntoIterator<Item = S>, S: AsRef<str>>(name: &str, rules: I) -> UdevRule {
+        assert!(
+            !name.is_empty() && !name.contains('/'),
+            "invalid udev rules file name {:?}",
+            name
+        );
+
+        let mut contents = String::new();
+        for rule in rules {
+            contents.push_str(rule.as_ref());
+            contents.push('\n');
+        }
+
+        UdevRule {
+            name: name.to_owned(),
+            contents,
+        }
+    }
+
+    pub fn install<R: Requirement + Supports<UdevRule>>(
+        self,
+        context: &mut Context<R>,
+        dependencies: &[GraphNodeReference],
+    ) -> GraphNodeReference {
+        context.add_node(self, dependencies)
+    }
+
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(Self::RULES_DIR).join(format!("{}.rules", self.name))
+    }
+
+    fn reload<S: System>(&self, system: &mut S) -> Result<(), UdevError<S>> {
+        let result = system
+            .execute_command("udevadm", &["control", "--reload"])
+            .map_err(UdevError::FailedToStart)?;
+        result.successful()?;
+
+        let result = system
+            .execute_command("udevadm", &["trigger"])
+            .map_err(UdevError::FailedToStart)?;
+        result.successful()?;
+
+        Ok(())
+    }
+
+    fn write<S: System>(&self, system: &mut S) -> Result<(), UdevError<S>> {
+        system
+            .put_file_contents(&self.path(), self.contents.as_bytes())
+            .map_err(UdevError::UnableToWrite)?;
+
+        self.reload(system)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UdevError<S: System> {
+    #[error("unable to write rules file: {0}")]
+    UnableToWrite(S::Error),
+
+    #[error("unable to remove rules file: {0}")]
+    UnableToRemove(S::Error),
+
+    #[error("unable to execute udevadm: {0}")]
+    FailedToStart(S::CommandError),
+
+    #[error("udevadm failed: {0} {1}")]
+    Unsuccessful(String, String),
+}
+
+impl<S: System> From<(&str, &str)> for UdevError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        UdevError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+impl Requirement for UdevRule {
+    type CreateError<S: System> = UdevError<S>;
+    type ModifyError<S: System> = UdevError<S>;
+    type DeleteError<S: System> = UdevError<S>;
+    type HasBeenCreatedError<S: System> = S::Error;
+
+    fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        self.write(system)
+    }
+
+    fn modify<S: System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        self.write(system)
+    }
+
+    fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        system
+            .remove_file(&self.path())
+            .map_err(UdevError::UnableToRemove)?;
+
+        self.reload(system)
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        system.path_exists(&self.path())
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        true
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+        Ok(match system.file_contents(&self.path()) {
+            Ok(contents) => contents == self.contents.as_bytes(),
+            Err(_) => false,
+        })
+    }
+
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let mut script = bootstrap::write_file(self.path().to_str()?, self.contents.as_bytes());
+        script.push_str(&bootstrap::command("udevadm", &["control", "--reload"]));
+        script.push_str(&bootstrap::command("udevadm", &["trigger"]));
+        Some(script)
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["udevadm"]
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Device,
+            self.path().display().to_string(),
+            format!("{} udev rules", self.contents.lines().count()),
+        )
+    }
+
+    const NAME: &'static str = "udev_rule";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A rules file in `/etc/udev/rules.d`.",
+        create: "Writes the file, reloads the udev rules and re-triggers device events, so that the rules also apply to devices that are already present.",
+        verify: "Compares the contents of the file.",
+        undo: "Removes the file and reloads the rules.",
+    };
+    const DOCS_URL: Option<&'static str> =
+        Some("https://man7.org/linux/man-pages/man7/udev.7.html");
+}
+
+impl Display for UdevRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "udev-rule({})", self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UdevRule;
+    use crate::{requirements::Requirement, system::System, testing::LxcInstance};
+
+    #[test]
+    pub fn serialize_deserialize_udev_rule() {
+        let r = UdevRule::new(
+            "70-gpu",
+            [r#"SUBSYSTEM=="drm", KERNEL=="renderD*", GROUP="render""#],
+        );
+        let json = r#"{"name":"70-gpu","contents":"SUBSYSTEM==\"drm\", KERNEL==\"renderD*\", GROUP=\"render\"\n"}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn udev_rule_name_is_checked() {
+        UdevRule::new("../70-gpu", ["foo"]);
+    }
+
+    #[test]
+    #[ignore]
+    pub fn lxc_udev_rule() {
+        let mut sys = LxcInstance::start(LxcInstance::DEFAULT_IMAGE);
+        let p = UdevRule::new(
+            "70-test",
+            [r#"SUBSYSTEM=="mem", KERNEL=="null", MODE="0666""#],
+        );
+
+        assert!(!p.has_been_created(&mut sys).unwrap());
+        assert!(!p.verify(&mut sys).unwrap());
+
+        p.create(&mut sys).unwrap();
+
+        assert!(p.has_been_created(&mut sys).unwrap());
+        assert!(p.verify(&mut sys).unwrap());
+
+        sys.put_file_contents(&p.path(), b"# changed\n").unwrap();
+        assert!(!p.verify(&mut sys).unwrap());
+
+        p.modify(&mut sys).unwrap();
+        assert!(p.verify(&mut sys).unwrap());
+
+        p.delete(&mut sys).unwrap();
+
+        assert!(!p.has_been_created(&mut sys).unwrap());
+        assert!(!p.verify(&mut sys).unwrap());
+    }
+}
@@ -2,8 +2,10 @@ use super::{AsParam, Context};
 use crate::system::System;
 use crate::utils::parse_etc_group;
 use crate::{
+    bootstrap,
     graph::GraphNodeReference,
-    requirements::{Requirement, Supports},
+    report::{Category, Description, Fact},
+    requirements::{Explanation, FailureHint, Requirement, Resource, Supports},
     system::NeverError,
 };
 use itertools::Itertools;
@@ -48,12 +50,10 @@ impl User {
 
         let info = info;
         let group = Group::add(context, name, info.system);
+        // A duplicate user fails the build after the current package
+        let _ = context.claim_unique("user", name);
         let mapped: &mut MappedUsers = context.state();
 
-        if mapped.users.iter().any(|(n, _)| n == name) {
-            panic!("The user {name} cannot be added multiple times");
-        }
-
         let uid = if let Some(u) = existing.iter().find(|user| user.name == name) {
             u.uid
         } else {
@@ -168,12 +168,10 @@ impl Group {
         system: bool,
     ) -> Group {
         let existing = crate::utils::parse_etc_group(File::open("/etc/group").unwrap()).unwrap();
+        // A duplicate group fails the build after the current package
+        let _ = context.claim_unique("group", name);
         let mapped: &mut MappedGroups = context.state();
 
-        if mapped.groups.iter().any(|(n, _)| n == name) {
-            panic!("The group {name} cannot be added multiple times");
-        }
-
         let gid = if let Some(g) = existing.iter().find(|group| group.name == name) {
             g.gid
         } else {
@@ -289,52 +287,54 @@ impl<S: System> From<(&str, &str)> for CreateUserError<S> {
 #[error("unable to check if user exists: {0}")]
 pub struct CheckUserError<S: System>(S::Error);
 
-impl Requirement for CreateUser {
-    type CreateError<S: System> = CreateUserError<S>;
-    type ModifyError<S: System> = CreateUserError<S>;
-    type DeleteError<S: System> = CreateUserError<S>;
-    type HasBeenCreatedError<S: System> = CheckUserError<S>;
-
-    fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
-        let group_str;
+impl CreateUser {
+    fn useradd_args(&self) -> Vec<String> {
         let mut args = Vec::new();
 
         if self.system {
-            args.push("--system");
+            args.push("--system".to_owned());
         }
 
         if let Some(home_dir) = &self.home_dir {
-            args.push("--create-home");
-            args.push("--home-dir");
-            args.push(home_dir.as_str());
+            args.push("--create-home".to_owned());
+            args.push("--home-dir".to_owned());
+            args.push(home_dir.clone());
         } else {
-            args.push("--no-create-home");
+            args.push("--no-create-home".to_owned());
         }
 
         if self.supplementary_groups.len() > 0 {
-            group_str = self.supplementary_groups.iter().join(",");
-
-            args.push("--groups");
-            args.push(&group_str);
+            args.push("--groups".to_owned());
+            args.push(self.supplementary_groups.iter().join(","));
         }
 
-        args.push("--shell");
-        args.push(&self.shell);
+        args.push("--shell".to_owned());
+        args.push(self.shell.clone());
 
-        let uidstr: String;
         if let Some(uid) = self.uid {
-            args.push("--uid");
-            uidstr = uid.to_string();
-            args.push(&uidstr);
+            args.push("--uid".to_owned());
+            args.push(uid.to_string());
         }
 
         if let Some(group) = &self.group {
-            args.push("--gid");
-            args.push(&group);
+            args.push("--gid".to_owned());
+            args.push(group.clone());
         }
 
-        args.push(&self.name);
+        args.push(self.name.clone());
+        args
+    }
+}
+
+impl Requirement for CreateUser {
+    type CreateError<S: System> = CreateUserError<S>;
+    type ModifyError<S: System> = CreateUserError<S>;
+    type DeleteError<S: System> = CreateUserError<S>;
+    type HasBeenCreatedError<S: System> = CheckUserError<S>;
 
+    fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        let args = self.useradd_args();
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
         let result = system
             .execute_command("useradd", &args)
             .map_err(CreateUserError::FailedToStart)?;
@@ -401,6 +401,10 @@ impl Requirement for CreateUser {
         self.name == other.name
     }
 
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
     fn supports_modifications(&self) -> bool {
         true
     }
@@ -419,7 +423,77 @@ impl Requirement for CreateUser {
         Ok(user.is_some())
     }
 
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let args = self.useradd_args();
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        Some(format!(
+            "id -u {} >/dev/null 2>&1 || {}",
+            bootstrap::quote(&self.name),
+            bootstrap::command("useradd", &args)
+        ))
+    }
+
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        vec![Fact::User {
+            name: self.name.clone(),
+            system: self.system,
+        }]
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["useradd", "usermod", "userdel"]
+    }
+
+    fn describe(&self) -> Description {
+        let mut summary = if self.system {
+            String::from("system user")
+        } else {
+            String::from("user")
+        };
+        if let Some(home_dir) = &self.home_dir {
+            summary.push_str(&format!(" with home {}", home_dir));
+        }
+        if !self.supplementary_groups.is_empty() {
+            summary.push_str(&format!(
+                " in {}",
+                self.supplementary_groups.iter().join(", ")
+            ));
+        }
+
+        Description::new(Category::User, &self.name, summary)
+    }
+
+    fn exclusive_resources(&self) -> Vec<Resource> {
+        self.uid.map(Resource::Uid).into_iter().collect()
+    }
+
     const NAME: &'static str = "user";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A user account.",
+        create: "Runs `useradd`. Changes to the home directory, groups and shell are applied with `usermod`.",
+        verify: "Checks that the user exists.",
+        undo: "Moves the user to the `users` group, so that its primary group is kept, and runs `userdel`.",
+    };
+    const DOCS_URL: Option<&'static str> =
+        Some("https://man7.org/linux/man-pages/man8/useradd.8.html");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "is not unique",
+            hint: "Another user already has this UID. Choose a different UID, or let useradd pick a free one.",
+        },
+        FailureHint {
+            pattern: "cannot lock /etc/passwd",
+            hint: "Another process holds the lock on the user database. Wait for it to finish, or remove a stale `/etc/passwd.lock`.",
+        },
+        FailureHint {
+            pattern: "cannot open /etc/passwd",
+            hint: "The user database cannot be written. Check with `lsattr /etc/passwd /etc/shadow` whether the files are immutable, and remove the flag with `chattr -i`.",
+        },
+        FailureHint {
+            pattern: "already exists",
+            hint: "A user with this name already exists, but was not created by side. Remove it, or apply with `--ask-overwrite` to decide what to do with it.",
+        },
+    ];
 }
 
 impl Display for CreateUser {
@@ -460,27 +534,32 @@ pub enum CheckGroupError<S: System> {
     ParseFailed(std::io::Error),
 }
 
-impl Requirement for CreateGroup {
-    type CreateError<S: System> = CreateGroupError<S>;
-    type ModifyError<S: System> = NeverError;
-    type DeleteError<S: System> = CreateGroupError<S>;
-    type HasBeenCreatedError<S: System> = CheckGroupError<S>;
-
-    fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+impl CreateGroup {
+    fn groupadd_args(&self) -> Vec<String> {
         let mut args = Vec::new();
         if self.system {
-            args.push("--system");
+            args.push("--system".to_owned());
         }
 
-        let gidstr: String;
         if let Some(gid) = self.gid {
-            args.push("--gid");
-            gidstr = gid.to_string();
-            args.push(&gidstr);
+            args.push("--gid".to_owned());
+            args.push(gid.to_string());
         }
 
-        args.push(&self.name);
+        args.push(self.name.clone());
+        args
+    }
+}
+
+impl Requirement for CreateGroup {
+    type CreateError<S: System> = CreateGroupError<S>;
+    type ModifyError<S: System> = NeverError;
+    type DeleteError<S: System> = CreateGroupError<S>;
+    type HasBeenCreatedError<S: System> = CheckGroupError<S>;
 
+    fn create<S: crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+        let args = self.groupadd_args();
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
         let result = system
             .execute_command("groupadd", &args)
             .map_err(CreateGroupError::FailedToStart)?;
@@ -521,6 +600,10 @@ impl Requirement for CreateGroup {
         self.name == other.name
     }
 
+    fn identity(&self) -> String {
+        self.name.clone()
+    }
+
     fn supports_modifications(&self) -> bool {
         false
     }
@@ -535,7 +618,56 @@ impl Requirement for CreateGroup {
         Ok(self.has_been_created(system).unwrap())
     }
 
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        let args = self.groupadd_args();
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        Some(format!(
+            "getent group {} >/dev/null || {}",
+            bootstrap::quote(&self.name),
+            bootstrap::command("groupadd", &args)
+        ))
+    }
+
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        vec![Fact::Group(self.name.clone())]
+    }
+
+    fn required_commands(&self) -> Vec<&'static str> {
+        vec!["groupadd", "groupdel"]
+    }
+
+    fn describe(&self) -> Description {
+        let summary = if self.system { "system group" } else { "group" };
+        Description::new(Category::Group, &self.name, summary)
+    }
+
+    fn exclusive_resources(&self) -> Vec<Resource> {
+        self.gid.map(Resource::Gid).into_iter().collect()
+    }
+
     const NAME: &'static str = "group";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "A group.",
+        create: "Runs `groupadd`.",
+        verify: "Checks that the group is listed in `/etc/group`.",
+        undo: "Runs `groupdel`.",
+    };
+    const DOCS_URL: Option<&'static str> =
+        Some("https://man7.org/linux/man-pages/man8/groupadd.8.html");
+    const FAILURE_HINTS: &'static [FailureHint] = &[
+        FailureHint {
+            pattern: "already exists",
+            hint: "A group with this name or GID already exists, but was not created by side.",
+        },
+        FailureHint {
+            pattern: "cannot lock /etc/group",
+            hint: "Another process holds the lock on the group database. Wait for it to finish, or remove a stale `/etc/group.lock`.",
+        },
+        FailureHint {
+            pattern: "cannot open /etc/group",
+            hint: "The group database cannot be written. Check with `lsattr /etc/group /etc/gshadow` whether the files are immutable, and remove the flag with `chattr -i`.",
+        },
+    ];
 }
 
 impl Display for CreateGroup {
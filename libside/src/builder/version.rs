@@ -0,0 +1,131 @@
+use super::fs::Sha3;
+use crate::report::{Category, Description};
+use crate::requirements::{Explanation, Requirement};
+use crate::system::{NeverError, System};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+/// Records the checksum of the source files of a package in the graph.
+/// The requirement does not change the system, but because it is updated whenever the source files change,
+/// requirements that depend on it are considered changed as well, even if their own parameters are identical.
+/// See [`super::Context::package_version`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PackageVersion {
+    package: String,
+    checksum: Sha3,
+}
+
+impl PackageVersion {
+    pub fn new(package: &str, checksum: Sha3) -> PackageVersion {
+        PackageVersion {
+            package: package.to_string(),
+            checksum,
+        }
+    }
+
+    pub fn package(&self) -> &str {
+        &self.package
+    }
+
+    pub fn checksum(&self) -> Sha3 {
+        self.checksum
+    }
+}
+
+impl Requirement for PackageVersion {
+    const NAME: &'static str = "package_version";
+    const EXPLANATION: Explanation = Explanation {
+        summary: "The checksum of the source files of a package.",
+        create: "Nothing. Because the checksum changes with the source files, the requirements that depend on it are applied again as well.",
+        verify: "Always passes.",
+        undo: "Nothing.",
+    };
+
+    type CreateError<S: System> = NeverError;
+    type ModifyError<S: System> = NeverError;
+    type DeleteError<S: System> = NeverError;
+    type HasBeenCreatedError<S: System> = NeverError;
+
+    fn create<S: System>(&self, _system: &mut S) -> Result<(), Self::CreateError<S>> {
+        Ok(())
+    }
+
+    fn modify<S: System>(&self, _system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        Ok(())
+    }
+
+    fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+        Ok(())
+    }
+
+    fn has_been_created<S: System>(
+        &self,
+        _system: &mut S,
+    ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+        Ok(false)
+    }
+
+    fn affects(&self, other: &Self) -> bool {
+        self.package == other.package
+    }
+
+    fn identity(&self) -> String {
+        self.package.clone()
+    }
+
+    fn supports_modifications(&self) -> bool {
+        true
+    }
+
+    fn can_undo(&self) -> bool {
+        true
+    }
+
+    fn may_pre_exist(&self) -> bool {
+        false
+    }
+
+    fn verify<S: System>(&self, _system: &mut S) -> Result<bool, ()> {
+        Ok(true)
+    }
+
+    fn describe(&self) -> Description {
+        Description::new(
+            Category::Other,
+            &self.package,
+            format!("source checksum {}", self.checksum),
+        )
+    }
+}
+
+impl Display for PackageVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "package_version({} = {})", self.package, self.checksum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PackageVersion;
+    use crate::builder::fs::Sha3;
+    use crate::requirements::Requirement;
+
+    #[test]
+    pub fn serialize_deserialize_package_version() {
+        let r = PackageVersion::new("www", Sha3::default());
+        let json = r#"{"package":"www","checksum":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}"#;
+
+        assert_eq!(serde_json::to_string(&r).unwrap(), json);
+        assert_eq!(r, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn changed_checksum_affects_same_package() {
+        let old = PackageVersion::new("www", Sha3::hash(b"old"));
+        let new = PackageVersion::new("www", Sha3::hash(b"new"));
+        let other = PackageVersion::new("api", Sha3::hash(b"new"));
+
+        assert!(old.affects(&new));
+        assert!(!new.affects(&other));
+    }
+}
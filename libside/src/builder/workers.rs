@@ -0,0 +1,202 @@
+//! A profile for pools of identical worker processes, such as queue consumers.
+//!
+//! The workers are instances `<name>@1` to `<name>@<count>` of a single systemd template unit, so they share one sandbox configuration.
+//! Scaling down is handled by the graph: the instances that are no longer part of the build are stopped and disabled when the previous install is undone.
+use std::time::Duration;
+
+use crate::config::systemd::{Restart, Service, Unit};
+use crate::graph::GraphNodeReference;
+use crate::requirements::{Requirement, Supports};
+
+use super::fs::{Chmod, CreateDirectory, FileWithContents};
+use super::systemd::{
+    EnableService, InstallServices, ServiceData, ServiceRunning, SystemdService, SystemdUnit,
+};
+use super::Context;
+
+/// How systemd restarts workers that fail.
+/// The delay between restarts grows from `initial_delay` to `max_delay` in `steps` steps.
+/// A worker that fails more than `burst` times within `interval` is not restarted anymore, so that a broken release does not keep crashing forever.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub steps: u32,
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            steps: 10,
+            burst: 20,
+            interval: Duration::from_secs(600),
+        }
+    }
+}
+
+impl Backoff {
+    /// Adds the restart policy to the `[Unit]` and `[Service]` sections of a unit.
+    pub fn apply(&self, unit: Unit, service: Service) -> (Unit, Service) {
+        let unit = unit
+            .start_limit_interval_sec(self.interval.as_secs().to_string())
+            .start_limit_burst(self.burst.to_string());
+        let mut service = service
+            .restart(Restart::OnFailure)
+            .restart_sec(self.initial_delay.as_secs().to_string());
+        if self.steps > 0 && self.max_delay > self.initial_delay {
+            service = service
+                .restart_steps(self.steps.to_string())
+                .restart_max_delay_sec(self.max_delay.as_secs().to_string());
+        }
+
+        (unit, service)
+    }
+}
+
+/// A number of worker processes that run the same command.
+/// Use `%i` in the unit to refer to the number of the worker.
+pub struct WorkerPool {
+    name: String,
+    count: usize,
+    backoff: Backoff,
+    data: ServiceData,
+}
+
+/// The installed workers of a [`WorkerPool`].
+pub struct Workers {
+    instances: Vec<SystemdService>,
+    running: Vec<GraphNodeReference>,
+}
+
+impl WorkerPool {
+    /// A pool of `count` workers, which is usually read from the package config.
+    /// The restart settings in `data` are replaced by the restart policy of the pool.
+    pub fn new(name: &str, count: usize, data: ServiceData) -> WorkerPool {
+        assert!(
+            !name.is_empty() && !name.contains(['@', '/']),
+            "invalid worker pool name {:?}",
+            name
+        );
+
+        WorkerPool {
+            name: name.to_owned(),
+            count,
+            backoff: Backoff::default(),
+            data,
+        }
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// The names of the instances, for example `mailer@1.service`.
+    pub fn instance_names(&self) -> Vec<String> {
+        (1..=self.count)
+            .map(|index| format!("{}@{}.service", self.name, index))
+            .collect()
+    }
+
+    /// Installs the template unit, and enables and (re)starts every worker.
+    /// The workers are restarted one after another, so that the pool keeps consuming while it is updated.
+    pub fn install<R>(self, context: &mut Context<R>) -> Workers
+    where
+        R: Requirement
+            + Supports<CreateDirectory>
+            + Supports<FileWithContents>
+            + Supports<Chmod>
+            + Supports<InstallServices>
+            + Supports<EnableService>
+            + Supports<ServiceRunning>,
+    {
+        let (unit, service) = self.backoff.apply(self.data.unit, self.data.service);
+        let template = ServiceData {
+            unit,
+            service,
+            ..self.data
+        }
+        .install(context, &format!("{}@", self.name));
+        let reloaded = InstallServices::run(context, template.start_dependencies());
+
+        let mut instances = Vec::new();
+        let mut running = Vec::new();
+        for index in 1..=self.count {
+            let mut dependencies = vec![reloaded];
+            dependencies.extend(running.last().copied());
+            let mut instance = SystemdService::from_name_unchecked(
+                &format!("{}@{}", self.name, index),
+                reloaded,
+                dependencies,
+            );
+            let enabled = EnableService::enable(context, &instance);
+            instance.add_start_dependencies([enabled]);
+
+            running.push(ServiceRunning::restart(context, &instance));
+            instances.push(instance);
+        }
+
+        Workers { instances, running }
+    }
+}
+
+impl Workers {
+    pub fn instances(&self) -> &[SystemdService] {
+        &self.instances
+    }
+
+    /// The nodes that (re)start the workers, in order.
+    pub fn graph_nodes(&self) -> &[GraphNodeReference] {
+        &self.running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, WorkerPool};
+    use crate::builder::systemd::ServiceData;
+    use crate::config::systemd::{Exec, Install, ResourceControl, Service, Unit};
+    use std::time::Duration;
+
+    #[test]
+    pub fn worker_restart_policy() {
+        let (unit, service) = Backoff::default().apply(Unit::new(), Service::new());
+
+        assert_eq!(
+            unit.to_string(),
+            "StartLimitIntervalSec=600\nStartLimitBurst=20\n"
+        );
+        assert_eq!(
+            service.to_string(),
+            "RestartSec=1\nRestartSteps=10\nRestartMaxDelaySec=60\nRestart=on-failure\n"
+        );
+
+        let fixed = Backoff {
+            max_delay: Duration::from_secs(5),
+            initial_delay: Duration::from_secs(5),
+            ..Default::default()
+        };
+        let (_, service) = fixed.apply(Unit::new(), Service::new());
+        assert_eq!(service.to_string(), "RestartSec=5\nRestart=on-failure\n");
+    }
+
+    #[test]
+    pub fn worker_instance_names() {
+        let data = ServiceData {
+            unit: Unit::new(),
+            install: Install::new(),
+            service: Service::new().exec_start_push("/usr/bin/consume --worker %i"),
+            exec: Exec::new(),
+            resource_control: ResourceControl::new(),
+        };
+
+        assert_eq!(
+            WorkerPool::new("mailer", 3, data).instance_names(),
+            vec!["mailer@1.service", "mailer@2.service", "mailer@3.service"]
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! Reconciling managed files with the changes that dpkg makes to them when a package is upgraded.
+//!
+//! When an upgraded package ships a new version of a conffile that libside also manages, dpkg either replaces the file with the new version,
+//! or keeps the file and writes the new version next to it as `.dpkg-dist` (or `.dpkg-new` while the package is not configured yet).
+//! A [`ConffilePolicy`] decides what happens with the package's version when the file is fixed or applied again.
+use crate::builder::fs::Sha3;
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+/// What to do when a package upgrade changed a managed file, or shipped a new version of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConffilePolicy {
+    /// Overwrite the package's version with ours, which is what happens for all files that are not conffiles.
+    #[default]
+    KeepOurs,
+
+    /// Use the package's version, and accept it as valid during verification.
+    TakeTheirs,
+
+    /// Merge the changes between the original file and the package's version into ours with `diff3`.
+    /// The package's version is kept as `.dpkg-dist`, so that the merge can be verified and repeated when our version changes.
+    Merge,
+}
+
+impl ConffilePolicy {
+    pub fn is_keep_ours(&self) -> bool {
+        *self == ConffilePolicy::KeepOurs
+    }
+}
+
+impl Display for ConffilePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConffilePolicy::KeepOurs => "keep ours",
+            ConffilePolicy::TakeTheirs => "take theirs",
+            ConffilePolicy::Merge => "merge",
+        })
+    }
+}
+
+/// A new version of a managed file that was shipped by a package upgrade.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageChange {
+    /// The package that owns the file.
+    pub package: String,
+
+    /// The file that contains the package's version. This is the file itself if dpkg replaced it.
+    pub theirs: PathBuf,
+}
+
+impl PackageChange {
+    /// True if dpkg replaced the managed file with the package's version.
+    pub fn replaced(&self, path: &Path) -> bool {
+        self.theirs == path
+    }
+}
+
+/// Appends `suffix` to the file name of `path`, such as `.dpkg-dist`.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Parses the output of `dpkg-query -S`, which is `package[, package...]: path`.
+fn parse_owner(output: &str) -> Option<&str> {
+    let (packages, _) = output.lines().next()?.split_once(": ")?;
+    packages
+        .split(", ")
+        .next()
+        .map(|package| package.split(':').next().unwrap_or(package))
+}
+
+/// Returns the md5 checksum of `path` in the output of `dpkg-query -W -f '${Conffiles}'`, which has lines like ` /etc/foo.conf <md5> [obsolete]`.
+fn parse_conffile_md5<'a>(output: &'a str, path: &Path) -> Option<&'a str> {
+    output.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(conffile), Some(md5)) if Path::new(conffile) == path => Some(md5),
+            _ => None,
+        }
+    })
+}
+
+fn md5<S: System>(system: &S, path: &Path) -> Option<String> {
+    let result = system
+        .execute_command("md5sum", &[path.to_str()?])
+        .ok()
+        .filter(|result| result.is_success())?;
+    result
+        .stdout_as_str()
+        .split_whitespace()
+        .next()
+        .map(str::to_owned)
+}
+
+/// Determines whether a package upgrade changed the conffile at `path`, or shipped a new version of it.
+/// Returns `None` if the file is not a conffile of an installed package, if dpkg is not available, or if the package did not change the file.
+pub fn detect<S: System>(system: &S, path: &Path) -> Option<PackageChange> {
+    let owner = system
+        .execute_command("dpkg-query", &["-S", path.to_str()?])
+        .ok()
+        .filter(|result| result.is_success())?;
+    let package = parse_owner(owner.stdout_as_str())?.to_owned();
+
+    let conffiles = system
+        .execute_command("dpkg-query", &["-W", "-f", "${Conffiles}\n", &package])
+        .ok()
+        .filter(|result| result.is_success())?;
+    let expected = parse_conffile_md5(conffiles.stdout_as_str(), path)?.to_owned();
+
+    for suffix in [".dpkg-dist", ".dpkg-new"] {
+        let candidate = with_suffix(path, suffix);
+        if system.path_exists(&candidate).unwrap_or(false) {
+            return Some(PackageChange {
+                package,
+                theirs: candidate,
+            });
+        }
+    }
+
+    if md5(system, path)? == expected {
+        Some(PackageChange {
+            package,
+            theirs: path.to_owned(),
+        })
+    } else {
+        None
+    }
+}
+
+/// Merges the changes from `base` to `theirs` into `ours` with `diff3 -m`.
+/// Returns `None` if the changes conflict. A missing `base` is treated as an empty file.
+pub fn merge<S: System>(
+    system: &S,
+    ours: &Path,
+    base: Option<&Path>,
+    theirs: &Path,
+) -> Result<Option<Vec<u8>>, S::CommandError> {
+    let base = base.and_then(Path::to_str).unwrap_or("/dev/null");
+    let result = system.execute_command(
+        "diff3",
+        &["-m", ours.to_str().unwrap(), base, theirs.to_str().unwrap()],
+    )?;
+
+    Ok(if result.is_success() {
+        Some(result.stdout().to_vec())
+    } else {
+        None
+    })
+}
+
+/// A three-way comparison of a managed file that was changed by a package upgrade, for the report.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ThreeWay {
+    pub path: PathBuf,
+    pub package: String,
+    pub policy: ConffilePolicy,
+
+    /// The checksum of the version that libside generated.
+    pub ours: Sha3,
+
+    /// The checksum of the package's version, and where it is stored.
+    pub theirs: Sha3,
+    pub theirs_path: PathBuf,
+
+    /// The checksum of the file before libside first overwrote it, if it was saved.
+    pub base: Option<Sha3>,
+
+    /// The checksum of the file as it is now.
+    pub current: Sha3,
+}
+
+impl Display for ThreeWay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} (changed by {}, policy: {})",
+            self.path.display(),
+            self.package,
+            self.policy
+        )?;
+        writeln!(f, "    ours   : {}", self.ours)?;
+        writeln!(
+            f,
+            "    theirs : {} ({})",
+            self.theirs,
+            self.theirs_path.display()
+        )?;
+        match &self.base {
+            Some(base) => writeln!(f, "    base   : {}", base)?,
+            None => writeln!(f, "    base   : <not saved>")?,
+        }
+        write!(f, "    current: {}", self.current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge, parse_conffile_md5, parse_owner, with_suffix, ConffilePolicy};
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    pub fn serialize_deserialize_conffile_policy() {
+        assert_eq!(
+            serde_json::to_string(&ConffilePolicy::TakeTheirs).unwrap(),
+            r#""take_theirs""#
+        );
+        assert_eq!(
+            serde_json::from_str::<ConffilePolicy>(r#""merge""#).unwrap(),
+            ConffilePolicy::Merge
+        );
+    }
+
+    #[test]
+    pub fn parse_dpkg_query_output() {
+        assert_eq!(
+            parse_owner("nginx-common: /etc/nginx/nginx.conf\n"),
+            Some("nginx-common")
+        );
+        assert_eq!(
+            parse_owner("libc6:amd64, libc6:i386: /etc/ld.so.conf.d/x86_64-linux-gnu.conf\n"),
+            Some("libc6")
+        );
+        assert_eq!(parse_owner(""), None);
+
+        let conffiles = " /etc/nginx/mime.types 7cd4a0d4e5a8a1a9d7d0b1fa9bd5c7a3\n /etc/nginx/nginx.conf 3ba4e3d1f5b8a0c4e2f1d0c9b8a7f6e5\n /etc/nginx/old.conf 0123 obsolete\n";
+        assert_eq!(
+            parse_conffile_md5(conffiles, Path::new("/etc/nginx/nginx.conf")),
+            Some("3ba4e3d1f5b8a0c4e2f1d0c9b8a7f6e5")
+        );
+        assert_eq!(
+            parse_conffile_md5(conffiles, Path::new("/etc/nginx/sites")),
+            None
+        );
+    }
+
+    #[test]
+    pub fn suffixes() {
+        assert_eq!(
+            with_suffix(Path::new("/etc/php.ini"), ".dpkg-dist"),
+            PathBuf::from("/etc/php.ini.dpkg-dist")
+        );
+    }
+
+    #[test]
+    pub fn merge_changes_from_the_package() {
+        let dir = TempDir::new("conffile");
+        std::fs::write(dir.join("base"), "a = 1\nb = 2\nc = 3\n").unwrap();
+        std::fs::write(dir.join("ours"), "a = 10\nb = 2\nc = 3\n").unwrap();
+        std::fs::write(dir.join("theirs"), "a = 1\nb = 2\nc = 30\n").unwrap();
+        std::fs::write(dir.join("conflict"), "a = 100\nb = 2\nc = 3\n").unwrap();
+
+        let merged = merge(
+            &LocalSystem::new(),
+            &dir.join("ours"),
+            Some(&dir.join("base")),
+            &dir.join("theirs"),
+        )
+        .unwrap();
+        let conflict = merge(
+            &LocalSystem::new(),
+            &dir.join("ours"),
+            Some(&dir.join("base")),
+            &dir.join("conflict"),
+        )
+        .unwrap();
+
+        assert_eq!(merged, Some(b"a = 10\nb = 2\nc = 30\n".to_vec()));
+        assert_eq!(conflict, None);
+    }
+}
@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+use crate::builder::credentials::SystemdCredential;
 use crate::builder::path::{Chroot, Path};
 use crate::builder::users::{Group, User};
 use crate::builder::AsParam;
@@ -359,13 +360,27 @@ directives! {
         (TTYVDisallocate, String)
 
         // Credentials
-        (LoadCredential, String)
-        (SetCredential, String)
+        (LoadCredential, multiple String)
+        (LoadCredentialEncrypted, multiple String)
+        (SetCredential, multiple String)
+        (SetCredentialEncrypted, multiple String)
 
         // Not implemented: System V compatibility
     ]
 }
 
+impl Exec {
+    /// Loads `credential` into the credentials directory of the service, with `LoadCredential=` or `LoadCredentialEncrypted=`.
+    pub fn credential(mut self, credential: &SystemdCredential) -> Self {
+        self.graph_dependencies.extend(credential.graph_node());
+        if credential.is_encrypted() {
+            self.load_credential_encrypted_push(credential.as_param())
+        } else {
+            self.load_credential_push(credential.as_param())
+        }
+    }
+}
+
 directives! {
     Unit [
         (Description, String)
@@ -435,6 +450,8 @@ directives! {
         (ExecStopPost, String)
         (Environment, multiple String)
         (RestartSec, String)
+        (RestartSteps, String)
+        (RestartMaxDelaySec, String)
         (TimeoutStartSec, String)
         (TimeoutStopSec, String)
         (TimeoutAbortSec, String)
@@ -0,0 +1,259 @@
+//! Deciding what happens when an apply finds existing state that it did not create.
+use crate::builder::ignore::glob;
+use crate::requirements::Requirement;
+use std::fmt::Display;
+use std::io::BufRead;
+use std::str::FromStr;
+use tracing::warn;
+
+/// What to do with a requirement that already exists on the system, even though it was not created by a previous install.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Replace the existing state with the requirement.
+    Overwrite,
+
+    /// Stop the apply and revert the changes that have already been made.
+    Abort,
+
+    /// Keep the existing state as it is, and record the requirement as pre-existing.
+    /// Undoing the requirement later leaves the existing state in place.
+    Adopt,
+}
+
+/// A requirement that conflicts with existing state on the system.
+#[derive(Debug)]
+pub struct Conflict<'a, R> {
+    pub requirement: &'a R,
+
+    /// A description of the existing state, as determined by `Requirement::diff`.
+    /// `None` if the requirement cannot describe the difference.
+    pub existing: Option<String>,
+}
+
+impl<'a, R: Display> Display for Conflict<'a, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} already exists", self.requirement)?;
+        if let Some(existing) = &self.existing {
+            write!(f, ": {}", existing)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decides how conflicts with existing state are resolved during an apply.
+pub trait ConflictResolution<R> {
+    fn resolve(&self, conflict: &Conflict<R>) -> Resolution;
+}
+
+impl<R, F: Fn(&Conflict<R>) -> Resolution> ConflictResolution<R> for F {
+    fn resolve(&self, conflict: &Conflict<R>) -> Resolution {
+        self(conflict)
+    }
+}
+
+/// A fixed conflict resolution policy, as selected on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Resolves every conflict with the same resolution.
+    Always(Resolution),
+
+    /// Asks on the terminal how each conflict should be resolved.
+    Ask,
+}
+
+impl ConflictPolicy {
+    pub fn from_ask_overwrite(ask_overwrite: bool) -> ConflictPolicy {
+        if ask_overwrite {
+            ConflictPolicy::Ask
+        } else {
+            ConflictPolicy::Always(Resolution::Abort)
+        }
+    }
+}
+
+impl<R: Display> ConflictResolution<R> for ConflictPolicy {
+    fn resolve(&self, conflict: &Conflict<R>) -> Resolution {
+        match self {
+            ConflictPolicy::Always(resolution) => *resolution,
+            ConflictPolicy::Ask => {
+                println!("{}", conflict);
+                println!("Type 'overwrite' to replace it, 'adopt' to keep it as it is, or anything else to abort");
+                let line = std::io::stdin()
+                    .lock()
+                    .lines()
+                    .next()
+                    .and_then(Result::ok)
+                    .unwrap_or_default();
+                parse_answer(&line)
+            }
+        }
+    }
+}
+
+/// Which existing state an apply may overwrite, as selected with `--overwrite`.
+///
+/// Except for `Interactive`, the policies decide without reading from stdin, so that automated applies can declare up front which pre-existing state they may overwrite.
+/// Like any [`ConflictResolution`], a policy can be passed to [`ApplySequence::run`](crate::graph::ApplySequence::run).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Abort the apply, and revert the changes that have already been made.
+    #[default]
+    Never,
+
+    /// Overwrite all existing state.
+    Always,
+
+    /// Ask on the terminal how each conflict should be resolved. Aborts if stdin is not a terminal.
+    Interactive,
+
+    /// Overwrite existing state if the identity of its requirement, such as the path of a file or the name of a package, matches one of the patterns, and abort otherwise.
+    /// Patterns support `*` and `?`, which never match a `/`. A pattern that matches a directory also matches everything below it.
+    Allowlist(Vec<String>),
+}
+
+impl OverwritePolicy {
+    /// Returns true if the allowlist contains a pattern that matches `identity` or one of its parent directories.
+    fn allows(patterns: &[String], identity: &str) -> bool {
+        let mut path = identity;
+        loop {
+            if patterns.iter().any(|pattern| glob(pattern, path)) {
+                return true;
+            }
+
+            match path.rfind('/') {
+                Some(index) if index > 0 => path = &path[..index],
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl FromStr for OverwritePolicy {
+    type Err = String;
+
+    /// Parses `never`, `always`, `ask`, or `allow=` followed by a comma-separated list of patterns.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(OverwritePolicy::Never),
+            "always" => Ok(OverwritePolicy::Always),
+            "ask" => Ok(OverwritePolicy::Interactive),
+            _ => match s.strip_prefix("allow=") {
+                Some(patterns) => Ok(OverwritePolicy::Allowlist(
+                    patterns
+                        .split(',')
+                        .filter(|pattern| !pattern.is_empty())
+                        .map(str::to_owned)
+                        .collect(),
+                )),
+                None => Err(format!(
+                    "unknown overwrite policy {}, expected never, always, ask or allow=<pattern>,...",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl<R: Requirement> ConflictResolution<R> for OverwritePolicy {
+    fn resolve(&self, conflict: &Conflict<R>) -> Resolution {
+        match self {
+            OverwritePolicy::Never => Resolution::Abort,
+            OverwritePolicy::Always => Resolution::Overwrite,
+            OverwritePolicy::Interactive => {
+                if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+                    ConflictPolicy::Ask.resolve(conflict)
+                } else {
+                    warn!(
+                        "{}, and stdin is not a terminal to ask whether to overwrite it",
+                        conflict
+                    );
+                    Resolution::Abort
+                }
+            }
+            OverwritePolicy::Allowlist(patterns) => {
+                if Self::allows(patterns, &conflict.requirement.identity()) {
+                    Resolution::Overwrite
+                } else {
+                    Resolution::Abort
+                }
+            }
+        }
+    }
+}
+
+fn parse_answer(line: &str) -> Resolution {
+    match line.trim() {
+        // 'yes' is what was asked for before adopting was possible
+        "overwrite" | "yes" => Resolution::Overwrite,
+        "adopt" => Resolution::Adopt,
+        _ => Resolution::Abort,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_answer, Conflict, ConflictResolution, OverwritePolicy, Resolution};
+    use crate::builder::fs::CreateDirectory;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn parse_answers() {
+        assert_eq!(parse_answer("overwrite\n"), Resolution::Overwrite);
+        assert_eq!(parse_answer("yes"), Resolution::Overwrite);
+        assert_eq!(parse_answer(" adopt "), Resolution::Adopt);
+        assert_eq!(parse_answer("no"), Resolution::Abort);
+        assert_eq!(parse_answer(""), Resolution::Abort);
+    }
+
+    #[test]
+    pub fn display_conflict() {
+        let conflict = Conflict {
+            requirement: &"file(/etc/motd)",
+            existing: Some(String::from("12 bytes with a different checksum")),
+        };
+
+        assert_eq!(
+            conflict.to_string(),
+            "file(/etc/motd) already exists: 12 bytes with a different checksum"
+        );
+    }
+
+    #[test]
+    pub fn overwrite_policies() {
+        assert_eq!("never".parse(), Ok(OverwritePolicy::Never));
+        assert_eq!("ask".parse(), Ok(OverwritePolicy::Interactive));
+        assert_eq!(
+            "allow=/etc/nginx,/srv/*/config".parse(),
+            Ok(OverwritePolicy::Allowlist(vec![
+                String::from("/etc/nginx"),
+                String::from("/srv/*/config")
+            ]))
+        );
+        assert!("sometimes".parse::<OverwritePolicy>().is_err());
+
+        let policy: OverwritePolicy = "allow=/etc/nginx,/srv/*/config".parse().unwrap();
+        let resolve = |path: &str| {
+            policy.resolve(&Conflict {
+                requirement: &CreateDirectory::new(PathBuf::from(path)),
+                existing: None,
+            })
+        };
+        assert_eq!(resolve("/etc/nginx"), Resolution::Overwrite);
+        assert_eq!(resolve("/etc/nginx/sites"), Resolution::Overwrite);
+        assert_eq!(resolve("/srv/www/config/app"), Resolution::Overwrite);
+        assert_eq!(resolve("/etc/nginx-old"), Resolution::Abort);
+        assert_eq!(resolve("/srv/config"), Resolution::Abort);
+
+        let directory = CreateDirectory::new(PathBuf::from("/etc/app"));
+        let conflict = Conflict {
+            requirement: &directory,
+            existing: None,
+        };
+        assert_eq!(OverwritePolicy::Never.resolve(&conflict), Resolution::Abort);
+        assert_eq!(
+            OverwritePolicy::Always.resolve(&conflict),
+            Resolution::Overwrite
+        );
+    }
+}
@@ -0,0 +1,465 @@
+//! A local control socket for `side agent`, so that orchestration tooling can drive the agent without running the CLI.
+//!
+//! The socket speaks JSON-RPC 2.0, with one request and one response per line.
+//! The supported methods are `status`, `trigger-build`, `trigger-verify` and `cancel`; none of them take parameters.
+//! Only root and the user that runs the agent can connect, which is checked with the peer credentials of the connection.
+use crate::agent::AgentStatus;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const PARSE_ERROR: i64 = -32700;
+const PERMISSION_DENIED: i64 = -32000;
+const NOT_APPLYING: i64 = -32001;
+const TOO_MANY_CONNECTIONS: i64 = -32002;
+
+/// How long a connection may stay idle before it is closed.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many connections are handled at the same time. Further connections are refused until one of them is closed.
+const MAX_CONNECTIONS: usize = 16;
+
+/// The maximum length of a request line in bytes. A connection that sends a longer line is closed.
+const MAX_REQUEST_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ControlError {
+    #[error("unable to connect to {}: {}", .0.display(), .1)]
+    UnableToConnect(PathBuf, std::io::Error),
+
+    #[error("unable to communicate with the agent: {}", .0)]
+    Io(std::io::Error),
+
+    #[error("invalid response from the agent: {}", .0)]
+    InvalidResponse(serde_json::Error),
+
+    #[error("the agent returned an error: {} ({})", .0.message, .0.code)]
+    Rpc(RpcError),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub id: Value,
+    pub method: String,
+
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl Response {
+    fn result(id: Value, result: Value) -> Response {
+        Response {
+            jsonrpc: String::from("2.0"),
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(id: Value, code: i64, message: &str) -> Response {
+        Response {
+            jsonrpc: String::from("2.0"),
+            id,
+            result: None,
+            error: Some(RpcError {
+                code,
+                message: message.to_owned(),
+            }),
+        }
+    }
+}
+
+/// The state that the agent shares with the control socket.
+pub struct Control {
+    /// The status of the agent, which is updated by the agent after every iteration.
+    pub status: Mutex<AgentStatus>,
+
+    /// True while the agent is applying a build.
+    pub applying: AtomicBool,
+
+    /// The flag that cancels the apply that is in progress, which is `ApplyLimits::cancelled`.
+    cancelled: Arc<AtomicBool>,
+
+    build: AtomicBool,
+    verify: AtomicBool,
+}
+
+impl Control {
+    pub fn new(status: AgentStatus, cancelled: Arc<AtomicBool>) -> Control {
+        Control {
+            status: Mutex::new(status),
+            applying: AtomicBool::new(false),
+            cancelled,
+            build: AtomicBool::new(false),
+            verify: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns true once if a build was requested through the socket.
+    pub fn take_build_request(&self) -> bool {
+        self.build.swap(false, Ordering::SeqCst)
+    }
+
+    /// Returns true once if a verification was requested through the socket.
+    pub fn take_verify_request(&self) -> bool {
+        self.verify.swap(false, Ordering::SeqCst)
+    }
+
+    /// True if a build or verification was requested that has not been taken yet.
+    pub fn has_requests(&self) -> bool {
+        self.build.load(Ordering::SeqCst) || self.verify.load(Ordering::SeqCst)
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        let id = request.id.clone();
+        match request.method.as_str() {
+            "status" => {
+                let mut status =
+                    serde_json::to_value(&*self.status.lock().unwrap()).unwrap_or_default();
+                if let Value::Object(fields) = &mut status {
+                    fields.insert(
+                        String::from("applying"),
+                        Value::Bool(self.applying.load(Ordering::SeqCst)),
+                    );
+                }
+
+                Response::result(id, status)
+            }
+            "trigger-build" => {
+                self.build.store(true, Ordering::SeqCst);
+                Response::result(id, Value::Bool(true))
+            }
+            "trigger-verify" => {
+                self.verify.store(true, Ordering::SeqCst);
+                Response::result(id, Value::Bool(true))
+            }
+            "cancel" => {
+                if self.applying.load(Ordering::SeqCst) {
+                    self.cancelled.store(true, Ordering::SeqCst);
+                    Response::result(id, Value::Bool(true))
+                } else {
+                    Response::error(id, NOT_APPLYING, "no apply is in progress")
+                }
+            }
+            _ => Response::error(id, METHOD_NOT_FOUND, "method not found"),
+        }
+    }
+}
+
+/// Returns the user id of the process on the other end of `stream`.
+fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    // SAFETY: `cred` and `len` are valid for writes, and `len` is the size of `cred`.
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if result == 0 {
+        Ok(cred.uid)
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Only root and the user that runs the agent may use the socket.
+fn is_authorized(uid: u32) -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    uid == 0 || uid == unsafe { libc::geteuid() }
+}
+
+fn respond(stream: &mut UnixStream, response: &Response) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(response).unwrap();
+    line.push(b'\n');
+    stream.write_all(&line)
+}
+
+fn handle_connection(mut stream: UnixStream, control: &Control) -> std::io::Result<()> {
+    let uid = peer_uid(&stream)?;
+    if !is_authorized(uid) {
+        warn!("Refused a control connection from uid {}", uid);
+        return respond(
+            &mut stream,
+            &Response::error(Value::Null, PERMISSION_DENIED, "permission denied"),
+        );
+    }
+
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+        let mut line = String::new();
+        let mut limited = (&mut reader).take(MAX_REQUEST_LENGTH as u64 + 1);
+        match limited.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                info!("Closing idle control connection from uid {}", uid);
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+        if line.len() > MAX_REQUEST_LENGTH {
+            warn!(
+                "Closing control connection from uid {}: request too long",
+                uid
+            );
+            return respond(
+                &mut stream,
+                &Response::error(Value::Null, INVALID_REQUEST, "request too long"),
+            );
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                info!("Control request from uid {}: {}", uid, request.method);
+                control.handle(&request)
+            }
+            Err(_) => Response::error(Value::Null, PARSE_ERROR, "parse error"),
+        };
+        respond(&mut stream, &response)?;
+    }
+
+    Ok(())
+}
+
+/// Binds a unix socket with mode 0660 at `path`.
+/// The socket is bound in a private directory and moved to `path` once its permissions are set, so that nobody else can connect before that.
+fn bind(path: &Path) -> std::io::Result<UnixListener> {
+    let mut private = path.as_os_str().to_owned();
+    private.push(".bind");
+    let private = PathBuf::from(private);
+    if private.exists() {
+        std::fs::remove_dir_all(&private)?;
+    }
+
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("socket");
+    let listener = UnixListener::bind(&bound)?;
+    std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o660))?;
+    std::fs::rename(&bound, path)?;
+    std::fs::remove_dir(&private)?;
+
+    Ok(listener)
+}
+
+/// Listens on the unix socket `path`, and handles every connection on its own thread,
+/// so that a client that keeps its connection open does not block `status` and `cancel` for other clients.
+/// At most [`MAX_CONNECTIONS`] connections are handled at the same time.
+/// An existing socket at `path`, left behind by an agent that did not stop cleanly, is replaced.
+pub fn serve(path: &Path, control: Arc<Control>) -> std::io::Result<JoinHandle<()>> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = bind(path)?;
+    let active = Arc::new(AtomicUsize::new(0));
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Control connection failed: {}", e);
+                    continue;
+                }
+            };
+
+            if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                active.fetch_sub(1, Ordering::SeqCst);
+                warn!("Refused a control connection: too many connections");
+                let _ = respond(
+                    &mut stream,
+                    &Response::error(Value::Null, TOO_MANY_CONNECTIONS, "too many connections"),
+                );
+                continue;
+            }
+
+            let control = control.clone();
+            let active = active.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, &control) {
+                    warn!("Control connection failed: {}", e);
+                }
+
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    }))
+}
+
+/// Calls `method` on the agent that listens on `path`, and returns the result.
+pub fn call(path: &Path, method: &str) -> Result<Value, ControlError> {
+    let mut stream =
+        UnixStream::connect(path).map_err(|e| ControlError::UnableToConnect(path.to_owned(), e))?;
+    let request = Request {
+        jsonrpc: String::from("2.0"),
+        id: Value::from(1),
+        method: method.to_owned(),
+        params: Value::Null,
+    };
+    let mut line = serde_json::to_vec(&request).unwrap();
+    line.push(b'\n');
+    stream.write_all(&line).map_err(ControlError::Io)?;
+
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .map_err(ControlError::Io)?;
+    let response: Response =
+        serde_json::from_str(&response).map_err(ControlError::InvalidResponse)?;
+
+    match response.error {
+        Some(err) => Err(ControlError::Rpc(err)),
+        None => Ok(response.result.unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        call, serve, Control, ControlError, Request, Response, INVALID_REQUEST, MAX_CONNECTIONS,
+        MAX_REQUEST_LENGTH, NOT_APPLYING, TOO_MANY_CONNECTIONS,
+    };
+    use crate::agent::AgentStatus;
+    use crate::testing::TempDir;
+    use serde_json::Value;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    pub fn serialize_deserialize_request() {
+        let json = r#"{"jsonrpc":"2.0","id":7,"method":"status"}"#;
+        let request: Request = serde_json::from_str(json).unwrap();
+
+        assert_eq!(request.method, "status");
+        assert_eq!(request.params, Value::Null);
+        assert_eq!(serde_json::to_string(&request).unwrap(), json);
+    }
+
+    #[test]
+    pub fn control_the_agent() {
+        let dir = TempDir::new("control");
+        let path = dir.join("control.sock");
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let control = Arc::new(Control::new(
+            AgentStatus {
+                applied_install: Some(3),
+                ..Default::default()
+            },
+            cancelled.clone(),
+        ));
+        serve(&path, control.clone()).unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o660
+        );
+
+        // A client that keeps its connection open does not block other clients
+        let _idle = UnixStream::connect(&path).unwrap();
+        let status = call(&path, "status").unwrap();
+        assert_eq!(status["applied_install"], Value::from(3));
+        assert_eq!(status["applying"], Value::Bool(false));
+
+        assert!(matches!(
+            call(&path, "cancel"),
+            Err(ControlError::Rpc(err)) if err.code == NOT_APPLYING
+        ));
+        control.applying.store(true, Ordering::SeqCst);
+        call(&path, "cancel").unwrap();
+        assert!(cancelled.load(Ordering::SeqCst));
+
+        assert!(!control.has_requests());
+        call(&path, "trigger-build").unwrap();
+        call(&path, "trigger-verify").unwrap();
+        assert!(control.take_build_request());
+        assert!(!control.take_build_request());
+        assert!(control.take_verify_request());
+
+        assert!(matches!(call(&path, "reboot"), Err(ControlError::Rpc(_))));
+    }
+
+    fn read_error(stream: UnixStream) -> i64 {
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        let response: Response = serde_json::from_str(&line).unwrap();
+        response.error.unwrap().code
+    }
+
+    #[test]
+    pub fn limit_connections_and_requests() {
+        let dir = TempDir::new("control-limits");
+        let path = dir.join("control.sock");
+        let control = Arc::new(Control::new(
+            AgentStatus::default(),
+            Arc::new(AtomicBool::new(false)),
+        ));
+        serve(&path, control).unwrap();
+        assert!(!dir.join("control.sock.bind").exists());
+
+        let mut long = UnixStream::connect(&path).unwrap();
+        long.write_all(&vec![b' '; MAX_REQUEST_LENGTH + 1]).unwrap();
+        assert_eq!(read_error(long), INVALID_REQUEST);
+
+        let idle = (0..MAX_CONNECTIONS)
+            .map(|_| UnixStream::connect(&path).unwrap())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            call(&path, "status"),
+            Err(ControlError::Rpc(err)) if err.code == TOO_MANY_CONNECTIONS
+        ));
+
+        // The connections are released once their handlers notice that they have been closed
+        drop(idle);
+        let released = (0..100).any(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            call(&path, "status").is_ok()
+        });
+        assert!(released);
+    }
+}
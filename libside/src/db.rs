@@ -0,0 +1,364 @@
+//! The encoding of the database of an install.
+//!
+//! Databases are JSON by default. Large graphs can be stored as CBOR instead, which is smaller and faster to parse.
+//! Binary databases start with [`MAGIC`], followed by the version of the envelope, the format of the contents and the schema version.
+//! JSON databases are an object with the schema version and the graph.
+//! The format is detected when a database is read, so installs with different formats can be mixed.
+//!
+//! The schema version is the number of [`Migrations`] that the builder has registered when the database is written.
+//! Databases with an older schema version are migrated when they are read, one migration at a time.
+//! Databases that were written before schema versions were introduced have schema version 0.
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The first bytes of a binary database. A JSON database always starts with `{`.
+pub const MAGIC: &[u8] = b"SIDEDB";
+
+/// The version of the envelope around binary databases.
+/// Version 1 had no schema version, and is read as schema version 0.
+const ENVELOPE_VERSION: u8 = 2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DbFormat {
+    #[default]
+    Json,
+    Cbor,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DbError {
+    #[error("invalid JSON: {}", .0)]
+    Json(serde_json::Error),
+
+    #[error("invalid CBOR: {}", .0)]
+    Cbor(String),
+
+    #[error("the database is truncated")]
+    Truncated,
+
+    #[error("unsupported database envelope version {}", .0)]
+    UnsupportedVersion(u8),
+
+    #[error("unknown database format {}", .0)]
+    UnknownFormat(u8),
+
+    #[error("the database has schema version {}, but this version only supports up to {}; it was written by a newer version", .0, .1)]
+    NewerSchema(u32, u32),
+
+    #[error("unable to migrate the database from schema version {}: {}", .0, .1)]
+    MigrationFailed(u32, String),
+}
+
+/// Converts the requirements of a database from one schema version to the next.
+pub trait Migration: Send + Sync {
+    /// Converts a requirement of kind `kind` (see [`crate::requirements::Requirement::NAME`]), serialized as JSON.
+    /// The kind can be changed as well, for requirements that were renamed. Requirements that the migration does not change must be left alone.
+    fn migrate(&self, kind: &mut String, requirement: &mut Value) -> Result<(), String>;
+}
+
+impl<F: Fn(&mut String, &mut Value) -> Result<(), String> + Send + Sync> Migration for F {
+    fn migrate(&self, kind: &mut String, requirement: &mut Value) -> Result<(), String> {
+        self(kind, requirement)
+    }
+}
+
+/// The migrations of a builder, in the order of the schema versions they upgrade from.
+/// Migrations must never be removed or reordered, because the schema version is the number of registered migrations.
+#[derive(Clone, Default)]
+pub struct Migrations {
+    steps: Vec<Arc<dyn Migration>>,
+}
+
+impl Migrations {
+    pub fn new() -> Migrations {
+        Migrations::default()
+    }
+
+    /// Registers the migration from the current schema version to the next one.
+    pub fn register(mut self, migration: impl Migration + 'static) -> Migrations {
+        self.steps.push(Arc::new(migration));
+        self
+    }
+
+    /// The schema version that databases are written with.
+    pub fn version(&self) -> u32 {
+        self.steps.len() as u32
+    }
+
+    /// Migrates the requirements of `graph` from schema version `from` to the current schema version.
+    pub fn migrate(&self, mut graph: Value, from: u32) -> Result<Value, DbError> {
+        if from > self.version() {
+            return Err(DbError::NewerSchema(from, self.version()));
+        }
+
+        for (version, migration) in self.steps.iter().enumerate().skip(from as usize) {
+            let failed = |e: &str| DbError::MigrationFailed(version as u32, e.to_owned());
+            let nodes = graph
+                .get_mut("nodes")
+                .and_then(Value::as_array_mut)
+                .ok_or_else(|| failed("the database has no nodes"))?;
+            for node in nodes.iter_mut() {
+                let requirement = node
+                    .get_mut("requirement")
+                    .and_then(Value::as_object_mut)
+                    .filter(|requirement| requirement.len() == 1)
+                    .ok_or_else(|| failed("a node has no requirement"))?;
+                let (mut kind, mut value) = std::mem::take(requirement).into_iter().next().unwrap();
+                migration
+                    .migrate(&mut kind, &mut value)
+                    .map_err(|e| failed(&e))?;
+                requirement.insert(kind, value);
+            }
+        }
+
+        Ok(graph)
+    }
+}
+
+impl Debug for Migrations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Migrations(version {})", self.version())
+    }
+}
+
+/// Migrations are compared by the schema version they result in.
+impl PartialEq for Migrations {
+    fn eq(&self, other: &Self) -> bool {
+        self.version() == other.version()
+    }
+}
+
+impl Eq for Migrations {}
+
+/// The contents of a JSON database.
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    schema: u32,
+    graph: T,
+}
+
+impl DbFormat {
+    fn tag(&self) -> Option<u8> {
+        match self {
+            DbFormat::Json => None,
+            DbFormat::Cbor => Some(1),
+        }
+    }
+
+    /// Determines the format of an encoded database.
+    pub fn detect(contents: &[u8]) -> Result<DbFormat, DbError> {
+        let header = match contents.strip_prefix(MAGIC) {
+            Some(header) => header,
+            None => return Ok(DbFormat::Json),
+        };
+
+        match header {
+            [1 | ENVELOPE_VERSION, 1, ..] => Ok(DbFormat::Cbor),
+            [1 | ENVELOPE_VERSION, format, ..] => Err(DbError::UnknownFormat(*format)),
+            [version, _, ..] => Err(DbError::UnsupportedVersion(*version)),
+            _ => Err(DbError::Truncated),
+        }
+    }
+}
+
+/// Returns the schema version and the contents of a binary database, after the envelope.
+fn unwrap_envelope(contents: &[u8]) -> Result<(u32, &[u8]), DbError> {
+    let header = &contents[MAGIC.len()..];
+    match header {
+        [1, _, contents @ ..] => Ok((0, contents)),
+        [ENVELOPE_VERSION, _, a, b, c, d, contents @ ..] => {
+            Ok((u32::from_be_bytes([*a, *b, *c, *d]), contents))
+        }
+        _ => Err(DbError::Truncated),
+    }
+}
+
+impl Display for DbFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            DbFormat::Json => "json",
+            DbFormat::Cbor => "cbor",
+        })
+    }
+}
+
+impl FromStr for DbFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(DbFormat::Json),
+            "cbor" => Ok(DbFormat::Cbor),
+            other => Err(format!(
+                "invalid database format {:?}, expected 'json' or 'cbor'",
+                other
+            )),
+        }
+    }
+}
+
+/// Encodes `value` in `format` with schema version `schema`, including the envelope for binary formats.
+pub fn encode<T: Serialize>(value: &T, format: DbFormat, schema: u32) -> Result<Vec<u8>, DbError> {
+    match format.tag() {
+        None => serde_json::to_vec(&Versioned {
+            schema,
+            graph: value,
+        })
+        .map_err(DbError::Json),
+        Some(tag) => {
+            let mut contents = MAGIC.to_vec();
+            contents.extend([ENVELOPE_VERSION, tag]);
+            contents.extend(schema.to_be_bytes());
+            ciborium::ser::into_writer(value, &mut contents)
+                .map_err(|e| DbError::Cbor(e.to_string()))?;
+
+            Ok(contents)
+        }
+    }
+}
+
+/// Decodes a database in any format, and migrates it from its schema version with `migrations`.
+pub fn decode<T: DeserializeOwned>(contents: &[u8], migrations: &Migrations) -> Result<T, DbError> {
+    let (schema, graph) = match DbFormat::detect(contents)? {
+        DbFormat::Json => match serde_json::from_slice(contents).map_err(DbError::Json)? {
+            Value::Object(db) if db.contains_key("schema") => {
+                let db: Versioned<Value> =
+                    serde_json::from_value(Value::Object(db)).map_err(DbError::Json)?;
+                (db.schema, db.graph)
+            }
+            graph => (0, graph),
+        },
+        DbFormat::Cbor => {
+            let (schema, contents) = unwrap_envelope(contents)?;
+            if schema == migrations.version() {
+                return ciborium::de::from_reader(contents)
+                    .map_err(|e| DbError::Cbor(e.to_string()));
+            }
+
+            let graph =
+                ciborium::de::from_reader(contents).map_err(|e| DbError::Cbor(e.to_string()))?;
+            (schema, graph)
+        }
+    };
+
+    let graph = migrations.migrate(graph, schema)?;
+    serde_json::from_value(graph).map_err(DbError::Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, DbError, DbFormat, Migrations, MAGIC};
+    use crate::builder::fs::{CreateDirectory, FileWithContents, Sha3};
+    use crate::graph::{Applied, Graph, Pending};
+    use serde_json::{json, Value};
+    use std::path::PathBuf;
+
+    crate::requirements!(R = CreateDirectory, FileWithContents);
+
+    #[test]
+    pub fn encode_decode_graph() {
+        let mut graph = Graph::<R, Pending>::new();
+        let dir = graph.add(CreateDirectory::new(PathBuf::from("/etc/side")), &[]);
+        graph.add(
+            FileWithContents::new(
+                PathBuf::from("/srv/files/config/a.conf"),
+                PathBuf::from("/etc/side/a.conf"),
+                Sha3::hash(b"a = 1\n"),
+            ),
+            &[dir],
+        );
+        let migrations = Migrations::new();
+        let json = encode(&graph, DbFormat::Json, 0).unwrap();
+        let graph: Graph<R, Applied> = decode(&json, &migrations).unwrap();
+
+        let cbor = encode(&graph, DbFormat::Cbor, 0).unwrap();
+        assert_eq!(DbFormat::detect(&json).unwrap(), DbFormat::Json);
+        assert_eq!(DbFormat::detect(&cbor).unwrap(), DbFormat::Cbor);
+        assert!(cbor.len() < json.len());
+        assert_eq!(
+            decode::<Graph<R, Applied>>(&cbor, &migrations).unwrap(),
+            graph
+        );
+    }
+
+    /// A migration for databases in which directories had a `dir` instead of a `path`.
+    fn rename_dir(kind: &mut String, requirement: &mut Value) -> Result<(), String> {
+        if kind == "directory" {
+            let requirement = requirement.as_object_mut().unwrap();
+            let dir = requirement.remove("dir").ok_or("directory without a dir")?;
+            requirement.insert(String::from("path"), dir);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn migrate_old_schemas() {
+        let old = json!({
+            "nodes": [{
+                "requirement": { "directory": { "dir": "/etc/side", "needs_cleanup": true } },
+                "preconditions": [],
+                "pre_existing": false,
+            }],
+            "state": null,
+        });
+        let expected = json!({
+            "nodes": [{
+                "requirement": { "directory": { "path": "/etc/side", "needs_cleanup": true } },
+                "preconditions": [],
+                "pre_existing": false,
+            }],
+            "state": null,
+        });
+        let migrations = Migrations::new().register(rename_dir);
+
+        // Written before schema versions were introduced
+        let unversioned = serde_json::to_vec(&old).unwrap();
+        let graph: Value = decode(&unversioned, &migrations).unwrap();
+        assert_eq!(graph, expected);
+        assert!(decode::<Graph<R, Applied>>(&unversioned, &migrations).is_ok());
+
+        // A binary database with the first envelope version
+        let mut cbor = MAGIC.to_vec();
+        cbor.extend([1, 1]);
+        ciborium::ser::into_writer(&old, &mut cbor).unwrap();
+        let graph: Value = decode(&cbor, &migrations).unwrap();
+        assert_eq!(graph, expected);
+
+        for format in [DbFormat::Json, DbFormat::Cbor] {
+            let current = encode(&expected, format, 1).unwrap();
+            assert_eq!(decode::<Value>(&current, &migrations).unwrap(), expected);
+            assert!(matches!(
+                decode::<Value>(&current, &Migrations::new()),
+                Err(DbError::NewerSchema(1, 0))
+            ));
+        }
+
+        let broken = encode(&expected, DbFormat::Json, 0).unwrap();
+        assert!(matches!(
+            decode::<Value>(&broken, &migrations),
+            Err(DbError::MigrationFailed(0, _))
+        ));
+    }
+
+    #[test]
+    pub fn reject_unknown_envelopes() {
+        let mut future = MAGIC.to_vec();
+        future.extend([3, 1]);
+        let mut unknown = MAGIC.to_vec();
+        unknown.extend([1, 9]);
+
+        assert!(matches!(
+            DbFormat::detect(&future),
+            Err(DbError::UnsupportedVersion(3))
+        ));
+        assert!(matches!(
+            DbFormat::detect(&unknown),
+            Err(DbError::UnknownFormat(9))
+        ));
+        assert!(matches!(DbFormat::detect(MAGIC), Err(DbError::Truncated)));
+    }
+}
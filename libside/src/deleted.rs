@@ -0,0 +1,241 @@
+//! The system files that installs delete with `Context::delete_default_system_file`, for `side deleted`.
+//!
+//! Every install saves the files it deletes to its own originals directory, see `Dirs::originals_path`, and records them in its install directory.
+//! A file that is deleted by several installs therefore has a backup for each of them, which can be listed and restored by hand.
+use crate::system::System;
+use crate::Dirs;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+/// A system file that was deleted, and the copy that was saved before it was deleted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeletedFile {
+    pub path: PathBuf,
+    pub backup: PathBuf,
+}
+
+/// The files that an install deletes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DeletedFiles {
+    files: Vec<DeletedFile>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeletedError<S: System> {
+    #[error("unable to read deleted files from {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("invalid deleted files in {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, serde_json::Error),
+
+    #[error("unable to serialize deleted files: {}", .0)]
+    UnableToSerialize(serde_json::Error),
+
+    #[error("unable to write deleted files to {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("unable to list the installs: {}", .0)]
+    UnableToListInstalls(S::Error),
+
+    #[error("there is no backup of {}", .0.display())]
+    NoBackup(PathBuf),
+
+    #[error("{} exists, use --force to overwrite it", .0.display())]
+    AlreadyExists(PathBuf),
+
+    #[error("unable to restore {} from {}: {}", .0.display(), .1.display(), .2)]
+    UnableToRestore(PathBuf, PathBuf, S::Error),
+}
+
+impl DeletedFiles {
+    pub fn push(&mut self, file: DeletedFile) {
+        self.files.push(file);
+    }
+
+    pub fn extend(&mut self, other: DeletedFiles) {
+        self.files.extend(other.files);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DeletedFile> {
+        self.files.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn find(&self, path: &Path) -> Option<&DeletedFile> {
+        self.files.iter().find(|file| file.path == path)
+    }
+
+    /// Loads the deleted files from `path`. Installs that were created before deleted files were recorded have none.
+    pub fn load<S: System>(path: &Path, system: &S) -> Result<DeletedFiles, DeletedError<S>> {
+        if !system
+            .path_exists(path)
+            .map_err(|e| DeletedError::UnableToRead(path.to_owned(), e))?
+        {
+            return Ok(DeletedFiles::default());
+        }
+
+        let contents = system
+            .file_contents(path)
+            .map_err(|e| DeletedError::UnableToRead(path.to_owned(), e))?;
+        serde_json::from_slice(&contents).map_err(|e| DeletedError::Invalid(path.to_owned(), e))
+    }
+
+    pub fn save<S: System>(&self, path: &Path, system: &S) -> Result<(), DeletedError<S>> {
+        let contents =
+            serde_json::to_string_pretty(self).map_err(DeletedError::UnableToSerialize)?;
+        system
+            .put_file_contents(path, contents.as_bytes())
+            .map_err(|e| DeletedError::UnableToWrite(path.to_owned(), e))
+    }
+}
+
+/// A deleted file of a specific install, and whether its backup still exists.
+/// Backups are removed when the install that deleted the file is undone, because the file is restored at that point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    pub install: u64,
+    pub file: DeletedFile,
+    pub exists: bool,
+}
+
+impl Display for Backup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.install, self.file.path.display())?;
+        if self.exists {
+            write!(f, " (backup: {})", self.file.backup.display())
+        } else {
+            write!(f, " (backup removed)")
+        }
+    }
+}
+
+/// The deleted files of all installs, newest install first.
+pub fn list<S: System>(dirs: &Dirs, system: &mut S) -> Result<Vec<Backup>, DeletedError<S>> {
+    let mut installs = dirs
+        .installed_versions(system)
+        .map_err(DeletedError::UnableToListInstalls)?;
+    installs.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut result = Vec::new();
+    for install in installs {
+        let files = dirs.get_install(install).load_deleted(system)?;
+        for file in files.files {
+            let exists = system
+                .path_exists(&file.backup)
+                .map_err(|e| DeletedError::UnableToRead(file.backup.clone(), e))?;
+            result.push(Backup {
+                install,
+                file,
+                exists,
+            });
+        }
+    }
+
+    Ok(result)
+}
+
+/// Copies the newest backup of `path` back to `path`, or the backup of `install` if it is specified.
+/// The backup is kept, so that the install that deleted the file can still be undone.
+pub fn restore<S: System>(
+    dirs: &Dirs,
+    system: &mut S,
+    path: &Path,
+    install: Option<u64>,
+    force: bool,
+) -> Result<Backup, DeletedError<S>> {
+    let backup = list(dirs, system)?
+        .into_iter()
+        .find(|backup| {
+            backup.exists
+                && backup.file.path == path
+                && install
+                    .map(|install| install == backup.install)
+                    .unwrap_or(true)
+        })
+        .ok_or_else(|| DeletedError::NoBackup(path.to_owned()))?;
+
+    if !force
+        && system
+            .path_exists(path)
+            .map_err(|e| DeletedError::UnableToRead(path.to_owned(), e))?
+    {
+        return Err(DeletedError::AlreadyExists(path.to_owned()));
+    }
+
+    system.copy_file(&backup.file.backup, path).map_err(|e| {
+        DeletedError::UnableToRestore(path.to_owned(), backup.file.backup.clone(), e)
+    })?;
+
+    Ok(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list, restore, DeletedError, DeletedFile, DeletedFiles};
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use crate::Dirs;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn serialize_deserialize_deleted_files() {
+        let mut files = DeletedFiles::default();
+        files.push(DeletedFile {
+            path: PathBuf::from("/etc/nginx/sites-enabled/default"),
+            backup: PathBuf::from("/srv/backups/_originals/3/etc/nginx/sites-enabled/default"),
+        });
+        let json = r#"[{"path":"/etc/nginx/sites-enabled/default","backup":"/srv/backups/_originals/3/etc/nginx/sites-enabled/default"}]"#;
+
+        assert_eq!(serde_json::to_string(&files).unwrap(), json);
+        assert_eq!(files, serde_json::from_str(json).unwrap());
+    }
+
+    #[test]
+    pub fn restore_newest_backup() {
+        let base = TempDir::new("deleted");
+        let dirs = Dirs::new(&base);
+        let target = base.join("default");
+        let mut system = LocalSystem::new();
+
+        for (install, contents) in [(1, "first"), (2, "second"), (3, "removed")] {
+            let backup = dirs.originals_path(install).join("default");
+            let install = dirs.get_install(install);
+            std::fs::create_dir_all(backup.parent().unwrap()).unwrap();
+            std::fs::create_dir_all(install.db().parent().unwrap()).unwrap();
+            if contents != "removed" {
+                std::fs::write(&backup, contents).unwrap();
+            }
+
+            let mut files = DeletedFiles::default();
+            files.push(DeletedFile {
+                path: target.clone(),
+                backup,
+            });
+            install.write_deleted(&system, &files).unwrap();
+        }
+
+        let backups = list(&dirs, &mut system).unwrap();
+        let restored = restore(&dirs, &mut system, &target, None, false).unwrap();
+        let contents = std::fs::read_to_string(&target).unwrap();
+        let exists = restore(&dirs, &mut system, &target, Some(1), false);
+        restore(&dirs, &mut system, &target, Some(1), true).unwrap();
+        let forced = std::fs::read_to_string(&target).unwrap();
+
+        assert_eq!(
+            backups
+                .iter()
+                .map(|b| (b.install, b.exists))
+                .collect::<Vec<_>>(),
+            vec![(3, false), (2, true), (1, true)]
+        );
+        assert_eq!(restored.install, 2);
+        assert_eq!(contents, "second");
+        assert!(matches!(exists, Err(DeletedError::AlreadyExists(_))));
+        assert_eq!(forced, "first");
+    }
+}
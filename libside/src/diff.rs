@@ -0,0 +1,268 @@
+//! Comparing the requirements of two installs, for `side diff`.
+//!
+//! A requirement of the newer install that is not in the older install is modified if a requirement of the older install [affects](Requirement::affects) it, and added otherwise.
+//! The requirements of the older install that are neither in the newer install nor replaced by a modified requirement are removed.
+use crate::graph::Graph;
+use crate::requirements::Requirement;
+use crate::review::diff_lines;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt::Display;
+
+/// A requirement that was added or removed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DiffEntry {
+    pub kind: &'static str,
+    pub package: Option<String>,
+    pub requirement: String,
+    pub value: Value,
+}
+
+/// A requirement that replaces a requirement of the older install with different parameters.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Modification {
+    pub kind: &'static str,
+    pub package: Option<String>,
+    pub requirement: String,
+    pub value: Value,
+    pub previous_value: Value,
+}
+
+impl Modification {
+    /// The lines of the requirement that changed, prefixed by `- ` or `+ `.
+    pub fn changed_lines(&self) -> Vec<String> {
+        diff_lines(
+            &serde_json::to_string_pretty(&self.previous_value).unwrap(),
+            &serde_json::to_string_pretty(&self.value).unwrap(),
+        )
+        .into_iter()
+        .filter(|line| !line.starts_with("  "))
+        .collect()
+    }
+}
+
+/// The differences between the requirements of install `from` and install `to`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct InstallDiff {
+    pub from: u64,
+    pub to: u64,
+    pub added: Vec<DiffEntry>,
+    pub removed: Vec<DiffEntry>,
+    pub modified: Vec<Modification>,
+    pub unchanged: usize,
+}
+
+impl InstallDiff {
+    /// Compares `old`, the graph of install `from`, with `new`, the graph of install `to`.
+    pub fn compare<R: Requirement, A: Default + Copy, B: Default + Copy>(
+        from: u64,
+        old: &Graph<R, A>,
+        to: u64,
+        new: &Graph<R, B>,
+    ) -> InstallDiff {
+        let old_values = old
+            .requirements()
+            .map(|r| serde_json::to_value(r).unwrap())
+            .collect::<Vec<_>>();
+        let new_values = new
+            .requirements()
+            .map(|r| serde_json::to_value(r).unwrap())
+            .collect::<Vec<_>>();
+        let old_set = old_values
+            .iter()
+            .map(Value::to_string)
+            .collect::<HashSet<_>>();
+        let new_set = new_values
+            .iter()
+            .map(Value::to_string)
+            .collect::<HashSet<_>>();
+        let old_requirements = old.requirements().collect::<Vec<_>>();
+
+        let mut diff = InstallDiff {
+            from,
+            to,
+            ..Default::default()
+        };
+
+        // The requirements of the older install that are replaced by a modified requirement
+        let mut replaced = vec![false; old_requirements.len()];
+        for ((requirement, package), value) in
+            new.requirements().zip(new.packages()).zip(new_values)
+        {
+            if old_set.contains(&value.to_string()) {
+                diff.unchanged += 1;
+                continue;
+            }
+
+            let previous = old_requirements
+                .iter()
+                .enumerate()
+                .position(|(index, old)| {
+                    !replaced[index]
+                        && !new_set.contains(&old_values[index].to_string())
+                        && old.affects(requirement)
+                });
+            match previous {
+                Some(index) => {
+                    replaced[index] = true;
+                    diff.modified.push(Modification {
+                        kind: requirement.kind(),
+                        package: package.map(str::to_owned),
+                        requirement: requirement.to_string(),
+                        value,
+                        previous_value: old_values[index].clone(),
+                    });
+                }
+                None => diff.added.push(DiffEntry {
+                    kind: requirement.kind(),
+                    package: package.map(str::to_owned),
+                    requirement: requirement.to_string(),
+                    value,
+                }),
+            }
+        }
+
+        for (index, (requirement, package)) in old.requirements().zip(old.packages()).enumerate() {
+            if !replaced[index] && !new_set.contains(&old_values[index].to_string()) {
+                diff.removed.push(DiffEntry {
+                    kind: requirement.kind(),
+                    package: package.map(str::to_owned),
+                    requirement: requirement.to_string(),
+                    value: old_values[index].clone(),
+                });
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+fn package_prefix(package: &Option<String>) -> String {
+    match package {
+        Some(package) => format!("[{}] ", package),
+        None => String::new(),
+    }
+}
+
+impl Display for InstallDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Install {} -> {}: {} added, {} removed, {} modified, {} unchanged",
+            self.from,
+            self.to,
+            self.added.len(),
+            self.removed.len(),
+            self.modified.len(),
+            self.unchanged
+        )?;
+
+        if !self.added.is_empty() {
+            writeln!(f, "Added:")?;
+            for entry in self.added.iter() {
+                writeln!(
+                    f,
+                    "  {}{}",
+                    package_prefix(&entry.package),
+                    entry.requirement
+                )?;
+            }
+        }
+
+        if !self.removed.is_empty() {
+            writeln!(f, "Removed:")?;
+            for entry in self.removed.iter() {
+                writeln!(
+                    f,
+                    "  {}{}",
+                    package_prefix(&entry.package),
+                    entry.requirement
+                )?;
+            }
+        }
+
+        if !self.modified.is_empty() {
+            writeln!(f, "Modified:")?;
+            for modification in self.modified.iter() {
+                writeln!(
+                    f,
+                    "  {}{}",
+                    package_prefix(&modification.package),
+                    modification.requirement
+                )?;
+                for line in modification.changed_lines() {
+                    writeln!(f, "    {}", line)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InstallDiff;
+    use crate::builder::fs::{CreateDirectory, FileWithContents, Sha3};
+    use crate::graph::{Graph, Pending};
+    use std::path::PathBuf;
+
+    crate::requirements!(R = CreateDirectory, FileWithContents);
+
+    fn file(to: &str, contents: &[u8]) -> FileWithContents {
+        FileWithContents::new(
+            PathBuf::from("/srv/files/config").join(to.trim_start_matches('/')),
+            PathBuf::from(to),
+            Sha3::hash(contents),
+        )
+    }
+
+    #[test]
+    pub fn compare_installs() {
+        let mut old = Graph::<R, Pending>::new();
+        let dir = old.add(CreateDirectory::new(PathBuf::from("/etc/app")), &[]);
+        old.add(file("/etc/app/a.conf", b"a = 1\n"), &[dir]);
+        old.add(file("/etc/app/b.conf", b"b = 1\n"), &[dir]);
+
+        let mut new = Graph::<R, Pending>::new();
+        let dir = new.add(CreateDirectory::new(PathBuf::from("/etc/app")), &[]);
+        new.add(file("/etc/app/a.conf", b"a = 2\n"), &[dir]);
+        new.add(file("/etc/app/c.conf", b"c = 1\n"), &[dir]);
+
+        let diff = InstallDiff::compare(1, &old, 2, &new);
+        assert_eq!(diff.unchanged, 1);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(
+            diff.added[0].value["file_with_contents"]["to"],
+            "/etc/app/c.conf"
+        );
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(
+            diff.removed[0].value["file_with_contents"]["to"],
+            "/etc/app/b.conf"
+        );
+        assert_eq!(diff.modified.len(), 1);
+        assert_eq!(
+            diff.modified[0].value["file_with_contents"]["to"],
+            "/etc/app/a.conf"
+        );
+        assert_eq!(
+            diff.modified[0].previous_value["file_with_contents"]["to"],
+            "/etc/app/a.conf"
+        );
+        assert!(diff.modified[0]
+            .changed_lines()
+            .iter()
+            .all(|line| line.starts_with("- ") || line.starts_with("+ ")));
+
+        let text = diff.to_string();
+        assert!(text.starts_with("Install 1 -> 2: 1 added, 1 removed, 1 modified, 1 unchanged\n"));
+
+        assert!(InstallDiff::compare(2, &new, 2, &new).is_empty());
+    }
+}
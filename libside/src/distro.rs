@@ -0,0 +1,363 @@
+//! The Linux distributions that builders can target.
+//!
+//! The distribution of a system is detected from `/etc/os-release` when a build starts, and is available to builders with `Context::distro`.
+//! It decides which package manager, service manager and tool paths requirements use, so that the same builder can target more than one distribution.
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::Path;
+
+/// The file that identifies the distribution, see `os-release(5)`.
+pub const OS_RELEASE: &str = "/etc/os-release";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Distro {
+    Debian,
+    Ubuntu,
+    Alpine,
+}
+
+/// Systems without `/etc/os-release` are assumed to run Ubuntu, which is what `side` has always targeted.
+impl Default for Distro {
+    fn default() -> Self {
+        Distro::Ubuntu
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageManager {
+    Apt,
+    Apk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceManager {
+    Systemd,
+    OpenRc,
+
+    /// SysV init scripts, managed with `service` and `update-rc.d`, such as in minimal Debian containers.
+    SysV,
+}
+
+/// Services are managed with systemd unless another service manager is detected.
+impl Default for ServiceManager {
+    fn default() -> Self {
+        ServiceManager::Systemd
+    }
+}
+
+impl ServiceManager {
+    /// Detects the service manager of `system`, which runs `distro`.
+    /// A system that has systemd installed uses it even if it is not running, such as a chroot or an image that is being built.
+    /// Systems without systemd or OpenRC fall back to SysV init scripts if they have `/etc/init.d`, and to the service manager of the distribution otherwise.
+    pub fn detect<S: System>(system: &S, distro: Distro) -> Result<ServiceManager, S::Error> {
+        let exists = |path: &str| system.path_exists(Path::new(path));
+        Ok(if exists("/run/systemd/system")? {
+            ServiceManager::Systemd
+        } else if exists("/sbin/openrc-run")? || exists("/usr/sbin/openrc-run")? {
+            ServiceManager::OpenRc
+        } else if exists("/bin/systemctl")? || exists("/usr/bin/systemctl")? {
+            ServiceManager::Systemd
+        } else if exists("/etc/init.d")? {
+            ServiceManager::SysV
+        } else {
+            distro.service_manager()
+        })
+    }
+
+    pub fn is_systemd(&self) -> bool {
+        *self == ServiceManager::Systemd
+    }
+}
+
+/// The paths of the tools that differ between distributions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tools {
+    /// A POSIX shell. Alpine does not install bash by default.
+    pub shell: &'static str,
+
+    /// A shell that refuses logins, for system users.
+    pub nologin: &'static str,
+}
+
+impl Distro {
+    /// Parses the contents of `/etc/os-release`.
+    /// Derivatives that are not supported themselves are recognized by their `ID_LIKE`, so that for example Raspbian is treated as Debian.
+    pub fn from_os_release(contents: &str) -> Option<Distro> {
+        let mut id = None;
+        let mut like = None;
+        for (key, value) in os_release_fields(contents) {
+            match key {
+                "ID" => id = Some(value.to_owned()),
+                "ID_LIKE" => like = Some(value.to_owned()),
+                _ => (),
+            }
+        }
+
+        std::iter::once(id.as_deref())
+            .flatten()
+            .chain(like.as_deref().into_iter().flat_map(str::split_whitespace))
+            .find_map(Distro::from_id)
+    }
+
+    fn from_id(id: &str) -> Option<Distro> {
+        match id {
+            "debian" => Some(Distro::Debian),
+            "ubuntu" => Some(Distro::Ubuntu),
+            "alpine" => Some(Distro::Alpine),
+            _ => None,
+        }
+    }
+
+    /// Detects the distribution of `system`.
+    /// Returns `None` if the system has an `/etc/os-release` of a distribution that is not supported.
+    pub fn detect<S: System>(system: &S) -> Result<Option<Distro>, S::Error> {
+        let path = Path::new(OS_RELEASE);
+        if !system.path_exists(path)? {
+            return Ok(Some(Distro::default()));
+        }
+
+        let contents = system.file_contents(path)?;
+        Ok(Distro::from_os_release(&String::from_utf8_lossy(&contents)))
+    }
+
+    pub fn package_manager(&self) -> PackageManager {
+        match self {
+            Distro::Debian | Distro::Ubuntu => PackageManager::Apt,
+            Distro::Alpine => PackageManager::Apk,
+        }
+    }
+
+    pub fn service_manager(&self) -> ServiceManager {
+        match self {
+            Distro::Debian | Distro::Ubuntu => ServiceManager::Systemd,
+            Distro::Alpine => ServiceManager::OpenRc,
+        }
+    }
+
+    /// The command and arguments that upgrade the system to the next release of the distribution without asking questions.
+    /// Debian and Alpine have no tool that finds the next release, so their package sources must already point to it.
+    pub fn release_upgrade_command(&self) -> (&'static str, Vec<&'static str>) {
+        match self {
+            Distro::Ubuntu => (
+                "do-release-upgrade",
+                vec!["-f", "DistUpgradeViewNonInteractive"],
+            ),
+            Distro::Debian => (
+                "env",
+                vec![
+                    "DEBIAN_FRONTEND=noninteractive",
+                    "apt-get",
+                    "full-upgrade",
+                    "-y",
+                    "-q",
+                ],
+            ),
+            Distro::Alpine => ("apk", vec!["upgrade", "--no-progress", "--available"]),
+        }
+    }
+
+    pub fn tools(&self) -> Tools {
+        match self {
+            Distro::Debian | Distro::Ubuntu => Tools {
+                shell: "/bin/bash",
+                nologin: "/usr/sbin/nologin",
+            },
+            Distro::Alpine => Tools {
+                shell: "/bin/sh",
+                nologin: "/sbin/nologin",
+            },
+        }
+    }
+}
+
+/// The release of the distribution that a system runs, such as Ubuntu 22.04.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OsVersion {
+    pub id: String,
+
+    /// Empty for rolling releases, which have no `VERSION_ID`.
+    pub version_id: String,
+}
+
+impl OsVersion {
+    /// Parses the contents of `/etc/os-release`. Returns `None` if there is no `ID`.
+    pub fn from_os_release(contents: &str) -> Option<OsVersion> {
+        let mut id = None;
+        let mut version_id = String::new();
+        for (key, value) in os_release_fields(contents) {
+            match key {
+                "ID" => id = Some(value.to_owned()),
+                "VERSION_ID" => version_id = value.to_owned(),
+                _ => (),
+            }
+        }
+
+        Some(OsVersion {
+            id: id?,
+            version_id,
+        })
+    }
+
+    /// Detects the release that `system` runs.
+    /// Returns `None` if the system has no `/etc/os-release`, or if it does not identify the distribution.
+    pub fn detect<S: System>(system: &S) -> Result<Option<OsVersion>, S::Error> {
+        let path = Path::new(OS_RELEASE);
+        if !system.path_exists(path)? {
+            return Ok(None);
+        }
+
+        let contents = system.file_contents(path)?;
+        Ok(OsVersion::from_os_release(&String::from_utf8_lossy(
+            &contents,
+        )))
+    }
+}
+
+impl Display for OsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.version_id.is_empty() {
+            write!(f, "{}", self.id)
+        } else {
+            write!(f, "{} {}", self.id, self.version_id)
+        }
+    }
+}
+
+/// The keys and unquoted values in the contents of `/etc/os-release`.
+fn os_release_fields(contents: &str) -> impl Iterator<Item = (&str, &str)> {
+    contents.lines().filter_map(|line| {
+        let (key, value) = line.trim().split_once('=')?;
+        Some((
+            key.trim(),
+            value.trim().trim_matches(|c| c == '"' || c == '\''),
+        ))
+    })
+}
+
+impl Display for Distro {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Distro::Debian => "debian",
+            Distro::Ubuntu => "ubuntu",
+            Distro::Alpine => "alpine",
+        })
+    }
+}
+
+impl PackageManager {
+    /// The command and arguments that install `package` without asking questions.
+    pub fn install_command<'a>(&self, package: &'a str) -> (&'static str, Vec<&'a str>) {
+        match self {
+            PackageManager::Apt => (
+                "apt-get",
+                vec!["install", "-y", "-q", "--no-install-recommends", package],
+            ),
+            PackageManager::Apk => (
+                "apk",
+                vec!["add", "--no-progress", "--update-cache", package],
+            ),
+        }
+    }
+
+    /// The command and arguments that remove `package`.
+    pub fn remove_command<'a>(&self, package: &'a str) -> (&'static str, Vec<&'a str>) {
+        match self {
+            PackageManager::Apt => ("apt-get", vec!["remove", "-y", "-q", package]),
+            PackageManager::Apk => ("apk", vec!["del", "--no-progress", package]),
+        }
+    }
+
+    /// The command and arguments that check whether `package` is installed.
+    /// The package is installed if the command succeeds and its output is accepted by [`PackageManager::is_installed`].
+    pub fn query_command<'a>(&self, package: &'a str) -> (&'static str, Vec<&'a str>) {
+        match self {
+            PackageManager::Apt => ("dpkg-query", vec!["-W", "-f=${Status}", package]),
+            PackageManager::Apk => ("apk", vec!["info", "-e", package]),
+        }
+    }
+
+    /// Interprets the output of a successful query command.
+    pub fn is_installed(&self, stdout: &str) -> bool {
+        match self {
+            PackageManager::Apt => stdout.starts_with("install"),
+            PackageManager::Apk => !stdout.trim().is_empty(),
+        }
+    }
+}
+
+impl Display for PackageManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            PackageManager::Apt => "apt",
+            PackageManager::Apk => "apk",
+        })
+    }
+}
+
+impl Display for ServiceManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            ServiceManager::Systemd => "systemd",
+            ServiceManager::OpenRc => "openrc",
+            ServiceManager::SysV => "sysv",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Distro, OsVersion, PackageManager, ServiceManager};
+
+    #[test]
+    pub fn detect_from_os_release() {
+        let ubuntu = "NAME=\"Ubuntu\"\nVERSION_ID=\"22.04\"\nID=ubuntu\nID_LIKE=debian\n";
+        let alpine = "NAME=\"Alpine Linux\"\nID=alpine\nVERSION_ID=3.18.4\n";
+        let raspbian = "ID=raspbian\nID_LIKE=debian\n";
+        let mint = "ID=linuxmint\nID_LIKE=\"ubuntu debian\"\n";
+        let fedora = "ID=fedora\nVERSION_ID=39\n";
+
+        assert_eq!(Distro::from_os_release(ubuntu), Some(Distro::Ubuntu));
+        assert_eq!(Distro::from_os_release(alpine), Some(Distro::Alpine));
+        assert_eq!(Distro::from_os_release(raspbian), Some(Distro::Debian));
+        assert_eq!(Distro::from_os_release(mint), Some(Distro::Ubuntu));
+        assert_eq!(Distro::from_os_release(fedora), None);
+    }
+
+    #[test]
+    pub fn os_version_from_os_release() {
+        let ubuntu = "NAME=\"Ubuntu\"\nVERSION_ID=\"22.04\"\nID=ubuntu\nID_LIKE=debian\n";
+        let arch = "NAME=\"Arch Linux\"\nID=arch\n";
+
+        let version = OsVersion::from_os_release(ubuntu).unwrap();
+        assert_eq!(version.id, "ubuntu");
+        assert_eq!(version.version_id, "22.04");
+        assert_eq!(version.to_string(), "ubuntu 22.04");
+        assert_eq!(
+            OsVersion::from_os_release(arch).unwrap().to_string(),
+            "arch"
+        );
+        assert_eq!(OsVersion::from_os_release("NAME=\"Unknown\"\n"), None);
+    }
+
+    #[test]
+    pub fn distro_managers() {
+        assert_eq!(Distro::Debian.package_manager(), PackageManager::Apt);
+        assert_eq!(Distro::Alpine.package_manager(), PackageManager::Apk);
+        assert_eq!(Distro::Ubuntu.service_manager(), ServiceManager::Systemd);
+        assert_eq!(Distro::Alpine.service_manager(), ServiceManager::OpenRc);
+        assert_eq!(
+            PackageManager::Apk.install_command("nginx"),
+            (
+                "apk",
+                vec!["add", "--no-progress", "--update-cache", "nginx"]
+            )
+        );
+        assert!(PackageManager::Apt.is_installed("install ok installed"));
+        assert!(!PackageManager::Apt.is_installed("deinstall ok config-files"));
+        assert!(PackageManager::Apk.is_installed("nginx\n"));
+    }
+}
@@ -0,0 +1,396 @@
+//! Diagnosing common problems with the environment, for `side doctor`.
+//!
+//! Every check produces a [`Finding`]. Checks never change the system, so `side doctor` is safe to run at any time.
+use crate::apply::SystemState;
+use crate::graph::{Applied, Graph};
+use crate::requirements::Requirement;
+use crate::system::System;
+use crate::Dirs;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The dpkg lock files that are held while apt or dpkg are running.
+const DPKG_LOCKS: [&str; 2] = ["/var/lib/dpkg/lock-frontend", "/var/lib/dpkg/lock"];
+
+/// The largest acceptable difference between the clocks of the system and the machine running `side`.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Clocks before this time (2020-01-01) have certainly not been set.
+const EARLIEST_PLAUSIBLE_TIME: Duration = Duration::from_secs(1_577_836_800);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// The result of a single check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DoctorReport {
+    findings: Vec<Finding>,
+}
+
+impl DoctorReport {
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    /// Returns true if any check found a problem that prevents builds or applies from succeeding.
+    pub fn has_errors(&self) -> bool {
+        self.findings.iter().any(|f| f.severity == Severity::Error)
+    }
+
+    fn add(&mut self, check: &'static str, severity: Severity, message: impl Into<String>) {
+        self.findings.push(Finding {
+            check,
+            severity,
+            message: message.into(),
+        });
+    }
+}
+
+impl Display for DoctorReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for finding in self.findings.iter() {
+            writeln!(
+                f,
+                "[{:7}] {}: {}",
+                finding.severity, finding.check, finding.message
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs all checks against `system`.
+pub fn diagnose<R: Requirement + DeserializeOwned, S: System>(
+    dirs: &Dirs,
+    system: &mut S,
+) -> DoctorReport {
+    let mut report = DoctorReport::default();
+    check_layout(dirs, system, &mut report);
+    let state = check_state::<R, S>(dirs, system, &mut report);
+    if let Some(state) = &state {
+        check_commands(&state.graph, system, &mut report);
+    }
+
+    check_dpkg_lock(system, &mut report);
+    check_clock(dirs, system, &mut report);
+
+    report
+}
+
+fn check_layout<S: System>(dirs: &Dirs, system: &mut S, report: &mut DoctorReport) {
+    let expected = [
+        &dirs.base,
+        &dirs.packages,
+        &dirs.installed,
+        &dirs.chroots,
+        &dirs.files_exposed,
+        &dirs.files_config,
+        &dirs.data,
+        &dirs.backups,
+        &dirs.secrets,
+    ];
+    let missing = expected
+        .iter()
+        .filter(|path| !system.path_is_dir(path).unwrap_or(false))
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        report.add("layout", Severity::Ok, "all directories exist");
+    } else {
+        report.add(
+            "layout",
+            Severity::Error,
+            format!(
+                "missing directories: {}. Run `side init` on an empty base directory",
+                missing.join(", ")
+            ),
+        );
+    }
+
+    match permissions(system, &dirs.secrets) {
+        Some(mode) if mode & 0o077 != 0 => report.add(
+            "permissions",
+            Severity::Warning,
+            format!(
+                "{} is accessible by other users (mode {:o}). Run `chmod 700 {}`",
+                dirs.secrets.display(),
+                mode,
+                dirs.secrets.display()
+            ),
+        ),
+        Some(_) => report.add(
+            "permissions",
+            Severity::Ok,
+            "secrets are only accessible by their owner",
+        ),
+        None => report.add(
+            "permissions",
+            Severity::Warning,
+            format!(
+                "unable to determine the permissions of {}",
+                dirs.secrets.display()
+            ),
+        ),
+    }
+}
+
+fn permissions<S: System>(system: &S, path: &Path) -> Option<u32> {
+    let result = system
+        .execute_command("stat", &["-c", "%a", path.to_str()?])
+        .ok()?;
+    if !result.is_success() {
+        return None;
+    }
+
+    u32::from_str_radix(result.stdout_as_str().trim(), 8).ok()
+}
+
+fn check_state<R: Requirement + DeserializeOwned, S: System>(
+    dirs: &Dirs,
+    system: &mut S,
+    report: &mut DoctorReport,
+) -> Option<SystemState<R>> {
+    let current = match dirs.current_install(system) {
+        Ok(current) => current,
+        Err(e) => {
+            report.add(
+                "state",
+                Severity::Error,
+                format!("unable to determine the current install: {}", e),
+            );
+            return None;
+        }
+    };
+
+    let graph = system
+        .file_contents(current.db())
+        .map_err(|e| e.to_string())
+        .and_then(|contents| {
+            crate::db::decode::<Graph<R, Applied>>(&contents, &dirs.migrations)
+                .map_err(|e| e.to_string())
+        });
+    match graph {
+        Ok(graph) => {
+            report.add(
+                "state",
+                Severity::Ok,
+                format!(
+                    "install {} has {} requirements",
+                    current.version,
+                    graph.len()
+                ),
+            );
+            Some(SystemState { graph })
+        }
+        Err(e) => {
+            report.add(
+                "state",
+                Severity::Error,
+                format!(
+                    "unable to load the database of install {} from {}: {}",
+                    current.version,
+                    current.db().display(),
+                    e
+                ),
+            );
+            None
+        }
+    }
+}
+
+fn check_commands<R: Requirement, S: System>(
+    graph: &Graph<R, Applied>,
+    system: &mut S,
+    report: &mut DoctorReport,
+) {
+    let required = graph
+        .requirements()
+        .flat_map(|r| r.required_commands())
+        .collect::<BTreeSet<_>>();
+    let missing = required
+        .iter()
+        .filter(|&&command| !command_exists(system, command))
+        .copied()
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        report.add(
+            "commands",
+            Severity::Ok,
+            format!("all {} required commands are available", required.len()),
+        );
+    } else {
+        report.add(
+            "commands",
+            Severity::Error,
+            format!(
+                "missing commands required by the current install: {}",
+                missing.join(", ")
+            ),
+        );
+    }
+}
+
+/// Returns true if `command` can be found in the `PATH` of `system`.
+pub(crate) fn command_exists<S: System>(system: &S, command: &str) -> bool {
+    system
+        .execute_command("sh", &["-c", "command -v \"$1\"", "sh", command])
+        .map(|result| result.is_success())
+        .unwrap_or(false)
+}
+
+fn check_dpkg_lock<S: System>(system: &mut S, report: &mut DoctorReport) {
+    if !command_exists(system, "dpkg") {
+        return;
+    }
+
+    if !command_exists(system, "fuser") {
+        report.add(
+            "dpkg",
+            Severity::Warning,
+            "unable to check the dpkg lock, because fuser is not available",
+        );
+        return;
+    }
+
+    // fuser is successful if any process has one of the files open
+    let locked = system
+        .execute_command("fuser", &DPKG_LOCKS)
+        .map(|result| result.is_success())
+        .unwrap_or(false);
+    if locked {
+        report.add(
+            "dpkg",
+            Severity::Warning,
+            "dpkg is locked by another process, installing packages will fail until it finishes",
+        );
+    } else {
+        report.add("dpkg", Severity::Ok, "dpkg is not locked");
+    }
+}
+
+fn check_clock<S: System>(dirs: &Dirs, system: &mut S, report: &mut DoctorReport) {
+    let now = system
+        .execute_command("date", &["+%s"])
+        .ok()
+        .filter(|result| result.is_success())
+        .and_then(|result| result.stdout_as_str().trim().parse::<u64>().ok())
+        .map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds));
+    let now = match now {
+        Some(now) => now,
+        None => {
+            report.add(
+                "clock",
+                Severity::Warning,
+                "unable to read the clock of the system",
+            );
+            return;
+        }
+    };
+
+    let last_install = dirs
+        .current_install(system)
+        .ok()
+        .and_then(|current| system.file_mtime(current.db()).ok().flatten());
+    let (severity, message) = clock_finding(now, SystemTime::now(), last_install);
+    report.add("clock", severity, message);
+}
+
+/// Judges the clock of the system (`now`), compared to the clock of the machine running `side` and the time of the last install.
+fn clock_finding(
+    now: SystemTime,
+    local: SystemTime,
+    last_install: Option<SystemTime>,
+) -> (Severity, String) {
+    if now < UNIX_EPOCH + EARLIEST_PLAUSIBLE_TIME {
+        return (
+            Severity::Error,
+            String::from("the clock has not been set, check NTP"),
+        );
+    }
+
+    let skew = now
+        .duration_since(local)
+        .or_else(|_| local.duration_since(now))
+        .unwrap_or_default();
+    if skew > MAX_CLOCK_SKEW {
+        return (
+            Severity::Warning,
+            format!(
+                "the clock differs by {} seconds from the local clock",
+                skew.as_secs()
+            ),
+        );
+    }
+
+    match last_install {
+        Some(last) if now < last => (
+            Severity::Warning,
+            String::from("the clock is behind the time of the last install"),
+        ),
+        _ => (Severity::Ok, String::from("the clock is plausible")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clock_finding, DoctorReport, Severity};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    pub fn clock_findings() {
+        let now = SystemTime::now();
+        let minute = Duration::from_secs(60);
+
+        assert_eq!(clock_finding(now, now, Some(now - minute)).0, Severity::Ok);
+        assert_eq!(clock_finding(UNIX_EPOCH, now, None).0, Severity::Error);
+        assert_eq!(
+            clock_finding(now + 5 * minute, now, None).0,
+            Severity::Warning
+        );
+        assert_eq!(
+            clock_finding(now, now, Some(now + minute)).0,
+            Severity::Warning
+        );
+    }
+
+    #[test]
+    pub fn report_errors() {
+        let mut report = DoctorReport::default();
+        report.add("clock", Severity::Warning, "skewed");
+        assert!(!report.has_errors());
+
+        report.add("layout", Severity::Error, "missing directories: /srv");
+        assert!(report.has_errors());
+        assert_eq!(
+            report.to_string(),
+            "[warning] clock: skewed\n[error  ] layout: missing directories: /srv\n"
+        );
+    }
+}
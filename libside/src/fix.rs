@@ -0,0 +1,338 @@
+//! Reports of the drift that `side verify --fix` corrected, for post-incident reviews.
+//!
+//! Before a requirement is fixed, the report records how the system differed from it with [`Requirement::diff`], and whether it is modified or created again.
+//! After the fix, every requirement is verified again.
+//! The report is saved as `<apply>.json` in the fixes directory, where `<apply>` is the id of the fix in the audit log, so `side history show` can show it next to the commands that the fix executed.
+use crate::graph::{Graph, NodeId};
+use crate::requirements::Requirement;
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of requirements that [`FixReport::limited`] shows.
+const MAX_SHOWN_REQUIREMENTS: usize = 20;
+
+/// The number of lines of the observed state of a requirement that [`FixReport::limited`] shows.
+const MAX_SHOWN_LINES: usize = 10;
+
+/// What a fix did with a requirement that no longer held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FixAction {
+    /// The requirement no longer existed, and was created again.
+    Create,
+
+    /// The requirement still existed, but differed from the install, and was modified.
+    Modify,
+}
+
+impl Display for FixAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            FixAction::Create => "create",
+            FixAction::Modify => "modify",
+        })
+    }
+}
+
+/// A requirement that no longer held, and what the fix did about it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FixedRequirement {
+    pub node: NodeId,
+    pub package: Option<String>,
+    pub requirement: String,
+
+    /// How the system differed from the requirement before the fix, if the requirement can describe it.
+    pub observed: Option<String>,
+    pub action: FixAction,
+
+    /// Whether the requirement verified after the fix.
+    pub verified: bool,
+}
+
+/// The requirements that were fixed by `side verify --fix`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FixReport {
+    /// The id of the fix in the audit log.
+    pub apply: u64,
+    pub install: u64,
+
+    /// When the fix was started, in seconds since the Unix epoch.
+    pub started: u64,
+    pub requirements: Vec<FixedRequirement>,
+
+    /// Why the fix could not be applied, if it failed. The requirements are still verified afterwards, to show what the failed fix left behind.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FixReportError<S: System> {
+    #[error("unable to read the fix report {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("invalid fix report {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, serde_json::Error),
+
+    #[error("unable to write the fix report {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+}
+
+impl FixReport {
+    /// Records the state of the `invalid` requirements of `graph`, the graph of install `install`, before they are fixed.
+    pub fn observe<R: Requirement, State: Default + Copy, S: System>(
+        apply: u64,
+        install: u64,
+        graph: &Graph<R, State>,
+        invalid: &[&R],
+        system: &mut S,
+    ) -> FixReport {
+        let requirements = invalid
+            .iter()
+            .map(|requirement| {
+                let node = NodeId::of(*requirement);
+                let exists = requirement.has_been_created(system).unwrap_or(false);
+                FixedRequirement {
+                    package: graph
+                        .find(&node)
+                        .and_then(|(_, n)| n.package().map(str::to_owned)),
+                    node,
+                    requirement: requirement.to_string(),
+                    observed: requirement.diff(system),
+                    action: if exists {
+                        FixAction::Modify
+                    } else {
+                        FixAction::Create
+                    },
+                    verified: false,
+                }
+            })
+            .collect();
+
+        FixReport {
+            apply,
+            install,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or(0),
+            requirements,
+            error: None,
+        }
+    }
+
+    /// Records that the fix failed with `error`.
+    pub fn failed(&mut self, error: &impl Display) {
+        self.error = Some(error.to_string());
+    }
+
+    /// Verifies the requirements again after the fix. `invalid` must be the requirements that were passed to [`FixReport::observe`].
+    pub fn verify<R: Requirement, S: System>(&mut self, invalid: &[&R], system: &mut S) {
+        for (fixed, requirement) in self.requirements.iter_mut().zip(invalid) {
+            fixed.verified = requirement.verify(system).unwrap_or(false);
+        }
+    }
+
+    /// Returns true if the fix was applied and every requirement verified after the fix.
+    pub fn is_success(&self) -> bool {
+        self.error.is_none() && self.requirements.iter().all(|r| r.verified)
+    }
+
+    fn path(dir: &Path, apply: u64) -> PathBuf {
+        dir.join(format!("{}.json", apply))
+    }
+
+    pub fn save<S: System>(&self, dir: &Path, system: &mut S) -> Result<(), FixReportError<S>> {
+        let path = Self::path(dir, self.apply);
+        system
+            .make_dir_all(dir)
+            .map_err(|e| FixReportError::UnableToWrite(path.clone(), e))?;
+        system
+            .put_file_contents(&path, &serde_json::to_vec_pretty(self).unwrap())
+            .map_err(|e| FixReportError::UnableToWrite(path.clone(), e))
+    }
+
+    /// Loads the report of the fix with id `apply`, or returns `None` if that apply was not a fix.
+    pub fn load<S: System>(
+        dir: &Path,
+        apply: u64,
+        system: &S,
+    ) -> Result<Option<FixReport>, FixReportError<S>> {
+        let path = Self::path(dir, apply);
+        if !system
+            .path_exists(&path)
+            .map_err(|e| FixReportError::UnableToRead(path.clone(), e))?
+        {
+            return Ok(None);
+        }
+
+        let contents = system
+            .file_contents(&path)
+            .map_err(|e| FixReportError::UnableToRead(path.clone(), e))?;
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| FixReportError::Invalid(path, e))
+    }
+
+    /// Displays the report, but only the first requirements and the first lines of what was observed, so that a fix of a badly drifted system does not flood the terminal.
+    /// The full report is shown by `side history show`.
+    pub fn limited(&self) -> Limited<'_> {
+        Limited(self)
+    }
+
+    fn write(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        max_requirements: usize,
+        max_lines: usize,
+    ) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Fix {} of install {}: {} requirements, {} verified afterwards",
+            self.apply,
+            self.install,
+            self.requirements.len(),
+            self.requirements.iter().filter(|r| r.verified).count()
+        )?;
+
+        if let Some(error) = &self.error {
+            writeln!(f, "  The fix failed: {}", error)?;
+        }
+
+        for fixed in self.requirements.iter().take(max_requirements) {
+            let package = match &fixed.package {
+                Some(package) => format!("[{}] ", package),
+                None => String::new(),
+            };
+            let result = if fixed.verified {
+                "fixed"
+            } else {
+                "still failing"
+            };
+            writeln!(
+                f,
+                "  {:6} {}{}: {}",
+                fixed.action, package, fixed.requirement, result
+            )?;
+
+            if let Some(observed) = &fixed.observed {
+                let lines = observed.lines().collect::<Vec<_>>();
+                for line in lines.iter().take(max_lines) {
+                    writeln!(f, "    | {}", line)?;
+                }
+
+                if lines.len() > max_lines {
+                    writeln!(f, "    | ... {} more lines", lines.len() - max_lines)?;
+                }
+            }
+        }
+
+        if self.requirements.len() > max_requirements {
+            writeln!(
+                f,
+                "  ... {} more requirements, see `side history show {}`",
+                self.requirements.len() - max_requirements,
+                self.apply
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for FixReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write(f, usize::MAX, usize::MAX)
+    }
+}
+
+/// A [`FixReport`] that only shows the first requirements, see [`FixReport::limited`].
+pub struct Limited<'r>(&'r FixReport);
+
+impl Display for Limited<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.write(f, MAX_SHOWN_REQUIREMENTS, MAX_SHOWN_LINES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FixAction, FixReport, FixedRequirement};
+    use crate::builder::fs::CreateDirectory;
+    use crate::graph::{Graph, NodeId, Pending};
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn observe_and_verify() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("fix");
+        let requirement = CreateDirectory::new(dir.join("app"));
+        let mut graph = Graph::<CreateDirectory, Pending>::new();
+        graph.add(requirement.clone(), &[]);
+
+        let invalid = vec![&requirement];
+        let mut report = FixReport::observe(4, 2, &graph, &invalid, &mut system);
+        assert_eq!(report.requirements.len(), 1);
+        assert_eq!(report.requirements[0].action, FixAction::Create);
+        assert_eq!(report.requirements[0].node, NodeId::of(&requirement));
+
+        report.verify(&invalid, &mut system);
+        assert!(!report.is_success());
+
+        std::fs::create_dir_all(dir.join("app")).unwrap();
+        report.verify(&invalid, &mut system);
+        assert!(report.is_success());
+
+        let mut failed = report.clone();
+        failed.failed(&"unable to create dir(app)");
+        assert!(!failed.is_success());
+        assert!(failed
+            .to_string()
+            .contains("  The fix failed: unable to create dir(app)\n"));
+
+        let fixes = dir.join("fixes");
+        report.save(&fixes, &mut system).unwrap();
+        assert_eq!(
+            FixReport::load(&fixes, 4, &system).unwrap(),
+            Some(report.clone())
+        );
+        assert_eq!(FixReport::load(&fixes, 5, &system).unwrap(), None);
+    }
+
+    #[test]
+    pub fn limit_output() {
+        let report = FixReport {
+            apply: 7,
+            install: 3,
+            started: 0,
+            requirements: (0..25)
+                .map(|i| FixedRequirement {
+                    node: NodeId::of(&CreateDirectory::new(PathBuf::from(format!("/srv/{}", i)))),
+                    package: Some(String::from("www")),
+                    requirement: format!("dir(/srv/{})", i),
+                    observed: Some((0..15).map(|l| format!("line {}\n", l)).collect()),
+                    action: FixAction::Modify,
+                    verified: true,
+                })
+                .collect(),
+            error: None,
+        };
+
+        let limited = report.limited().to_string();
+        assert!(
+            limited.starts_with("Fix 7 of install 3: 25 requirements, 25 verified afterwards\n")
+        );
+        assert!(limited.contains("  modify [www] dir(/srv/0): fixed\n"));
+        assert!(limited.contains("    | ... 5 more lines\n"));
+        assert!(!limited.contains("dir(/srv/20)"));
+        assert!(limited.ends_with("  ... 5 more requirements, see `side history show 7`\n"));
+
+        let full = report.to_string();
+        assert!(full.contains("dir(/srv/24)"));
+        assert!(full.contains("    | line 14\n"));
+    }
+}
@@ -0,0 +1,302 @@
+//! Spreading the applies of many hosts that run `side agent` over time, so that they do not all hit shared services at once.
+//!
+//! Every pull of the agent is delayed by a random splay, and applies can be limited to a maximum number of hosts at a time with an [`ApplyLock`].
+//! The lock is either a directory on shared storage, which contains one file per host that is applying, or an HTTP lock service.
+//!
+//! A lock service receives `POST <url>/acquire` and `POST <url>/release` requests with the form fields `holder` and `max`.
+//! It grants the lock with a 2xx response, and refuses it with any other response.
+use crate::system::{LocalSystem, System};
+use rand::Rng;
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+/// How long to wait between attempts to acquire the lock, in addition to a random splay.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The exit code of `curl --fail` for HTTP errors.
+const CURL_HTTP_ERROR: i32 = 22;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FleetError {
+    #[error("unable to access the lock directory {}: {}", .0.display(), .1)]
+    Io(PathBuf, std::io::Error),
+
+    #[error("unable to reach the lock service {}: {}", .0, .1)]
+    Http(String, String),
+}
+
+/// Limits the number of hosts that apply at the same time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApplyLock {
+    /// A directory on shared storage, such as NFS.
+    Directory(PathBuf),
+
+    /// The URL of a lock service.
+    Http(String),
+}
+
+impl FromStr for ApplyLock {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(ApplyLock::Http(s.trim_end_matches('/').to_owned()))
+        } else {
+            match s.split_once(':') {
+                Some(("file", path)) if !path.is_empty() => {
+                    Ok(ApplyLock::Directory(PathBuf::from(path)))
+                }
+                _ => Err(format!(
+                    "invalid apply lock {:?}, expected 'file:<dir>' or an http(s) URL",
+                    s
+                )),
+            }
+        }
+    }
+}
+
+impl Display for ApplyLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApplyLock::Directory(path) => write!(f, "file:{}", path.display()),
+            ApplyLock::Http(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// A slot of the lock, which must be released with [`ApplyLock::release`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Slot {
+    File(PathBuf),
+    Http,
+}
+
+/// A slot that is released when it is dropped.
+pub struct HeldSlot<'l> {
+    lock: &'l ApplyLock,
+    slot: Option<Slot>,
+    holder: String,
+    max: usize,
+}
+
+impl Drop for HeldSlot<'_> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            if let Err(e) = self.lock.release(slot, &self.holder, self.max) {
+                warn!("Unable to release the apply lock: {}", e);
+            }
+        }
+    }
+}
+
+/// Identifies this agent to the lock: the hostname and process id.
+pub fn holder() -> String {
+    format!("{}:{}", hostname(), std::process::id())
+}
+
+/// The hostname of the machine that side runs on, or `unknown`.
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_owned())
+        .unwrap_or_else(|_| String::from("unknown"))
+}
+
+/// A random duration between zero and `max`.
+pub fn splay(max: Duration) -> Duration {
+    if max.is_zero() {
+        Duration::ZERO
+    } else {
+        Duration::from_millis(rand::thread_rng().gen_range(0..=max.as_millis() as u64))
+    }
+}
+
+fn post(url: &str, holder: &str, max: usize) -> Result<bool, FleetError> {
+    let result = LocalSystem::new()
+        .execute_command(
+            "curl",
+            &[
+                "--silent",
+                "--show-error",
+                "--fail",
+                "--max-time",
+                "30",
+                "--data-urlencode",
+                &format!("holder={}", holder),
+                "--data",
+                &format!("max={}", max),
+                url,
+            ],
+        )
+        .map_err(|e| FleetError::Http(url.to_owned(), e.to_string()))?;
+
+    match result.exit_code() {
+        Some(0) => Ok(true),
+        Some(CURL_HTTP_ERROR) => Ok(false),
+        _ => Err(FleetError::Http(
+            url.to_owned(),
+            result.stderr_as_str().trim().to_owned(),
+        )),
+    }
+}
+
+impl ApplyLock {
+    /// Tries to acquire one of `max` slots without waiting. Returns `None` if all slots are held.
+    /// Slot files that are older than `stale_after` were left behind by a host that crashed, and are taken over.
+    pub fn try_acquire(
+        &self,
+        holder: &str,
+        max: usize,
+        stale_after: Duration,
+    ) -> Result<Option<Slot>, FleetError> {
+        match self {
+            ApplyLock::Directory(dir) => {
+                std::fs::create_dir_all(dir).map_err(|e| FleetError::Io(dir.clone(), e))?;
+                for index in 0..max {
+                    let path = dir.join(format!("slot-{}", index));
+                    let stale = std::fs::metadata(&path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                        .map(|age| age > stale_after)
+                        .unwrap_or(false);
+                    if stale {
+                        warn!("Taking over stale apply lock {}", path.display());
+                        let _ = std::fs::remove_file(&path);
+                    }
+
+                    match OpenOptions::new().write(true).create_new(true).open(&path) {
+                        Ok(mut file) => {
+                            file.write_all(holder.as_bytes())
+                                .map_err(|e| FleetError::Io(path.clone(), e))?;
+                            return Ok(Some(Slot::File(path)));
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                        Err(e) => return Err(FleetError::Io(path, e)),
+                    }
+                }
+
+                Ok(None)
+            }
+            ApplyLock::Http(url) => {
+                Ok(post(&format!("{}/acquire", url), holder, max)?.then_some(Slot::Http))
+            }
+        }
+    }
+
+    /// Waits until a slot is acquired, or until `stopped` is set, in which case `None` is returned.
+    pub fn acquire(
+        &self,
+        holder: &str,
+        max: usize,
+        stale_after: Duration,
+        stopped: &AtomicBool,
+    ) -> Result<Option<HeldSlot<'_>>, FleetError> {
+        let mut waiting = false;
+        while !stopped.load(Ordering::SeqCst) {
+            if let Some(slot) = self.try_acquire(holder, max, stale_after)? {
+                return Ok(Some(HeldSlot {
+                    lock: self,
+                    slot: Some(slot),
+                    holder: holder.to_owned(),
+                    max,
+                }));
+            }
+
+            if !waiting {
+                info!("Waiting until fewer than {} hosts are applying...", max);
+                waiting = true;
+            }
+
+            let retry = Instant::now() + RETRY_INTERVAL + splay(RETRY_INTERVAL);
+            while Instant::now() < retry && !stopped.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Releases a slot. A slot file that was taken over by another host in the meantime is left alone.
+    pub fn release(&self, slot: Slot, holder: &str, max: usize) -> Result<(), FleetError> {
+        match (self, slot) {
+            (ApplyLock::Directory(_), Slot::File(path)) => {
+                let owned = std::fs::read_to_string(&path)
+                    .map(|contents| contents == holder)
+                    .unwrap_or(false);
+                if owned {
+                    std::fs::remove_file(&path).map_err(|e| FleetError::Io(path, e))?;
+                }
+
+                Ok(())
+            }
+            (ApplyLock::Http(url), _) => post(&format!("{}/release", url), holder, max).map(|_| ()),
+            (ApplyLock::Directory(_), Slot::Http) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{splay, ApplyLock, Slot};
+    use crate::testing::TempDir;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    pub fn parse_apply_locks() {
+        assert_eq!(
+            "file:/mnt/shared/locks".parse::<ApplyLock>().unwrap(),
+            ApplyLock::Directory(PathBuf::from("/mnt/shared/locks"))
+        );
+        assert_eq!(
+            "https://locks.example.com/side/"
+                .parse::<ApplyLock>()
+                .unwrap(),
+            ApplyLock::Http(String::from("https://locks.example.com/side"))
+        );
+        assert_eq!(
+            ApplyLock::Directory(PathBuf::from("/mnt/locks")).to_string(),
+            "file:/mnt/locks"
+        );
+        assert!("/mnt/locks".parse::<ApplyLock>().is_err());
+    }
+
+    #[test]
+    pub fn splay_is_bounded() {
+        assert_eq!(splay(Duration::ZERO), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(splay(Duration::from_secs(5)) <= Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    pub fn limit_concurrent_applies() {
+        let dir = TempDir::new("fleet");
+        let lock = ApplyLock::Directory(dir.to_path_buf());
+        let hour = Duration::from_secs(3600);
+
+        let a = lock.try_acquire("a", 2, hour).unwrap().unwrap();
+        let b = lock.try_acquire("b", 2, hour).unwrap().unwrap();
+        assert_eq!(lock.try_acquire("c", 2, hour).unwrap(), None);
+
+        lock.release(a, "a", 2).unwrap();
+        let c = lock.try_acquire("c", 2, hour).unwrap().unwrap();
+        assert_eq!(c, Slot::File(dir.join("slot-0")));
+
+        // All slots are considered stale, so `d` takes over the slot of `c`, which must not release it afterwards.
+        let d = lock.try_acquire("d", 2, Duration::ZERO).unwrap().unwrap();
+        assert_eq!(d, Slot::File(dir.join("slot-0")));
+        lock.release(c, "c", 2).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("slot-0")).unwrap(), "d");
+
+        lock.release(b, "b", 2).unwrap();
+        lock.release(d, "d", 2).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    }
+}
@@ -1,13 +1,80 @@
-use crate::requirements::{Requirement, Supports};
+use crate::audit::{self, ChangeAction};
+use crate::batch::BatchedSystem;
+use crate::builder::fs::Sha3;
+use crate::conflict::{Conflict, ConflictPolicy, ConflictResolution, Resolution};
+use crate::doctor::command_exists;
+use crate::requirements::{BackupError, Explanation, Requirement, Supports, VerificationFailure};
+use crate::resume::{ApplyProgress, ProgressEvent};
+use crate::retry::RetrySettings;
 use crate::system::System;
+use crate::transfer::TransferLimits;
 use serde::{Deserialize, Serialize};
-use std::fmt::Display;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fmt::Display, fmt::Write, ops::Range};
+use tracing::{info, info_span, warn};
+
+/// Identifies a requirement across versions of the graph, unlike [`GraphNodeReference`], which is only valid within a single graph.
+/// It is a hash of the kind of the requirement and its [`Requirement::identity`], so it stays the same when for example the contents of a file change.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct NodeId(String);
+
+impl NodeId {
+    pub fn of<R: Requirement>(requirement: &R) -> NodeId {
+        let key = format!("{}\0{}", requirement.kind(), requirement.identity());
+        NodeId(Sha3::hash(key.as_bytes()).to_string()[..16].to_owned())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct GraphNode<R> {
     requirement: R,
     preconditions: Vec<usize>,
     pre_existing: bool,
+
+    /// The stable identity of the requirement. Missing in graphs that were saved by older versions, see [`GraphNode::id`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<NodeId>,
+
+    /// The package that added this node to the graph, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    package: Option<String>,
+}
+
+impl<R: Requirement> GraphNode<R> {
+    /// The stable identity of the requirement, which can be used to find the same requirement in other versions of the graph.
+    pub fn id(&self) -> NodeId {
+        match &self.id {
+            Some(id) => id.clone(),
+            None => NodeId::of(&self.requirement),
+        }
+    }
+}
+
+impl<R> GraphNode<R> {
+    pub fn requirement(&self) -> &R {
+        &self.requirement
+    }
+
+    /// The package that added this node to the graph, if known.
+    pub fn package(&self) -> Option<&str> {
+        self.package.as_deref()
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -23,6 +90,10 @@ pub struct Applied;
 pub struct Graph<R, State> {
     nodes: Vec<GraphNode<R>>,
     state: State,
+
+    /// The nodes that must have been applied to reach each named checkpoint, see [`Graph::add_checkpoint`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    checkpoints: BTreeMap<String, Vec<usize>>,
 }
 
 impl<R: Requirement> Graph<R, Pending> {
@@ -36,23 +107,35 @@ impl<R: Requirement> Graph<R, Pending> {
         R: Supports<T>,
     {
         let index = self.nodes.len();
+        let requirement: R = Supports::create_from(requirement);
         self.nodes.push(GraphNode {
-            requirement: Supports::create_from(requirement),
+            id: Some(NodeId::of(&requirement)),
+            requirement,
             preconditions: depends_on.into_iter().map(|r| r.0).collect(),
             pre_existing: false,
+            package: None,
         });
 
         GraphNodeReference(index)
     }
 
-    pub fn apply_execution_results(mut self, results: ApplyResult) -> Graph<R, Applied> {
-        for entry in results.pre_existing {
-            self.nodes[entry.0].pre_existing = true;
-        }
+    /// Adds `nodes` to the checkpoint `name`, which is reached once they and all of their preconditions have been applied.
+    /// A checkpoint can be extended by several packages: it is only reached once the nodes of all of them have been applied.
+    pub fn add_checkpoint<'a>(
+        &mut self,
+        name: &str,
+        nodes: impl IntoIterator<Item = &'a GraphNodeReference>,
+    ) {
+        self.checkpoints
+            .entry(name.to_owned())
+            .or_default()
+            .extend(nodes.into_iter().map(|node| node.0));
+    }
 
-        Graph {
-            nodes: self.nodes,
-            state: Applied,
+    /// Records `package` as the package that added the nodes in `nodes`.
+    pub(crate) fn assign_package(&mut self, nodes: Range<usize>, package: &str) {
+        for node in self.nodes[nodes].iter_mut() {
+            node.package = Some(package.to_owned());
         }
     }
 }
@@ -62,9 +145,49 @@ impl<R: Requirement, State: Default + Copy> Graph<R, State> {
         Graph {
             nodes: Vec::new(),
             state: State::default(),
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Marks the requirements that already existed before the apply, and returns the applied graph.
+    pub fn apply_execution_results(mut self, results: ApplyResult) -> Graph<R, Applied> {
+        for entry in results.pre_existing {
+            self.nodes[entry.0].pre_existing = true;
+        }
+
+        Graph {
+            nodes: self.nodes,
+            state: Applied,
+            checkpoints: self.checkpoints,
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn requirements(&self) -> impl Iterator<Item = &R> {
+        self.nodes.iter().map(|n| &n.requirement)
+    }
+
+    /// Returns the node with the stable identity `id`, if the graph contains it.
+    pub fn find(&self, id: &NodeId) -> Option<(GraphNodeReference, &GraphNode<R>)> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .find(|(_, node)| &node.id() == id)
+            .map(|(index, node)| (GraphNodeReference(index), node))
+    }
+
+    /// Returns the package that added each node, in the same order as [`Graph::requirements`].
+    pub fn packages(&self) -> impl Iterator<Item = Option<&str>> {
+        self.nodes.iter().map(|n| n.package.as_deref())
+    }
+
     pub fn invert(&self) -> Graph<R, State> {
         Graph {
             nodes: self
@@ -82,24 +205,115 @@ impl<R: Requirement, State: Default + Copy> Graph<R, State> {
                         .map(|(index, _)| index)
                         .collect(),
                     pre_existing: n.pre_existing,
+                    id: n.id.clone(),
+                    package: n.package.clone(),
                 })
                 .rev()
                 .collect(),
             state: self.state,
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    /// The names of the checkpoints in the graph, in alphabetical order.
+    pub fn checkpoints(&self) -> impl Iterator<Item = &str> {
+        self.checkpoints.keys().map(String::as_str)
+    }
+
+    /// Renders the graph in the DOT language of Graphviz, for example for `dot -Tsvg`.
+    /// Every node is labelled with its requirement and has an edge from each of its preconditions.
+    /// Nodes are grouped by the package that added them, and pre-existing nodes are dashed.
+    pub fn to_dot(&self) -> String {
+        let mut packages = BTreeMap::<Option<&str>, Vec<usize>>::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            packages
+                .entry(node.package.as_deref())
+                .or_default()
+                .push(index);
+        }
+
+        let mut result = String::from("digraph install {\n    node [shape=box];\n");
+        for (package, nodes) in packages {
+            let indent = match package {
+                Some(package) => {
+                    writeln!(
+                        result,
+                        "    subgraph {} {{\n        label={};",
+                        dot_string(&format!("cluster_{}", package)),
+                        dot_string(package)
+                    )
+                    .unwrap();
+                    "        "
+                }
+                None => "    ",
+            };
+
+            for index in nodes {
+                let node = &self.nodes[index];
+                writeln!(
+                    result,
+                    "{}n{} [label={}{}];",
+                    indent,
+                    index,
+                    dot_string(&node.requirement.to_string()),
+                    if node.pre_existing {
+                        ", style=dashed"
+                    } else {
+                        ""
+                    }
+                )
+                .unwrap();
+            }
+
+            if package.is_some() {
+                result.push_str("    }\n");
+            }
+        }
+
+        for (index, node) in self.nodes.iter().enumerate() {
+            for precondition in node.preconditions.iter() {
+                writeln!(result, "    n{} -> n{};", precondition, index).unwrap();
+            }
+        }
+
+        result.push_str("}\n");
+        result
+    }
+
+    /// Returns which nodes must have been applied to reach all of the `checkpoints`, or the name of the first checkpoint that the graph does not contain.
+    fn checkpoint_nodes(&self, checkpoints: &[String]) -> Result<Vec<bool>, String> {
+        let mut result = vec![false; self.nodes.len()];
+        let mut scanlist = Vec::new();
+        for name in checkpoints {
+            let nodes = self.checkpoints.get(name).ok_or_else(|| name.clone())?;
+            scanlist.extend(nodes.iter().copied());
+        }
+
+        while let Some(index) = scanlist.pop() {
+            if !result[index] {
+                result[index] = true;
+                scanlist.extend(self.nodes[index].preconditions.iter().copied());
+            }
         }
+
+        Ok(result)
     }
 
+    /// Returns the new indices of the retained nodes that `node` depends on, directly or through removed nodes.
     fn collect_inherited_preconditions(
         &self,
         node: &GraphNode<R>,
         map: &[Option<usize>],
     ) -> Vec<usize> {
         let mut result = Vec::new();
-        let mut scanlist = Vec::new();
-        scanlist.push(node);
-
+        let mut seen = HashSet::new();
+        let mut scanlist = vec![node];
         while let Some(node) = scanlist.pop() {
             for index in node.preconditions.iter().copied() {
+                if !seen.insert(index) {
+                    continue;
+                }
+
                 match map[index] {
                     None => scanlist.push(&self.nodes[index]),
                     Some(new_index) => result.push(new_index),
@@ -124,14 +338,41 @@ impl<R: Requirement, State: Default + Copy> Graph<R, State> {
             counter += 1;
         }
 
-        let inherited_preconditions = mapping
-            .iter()
-            .zip(self.nodes.iter())
-            .map(|(map, node)| match map {
-                Some(_) => Vec::new(),
-                None => self.collect_inherited_preconditions(node, &mapping),
-            })
-            .collect::<Vec<_>>();
+        // The preconditions that a removed node passes on to the nodes that depend on it.
+        // Graphs that are built with `add` only have preconditions that refer to earlier nodes, so those of the preconditions themselves have already been determined.
+        // Graphs that are read from a database may not, and fall back to searching the preconditions of every removed node.
+        let ordered = self.nodes.iter().enumerate().all(|(index, node)| {
+            node.preconditions
+                .iter()
+                .all(|&precondition| precondition < index)
+        });
+        let mut inherited_preconditions: Vec<Vec<usize>> = Vec::with_capacity(self.nodes.len());
+        for (node, map) in self.nodes.iter().zip(mapping.iter()) {
+            let mut inherited = Vec::new();
+            if map.is_none() && !ordered {
+                inherited = self.collect_inherited_preconditions(node, &mapping);
+            } else if map.is_none() {
+                let mut seen = HashSet::new();
+                for &pc in node.preconditions.iter() {
+                    match mapping[pc] {
+                        Some(new_index) => {
+                            if seen.insert(new_index) {
+                                inherited.push(new_index);
+                            }
+                        }
+                        None => {
+                            for &index in inherited_preconditions[pc].iter() {
+                                if seen.insert(index) {
+                                    inherited.push(index);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            inherited_preconditions.push(inherited);
+        }
 
         // Remap all preconditions
         for node in self.nodes.iter_mut() {
@@ -148,22 +389,35 @@ impl<R: Requirement, State: Default + Copy> Graph<R, State> {
                 *pc = mapping[*pc].unwrap();
             }
 
+            let mut seen = node.preconditions.iter().copied().collect::<HashSet<_>>();
             for new in new_preconditions {
-                if !node.preconditions.contains(&new) {
+                if seen.insert(new) {
                     node.preconditions.push(new);
                 }
             }
         }
 
-        // Remove nodes
-        for (index, _) in mapping
-            .iter()
-            .enumerate()
-            .rev()
-            .filter(|(_, k)| k.is_none())
-        {
-            self.nodes.remove(index);
+        // A checkpoint that contains a removed node contains the preconditions that it passes on instead
+        for nodes in self.checkpoints.values_mut() {
+            let mut seen = HashSet::new();
+            *nodes = nodes
+                .iter()
+                .flat_map(|&index| match mapping[index] {
+                    Some(new_index) => vec![new_index],
+                    None => inherited_preconditions[index].clone(),
+                })
+                .filter(|&index| seen.insert(index))
+                .collect();
         }
+
+        // Remove nodes
+        let nodes = std::mem::take(&mut self.nodes);
+        self.nodes = nodes
+            .into_iter()
+            .zip(mapping)
+            .filter(|(_, map)| map.is_some())
+            .map(|(node, _)| node)
+            .collect();
     }
 
     pub fn extract_undo_graph<S: System>(
@@ -199,6 +453,7 @@ impl<R: Requirement, State: Default + Copy> Graph<R, State> {
             prev,
             undo,
             target: self,
+            strategy: ApplyStrategy::default(),
         })
     }
 
@@ -209,18 +464,67 @@ impl<R: Requirement, State: Default + Copy> Graph<R, State> {
     }
 }
 
+/// Quotes `s` as a string in the DOT language.
+fn dot_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
 impl<R: Requirement> Graph<R, Applied> {
+    /// The requirements that hold once this graph has been applied over `prev` up to the `checkpoints`:
+    /// the nodes that are needed to reach the checkpoints, and the nodes of `prev` that they do not replace.
+    /// Requirements of `prev` that are no longer needed at all are kept until the entire graph is applied.
+    ///
+    /// Returns the name of the first checkpoint that the graph does not contain as the error.
+    pub fn up_to_checkpoints(
+        &self,
+        prev: &Graph<R, Applied>,
+        checkpoints: &[String],
+    ) -> Result<Graph<R, Applied>, String> {
+        let reached = self.checkpoint_nodes(checkpoints)?;
+        let mut staged = self.clone();
+        staged.retain(|index, _| reached[index]);
+
+        let mut kept = prev.clone();
+        kept.retain(|_, node| {
+            !staged
+                .nodes
+                .iter()
+                .any(|new_node| node.requirement.affects(&new_node.requirement))
+        });
+
+        let offset = kept.nodes.len();
+        kept.nodes.extend(staged.nodes.into_iter().map(|mut node| {
+            for precondition in node.preconditions.iter_mut() {
+                *precondition += offset;
+            }
+
+            node
+        }));
+        kept.checkpoints = BTreeMap::new();
+
+        Ok(kept)
+    }
+
     pub fn generate_fix_sequence<S: System>(
         &self,
         _system: &mut S,
-    ) -> Result<ApplySequence<R>, ()> {
+    ) -> Result<ApplySequence<R>, CycleError> {
         let mut result = ApplySequence {
             undo: Vec::new(),
             todo: Vec::new(),
+            deferred: Vec::new(),
             prev: self,
+            target: &self.nodes,
+            backups: None,
+            progress: None,
         };
 
-        let mut walker = GraphWalker::new(&self);
+        let mut walker = GraphWalker::new(self)?;
         while let Some((index, node)) = walker.next() {
             result.todo.push(Do {
                 created_by_us: true,
@@ -234,25 +538,57 @@ impl<R: Requirement> Graph<R, Applied> {
     }
 }
 
+/// The order in which an apply undoes the requirements that are no longer needed, and applies the new requirements.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplyStrategy {
+    /// Undo all old requirements, then apply the new requirements.
+    #[default]
+    UndoFirst,
+
+    /// Apply the new requirements first, and only undo the old requirements once all new requirements have been applied.
+    /// If the apply fails, the old requirements still exist.
+    /// Old requirements that hold a resource that a new requirement needs (see [`Requirement::exclusive_resources`]) are still undone first.
+    CreateFirst,
+}
+
 impl<'g, R: Requirement, State> ComparedGraph<'g, R, State> {
+    /// Sets the order in which the application sequence undoes old requirements and applies new requirements.
+    pub fn with_strategy(mut self, strategy: ApplyStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
     pub fn generate_application_sequence<S: System>(
         &self,
         _system: &mut S,
-    ) -> Result<ApplySequence<R>, ()> {
+    ) -> Result<ApplySequence<R>, CycleError> {
         let mut result = ApplySequence {
             undo: Vec::new(),
             todo: Vec::new(),
+            deferred: Vec::new(),
             prev: self.prev,
+            target: &self.target.nodes,
+            backups: None,
+            progress: None,
         };
-        let mut walker = GraphWalker::new(&self.undo);
-        while let Some((_, node)) = walker.next() {
-            result.undo.push(Undo {
+
+        let undo_first = self.undo_first();
+        let mut walker = GraphWalker::canonical(&self.undo)?;
+        while let Some((index, node)) = walker.next() {
+            let entry = Undo {
                 pre_existing: node.pre_existing,
                 requirement: &node.requirement,
-            });
+            };
+
+            if undo_first[index] {
+                result.undo.push(entry);
+            } else {
+                result.deferred.push(entry);
+            }
         }
 
-        let mut walker = GraphWalker::new(&self.target);
+        let mut walker = GraphWalker::canonical(self.target)?;
         while let Some((index, node)) = walker.next() {
             let (should_exist, created_by_us) = match self
                 .prev
@@ -274,37 +610,186 @@ impl<'g, R: Requirement, State> ComparedGraph<'g, R, State> {
 
         Ok(result)
     }
+
+    /// Determines which nodes of the undo graph must be undone before the new requirements are applied.
+    fn undo_first(&self) -> Vec<bool> {
+        if self.strategy == ApplyStrategy::UndoFirst {
+            return vec![true; self.undo.nodes.len()];
+        }
+
+        // Requirements that already existed in the previous graph have been able to coexist with the old requirements
+        let claimed = self
+            .target
+            .nodes
+            .iter()
+            .filter(|node| {
+                !self
+                    .prev
+                    .nodes
+                    .iter()
+                    .any(|prev| prev.requirement.affects(&node.requirement))
+            })
+            .flat_map(|node| node.requirement.exclusive_resources())
+            .collect::<Vec<_>>();
+        let mut result = self
+            .undo
+            .nodes
+            .iter()
+            .map(|node| {
+                node.requirement
+                    .exclusive_resources()
+                    .iter()
+                    .any(|resource| claimed.iter().any(|c| resource.conflicts_with(c)))
+            })
+            .collect::<Vec<_>>();
+
+        // Everything that must be undone before a conflicting node must be undone first as well
+        let mut scanlist = (0..result.len())
+            .filter(|&index| result[index])
+            .collect::<Vec<_>>();
+        while let Some(index) = scanlist.pop() {
+            for &precondition in self.undo.nodes[index].preconditions.iter() {
+                if !result[precondition] {
+                    result[precondition] = true;
+                    scanlist.push(precondition);
+                }
+            }
+        }
+
+        result
+    }
 }
 
 pub struct ComparedGraph<'g, R, State> {
     undo: Graph<R, Applied>,
     prev: &'g Graph<R, Applied>,
     target: &'g Graph<R, State>,
+    strategy: ApplyStrategy,
+}
+
+/// The preconditions of a graph form a cycle, so there is no order in which its requirements can be applied.
+/// This is always caused by a bug, because a builder can only add preconditions on nodes that already exist.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("the requirements depend on each other in a cycle: {}", .requirements.join(", "))]
+pub struct CycleError {
+    /// The nodes on the cycles, and on the paths between them.
+    pub nodes: Vec<usize>,
+    pub requirements: Vec<String>,
 }
 
+/// Visits the nodes of a graph in an order in which every node comes after its preconditions.
+/// Of the nodes whose preconditions have all been visited, the node that was added last is visited first,
+/// unless the walker was created with [`GraphWalker::canonical`].
 pub struct GraphWalker<'a, R, State> {
     graph: &'a Graph<R, State>,
-    fulfilled: Vec<bool>,
+    order: std::vec::IntoIter<usize>,
 }
 
-impl<'a, R, State> GraphWalker<'a, R, State> {
-    pub fn new(graph: &'a Graph<R, State>) -> Self {
-        GraphWalker {
-            fulfilled: vec![false; graph.nodes.len()],
-            graph,
+impl<'a, R: Display, State> GraphWalker<'a, R, State> {
+    /// Determines the order in which the nodes of `graph` are visited, or fails if the graph contains a cycle.
+    pub fn new(graph: &'a Graph<R, State>) -> Result<Self, CycleError> {
+        Self::ordered_by(graph, |index| index)
+    }
+
+    /// Visits the nodes of `graph` in an order that does not depend on the order in which independent nodes were added,
+    /// so that the same requirements always produce the same sequence.
+    /// Of the nodes whose preconditions have all been visited, the node with the lowest kind and identity is visited first.
+    /// Nodes with the same kind and identity are visited in the same order as [`GraphWalker::new`] would.
+    pub fn canonical(graph: &'a Graph<R, State>) -> Result<Self, CycleError>
+    where
+        R: Requirement,
+    {
+        let keys = graph
+            .nodes
+            .iter()
+            .map(|node| (node.requirement.kind(), node.requirement.identity()))
+            .collect::<Vec<_>>();
+        Self::ordered_by(graph, |index| (Reverse(&keys[index]), index))
+    }
+
+    /// Of the nodes whose preconditions have all been visited, visits the node with the highest `key` first.
+    fn ordered_by<K: Ord>(
+        graph: &'a Graph<R, State>,
+        key: impl Fn(usize) -> K,
+    ) -> Result<Self, CycleError> {
+        let nodes = &graph.nodes;
+        let mut dependents = vec![Vec::new(); nodes.len()];
+        for (index, node) in nodes.iter().enumerate() {
+            for &precondition in node.preconditions.iter() {
+                dependents[precondition].push(index);
+            }
+        }
+
+        // The number of preconditions of every node that have not been visited yet
+        let mut unvisited = nodes
+            .iter()
+            .map(|node| node.preconditions.len())
+            .collect::<Vec<_>>();
+        let mut ready = (0..nodes.len())
+            .filter(|&index| unvisited[index] == 0)
+            .map(|index| (key(index), index))
+            .collect::<BinaryHeap<_>>();
+        let mut order = Vec::with_capacity(nodes.len());
+        while let Some((_, index)) = ready.pop() {
+            order.push(index);
+            for &dependent in dependents[index].iter() {
+                unvisited[dependent] -= 1;
+                if unvisited[dependent] == 0 {
+                    ready.push((key(dependent), dependent));
+                }
+            }
+        }
+
+        if order.len() < nodes.len() {
+            return Err(CycleError::find(graph, &dependents, &unvisited));
         }
+
+        Ok(GraphWalker {
+            graph,
+            order: order.into_iter(),
+        })
     }
+}
 
+impl<'a, R, State> GraphWalker<'a, R, State> {
     pub fn next(&mut self) -> Option<(usize, &'a GraphNode<R>)> {
-        for (index, node) in self.graph.nodes.iter().enumerate().rev() {
-            if !self.fulfilled[index] && node.preconditions.iter().all(|n| self.fulfilled[*n]) {
-                self.fulfilled[index] = true;
-                return Some((index, node));
+        self.order
+            .next()
+            .map(|index| (index, &self.graph.nodes[index]))
+    }
+}
+
+impl CycleError {
+    /// Determines the nodes on the cycles of `graph`, from the nodes that could not be visited because they have `unvisited` preconditions.
+    fn find<R: Display, State>(
+        graph: &Graph<R, State>,
+        dependents: &[Vec<usize>],
+        unvisited: &[usize],
+    ) -> CycleError {
+        let mut on_cycle = unvisited.iter().map(|&count| count > 0).collect::<Vec<_>>();
+
+        // Nodes that depend on a cycle without being on one end in a node without dependents on the cycle
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in 0..on_cycle.len() {
+                if on_cycle[index] && !dependents[index].iter().any(|&d| on_cycle[d]) {
+                    on_cycle[index] = false;
+                    changed = true;
+                }
             }
         }
 
-        assert!(self.fulfilled.iter().all(|f| *f));
-        None
+        let nodes = (0..on_cycle.len())
+            .filter(|&index| on_cycle[index])
+            .collect::<Vec<_>>();
+        CycleError {
+            requirements: nodes
+                .iter()
+                .map(|&index| graph.nodes[index].requirement.to_string())
+                .collect(),
+            nodes,
+        }
     }
 }
 
@@ -326,7 +811,139 @@ pub struct Do<'r, R> {
 pub struct ApplySequence<'r, R> {
     undo: Vec<Undo<'r, R>>,
     todo: Vec<Do<'r, R>>,
+
+    /// Undos that are run after all todos have been applied, see [`ApplyStrategy::CreateFirst`].
+    deferred: Vec<Undo<'r, R>>,
     prev: &'r Graph<R, Applied>,
+    target: &'r [GraphNode<R>],
+
+    /// Where requirements back up their data before they are undone, see [`ApplySequence::with_backups`].
+    backups: Option<PathBuf>,
+
+    /// Where the progress of the apply is recorded, see [`ApplySequence::with_progress`].
+    progress: Option<ProgressLog>,
+}
+
+/// The progress of an [`ApplySequence`], which is written to `path` after every requirement.
+#[derive(Debug, Clone, PartialEq)]
+struct ProgressLog {
+    path: PathBuf,
+
+    /// The progress of an earlier run of the sequence that was interrupted, which is empty for a new apply.
+    resumed: ApplyProgress,
+    current: RefCell<ApplyProgress>,
+}
+
+/// The changes that an [`ApplySequence`] would make, without running it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan<'r, R> {
+    undo: Vec<&'r R>,
+    create: Vec<&'r R>,
+    update: Vec<&'r R>,
+    unchanged: usize,
+}
+
+impl<'r, R> Plan<'r, R> {
+    /// Returns true if applying the sequence would not change anything.
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty() && self.create.is_empty() && self.update.is_empty()
+    }
+
+    pub fn undo(&self) -> &[&'r R] {
+        &self.undo
+    }
+
+    pub fn create(&self) -> &[&'r R] {
+        &self.create
+    }
+
+    pub fn update(&self) -> &[&'r R] {
+        &self.update
+    }
+
+    pub fn unchanged(&self) -> usize {
+        self.unchanged
+    }
+}
+
+impl<'r, R: Display> Display for Plan<'r, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for requirement in self.undo.iter() {
+            writeln!(f, "  undo: {}", requirement)?;
+        }
+
+        for requirement in self.create.iter() {
+            writeln!(f, "  create: {}", requirement)?;
+        }
+
+        for requirement in self.update.iter() {
+            writeln!(f, "  update: {}", requirement)?;
+        }
+
+        writeln!(f, "  {} unchanged", self.unchanged)
+    }
+}
+
+/// What running an [`ApplySequence`] does with a requirement, see [`ApplySequence::changes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Undo,
+    Create,
+    Update,
+    Unchanged,
+}
+
+/// A single requirement of an [`ApplySequence`], see [`ApplySequence::changes`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change<'r, R> {
+    pub kind: ChangeKind,
+    pub requirement: &'r R,
+
+    /// The requirement that is replaced by an update, if it can be found in the previous graph.
+    pub previous: Option<&'r R>,
+
+    /// The package that added the requirement, if known.
+    pub package: Option<&'r str>,
+}
+
+/// A problem found by [`ApplySequence::preflight`], which would make the apply fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreflightProblem {
+    pub requirement: String,
+    pub problem: String,
+}
+
+/// The problems found by [`ApplySequence::preflight`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreflightReport {
+    problems: Vec<PreflightProblem>,
+}
+
+impl PreflightReport {
+    pub fn problems(&self) -> &[PreflightProblem] {
+        &self.problems
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn add(&mut self, requirement: &impl Display, problem: String) {
+        self.problems.push(PreflightProblem {
+            requirement: requirement.to_string(),
+            problem,
+        });
+    }
+}
+
+impl Display for PreflightReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for problem in self.problems.iter() {
+            writeln!(f, "  {}: {}", problem.requirement, problem.problem)?;
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -335,7 +952,7 @@ pub struct VerifySequence<'r, R> {
 }
 
 #[must_use]
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct ApplyResult {
     pre_existing: Vec<GraphNodeReference>,
 }
@@ -354,881 +971,3005 @@ pub enum RequirementOperationError<R: Requirement, S: System> {
     #[error("couldn't be deleted: {}", inner)]
     DeleteFailed { inner: R::DeleteError<S> },
 
+    #[error("couldn't be backed up before it was undone: {}", inner)]
+    BackupFailed { inner: BackupError<S> },
+
     #[error("already exists, refusing to overwrite")]
     PreExisting,
-}
 
-#[derive(Debug, thiserror::Error)]
-#[error("{} {}", requirement, inner)]
-pub struct RunError<R: Requirement, S: System> {
-    requirement: R,
-    pub revert_info: RevertInfo,
-    inner: RequirementOperationError<R, S>,
+    #[error("was not applied because the apply was cancelled")]
+    Cancelled,
+
+    #[error("was not applied because the apply exceeded its timeout")]
+    TimedOut,
+
+    #[error("exceeded the timeout for a single requirement")]
+    NodeTimedOut,
 }
 
-#[derive(Copy, Clone, Debug)]
-pub enum Position {
-    Undo(usize),
-    Todo(usize),
-}
-
-#[derive(Clone, Debug)]
-pub struct RevertInfo {
-    position: Position,
-    pre_existing: Vec<GraphNodeReference>,
-}
+/// Limits on how long an apply may run, and a flag to cancel it.
+///
+/// The limits are checked between requirements: a requirement that is being applied is never interrupted halfway.
+/// When a limit is exceeded, the apply fails with a `RunError` whose `RevertInfo` can be used to revert the changes that have been made.
+#[derive(Clone, Debug, Default)]
+pub struct ApplyLimits {
+    /// The maximum duration of the entire apply.
+    pub timeout: Option<Duration>,
 
-impl<'r, R: Requirement> ApplySequence<'r, R> {
-    #[must_use]
-    pub fn run<S: System>(
-        &self,
-        system: &mut S,
-        ask_overwrite: impl Fn(&str) -> bool,
-    ) -> Result<ApplyResult, RunError<R, S>> {
-        let mut result = ApplyResult {
-            pre_existing: Vec::new(),
-        };
+    /// The maximum duration of a single requirement.
+    /// A requirement that takes longer fails once it has finished, and is reverted along with the rest of the apply.
+    pub node_timeout: Option<Duration>,
 
-        for (index, entry) in self.undo.iter().enumerate() {
-            println!("  undo: {}", entry.requirement);
-            if entry.pre_existing {
-                entry.requirement.pre_existing_delete(system)
-            } else {
-                entry.requirement.delete(system)
-            }
-            .map_err(|inner| RunError {
-                requirement: entry.requirement.clone(),
-                revert_info: RevertInfo {
-                    position: Position::Undo(index),
-                    pre_existing: result.pre_existing.clone(),
-                },
-                inner: RequirementOperationError::DeleteFailed { inner },
-            })?;
-        }
+    /// When set, no further requirements are applied. This is set from a signal handler when the operator presses Ctrl-C.
+    pub cancelled: Arc<AtomicBool>,
 
-        for (index, entry) in self.todo.iter().enumerate() {
-            let r = &entry.requirement;
-            println!("  require: {}", r);
-            match r.has_been_created(system) {
-                Ok(has_been_created) => {
-                    if has_been_created {
-                        if !entry.should_exist && !r.may_pre_exist() {
-                            if !ask_overwrite(&format!("{}", r)) {
-                                return Err(RunError {
-                                    requirement: entry.requirement.clone(),
-                                    revert_info: RevertInfo {
-                                        position: Position::Todo(index),
-                                        pre_existing: result.pre_existing.clone(),
-                                    },
-                                    inner: RequirementOperationError::PreExisting,
-                                });
-                            }
-                        }
+    /// How exposed files are copied into the new install.
+    pub transfer: TransferLimits,
 
-                        if !entry.created_by_us {
-                            result.pre_existing.push(entry.source);
-                        }
+    /// How often requirements that fail are attempted again before the apply fails.
+    pub retry: RetrySettings,
+}
 
-                        r.modify(system).map_err(|inner| RunError {
-                            requirement: entry.requirement.clone(),
-                            revert_info: RevertInfo {
-                                position: Position::Todo(index),
-                                pre_existing: result.pre_existing.clone(),
-                            },
-                            inner: RequirementOperationError::ModifyFailed { inner },
-                        })?;
-                    } else {
-                        r.create(system).map_err(|inner| RunError {
-                            requirement: entry.requirement.clone(),
-                            revert_info: RevertInfo {
-                                position: Position::Todo(index),
-                                pre_existing: result.pre_existing.clone(),
-                            },
-                            inner: RequirementOperationError::CreateFailed { inner },
-                        })?;
-                    }
-                }
-                Err(inner) => {
-                    return Err(RunError {
-                        requirement: entry.requirement.clone(),
-                        revert_info: RevertInfo {
-                            position: Position::Todo(index),
-                            pre_existing: result.pre_existing.clone(),
-                        },
-                        inner: RequirementOperationError::UnableToCheck { inner },
-                    })
+impl ApplyLimits {
+    /// Returns the reason the apply must stop before the next requirement, if any.
+    fn check<R: Requirement, S: System>(
+        &self,
+        started: Instant,
+    ) -> Option<RequirementOperationError<R, S>> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            Some(RequirementOperationError::Cancelled)
+        } else {
+            match self.timeout {
+                Some(timeout) if started.elapsed() > timeout => {
+                    Some(RequirementOperationError::TimedOut)
                 }
+                _ => None,
             }
         }
-
-        Ok(result)
     }
 
-    pub fn revert<S: System>(
+    /// Runs `operation` on `requirement`, and runs it again when it fails and the retry policy of the requirement allows another attempt.
+    /// No further attempts are made once the apply is cancelled or exceeds its timeout.
+    fn attempt<R: Requirement, S: System, T, E: std::error::Error>(
         &self,
-        system: &mut S,
-        info: &RevertInfo,
-    ) -> Result<(), RunError<R, S>> {
-        let num_todo = match info.position {
-            Position::Undo(_) => 0,
-            Position::Todo(index) => index,
-        };
-
-        // We need to undo any changes that won't be overwritten by re-applying the previous graph
-        for entry in self.todo.iter().take(num_todo).rev() {
-            if !self
-                .prev
-                .nodes
-                .iter()
-                .any(|n| n.requirement.affects(entry.requirement))
-            {
-                println!("  undo: {}", entry.requirement);
-                if entry.requirement.can_undo() {
-                    if info.pre_existing.contains(&entry.source) {
-                        entry.requirement.pre_existing_delete(system)
-                    } else {
-                        entry.requirement.delete(system)
+        requirement: &R,
+        started: Instant,
+        mut operation: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let policy = self.retry.policy_for(requirement);
+        let mut attempt = 1;
+        loop {
+            match operation() {
+                Err(e) if policy.allows(attempt) => {
+                    let delay = policy.delay(attempt);
+                    warn!(
+                        "  attempt {} of {} failed, retrying in {:?}: {}",
+                        attempt, policy.max_attempts, delay, e
+                    );
+                    std::thread::sleep(delay);
+                    if self.check::<R, S>(started).is_some() {
+                        return Err(e);
                     }
-                    .unwrap();
+
+                    attempt += 1;
                 }
+                result => return result,
             }
         }
-
-        let fix_sequence = self.prev.generate_fix_sequence(system).unwrap();
-        let _ = fix_sequence.run(system, |_| false).unwrap();
-
-        Ok(())
     }
-}
-
-#[derive(Clone, Debug)]
-pub enum VerificationState<'r, R> {
-    Ok,
-    Invalid { invalid: Vec<&'r R> },
-}
 
-impl<'r, R: Display> Display for VerificationState<'r, R> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            VerificationState::Ok => write!(f, "all OK")?,
-            VerificationState::Invalid { invalid } => {
-                for item in invalid.iter() {
-                    writeln!(f, "corrupted: {}", item)?;
-                }
+    fn check_node<R: Requirement, S: System>(
+        &self,
+        node_started: Instant,
+    ) -> Option<RequirementOperationError<R, S>> {
+        match self.node_timeout {
+            Some(timeout) if node_started.elapsed() > timeout => {
+                Some(RequirementOperationError::NodeTimedOut)
             }
+            _ => None,
         }
-
-        Ok(())
     }
 }
 
-impl<'r, R: Requirement + Display> VerifySequence<'r, R> {
-    pub fn run<S: System>(self, system: &mut S) -> Result<VerificationState<'r, R>, ()> {
-        let mut invalid = Vec::new();
-        for entry in self.items {
-            if entry.verify(system)? {
-                println!("  ok: {}", entry);
-            } else {
-                println!("  invalid: {}", entry);
-                invalid.push(entry);
-            }
-        }
-
-        Ok(if invalid.len() > 0 {
-            VerificationState::Invalid { invalid }
-        } else {
-            VerificationState::Ok
-        })
+impl<R: Requirement, S: System> RequirementOperationError<R, S> {
+    /// Returns true if the error stops the entire apply, rather than only the requirement that failed.
+    fn stops_apply(&self) -> bool {
+        matches!(
+            self,
+            RequirementOperationError::Cancelled
+                | RequirementOperationError::TimedOut
+                | RequirementOperationError::NodeTimedOut
+        )
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        graph::{Applied, ApplyResult, Do, GraphNodeReference, Pending, Undo},
-        requirements::Supports,
-    };
-    use serde::{Deserialize, Serialize};
-    use std::{collections::HashSet, fmt::Display, path::PathBuf};
+#[derive(Debug, thiserror::Error)]
+#[error("{} {}", requirement, inner)]
+pub struct RunError<R: Requirement, S: System> {
+    requirement: R,
+    pub revert_info: RevertInfo,
+    inner: RequirementOperationError<R, S>,
+    context: Box<FailureContext>,
+}
 
-    use super::{Graph, Requirement, System};
+/// Describes where in the application sequence a failure occurred.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FailureContext {
+    /// The node in the target graph, or `None` if the failure occurred while undoing a node of the previous graph.
+    node: Option<usize>,
+    package: Option<String>,
+    dependencies: Vec<String>,
+    applied: usize,
+    remaining: usize,
+}
 
-    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-    struct Foo {
-        id: u64,
-        can_undo: bool,
+impl<R: Requirement, S: System> RunError<R, S> {
+    pub fn requirement(&self) -> &R {
+        &self.requirement
     }
 
-    impl Foo {
-        const ROOT: Foo = Foo {
-            id: 0,
-            can_undo: true,
-        };
-        const ROOT_NOUNDO: Foo = Foo {
-            id: 0,
-            can_undo: false,
-        };
-        const A: Foo = Foo {
-            id: 1,
-            can_undo: true,
-        };
-        const A_NOUNDO: Foo = Foo {
-            id: 1,
-            can_undo: false,
-        };
-        const B: Foo = Foo {
-            id: 2,
-            can_undo: true,
-        };
-        const B_NOUNDO: Foo = Foo {
-            id: 2,
-            can_undo: false,
-        };
-        const C: Foo = Foo {
-            id: 3,
-            can_undo: true,
-        };
-        const C_NOUNDO: Foo = Foo {
-            id: 3,
-            can_undo: false,
-        };
-        const D: Foo = Foo {
-            id: 4,
-            can_undo: true,
-        };
-        const END: Foo = Foo {
-            id: 100,
-            can_undo: true,
-        };
+    pub fn inner(&self) -> &RequirementOperationError<R, S> {
+        &self.inner
     }
 
-    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-    struct AlwaysFail;
+    pub fn node(&self) -> Option<GraphNodeReference> {
+        self.context.node.map(GraphNodeReference)
+    }
 
-    impl Display for AlwaysFail {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(f, "AlwaysFail")
-        }
+    /// The stable identity of the failing requirement.
+    pub fn id(&self) -> NodeId {
+        NodeId::of(&self.requirement)
     }
 
-    impl Requirement for AlwaysFail {
-        const NAME: &'static str = "alwaysfail";
+    /// The name of the package that created the failing requirement.
+    pub fn package(&self) -> Option<&str> {
+        self.context.package.as_deref()
+    }
 
-        type CreateError<S: System> = FakeError;
-        type ModifyError<S: System> = FakeError;
-        type DeleteError<S: System> = FakeError;
-        type HasBeenCreatedError<S: System> = FakeError;
+    /// The requirements that the failing requirement depends on.
+    pub fn dependencies(&self) -> &[String] {
+        &self.context.dependencies
+    }
 
-        fn create<S: System>(&self, _system: &mut S) -> Result<(), Self::CreateError<S>> {
-            Err(FakeError)
-        }
-        fn modify<S: System>(&self, _system: &mut S) -> Result<(), Self::ModifyError<S>> {
-            Err(FakeError)
-        }
-        fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
-            Err(FakeError)
-        }
+    /// The number of steps that had been applied successfully before the failure.
+    pub fn applied(&self) -> usize {
+        self.context.applied
+    }
 
-        fn has_been_created<S: System>(
-            &self,
-            _system: &mut S,
-        ) -> Result<bool, Self::HasBeenCreatedError<S>> {
-            Err(FakeError)
-        }
+    /// The number of steps that had not been applied yet, including the step that failed.
+    pub fn remaining(&self) -> usize {
+        self.context.remaining
+    }
 
-        fn affects(&self, _other: &Self) -> bool {
-            true
-        }
-        fn supports_modifications(&self) -> bool {
-            false
-        }
-        fn can_undo(&self) -> bool {
-            true
-        }
-        fn may_pre_exist(&self) -> bool {
-            false
-        }
-        fn verify<S: System>(&self, _system: &mut S) -> Result<bool, ()> {
-            Ok(true)
-        }
+    pub fn report(&self) -> FailureReport<'_, R, S> {
+        FailureReport(self)
     }
+}
 
-    #[derive(Debug, thiserror::Error)]
-    #[error("Error")]
-    struct FakeError;
+/// A multi-line, human-readable description of a [`RunError`].
+pub struct FailureReport<'e, R: Requirement, S: System>(&'e RunError<R, S>);
 
-    impl Requirement for Foo {
-        type CreateError<S: System> = S::Error;
-        type ModifyError<S: System> = FakeError;
-        type DeleteError<S: System> = S::Error;
-        type HasBeenCreatedError<S: System> = S::Error;
+impl<'e, R: Requirement, S: System> Display for FailureReport<'e, R, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let err = self.0;
+        let action = match err.revert_info.position {
+            Position::Undo(_) | Position::Cleanup(_) => "undoing",
+            Position::Todo(_) => "applying",
+        };
 
-        fn create<S: super::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
-            Ok(system.copy_file(&PathBuf::new(), &PathBuf::from(format!("{}", self.id)))?)
+        writeln!(f, "Failure while {} {}", action, err.requirement)?;
+        writeln!(f, "  reason      : {}", err.inner)?;
+        writeln!(
+            f,
+            "  package     : {}",
+            err.package().unwrap_or("<unknown>")
+        )?;
+        if let Some(node) = err.context.node {
+            writeln!(f, "  node        : #{}", node)?;
         }
 
-        fn modify<S: super::System>(&self, _system: &mut S) -> Result<(), Self::ModifyError<S>> {
-            Ok(())
-        }
+        writeln!(f, "  id          : {}", err.id())?;
 
-        fn delete<S: super::System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
-            Ok(system.remove_file(&PathBuf::from(format!("{}", self.id)))?)
+        if err.dependencies().is_empty() {
+            writeln!(f, "  depends on  : <nothing>")?;
+        } else {
+            writeln!(f, "  depends on  : {}", err.dependencies().join(", "))?;
         }
 
-        fn has_been_created<S: super::System>(
-            &self,
-            system: &mut S,
-        ) -> Result<bool, Self::HasBeenCreatedError<S>> {
-            Ok(system.path_exists(&PathBuf::from(format!("{}", self.id)))?)
+        let documentation = err.requirement.documentation();
+        if documentation.explanation == Explanation::UNDOCUMENTED {
+            writeln!(f, "  kind        : {}", documentation.kind)?;
+        } else {
+            writeln!(
+                f,
+                "  kind        : {}: {}",
+                documentation.kind, documentation.explanation.summary
+            )?;
         }
 
-        fn affects(&self, other: &Self) -> bool {
-            self.id == other.id
+        for hint in documentation.hints_for(&err.inner.to_string()) {
+            writeln!(f, "  hint        : {}", hint)?;
         }
 
-        fn supports_modifications(&self) -> bool {
-            false
+        if let Some(url) = documentation.url {
+            writeln!(f, "  docs        : {}", url)?;
         }
 
-        fn can_undo(&self) -> bool {
-            self.can_undo
-        }
+        write!(
+            f,
+            "  progress    : {} applied, {} remaining",
+            err.applied(),
+            err.remaining()
+        )
+    }
+}
 
-        fn may_pre_exist(&self) -> bool {
-            false
-        }
+#[derive(Copy, Clone, Debug)]
+pub enum Position {
+    Undo(usize),
+    Todo(usize),
 
-        fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
-            self.has_been_created(system).map_err(|_| ())
-        }
+    /// A deferred undo, which runs after all todos have been applied.
+    Cleanup(usize),
+}
 
-        const NAME: &'static str = "foo";
-    }
+#[derive(Clone, Debug)]
+pub struct RevertInfo {
+    position: Position,
+    pre_existing: Vec<GraphNodeReference>,
 
-    impl std::fmt::Display for Foo {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            std::fmt::Debug::fmt(self, f)
-        }
-    }
+    /// The todos before `position` that were not applied because they are in a failed subtree, see [`ApplySequence::run_isolated`].
+    skipped: Vec<GraphNodeReference>,
+}
 
-    #[derive(Debug)]
-    struct FakeSystem {
-        created: HashSet<PathBuf>,
+/// A subtree of the target graph that failed in [`ApplySequence::run_isolated`]:
+/// the requirement that failed, and the requirements that depend on it, directly or indirectly, which were skipped.
+#[derive(Debug)]
+pub struct FailedSubtree<R: Requirement, S: System> {
+    error: RunError<R, S>,
+    skipped: Vec<R>,
+}
+
+impl<R: Requirement, S: System> FailedSubtree<R, S> {
+    pub fn error(&self) -> &RunError<R, S> {
+        &self.error
     }
 
-    impl System for FakeSystem {
-        type Error = FakeError;
-        type CommandError = FakeError;
+    pub fn skipped(&self) -> &[R] {
+        &self.skipped
+    }
+}
 
-        fn path_exists(&self, path: &std::path::Path) -> Result<bool, Self::Error> {
-            Ok(self.created.contains(&path.to_path_buf()))
-        }
+/// The number of requirements of a package that [`ApplySequence::run_isolated`] applied, failed to apply and skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackageOutcome {
+    pub package: Option<String>,
+    pub applied: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
 
-        fn path_is_dir(&self, _path: &std::path::Path) -> Result<bool, Self::Error> {
-            todo!()
-        }
+/// The result of [`ApplySequence::run_isolated`], which may have applied only part of the target graph.
+#[must_use]
+#[derive(Debug)]
+pub struct PartialApply<R: Requirement, S: System> {
+    result: ApplyResult,
+    failed: Vec<FailedSubtree<R, S>>,
+    packages: Vec<PackageOutcome>,
+}
 
-        fn file_contents(&self, _path: &std::path::Path) -> Result<Vec<u8>, Self::Error> {
-            todo!()
-        }
+impl<R: Requirement, S: System> PartialApply<R, S> {
+    /// Returns true if no subtree failed, so that the entire target graph has been applied.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
 
-        fn execute_command(
-            &self,
-            _path: &str,
-            _args: &[&str],
-        ) -> Result<crate::system::CommandResult, Self::Error> {
-            todo!()
-        }
+    pub fn failed(&self) -> &[FailedSubtree<R, S>] {
+        &self.failed
+    }
 
-        fn copy_file(
-            &mut self,
-            _from: &std::path::Path,
-            to: &std::path::Path,
-        ) -> Result<(), Self::Error> {
-            self.created.insert(to.to_path_buf());
+    /// The outcome per package, in the order in which the packages were first applied.
+    pub fn packages(&self) -> &[PackageOutcome] {
+        &self.packages
+    }
 
-            Ok(())
-        }
+    pub fn into_result(self) -> ApplyResult {
+        self.result
+    }
 
-        fn make_dir(&mut self, path: &std::path::Path) -> Result<(), Self::Error> {
-            self.created.insert(path.to_path_buf());
+    fn outcome(&mut self, package: Option<&str>) -> &mut PackageOutcome {
+        let index = match self
+            .packages
+            .iter()
+            .position(|outcome| outcome.package.as_deref() == package)
+        {
+            Some(index) => index,
+            None => {
+                self.packages.push(PackageOutcome {
+                    package: package.map(str::to_owned),
+                    ..Default::default()
+                });
+                self.packages.len() - 1
+            }
+        };
 
-            Ok(())
-        }
+        &mut self.packages[index]
+    }
+}
 
-        fn remove_dir(&mut self, path: &std::path::Path) -> Result<(), Self::Error> {
-            self.created.retain(|item| item != path);
+impl<R: Requirement, S: System> Display for PartialApply<R, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} of {} packages applied completely, {} subtrees failed",
+            self.packages
+                .iter()
+                .filter(|outcome| outcome.failed == 0 && outcome.skipped == 0)
+                .count(),
+            self.packages.len(),
+            self.failed.len()
+        )?;
+        for outcome in self.packages.iter() {
+            let status = if outcome.failed == 0 && outcome.skipped == 0 {
+                "ok"
+            } else {
+                "failed"
+            };
+            writeln!(
+                f,
+                "  {:6} {}: {} applied, {} failed, {} skipped",
+                status,
+                outcome.package.as_deref().unwrap_or("<unknown>"),
+                outcome.applied,
+                outcome.failed,
+                outcome.skipped
+            )?;
+        }
 
-            Ok(())
+        for subtree in self.failed.iter() {
+            writeln!(f, "Failed: {}", subtree.error)?;
+            for requirement in subtree.skipped.iter() {
+                writeln!(f, "  skipped: {}", requirement)?;
+            }
         }
 
-        fn remove_file(&mut self, path: &std::path::Path) -> Result<(), Self::Error> {
-            self.created.retain(|item| item != path);
+        Ok(())
+    }
+}
 
-            Ok(())
-        }
+impl<'r, R: Requirement> ApplySequence<'r, R> {
+    /// Backs up the data of requirements that are undone into a subdirectory of `dir` before they are undone, see [`Requirement::backup`].
+    /// Without a backups directory, requirements are undone without a backup.
+    pub fn with_backups(mut self, dir: PathBuf) -> Self {
+        self.backups = Some(dir);
+        self
+    }
 
-        fn get_user(&mut self, _name: &str) -> Result<Option<()>, Self::Error> {
-            todo!()
-        }
+    /// Records the requirements that have been undone and applied in `path` after every requirement, see [`resume`](crate::resume).
+    /// If `progress` was recorded by an earlier run of the same sequence that was interrupted, the requirements that it undid are skipped,
+    /// and so are the requirements that it applied which still exist.
+    pub fn with_progress(mut self, path: PathBuf, progress: ApplyProgress) -> Self {
+        self.progress = Some(ProgressLog {
+            path,
+            current: RefCell::new(progress.clone()),
+            resumed: progress,
+        });
+        self
+    }
 
-        fn execute_command_with_input(
-            &self,
-            _path: &str,
-            _args: &[&str],
-            _input: &[u8],
-        ) -> Result<crate::system::CommandResult, Self::CommandError> {
-            todo!()
-        }
+    /// The progress of the interrupted run that is being resumed, if any.
+    fn resumed(&self) -> Option<&ApplyProgress> {
+        self.progress.as_ref().map(|log| &log.resumed)
+    }
 
-        fn chmod(&mut self, _path: &std::path::Path, _mode: u32) -> Result<(), Self::Error> {
-            todo!()
+    /// Writes a snapshot of the progress when a run starts, if the sequence records its progress.
+    /// A failure to record the progress is logged, and does not fail the apply.
+    fn start_recording<S: System>(&self, system: &mut S, result: &ApplyResult) {
+        if let Some(log) = &self.progress {
+            let mut progress = log.current.borrow_mut();
+            progress.pre_existing = result.pre_existing.iter().map(|node| node.0).collect();
+            if let Err(e) = progress.save(&log.path, system) {
+                warn!("Unable to record the progress of the apply: {}", e);
+            }
         }
+    }
 
-        fn put_file_contents(
-            &self,
-            _path: &std::path::Path,
-            _contents: &[u8],
-        ) -> Result<(), Self::Error> {
-            todo!()
+    /// Adds `event` to the recorded progress, along with the requirements that have been found to pre-exist since the last event.
+    /// A failure to record the progress is logged, and does not fail the apply.
+    fn record<S: System>(&self, system: &mut S, result: &ApplyResult, event: ProgressEvent) {
+        if let Some(log) = &self.progress {
+            let mut progress = log.current.borrow_mut();
+            let mut events = result
+                .pre_existing
+                .iter()
+                .filter(|node| !progress.pre_existing.contains(&node.0))
+                .map(|node| ProgressEvent::PreExisting(node.0))
+                .collect::<Vec<_>>();
+            events.push(event);
+            for event in events.iter() {
+                progress.apply(event.clone());
+            }
+
+            if let Err(e) = ApplyProgress::append(&log.path, &events, system) {
+                warn!("Unable to record the progress of the apply: {}", e);
+            }
         }
+    }
 
-        fn make_dir_all(&mut self, _path: &std::path::Path) -> Result<(), Self::Error> {
-            todo!()
+    /// The result of the requirements that have been applied before the run started, which is empty unless an interrupted run is resumed.
+    fn initial_result(&self) -> ApplyResult {
+        ApplyResult {
+            pre_existing: self
+                .resumed()
+                .map(|progress| {
+                    progress
+                        .pre_existing
+                        .iter()
+                        .map(|&node| GraphNodeReference(node))
+                        .collect()
+                })
+                .unwrap_or_default(),
         }
+    }
 
-        fn dir_is_empty(&mut self, _path: &std::path::Path) -> Result<bool, Self::Error> {
-            todo!()
+    /// Summarizes the changes that running this sequence would make.
+    /// A requirement is considered updated if a requirement that affects it was applied before, but with different parameters,
+    /// or if one of its dependencies is created or updated.
+    pub fn plan(&self) -> Plan<'r, R> {
+        let mut plan = Plan {
+            undo: Vec::new(),
+            create: Vec::new(),
+            update: Vec::new(),
+            unchanged: 0,
+        };
+
+        for change in self.changes() {
+            match change.kind {
+                ChangeKind::Undo => plan.undo.push(change.requirement),
+                ChangeKind::Create => plan.create.push(change.requirement),
+                ChangeKind::Update => plan.update.push(change.requirement),
+                ChangeKind::Unchanged => plan.unchanged += 1,
+            }
         }
 
-        fn read_dir(&mut self, _path: &std::path::Path) -> Result<Vec<String>, Self::Error> {
-            todo!()
+        plan
+    }
+
+    /// Lists every requirement that the sequence undoes or requires, in the order in which they are run,
+    /// with the package that added it and, for updates, the requirement that it replaces.
+    /// The changes are classified in the same way as in [`ApplySequence::plan`].
+    pub fn changes(&self) -> Vec<Change<'r, R>> {
+        let prev: &'r Graph<R, Applied> = self.prev;
+        let previous = prev
+            .nodes
+            .iter()
+            .map(|n| serde_json::to_string(&n.requirement).unwrap())
+            .collect::<Vec<_>>();
+        let mut changes = self
+            .undo
+            .iter()
+            .chain(self.deferred.iter())
+            .map(|u| Change {
+                kind: ChangeKind::Undo,
+                requirement: u.requirement,
+                previous: None,
+                package: prev
+                    .find(&NodeId::of(u.requirement))
+                    .and_then(|(_, n)| n.package.as_deref()),
+            })
+            .collect::<Vec<_>>();
+
+        let mut changed = vec![false; self.target.len()];
+        for entry in self.todo.iter() {
+            let index = entry.source.0;
+            let node = &self.target[index];
+            let dependency_changed = node
+                .preconditions
+                .iter()
+                .any(|&dependency| changed[dependency]);
+            let kind = if !entry.should_exist {
+                ChangeKind::Create
+            } else if !dependency_changed
+                && previous.contains(&serde_json::to_string(entry.requirement).unwrap())
+            {
+                ChangeKind::Unchanged
+            } else {
+                ChangeKind::Update
+            };
+            changed[index] = kind != ChangeKind::Unchanged;
+
+            changes.push(Change {
+                kind,
+                requirement: entry.requirement,
+                previous: match kind {
+                    ChangeKind::Update => prev
+                        .find(&node.id())
+                        .map(|(_, n)| &n.requirement)
+                        .or_else(|| {
+                            prev.requirements()
+                                .find(|previous| previous.affects(entry.requirement))
+                        }),
+                    _ => None,
+                },
+                package: node.package.as_deref(),
+            });
         }
+
+        changes
     }
 
-    #[test]
-    pub fn invert() {
-        let mut g = Graph::<Foo, Pending>::new();
-        let root = g.add(Foo::ROOT, &[]);
-        let a = g.add(Foo::A, &[root]);
-        let b = g.add(Foo::B, &[root]);
-        let c = g.add(Foo::C, &[a, root]);
-        let _end = g.add(Foo::END, &[b, c]);
+    /// Checks, without changing the system, that the requirements in the sequence can be applied, before anything is undone.
+    /// A requirement is only checked if all of its dependencies already hold,
+    /// because otherwise its checks may depend on changes that the sequence makes first, such as installing the package that provides a command.
+    pub fn preflight<S: System>(&self, system: &mut S) -> PreflightReport {
+        let mut report = PreflightReport::default();
+        let mut holds = vec![false; self.target.len()];
+        let mut available = HashMap::new();
+        for entry in self.todo.iter() {
+            let index = entry.source.0;
+            let dependencies_hold = self.target[index]
+                .preconditions
+                .iter()
+                .all(|&dependency| holds[dependency]);
+            holds[index] = dependencies_hold && entry.should_exist;
+            if !dependencies_hold {
+                continue;
+            }
 
-        println!("graph    : {:?}", g);
+            for command in entry.requirement.required_commands() {
+                let exists = *available
+                    .entry(command)
+                    .or_insert_with(|| command_exists(system, command));
+                if !exists {
+                    report.add(
+                        entry.requirement,
+                        format!("the command {} is not available", command),
+                    );
+                }
+            }
 
-        let g = g.invert();
+            for problem in entry.requirement.preflight(system) {
+                report.add(entry.requirement, problem);
+            }
+        }
 
-        println!("inv      : {:?}", g);
+        report
+    }
 
-        let mut expected = Graph::<Foo, Pending>::new();
-        let end = expected.add(Foo::END, &[]);
-        let c = expected.add(Foo::C, &[end]);
-        let b = expected.add(Foo::B, &[end]);
-        let a = expected.add(Foo::A, &[c]);
-        let _root = expected.add(Foo::ROOT, &[c, b, a]);
+    fn failure<S: System>(
+        &self,
+        position: Position,
+        result: &ApplyResult,
+        inner: RequirementOperationError<R, S>,
+    ) -> RunError<R, S> {
+        let undone = |entry: &Undo<'r, R>| {
+            let node = self
+                .prev
+                .nodes
+                .iter()
+                .find(|n| n.requirement.affects(entry.requirement));
+            (entry.requirement, node.map(|n| (n, &self.prev.nodes[..])))
+        };
+        let (requirement, node) = match position {
+            Position::Undo(index) => undone(&self.undo[index]),
+            Position::Cleanup(index) => undone(&self.deferred[index]),
+            Position::Todo(index) => {
+                let entry = &self.todo[index];
+                let node = &self.target[entry.source.0];
+                (entry.requirement, Some((node, self.target)))
+            }
+        };
 
-        println!("expected : {:?}", expected);
-        assert_eq!(g, expected);
+        let (applied, remaining) = match position {
+            Position::Undo(index) => (
+                index,
+                self.undo.len() - index + self.todo.len() + self.deferred.len(),
+            ),
+            Position::Todo(index) => (
+                self.undo.len() + index,
+                self.todo.len() - index + self.deferred.len(),
+            ),
+            Position::Cleanup(index) => (
+                self.undo.len() + self.todo.len() + index,
+                self.deferred.len() - index,
+            ),
+        };
+
+        RunError {
+            requirement: requirement.clone(),
+            revert_info: RevertInfo {
+                position,
+                pre_existing: result.pre_existing.clone(),
+                skipped: Vec::new(),
+            },
+            inner,
+            context: Box::new(FailureContext {
+                node: match position {
+                    Position::Undo(_) | Position::Cleanup(_) => None,
+                    Position::Todo(index) => Some(self.todo[index].source.0),
+                },
+                package: node.and_then(|(node, _)| node.package.clone()),
+                dependencies: node
+                    .map(|(node, nodes)| {
+                        node.preconditions
+                            .iter()
+                            .map(|&index| nodes[index].requirement.to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                applied,
+                remaining,
+            }),
+        }
     }
 
-    #[test]
-    pub fn retain_all_but_one() {
-        let mut g = Graph::<Foo, Pending>::new();
-        let root = g.add(Foo::ROOT, &[]);
-        let a = g.add(Foo::A, &[root]);
-        let b = g.add(Foo::B, &[root]);
-        let c = g.add(Foo::C, &[a, root]);
-        let _end = g.add(Foo::END, &[b, c]);
+    #[must_use]
+    pub fn run<S: System>(
+        &self,
+        system: &mut S,
+        resolution: &impl ConflictResolution<R>,
+    ) -> Result<ApplyResult, RunError<R, S>> {
+        self.run_with_limits(system, resolution, &ApplyLimits::default())
+    }
 
-        println!("graph    : {:?}", g);
+    /// Runs the sequence, but stops before the next requirement when the apply is cancelled or one of the `limits` is exceeded.
+    pub fn run_with_limits<S: System>(
+        &self,
+        system: &mut S,
+        resolution: &impl ConflictResolution<R>,
+        limits: &ApplyLimits,
+    ) -> Result<ApplyResult, RunError<R, S>> {
+        let started = Instant::now();
+        let mut result = self.initial_result();
+        audit::set_node(system, None);
+        self.start_recording(system, &result);
 
-        g.retain(|_, f| f.requirement != Foo::C);
+        self.run_undo(system, &self.undo, Position::Undo, &result, limits, started)?;
 
-        println!("retained : {:?}", g);
+        for index in 0..self.todo.len() {
+            if let Some(inner) = limits.check(started) {
+                return Err(self.failure(Position::Todo(index), &result, inner));
+            }
 
-        let mut expected = Graph::<Foo, Pending>::new();
-        let root = expected.add(Foo::ROOT, &[]);
-        let a = expected.add(Foo::A, &[root]);
-        let b = expected.add(Foo::B, &[root]);
-        let _end = expected.add(Foo::END, &[b, a, root]);
+            self.run_todo(system, resolution, limits, started, index, &mut result)?;
+            self.record_applied(system, index, &result);
+        }
 
-        println!("expected : {:?}", expected);
-        assert_eq!(g, expected);
+        self.run_undo(
+            system,
+            &self.deferred,
+            Position::Cleanup,
+            &result,
+            limits,
+            started,
+        )?;
+        audit::set_node(system, None);
+
+        Ok(result)
     }
 
-    #[test]
-    pub fn retain_all_but_two() {
-        let mut g = Graph::<Foo, Pending>::new();
-        let root = g.add(Foo::ROOT, &[]);
-        let a = g.add(Foo::A, &[root]);
-        let b = g.add(Foo::B, &[a]);
-        let c = g.add(Foo::C, &[a, root]);
-        let _end = g.add(Foo::END, &[b, c]);
+    /// Runs the sequence like [`ApplySequence::run_with_limits`], but a requirement that fails only fails its own subtree:
+    /// the requirements that depend on it, directly or indirectly, are skipped, and all other requirements are still applied.
+    /// Each failed subtree can be reverted on its own with [`ApplySequence::revert_subtree`].
+    ///
+    /// Failures while undoing old requirements, cancellation and timeouts still fail the entire apply, which must then be reverted with [`ApplySequence::revert`].
+    pub fn run_isolated<S: System>(
+        &self,
+        system: &mut S,
+        resolution: &impl ConflictResolution<R>,
+        limits: &ApplyLimits,
+    ) -> Result<PartialApply<R, S>, RunError<R, S>> {
+        let started = Instant::now();
+        let mut partial = PartialApply {
+            result: self.initial_result(),
+            failed: Vec::new(),
+            packages: Vec::new(),
+        };
+        audit::set_node(system, None);
+        self.start_recording(system, &partial.result);
+
+        self.run_undo(
+            system,
+            &self.undo,
+            Position::Undo,
+            &partial.result,
+            limits,
+            started,
+        )?;
+
+        // The failed subtree that each node of the target graph belongs to, if any
+        let mut subtrees: Vec<Option<usize>> = vec![None; self.target.len()];
+        let mut skipped = Vec::new();
+        for (index, entry) in self.todo.iter().enumerate() {
+            if let Some(inner) = limits.check(started) {
+                let mut err = self.failure(Position::Todo(index), &partial.result, inner);
+                err.revert_info.skipped = skipped;
+                return Err(err);
+            }
 
-        println!("graph    : {:?}", g);
+            let node = &self.target[entry.source.0];
+            let failed_dependency = node
+                .preconditions
+                .iter()
+                .find_map(|&dependency| subtrees[dependency]);
+            if let Some(subtree) = failed_dependency {
+                info!("  skip: {}", entry.requirement);
+                subtrees[entry.source.0] = Some(subtree);
+                skipped.push(entry.source);
+                partial.failed[subtree]
+                    .skipped
+                    .push(entry.requirement.clone());
+                partial.outcome(node.package()).skipped += 1;
+                continue;
+            }
 
-        g.retain(|_, f| f.requirement != Foo::A && f.requirement != Foo::B);
+            match self.run_todo(
+                system,
+                resolution,
+                limits,
+                started,
+                index,
+                &mut partial.result,
+            ) {
+                Ok(()) => {
+                    self.record_applied(system, index, &partial.result);
+                    partial.outcome(node.package()).applied += 1;
+                }
+                Err(err) if !err.inner.stops_apply() => {
+                    warn!(
+                        "  failed, skipping the requirements that depend on it: {}",
+                        err
+                    );
+                    subtrees[entry.source.0] = Some(partial.failed.len());
+                    skipped.push(entry.source);
+                    partial.failed.push(FailedSubtree {
+                        error: err,
+                        skipped: Vec::new(),
+                    });
+                    partial.outcome(node.package()).failed += 1;
+                }
+                Err(mut err) => {
+                    err.revert_info.skipped = skipped;
+                    return Err(err);
+                }
+            }
+        }
 
-        println!("retained : {:?}", g);
+        self.run_undo(
+            system,
+            &self.deferred,
+            Position::Cleanup,
+            &partial.result,
+            limits,
+            started,
+        )
+        .map_err(|mut err| {
+            err.revert_info.skipped = skipped;
+            err
+        })?;
+        audit::set_node(system, None);
+
+        Ok(partial)
+    }
 
-        let mut expected = Graph::<Foo, Pending>::new();
-        let root = expected.add(Foo::ROOT, &[]);
-        let c = expected.add(Foo::C, &[root]);
-        let _end = expected.add(Foo::END, &[c, root]);
+    /// Reverts a subtree that failed in [`ApplySequence::run_isolated`], by applying the requirements of the previous graph that the failed requirement would have replaced again.
+    /// The requirements that depend on the failed requirement were skipped, so they have not been changed.
+    pub fn revert_subtree<S: System>(
+        &self,
+        system: &mut S,
+        subtree: &FailedSubtree<R, S>,
+    ) -> Result<(), RunError<R, S>> {
+        let failed = subtree.error.requirement();
+        let mut fix_sequence = self.prev.generate_fix_sequence(system).unwrap();
+        fix_sequence
+            .todo
+            .retain(|entry| entry.requirement.affects(failed));
+        let _ = fix_sequence.run(system, &ConflictPolicy::Always(Resolution::Abort))?;
 
-        println!("expected : {:?}", expected);
-        assert_eq!(g, expected);
+        Ok(())
     }
 
-    #[test]
-    pub fn trivial_sequence() {
-        let prev = Graph::<Foo, Applied>::new();
-        let mut next = Graph::<Foo, Pending>::new();
-        let root = next.add(Foo::ROOT, &[]);
-        let a = next.add(Foo::A, &[root]);
-        let b = next.add(Foo::B, &[root]);
-        let c = next.add(Foo::C, &[a, root]);
-        let _end = next.add(Foo::END, &[b, c]);
-
-        println!("prev       : {:?}", prev);
-        println!("next       : {:?}", next);
+    /// Records that the todo at `index` has been applied.
+    fn record_applied<S: System>(&self, system: &mut S, index: usize, result: &ApplyResult) {
+        let node = self.todo[index].source.0;
+        self.record(system, result, ProgressEvent::Applied(node));
+    }
 
-        let mut sys = FakeSystem {
-            created: Default::default(),
+    /// Applies the todo at `index`. A failure is reported at that position, so that the todos before it are reverted.
+    /// When an interrupted run is resumed, a todo that it applied is skipped if it still exists, and a todo that it was applying is not treated as pre-existing.
+    fn run_todo<S: System>(
+        &self,
+        system: &mut S,
+        resolution: &impl ConflictResolution<R>,
+        limits: &ApplyLimits,
+        started: Instant,
+        index: usize,
+        result: &mut ApplyResult,
+    ) -> Result<(), RunError<R, S>> {
+        let entry = &self.todo[index];
+        let node_started = Instant::now();
+        audit::set_node(system, Some(self.target[entry.source.0].id()));
+        let r = &entry.requirement;
+        let _span = info_span!(
+            "requirement",
+            action = "require",
+            node = entry.source.0,
+            kind = r.kind(),
+            requirement = %r
+        )
+        .entered();
+        info!("  require: {}", r);
+        let (applied_before, started_before) = match self.resumed() {
+            Some(progress) => (
+                progress.applied.contains(&entry.source.0),
+                progress.started == Some(entry.source.0),
+            ),
+            None => (false, false),
         };
+        // Requirements that are unchanged since the previous install are not recorded as applied
+        let mut changed = !entry.should_exist;
+        match r.has_been_created(system) {
+            Ok(true) if applied_before => {
+                info!("  already applied: {}", r);
+                return Ok(());
+            }
+            Ok(has_been_created) => {
+                // The pre-existing requirements of the interrupted run are already part of the result
+                let resumed = applied_before || started_before;
+                if has_been_created {
+                    let resolution = if resumed {
+                        Resolution::Overwrite
+                    } else if !entry.should_exist && !r.may_pre_exist() {
+                        match r.resolve_existing(system) {
+                            Some(resolution) => resolution,
+                            None => {
+                                let conflict = Conflict {
+                                    requirement: *r,
+                                    existing: r.diff(system),
+                                };
+                                resolution.resolve(&conflict)
+                            }
+                        }
+                    } else {
+                        Resolution::Overwrite
+                    };
+
+                    if resolution == Resolution::Abort {
+                        return Err(self.failure(
+                            Position::Todo(index),
+                            result,
+                            RequirementOperationError::PreExisting,
+                        ));
+                    }
 
-        let cmp = next.compare_with(&mut sys, &prev).unwrap();
-        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+                    if !entry.created_by_us && !resumed {
+                        result.pre_existing.push(entry.source);
+                    }
+
+                    // Adopted state is backed up as well, because undoing the requirement restores it from the backup
+                    if resolution == Resolution::Adopt {
+                        r.backup_existing(system).map_err(|inner| {
+                            self.failure(
+                                Position::Todo(index),
+                                result,
+                                RequirementOperationError::ModifyFailed { inner },
+                            )
+                        })?;
+
+                        info!("  adopted: {}", r);
+                        return Ok(());
+                    }
+
+                    self.record(system, result, ProgressEvent::Started(entry.source.0));
+                    if !entry.should_exist {
+                        r.backup_existing(system).map_err(|inner| {
+                            self.failure(
+                                Position::Todo(index),
+                                result,
+                                RequirementOperationError::ModifyFailed { inner },
+                            )
+                        })?;
+                    }
+
+                    limits
+                        .attempt::<R, S, _, _>(r, started, || r.modify(system))
+                        .map_err(|inner| {
+                            self.failure(
+                                Position::Todo(index),
+                                result,
+                                RequirementOperationError::ModifyFailed { inner },
+                            )
+                        })?;
+                } else {
+                    changed = true;
+                    self.record(system, result, ProgressEvent::Started(entry.source.0));
+                    limits
+                        .attempt::<R, S, _, _>(r, started, || r.create(system))
+                        .map_err(|inner| {
+                            self.failure(
+                                Position::Todo(index),
+                                result,
+                                RequirementOperationError::CreateFailed { inner },
+                            )
+                        })?;
+                }
+            }
+            Err(inner) => {
+                return Err(self.failure(
+                    Position::Todo(index),
+                    result,
+                    RequirementOperationError::UnableToCheck { inner },
+                ))
+            }
+        }
+
+        if let Some(inner) = limits.check_node(node_started) {
+            let mut err = self.failure(Position::Todo(index), result, inner);
+            // The requirement has been applied, so it must be reverted as well
+            err.revert_info.position = Position::Todo(index + 1);
+            return Err(err);
+        }
+
+        if changed {
+            audit::requirement_changed(system, ChangeAction::Applied, r);
+        }
+
+        Ok(())
+    }
+
+    fn run_undo<S: System>(
+        &self,
+        system: &mut S,
+        entries: &[Undo<'r, R>],
+        position: fn(usize) -> Position,
+        result: &ApplyResult,
+        limits: &ApplyLimits,
+        started: Instant,
+    ) -> Result<(), RunError<R, S>> {
+        for (index, entry) in entries.iter().enumerate() {
+            if let Some(inner) = limits.check(started) {
+                return Err(self.failure(position(index), result, inner));
+            }
+
+            let _span = info_span!(
+                "requirement",
+                action = "undo",
+                kind = entry.requirement.kind(),
+                requirement = %entry.requirement
+            )
+            .entered();
+            let id = NodeId::of(entry.requirement);
+            if self
+                .resumed()
+                .is_some_and(|progress| progress.undone.contains(&id))
+            {
+                info!("  already undone: {}", entry.requirement);
+                continue;
+            }
+
+            info!("  undo: {}", entry.requirement);
+            audit::set_node(system, Some(id.clone()));
+            if !entry.pre_existing {
+                self.backup(system, entry.requirement).map_err(|inner| {
+                    self.failure(
+                        position(index),
+                        result,
+                        RequirementOperationError::BackupFailed { inner },
+                    )
+                })?;
+            }
+
+            limits
+                .attempt::<R, S, _, _>(entry.requirement, started, || {
+                    if entry.pre_existing {
+                        entry.requirement.pre_existing_delete(system)
+                    } else {
+                        entry.requirement.delete(system)
+                    }
+                })
+                .map_err(|inner| {
+                    self.failure(
+                        position(index),
+                        result,
+                        RequirementOperationError::DeleteFailed { inner },
+                    )
+                })?;
+            audit::requirement_changed(system, ChangeAction::Undone, entry.requirement);
+            self.record(system, result, ProgressEvent::Undone(id));
+        }
+
+        Ok(())
+    }
+
+    /// Backs up the data that undoing `requirement` would destroy, if the sequence has a backups directory.
+    fn backup<S: System>(&self, system: &mut S, requirement: &R) -> Result<(), BackupError<S>> {
+        if let Some(backups) = &self.backups {
+            let to = backups.join(NodeId::of(requirement).as_str());
+            if requirement.backup(system, &to)? {
+                info!("  backed up {} to {}", requirement, to.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn revert<S: System>(
+        &self,
+        system: &mut S,
+        info: &RevertInfo,
+    ) -> Result<(), RunError<R, S>> {
+        let num_todo = match info.position {
+            Position::Undo(_) => 0,
+            Position::Todo(index) => index,
+            Position::Cleanup(_) => self.todo.len(),
+        };
+
+        // We need to undo any changes that won't be overwritten by re-applying the previous graph
+        for entry in self.todo.iter().take(num_todo).rev() {
+            if info.skipped.contains(&entry.source) {
+                continue;
+            }
+
+            if !self
+                .prev
+                .nodes
+                .iter()
+                .any(|n| n.requirement.affects(entry.requirement))
+            {
+                let _span = info_span!(
+                    "requirement",
+                    action = "revert",
+                    kind = entry.requirement.kind(),
+                    requirement = %entry.requirement
+                )
+                .entered();
+                info!("  undo: {}", entry.requirement);
+                audit::set_node(system, Some(self.target[entry.source.0].id()));
+                if entry.requirement.can_undo() {
+                    if info.pre_existing.contains(&entry.source) {
+                        entry.requirement.pre_existing_delete(system)
+                    } else {
+                        entry.requirement.delete(system)
+                    }
+                    .unwrap();
+                    audit::requirement_changed(system, ChangeAction::Reverted, entry.requirement);
+                }
+            }
+        }
+
+        let fix_sequence = self.prev.generate_fix_sequence(system).unwrap();
+        let _ = fix_sequence
+            .run(system, &ConflictPolicy::Always(Resolution::Abort))
+            .unwrap();
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum VerificationState<'r, R> {
+    Ok,
+    Invalid {
+        invalid: Vec<&'r R>,
+
+        /// Why each of the `invalid` requirements failed verification, in the same order.
+        failures: Vec<VerificationFailure>,
+    },
+}
+
+impl<'r, R: Display> Display for VerificationState<'r, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerificationState::Ok => write!(f, "all OK")?,
+            VerificationState::Invalid { invalid, failures } => {
+                for (item, failure) in invalid.iter().zip(failures.iter()) {
+                    writeln!(f, "corrupted: {} ({})", item, failure)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'r, R> VerifySequence<'r, R> {
+    /// Only verifies the requirements for which `selected` is true. `selected` contains an entry for every node of the graph.
+    pub fn select(self, selected: &[bool]) -> Self {
+        VerifySequence {
+            items: self
+                .items
+                .into_iter()
+                .zip(selected)
+                .filter(|(_, &selected)| selected)
+                .map(|(item, _)| item)
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+impl<'r, R: Requirement + Display> VerifySequence<'r, R> {
+    /// Verifies all requirements. The checks that the requirements list in `verify_probes` are prefetched in a single batch.
+    pub fn run<S: System>(self, system: &mut S) -> Result<VerificationState<'r, R>, ()> {
+        let _span = info_span!("verify", requirements = self.items.len()).entered();
+        let probes = self
+            .items
+            .iter()
+            .flat_map(|entry| entry.verify_probes())
+            .collect::<Vec<_>>();
+        let mut system = BatchedSystem::new(system);
+        if let Err(e) = system.prefetch(&probes) {
+            warn!(
+                "Unable to prefetch verification checks, checking one by one: {}",
+                e
+            );
+        }
+
+        let mut invalid = Vec::new();
+        let mut failures = Vec::new();
+        for entry in self.items {
+            let _span = info_span!(
+                "requirement",
+                action = "verify",
+                kind = entry.kind(),
+                requirement = %entry
+            )
+            .entered();
+            if entry.verify(&mut system)? {
+                info!("  ok: {}", entry);
+            } else {
+                let failure = entry.verification_failure(&mut system);
+                warn!("  invalid: {} ({})", entry, failure);
+                invalid.push(entry);
+                failures.push(failure);
+            }
+        }
+
+        Ok(if invalid.len() > 0 {
+            VerificationState::Invalid { invalid, failures }
+        } else {
+            VerificationState::Ok
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        audit::{ChangeAction, Recorder},
+        builder::fs::CreateDirectory,
+        conflict::{ConflictPolicy, Resolution},
+        graph::{
+            Applied, ApplyLimits, ApplyResult, ApplyStrategy, Do, GraphNodeReference, GraphWalker,
+            NodeId, PackageOutcome, Pending, RequirementOperationError, Undo,
+        },
+        requirements::{BackupError, FailureHint, Resource},
+        resume::ApplyProgress,
+        retry::{RetryPolicy, RetrySettings},
+        system::LocalSystem,
+        testing::{failing::FailingSystem, TempDir},
+    };
+    use serde::{Deserialize, Serialize};
+    use std::{
+        collections::HashSet, fmt::Display, path::PathBuf, sync::atomic::Ordering, time::Duration,
+    };
+
+    use super::{ChangeKind, Graph, Requirement, System};
+
+    const ABORT: ConflictPolicy = ConflictPolicy::Always(Resolution::Abort);
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Foo {
+        id: u64,
+        can_undo: bool,
+    }
+
+    impl Foo {
+        const ROOT: Foo = Foo {
+            id: 0,
+            can_undo: true,
+        };
+        const ROOT_NOUNDO: Foo = Foo {
+            id: 0,
+            can_undo: false,
+        };
+        const A: Foo = Foo {
+            id: 1,
+            can_undo: true,
+        };
+        const A_NOUNDO: Foo = Foo {
+            id: 1,
+            can_undo: false,
+        };
+        const B: Foo = Foo {
+            id: 2,
+            can_undo: true,
+        };
+        const B_NOUNDO: Foo = Foo {
+            id: 2,
+            can_undo: false,
+        };
+        const C: Foo = Foo {
+            id: 3,
+            can_undo: true,
+        };
+        const C_NOUNDO: Foo = Foo {
+            id: 3,
+            can_undo: false,
+        };
+        const D: Foo = Foo {
+            id: 4,
+            can_undo: true,
+        };
+        const END: Foo = Foo {
+            id: 100,
+            can_undo: true,
+        };
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct AlwaysFail;
+
+    impl Display for AlwaysFail {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "AlwaysFail")
+        }
+    }
+
+    impl Requirement for AlwaysFail {
+        const NAME: &'static str = "alwaysfail";
+        const DOCS_URL: Option<&'static str> = Some("https://example.com/alwaysfail");
+        const FAILURE_HINTS: &'static [FailureHint] = &[
+            FailureHint {
+                pattern: "Error",
+                hint: "It always fails.",
+            },
+            FailureHint {
+                pattern: "not found",
+                hint: "It was never there.",
+            },
+        ];
+
+        type CreateError<S: System> = FakeError;
+        type ModifyError<S: System> = FakeError;
+        type DeleteError<S: System> = FakeError;
+        type HasBeenCreatedError<S: System> = FakeError;
+
+        fn create<S: System>(&self, _system: &mut S) -> Result<(), Self::CreateError<S>> {
+            Err(FakeError)
+        }
+        fn modify<S: System>(&self, _system: &mut S) -> Result<(), Self::ModifyError<S>> {
+            Err(FakeError)
+        }
+        fn delete<S: System>(&self, _system: &mut S) -> Result<(), Self::DeleteError<S>> {
+            Err(FakeError)
+        }
+
+        fn has_been_created<S: System>(
+            &self,
+            _system: &mut S,
+        ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+            Err(FakeError)
+        }
+
+        fn affects(&self, _other: &Self) -> bool {
+            true
+        }
+        fn supports_modifications(&self) -> bool {
+            false
+        }
+        fn can_undo(&self) -> bool {
+            true
+        }
+        fn may_pre_exist(&self) -> bool {
+            false
+        }
+        fn verify<S: System>(&self, _system: &mut S) -> Result<bool, ()> {
+            Ok(true)
+        }
+
+        fn preflight<S: System>(&self, _system: &mut S) -> Vec<String> {
+            vec![String::from("always fails")]
+        }
+    }
+
+    /// Holds a uid exclusively, so that it conflicts with other `Exclusive`s with the same uid.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Exclusive {
+        id: u64,
+        uid: u32,
+    }
+
+    impl Display for Exclusive {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Exclusive({}, {})", self.id, self.uid)
+        }
+    }
+
+    impl Requirement for Exclusive {
+        const NAME: &'static str = "exclusive";
+
+        type CreateError<S: System> = S::Error;
+        type ModifyError<S: System> = FakeError;
+        type DeleteError<S: System> = S::Error;
+        type HasBeenCreatedError<S: System> = S::Error;
+
+        fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+            system.copy_file(&PathBuf::new(), &PathBuf::from(format!("x{}", self.id)))
+        }
+        fn modify<S: System>(&self, _system: &mut S) -> Result<(), Self::ModifyError<S>> {
+            Ok(())
+        }
+        fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+            system.remove_file(&PathBuf::from(format!("x{}", self.id)))
+        }
+
+        fn has_been_created<S: System>(
+            &self,
+            system: &mut S,
+        ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+            system.path_exists(&PathBuf::from(format!("x{}", self.id)))
+        }
+
+        fn affects(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+        fn supports_modifications(&self) -> bool {
+            false
+        }
+        fn can_undo(&self) -> bool {
+            true
+        }
+        fn may_pre_exist(&self) -> bool {
+            false
+        }
+        fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+            self.has_been_created(system).map_err(|_| ())
+        }
+
+        fn exclusive_resources(&self) -> Vec<Resource> {
+            vec![Resource::Uid(self.uid)]
+        }
+    }
+
+    /// Fails to be created the first `failures` times, like apt waiting for the dpkg lock.
+    /// The attempts are recorded as files on the system.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Flaky {
+        failures: usize,
+    }
+
+    impl Display for Flaky {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Flaky({})", self.failures)
+        }
+    }
+
+    impl Requirement for Flaky {
+        const NAME: &'static str = "flaky";
+
+        type CreateError<S: System> = FakeError;
+        type ModifyError<S: System> = FakeError;
+        type DeleteError<S: System> = S::Error;
+        type HasBeenCreatedError<S: System> = S::Error;
+
+        fn create<S: System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+            let attempts = (0..)
+                .take_while(|n| {
+                    system
+                        .path_exists(&PathBuf::from(format!("attempt{}", n)))
+                        .unwrap()
+                })
+                .count();
+            if attempts < self.failures {
+                system
+                    .copy_file(
+                        &PathBuf::new(),
+                        &PathBuf::from(format!("attempt{}", attempts)),
+                    )
+                    .unwrap();
+                Err(FakeError)
+            } else {
+                system
+                    .copy_file(&PathBuf::new(), &PathBuf::from("flaky"))
+                    .map_err(|_| FakeError)
+            }
+        }
+        fn modify<S: System>(&self, _system: &mut S) -> Result<(), Self::ModifyError<S>> {
+            Ok(())
+        }
+        fn delete<S: System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+            system.remove_file(&PathBuf::from("flaky"))
+        }
+
+        fn has_been_created<S: System>(
+            &self,
+            system: &mut S,
+        ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+            system.path_exists(&PathBuf::from("flaky"))
+        }
+
+        fn affects(&self, _other: &Self) -> bool {
+            true
+        }
+        fn supports_modifications(&self) -> bool {
+            false
+        }
+        fn can_undo(&self) -> bool {
+            true
+        }
+        fn may_pre_exist(&self) -> bool {
+            false
+        }
+        fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+            self.has_been_created(system).map_err(|_| ())
+        }
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("Error")]
+    struct FakeError;
+
+    impl Requirement for Foo {
+        type CreateError<S: System> = S::Error;
+        type ModifyError<S: System> = FakeError;
+        type DeleteError<S: System> = S::Error;
+        type HasBeenCreatedError<S: System> = S::Error;
+
+        fn create<S: super::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+            Ok(system.copy_file(&PathBuf::new(), &PathBuf::from(format!("{}", self.id)))?)
+        }
+
+        fn modify<S: super::System>(&self, _system: &mut S) -> Result<(), Self::ModifyError<S>> {
+            Ok(())
+        }
+
+        fn delete<S: super::System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+            Ok(system.remove_file(&PathBuf::from(format!("{}", self.id)))?)
+        }
+
+        fn has_been_created<S: super::System>(
+            &self,
+            system: &mut S,
+        ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+            Ok(system.path_exists(&PathBuf::from(format!("{}", self.id)))?)
+        }
+
+        fn affects(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+
+        fn supports_modifications(&self) -> bool {
+            false
+        }
+
+        fn can_undo(&self) -> bool {
+            self.can_undo
+        }
+
+        fn may_pre_exist(&self) -> bool {
+            false
+        }
+
+        fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()> {
+            self.has_been_created(system).map_err(|_| ())
+        }
+
+        fn backup<S: System>(
+            &self,
+            system: &mut S,
+            to: &std::path::Path,
+        ) -> Result<bool, BackupError<S>> {
+            system
+                .copy_file(&PathBuf::from(format!("{}", self.id)), to)
+                .map_err(|e| BackupError::UnableToWrite(to.to_owned(), e))?;
+            Ok(true)
+        }
+
+        const NAME: &'static str = "foo";
+    }
+
+    impl std::fmt::Display for Foo {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            std::fmt::Debug::fmt(self, f)
+        }
+    }
+
+    #[derive(Debug)]
+    struct FakeSystem {
+        created: HashSet<PathBuf>,
+        recorder: Option<Recorder>,
+    }
+
+    impl System for FakeSystem {
+        type Error = FakeError;
+        type CommandError = FakeError;
+
+        fn path_exists(&self, path: &std::path::Path) -> Result<bool, Self::Error> {
+            Ok(self.created.contains(&path.to_path_buf()))
+        }
+
+        fn path_is_dir(&self, _path: &std::path::Path) -> Result<bool, Self::Error> {
+            todo!()
+        }
+
+        fn file_contents(&self, _path: &std::path::Path) -> Result<Vec<u8>, Self::Error> {
+            todo!()
+        }
+
+        fn execute_command(
+            &self,
+            _path: &str,
+            _args: &[&str],
+        ) -> Result<crate::system::CommandResult, Self::Error> {
+            todo!()
+        }
+
+        fn copy_file(
+            &mut self,
+            _from: &std::path::Path,
+            to: &std::path::Path,
+        ) -> Result<(), Self::Error> {
+            self.created.insert(to.to_path_buf());
+
+            Ok(())
+        }
+
+        fn symlink(
+            &mut self,
+            _target: &std::path::Path,
+            link: &std::path::Path,
+        ) -> Result<(), Self::Error> {
+            self.created.insert(link.to_path_buf());
+
+            Ok(())
+        }
+
+        fn make_dir(&mut self, path: &std::path::Path) -> Result<(), Self::Error> {
+            self.created.insert(path.to_path_buf());
+
+            Ok(())
+        }
+
+        fn remove_dir(&mut self, path: &std::path::Path) -> Result<(), Self::Error> {
+            self.created.retain(|item| item != path);
+
+            Ok(())
+        }
+
+        fn remove_file(&mut self, path: &std::path::Path) -> Result<(), Self::Error> {
+            self.created.retain(|item| item != path);
+
+            Ok(())
+        }
+
+        fn get_user(&mut self, _name: &str) -> Result<Option<()>, Self::Error> {
+            todo!()
+        }
+
+        fn execute_command_with_input(
+            &self,
+            _path: &str,
+            _args: &[&str],
+            _input: &[u8],
+        ) -> Result<crate::system::CommandResult, Self::CommandError> {
+            todo!()
+        }
+
+        fn chmod(&mut self, _path: &std::path::Path, _mode: u32) -> Result<(), Self::Error> {
+            todo!()
+        }
+
+        fn put_file_contents(
+            &self,
+            _path: &std::path::Path,
+            _contents: &[u8],
+        ) -> Result<(), Self::Error> {
+            todo!()
+        }
+
+        fn make_dir_all(&mut self, _path: &std::path::Path) -> Result<(), Self::Error> {
+            todo!()
+        }
+
+        fn dir_is_empty(&mut self, _path: &std::path::Path) -> Result<bool, Self::Error> {
+            todo!()
+        }
+
+        fn read_dir(&mut self, _path: &std::path::Path) -> Result<Vec<String>, Self::Error> {
+            todo!()
+        }
+
+        fn recorder(&self) -> Option<&Recorder> {
+            self.recorder.as_ref()
+        }
+
+        fn set_recorder(&mut self, recorder: Option<Recorder>) -> Option<Recorder> {
+            std::mem::replace(&mut self.recorder, recorder)
+        }
+    }
+
+    #[test]
+    pub fn invert() {
+        let mut g = Graph::<Foo, Pending>::new();
+        let root = g.add(Foo::ROOT, &[]);
+        let a = g.add(Foo::A, &[root]);
+        let b = g.add(Foo::B, &[root]);
+        let c = g.add(Foo::C, &[a, root]);
+        let _end = g.add(Foo::END, &[b, c]);
+
+        println!("graph    : {:?}", g);
+
+        let g = g.invert();
+
+        println!("inv      : {:?}", g);
+
+        let mut expected = Graph::<Foo, Pending>::new();
+        let end = expected.add(Foo::END, &[]);
+        let c = expected.add(Foo::C, &[end]);
+        let b = expected.add(Foo::B, &[end]);
+        let a = expected.add(Foo::A, &[c]);
+        let _root = expected.add(Foo::ROOT, &[c, b, a]);
+
+        println!("expected : {:?}", expected);
+        assert_eq!(g, expected);
+    }
+
+    #[test]
+    pub fn retain_all_but_one() {
+        let mut g = Graph::<Foo, Pending>::new();
+        let root = g.add(Foo::ROOT, &[]);
+        let a = g.add(Foo::A, &[root]);
+        let b = g.add(Foo::B, &[root]);
+        let c = g.add(Foo::C, &[a, root]);
+        let _end = g.add(Foo::END, &[b, c]);
+
+        println!("graph    : {:?}", g);
+
+        g.retain(|_, f| f.requirement != Foo::C);
+
+        println!("retained : {:?}", g);
+
+        let mut expected = Graph::<Foo, Pending>::new();
+        let root = expected.add(Foo::ROOT, &[]);
+        let a = expected.add(Foo::A, &[root]);
+        let b = expected.add(Foo::B, &[root]);
+        let _end = expected.add(Foo::END, &[b, a, root]);
+
+        println!("expected : {:?}", expected);
+        assert_eq!(g, expected);
+    }
+
+    #[test]
+    pub fn retain_all_but_two() {
+        let mut g = Graph::<Foo, Pending>::new();
+        let root = g.add(Foo::ROOT, &[]);
+        let a = g.add(Foo::A, &[root]);
+        let b = g.add(Foo::B, &[a]);
+        let c = g.add(Foo::C, &[a, root]);
+        let _end = g.add(Foo::END, &[b, c]);
+
+        println!("graph    : {:?}", g);
+
+        g.retain(|_, f| f.requirement != Foo::A && f.requirement != Foo::B);
+
+        println!("retained : {:?}", g);
+
+        let mut expected = Graph::<Foo, Pending>::new();
+        let root = expected.add(Foo::ROOT, &[]);
+        let c = expected.add(Foo::C, &[root]);
+        let _end = expected.add(Foo::END, &[c, root]);
+
+        println!("expected : {:?}", expected);
+        assert_eq!(g, expected);
+    }
+
+    #[test]
+    pub fn retain_long_chain() {
+        let mut g = Graph::<Foo, Pending>::new();
+        let root = g.add(Foo::ROOT, &[]);
+        let mut last = root;
+        for id in 1_000..21_000 {
+            let foo = Foo { id, can_undo: true };
+            last = g.add(foo, &[last, root]);
+        }
+        let _end = g.add(Foo::END, &[last]);
+
+        g.retain(|_, f| f.requirement == Foo::ROOT || f.requirement == Foo::END);
+
+        let mut expected = Graph::<Foo, Pending>::new();
+        let root = expected.add(Foo::ROOT, &[]);
+        let _end = expected.add(Foo::END, &[root]);
+        assert_eq!(g, expected);
+    }
+
+    #[test]
+    pub fn retain_forward_preconditions() {
+        // Graphs read from a database may have preconditions that refer to later nodes
+        let mut g = Graph::<Foo, Pending>::new();
+        g.add(Foo::END, &[]);
+        for id in 1..=2 {
+            g.add(Foo { id, can_undo: true }, &[]);
+        }
+        g.add(Foo::ROOT, &[]);
+        g.nodes[0].preconditions = vec![1];
+        g.nodes[1].preconditions = vec![2];
+        g.nodes[2].preconditions = vec![3];
+
+        g.retain(|_, f| f.requirement == Foo::ROOT || f.requirement == Foo::END);
+
+        assert_eq!(g.len(), 2);
+        assert_eq!(g.nodes[0].requirement, Foo::END);
+        assert_eq!(g.nodes[0].preconditions, vec![1]);
+        assert!(g.nodes[1].preconditions.is_empty());
+    }
+
+    #[test]
+    pub fn node_ids_are_stable_across_versions() {
+        crate::requirements!(NodeTy = Foo, AlwaysFail);
+
+        let mut v1 = Graph::<NodeTy, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let _a = v1.add(Foo::A, &[root]);
+
+        let mut v2 = Graph::<NodeTy, Pending>::new();
+        let root = v2.add(Foo::ROOT, &[]);
+        let b = v2.add(Foo::B, &[root]);
+        let _a = v2.add(Foo::A, &[b]);
+
+        let id = v1.nodes[1].id();
+        assert_eq!(id, NodeId::of(&Foo::A));
+        assert_eq!(v2.find(&id).map(|(r, _)| r), Some(GraphNodeReference(2)));
+        assert_eq!(v2.find(&NodeId::of(&AlwaysFail)), None);
+
+        // Graphs saved by older versions do not contain the ids
+        let mut old = v1.clone();
+        old.nodes[1].id = None;
+        assert_eq!(old.nodes[1].id(), id);
+    }
+
+    #[test]
+    pub fn trivial_sequence() {
+        let prev = Graph::<Foo, Applied>::new();
+        let mut next = Graph::<Foo, Pending>::new();
+        let root = next.add(Foo::ROOT, &[]);
+        let a = next.add(Foo::A, &[root]);
+        let b = next.add(Foo::B, &[root]);
+        let c = next.add(Foo::C, &[a, root]);
+        let _end = next.add(Foo::END, &[b, c]);
+
+        println!("prev       : {:?}", prev);
+        println!("next       : {:?}", next);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = next.compare_with(&mut sys, &prev).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+
+        assert_eq!(seq.undo, vec![]);
+
+        assert_eq!(
+            seq.todo,
+            vec![
+                Do {
+                    created_by_us: false,
+                    should_exist: false,
+                    source: GraphNodeReference(0),
+                    requirement: &Foo::ROOT,
+                },
+                Do {
+                    created_by_us: false,
+                    should_exist: false,
+                    source: GraphNodeReference(1),
+                    requirement: &Foo::A,
+                },
+                Do {
+                    created_by_us: false,
+                    should_exist: false,
+                    source: GraphNodeReference(2),
+                    requirement: &Foo::B,
+                },
+                Do {
+                    created_by_us: false,
+                    should_exist: false,
+                    source: GraphNodeReference(3),
+                    requirement: &Foo::C,
+                },
+                Do {
+                    created_by_us: false,
+                    should_exist: false,
+                    source: GraphNodeReference(4),
+                    requirement: &Foo::END,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    pub fn trivial_sequence_backwards() {
+        let mut prev = Graph::<Foo, Pending>::new();
+        let root = prev.add(Foo::ROOT, &[]);
+        let a = prev.add(Foo::A_NOUNDO, &[root]);
+        let b = prev.add(Foo::B_NOUNDO, &[root]);
+        let c = prev.add(Foo::C, &[a, root]);
+        let _end = prev.add(Foo::END, &[b, c]);
+        let prev = Graph {
+            nodes: prev.nodes,
+            state: Applied,
+            checkpoints: prev.checkpoints,
+        };
+
+        let next = Graph::<Foo, Pending>::new();
+
+        println!("prev       : {:?}", prev);
+        println!("next       : {:?}", next);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = next.compare_with(&mut sys, &prev).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+
+        assert_eq!(
+            seq.undo,
+            vec![
+                Undo {
+                    pre_existing: false,
+                    requirement: &Foo::END,
+                },
+                Undo {
+                    pre_existing: false,
+                    requirement: &Foo::C,
+                },
+                Undo {
+                    pre_existing: false,
+                    requirement: &Foo::ROOT,
+                },
+            ]
+        );
+
+        assert_eq!(seq.todo, vec![]);
+    }
+
+    #[test]
+    pub fn walk_in_dependency_order() {
+        let mut graph = Graph::<Foo, Pending>::new();
+        let root = graph.add(Foo::ROOT, &[]);
+        let a = graph.add(Foo::A, &[root]);
+        graph.add(Foo::B, &[root]);
+        graph.add(Foo::C, &[a]);
+
+        let mut walker = GraphWalker::new(&graph).unwrap();
+        let mut order = Vec::new();
+        while let Some((index, _)) = walker.next() {
+            order.push(index);
+        }
+
+        // The node that was added last is visited first once its preconditions are fulfilled
+        assert_eq!(order, vec![0, 2, 1, 3]);
+
+        let mut chain = Graph::<Foo, Pending>::new();
+        let mut previous = chain.add(
+            Foo {
+                id: 0,
+                can_undo: true,
+            },
+            &[],
+        );
+        for id in 1..20_000 {
+            previous = chain.add(Foo { id, can_undo: true }, &[previous]);
+        }
+
+        let mut walker = GraphWalker::new(&chain).unwrap();
+        let mut expected = 0;
+        while let Some((index, _)) = walker.next() {
+            assert_eq!(index, expected);
+            expected += 1;
+        }
+        assert_eq!(expected, 20_000);
+    }
+
+    #[test]
+    pub fn canonical_sequence() {
+        let mut first = Graph::<Foo, Pending>::new();
+        let root = first.add(Foo::ROOT, &[]);
+        let a = first.add(Foo::A, &[root]);
+        first.add(Foo::B, &[root]);
+        first.add(Foo::C, &[a]);
+        first.add(Foo::END, &[]);
+
+        // The same requirements, with the independent nodes added in a different order
+        let mut second = Graph::<Foo, Pending>::new();
+        second.add(Foo::END, &[]);
+        let root = second.add(Foo::ROOT, &[]);
+        second.add(Foo::B, &[root]);
+        let a = second.add(Foo::A, &[root]);
+        second.add(Foo::C, &[a]);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+        let v0 = Graph::<Foo, Applied>::new();
+        let sequence = |graph: &Graph<Foo, Pending>, sys: &mut FakeSystem| {
+            let cmp = graph.compare_with(sys, &v0).unwrap();
+            let seq = cmp.generate_application_sequence(sys).unwrap();
+            seq.todo
+                .iter()
+                .map(|todo| todo.requirement.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // Identities are compared as strings, so `{"id":100,..}` comes before `{"id":2,..}`
+        let expected = vec![Foo::ROOT, Foo::A, Foo::END, Foo::B, Foo::C];
+        assert_eq!(sequence(&first, &mut sys), expected);
+        assert_eq!(sequence(&second, &mut sys), expected);
+    }
+
+    #[test]
+    pub fn detect_cycles() {
+        let mut graph = Graph::<Foo, Pending>::new();
+        let root = graph.add(Foo::ROOT, &[]);
+        let a = graph.add(Foo::A, &[root]);
+        let b = graph.add(Foo::B, &[a]);
+        graph.add(Foo::C, &[b]);
+        graph.nodes[0].preconditions.push(2);
+
+        let err = GraphWalker::new(&graph).err().unwrap();
+        assert_eq!(err.nodes, vec![0, 1, 2]);
+        assert_eq!(
+            err.to_string(),
+            "the requirements depend on each other in a cycle: Foo { id: 0, can_undo: true }, Foo { id: 1, can_undo: true }, Foo { id: 2, can_undo: true }"
+        );
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+        let v0 = Graph::<Foo, Applied>::new();
+        let cmp = graph.compare_with(&mut sys, &v0).unwrap();
+        assert_eq!(cmp.generate_application_sequence(&mut sys).err(), Some(err));
+    }
+
+    #[test]
+    pub fn export_dot() {
+        let mut graph = Graph::<Foo, Pending>::new();
+        let root = graph.add(Foo::ROOT, &[]);
+        let a = graph.add(Foo::A, &[root]);
+        graph.add(Foo::B, &[root, a]);
+        graph.assign_package(1..3, "www");
+
+        assert_eq!(
+            graph.to_dot(),
+            "digraph install {
+    node [shape=box];
+    n0 [label=\"Foo { id: 0, can_undo: true }\"];
+    subgraph \"cluster_www\" {
+        label=\"www\";
+        n1 [label=\"Foo { id: 1, can_undo: true }\"];
+        n2 [label=\"Foo { id: 2, can_undo: true }\"];
+    }
+    n0 -> n1;
+    n0 -> n2;
+    n1 -> n2;
+}
+"
+        );
+        assert_eq!(super::dot_string("say \"hi\"\n"), r#""say \"hi\"\n""#);
+    }
+
+    #[test]
+    pub fn plan() {
+        let mut prev = Graph::<Foo, Pending>::new();
+        let root = prev.add(Foo::ROOT, &[]);
+        prev.add(Foo::A, &[root]);
+        prev.add(Foo::B, &[root]);
+        let prev = Graph {
+            nodes: prev.nodes,
+            state: Applied,
+            checkpoints: prev.checkpoints,
+        };
+
+        let mut next = Graph::<Foo, Pending>::new();
+        let root = next.add(Foo::ROOT, &[]);
+        next.add(Foo::A_NOUNDO, &[root]);
+        next.add(Foo::C, &[root]);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = next.compare_with(&mut sys, &prev).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let plan = seq.plan();
+
+        assert_eq!(plan.undo(), &[&Foo::B]);
+        assert_eq!(plan.create(), &[&Foo::C]);
+        assert_eq!(plan.update(), &[&Foo::A_NOUNDO]);
+        assert_eq!(plan.unchanged(), 1);
+        assert!(!plan.is_empty());
+    }
+
+    #[test]
+    pub fn changes() {
+        let mut prev = Graph::<Foo, Pending>::new();
+        let root = prev.add(Foo::ROOT, &[]);
+        prev.add(Foo::A, &[root]);
+        prev.add(Foo::B, &[root]);
+        prev.assign_package(0..3, "base");
+        let prev = Graph {
+            nodes: prev.nodes,
+            state: Applied,
+            checkpoints: prev.checkpoints,
+        };
+
+        let mut next = Graph::<Foo, Pending>::new();
+        let root = next.add(Foo::ROOT, &[]);
+        next.add(Foo::A_NOUNDO, &[root]);
+        next.add(Foo::C, &[root]);
+        next.assign_package(0..2, "base");
+        next.assign_package(2..3, "app");
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = next.compare_with(&mut sys, &prev).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let changes = seq
+            .changes()
+            .into_iter()
+            .map(|c| (c.kind, c.requirement, c.previous, c.package))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            changes,
+            vec![
+                (ChangeKind::Undo, &Foo::B, None, Some("base")),
+                (ChangeKind::Unchanged, &Foo::ROOT, None, Some("base")),
+                (
+                    ChangeKind::Update,
+                    &Foo::A_NOUNDO,
+                    Some(&Foo::A),
+                    Some("base")
+                ),
+                (ChangeKind::Create, &Foo::C, None, Some("app")),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn plan_propagates_changes() {
+        let mut prev = Graph::<Foo, Pending>::new();
+        let root = prev.add(Foo::ROOT, &[]);
+        let a = prev.add(Foo::A, &[root]);
+        prev.add(Foo::B, &[a]);
+        prev.add(Foo::C, &[]);
+        let prev = Graph {
+            nodes: prev.nodes,
+            state: Applied,
+            checkpoints: prev.checkpoints,
+        };
+
+        let mut next = Graph::<Foo, Pending>::new();
+        let root = next.add(Foo::ROOT_NOUNDO, &[]);
+        let a = next.add(Foo::A, &[root]);
+        next.add(Foo::B, &[a]);
+        next.add(Foo::C, &[]);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = next.compare_with(&mut sys, &prev).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let plan = seq.plan();
+
+        assert!(plan.create().is_empty());
+        assert_eq!(plan.update(), &[&Foo::ROOT_NOUNDO, &Foo::A, &Foo::B]);
+        assert_eq!(plan.unchanged(), 1);
+    }
+
+    #[test]
+    pub fn inherited_preconditions() {
+        let mut prev = Graph::<Foo, Pending>::new();
+        let root = prev.add(Foo::ROOT_NOUNDO, &[]);
+        let a = prev.add(Foo::A_NOUNDO, &[root]);
+        let b = prev.add(Foo::B, &[a, root]);
+        let c = prev.add(Foo::C_NOUNDO, &[b]);
+        let _end = prev.add(Foo::END, &[b, c]);
+        let prev = prev.invert();
+        let prev = Graph {
+            nodes: prev.nodes,
+            state: Applied,
+            checkpoints: prev.checkpoints,
+        };
+
+        let next = Graph::<Foo, Pending>::new();
+
+        println!("prev       : {:?}", prev);
+        println!("next       : {:?}", next);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = next.compare_with(&mut sys, &prev).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+
+        assert_eq!(
+            seq.undo,
+            vec![
+                Undo {
+                    pre_existing: false,
+                    requirement: &Foo::B,
+                },
+                Undo {
+                    pre_existing: false,
+                    requirement: &Foo::END,
+                },
+            ]
+        );
+
+        assert_eq!(seq.todo, vec![]);
+    }
+
+    #[test]
+    pub fn normal_sequence() {
+        let mut prev = Graph::<Foo, Pending>::new();
+        let root = prev.add(Foo::ROOT, &[]);
+        let a = prev.add(Foo::A, &[root]);
+        let _c = prev.add(Foo::C, &[a, root]);
+        let _x = prev.add(Foo::D, &[root]);
+        let prev = prev.apply_execution_results(ApplyResult {
+            pre_existing: Vec::new(),
+        });
+
+        let mut next = Graph::<Foo, Pending>::new();
+        let root = next.add(Foo::ROOT, &[]);
+        let a = next.add(Foo::A, &[root]);
+        let b = next.add(Foo::B, &[root]);
+        let c = next.add(Foo::C, &[a, root]);
+        let _end = next.add(Foo::END, &[b, c]);
+
+        println!("prev       : {:?}", prev);
+        println!("next       : {:?}", next);
+
+        let mut sys = FakeSystem {
+            created: [PathBuf::from("4")].into_iter().collect(),
+            recorder: None,
+        };
+
+        let cmp = next.compare_with(&mut sys, &prev).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+
+        assert_eq!(
+            seq.undo,
+            vec![Undo {
+                pre_existing: false,
+                requirement: &Foo::D,
+            },]
+        );
+
+        assert_eq!(
+            seq.todo,
+            vec![
+                Do {
+                    created_by_us: true,
+                    should_exist: true,
+                    source: GraphNodeReference(0),
+                    requirement: &Foo::ROOT,
+                },
+                Do {
+                    created_by_us: true,
+                    should_exist: true,
+                    source: GraphNodeReference(1),
+                    requirement: &Foo::A,
+                },
+                Do {
+                    created_by_us: false,
+                    should_exist: false,
+                    source: GraphNodeReference(2),
+                    requirement: &Foo::B,
+                },
+                Do {
+                    created_by_us: true,
+                    should_exist: true,
+                    source: GraphNodeReference(3),
+                    requirement: &Foo::C,
+                },
+                Do {
+                    created_by_us: false,
+                    should_exist: false,
+                    source: GraphNodeReference(4),
+                    requirement: &Foo::END,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    pub fn apply() {
+        let v0 = Graph::<Foo, Applied>::new();
+        let mut v1 = Graph::<Foo, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let a = v1.add(Foo::A, &[root]);
+        let b = v1.add(Foo::B, &[root]);
+        let c = v1.add(Foo::C, &[a, root]);
+        let _end = v1.add(Foo::END, &[b, c]);
+
+        println!("prev       : {:?}", v0);
+        println!("next       : {:?}", v1);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let results = seq.run(&mut sys, &ABORT).unwrap();
+        let v1 = v1.apply_execution_results(results);
+
+        assert_eq!(
+            sys.created,
+            [
+                PathBuf::from("0"),
+                PathBuf::from("1"),
+                PathBuf::from("2"),
+                PathBuf::from("3"),
+                PathBuf::from("100"),
+            ]
+            .into_iter()
+            .collect()
+        );
 
-        assert_eq!(seq.undo, vec![]);
+        let mut v2 = Graph::<Foo, Pending>::new();
+        let root = v2.add(Foo::ROOT, &[]);
+        let a = v2.add(Foo::A, &[root]);
+        let c = v2.add(Foo::C, &[a, root]);
+        let _end = v2.add(Foo::END, &[c]);
+
+        println!("prev       : {:?}", v1);
+        println!("next       : {:?}", v2);
+
+        let cmp = v2.compare_with(&mut sys, &v1).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let results = seq.run(&mut sys, &ABORT).unwrap();
+        let _v2 = v2.apply_execution_results(results);
 
         assert_eq!(
-            seq.todo,
-            vec![
-                Do {
-                    created_by_us: false,
-                    should_exist: false,
-                    source: GraphNodeReference(0),
-                    requirement: &Foo::ROOT,
-                },
-                Do {
-                    created_by_us: false,
-                    should_exist: false,
-                    source: GraphNodeReference(2),
-                    requirement: &Foo::B,
-                },
-                Do {
-                    created_by_us: false,
-                    should_exist: false,
-                    source: GraphNodeReference(1),
-                    requirement: &Foo::A,
-                },
-                Do {
-                    created_by_us: false,
-                    should_exist: false,
-                    source: GraphNodeReference(3),
-                    requirement: &Foo::C,
-                },
-                Do {
-                    created_by_us: false,
-                    should_exist: false,
-                    source: GraphNodeReference(4),
-                    requirement: &Foo::END,
-                },
+            sys.created,
+            [
+                PathBuf::from("0"),
+                PathBuf::from("1"),
+                PathBuf::from("3"),
+                PathBuf::from("100"),
             ]
+            .into_iter()
+            .collect()
         );
     }
 
     #[test]
-    pub fn trivial_sequence_backwards() {
-        let mut prev = Graph::<Foo, Pending>::new();
-        let root = prev.add(Foo::ROOT, &[]);
-        let a = prev.add(Foo::A_NOUNDO, &[root]);
-        let b = prev.add(Foo::B_NOUNDO, &[root]);
-        let c = prev.add(Foo::C, &[a, root]);
-        let _end = prev.add(Foo::END, &[b, c]);
-        let prev = Graph {
-            nodes: prev.nodes,
-            state: Applied,
+    pub fn apply_records_changed_requirements() {
+        let v0 = Graph::<Foo, Applied>::new();
+        let mut v1 = Graph::<Foo, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let _a = v1.add(Foo::A, &[root]);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
         };
 
-        let next = Graph::<Foo, Pending>::new();
+        let recorder = Recorder::default();
+        sys.set_recorder(Some(recorder.clone()));
 
-        println!("prev       : {:?}", prev);
-        println!("next       : {:?}", next);
+        recorder.start_tracking();
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let results = seq.run(&mut sys, &ABORT).unwrap();
+        let v1 = v1.apply_execution_results(results);
+        let (_, changes) = recorder.stop_tracking();
+        assert_eq!(
+            changes
+                .iter()
+                .map(|change| (change.action, change.requirement.clone()))
+                .collect::<Vec<_>>(),
+            [
+                (ChangeAction::Applied, Foo::ROOT.to_string()),
+                (ChangeAction::Applied, Foo::A.to_string()),
+            ]
+        );
+
+        // The root is unchanged, so only the undo of A is recorded
+        let mut v2 = Graph::<Foo, Pending>::new();
+        v2.add(Foo::ROOT, &[]);
+
+        recorder.start_tracking();
+        let cmp = v2.compare_with(&mut sys, &v1).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let _ = seq.run(&mut sys, &ABORT).unwrap();
+        let (_, changes) = recorder.stop_tracking();
+        assert_eq!(
+            changes
+                .iter()
+                .map(|change| (change.action, change.requirement.clone()))
+                .collect::<Vec<_>>(),
+            [(ChangeAction::Undone, Foo::A.to_string())]
+        );
+    }
+
+    #[test]
+    pub fn undo_backs_up_data() {
+        let v0 = Graph::<Foo, Applied>::new();
+        let mut v1 = Graph::<Foo, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let _a = v1.add(Foo::A, &[root]);
 
         let mut sys = FakeSystem {
             created: Default::default(),
+            recorder: None,
         };
 
-        let cmp = next.compare_with(&mut sys, &prev).unwrap();
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
         let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let results = seq.run(&mut sys, &ABORT).unwrap();
+        let v1 = v1.apply_execution_results(results);
+
+        let mut v2 = Graph::<Foo, Pending>::new();
+        v2.add(Foo::ROOT, &[]);
+
+        let cmp = v2.compare_with(&mut sys, &v1).unwrap();
+        let seq = cmp
+            .generate_application_sequence(&mut sys)
+            .unwrap()
+            .with_backups(PathBuf::from("backups"));
+        let _results = seq.run(&mut sys, &ABORT).unwrap();
 
+        let backup = PathBuf::from("backups").join(NodeId::of(&Foo::A).as_str());
         assert_eq!(
-            seq.undo,
-            vec![
-                Undo {
-                    pre_existing: false,
-                    requirement: &Foo::END,
-                },
-                Undo {
-                    pre_existing: false,
-                    requirement: &Foo::C,
-                },
-                Undo {
-                    pre_existing: false,
-                    requirement: &Foo::ROOT,
-                },
+            sys.created,
+            [PathBuf::from("0"), backup].into_iter().collect()
+        );
+    }
+
+    #[test]
+    pub fn apply_revert() {
+        crate::requirements!(NodeTy = Foo, AlwaysFail);
+
+        let v0 = Graph::<NodeTy, Applied>::new();
+        let mut v1 = Graph::<NodeTy, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let a = v1.add(Foo::A, &[root]);
+        let b = v1.add(Foo::B, &[root]);
+        let c = v1.add(Foo::C, &[a, root]);
+        let _end = v1.add(Foo::END, &[b, c]);
+
+        println!("prev       : {:?}", v0);
+        println!("next       : {:?}", v1);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let results = seq.run(&mut sys, &ABORT).unwrap();
+        let v1 = v1.apply_execution_results(results);
+
+        assert_eq!(
+            sys.created,
+            [
+                PathBuf::from("0"),
+                PathBuf::from("1"),
+                PathBuf::from("2"),
+                PathBuf::from("3"),
+                PathBuf::from("100"),
             ]
+            .into_iter()
+            .collect()
         );
 
-        assert_eq!(seq.todo, vec![]);
+        let mut v2 = Graph::<NodeTy, Pending>::new();
+        let root = v2.add(Foo::ROOT, &[]);
+        let a = v2.add(Foo::A, &[root]);
+        let c = v2.add(Foo::C, &[a, root]);
+        let end = v2.add(Foo::END, &[c]);
+        let _fail = v2.add(AlwaysFail, &[end]);
+
+        println!("prev       : {:?}", v1);
+        println!("next       : {:?}", v2);
+
+        let cmp = v2.compare_with(&mut sys, &v1).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let err = seq.run(&mut sys, &ABORT).unwrap_err();
+        println!("Apply failed successfully");
+        println!("System state: {:?}", sys);
+        seq.revert(&mut sys, &err.revert_info).unwrap();
+
+        assert_eq!(
+            sys.created,
+            [
+                PathBuf::from("0"),
+                PathBuf::from("1"),
+                PathBuf::from("2"),
+                PathBuf::from("3"),
+                PathBuf::from("100"),
+            ]
+            .into_iter()
+            .collect()
+        );
     }
 
     #[test]
-    pub fn inherited_preconditions() {
-        let mut prev = Graph::<Foo, Pending>::new();
-        let root = prev.add(Foo::ROOT_NOUNDO, &[]);
-        let a = prev.add(Foo::A_NOUNDO, &[root]);
-        let b = prev.add(Foo::B, &[a, root]);
-        let c = prev.add(Foo::C_NOUNDO, &[b]);
-        let _end = prev.add(Foo::END, &[b, c]);
-        let prev = prev.invert();
-        let prev = Graph {
-            nodes: prev.nodes,
-            state: Applied,
+    pub fn apply_revert_after_failed_copy() {
+        let v0 = Graph::<Foo, Applied>::new();
+        let mut v1 = Graph::<Foo, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let _a = v1.add(Foo::A, &[root]);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
         };
 
-        let next = Graph::<Foo, Pending>::new();
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let results = seq.run(&mut sys, &ABORT).unwrap();
+        let v1 = v1.apply_execution_results(results);
+        let before = sys.created.clone();
 
-        println!("prev       : {:?}", prev);
-        println!("next       : {:?}", next);
+        let mut v2 = Graph::<Foo, Pending>::new();
+        let root = v2.add(Foo::ROOT, &[]);
+        let a = v2.add(Foo::A, &[root]);
+        let b = v2.add(Foo::B, &[root]);
+        let c = v2.add(Foo::C, &[a]);
+        let _end = v2.add(Foo::END, &[b, c]);
+
+        // Creating the second new requirement fails halfway through the apply
+        let mut sys = FailingSystem::new(sys).fail_copy(2);
+        let cmp = v2.compare_with(&mut sys, &v1).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let err = seq.run(&mut sys, &ABORT).unwrap_err();
+        assert_eq!(err.applied(), 3);
+        assert_eq!(err.remaining(), 2);
+        assert_eq!(sys.copies(), 2);
+        assert_eq!(sys.inner().created.len(), before.len() + 1);
+
+        seq.revert(&mut sys, &err.revert_info).unwrap();
+        assert_eq!(sys.inner().created, before);
+    }
+
+    #[test]
+    pub fn create_first_keeps_old_requirements_until_all_todos_succeed() {
+        crate::requirements!(NodeTy = Foo, AlwaysFail);
+
+        let v0 = Graph::<NodeTy, Applied>::new();
+        let mut v1 = Graph::<NodeTy, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let a = v1.add(Foo::A, &[root]);
+        let _b = v1.add(Foo::B, &[a]);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let results = seq.run(&mut sys, &ABORT).unwrap();
+        let v1 = v1.apply_execution_results(results);
+        let before = sys.created.clone();
+
+        let mut v2 = Graph::<NodeTy, Pending>::new();
+        let root = v2.add(Foo::ROOT, &[]);
+        let c = v2.add(Foo::C, &[root]);
+        let _fail = v2.add(AlwaysFail, &[c]);
+
+        let cmp = v2
+            .compare_with(&mut sys, &v1)
+            .unwrap()
+            .with_strategy(ApplyStrategy::CreateFirst);
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        assert!(seq.undo.is_empty());
+        assert_eq!(seq.deferred.len(), 2);
+
+        let err = seq.run(&mut sys, &ABORT).unwrap_err();
+        assert_eq!(err.applied(), 2);
+        assert_eq!(err.remaining(), 3);
+
+        // The old requirements have not been undone yet
+        assert!(sys.created.contains(&PathBuf::from("1")));
+        assert!(sys.created.contains(&PathBuf::from("2")));
+
+        seq.revert(&mut sys, &err.revert_info).unwrap();
+        assert_eq!(sys.created, before);
+
+        let mut v3 = Graph::<NodeTy, Pending>::new();
+        let root = v3.add(Foo::ROOT, &[]);
+        let _c = v3.add(Foo::C, &[root]);
+
+        let cmp = v3
+            .compare_with(&mut sys, &v1)
+            .unwrap()
+            .with_strategy(ApplyStrategy::CreateFirst);
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let _ = seq.run(&mut sys, &ABORT).unwrap();
+        assert_eq!(
+            sys.created,
+            [PathBuf::from("0"), PathBuf::from("3")]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    pub fn create_first_undoes_conflicting_requirements_first() {
+        crate::requirements!(NodeTy = Foo, Exclusive);
+
+        let mut v1 = Graph::<NodeTy, Pending>::new();
+        let old = v1.add(Exclusive { id: 1, uid: 1000 }, &[]);
+        let _a = v1.add(Foo::A, &[old]);
+        let _b = v1.add(Foo::B, &[]);
+        let v1 = v1.apply_execution_results(ApplyResult {
+            pre_existing: Vec::new(),
+        });
+
+        let mut v2 = Graph::<NodeTy, Pending>::new();
+        let _new = v2.add(Exclusive { id: 2, uid: 1000 }, &[]);
 
         let mut sys = FakeSystem {
             created: Default::default(),
+            recorder: None,
         };
 
-        let cmp = next.compare_with(&mut sys, &prev).unwrap();
+        let cmp = v2
+            .compare_with(&mut sys, &v1)
+            .unwrap()
+            .with_strategy(ApplyStrategy::CreateFirst);
         let seq = cmp.generate_application_sequence(&mut sys).unwrap();
 
+        // A depends on the conflicting requirement, so it must be undone first as well
         assert_eq!(
-            seq.undo,
+            seq.undo.iter().map(|u| u.requirement).collect::<Vec<_>>(),
             vec![
-                Undo {
-                    pre_existing: false,
-                    requirement: &Foo::B,
-                },
-                Undo {
-                    pre_existing: false,
-                    requirement: &Foo::END,
+                &NodeTy::Foo { val: Foo::A },
+                &NodeTy::Exclusive {
+                    val: Exclusive { id: 1, uid: 1000 }
                 },
             ]
         );
-
-        assert_eq!(seq.todo, vec![]);
+        assert_eq!(
+            seq.deferred
+                .iter()
+                .map(|u| u.requirement)
+                .collect::<Vec<_>>(),
+            vec![&NodeTy::Foo { val: Foo::B }]
+        );
     }
 
     #[test]
-    pub fn normal_sequence() {
-        let mut prev = Graph::<Foo, Pending>::new();
-        let root = prev.add(Foo::ROOT, &[]);
-        let a = prev.add(Foo::A, &[root]);
-        let _c = prev.add(Foo::C, &[a, root]);
-        let _x = prev.add(Foo::D, &[root]);
-        let prev = prev.apply_execution_results(ApplyResult {
+    pub fn preflight_checks_requirements_whose_dependencies_hold() {
+        crate::requirements!(NodeTy = Foo, AlwaysFail);
+
+        let mut v1 = Graph::<NodeTy, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let _b = v1.add(Foo::B, &[root]);
+        let v1 = v1.apply_execution_results(ApplyResult {
             pre_existing: Vec::new(),
         });
 
-        let mut next = Graph::<Foo, Pending>::new();
-        let root = next.add(Foo::ROOT, &[]);
-        let a = next.add(Foo::A, &[root]);
-        let b = next.add(Foo::B, &[root]);
-        let c = next.add(Foo::C, &[a, root]);
-        let _end = next.add(Foo::END, &[b, c]);
+        let mut sys = FakeSystem {
+            created: [PathBuf::from("0"), PathBuf::from("2")]
+                .into_iter()
+                .collect(),
+            recorder: None,
+        };
 
-        println!("prev       : {:?}", prev);
-        println!("next       : {:?}", next);
+        let mut v2 = Graph::<NodeTy, Pending>::new();
+        let root = v2.add(Foo::ROOT, &[]);
+        let _fail = v2.add(AlwaysFail, &[root]);
+
+        let cmp = v2.compare_with(&mut sys, &v1).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let report = seq.preflight(&mut sys);
+
+        assert_eq!(report.problems().len(), 1);
+        assert_eq!(report.problems()[0].requirement, "AlwaysFail");
+        assert_eq!(report.to_string(), "  AlwaysFail: always fails\n");
+
+        // B is only undone when the sequence runs
+        assert_eq!(sys.created.len(), 2);
+
+        // A is created by the sequence, so the checks of requirements that depend on it cannot be run yet
+        let mut v3 = Graph::<NodeTy, Pending>::new();
+        let root = v3.add(Foo::ROOT, &[]);
+        let a = v3.add(Foo::A, &[root]);
+        let _fail = v3.add(AlwaysFail, &[a]);
+
+        let cmp = v3.compare_with(&mut sys, &v1).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        assert!(seq.preflight(&mut sys).is_ok());
+    }
+
+    #[test]
+    pub fn cancelled_apply_is_reverted() {
+        let v0 = Graph::<Foo, Applied>::new();
+        let mut v1 = Graph::<Foo, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let _a = v1.add(Foo::A, &[root]);
 
         let mut sys = FakeSystem {
-            created: [PathBuf::from("4")].into_iter().collect(),
+            created: Default::default(),
+            recorder: None,
         };
 
-        let cmp = next.compare_with(&mut sys, &prev).unwrap();
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
         let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let limits = ApplyLimits::default();
+        limits.cancelled.store(true, Ordering::SeqCst);
+        let err = seq.run_with_limits(&mut sys, &ABORT, &limits).unwrap_err();
 
-        assert_eq!(
-            seq.undo,
-            vec![Undo {
-                pre_existing: false,
-                requirement: &Foo::D,
-            },]
-        );
+        assert!(matches!(err.inner(), RequirementOperationError::Cancelled));
+        assert_eq!(err.applied(), 0);
+        assert!(sys.created.is_empty());
 
-        assert_eq!(
-            seq.todo,
-            vec![
-                Do {
-                    created_by_us: true,
-                    should_exist: true,
-                    source: GraphNodeReference(0),
-                    requirement: &Foo::ROOT,
-                },
-                Do {
-                    created_by_us: false,
-                    should_exist: false,
-                    source: GraphNodeReference(2),
-                    requirement: &Foo::B,
-                },
-                Do {
-                    created_by_us: true,
-                    should_exist: true,
-                    source: GraphNodeReference(1),
-                    requirement: &Foo::A,
-                },
-                Do {
-                    created_by_us: true,
-                    should_exist: true,
-                    source: GraphNodeReference(3),
-                    requirement: &Foo::C,
-                },
-                Do {
-                    created_by_us: false,
-                    should_exist: false,
-                    source: GraphNodeReference(4),
-                    requirement: &Foo::END,
-                },
-            ]
-        );
+        seq.revert(&mut sys, &err.revert_info).unwrap();
+        assert!(sys.created.is_empty());
     }
 
     #[test]
-    pub fn apply() {
+    pub fn node_timeout_reverts_the_slow_node() {
         let v0 = Graph::<Foo, Applied>::new();
         let mut v1 = Graph::<Foo, Pending>::new();
         let root = v1.add(Foo::ROOT, &[]);
-        let a = v1.add(Foo::A, &[root]);
-        let b = v1.add(Foo::B, &[root]);
-        let c = v1.add(Foo::C, &[a, root]);
-        let _end = v1.add(Foo::END, &[b, c]);
+        let _a = v1.add(Foo::A, &[root]);
 
-        println!("prev       : {:?}", v0);
-        println!("next       : {:?}", v1);
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let limits = ApplyLimits {
+            node_timeout: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let err = seq.run_with_limits(&mut sys, &ABORT, &limits).unwrap_err();
+
+        assert!(matches!(
+            err.inner(),
+            RequirementOperationError::NodeTimedOut
+        ));
+        assert_eq!(err.node(), Some(GraphNodeReference(0)));
+        assert_eq!(sys.created, [PathBuf::from("0")].into_iter().collect());
+
+        seq.revert(&mut sys, &err.revert_info).unwrap();
+        assert!(sys.created.is_empty());
+    }
+
+    #[test]
+    pub fn transient_failures_are_retried() {
+        let v0 = Graph::<Flaky, Applied>::new();
+        let mut v1 = Graph::<Flaky, Pending>::new();
+        v1.add(Flaky { failures: 2 }, &[]);
 
         let mut sys = FakeSystem {
             created: Default::default(),
+            recorder: None,
         };
 
         let cmp = v1.compare_with(&mut sys, &v0).unwrap();
         let seq = cmp.generate_application_sequence(&mut sys).unwrap();
-        let results = seq.run(&mut sys, |_| false).unwrap();
-        let v1 = v1.apply_execution_results(results);
+        let limits = ApplyLimits {
+            retry: RetrySettings {
+                max_attempts: Some(3),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let _results = seq.run_with_limits(&mut sys, &ABORT, &limits).unwrap();
+        assert!(sys.created.contains(&PathBuf::from("flaky")));
+        assert!(sys.created.contains(&PathBuf::from("attempt1")));
+    }
+
+    #[test]
+    pub fn retries_are_limited_per_kind() {
+        let v0 = Graph::<Flaky, Applied>::new();
+        let mut v1 = Graph::<Flaky, Pending>::new();
+        v1.add(Flaky { failures: 2 }, &[]);
+
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
 
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
+        let seq = cmp.generate_application_sequence(&mut sys).unwrap();
+        let limits = ApplyLimits {
+            retry: RetrySettings {
+                max_attempts: Some(3),
+                kinds: [(String::from("flaky"), RetryPolicy::new(2, 0))]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let err = seq.run_with_limits(&mut sys, &ABORT, &limits).unwrap_err();
+
+        assert!(matches!(
+            err.inner(),
+            RequirementOperationError::CreateFailed { .. }
+        ));
+        assert!(!sys.created.contains(&PathBuf::from("flaky")));
+        assert!(sys.created.contains(&PathBuf::from("attempt1")));
+
+        // Without retries, the first failure aborts the apply
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+        let err = seq
+            .run_with_limits(&mut sys, &ABORT, &ApplyLimits::default())
+            .unwrap_err();
+        assert_eq!(err.applied(), 0);
         assert_eq!(
             sys.created,
-            [
-                PathBuf::from("0"),
-                PathBuf::from("1"),
-                PathBuf::from("2"),
-                PathBuf::from("3"),
-                PathBuf::from("100"),
-            ]
-            .into_iter()
-            .collect()
+            [PathBuf::from("attempt0")].into_iter().collect()
         );
+    }
 
-        let mut v2 = Graph::<Foo, Pending>::new();
-        let root = v2.add(Foo::ROOT, &[]);
-        let a = v2.add(Foo::A, &[root]);
-        let c = v2.add(Foo::C, &[a, root]);
-        let _end = v2.add(Foo::END, &[c]);
+    #[test]
+    pub fn failure_context() {
+        crate::requirements!(NodeTy = Foo, AlwaysFail);
 
-        println!("prev       : {:?}", v1);
-        println!("next       : {:?}", v2);
+        let v0 = Graph::<NodeTy, Applied>::new();
+        let mut v1 = Graph::<NodeTy, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let a = v1.add(Foo::A, &[root]);
+        v1.assign_package(0..2, "base");
+        let _fail = v1.add(AlwaysFail, &[root, a]);
+        let _end = v1.add(Foo::END, &[]);
+        v1.assign_package(2..4, "app");
 
-        let cmp = v2.compare_with(&mut sys, &v1).unwrap();
+        let mut sys = FakeSystem {
+            created: Default::default(),
+            recorder: None,
+        };
+
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
         let seq = cmp.generate_application_sequence(&mut sys).unwrap();
-        let results = seq.run(&mut sys, |_| false).unwrap();
-        let _v2 = v2.apply_execution_results(results);
+        let err = seq.run(&mut sys, &ABORT).unwrap_err();
 
+        assert_eq!(err.node(), Some(GraphNodeReference(2)));
+        assert_eq!(err.package(), Some("app"));
         assert_eq!(
-            sys.created,
-            [
-                PathBuf::from("0"),
-                PathBuf::from("1"),
-                PathBuf::from("3"),
-                PathBuf::from("100"),
-            ]
-            .into_iter()
-            .collect()
+            err.dependencies(),
+            &[Foo::ROOT.to_string(), Foo::A.to_string()]
         );
+        assert_eq!(err.applied(), 2);
+        assert_eq!(err.remaining(), 2);
+
+        let report = err.report().to_string();
+        assert!(report.contains("package     : app"));
+        assert!(report.contains("2 applied, 2 remaining"));
+        assert!(report.contains("kind        : alwaysfail\n"));
+        assert!(report.contains("hint        : It always fails."));
+        assert!(!report.contains("It was never there."));
+        assert!(report.contains("docs        : https://example.com/alwaysfail"));
     }
 
     #[test]
-    pub fn apply_revert() {
+    pub fn isolated_failures_only_skip_dependents() {
         crate::requirements!(NodeTy = Foo, AlwaysFail);
 
         let v0 = Graph::<NodeTy, Applied>::new();
         let mut v1 = Graph::<NodeTy, Pending>::new();
         let root = v1.add(Foo::ROOT, &[]);
         let a = v1.add(Foo::A, &[root]);
-        let b = v1.add(Foo::B, &[root]);
-        let c = v1.add(Foo::C, &[a, root]);
-        let _end = v1.add(Foo::END, &[b, c]);
-
-        println!("prev       : {:?}", v0);
-        println!("next       : {:?}", v1);
+        v1.assign_package(0..2, "base");
+        let fail = v1.add(AlwaysFail, &[root]);
+        let _c = v1.add(Foo::C, &[fail]);
+        v1.assign_package(2..4, "app");
+        let _b = v1.add(Foo::B, &[a]);
+        v1.assign_package(4..5, "base");
 
         let mut sys = FakeSystem {
             created: Default::default(),
+            recorder: None,
         };
 
         let cmp = v1.compare_with(&mut sys, &v0).unwrap();
         let seq = cmp.generate_application_sequence(&mut sys).unwrap();
-        let results = seq.run(&mut sys, |_| false).unwrap();
-        let v1 = v1.apply_execution_results(results);
+        let partial = seq
+            .run_isolated(&mut sys, &ABORT, &ApplyLimits::default())
+            .unwrap();
 
+        assert!(!partial.is_complete());
         assert_eq!(
             sys.created,
-            [
-                PathBuf::from("0"),
-                PathBuf::from("1"),
-                PathBuf::from("2"),
-                PathBuf::from("3"),
-                PathBuf::from("100"),
+            [PathBuf::from("0"), PathBuf::from("1"), PathBuf::from("2")]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(partial.failed().len(), 1);
+        assert_eq!(partial.failed()[0].error().node(), Some(fail));
+        assert_eq!(partial.failed()[0].skipped().len(), 1);
+        assert_eq!(
+            partial.failed()[0].skipped()[0].to_string(),
+            Foo::C.to_string()
+        );
+        assert_eq!(
+            partial.packages(),
+            &[
+                PackageOutcome {
+                    package: Some(String::from("base")),
+                    applied: 3,
+                    failed: 0,
+                    skipped: 0,
+                },
+                PackageOutcome {
+                    package: Some(String::from("app")),
+                    applied: 0,
+                    failed: 1,
+                    skipped: 1,
+                },
             ]
-            .into_iter()
-            .collect()
         );
+        assert!(partial
+            .to_string()
+            .starts_with("1 of 2 packages applied completely, 1 subtrees failed\n"));
 
-        let mut v2 = Graph::<NodeTy, Pending>::new();
-        let root = v2.add(Foo::ROOT, &[]);
-        let a = v2.add(Foo::A, &[root]);
-        let c = v2.add(Foo::C, &[a, root]);
-        let end = v2.add(Foo::END, &[c]);
-        let _fail = v2.add(AlwaysFail, &[end]);
+        // Nothing in the previous graph was replaced by the failed requirement
+        seq.revert_subtree(&mut sys, &partial.failed()[0]).unwrap();
+        assert_eq!(sys.created.len(), 3);
+    }
 
-        println!("prev       : {:?}", v1);
-        println!("next       : {:?}", v2);
+    #[test]
+    pub fn resolve_conflicts() {
+        let v0 = Graph::<Foo, Applied>::new();
+        let mut v1 = Graph::<Foo, Pending>::new();
+        let root = v1.add(Foo::ROOT, &[]);
+        let _a = v1.add(Foo::A, &[root]);
 
-        let cmp = v2.compare_with(&mut sys, &v1).unwrap();
+        let mut sys = FakeSystem {
+            created: [PathBuf::from("0")].into_iter().collect(),
+            recorder: None,
+        };
+
+        let cmp = v1.compare_with(&mut sys, &v0).unwrap();
         let seq = cmp.generate_application_sequence(&mut sys).unwrap();
-        let err = seq.run(&mut sys, |_| false).unwrap_err();
-        println!("Apply failed successfully");
-        println!("System state: {:?}", sys);
-        seq.revert(&mut sys, &err.revert_info).unwrap();
+        assert!(seq.run(&mut sys, &ABORT).is_err());
+
+        let results = seq
+            .run(&mut sys, &|conflict: &crate::conflict::Conflict<Foo>| {
+                assert_eq!(conflict.existing, None);
+                Resolution::Adopt
+            })
+            .unwrap();
+        assert_eq!(results.pre_existing, vec![GraphNodeReference(0)]);
+        assert_eq!(sys.created.len(), 2);
 
+        let results = seq
+            .run(&mut sys, &ConflictPolicy::Always(Resolution::Overwrite))
+            .unwrap();
         assert_eq!(
-            sys.created,
-            [
-                PathBuf::from("0"),
-                PathBuf::from("1"),
-                PathBuf::from("2"),
-                PathBuf::from("3"),
-                PathBuf::from("100"),
-            ]
-            .into_iter()
-            .collect()
+            results.pre_existing,
+            vec![GraphNodeReference(0), GraphNodeReference(1)]
+        );
+    }
+
+    #[test]
+    pub fn apply_up_to_checkpoints() {
+        let mut prev = Graph::<Foo, Pending>::new();
+        prev.add(Foo::A, &[]);
+        prev.add(Foo::C, &[]);
+        let prev = prev.apply_execution_results(ApplyResult {
+            pre_existing: Vec::new(),
+        });
+
+        let mut next = Graph::<Foo, Pending>::new();
+        let root = next.add(Foo::ROOT, &[]);
+        let b = next.add(Foo::B, &[root]);
+        let c = next.add(Foo::C, &[b]);
+        let end = next.add(Foo::END, &[c]);
+        next.add_checkpoint("infra", &[b]);
+        next.add_checkpoint("all", &[end]);
+        let next = next.apply_execution_results(ApplyResult {
+            pre_existing: Vec::new(),
+        });
+        assert_eq!(next.checkpoints().collect::<Vec<_>>(), vec!["all", "infra"]);
+
+        // A and C are kept, because they are not replaced by anything up to the checkpoint
+        let staged = next
+            .up_to_checkpoints(&prev, &[String::from("infra")])
+            .unwrap();
+        assert_eq!(
+            staged.requirements().cloned().collect::<Vec<_>>(),
+            vec![Foo::A, Foo::C, Foo::ROOT, Foo::B]
+        );
+        assert_eq!(staged.nodes[3].preconditions, vec![2]);
+
+        // C is replaced by the C of the next graph
+        let staged = next
+            .up_to_checkpoints(&prev, &[String::from("infra"), String::from("all")])
+            .unwrap();
+        assert_eq!(
+            staged.requirements().cloned().collect::<Vec<_>>(),
+            vec![Foo::A, Foo::ROOT, Foo::B, Foo::C, Foo::END]
+        );
+
+        assert_eq!(
+            next.up_to_checkpoints(&prev, &[String::from("sites")]),
+            Err(String::from("sites"))
         );
+
+        // A checkpoint on a removed node moves to its preconditions
+        let mut retained = next.clone();
+        retained.retain(|index, _| index != b.0);
+        assert_eq!(retained.checkpoints["infra"], vec![0]);
+    }
+
+    #[test]
+    pub fn resume_skips_completed_requirements() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("resume");
+        let progress_path = dir.join("progress.json");
+        let old = CreateDirectory::new(dir.join("old"));
+
+        let mut v0 = Graph::<CreateDirectory, Pending>::new();
+        v0.add(old.clone(), &[]);
+        let v0 = v0.apply_execution_results(ApplyResult::default());
+
+        let mut v1 = Graph::<CreateDirectory, Pending>::new();
+        let a = v1.add(CreateDirectory::new(dir.join("a")), &[]);
+        v1.add(CreateDirectory::new(dir.join("b")), &[a]);
+        v1.add(CreateDirectory::new(dir.join("c")), &[]);
+
+        // The interrupted apply undid `old`, applied `a`, and was interrupted while it applied `b`
+        system.make_dir(&dir.join("a")).unwrap();
+        system.make_dir(&dir.join("b")).unwrap();
+        let mut interrupted = ApplyProgress::new(1, 2);
+        interrupted.undone.insert(NodeId::of(&old));
+        interrupted.applied.insert(0);
+        interrupted.started = Some(1);
+
+        // Undoing `old` again would fail, because it no longer exists
+        let cmp = v1.compare_with(&mut system, &v0).unwrap();
+        let seq = cmp
+            .generate_application_sequence(&mut system)
+            .unwrap()
+            .with_progress(progress_path.clone(), interrupted);
+        let result = seq.run(&mut system, &ABORT).unwrap();
+        assert!(result.pre_existing.is_empty());
+        assert!(system.path_exists(&dir.join("c")).unwrap());
+
+        let progress = ApplyProgress::load(&progress_path, &system)
+            .unwrap()
+            .unwrap();
+        assert_eq!(progress.applied.into_iter().collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(progress.undone.len(), 1);
+        assert_eq!(progress.started, None);
     }
 }
@@ -1,27 +1,126 @@
-use crate::{builder::Packages, graph::VerificationState};
-use apply::SystemState;
-use builder::{fs::CreateDirectory, Builder};
+// The directive lists in `config::systemd` are expanded recursively, one directive at a time
+#![recursion_limit = "256"]
+
+use crate::{
+    agent::{AgentError, AgentEvent, AgentOptions, AgentStatus},
+    builder::Packages,
+    control::{Control, ControlError},
+    graph::{ApplyLimits, ApplyResult, ApplySequence, ApplyStrategy, NodeId, VerificationState},
+    transfer::TransferLimits,
+};
+use apply::{PreviousInstall, SystemState};
+use arch::{Arch, ArchError};
+use audit::{AuditError, AuditLog, Invocation, InvocationLog, Recorder};
+use bootstrap::{Bootstrap, BootstrapFormat};
+use builder::{fs::CreateDirectory, BuildPhaseError, Builder};
+use clap::{CommandFactory, FromArgMatches, Subcommand};
+use clap_complete::Shell;
+use conflict::{ConflictPolicy, OverwritePolicy, Resolution};
+use db::{DbError, DbFormat, Migrations};
+use deleted::{DeletedError, DeletedFiles};
+use diff::InstallDiff;
+use fix::{FixReport, FixReportError};
+use itertools::Itertools;
+use lint::LintError;
+use notify::{Notification, Outcome};
+use oci::{BuildTarget, OciError};
+use os_upgrade::{OsUpgrade, OsUpgradeError, OsUpgradeHistory};
+use outputs::{Outputs, OutputsError};
+use preview::{PreviewError, Sandbox};
+use reboot::RebootError;
+use report::Report;
 use requirements::{Requirement, Supports};
+use resume::{ApplyProgress, ResumeError};
+use retention::{RetentionError, RetentionPolicy};
+use retry::RetrySettings;
+use review::{Decision, Review};
+use rollout::{Rollout, RolloutError};
+use sampling::SampleSize;
+use scaffold::{PackageTemplate, ScaffoldError};
 use serde::{de::DeserializeOwned, Serialize};
+use settings::{Settings, SettingsError};
+use signal_hook::consts::{SIGINT, SIGTERM};
 use std::{
-    io::{BufRead, Cursor},
+    collections::{BTreeMap, BTreeSet},
     num::ParseIntError,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use structopt::StructOpt;
-use system::System;
+use system::{System, SystemLock};
+use tracing::{error, info, warn};
+use verification::{VerificationReport, VerifyFormat};
+use watch::Snapshot;
 
 pub use libside_procmacro::config_file;
 
+pub mod agent;
 pub mod apply;
+pub mod arch;
+pub mod audit;
+pub mod batch;
+pub mod bootstrap;
 pub mod builder;
+pub mod conffile;
 pub mod config;
+pub mod conflict;
+pub mod control;
+pub mod db;
+pub mod deleted;
+pub mod diff;
+pub mod distro;
+pub mod doctor;
+pub mod fix;
+pub mod fleet;
 pub mod graph;
+pub mod lint;
+pub mod logging;
+pub mod notify;
+pub mod oci;
+pub mod os_upgrade;
+pub mod outputs;
+pub mod preview;
+pub mod reboot;
+pub mod report;
 pub mod requirements;
+pub mod resume;
+pub mod retention;
+pub mod retry;
+pub mod review;
+pub mod rollout;
+pub mod sampling;
+pub mod scaffold;
 pub mod secrets;
+pub mod settings;
+pub mod simple;
 pub mod system;
 pub mod testing;
+pub mod transfer;
 pub mod utils;
+pub mod verification;
+pub mod watch;
+
+/// The directory in the backups directory that contains the original system files, see `Dirs::originals_path`.
+/// It cannot be used as a package name.
+pub const ORIGINALS: &str = "_originals";
+
+/// The directory in the backups directory that contains the values that were replaced by patches, see `Dirs::patches_path`.
+/// It cannot be used as a package name.
+pub const PATCHES: &str = "_patches";
+
+/// The directory in the backups directory that contains the alternatives that were selected before `Alternative` requirements, see `Dirs::alternatives_path`.
+/// It cannot be used as a package name.
+pub const ALTERNATIVES: &str = "_alternatives";
+
+/// The directory in the backups directory that contains the data that was backed up before requirements were undone, see `Dirs::undone_path`.
+/// It cannot be used as a package name.
+pub const UNDONE: &str = "_undone";
+
+/// How long `side apply --allow-reboot` waits for the system to come back after rebooting.
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(15 * 60);
 
 #[derive(Debug, thiserror::Error)]
 pub enum RunError<S: System, B: Builder> {
@@ -36,6 +135,121 @@ pub enum RunError<S: System, B: Builder> {
 
     #[error("Verification failed")]
     VerificationFailed,
+
+    #[error("There is no instance named {:?}", .0)]
+    UnknownInstance(String),
+
+    #[error("Building the OCI image failed: {}", .0)]
+    OciFailed(OciError<S>),
+
+    #[error("Unable to write bootstrap script to {}: {}", .0.display(), .1)]
+    UnableToWriteBootstrap(PathBuf, S::Error),
+
+    #[error("Unable to scan the packages directory: {}", .0)]
+    WatchFailed(S::Error),
+
+    #[error("Unable to preview the build: {}", .0)]
+    PreviewFailed(PreviewError<S>),
+
+    #[error("The preview was unsuccessful")]
+    PreviewUnsuccessful,
+
+    #[error("Unable to lint the build: {}", .0)]
+    LintFailed(LintError<S>),
+
+    #[error("Some configuration files are invalid")]
+    LintUnsuccessful,
+
+    #[error("Unable to load the outputs: {}", .0)]
+    OutputsFailed(OutputsError<S>),
+
+    #[error("There is no output named {:?}", .0)]
+    UnknownOutput(String),
+
+    #[error("The doctor found problems with the environment")]
+    DoctorFoundProblems,
+
+    #[error("Unable to reboot: {}", .0)]
+    RebootFailed(RebootError<S>),
+
+    #[error("Unable to access the audit log: {}", .0)]
+    AuditFailed(AuditError<S>),
+
+    #[error("Unable to access the fix report: {}", .0)]
+    FixReportFailed(FixReportError<S>),
+
+    #[error("There is no apply with id {} in the audit log", .0)]
+    UnknownApply(u64),
+
+    #[error("There is no invocation with id {} in the invocation log", .0)]
+    UnknownInvocation(u64),
+
+    #[error("Unable to access the deleted files: {}", .0)]
+    DeletedFailed(DeletedError<S>),
+
+    #[error("The agent failed: {}", .0)]
+    AgentFailed(AgentError<S>),
+
+    #[error("Unable to control the agent: {}", .0)]
+    ControlFailed(ControlError),
+
+    #[error(
+        "Install {} is older than the current install {}; pass --allow-downgrade to apply it anyway",
+        .1, .0
+    )]
+    DowngradeNotAllowed(u64, u64),
+
+    #[error("There is no install {}", .0)]
+    UnknownInstall(u64),
+
+    #[error("The changes were not approved")]
+    NotApproved,
+
+    #[error("Unable to list the installs: {}", .0)]
+    UnableToListInstalls(S::Error),
+
+    #[error("Unable to determine the current install: {}", .0)]
+    CurrentInstallFailed(GetCurrentStateError<S>),
+
+    #[error("Unable to read the database: {}", .0)]
+    DbReadFailed(DbReadError<S>),
+
+    #[error("Unable to write the database: {}", .0)]
+    DbWriteFailed(DbWriteError<S>),
+
+    #[error("The release upgrade failed: {}", .0)]
+    OsUpgradeFailed(OsUpgradeError<S>),
+
+    #[error("Unable to create the package: {}", .0)]
+    ScaffoldFailed(ScaffoldError<S>),
+
+    #[error("There is no requirement of kind {:?}; run `side explain` to list the kinds", .0)]
+    UnknownRequirementKind(String),
+
+    #[error("Unable to write the man pages: {}", .0)]
+    ManFailed(std::io::Error),
+
+    #[error("Unable to load the settings: {}", .0)]
+    SettingsFailed(SettingsError<S>),
+
+    #[error("Unable to remove old backups and installs: {}", .0)]
+    RetentionFailed(RetentionError<S>),
+
+    #[error("The staged rollout failed: {}", .0)]
+    RolloutFailed(RolloutError<S>),
+
+    #[error("Unable to resume the apply: {}", .0)]
+    ResumeFailed(ResumeError<S>),
+
+    #[error(
+        "Another run of side{} holds the lock on {}; pass --wait to wait until it finishes",
+        .holder.map(|pid| format!(" (pid {})", pid)).unwrap_or_default(),
+        .path.display()
+    )]
+    Locked { path: PathBuf, holder: Option<u32> },
+
+    #[error("Unable to lock {}: {}", .0.display(), .1)]
+    LockFailed(PathBuf, S::Error),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -62,12 +276,21 @@ pub enum DbWriteError<S: System> {
     UnableToWriteCurrentVersion(PathBuf, S::Error),
 
     #[error("Unable to serialize database: {}", .0)]
-    UnableToSerialize(serde_json::Error),
+    UnableToSerialize(DbError),
 
     #[error("Unable to create database {}: {}", .0.display(), .1)]
     UnableToCreateDb(PathBuf, S::Error),
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum DbReadError<S: System> {
+    #[error("Unable to read database {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("Invalid database {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, DbError),
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum GetCurrentStateError<S: System> {
     #[error("Unable to read {:?}: {}", .0, .1)]
@@ -80,7 +303,7 @@ pub enum GetCurrentStateError<S: System> {
 #[derive(Debug, thiserror::Error)]
 pub enum BuildError<S: System, B: Builder> {
     #[error("Build failed: {}", .0)]
-    BuildFailed(B::BuildError),
+    BuildFailed(BuildPhaseError<S, B::BuildError>),
 
     #[error("Unable to generate files needed for the build: ")]
     UnableToGenerateFiles(()),
@@ -91,14 +314,32 @@ pub enum BuildError<S: System, B: Builder> {
     #[error("Unable to determine differences with previous build: {}", .0)]
     DiffFailed(<B::Requirement as Requirement>::HasBeenCreatedError<S>),
 
-    #[error("Unable to generate an application sequence: ")]
-    ApplicationSequenceGenerationFailed(()),
+    #[error("Unable to generate an application sequence: {}", .0)]
+    ApplicationSequenceGenerationFailed(graph::CycleError),
+
+    #[error("Preflight checks failed, nothing has been changed:\n{}", .0)]
+    PreflightFailed(graph::PreflightReport),
 
     #[error("Unable to apply the build: {}", .0)]
     ApplyFailed(graph::RunError<B::Requirement, S>),
 
+    #[error("The pre-apply hook failed, nothing has been changed: {}", .0)]
+    PreApplyHookFailed(B::BuildError),
+
+    #[error("The install has been applied, but the post-apply hook failed: {}", .0)]
+    PostApplyHookFailed(B::BuildError),
+
+    #[error("The install was applied except for the subtrees that failed, which have been reverted. `side verify --fix` applies them again:\n{}", .0)]
+    PartiallyApplied(String),
+
     #[error("Unable to save new state: ")]
     SaveError(()),
+
+    #[error("Unable to determine the architecture: {}", .0)]
+    UnableToCheckArch(ArchError<S>),
+
+    #[error("The install was built for {}, but the system is {}", .0, .1)]
+    WrongArch(Arch, Arch),
 }
 
 impl<S: System, B: Builder> From<BuildError<S, B>> for RunError<S, B> {
@@ -107,12 +348,16 @@ impl<S: System, B: Builder> From<BuildError<S, B>> for RunError<S, B> {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Dirs {
     base: PathBuf,
 
     /// /srv/packages
     packages: PathBuf,
 
+    /// /srv/sources, the cache of package sources that are fetched from git repositories and archives
+    sources: PathBuf,
+
     /// /srv/installed
     installed: PathBuf,
 
@@ -125,9 +370,6 @@ pub struct Dirs {
     /// /srv/files/config
     files_config: PathBuf,
 
-    /// /srv/files/deleted
-    deleted: PathBuf,
-
     /// /srv/data
     data: PathBuf,
 
@@ -136,6 +378,57 @@ pub struct Dirs {
 
     /// /srv/secrets
     secrets: PathBuf,
+
+    /// /srv/credentials
+    credentials: PathBuf,
+
+    /// /srv/ports.json
+    ports: PathBuf,
+
+    /// /srv/kv.json
+    kv: PathBuf,
+
+    /// /srv/reboots
+    reboots: PathBuf,
+
+    /// /srv/host-keys
+    host_keys: PathBuf,
+
+    /// /srv/audit.log
+    audit_log: PathBuf,
+
+    /// /srv/audit/invocations.jsonl, with the id of the next invocation in /srv/audit/invocations.next
+    invocations: PathBuf,
+
+    /// /srv/fixes
+    fixes: PathBuf,
+
+    /// /srv/os-upgrades.json
+    os_upgrades: PathBuf,
+
+    /// /srv/rollout.json
+    rollout: PathBuf,
+
+    /// /srv/agent.json
+    agent_status: PathBuf,
+
+    /// /srv/agent.sock
+    agent_socket: PathBuf,
+
+    /// /srv/side.toml
+    settings: PathBuf,
+
+    /// /srv/lock
+    lock: PathBuf,
+
+    /// Whether to wait for the lock when another run holds it, instead of failing
+    wait_for_lock: bool,
+
+    /// The format in which new databases are written
+    db_format: DbFormat,
+
+    /// The migrations of the builder, which determine the schema version of new databases
+    migrations: Migrations,
 }
 
 fn create_dir_with_err<S: System>(system: &mut S, dir: &Path) -> Result<(), InitError<S>> {
@@ -150,17 +443,56 @@ impl Dirs {
         Dirs {
             base: base.to_owned(),
             packages: base.join("packages"),
+            sources: base.join("sources"),
             installed: base.join("installed"),
             files_exposed: base.join("files/exposed"),
             files_config: base.join("files/config"),
-            deleted: base.join("files/deleted"),
             chroots: base.join("chroots"),
             data: base.join("data"),
             backups: base.join("backups"),
             secrets: base.join("secrets"),
+            credentials: base.join("credentials"),
+            ports: base.join("ports.json"),
+            kv: base.join("kv.json"),
+            reboots: base.join("reboots"),
+            host_keys: base.join("host-keys"),
+            audit_log: base.join("audit.log"),
+            invocations: base.join("audit/invocations.jsonl"),
+            fixes: base.join("fixes"),
+            os_upgrades: base.join("os-upgrades.json"),
+            rollout: base.join("rollout.json"),
+            agent_status: base.join("agent.json"),
+            agent_socket: base.join("agent.sock"),
+            settings: base.join("side.toml"),
+            lock: base.join("lock"),
+            wait_for_lock: false,
+            db_format: DbFormat::default(),
+            migrations: Migrations::new(),
         }
     }
 
+    /// Writes the databases of new installs in `format`. Existing databases are read in any format.
+    pub fn with_db_format(mut self, format: DbFormat) -> Self {
+        self.db_format = format;
+        self
+    }
+
+    /// Waits for the lock on the base directory when another run of side holds it, instead of failing.
+    pub fn with_lock_wait(mut self, wait: bool) -> Self {
+        self.wait_for_lock = wait;
+        self
+    }
+
+    /// Migrates databases with an older schema version with `migrations` when they are read, and writes new databases with the schema version of `migrations`.
+    pub fn with_migrations(mut self, migrations: Migrations) -> Self {
+        self.migrations = migrations;
+        self
+    }
+
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
     pub fn get_install(&self, version: u64) -> StateDirs {
         let v = version.to_string();
         let versioned_base = self.installed.join(&v);
@@ -168,6 +500,11 @@ impl Dirs {
         StateDirs {
             version,
             db: versioned_base.join("db"),
+            pending_db: versioned_base.join("db.pending"),
+            progress: versioned_base.join("progress.json"),
+            outputs: versioned_base.join("outputs.json"),
+            deleted: versioned_base.join("deleted.json"),
+            arch: versioned_base.join("arch.json"),
             generated: versioned_base.join("generated"),
             config: self.files_config.clone(),
             base: versioned_base,
@@ -175,9 +512,32 @@ impl Dirs {
             files_exposed: self.files_exposed.clone(),
             data: self.data.clone(),
             backup: self.backups.clone(),
+            db_format: self.db_format,
+            migrations: self.migrations.clone(),
         }
     }
 
+    /// Where the original system files that are overwritten or deleted by install `version` are saved, so that they can be restored when they are no longer managed.
+    pub fn originals_path(&self, version: u64) -> PathBuf {
+        self.backups.join(ORIGINALS).join(version.to_string())
+    }
+
+    /// Where the values that are replaced by patched files are saved, so that the patches can be undone.
+    /// Unlike originals, these are not versioned: an unchanged patch keeps its saved values across installs.
+    pub fn patches_path(&self) -> PathBuf {
+        self.backups.join(PATCHES)
+    }
+
+    /// Where the alternatives that are replaced by `Alternative` requirements are saved, so that they can be selected again on undo.
+    pub fn alternatives_path(&self) -> PathBuf {
+        self.backups.join(ALTERNATIVES)
+    }
+
+    /// Where the data that requirements back up before they are undone by the apply of install `version` is saved, see `Requirement::backup`.
+    pub fn undone_path(&self, version: u64) -> PathBuf {
+        self.backups.join(UNDONE).join(version.to_string())
+    }
+
     fn current_path(&self) -> PathBuf {
         self.installed.join("current")
     }
@@ -206,20 +566,35 @@ impl Dirs {
         system: &mut S,
     ) -> Result<(), S::Error> {
         let current = self.current_path();
-        system.put_file_contents(&current, format!("{}", new.version).as_bytes())
+        system.put_file_contents(&current, format!("{}", new.version).as_bytes())?;
+
+        // A rollout only describes the system while the install that it started from is current
+        if system.path_exists(&self.rollout)? {
+            system.remove_file(&self.rollout)?;
+        }
+
+        Ok(())
+    }
+
+    /// The versions of all installs, in no particular order.
+    pub fn installed_versions<S: System>(&self, system: &mut S) -> Result<Vec<u64>, S::Error> {
+        Ok(system
+            .read_dir(&self.installed)?
+            .iter()
+            .flat_map(|dir| dir.parse::<u64>().ok())
+            .collect())
     }
 
     pub fn fresh_install<S: System>(
         &self,
         system: &mut S,
     ) -> Result<StateDirs, GetCurrentStateError<S>> {
-        let mut max = 0u64;
-        for dir in system.read_dir(&self.installed).unwrap() {
-            match dir.parse::<u64>() {
-                Ok(n) => max = max.max(n),
-                _ => (),
-            }
-        }
+        let max = self
+            .installed_versions(system)
+            .unwrap()
+            .into_iter()
+            .max()
+            .unwrap_or(0);
 
         let next_version = max + 1;
         Ok(self.get_install(next_version))
@@ -234,9 +609,13 @@ impl Dirs {
         // Make sure nobody besides us can read the base dir
         system.chmod(&self.base, 0o700).unwrap();
 
-        if !system
-            .dir_is_empty(&self.base)
-            .map_err(|e| InitError::UnableToOpen(self.base.to_owned(), e))?
+        // The lock file is created by `init` itself, see `Command::locks`
+        let existing = system
+            .read_dir(&self.base)
+            .map_err(|e| InitError::UnableToOpen(self.base.to_owned(), e))?;
+        if existing
+            .iter()
+            .any(|name| self.lock.file_name() != Some(name.as_ref()))
         {
             return Err(InitError::BaseDirectoryNotEmpty(self.base.to_owned()));
         }
@@ -249,6 +628,9 @@ impl Dirs {
         create_dir_with_err(system, &self.data)?;
         create_dir_with_err(system, &self.backups)?;
         create_dir_with_err(system, &self.secrets)?;
+        create_dir_with_err(system, &self.credentials)?;
+        create_dir_with_err(system, &self.reboots)?;
+        create_dir_with_err(system, &self.host_keys)?;
 
         let install = self.get_install(0);
         install.create_dirs(system)?;
@@ -263,6 +645,95 @@ impl Dirs {
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum InstanceError {
+    #[error("An instance named {:?} already exists", .0)]
+    DuplicateName(String),
+
+    #[error("The base directory {:?} overlaps with the base directory of instance {:?}", .0, .1)]
+    OverlappingBaseDir(PathBuf, String),
+}
+
+/// A set of independent base directories that are managed from a single process.
+///
+/// Each instance has its own [`Dirs`], and therefore its own packages, secrets and installs.
+/// Base directories are not allowed to overlap, so no state is ever shared between two instances.
+#[derive(Debug, Default)]
+pub struct Instances {
+    instances: BTreeMap<String, Dirs>,
+}
+
+impl Instances {
+    pub fn new() -> Self {
+        Instances {
+            instances: BTreeMap::new(),
+        }
+    }
+
+    pub fn add<P: AsRef<Path>>(&mut self, name: &str, base: P) -> Result<&Dirs, InstanceError> {
+        let base = base.as_ref();
+        if self.instances.contains_key(name) {
+            return Err(InstanceError::DuplicateName(name.to_owned()));
+        }
+
+        if let Some((other, _)) = self
+            .instances
+            .iter()
+            .find(|(_, dirs)| dirs.base.starts_with(base) || base.starts_with(&dirs.base))
+        {
+            return Err(InstanceError::OverlappingBaseDir(
+                base.to_owned(),
+                other.clone(),
+            ));
+        }
+
+        Ok(self
+            .instances
+            .entry(name.to_owned())
+            .or_insert_with(|| Dirs::new(base)))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Dirs> {
+        self.instances.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Dirs> {
+        self.instances.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Dirs)> {
+        self.instances
+            .iter()
+            .map(|(name, dirs)| (name.as_str(), dirs))
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    /// Runs `command` against the base directory of the instance called `name`.
+    pub fn run_command<S: System, B: Builder>(
+        &self,
+        name: &str,
+        command: Command,
+        system: &mut S,
+        builder: B,
+    ) -> Result<(), RunError<S, B>>
+    where
+        B::Requirement: Supports<CreateDirectory>,
+    {
+        let dirs = self
+            .get(name)
+            .ok_or_else(|| RunError::UnknownInstance(name.to_owned()))?;
+
+        SiDe::run_command(command, dirs, system, builder)
+    }
+}
+
 pub struct VersionedPath {
     path: PathBuf,
     version: u64,
@@ -289,12 +760,19 @@ pub struct StateDirs {
     version: u64,
     base: PathBuf,
     db: PathBuf,
+    pending_db: PathBuf,
+    progress: PathBuf,
+    outputs: PathBuf,
+    deleted: PathBuf,
+    arch: PathBuf,
     generated: PathBuf,
     chroots: PathBuf,
     data: PathBuf,
     config: PathBuf,
     files_exposed: PathBuf,
     backup: PathBuf,
+    db_format: DbFormat,
+    migrations: Migrations,
 }
 
 impl StateDirs {
@@ -302,6 +780,39 @@ impl StateDirs {
         &self.db
     }
 
+    pub fn load_outputs<S: System>(&self, system: &S) -> Result<Outputs, OutputsError<S>> {
+        Outputs::load(&self.outputs, system)
+    }
+
+    pub fn write_outputs<S: System>(
+        &self,
+        system: &S,
+        outputs: &Outputs,
+    ) -> Result<(), OutputsError<S>> {
+        outputs.save(&self.outputs, system)
+    }
+
+    pub fn load_deleted<S: System>(&self, system: &S) -> Result<DeletedFiles, DeletedError<S>> {
+        DeletedFiles::load(&self.deleted, system)
+    }
+
+    pub fn write_deleted<S: System>(
+        &self,
+        system: &S,
+        deleted: &DeletedFiles,
+    ) -> Result<(), DeletedError<S>> {
+        deleted.save(&self.deleted, system)
+    }
+
+    /// The architecture that the install was built for, or `None` for installs that were created before it was recorded.
+    pub fn load_arch<S: System>(&self, system: &S) -> Result<Option<Arch>, ArchError<S>> {
+        Arch::load(&self.arch, system)
+    }
+
+    pub fn write_arch<S: System>(&self, system: &S, arch: Arch) -> Result<(), ArchError<S>> {
+        arch.save(&self.arch, system)
+    }
+
     pub fn create_dirs<S: System>(&self, system: &mut S) -> Result<(), InitError<S>> {
         create_dir_with_err(system, &self.generated)?;
         create_dir_with_err(system, &self.chroots)?;
@@ -340,12 +851,19 @@ impl StateDirs {
         self.generated.join(name).join("deleted-file-backup")
     }
 
-    pub fn load_install<R: DeserializeOwned, S: System>(&self, system: &mut S) -> SystemState<R> {
-        let contents = system.file_contents(&self.db).unwrap();
+    pub fn load_install<R: DeserializeOwned, S: System>(
+        &self,
+        system: &mut S,
+    ) -> Result<SystemState<R>, DbReadError<S>> {
+        self.read_db(system)
+    }
 
-        SystemState {
-            graph: serde_json::from_reader(&mut Cursor::new(contents)).unwrap(),
-        }
+    /// Reads the database of the install, in any format, and migrates it if it has an older schema version.
+    pub fn read_db<R: DeserializeOwned, S: System>(
+        &self,
+        system: &S,
+    ) -> Result<SystemState<R>, DbReadError<S>> {
+        self.read_db_from(&self.db, system)
     }
 
     pub fn write_dbs<R: Serialize, S: System>(
@@ -353,11 +871,81 @@ impl StateDirs {
         system: &mut S,
         dbs: &SystemState<R>,
     ) -> Result<(), DbWriteError<S>> {
-        let contents =
-            serde_json::to_string(&dbs.graph).map_err(DbWriteError::UnableToSerialize)?;
+        self.write_db_to(&self.db, system, dbs)
+    }
+
+    /// Reads the database that `side build` wrote before it applied the install, see [`StateDirs::write_pending_db`].
+    pub fn read_pending_db<R: DeserializeOwned, S: System>(
+        &self,
+        system: &S,
+    ) -> Result<SystemState<R>, DbReadError<S>> {
+        self.read_db_from(&self.pending_db, system)
+    }
+
+    fn read_db_from<R: DeserializeOwned, S: System>(
+        &self,
+        path: &Path,
+        system: &S,
+    ) -> Result<SystemState<R>, DbReadError<S>> {
+        let contents = system
+            .file_contents(path)
+            .map_err(|e| DbReadError::UnableToRead(path.to_owned(), e))?;
+
+        Ok(SystemState {
+            graph: db::decode(&contents, &self.migrations)
+                .map_err(|e| DbReadError::Invalid(path.to_owned(), e))?,
+        })
+    }
+
+    /// Writes the database of a new install before it is applied, so that an interrupted apply can be resumed.
+    /// The requirements that already existed are only known once the apply has finished, so it is kept apart from the database of the install until then.
+    pub fn write_pending_db<R: Serialize, S: System>(
+        &self,
+        system: &mut S,
+        dbs: &SystemState<R>,
+    ) -> Result<(), DbWriteError<S>> {
+        self.write_db_to(&self.pending_db, system, dbs)
+    }
+
+    fn write_db_to<R: Serialize, S: System>(
+        &self,
+        path: &Path,
+        system: &mut S,
+        dbs: &SystemState<R>,
+    ) -> Result<(), DbWriteError<S>> {
+        let contents = db::encode(&dbs.graph, self.db_format, self.migrations.version())
+            .map_err(DbWriteError::UnableToSerialize)?;
         system
-            .put_file_contents(&self.db, contents.as_bytes())
-            .map_err(|e| DbWriteError::UnableToCreateDb(self.db.clone(), e))?;
+            .put_file_contents(path, &contents)
+            .map_err(|e| DbWriteError::UnableToCreateDb(path.to_owned(), e))?;
+
+        Ok(())
+    }
+
+    /// Where the progress of an apply of the install is recorded while it runs, see [`resume`].
+    pub fn progress(&self) -> &Path {
+        &self.progress
+    }
+
+    /// Loads the progress of an interrupted apply of the install, if any.
+    pub fn load_progress<S: System>(
+        &self,
+        system: &S,
+    ) -> Result<Option<ApplyProgress>, ResumeError<S>> {
+        ApplyProgress::load(&self.progress, system)
+    }
+
+    /// Removes the progress and the pending database once an apply of the install has finished or has been reverted.
+    pub fn finish_apply<S: System>(&self, system: &mut S) -> Result<(), ResumeError<S>> {
+        ApplyProgress::remove(&self.progress, system)?;
+        let exists = system
+            .path_exists(&self.pending_db)
+            .map_err(|e| ResumeError::UnableToRemove(self.pending_db.clone(), e))?;
+        if exists {
+            system
+                .remove_file(&self.pending_db)
+                .map_err(|e| ResumeError::UnableToRemove(self.pending_db.clone(), e))?;
+        }
 
         Ok(())
     }
@@ -365,37 +953,401 @@ impl StateDirs {
 
 pub struct SiDe {}
 
-#[derive(StructOpt)]
+#[derive(Subcommand)]
 pub enum Command {
+    /// Create the directories and the initial install in the base directory
     Init,
+    /// Print the current install as JSON
+    #[command(visible_alias = "st")]
     Status,
+    /// Build the packages into a new install and apply it
+    #[command(visible_alias = "b")]
     Build {
-        #[structopt(long = "ignore-verification")]
+        #[arg(long = "ignore-verification")]
         ignore_verification: bool,
 
-        #[structopt(long = "ask-overwrite")]
+        #[arg(long = "ask-overwrite")]
         ask_overwrite: bool,
+
+        /// Which existing state may be overwritten: `never`, `always`, `ask` or `allow=<pattern>,...`. Defaults to `never`, or `ask` with --ask-overwrite
+        #[arg(long = "overwrite", conflicts_with = "ask_overwrite")]
+        overwrite: Option<OverwritePolicy>,
+
+        /// Show the changes and ask for approval before applying them
+        #[arg(long = "review")]
+        review: bool,
+
+        /// Where to apply the build: `live` or `oci:<path>`
+        #[arg(long = "target", default_value = "live")]
+        target: BuildTarget,
+
+        /// The root filesystem to build an OCI image from
+        #[arg(long = "rootfs")]
+        rootfs: Option<PathBuf>,
+
+        /// Cancel and revert the apply if it takes longer than this many seconds
+        #[arg(long = "timeout")]
+        timeout: Option<u64>,
+
+        /// Cancel and revert the apply if a single requirement takes longer than this many seconds
+        #[arg(long = "node-timeout")]
+        node_timeout: Option<u64>,
+
+        /// Apply the new requirements before undoing the old ones, so that a failed apply never leaves the old requirements removed
+        #[arg(long = "create-first")]
+        create_first: bool,
+
+        /// When a requirement fails, only skip and revert the requirements that depend on it, and keep applying the others
+        #[arg(long = "isolate-failures")]
+        isolate_failures: bool,
+
+        /// Limit copying exposed files on remote systems to this many KiB per second
+        #[arg(long = "bandwidth-limit")]
+        bandwidth_limit: Option<u64>,
+
+        /// Copy exposed files on remote systems in chunks of this many KiB. An interrupted copy resumes after the last complete chunk
+        #[arg(long = "chunk-size", default_value = "8192", value_parser = clap::value_parser!(u64).range(1..))]
+        chunk_size: u64,
     },
+    /// Apply an existing install
     Apply {
+        /// The number of the install to apply
         target: u64,
 
-        #[structopt(long = "ignore-verification")]
+        #[arg(long = "ignore-verification")]
         ignore_verification: bool,
 
-        #[structopt(long = "ask-overwrite")]
+        #[arg(long = "ask-overwrite")]
         ask_overwrite: bool,
+
+        /// Which existing state may be overwritten: `never`, `always`, `ask` or `allow=<pattern>,...`. Defaults to `never`, or `ask` with --ask-overwrite
+        #[arg(long = "overwrite", conflicts_with = "ask_overwrite")]
+        overwrite: Option<OverwritePolicy>,
+
+        /// Show the changes and ask for approval before applying them
+        #[arg(long = "review")]
+        review: bool,
+
+        /// Reboot at the end of a successful apply if a change requires it, and verify the install after the system has come back
+        #[arg(long = "allow-reboot")]
+        allow_reboot: bool,
+
+        /// Cancel and revert the apply if it takes longer than this many seconds
+        #[arg(long = "timeout")]
+        timeout: Option<u64>,
+
+        /// Cancel and revert the apply if a single requirement takes longer than this many seconds
+        #[arg(long = "node-timeout")]
+        node_timeout: Option<u64>,
+
+        /// Apply the new requirements before undoing the old ones, so that a failed apply never leaves the old requirements removed
+        #[arg(long = "create-first")]
+        create_first: bool,
+
+        /// When a requirement fails, only skip and revert the requirements that depend on it, and keep applying the others
+        #[arg(long = "isolate-failures")]
+        isolate_failures: bool,
+
+        /// Apply the target even if it is older than the current install
+        #[arg(long = "allow-downgrade")]
+        allow_downgrade: bool,
+
+        /// Only apply the target up to this checkpoint, and keep the current install until the entire target has been applied
+        #[arg(long = "until")]
+        until: Option<String>,
+    },
+    /// Continue an apply that was interrupted, skipping the requirements that it already undid and applied
+    Resume {
+        /// Resume the apply of this install, instead of the newest install whose apply was interrupted
+        #[arg(long = "install")]
+        install: Option<u64>,
+
+        /// Which existing state may be overwritten: `never`, `always`, `ask` or `allow=<pattern>,...`. Defaults to `never`
+        #[arg(long = "overwrite")]
+        overwrite: Option<OverwritePolicy>,
     },
+    /// Check that the system still matches the current install
+    #[command(visible_alias = "check")]
     Verify {
-        #[structopt(long = "fix")]
+        /// Fix the requirements that no longer hold
+        #[arg(long = "fix")]
         fix: bool,
+
+        /// Show the result of every requirement. With --fix, the install is only fixed if the result is approved
+        #[arg(long = "review")]
+        review: bool,
+
+        /// Only verify a random share of the requirements of every kind, such as `10%`
+        #[arg(long = "sample")]
+        sample: Option<SampleSize>,
+
+        /// The seed that selects the sample. A random seed is chosen and printed by default; pass it again to verify the same sample
+        #[arg(long = "seed", requires = "sample")]
+        seed: Option<u64>,
+
+        /// Only verify the requirements that were added or modified since this install
+        #[arg(long = "changed-since")]
+        changed_since: Option<u64>,
+
+        /// The output format: `text`, or `json` to print the result of every requirement to stdout
+        #[arg(long = "format", default_value = "text", conflicts_with = "review")]
+        format: VerifyFormat,
+    },
+    /// Generate a first-boot script from an install
+    Bootstrap {
+        /// The output format: `shell` or `cloud-init`
+        #[arg(long = "format", default_value = "shell")]
+        format: BootstrapFormat,
+
+        /// The file to write the output to, instead of stdout
+        #[arg(long = "output")]
+        output: Option<PathBuf>,
+
+        /// The install to generate the script from, instead of the current install
+        #[arg(long = "install")]
+        install: Option<u64>,
+    },
+    /// Describe what is managed on this system
+    Report {
+        /// Print the report as JSON
+        #[arg(long = "json")]
+        json: bool,
+    },
+    /// Print the requirement graph of an install in the DOT language of Graphviz
+    Graph {
+        /// The install to print, instead of the current install
+        version: Option<u64>,
+    },
+    /// Compare the requirements of two installs: which were added, removed and modified
+    Diff {
+        /// The older install
+        from: u64,
+
+        /// The newer install
+        to: u64,
+
+        /// Print the differences as JSON
+        #[arg(long = "json")]
+        json: bool,
+    },
+    /// Rebuild and print the changes that would be applied whenever a package changes
+    Watch {
+        /// The number of seconds between checks for changes
+        #[arg(long = "interval", default_value = "2", value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+    },
+    /// Apply the build to a throwaway sandbox seeded with the current install, and verify the result
+    Preview {
+        /// The sandbox to use: `lxc`, `lxc:<image>` or `chroot:<rootfs>`
+        #[arg(long = "sandbox", default_value = "lxc")]
+        sandbox: Sandbox,
+    },
+    /// Render the configuration files of the build in a throwaway sandbox, check them with nginx, php-fpm and systemd-analyze, and write them to a directory
+    Lint {
+        /// The directory to write the configuration files to, at their paths relative to the root directory
+        output: PathBuf,
+
+        /// The sandbox to use: `lxc`, `lxc:<image>` or `chroot:<rootfs>`
+        #[arg(long = "sandbox", default_value = "lxc")]
+        sandbox: Sandbox,
+    },
+    /// Print the outputs that the packages declared, or a single output
+    Output {
+        /// The name of the output, such as `www.url`. Prints all outputs if omitted
+        name: Option<String>,
+
+        /// Print the output as JSON
+        #[arg(long = "json")]
+        json: bool,
+
+        /// The install to read the outputs from, instead of the current install
+        #[arg(long = "install")]
+        install: Option<u64>,
+    },
+    /// Check the environment for common problems
+    Doctor {
+        /// Print the findings as JSON
+        #[arg(long = "json")]
+        json: bool,
+    },
+    /// Show the commands that were executed by previous applies
+    #[command(subcommand, visible_alias = "log")]
+    History(HistoryCommand),
+    /// List or restore the system files that were deleted by installs
+    #[command(subcommand)]
+    Deleted(DeletedCommand),
+    /// Periodically pull the packages, apply them when they changed, and verify the current install
+    Agent(AgentOptions),
+    /// Send a request to a running agent: `status`, `trigger-build`, `trigger-verify` or `cancel`
+    Control { method: String },
+    /// Manage the databases of the installs
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Remove the backups and old installs that the retention policy in side.toml no longer keeps
+    Maintain {
+        /// Only print what would be removed
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Upgrade the operating system to the next release of its distribution, and verify the current install before and after
+    UpgradeOs {
+        /// Upgrade even if the current install does not verify. Requirements that are already broken are not reported as invalidated
+        #[arg(long = "ignore-verification")]
+        ignore_verification: bool,
+
+        /// Reboot after the upgrade, and verify the install after the system has come back
+        #[arg(long = "allow-reboot")]
+        allow_reboot: bool,
+    },
+    /// Print a completion script for a shell: `bash`, `zsh`, `fish`, `elvish` or `powershell`
+    Completions { shell: Shell },
+    /// Create a new package from a template of the builder
+    NewPackage {
+        /// The name of the package, which is also the name of its directory
+        name: String,
+
+        /// The kind of package to create
+        #[arg(long = "template")]
+        template: PackageTemplate,
+    },
+    /// Explain what creating, verifying and undoing a kind of requirement does. Lists the kinds if omitted
+    Explain { kind: Option<String> },
+    /// Generate man pages for side and its subcommands
+    Man {
+        /// Write a page for every command to this directory, instead of printing the page of side
+        #[arg(long = "out-dir")]
+        out_dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Rewrite the databases in another format: `json` or `cbor`
+    Convert {
+        format: DbFormat,
+
+        /// Convert only the database of this install, instead of all installs
+        #[arg(long = "install")]
+        install: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DeletedCommand {
+    /// List the deleted files of all installs, newest install first
+    List,
+    /// Copy the backup of a deleted file back to where it was deleted
+    Restore {
+        path: PathBuf,
+
+        /// Restore the backup of this install, instead of the newest backup
+        #[arg(long = "install")]
+        install: Option<u64>,
+
+        /// Overwrite the file if it exists
+        #[arg(long = "force")]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum HistoryCommand {
+    /// List the applies in the audit log
+    List,
+    /// Print the commands that were executed by an apply, and for fixes by `verify --fix`, what was fixed
+    Show {
+        apply: u64,
+
+        /// Print the commands as JSON
+        #[arg(long = "json")]
+        json: bool,
+    },
+    /// List every build, apply and verification: who ran it, the installs it went from and to, and how it ended
+    Invocations {
+        /// Print the invocations as JSON, including the requirements they applied and undid
+        #[arg(long = "json")]
+        json: bool,
+    },
+    /// Print the requirements that an invocation applied, undid and reverted
+    Invocation {
+        id: u64,
+
+        /// Print the invocation as JSON
+        #[arg(long = "json")]
+        json: bool,
     },
 }
 
-#[derive(StructOpt)]
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Init => "init",
+            Command::Status => "status",
+            Command::Build { .. } => "build",
+            Command::Apply { .. } => "apply",
+            Command::Resume { .. } => "resume",
+            Command::Verify { .. } => "verify",
+            Command::Bootstrap { .. } => "bootstrap",
+            Command::Report { .. } => "report",
+            Command::Graph { .. } => "graph",
+            Command::Diff { .. } => "diff",
+            Command::Watch { .. } => "watch",
+            Command::Preview { .. } => "preview",
+            Command::Lint { .. } => "lint",
+            Command::Output { .. } => "output",
+            Command::Doctor { .. } => "doctor",
+            Command::History(_) => "history",
+            Command::Deleted(_) => "deleted",
+            Command::Agent(_) => "agent",
+            Command::Control { .. } => "control",
+            Command::Db(_) => "db",
+            Command::Maintain { .. } => "maintain",
+            Command::UpgradeOs { .. } => "upgrade-os",
+            Command::Completions { .. } => "completions",
+            Command::NewPackage { .. } => "new-package",
+            Command::Explain { .. } => "explain",
+            Command::Man { .. } => "man",
+        }
+    }
+
+    /// Whether the command changes the installs or the system, so that it must not run at the same time as another such command.
+    /// The agent takes the lock for each of its applies instead.
+    pub fn locks(&self) -> bool {
+        match self {
+            Command::Init
+            | Command::Build { .. }
+            | Command::Apply { .. }
+            | Command::Resume { .. }
+            | Command::Verify { fix: true, .. }
+            | Command::Db(_)
+            | Command::Deleted(DeletedCommand::Restore { .. })
+            | Command::UpgradeOs { .. } => true,
+            Command::Maintain { dry_run } => !dry_run,
+            _ => false,
+        }
+    }
+}
+
+#[derive(clap::Parser)]
+#[command(about, subcommand_negates_reqs = true)]
 pub struct Args {
-    base_dir: PathBuf,
+    /// The directory that contains the packages and installs, such as `/srv`. Not needed for `completions`, `explain` and `man`
+    #[arg(required = true)]
+    base_dir: Option<PathBuf>,
 
-    #[structopt(subcommand)]
+    /// Print log messages as JSON instead of plain text
+    #[arg(long = "log-json")]
+    log_json: bool,
+
+    /// The format of the databases of new installs: `json` or `cbor`
+    #[arg(long = "db-format", default_value = "json")]
+    db_format: DbFormat,
+
+    /// Wait until other runs of side on the base directory have finished, instead of failing
+    #[arg(long = "wait")]
+    wait: bool,
+
+    #[command(subcommand)]
     command: Command,
 }
 
@@ -404,6 +1356,11 @@ pub struct Status {
     current_version: u64,
     base_path: PathBuf,
     backup_path: PathBuf,
+    pending_reboots: Vec<String>,
+    os_upgrades: Vec<OsUpgrade>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rollout: Option<Rollout>,
 }
 
 impl SiDe {
@@ -411,8 +1368,29 @@ impl SiDe {
     where
         B::Requirement: Supports<CreateDirectory>,
     {
-        let args = Args::from_args();
-        let dirs = Dirs::new(&args.base_dir);
+        let matches = Args::command().version(version()).get_matches();
+        let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        if args.log_json {
+            logging::init_json();
+        } else {
+            logging::init();
+        }
+
+        let base_dir = match (&args.base_dir, &args.command) {
+            (Some(base_dir), _) => base_dir.clone(),
+            (None, Command::Completions { .. } | Command::Explain { .. } | Command::Man { .. }) => {
+                PathBuf::new()
+            }
+            (None, _) => Args::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "the base directory is required",
+                )
+                .exit(),
+        };
+        let dirs = Dirs::new(base_dir)
+            .with_db_format(args.db_format)
+            .with_lock_wait(args.wait);
 
         Self::run_command(args.command, &dirs, system, builder)
     }
@@ -426,7 +1404,62 @@ impl SiDe {
     where
         B::Requirement: Supports<CreateDirectory>,
     {
-        match command {
+        let _span = tracing::info_span!(
+            "command",
+            name = command.name(),
+            base_dir = %dirs.base.display()
+        )
+        .entered();
+        let dirs = &dirs.clone().with_migrations(B::migrations());
+        let name = command.name();
+        let label = match command {
+            Command::Verify { fix: true, .. } => String::from("verify --fix"),
+            _ => name.to_owned(),
+        };
+        let notifies = matches!(
+            command,
+            Command::Build {
+                target: BuildTarget::Live,
+                ..
+            } | Command::Apply { .. }
+                | Command::Resume { .. }
+                | Command::Verify { .. }
+        );
+        // The base directory does not exist yet when `init` takes the lock
+        if matches!(command, Command::Init) {
+            system
+                .make_dir_all(&dirs.base)
+                .map_err(|e| RunError::LockFailed(dirs.lock.clone(), e))?;
+        }
+
+        let _lock = if command.locks() {
+            Some(lock::<S, B>(dirs, system)?)
+        } else {
+            None
+        };
+
+        if notifies {
+            let result = recorded(dirs, system, &label, |system| {
+                Self::execute(command, dirs, system, builder)
+            });
+            let (outcome, summary) = outcome_of(name, &result);
+            notify(dirs, system, name, outcome, summary);
+            result
+        } else {
+            Self::execute(command, dirs, system, builder)
+        }
+    }
+
+    fn execute<S: System, B: Builder>(
+        command: Command,
+        dirs: &Dirs,
+        system: &mut S,
+        builder: B,
+    ) -> Result<(), RunError<S, B>>
+    where
+        B::Requirement: Supports<CreateDirectory>,
+    {
+        match command {
             Command::Init => {
                 dirs.initialize::<B::Requirement, S>(system)
                     .map_err(RunError::InitFailed)?;
@@ -435,6 +1468,16 @@ impl SiDe {
             }
             Command::Status => {
                 let current = dirs.current_install(system).unwrap();
+                let pending_reboots = reboot::pending(dirs, system)
+                    .map_err(RunError::RebootFailed)?
+                    .into_iter()
+                    .map(|pending| pending.reason)
+                    .collect();
+                let os_upgrades = OsUpgradeHistory::new(&dirs.os_upgrades)
+                    .load(system)
+                    .map_err(RunError::OsUpgradeFailed)?;
+                let rollout =
+                    Rollout::load(&dirs.rollout, system).map_err(RunError::RolloutFailed)?;
 
                 println!(
                     "{}",
@@ -442,6 +1485,9 @@ impl SiDe {
                         current_version: current.version,
                         base_path: dirs.base.clone(),
                         backup_path: dirs.backups.clone(),
+                        pending_reboots,
+                        os_upgrades,
+                        rollout,
                     })
                     .unwrap()
                 );
@@ -452,157 +1498,2316 @@ impl SiDe {
                 target,
                 ignore_verification,
                 ask_overwrite,
+                overwrite,
+                review,
+                allow_reboot,
+                timeout,
+                node_timeout,
+                create_first,
+                isolate_failures,
+                allow_downgrade,
+                until,
             } => {
-                let current = dirs.current_install(system).unwrap();
-                let target = dirs.get_install(target);
-                let current_state = current.load_install::<B::Requirement, S>(system);
-                let target_state = target.load_install::<B::Requirement, S>(system);
-
-                if ignore_verification {
-                    println!("Skipping verification of current state...");
-                } else {
-                    println!("Verifying current state...");
-                    match current_state.verify_system_state(system).unwrap() {
-                        VerificationState::Ok => println!("Verification OK"),
-                        err @ VerificationState::Invalid { .. } => {
-                            panic!("Verification failed:\n{}", err)
-                        }
-                    }
+                existing_install::<S, B>(dirs, system, target)?;
+                let settings =
+                    Settings::load(&dirs.settings, system).map_err(RunError::SettingsFailed)?;
+                let limits = apply_limits(
+                    timeout,
+                    node_timeout,
+                    TransferLimits::default(),
+                    settings.retry,
+                );
+                audited(dirs, system, |system| {
+                    check_downgrade(dirs, system, target, allow_downgrade)?;
+                    apply_install(
+                        dirs,
+                        system,
+                        &builder,
+                        target,
+                        Interaction {
+                            overwrite: overwrite_policy(ask_overwrite, overwrite),
+                            review,
+                        },
+                        &limits,
+                        ApplyMode {
+                            ignore_verification,
+                            until: until.clone(),
+                            ..apply_mode(create_first, isolate_failures)
+                        },
+                    )
+                })?;
+                maintain_after_apply::<S, B>(dirs, system);
+                if allow_reboot {
+                    reboot_if_pending::<S, B>(dirs, system)?;
                 }
 
-                println!("Current: {}", current.version);
-                println!("Target : {}", target.version);
-
-                let cmp = target_state
-                    .graph
-                    .compare_with(system, &current_state.graph)
-                    .map_err(BuildError::DiffFailed)?;
-                let instructions = cmp
-                    .generate_application_sequence(system)
-                    .map_err(BuildError::ApplicationSequenceGenerationFailed)?;
-
-                // The result returned by run describes which requirements were pre-existing;
-                // That's not relevant to us, because we want to keep the original values that we determined when we created this install.
-                match instructions.run(system, |s| {
-                    if ask_overwrite {
-                        println!("Can {} be overwritten? Type 'yes' to continue or anything else to abort", s);
-                        let line = std::io::stdin().lock().lines().next().unwrap().unwrap();
-                        return line.trim() == "yes";
-                    }
-
-                    return false;
-                }) {
-                    Ok(_) => {}
-                    Err(err) => {
-                        println!();
-                        println!("Error: {}", err);
-                        println!("Reverting...");
-                        instructions
-                            .revert(system, &err.revert_info)
-                            .unwrap();
-
-                        println!("Revert OK");
-                        return Err(BuildError::ApplyFailed(err).into());
-                    }
+                Ok(())
+            }
+            Command::Resume { install, overwrite } => {
+                let progress = interrupted_apply::<S, B>(dirs, system, install)?;
+                let current = dirs
+                    .current_install(system)
+                    .map_err(RunError::CurrentInstallFailed)?;
+                if current.version != progress.from {
+                    return Err(RunError::ResumeFailed(ResumeError::CurrentChanged {
+                        install: progress.to,
+                        from: progress.from,
+                        current: current.version,
+                    }));
                 }
 
-                dirs.set_current_install(&target, system)
-                    .map_err(BuildError::UnableToChangeCurrentInstall)?;
-                println!("Done!");
+                info!("Resuming: {}", progress);
+                let settings =
+                    Settings::load(&dirs.settings, system).map_err(RunError::SettingsFailed)?;
+                let limits = apply_limits(None, None, TransferLimits::default(), settings.retry);
+                audited(dirs, system, |system| {
+                    apply_install(
+                        dirs,
+                        system,
+                        &builder,
+                        progress.to,
+                        Interaction {
+                            overwrite: overwrite_policy(false, overwrite),
+                            review: false,
+                        },
+                        &limits,
+                        // The current state is partially changed by the interrupted apply, so it cannot be verified
+                        ApplyMode {
+                            ignore_verification: true,
+                            until: progress.until.clone(),
+                            resume: Some(progress.clone()),
+                            ..apply_mode(
+                                progress.strategy == ApplyStrategy::CreateFirst,
+                                progress.isolate_failures,
+                            )
+                        },
+                    )
+                })?;
+                maintain_after_apply::<S, B>(dirs, system);
 
                 Ok(())
             }
             Command::Build {
                 ignore_verification,
                 ask_overwrite,
+                overwrite,
+                review,
+                target,
+                rootfs,
+                timeout,
+                node_timeout,
+                create_first,
+                isolate_failures,
+                bandwidth_limit,
+                chunk_size,
+            } => match target {
+                BuildTarget::Live => {
+                    let transfer = TransferLimits {
+                        chunk_size: chunk_size * 1024,
+                        bandwidth: bandwidth_limit.map(|limit| limit * 1024),
+                    };
+                    let settings =
+                        Settings::load(&dirs.settings, system).map_err(RunError::SettingsFailed)?;
+                    let limits = apply_limits(timeout, node_timeout, transfer, settings.retry);
+                    audited(dirs, system, |system| {
+                        build(
+                            dirs,
+                            system,
+                            &builder,
+                            Interaction {
+                                overwrite: overwrite_policy(ask_overwrite, overwrite),
+                                review,
+                            },
+                            &limits,
+                            ApplyMode {
+                                ignore_verification,
+                                ..apply_mode(create_first, isolate_failures)
+                            },
+                        )
+                    })?;
+                    maintain_after_apply::<S, B>(dirs, system);
+
+                    Ok(())
+                }
+                BuildTarget::Oci(output) => {
+                    if review {
+                        warn!("--review is ignored when building an OCI image");
+                    }
+
+                    let rootfs = rootfs.ok_or(RunError::OciFailed(OciError::MissingRootfs))?;
+                    oci::build_image(
+                        dirs,
+                        system,
+                        builder,
+                        &rootfs,
+                        &output,
+                        overwrite_policy(ask_overwrite, overwrite),
+                    )
+                    .map_err(RunError::OciFailed)
+                }
+            },
+            Command::Verify {
+                fix,
+                review,
+                sample,
+                seed,
+                changed_since,
+                format,
+            } => {
+                let (current, current_state, rollout) = load_current_state::<S, B>(dirs, system)?;
+                info!("Current install: {}", current.base.display());
+                if let Some(rollout) = rollout {
+                    info!("Rollout in progress: {}", rollout);
+                }
+
+                let graph = &current_state.graph;
+                let mut selected = vec![true; graph.len()];
+                if let Some(version) = changed_since {
+                    let since = existing_install::<S, B>(dirs, system, version)?
+                        .load_install::<B::Requirement, S>(system)
+                        .map_err(RunError::DbReadFailed)?;
+                    selected = sampling::changed_since(&since.graph, graph);
+                }
+                if let Some(size) = sample {
+                    let seed = seed.unwrap_or_else(rand::random);
+                    info!("Sampling {} of the requirements with --seed {}", size, seed);
+                    selected = sampling::stratified_sample(graph, &selected, size, seed);
+                }
+
+                let seq = graph.generate_verify_sequence().unwrap().select(&selected);
+                if seq.len() < graph.len() {
+                    info!("Verifying {} of {} requirements", seq.len(), graph.len());
+                }
+                let state = seq.run(system).unwrap();
+                if format == VerifyFormat::Json {
+                    let report = VerificationReport::new(current.version, graph, &selected, &state);
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                }
+
+                let approved = !review
+                    || review::run(&Review::verification(
+                        &format!("Verify install {}", current.version),
+                        &current_state.graph,
+                        &state,
+                        system,
+                        fix,
+                    )) == Decision::Approve;
+                match &state {
+                    VerificationState::Ok => info!("Verification OK"),
+                    VerificationState::Invalid { invalid, .. } => {
+                        warn!("Verification failed:\n{}", state);
+
+                        if fix && !approved {
+                            return Err(RunError::NotApproved);
+                        } else if fix {
+                            audited::<S, B>(dirs, system, |system| {
+                                let recorder = system.recorder();
+                                if let Some(recorder) = recorder {
+                                    recorder.fix_started();
+                                }
+                                let mut report = FixReport::observe(
+                                    recorder
+                                        .and_then(Recorder::recording_apply)
+                                        .unwrap_or_default(),
+                                    current.version,
+                                    &current_state.graph,
+                                    invalid,
+                                    system,
+                                );
+                                // The result returned by run describes which requirements were pre-existing;
+                                // That's not relevant to us, because we want to keep the original values that we determined when we created this install.
+                                // A failed fix is recorded in the report instead, so that it is saved for the post-incident review.
+                                match current_state.graph.generate_fix_sequence(system) {
+                                    Ok(seq) => {
+                                        if let Err(e) = seq
+                                            .run(system, &ConflictPolicy::Always(Resolution::Abort))
+                                        {
+                                            error!("Fixing failed: {}", e);
+                                            report.failed(&e);
+                                        }
+                                    }
+                                    Err(e) => {
+                                        error!("Unable to determine the fix: {}", e);
+                                        report.failed(&e);
+                                    }
+                                }
+
+                                report.verify(invalid, system);
+                                print!("{}", report.limited());
+                                report
+                                    .save(&dirs.fixes, system)
+                                    .map_err(RunError::FixReportFailed)?;
+
+                                if report.is_success() {
+                                    info!("Fixing successful!");
+                                    Ok(())
+                                } else {
+                                    Err(RunError::VerificationFailed)
+                                }
+                            })?;
+                        } else {
+                            return Err(RunError::VerificationFailed);
+                        }
+                    }
+                }
+
+                Ok(())
+            }
+            Command::Bootstrap {
+                format,
+                output,
+                install,
             } => {
+                let install = match install {
+                    Some(version) => existing_install::<S, B>(dirs, system, version)?,
+                    None => dirs.current_install(system).unwrap(),
+                };
+                info!(
+                    "Generating bootstrap script from install {}",
+                    install.version
+                );
+
+                let state = install
+                    .load_install::<B::Requirement, S>(system)
+                    .map_err(RunError::DbReadFailed)?;
+                let bootstrap = Bootstrap::generate(&state.graph, system);
+                for node in bootstrap.unsupported() {
+                    warn!("Cannot be represented in the bootstrap script: {}", node);
+                }
+
+                let rendered = bootstrap.render(format);
+                match output {
+                    Some(path) => system
+                        .put_file_contents(&path, rendered.as_bytes())
+                        .map_err(|e| RunError::UnableToWriteBootstrap(path.clone(), e))?,
+                    None => print!("{}", rendered),
+                }
+
+                Ok(())
+            }
+            Command::Report { json } => {
                 let current = dirs.current_install(system).unwrap();
-                let current_state = current.load_install::<B::Requirement, S>(system);
+                let state = current
+                    .load_install::<B::Requirement, S>(system)
+                    .map_err(RunError::DbReadFailed)?;
+                let report = Report::generate(current.version, &state.graph, system);
+
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                } else {
+                    print!("{}", report);
+                }
+
+                Ok(())
+            }
+            Command::Graph { version } => {
+                let install = match version {
+                    Some(version) => existing_install::<S, B>(dirs, system, version)?,
+                    None => dirs.current_install(system).unwrap(),
+                };
+                let state = install
+                    .load_install::<B::Requirement, S>(system)
+                    .map_err(RunError::DbReadFailed)?;
+                print!("{}", state.graph.to_dot());
 
-                if ignore_verification {
-                    println!("Skipping verification of current state...");
+                Ok(())
+            }
+            Command::Diff { from, to, json } => {
+                let old = existing_install::<S, B>(dirs, system, from)?
+                    .load_install::<B::Requirement, S>(system)
+                    .map_err(RunError::DbReadFailed)?;
+                let new = existing_install::<S, B>(dirs, system, to)?
+                    .load_install::<B::Requirement, S>(system)
+                    .map_err(RunError::DbReadFailed)?;
+                let diff = InstallDiff::compare(from, &old.graph, to, &new.graph);
+
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&diff).unwrap());
                 } else {
-                    println!("Verifying current state...");
-                    match current_state.verify_system_state(system).unwrap() {
-                        VerificationState::Ok => println!("Verification OK"),
-                        err @ VerificationState::Invalid { .. } => {
-                            panic!("Verification failed:\n{}", err)
+                    print!("{}", diff);
+                }
+
+                Ok(())
+            }
+            Command::Watch { interval } => {
+                watch(dirs, system, &builder, Duration::from_secs(interval))
+            }
+            Command::Preview { sandbox } => {
+                info!("Previewing the build in {:?}", sandbox);
+                let report = preview::preview(dirs, system, builder, &sandbox)
+                    .map_err(RunError::PreviewFailed)?;
+                print!("{}", report);
+
+                if report.is_success() {
+                    Ok(())
+                } else {
+                    Err(RunError::PreviewUnsuccessful)
+                }
+            }
+            Command::Lint { output, sandbox } => {
+                info!("Linting the build in {:?}", sandbox);
+                let report =
+                    lint::lint(dirs, system, &builder, &sandbox).map_err(RunError::LintFailed)?;
+                report
+                    .write(&output, system)
+                    .map_err(RunError::LintFailed)?;
+                print!("{}", report);
+
+                if report.is_success() {
+                    Ok(())
+                } else {
+                    Err(RunError::LintUnsuccessful)
+                }
+            }
+            Command::Output {
+                name,
+                json,
+                install,
+            } => {
+                let install = match install {
+                    Some(version) => existing_install::<S, B>(dirs, system, version)?,
+                    None => dirs.current_install(system).unwrap(),
+                };
+                let outputs = install
+                    .load_outputs(system)
+                    .map_err(RunError::OutputsFailed)?;
+
+                match name {
+                    Some(name) => {
+                        let value = outputs
+                            .get(&name)
+                            .ok_or(RunError::UnknownOutput(name.clone()))?;
+                        if json {
+                            println!("{}", value);
+                        } else {
+                            println!("{}", outputs::format_value(value));
                         }
                     }
+                    None if json => {
+                        println!("{}", serde_json::to_string_pretty(&outputs).unwrap())
+                    }
+                    None => print!("{}", outputs),
                 }
 
-                let new_install = dirs.fresh_install(system).unwrap();
-                println!("Current install: {}", current.base.display());
-                println!("New install: {}", new_install.base.display());
-
-                let packages = Packages::load(&dirs, system).unwrap();
-                let prepared = builder::run(&dirs, system, packages, &new_install, builder)
-                    .map_err(BuildError::BuildFailed)?;
-
-                let graph = prepared
-                    .generate_files(system, &current_state)
-                    .map_err(BuildError::UnableToGenerateFiles)?;
-                let cmp = graph
-                    .compare_with(system, &current_state.graph)
-                    .map_err(BuildError::DiffFailed)?;
-                let instructions = cmp
-                    .generate_application_sequence(system)
-                    .map_err(BuildError::ApplicationSequenceGenerationFailed)?;
-                match instructions.run(system, |s| {
-                    if ask_overwrite {
-                        println!("Can {} be overwritten? Type 'yes' to continue or anything else to abort", s);
-                        let line = std::io::stdin().lock().lines().next().unwrap().unwrap();
-                        return line.trim() == "yes";
+                Ok(())
+            }
+            Command::Doctor { json } => {
+                let report = doctor::diagnose::<B::Requirement, S>(dirs, system);
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+                } else {
+                    print!("{}", report);
+                }
+
+                if report.has_errors() {
+                    Err(RunError::DoctorFoundProblems)
+                } else {
+                    Ok(())
+                }
+            }
+            Command::History(command) => {
+                let audit_log = AuditLog::new(&dirs.audit_log);
+                let invocation_log = InvocationLog::new(&dirs.invocations);
+
+                match command {
+                    HistoryCommand::List => {
+                        let entries = audit_log.load(system).map_err(RunError::AuditFailed)?;
+                        for (apply, commands) in &entries.iter().group_by(|entry| entry.apply) {
+                            let commands = commands.collect::<Vec<_>>();
+                            let downgrade = commands
+                                .iter()
+                                .find_map(|entry| entry.downgrade)
+                                .map(|downgrade| format!(" ({})", downgrade))
+                                .unwrap_or_default();
+                            println!(
+                                "{} started {} ran {} commands{}",
+                                apply,
+                                commands[0].started,
+                                commands.len(),
+                                downgrade
+                            );
+                        }
+                    }
+                    HistoryCommand::Show { apply, json } => {
+                        let entries = audit_log.load(system).map_err(RunError::AuditFailed)?;
+                        let commands = entries
+                            .iter()
+                            .filter(|entry| entry.apply == apply)
+                            .collect::<Vec<_>>();
+                        if commands.is_empty() {
+                            return Err(RunError::UnknownApply(apply));
+                        }
+
+                        if json {
+                            println!("{}", serde_json::to_string_pretty(&commands).unwrap());
+                        } else {
+                            for command in commands {
+                                println!("{}", command);
+                            }
+
+                            if let Some(report) = FixReport::load(&dirs.fixes, apply, system)
+                                .map_err(RunError::FixReportFailed)?
+                            {
+                                print!("{}", report);
+                            }
+                        }
+                    }
+                    HistoryCommand::Invocations { json } => {
+                        let invocations =
+                            invocation_log.load(system).map_err(RunError::AuditFailed)?;
+                        if json {
+                            println!("{}", serde_json::to_string_pretty(&invocations).unwrap());
+                        } else {
+                            for invocation in invocations {
+                                println!("{}", invocation.summary());
+                            }
+                        }
+                    }
+                    HistoryCommand::Invocation { id, json } => {
+                        let invocation = invocation_log
+                            .load(system)
+                            .map_err(RunError::AuditFailed)?
+                            .into_iter()
+                            .find(|invocation| invocation.id == id)
+                            .ok_or(RunError::UnknownInvocation(id))?;
+                        if json {
+                            println!("{}", serde_json::to_string_pretty(&invocation).unwrap());
+                        } else {
+                            print!("{}", invocation);
+                        }
+                    }
+                }
+
+                Ok(())
+            }
+            Command::Deleted(command) => {
+                match command {
+                    DeletedCommand::List => {
+                        let backups =
+                            deleted::list(dirs, system).map_err(RunError::DeletedFailed)?;
+                        for backup in backups {
+                            println!("{}", backup);
+                        }
                     }
+                    DeletedCommand::Restore {
+                        path,
+                        install,
+                        force,
+                    } => {
+                        let restored = deleted::restore(dirs, system, &path, install, force)
+                            .map_err(RunError::DeletedFailed)?;
+                        println!(
+                            "Restored {} from install {}",
+                            path.display(),
+                            restored.install
+                        );
+
+                        let still_deleted = dirs
+                            .current_install(system)
+                            .ok()
+                            .and_then(|current| current.load_deleted(system).ok())
+                            .map(|deleted| deleted.find(&path).is_some())
+                            .unwrap_or(false);
+                        if still_deleted {
+                            warn!(
+                                "The current install deletes {}, so it will be deleted again by the next apply",
+                                path.display()
+                            );
+                        }
+                    }
+                }
+
+                Ok(())
+            }
+            Command::Agent(options) => {
+                if options.install_unit {
+                    let exe = std::env::current_exe().map_err(RunError::CurrentExeNotFound)?;
+                    agent::install_unit(system, &exe, &dirs.base, &options)
+                        .map_err(RunError::AgentFailed)
+                } else {
+                    run_agent(dirs, system, &builder, &options)
+                }
+            }
+            Command::Control { method } => {
+                let result =
+                    control::call(&dirs.agent_socket, &method).map_err(RunError::ControlFailed)?;
+                println!("{}", serde_json::to_string_pretty(&result).unwrap());
 
-                    return false;
-                }) {
-                    Ok(result) => {
-                        let _new_state = prepared.save(system, result).map_err(BuildError::SaveError)?;
-                        dirs.set_current_install(&new_install, system)
-                            .map_err(BuildError::UnableToChangeCurrentInstall)?;
+                Ok(())
+            }
+            Command::Db(DbCommand::Convert { format, install }) => {
+                let versions = match install {
+                    Some(version) => vec![existing_install::<S, B>(dirs, system, version)?.version],
+                    None => dirs
+                        .installed_versions(system)
+                        .map_err(RunError::UnableToListInstalls)?,
+                };
+
+                let target = dirs.clone().with_db_format(format);
+                for version in versions {
+                    let state = dirs
+                        .get_install(version)
+                        .read_db::<B::Requirement, S>(system)
+                        .map_err(RunError::DbReadFailed)?;
+                    target
+                        .get_install(version)
+                        .write_dbs(system, &state)
+                        .map_err(RunError::DbWriteFailed)?;
+                    info!(
+                        "Converted the database of install {} to {}",
+                        version, format
+                    );
+                }
 
-                        Ok(())
+                Ok(())
+            }
+            Command::Maintain { dry_run } => {
+                let settings =
+                    Settings::load(&dirs.settings, system).map_err(RunError::SettingsFailed)?;
+                if settings.retention.is_unlimited() {
+                    warn!(
+                        "There is no retention policy in {}, so nothing is removed",
+                        dirs.settings.display()
+                    );
+                    return Ok(());
+                }
+
+                maintain::<S, B>(dirs, system, &settings.retention, dry_run)
+            }
+            Command::UpgradeOs {
+                ignore_verification,
+                allow_reboot,
+            } => audited(dirs, system, |system| {
+                upgrade_os::<S, B>(dirs, system, ignore_verification, allow_reboot)
+            }),
+            Command::Completions { shell } => {
+                clap_complete::generate(
+                    shell,
+                    &mut Args::command(),
+                    binary_name(),
+                    &mut std::io::stdout(),
+                );
+
+                Ok(())
+            }
+            Command::NewPackage { name, template } => {
+                scaffold::check_name(&name).map_err(RunError::ScaffoldFailed)?;
+                let files = builder
+                    .package_template(template, &name)
+                    .ok_or(ScaffoldError::UnsupportedTemplate(template))
+                    .map_err(RunError::ScaffoldFailed)?;
+                let path = scaffold::create_package::<S, B::PackageConfig>(
+                    system,
+                    &dirs.packages,
+                    &name,
+                    &files,
+                )
+                .map_err(RunError::ScaffoldFailed)?;
+
+                info!("Created package {} in {}", name, path.display());
+                for file in files {
+                    println!("{}", path.join(file.path).display());
+                }
+
+                Ok(())
+            }
+            Command::Explain { kind } => {
+                let kinds = B::Requirement::kinds();
+                match kind {
+                    Some(kind) => {
+                        let documentation = kinds
+                            .into_iter()
+                            .find(|documentation| documentation.kind == kind)
+                            .ok_or(RunError::UnknownRequirementKind(kind.clone()))?;
+                        println!("{}", documentation);
                     }
-                    Err(err) => {
-                        println!();
-                        println!("Error: {}", err);
-                        println!("Reverting...");
-                        instructions
-                            .revert(system, &err.revert_info)
-                            .unwrap();
-
-                        println!("Revert OK");
-                        Err(BuildError::ApplyFailed(err).into())
+                    None => {
+                        let width = kinds
+                            .iter()
+                            .map(|documentation| documentation.kind.len())
+                            .max()
+                            .unwrap_or(0);
+                        for documentation in kinds {
+                            println!(
+                                "{:width$}  {}",
+                                documentation.kind,
+                                documentation.explanation.summary,
+                                width = width
+                            );
+                        }
                     }
                 }
+
+                Ok(())
             }
-            Command::Verify { fix } => {
-                let current = dirs.current_install(system).unwrap();
-                println!("Current install: {}", current.base.display());
+            Command::Man { out_dir } => {
+                let command = Args::command().name(binary_name()).version(version());
+                match out_dir {
+                    Some(out_dir) => clap_mangen::generate_to(command, &out_dir),
+                    None => clap_mangen::Man::new(command).render(&mut std::io::stdout()),
+                }
+                .map_err(RunError::ManFailed)
+            }
+        }
+    }
+}
 
-                let current_state = current.load_install::<B::Requirement, S>(system);
-                match current_state.verify_system_state(system).unwrap() {
-                    VerificationState::Ok => println!("Verification OK"),
-                    err @ VerificationState::Invalid { .. } => {
-                        println!("Verification failed:\n{}", err);
+/// The name that the binary was started as, which is used in completion scripts and man pages.
+fn binary_name() -> String {
+    std::env::args()
+        .next()
+        .and_then(|path| {
+            Path::new(&path)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| String::from("side"))
+}
 
-                        if fix {
-                            let seq = current_state.graph.generate_fix_sequence(system).unwrap();
+/// The version that `--version` prints: the version of `libside`, the platform it was built for,
+/// and the commit in `SIDE_GIT_COMMIT` if that was set at build time.
+pub fn version() -> String {
+    let mut metadata = vec![format!(
+        "{}-{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS
+    )];
+    if let Some(commit) = option_env!("SIDE_GIT_COMMIT") {
+        metadata.push(format!("commit {}", commit));
+    }
 
-                            // The result returned by run describes which requirements were pre-existing;
-                            // That's not relevant to us, because we want to keep the original values that we determined when we created this install.
-                            let _ = seq.run(system, |_| false).unwrap();
+    if cfg!(feature = "tui") {
+        metadata.push(String::from("tui"));
+    }
 
-                            println!("Fixing successful!");
-                        } else {
-                            return Err(RunError::VerificationFailed);
+    if cfg!(debug_assertions) {
+        metadata.push(String::from("debug"));
+    }
+
+    format!(
+        "{} (libside, {})",
+        env!("CARGO_PKG_VERSION"),
+        metadata.join(", ")
+    )
+}
+
+/// Returns install `version`, or an error if there is no such install.
+fn existing_install<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    version: u64,
+) -> Result<StateDirs, RunError<S, B>> {
+    let versions = dirs
+        .installed_versions(system)
+        .map_err(RunError::UnableToListInstalls)?;
+    if versions.contains(&version) {
+        Ok(dirs.get_install(version))
+    } else {
+        Err(RunError::UnknownInstall(version))
+    }
+}
+
+/// Runs `apply` while recording the commands that it executes in the audit log.
+/// The commands are recorded by the recorder that is attached to `system`, or by a new recorder that is attached for the duration of the apply.
+/// A failure to write the audit log is only logged if the apply itself failed, so that the original error is not hidden.
+fn audited<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    apply: impl FnOnce(&mut S) -> Result<(), RunError<S, B>>,
+) -> Result<(), RunError<S, B>> {
+    let log = AuditLog::new(&dirs.audit_log);
+    let id = log.next_apply(system).map_err(RunError::AuditFailed)?;
+    info!("Apply id: {}", id);
+
+    let recorder = system.recorder().cloned().unwrap_or_default();
+    let previous = system.set_recorder(Some(recorder.clone()));
+    recorder.start_recording(id);
+    let result = apply(system);
+    let entries = recorder.stop_recording();
+    system.set_recorder(previous);
+
+    match (result, log.append(system, &entries)) {
+        (Ok(()), written) => written.map_err(RunError::AuditFailed),
+        (Err(err), Ok(())) => Err(err),
+        (Err(err), Err(audit_err)) => {
+            error!("Unable to write the audit log: {}", audit_err);
+            Err(err)
+        }
+    }
+}
+
+/// Runs `command`, and records it in the invocation log along with the requirements that it applied and undid.
+/// A failure to write the log is only logged if the command itself failed, so that the original error is not hidden.
+fn recorded<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    command: &str,
+    run: impl FnOnce(&mut S) -> Result<(), RunError<S, B>>,
+) -> Result<(), RunError<S, B>> {
+    let current = |system: &mut S| {
+        dirs.current_install(system)
+            .ok()
+            .map(|install| install.version)
+    };
+    let from = current(system);
+    let started = Instant::now();
+    let started_at = agent::now();
+
+    let recorder = system.recorder().cloned().unwrap_or_default();
+    let previous = system.set_recorder(Some(recorder.clone()));
+    recorder.start_tracking();
+    let result = run(system);
+    let (apply, changes) = recorder.stop_tracking();
+    system.set_recorder(previous);
+
+    let (outcome, _) = outcome_of(command, &result);
+    let invocation = Invocation {
+        id: 0,
+        started: started_at,
+        duration_ms: started.elapsed().as_millis() as u64,
+        user: audit::current_user(),
+        command: command.to_owned(),
+        from,
+        to: current(system),
+        apply,
+        changes,
+        outcome,
+        error: result.as_ref().err().map(ToString::to_string),
+    };
+    let written = InvocationLog::new(&dirs.invocations).append(system, invocation);
+    match (result, written) {
+        (Ok(()), written) => written.map(|_| ()).map_err(RunError::AuditFailed),
+        (Err(err), Ok(_)) => Err(err),
+        (Err(err), Err(audit_err)) => {
+            error!("Unable to write the invocation log: {}", audit_err);
+            Err(err)
+        }
+    }
+}
+
+/// Takes the lock on the base directory, so that runs of side do not race on the installs, the secrets and the system itself.
+/// Fails if another run holds the lock, unless the dirs wait for it.
+fn lock<S: System, B: Builder>(dirs: &Dirs, system: &mut S) -> Result<SystemLock, RunError<S, B>> {
+    let take = |system: &mut S, wait| {
+        system
+            .lock(&dirs.lock, wait)
+            .map_err(|e| RunError::LockFailed(dirs.lock.clone(), e))
+    };
+    if let Some(lock) = take(system, false)? {
+        return Ok(lock);
+    }
+
+    let holder = lock_holder(dirs, system);
+    if dirs.wait_for_lock {
+        info!(
+            "Waiting for another run of side{} to release {}",
+            holder
+                .map(|pid| format!(" (pid {})", pid))
+                .unwrap_or_default(),
+            dirs.lock.display()
+        );
+        if let Some(lock) = take(system, true)? {
+            return Ok(lock);
+        }
+    }
+
+    Err(RunError::Locked {
+        path: dirs.lock.clone(),
+        holder,
+    })
+}
+
+/// The PID that the current holder of the lock wrote into the lock file.
+fn lock_holder<S: System>(dirs: &Dirs, system: &S) -> Option<u32> {
+    let contents = system.file_contents(&dirs.lock).ok()?;
+    String::from_utf8_lossy(&contents).trim().parse().ok()
+}
+
+/// The outcome of `command` for a notification, and a summary of its result.
+fn outcome_of<S: System, B: Builder>(
+    command: &str,
+    result: &Result<(), RunError<S, B>>,
+) -> (Outcome, String) {
+    match result {
+        Ok(()) => (Outcome::Succeeded, format!("side {} succeeded", command)),
+        Err(err @ RunError::BuildFailed(BuildError::ApplyFailed(_))) => {
+            (Outcome::Reverted, err.to_string())
+        }
+        Err(err @ RunError::BuildFailed(BuildError::PartiallyApplied(_))) => {
+            (Outcome::PartiallyApplied, err.to_string())
+        }
+        Err(err) => (Outcome::Failed, err.to_string()),
+    }
+}
+
+/// Sends the outcome of `command` to the notifiers in the settings. Failures are only logged, so that they do not hide the result of the command.
+fn notify<S: System>(
+    dirs: &Dirs,
+    system: &mut S,
+    command: &str,
+    outcome: Outcome,
+    summary: String,
+) {
+    let settings = match Settings::load(&dirs.settings, system) {
+        Ok(settings) => settings,
+        Err(e) => {
+            warn!("Unable to send notifications: {}", e);
+            return;
+        }
+    };
+
+    let install = dirs
+        .current_install(system)
+        .ok()
+        .map(|install| install.version);
+    let notification =
+        Notification::new(command, dirs.base.clone(), outcome, summary).with_install(install);
+    settings.notify.send(system, &notification);
+}
+
+/// Removes the backups and old installs that `policy` no longer keeps. With `dry_run`, they are only printed.
+fn maintain<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> Result<(), RunError<S, B>> {
+    let current = dirs.current_install(system).unwrap();
+    let state = current
+        .read_db::<B::Requirement, S>(system)
+        .map_err(RunError::DbReadFailed)?;
+    let in_use = state
+        .graph
+        .requirements()
+        .flat_map(|requirement| requirement.saved_original())
+        .map(|(_, backup)| backup.to_path_buf())
+        .collect::<Vec<_>>();
+    let entries = retention::entries(dirs, system, current.version, &in_use)
+        .map_err(RunError::RetentionFailed)?;
+    let plan = retention::plan(entries, policy, SystemTime::now());
+    let freed = plan.freed() as f64 / (1024. * 1024.);
+    if dry_run {
+        for removal in plan.removals.iter() {
+            println!("Would remove {}", removal);
+        }
+
+        println!(
+            "Would free {:.1} MiB, keeping {} entries",
+            freed,
+            plan.kept.len()
+        );
+    } else {
+        for removal in plan.removals.iter() {
+            info!("Removing {}", removal);
+        }
+
+        retention::remove(system, &plan).map_err(RunError::RetentionFailed)?;
+        if !plan.removals.is_empty() {
+            info!(
+                "Freed {:.1} MiB, keeping {} entries",
+                freed,
+                plan.kept.len()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Enforces the retention policy of the base directory after a successful apply.
+/// The apply has already succeeded at this point, so problems are only logged.
+fn maintain_after_apply<S: System, B: Builder>(dirs: &Dirs, system: &mut S) {
+    let result = Settings::load(&dirs.settings, system)
+        .map_err(RunError::SettingsFailed)
+        .and_then(|settings| {
+            if settings.retention.is_unlimited() {
+                Ok(())
+            } else {
+                maintain::<S, B>(dirs, system, &settings.retention, false)
+            }
+        });
+    if let Err(err) = result {
+        warn!("{}", err);
+    }
+}
+
+/// Refuses to apply an install that is older than the current install, unless `allow_downgrade` is set.
+/// Allowed downgrades are recorded in the audit log.
+fn check_downgrade<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    target: u64,
+    allow_downgrade: bool,
+) -> Result<(), RunError<S, B>> {
+    let current = dirs.current_install(system).unwrap();
+    if target < current.version {
+        if !allow_downgrade {
+            return Err(RunError::DowngradeNotAllowed(current.version, target));
+        }
+
+        warn!(
+            "Downgrading from install {} to the older install {}",
+            current.version, target
+        );
+        if let Some(recorder) = system.recorder() {
+            recorder.downgrade_started(current.version, target);
+        }
+    }
+
+    Ok(())
+}
+
+/// What `build` and `apply_install` ask the operator.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Interaction {
+    /// How to resolve conflicts with existing state.
+    pub overwrite: OverwritePolicy,
+
+    /// Show the changes and ask for approval before applying them, see [`review`].
+    pub review: bool,
+}
+
+impl Interaction {
+    /// Asks for approval of the changes that `instructions` make, if reviews are enabled.
+    fn review<S: System, B: Builder>(
+        &self,
+        title: &str,
+        instructions: &ApplySequence<B::Requirement>,
+    ) -> Result<(), RunError<S, B>> {
+        if self.review && review::run(&Review::plan(title, instructions)) != Decision::Approve {
+            return Err(RunError::NotApproved);
+        }
+
+        Ok(())
+    }
+}
+
+/// How `build` and `apply_install` run the changes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ApplyMode {
+    pub strategy: ApplyStrategy,
+
+    /// Do not verify the current state before applying.
+    pub ignore_verification: bool,
+
+    /// Only skip and revert the requirements that depend on a requirement that failed, see [`ApplySequence::run_isolated`].
+    pub isolate_failures: bool,
+
+    /// Only apply the target up to this checkpoint, see [`rollout`]. Only used by `apply_install`.
+    pub until: Option<String>,
+
+    /// Run the apply hooks of the builder, such as [`Builder::pre_apply`]. Sandboxes and images are applied without them.
+    pub hooks: bool,
+
+    /// Resume an apply that was interrupted, see [`resume`]. Only used by `apply_install`.
+    pub resume: Option<ApplyProgress>,
+}
+
+fn apply_mode(create_first: bool, isolate_failures: bool) -> ApplyMode {
+    ApplyMode {
+        strategy: if create_first {
+            ApplyStrategy::CreateFirst
+        } else {
+            ApplyStrategy::UndoFirst
+        },
+        ignore_verification: false,
+        isolate_failures,
+        until: None,
+        hooks: true,
+        resume: None,
+    }
+}
+
+/// The current install, the requirements that hold on the system, and the staged rollout in progress, if any.
+type CurrentState<R> = (StateDirs, SystemState<R>, Option<Rollout>);
+
+/// Loads the current install, and the requirements that hold on the system.
+/// While a staged rollout is in progress, these include the requirements of the install that is being rolled out, up to the checkpoints that have been applied.
+fn load_current_state<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+) -> Result<CurrentState<B::Requirement>, RunError<S, B>> {
+    let current = dirs.current_install(system).unwrap();
+    let mut state = current
+        .load_install::<B::Requirement, S>(system)
+        .map_err(RunError::DbReadFailed)?;
+    let rollout = Rollout::load(&dirs.rollout, system).map_err(RunError::RolloutFailed)?;
+    if let Some(rollout) = &rollout {
+        let target = dirs
+            .get_install(rollout.to)
+            .load_install::<B::Requirement, S>(system)
+            .map_err(RunError::DbReadFailed)?;
+        state.graph = rollout
+            .state(&state.graph, &target.graph)
+            .map_err(RunError::RolloutFailed)?;
+    }
+
+    Ok((current, state, rollout))
+}
+
+/// The policy selected with `--overwrite`, or with the older `--ask-overwrite`.
+fn overwrite_policy(ask_overwrite: bool, overwrite: Option<OverwritePolicy>) -> OverwritePolicy {
+    match overwrite {
+        Some(policy) => policy,
+        None if ask_overwrite => OverwritePolicy::Interactive,
+        None => OverwritePolicy::Never,
+    }
+}
+
+/// Creates the limits for an apply, and cancels the apply when the operator presses Ctrl-C.
+///
+/// The first Ctrl-C lets the requirement that is being applied finish (the commands it runs receive the signal as well), and then reverts the apply.
+/// A second Ctrl-C terminates `side` immediately.
+fn apply_limits(
+    timeout: Option<u64>,
+    node_timeout: Option<u64>,
+    transfer: TransferLimits,
+    retry: RetrySettings,
+) -> ApplyLimits {
+    let limits = ApplyLimits {
+        timeout: timeout.map(Duration::from_secs),
+        node_timeout: node_timeout.map(Duration::from_secs),
+        transfer,
+        retry,
+        ..Default::default()
+    };
+
+    let registered =
+        signal_hook::flag::register_conditional_shutdown(SIGINT, 1, limits.cancelled.clone())
+            .and_then(|_| signal_hook::flag::register(SIGINT, limits.cancelled.clone()));
+    if let Err(e) = registered {
+        warn!(
+            "Unable to handle Ctrl-C, interrupting the apply will not revert it: {}",
+            e
+        );
+    }
+
+    limits
+}
+
+/// Applies the existing install `target` to `system`.
+fn apply_install<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    builder: &B,
+    target: u64,
+    interaction: Interaction,
+    limits: &ApplyLimits,
+    mode: ApplyMode,
+) -> Result<(), RunError<S, B>> {
+    let (current, current_state, rollout) = load_current_state::<S, B>(dirs, system)?;
+    let target = dirs.get_install(target);
+    let _span =
+        tracing::info_span!("apply", from = current.version, install = target.version).entered();
+    // The database of a build that was interrupted is only complete once the apply has finished
+    let resumes_build = mode.resume.as_ref().is_some_and(|progress| progress.build);
+    let target_state = if resumes_build {
+        target.read_pending_db::<B::Requirement, S>(system)
+    } else {
+        target.load_install::<B::Requirement, S>(system)
+    }
+    .map_err(RunError::DbReadFailed)?;
+
+    if let Some(built_for) = target
+        .load_arch(system)
+        .map_err(BuildError::UnableToCheckArch)?
+    {
+        let arch = Arch::detect(system)
+            .map_err(BuildError::UnableToCheckArch)?
+            .unwrap_or_default();
+        if arch != built_for {
+            return Err(BuildError::WrongArch(built_for, arch).into());
+        }
+    }
+
+    if mode.ignore_verification {
+        info!("Skipping verification of current state...");
+    } else {
+        info!("Verifying current state...");
+        match current_state.verify_system_state(system).unwrap() {
+            VerificationState::Ok => info!("Verification OK"),
+            err @ VerificationState::Invalid { .. } => {
+                panic!("Verification failed:\n{}", err)
+            }
+        }
+    }
+
+    info!("Current: {}", current.version);
+    info!("Target : {}", target.version);
+
+    // With --until, only the requirements up to the checkpoint are applied.
+    // The current state already contains the checkpoints that an earlier apply of the same rollout reached.
+    let (target_graph, rollout) = match &mode.until {
+        Some(checkpoint) => {
+            let rollout = match rollout {
+                Some(rollout) if rollout.to != target.version => {
+                    return Err(RunError::RolloutFailed(RolloutError::InProgress(rollout)))
+                }
+                Some(rollout) => rollout,
+                None => Rollout {
+                    from: current.version,
+                    to: target.version,
+                    checkpoints: Vec::new(),
+                },
+            };
+            let step = Rollout {
+                checkpoints: vec![checkpoint.clone()],
+                ..rollout.clone()
+            };
+            let staged = step
+                .state(&current_state.graph, &target_state.graph)
+                .map_err(RunError::RolloutFailed)?;
+
+            info!("Checkpoint: {}", checkpoint);
+            (staged, Some(rollout.with_checkpoint(checkpoint)))
+        }
+        None => (target_state.graph, None),
+    };
+
+    let cmp = target_graph
+        .compare_with(system, &current_state.graph)
+        .map_err(BuildError::DiffFailed)?
+        .with_strategy(mode.strategy);
+    let instructions = cmp
+        .generate_application_sequence(system)
+        .map_err(BuildError::ApplicationSequenceGenerationFailed)?
+        .with_backups(dirs.undone_path(target.version))
+        .with_progress(
+            target.progress.clone(),
+            mode.resume.clone().unwrap_or_else(|| ApplyProgress {
+                strategy: mode.strategy,
+                isolate_failures: mode.isolate_failures,
+                until: mode.until.clone(),
+                ..ApplyProgress::new(current.version, target.version)
+            }),
+        );
+    preflight::<S, B>(system, &instructions)?;
+    interaction.review::<S, B>(
+        &match &mode.until {
+            Some(checkpoint) => format!(
+                "Apply install {} over install {} up to checkpoint {}",
+                target.version, current.version, checkpoint
+            ),
+            None => format!(
+                "Apply install {} over install {}",
+                target.version, current.version
+            ),
+        },
+        &instructions,
+    )?;
+
+    // The result returned by run describes which requirements were pre-existing;
+    // That's not relevant to us, because we want to keep the original values that we determined when we created this install.
+    // Only a build that is resumed still has to record them.
+    let run = run_instructions::<S, B>(
+        system,
+        builder,
+        &instructions,
+        &interaction.overwrite,
+        limits,
+        &mode,
+    );
+    let (result, failed) = finish_on_error(system, &target, run)?;
+    // The install is saved and made current even if the hook fails, because the apply is not reverted
+    let post_apply = if mode.hooks {
+        builder.post_apply(system, &instructions)
+    } else {
+        Ok(())
+    };
+    if resumes_build {
+        let state = SystemState {
+            graph: target_graph.apply_execution_results(result),
+        };
+        target
+            .write_dbs(system, &state)
+            .map_err(RunError::DbWriteFailed)?;
+    }
+
+    match rollout {
+        Some(rollout) => {
+            rollout
+                .save(&dirs.rollout, system)
+                .map_err(RunError::RolloutFailed)?;
+            info!(
+                "Reached checkpoint {}; run `side apply {}` to apply the rest",
+                mode.until.as_deref().unwrap_or_default(),
+                target.version
+            );
+        }
+        None => dirs
+            .set_current_install(&target, system)
+            .map_err(BuildError::UnableToChangeCurrentInstall)?,
+    }
+
+    target
+        .finish_apply(system)
+        .map_err(RunError::ResumeFailed)?;
+    post_apply.map_err(BuildError::PostApplyHookFailed)?;
+
+    if let Some(summary) = failed {
+        return Err(BuildError::PartiallyApplied(summary).into());
+    }
+
+    info!("Done!");
+
+    Ok(())
+}
+
+/// Runs `instructions`, and reverts them if the apply fails.
+///
+/// The `pre_apply` and `post_revert` hooks of `builder` run around the apply if `mode.hooks` is set.
+/// With `mode.isolate_failures`, a requirement that fails only fails the requirements that depend on it: they are skipped, the failed requirement is reverted, and the rest of the apply is kept.
+/// The summary of the failed subtrees is returned along with the result, if any subtree failed.
+fn run_instructions<S: System, B: Builder>(
+    system: &mut S,
+    builder: &B,
+    instructions: &ApplySequence<B::Requirement>,
+    policy: &OverwritePolicy,
+    limits: &ApplyLimits,
+    mode: &ApplyMode,
+) -> Result<(ApplyResult, Option<String>), RunError<S, B>> {
+    if mode.hooks {
+        builder
+            .pre_apply(system, instructions)
+            .map_err(BuildError::PreApplyHookFailed)?;
+    }
+
+    let result = if mode.isolate_failures {
+        match instructions.run_isolated(system, policy, limits) {
+            Ok(partial) if partial.is_complete() => Ok((partial.into_result(), None)),
+            Ok(partial) => {
+                warn!("Some requirements failed:\n{}", partial);
+                for subtree in partial.failed() {
+                    info!("Reverting {}...", subtree.error().requirement());
+                    instructions.revert_subtree(system, subtree).unwrap();
+                    error!("{}", subtree.error().report());
+                }
+
+                info!("Revert OK");
+                let summary = partial.to_string();
+                Ok((partial.into_result(), Some(summary)))
+            }
+            Err(err) => Err(err),
+        }
+    } else {
+        instructions
+            .run_with_limits(system, policy, limits)
+            .map(|result| (result, None))
+    };
+
+    result.map_err(|err| {
+        error!("Error: {}", err);
+        info!("Reverting...");
+        instructions.revert(system, &err.revert_info).unwrap();
+
+        info!("Revert OK");
+        if mode.hooks {
+            if let Err(hook_err) = builder.post_revert(system, instructions) {
+                error!("The post-revert hook failed: {}", hook_err);
+            }
+        }
+
+        error!("{}", err.report());
+        BuildError::ApplyFailed(err).into()
+    })
+}
+
+/// The progress of the interrupted apply of `install`, or of the newest install whose apply was interrupted.
+fn interrupted_apply<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    install: Option<u64>,
+) -> Result<ApplyProgress, RunError<S, B>> {
+    let versions = match install {
+        Some(version) => vec![version],
+        None => {
+            let mut versions = dirs
+                .installed_versions(system)
+                .map_err(|e| ResumeError::UnableToRead(dirs.installed.clone(), e))
+                .map_err(RunError::ResumeFailed)?;
+            versions.sort_unstable_by(|a, b| b.cmp(a));
+            versions
+        }
+    };
+
+    for version in versions {
+        let progress = dirs
+            .get_install(version)
+            .load_progress(system)
+            .map_err(RunError::ResumeFailed)?;
+        if let Some(progress) = progress {
+            return Ok(progress);
+        }
+    }
+
+    Err(RunError::ResumeFailed(match install {
+        Some(version) => ResumeError::NotInterrupted(version),
+        None => ResumeError::NothingToResume,
+    }))
+}
+
+/// Removes the progress of the apply of `install` if the apply failed, because it has been reverted.
+fn finish_on_error<S: System, B: Builder, T>(
+    system: &mut S,
+    install: &StateDirs,
+    result: Result<T, RunError<S, B>>,
+) -> Result<T, RunError<S, B>> {
+    if result.is_err() {
+        if let Err(e) = install.finish_apply(system) {
+            error!("{}", e);
+        }
+    }
+
+    result
+}
+
+/// Checks that `instructions` can be applied, before anything on `system` is changed.
+fn preflight<S: System, B: Builder>(
+    system: &mut S,
+    instructions: &ApplySequence<B::Requirement>,
+) -> Result<(), RunError<S, B>> {
+    info!("Running preflight checks...");
+    let report = instructions.preflight(system);
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(BuildError::PreflightFailed(report).into())
+    }
+}
+
+/// Upgrades the operating system to the next release, and reports the requirements of the current install that the upgrade invalidated.
+/// The upgrade is recorded in the upgrade history, also if it invalidated requirements.
+fn upgrade_os<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    ignore_verification: bool,
+    allow_reboot: bool,
+) -> Result<(), RunError<S, B>> {
+    let pending = reboot::pending(dirs, system).map_err(RunError::RebootFailed)?;
+    if !pending.is_empty() {
+        let reasons = pending
+            .into_iter()
+            .map(|pending| pending.reason)
+            .collect::<Vec<_>>();
+        return Err(RunError::OsUpgradeFailed(OsUpgradeError::RebootPending(
+            reasons.join(", "),
+        )));
+    }
+
+    let (distro, from) = os_upgrade::detect(system).map_err(RunError::OsUpgradeFailed)?;
+    let current = dirs.current_install(system).unwrap();
+    let current_state = current
+        .load_install::<B::Requirement, S>(system)
+        .map_err(RunError::DbReadFailed)?;
+
+    info!(
+        "Verifying install {} before the upgrade...",
+        current.version
+    );
+    let invalid_before = match current_state.verify_system_state(system).unwrap() {
+        VerificationState::Ok => {
+            info!("Verification OK");
+            BTreeSet::new()
+        }
+        err @ VerificationState::Invalid { .. } if !ignore_verification => {
+            warn!("Verification failed:\n{}", err);
+            return Err(RunError::VerificationFailed);
+        }
+        VerificationState::Invalid { invalid, .. } => {
+            warn!(
+                "Upgrading although {} requirements do not verify",
+                invalid.len()
+            );
+            invalid.into_iter().map(NodeId::of).collect()
+        }
+    };
+
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    info!("Upgrading {}...", from);
+    os_upgrade::upgrade(system, distro).map_err(RunError::OsUpgradeFailed)?;
+
+    if allow_reboot {
+        info!("Rebooting...");
+        reboot::reboot_and_wait(system, REBOOT_TIMEOUT).map_err(RunError::RebootFailed)?;
+    }
+
+    let (_, to) = os_upgrade::detect(system).map_err(RunError::OsUpgradeFailed)?;
+    info!("Upgraded from {} to {}", from, to);
+
+    info!("Verifying install {} after the upgrade...", current.version);
+    let invalidated = match current_state.verify_system_state(system).unwrap() {
+        VerificationState::Ok => Vec::new(),
+        VerificationState::Invalid { invalid, .. } => {
+            os_upgrade::invalidated(&invalid_before, &invalid)
+        }
+    };
+
+    OsUpgradeHistory::new(&dirs.os_upgrades)
+        .append(
+            system,
+            OsUpgrade {
+                install: current.version,
+                started,
+                from,
+                to,
+                invalidated: invalidated.clone(),
+            },
+        )
+        .map_err(RunError::OsUpgradeFailed)?;
+
+    if invalidated.is_empty() {
+        info!("Verification OK");
+    } else {
+        for item in invalidated.iter() {
+            warn!("Invalidated by the upgrade: {}", item);
+        }
+
+        warn!("Run `side verify --fix` or build a new install to restore the invalidated requirements");
+    }
+
+    Ok(())
+}
+
+/// Reboots `system` if any change requires it, and verifies the current install once the system has come back.
+fn reboot_if_pending<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+) -> Result<(), RunError<S, B>> {
+    let pending = reboot::pending(dirs, system).map_err(RunError::RebootFailed)?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    for reboot in pending.iter() {
+        info!("Reboot required: {}", reboot.reason);
+    }
+
+    info!("Rebooting...");
+    reboot::reboot_and_wait(system, REBOOT_TIMEOUT).map_err(RunError::RebootFailed)?;
+
+    info!("Verifying the install after the reboot...");
+    let current = dirs.current_install(system).unwrap();
+    let current_state = current
+        .load_install::<B::Requirement, S>(system)
+        .map_err(RunError::DbReadFailed)?;
+    match current_state.verify_system_state(system).unwrap() {
+        VerificationState::Ok => {
+            info!("Verification OK");
+            Ok(())
+        }
+        err @ VerificationState::Invalid { .. } => {
+            warn!("Verification failed:\n{}", err);
+            Err(RunError::VerificationFailed)
+        }
+    }
+}
+
+/// Builds the packages in `dirs` whenever they change, and prints the changes that applying the build would make.
+/// Only returns if the packages directory can no longer be read.
+fn watch<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    builder: &B,
+    interval: Duration,
+) -> Result<(), RunError<S, B>>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    let mut snapshot = None;
+    loop {
+        let next = Snapshot::take(&dirs.packages, system).map_err(RunError::WatchFailed)?;
+        let changed = match &snapshot {
+            Some(previous) => {
+                let changes = next.changes(previous);
+                for path in changes.iter() {
+                    info!("Changed: {}", path.display());
+                }
+
+                !changes.is_empty()
+            }
+            None => true,
+        };
+
+        if changed {
+            match plan(dirs, system, builder) {
+                Ok(()) => {}
+                Err(err) => error!("Build failed: {}", err),
+            }
+
+            info!("Waiting for changes...");
+        }
+
+        snapshot = Some(next);
+        std::thread::sleep(interval);
+    }
+}
+
+/// Pulls the packages from the source of the agent every `interval`, and builds and applies them when they changed.
+/// The current install is verified every `verify_interval`, and before applying changes; changes are not applied on top of an invalid install.
+/// Builds and verifications can also be requested, and applies cancelled, through the control socket.
+/// Returns once the agent is stopped with SIGTERM or Ctrl-C. An apply that is in progress at that point is reverted.
+fn run_agent<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    builder: &B,
+    options: &AgentOptions,
+) -> Result<(), RunError<S, B>>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    let settings = Settings::load(&dirs.settings, system).map_err(RunError::SettingsFailed)?;
+    let limits = apply_limits(
+        options.timeout,
+        options.node_timeout,
+        TransferLimits::default(),
+        settings.retry,
+    );
+    let stopped = Arc::new(AtomicBool::new(false));
+    let registered = signal_hook::flag::register(SIGTERM, limits.cancelled.clone())
+        .and_then(|_| signal_hook::flag::register(SIGTERM, stopped.clone()))
+        .and_then(|_| signal_hook::flag::register(SIGINT, stopped.clone()));
+    if let Err(e) = registered {
+        warn!(
+            "Unable to handle SIGTERM, stopping the agent will not revert an apply: {}",
+            e
+        );
+    }
+
+    let status = AgentStatus::load(system, &dirs.agent_status);
+    let control = Arc::new(Control::new(status, limits.cancelled.clone()));
+    match control::serve(&dirs.agent_socket, control.clone()) {
+        Ok(_) => info!("Listening on {}", dirs.agent_socket.display()),
+        Err(e) => warn!(
+            "Unable to listen on {}, the agent cannot be controlled: {}",
+            dirs.agent_socket.display(),
+            e
+        ),
+    }
+
+    info!("Pulling packages from {}", options.source);
+    let mut snapshot = None;
+    let mut next_verification = Instant::now();
+    let mut wake_up = Instant::now() + fleet::splay(Duration::from_secs(options.splay));
+    loop {
+        while Instant::now() < wake_up && !stopped.load(Ordering::SeqCst) && !control.has_requests()
+        {
+            std::thread::sleep(Duration::from_secs(1));
+        }
+
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let mut events = Vec::new();
+        let pulled = options
+            .source
+            .pull(system, &dirs.packages)
+            .map_err(|e| e.to_string())
+            .and_then(|_| Snapshot::take(&dirs.packages, system).map_err(|e| e.to_string()));
+        match pulled {
+            Ok(next) => {
+                let changes = match &snapshot {
+                    Some(previous) => next.changes(previous).len(),
+                    None => 0,
+                };
+                events.push(AgentEvent::Pulled { changes });
+
+                if control.take_build_request() || snapshot.is_none() || changes > 0 {
+                    let event =
+                        agent_apply(dirs, system, builder, options, &limits, &control, &stopped);
+                    if let Some(event) = event {
+                        // A failed apply is not retried until the packages change again, but a skipped apply is.
+                        if matches!(
+                            event,
+                            AgentEvent::Applied { .. } | AgentEvent::ApplyFailed(_)
+                        ) {
+                            snapshot = Some(next);
                         }
+
+                        events.push(event);
                     }
                 }
+            }
+            Err(err) => events.push(AgentEvent::PullFailed(err)),
+        }
+
+        if control.take_verify_request() || Instant::now() >= next_verification {
+            events.push(match verify_current::<S, B>(dirs, system) {
+                Ok(()) => AgentEvent::Verified,
+                Err(err) => {
+                    // Successful periodic verifications are not sent, because they would flood the notifiers
+                    notify(
+                        dirs,
+                        system,
+                        "agent",
+                        Outcome::Failed,
+                        format!("Verification failed:\n{}", err),
+                    );
+                    AgentEvent::VerificationFailed(err)
+                }
+            });
+            next_verification = Instant::now() + Duration::from_secs(options.verify_interval);
+        }
+
+        let mut status = control.status.lock().unwrap();
+        for event in events.iter() {
+            event.report();
+            status.record(event, agent::now());
+        }
+
+        if let Err(e) = status.save(system, &dirs.agent_status) {
+            warn!("Unable to save the agent status: {}", e);
+        }
+        drop(status);
+
+        wake_up = Instant::now()
+            + Duration::from_secs(options.interval)
+            + fleet::splay(Duration::from_secs(options.splay));
+    }
+
+    let _ = std::fs::remove_file(&dirs.agent_socket);
+    info!("Agent stopped");
+    Ok(())
+}
+
+/// Builds and applies the packages for the agent, after verifying the current install.
+/// If an apply lock is configured, the apply waits until a slot is available. Returns `None` if the agent was stopped in the meantime.
+fn agent_apply<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    builder: &B,
+    options: &AgentOptions,
+    limits: &ApplyLimits,
+    control: &Control,
+    stopped: &AtomicBool,
+) -> Option<AgentEvent>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    // A slot that is held for longer than any apply can take was left behind by an agent that crashed.
+    let stale_after = Duration::from_secs(2 * options.timeout.unwrap_or(3600));
+    let _slot = match &options.apply_lock {
+        Some(lock) => {
+            match lock.acquire(
+                &fleet::holder(),
+                options.max_concurrent,
+                stale_after,
+                stopped,
+            ) {
+                Ok(Some(slot)) => Some(slot),
+                Ok(None) => return None,
+                Err(err) => return Some(AgentEvent::ApplySkipped(err.to_string())),
+            }
+        }
+        None => None,
+    };
+
+    // A manual run that holds the lock is not interrupted; the agent applies the changes on its next check
+    let _lock = match system.lock(&dirs.lock, false) {
+        Ok(Some(lock)) => lock,
+        Ok(None) => {
+            return Some(AgentEvent::ApplySkipped(format!(
+                "another run of side{} holds {}",
+                lock_holder(dirs, system)
+                    .map(|pid| format!(" (pid {})", pid))
+                    .unwrap_or_default(),
+                dirs.lock.display()
+            )))
+        }
+        Err(err) => {
+            return Some(AgentEvent::ApplySkipped(format!(
+                "unable to lock {}: {}",
+                dirs.lock.display(),
+                err
+            )))
+        }
+    };
+
+    if let Err(err) = verify_current::<S, B>(dirs, system) {
+        return Some(AgentEvent::ApplySkipped(format!(
+            "the current install is invalid:\n{}",
+            err
+        )));
+    }
+
+    control.applying.store(true, Ordering::SeqCst);
+    let result = recorded(dirs, system, "agent", |system| {
+        audited(dirs, system, |system| {
+            build(
+                dirs,
+                system,
+                builder,
+                Interaction::default(),
+                limits,
+                ApplyMode {
+                    ignore_verification: true,
+                    ..apply_mode(options.create_first, false)
+                },
+            )
+        })
+    });
+    control.applying.store(false, Ordering::SeqCst);
+
+    // An apply that was cancelled through the control socket does not stop the agent.
+    if !stopped.load(Ordering::SeqCst) {
+        limits.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    if result.is_ok() {
+        maintain_after_apply::<S, B>(dirs, system);
+    }
+
+    let (outcome, summary) = outcome_of("agent", &result);
+    notify(dirs, system, "agent", outcome, summary);
+
+    Some(match result {
+        Ok(()) => AgentEvent::Applied {
+            install: dirs.current_install(system).unwrap().version,
+        },
+        Err(err) => AgentEvent::ApplyFailed(err.to_string()),
+    })
+}
+
+/// Verifies the current install, and returns the problems that were found if it is invalid.
+fn verify_current<S: System, B: Builder>(dirs: &Dirs, system: &mut S) -> Result<(), String> {
+    let (_, current_state, _) =
+        load_current_state::<S, B>(dirs, system).map_err(|e| e.to_string())?;
+    match current_state.verify_system_state(system) {
+        Ok(VerificationState::Ok) => Ok(()),
+        Ok(err @ VerificationState::Invalid { .. }) => Err(err.to_string()),
+        Err(()) => Err(String::from("unable to generate the verification sequence")),
+    }
+}
+
+/// Builds the packages in `dirs` into a temporary install and prints the changes that applying it would make.
+/// The temporary install is removed afterwards, and the system is not changed.
+fn plan<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    builder: &B,
+) -> Result<(), RunError<S, B>>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    let (current, current_state, _) = load_current_state::<S, B>(dirs, system)?;
+    let new_install = dirs.fresh_install(system).unwrap();
+
+    let packages = Packages::load(dirs, system).map_err(BuildError::BuildFailed)?;
+    let previous = PreviousInstall::new(current.version, &current_state);
+    let result = builder::run(dirs, system, packages, &new_install, previous, builder)
+        .map_err(BuildError::BuildFailed)
+        .and_then(|prepared| {
+            let graph = prepared
+                .generate_files(system, &current_state)
+                .map_err(BuildError::UnableToGenerateFiles)?;
+            let cmp = graph
+                .compare_with(system, &current_state.graph)
+                .map_err(BuildError::DiffFailed)?;
+            let instructions = cmp
+                .generate_application_sequence(system)
+                .map_err(BuildError::ApplicationSequenceGenerationFailed)?;
+            let plan = instructions.plan();
+
+            if plan.is_empty() {
+                info!("No changes");
+            } else {
+                print!("{}", plan);
+                for requirement in plan.create().iter().chain(plan.update().iter()) {
+                    if let Some(diff) = requirement.contents_diff(system) {
+                        print!("{}", diff);
+                    }
+                }
+            }
+
+            Ok(())
+        });
+
+    let base = new_install.base.to_string_lossy();
+    match system.execute_command("rm", &["-rf", &base]) {
+        Ok(result) if result.is_success() => {}
+        _ => warn!("Unable to remove temporary install {}", base),
+    }
+
+    Ok(result?)
+}
+
+/// A generated configuration file: the path that it is installed at, and its contents.
+pub(crate) type RenderedFile = (PathBuf, Vec<u8>);
+
+/// Builds the packages in `dirs` into a temporary install and returns the configuration files that they generate, with the paths that they are installed at.
+/// The temporary install is removed afterwards, and the files are not written.
+pub(crate) fn render_files<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    builder: &B,
+) -> Result<Vec<RenderedFile>, RunError<S, B>>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    let (current, current_state, _) = load_current_state::<S, B>(dirs, system)?;
+    let new_install = dirs.fresh_install(system).unwrap();
+
+    let packages = Packages::load(dirs, system).map_err(BuildError::BuildFailed)?;
+    let previous = PreviousInstall::new(current.version, &current_state);
+    let result = builder::run(dirs, system, packages, &new_install, previous, builder)
+        .map(|prepared| {
+            prepared
+                .generated_files()
+                .map(|(path, contents)| (path, contents.to_vec()))
+                .collect::<Vec<_>>()
+        })
+        .map_err(BuildError::BuildFailed);
+
+    let base = new_install.base.to_string_lossy();
+    match system.execute_command("rm", &["-rf", &base]) {
+        Ok(result) if result.is_success() => {}
+        _ => warn!("Unable to remove temporary install {}", base),
+    }
 
+    Ok(result?)
+}
+
+/// Builds the packages in `dirs` and applies the result to `system`.
+fn build<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    builder: &B,
+    interaction: Interaction,
+    limits: &ApplyLimits,
+    mode: ApplyMode,
+) -> Result<(), RunError<S, B>>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    let (current, current_state, _) = load_current_state::<S, B>(dirs, system)?;
+
+    if mode.ignore_verification {
+        info!("Skipping verification of current state...");
+    } else {
+        info!("Verifying current state...");
+        match current_state.verify_system_state(system).unwrap() {
+            VerificationState::Ok => info!("Verification OK"),
+            err @ VerificationState::Invalid { .. } => {
+                panic!("Verification failed:\n{}", err)
+            }
+        }
+    }
+
+    let new_install = dirs.fresh_install(system).unwrap();
+    let _span = tracing::info_span!(
+        "build",
+        from = current.version,
+        install = new_install.version
+    )
+    .entered();
+    info!("Current install: {}", current.base.display());
+    info!("New install: {}", new_install.base.display());
+
+    let packages = Packages::load(&dirs, system).map_err(BuildError::BuildFailed)?;
+    let previous = PreviousInstall::new(current.version, &current_state);
+    let prepared = builder::run(&dirs, system, packages, &new_install, previous, builder)
+        .map_err(BuildError::BuildFailed)?
+        .with_transfer_limits(limits.transfer);
+
+    let graph = prepared
+        .generate_files(system, &current_state)
+        .map_err(BuildError::UnableToGenerateFiles)?;
+    let cmp = graph
+        .compare_with(system, &current_state.graph)
+        .map_err(BuildError::DiffFailed)?
+        .with_strategy(mode.strategy);
+    let instructions = cmp
+        .generate_application_sequence(system)
+        .map_err(BuildError::ApplicationSequenceGenerationFailed)?
+        .with_backups(dirs.undone_path(new_install.version))
+        .with_progress(
+            new_install.progress.clone(),
+            ApplyProgress {
+                build: true,
+                strategy: mode.strategy,
+                isolate_failures: mode.isolate_failures,
+                ..ApplyProgress::new(current.version, new_install.version)
+            },
+        );
+    preflight::<S, B>(system, &instructions)?;
+    interaction.review::<S, B>(
+        &format!("Build install {}", new_install.version),
+        &instructions,
+    )?;
+    prepared.save_pending(system);
+    let run = run_instructions::<S, B>(
+        system,
+        builder,
+        &instructions,
+        &interaction.overwrite,
+        limits,
+        &mode,
+    );
+    let (result, failed) = finish_on_error(system, &new_install, run)?;
+    // The install is saved and made current even if the hook fails, because the apply is not reverted
+    let post_apply = if mode.hooks {
+        builder.post_apply(system, &instructions)
+    } else {
+        Ok(())
+    };
+    let _new_state = prepared
+        .save(system, result)
+        .map_err(BuildError::SaveError)?;
+    dirs.set_current_install(&new_install, system)
+        .map_err(BuildError::UnableToChangeCurrentInstall)?;
+    new_install
+        .finish_apply(system)
+        .map_err(RunError::ResumeFailed)?;
+    post_apply.map_err(BuildError::PostApplyHookFailed)?;
+
+    match failed {
+        Some(summary) => Err(BuildError::PartiallyApplied(summary).into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        interrupted_apply, lock, run_instructions, ApplyMode, Args, Command, Dirs, HistoryCommand,
+        InitError, InstanceError, Instances, RunError,
+    };
+    use crate::builder::{fs::CreateDirectory, Builder, Context, Package};
+    use crate::conflict::OverwritePolicy;
+    use crate::graph::{Applied, ApplyLimits, ApplySequence, Graph, Pending};
+    use crate::resume::ApplyProgress;
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+    use clap::{CommandFactory, Parser};
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("hook failed")]
+    struct HookError;
+
+    /// Records the hooks that were called, and fails `pre_apply` if `fail_pre_apply` is set.
+    #[derive(Debug, Default)]
+    struct HookBuilder {
+        calls: Mutex<Vec<&'static str>>,
+        fail_pre_apply: bool,
+    }
+
+    impl Builder for HookBuilder {
+        type PackageConfig = ();
+        type Data = ();
+        type Prepared = ();
+        type Requirement = CreateDirectory;
+        type BuildError = HookError;
+
+        fn start_build(
+            &self,
+            _context: &mut Context<Self::Requirement>,
+        ) -> Result<Self::Data, Self::BuildError> {
+            Ok(())
+        }
+
+        fn prepare_package(
+            &self,
+            _package: &Package<Self::PackageConfig>,
+        ) -> Result<Self::Prepared, Self::BuildError> {
+            Ok(())
+        }
+
+        fn build_package(
+            &self,
+            _package: &Package<Self::PackageConfig>,
+            _prepared: Self::Prepared,
+            _context: &mut Context<Self::Requirement>,
+            _data: &mut Self::Data,
+        ) -> Result<(), Self::BuildError> {
+            Ok(())
+        }
+
+        fn finish_build(
+            &self,
+            _context: &mut Context<Self::Requirement>,
+            _data: Self::Data,
+        ) -> Result<(), Self::BuildError> {
+            Ok(())
+        }
+
+        fn pre_apply<S: System>(
+            &self,
+            _system: &mut S,
+            sequence: &ApplySequence<Self::Requirement>,
+        ) -> Result<(), Self::BuildError> {
+            assert!(!sequence.plan().is_empty());
+            self.calls.lock().unwrap().push("pre_apply");
+            if self.fail_pre_apply {
+                Err(HookError)
+            } else {
                 Ok(())
             }
         }
+
+        fn post_revert<S: System>(
+            &self,
+            _system: &mut S,
+            _sequence: &ApplySequence<Self::Requirement>,
+        ) -> Result<(), Self::BuildError> {
+            self.calls.lock().unwrap().push("post_revert");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn instances_are_independent() {
+        let mut instances = Instances::new();
+        instances.add("a", "/srv/a").unwrap();
+        instances.add("b", "/srv/b").unwrap();
+
+        let a = instances.get("a").unwrap();
+        let b = instances.get("b").unwrap();
+        assert_eq!(a.base(), Path::new("/srv/a"));
+        assert_eq!(b.base(), Path::new("/srv/b"));
+        assert_ne!(a.secrets, b.secrets);
+        assert_ne!(a.packages, b.packages);
+        assert_eq!(instances.len(), 2);
+    }
+
+    #[test]
+    fn instances_cannot_overlap() {
+        let mut instances = Instances::new();
+        instances.add("a", "/srv/a").unwrap();
+
+        assert!(matches!(
+            instances.add("a", "/srv/other"),
+            Err(InstanceError::DuplicateName(_))
+        ));
+        assert!(matches!(
+            instances.add("b", "/srv/a/nested"),
+            Err(InstanceError::OverlappingBaseDir(_, _))
+        ));
+        assert!(matches!(
+            instances.add("c", "/srv"),
+            Err(InstanceError::OverlappingBaseDir(_, _))
+        ));
+    }
+
+    #[test]
+    fn originals_are_versioned() {
+        let dirs = Dirs::new("/srv");
+
+        assert_eq!(
+            dirs.originals_path(3),
+            Path::new("/srv/backups/_originals/3")
+        );
+        assert_ne!(dirs.originals_path(3), dirs.originals_path(4));
+    }
+
+    #[test]
+    fn apply_hooks() {
+        let mut system = LocalSystem::new();
+        let temp = TempDir::new("hooks");
+        let dir = temp.join("hooked");
+        let v0 = Graph::<CreateDirectory, Applied>::new();
+        let run = |builder: &HookBuilder, graph: &Graph<_, Pending>, hooks: bool| {
+            let mut system = LocalSystem::new();
+            let cmp = graph.compare_with(&mut system, &v0).unwrap();
+            let sequence = cmp.generate_application_sequence(&mut system).unwrap();
+            let mode = ApplyMode {
+                hooks,
+                ..ApplyMode::default()
+            };
+            run_instructions::<_, HookBuilder>(
+                &mut system,
+                builder,
+                &sequence,
+                &OverwritePolicy::Never,
+                &ApplyLimits::default(),
+                &mode,
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+        };
+
+        let mut create = Graph::new();
+        create.add(CreateDirectory::new(dir.clone()), &[]);
+        let builder = HookBuilder {
+            fail_pre_apply: true,
+            ..HookBuilder::default()
+        };
+        assert_eq!(
+            run(&builder, &create, true).unwrap_err(),
+            "Build failed: The pre-apply hook failed, nothing has been changed: hook failed"
+        );
+        assert!(!system.path_exists(&dir).unwrap());
+
+        let builder = HookBuilder::default();
+        run(&builder, &create, true).unwrap();
+        assert!(system.path_exists(&dir).unwrap());
+        system.remove_dir(&dir).unwrap();
+
+        // The second directory cannot be created, so the first one is removed again
+        let mut failing = Graph::new();
+        let parent = failing.add(CreateDirectory::new(dir.clone()), &[]);
+        failing.add(CreateDirectory::new(dir.join("missing/child")), &[parent]);
+        assert!(run(&builder, &failing, true).is_err());
+        assert!(!system.path_exists(&dir).unwrap());
+        assert_eq!(
+            *builder.calls.lock().unwrap(),
+            ["pre_apply", "pre_apply", "post_revert"]
+        );
+
+        let builder = HookBuilder::default();
+        assert!(run(&builder, &failing, false).is_err());
+        assert!(builder.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_runs_are_locked() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("locked");
+        let dirs = Dirs::new(&dir);
+
+        let held = system.lock(&dirs.lock, false).unwrap().unwrap();
+        let err = lock::<_, HookBuilder>(&dirs, &mut system).unwrap_err();
+        assert!(matches!(
+            err,
+            RunError::Locked { holder: Some(pid), .. } if pid == std::process::id()
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Another run of side (pid {}) holds the lock on {}; pass --wait to wait until it finishes",
+                std::process::id(),
+                dir.join("lock").display()
+            )
+        );
+
+        // With --wait, the run continues once the other run has finished
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(held);
+        });
+        let dirs = dirs.with_lock_wait(true);
+        let lock = lock::<_, HookBuilder>(&dirs, &mut system).unwrap();
+        assert!(lock.is_held());
+        release.join().unwrap();
+
+        drop(lock);
+    }
+
+    #[test]
+    fn init_is_locked() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("init-locked");
+        let dirs = Dirs::new(dir.join("base"));
+
+        assert!(Command::Init.locks());
+        system.make_dir_all(&dirs.base).unwrap();
+        let lock = lock::<_, HookBuilder>(&dirs, &mut system).unwrap();
+        dirs.initialize::<CreateDirectory, _>(&mut system).unwrap();
+        assert!(lock.is_held());
+        drop(lock);
+
+        assert!(matches!(
+            dirs.initialize::<CreateDirectory, _>(&mut system),
+            Err(InitError::BaseDirectoryNotEmpty(_))
+        ));
+    }
+
+    #[test]
+    fn interrupted_applies_are_found() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("interrupted");
+        let dirs = Dirs::new(&dir);
+        for version in [3, 4] {
+            system
+                .make_dir_all(&dirs.get_install(version).base)
+                .unwrap();
+        }
+
+        let found = |system: &mut LocalSystem, install| {
+            interrupted_apply::<_, HookBuilder>(&dirs, system, install).map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            found(&mut system, None).unwrap_err(),
+            "Unable to resume the apply: there is no interrupted apply to resume"
+        );
+
+        let progress = ApplyProgress::new(2, 3);
+        let install = dirs.get_install(3);
+        progress.save(install.progress(), &mut system).unwrap();
+        assert_eq!(found(&mut system, None).unwrap(), progress);
+        assert_eq!(found(&mut system, Some(3)).unwrap(), progress);
+        assert_eq!(
+            found(&mut system, Some(4)).unwrap_err(),
+            "Unable to resume the apply: the apply of install 4 was not interrupted"
+        );
+
+        install.finish_apply(&mut system).unwrap();
+        assert!(found(&mut system, Some(3)).is_err());
+    }
+
+    #[test]
+    fn cli_is_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn parse_arguments() {
+        let args =
+            Args::try_parse_from(["side", "/srv", "apply", "3", "--allow-downgrade"]).unwrap();
+        assert_eq!(args.base_dir.as_deref(), Some(Path::new("/srv")));
+        assert!(matches!(
+            args.command,
+            Command::Apply {
+                target: 3,
+                allow_downgrade: true,
+                ..
+            }
+        ));
+
+        let args = Args::try_parse_from(["side", "/srv", "check", "--fix"]).unwrap();
+        assert!(matches!(args.command, Command::Verify { fix: true, .. }));
+        assert!(args.command.locks());
+        assert!(!args.wait);
+
+        let args = Args::try_parse_from(["side", "/srv", "verify"]).unwrap();
+        assert!(!args.command.locks());
+
+        let args = Args::try_parse_from(["side", "/srv", "--wait", "apply", "3"]).unwrap();
+        assert!(args.wait);
+
+        let args = Args::try_parse_from(["side", "/srv", "resume", "--install", "4"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::Resume {
+                install: Some(4),
+                overwrite: None
+            }
+        ));
+        assert!(args.command.locks());
+
+        let args =
+            Args::try_parse_from(["side", "/srv", "history", "invocation", "3", "--json"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::History(HistoryCommand::Invocation { id: 3, json: true })
+        ));
+
+        let args = Args::try_parse_from(["side", "/srv", "graph", "3"]).unwrap();
+        assert!(matches!(args.command, Command::Graph { version: Some(3) }));
+
+        let args = Args::try_parse_from(["side", "completions", "bash"]).unwrap();
+        assert_eq!(args.base_dir, None);
+        assert!(matches!(args.command, Command::Completions { .. }));
+    }
+
+    #[test]
+    fn reject_invalid_arguments() {
+        assert!(Args::try_parse_from(["side", "/srv", "apply", "latest"]).is_err());
+        assert!(Args::try_parse_from(["side", "/srv", "build", "--chunk-size", "0"]).is_err());
     }
 }
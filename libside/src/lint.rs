@@ -0,0 +1,370 @@
+//! Checking the configuration files of a build without applying it, for `side lint`.
+//!
+//! The packages are built in a throwaway sandbox, like [`preview`](crate::preview) does, so that the system and its secrets are never changed.
+//! The generated configuration files are written to their paths in the sandbox, and checked there with the validators that the sandbox provides:
+//! `nginx -t` for nginx configurations, `php-fpm -t` for php-fpm configurations and `systemd-analyze verify` for systemd units.
+//! A sandbox without a validator skips the files that it would check. The files are also copied to a directory on the system, for inspection or as a CI artifact.
+use crate::builder::{fs::CreateDirectory, Builder};
+use crate::preview::{in_sandbox, InSandbox, PreviewError, Sandbox};
+use crate::requirements::Supports;
+use crate::system::System;
+use crate::{Dirs, RenderedFile};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+/// The directory in the sandbox that validators may write to.
+const SCRATCH: &str = "/tmp/side-lint";
+
+#[derive(Debug, thiserror::Error)]
+pub enum LintError<S: System> {
+    #[error("{}", .0)]
+    Sandbox(PreviewError<S>),
+
+    #[error("unable to build the packages: {}", .0)]
+    BuildFailed(String),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+}
+
+/// A program that checks a kind of configuration file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Validator {
+    Nginx,
+    PhpFpm,
+    Systemd,
+}
+
+impl Validator {
+    /// Determines which validator checks the file at `path`, if any.
+    pub fn detect(path: &Path, contents: &[u8]) -> Option<Validator> {
+        const UNITS: [&str; 6] = ["service", "socket", "timer", "mount", "path", "target"];
+        if path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| UNITS.contains(&extension))
+            .unwrap_or(false)
+        {
+            return Some(Validator::Systemd);
+        }
+
+        let contents = String::from_utf8_lossy(contents);
+        // Only a main nginx configuration can be tested on its own, included files are tested with it
+        let nginx = contents.lines().any(|line| {
+            ["http", "events"].iter().any(|block| {
+                line.strip_prefix(block)
+                    .map(|rest| rest.trim_start().starts_with('{'))
+                    .unwrap_or(false)
+            })
+        });
+        if nginx {
+            return Some(Validator::Nginx);
+        }
+
+        let mut lines = contents.lines().map(str::trim);
+        let section = lines
+            .clone()
+            .any(|line| line.starts_with('[') && line.ends_with(']'));
+        let pool = lines.any(|line| {
+            matches!(
+                line.split_once('=').map(|(key, _)| key.trim()),
+                Some("listen" | "pm")
+            )
+        });
+        if section && pool {
+            return Some(Validator::PhpFpm);
+        }
+
+        None
+    }
+
+    /// The programs that can run the validator, in order of preference.
+    fn programs(&self) -> &'static [&'static str] {
+        match self {
+            Validator::Nginx => &["nginx"],
+            Validator::PhpFpm => &[
+                "php-fpm",
+                "php-fpm8.3",
+                "php-fpm8.2",
+                "php-fpm8.1",
+                "php-fpm8.0",
+                "php-fpm7.4",
+            ],
+            Validator::Systemd => &["systemd-analyze"],
+        }
+    }
+
+    fn args<'a>(&self, path: &'a str) -> Vec<&'a str> {
+        match self {
+            // The prefix keeps nginx from touching the pid and log files of a running nginx
+            Validator::Nginx => vec!["-t", "-q", "-p", "/tmp/side-lint/nginx/", "-c", path],
+            Validator::PhpFpm => vec!["-t", "-y", path],
+            Validator::Systemd => vec!["verify", path],
+        }
+    }
+}
+
+impl Display for Validator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Validator::Nginx => "nginx",
+            Validator::PhpFpm => "php-fpm",
+            Validator::Systemd => "systemd",
+        })
+    }
+}
+
+/// The outcome of checking a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Check {
+    Passed,
+    Failed(String),
+
+    /// The sandbox does not provide the validator.
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckedFile {
+    pub path: PathBuf,
+    pub validator: Validator,
+    pub check: Check,
+}
+
+/// The generated files of a build, and the outcome of checking them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LintReport {
+    files: Vec<RenderedFile>,
+    checked: Vec<CheckedFile>,
+}
+
+impl LintReport {
+    pub fn checked(&self) -> &[CheckedFile] {
+        &self.checked
+    }
+
+    /// Returns true if no file failed its check.
+    pub fn is_success(&self) -> bool {
+        self.checked
+            .iter()
+            .all(|file| !matches!(file.check, Check::Failed(_)))
+    }
+
+    /// Writes the generated files to `dir`, at their paths relative to the root directory.
+    pub fn write<S: System>(&self, dir: &Path, system: &mut S) -> Result<(), LintError<S>> {
+        for (path, contents) in self.files.iter() {
+            let target = dir.join(path.strip_prefix("/").unwrap_or(path));
+            if let Some(parent) = target.parent() {
+                system
+                    .make_dir_all(parent)
+                    .map_err(|e| LintError::UnableToWrite(parent.to_owned(), e))?;
+            }
+
+            system
+                .put_file_contents(&target, contents)
+                .map_err(|e| LintError::UnableToWrite(target.clone(), e))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for LintReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for file in self.checked.iter() {
+            match &file.check {
+                Check::Passed => writeln!(f, "ok: {} ({})", file.path.display(), file.validator)?,
+                Check::Failed(err) => writeln!(
+                    f,
+                    "failed: {} ({})\n  {}",
+                    file.path.display(),
+                    file.validator,
+                    err.trim_end().replace('\n', "\n  ")
+                )?,
+                Check::Skipped => writeln!(
+                    f,
+                    "skipped: {} ({} is not available in the sandbox)",
+                    file.path.display(),
+                    file.validator
+                )?,
+            }
+        }
+
+        let failed = self
+            .checked
+            .iter()
+            .filter(|file| matches!(file.check, Check::Failed(_)))
+            .count();
+        writeln!(
+            f,
+            "{} files generated, {} checked, {} failed",
+            self.files.len(),
+            self.checked.len(),
+            failed
+        )
+    }
+}
+
+struct Lint<'b, B>(&'b B);
+
+impl<'b, B: Builder> InSandbox for Lint<'b, B>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    type Output = Result<LintReport, String>;
+
+    fn run<P: System>(self, dirs: &Dirs, sandbox: &mut P) -> Self::Output {
+        let files = crate::render_files(dirs, sandbox, self.0).map_err(|e| e.to_string())?;
+        for (path, contents) in files.iter() {
+            if let Some(parent) = path.parent() {
+                sandbox.make_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+
+            sandbox
+                .put_file_contents(path, contents)
+                .map_err(|e| e.to_string())?;
+        }
+
+        sandbox
+            .make_dir_all(&Path::new(SCRATCH).join("nginx/logs"))
+            .map_err(|e| e.to_string())?;
+
+        let mut programs = HashMap::new();
+        let mut checked = Vec::new();
+        for (path, contents) in files.iter() {
+            let Some(validator) = Validator::detect(path, contents) else {
+                continue;
+            };
+
+            let program = *programs
+                .entry(validator)
+                .or_insert_with(|| find_program(sandbox, validator.programs()));
+            let check = match program {
+                Some(program) => {
+                    let path = path.to_str().expect("paths must be valid UTF-8");
+                    match sandbox.execute_command(program, &validator.args(path)) {
+                        Ok(result) if result.is_success() => Check::Passed,
+                        Ok(result) => Check::Failed(format!(
+                            "{}{}",
+                            result.stdout_as_str(),
+                            result.stderr_as_str()
+                        )),
+                        Err(e) => Check::Failed(e.to_string()),
+                    }
+                }
+                None => Check::Skipped,
+            };
+
+            checked.push(CheckedFile {
+                path: path.clone(),
+                validator,
+                check,
+            });
+        }
+
+        Ok(LintReport { files, checked })
+    }
+}
+
+/// Returns the first of `programs` that is installed in `sandbox`.
+fn find_program<P: System>(sandbox: &mut P, programs: &[&'static str]) -> Option<&'static str> {
+    programs.iter().copied().find(|program| {
+        sandbox
+            .execute_command("sh", &["-c", &format!("command -v {}", program)])
+            .map(|result| result.is_success())
+            .unwrap_or(false)
+    })
+}
+
+/// Builds the packages in `dirs` in `sandbox`, and checks the generated configuration files there. The sandbox is removed afterwards.
+pub fn lint<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    builder: &B,
+    sandbox: &Sandbox,
+) -> Result<LintReport, LintError<S>>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    in_sandbox(dirs, system, sandbox, Lint(builder))
+        .map_err(LintError::Sandbox)?
+        .map_err(LintError::BuildFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Check, CheckedFile, LintReport, Validator};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    pub fn detect_validators() {
+        let detect =
+            |path: &str, contents: &str| Validator::detect(Path::new(path), contents.as_bytes());
+
+        assert_eq!(
+            detect(
+                "/srv/installed/3/config/www/nginx.conf",
+                "events {}\nhttp {\n    include sites/*;\n}\n"
+            ),
+            Some(Validator::Nginx)
+        );
+        assert_eq!(
+            detect(
+                "/srv/installed/3/config/www/sites/a.conf",
+                "server {\n    listen 80;\n}\n"
+            ),
+            None
+        );
+        assert_eq!(
+            detect(
+                "/srv/installed/3/config/www/fpm.conf",
+                "[global]\nerror_log = /dev/stderr\n[www]\nlisten = /run/php.sock\npm = static\n"
+            ),
+            Some(Validator::PhpFpm)
+        );
+        assert_eq!(
+            detect(
+                "/etc/systemd/system/backup.timer",
+                "[Timer]\nOnCalendar=daily\n"
+            ),
+            Some(Validator::Systemd)
+        );
+        assert_eq!(
+            detect(
+                "/etc/dpkg/dpkg.cfg.d/01_nodoc",
+                "path-exclude /usr/share/doc/*\n"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    pub fn report_fails_on_failed_checks() {
+        let mut report = LintReport {
+            files: vec![
+                (PathBuf::from("/etc/systemd/system/a.service"), Vec::new()),
+                (PathBuf::from("/etc/nginx.conf"), Vec::new()),
+            ],
+            checked: vec![CheckedFile {
+                path: PathBuf::from("/etc/systemd/system/a.service"),
+                validator: Validator::Systemd,
+                check: Check::Skipped,
+            }],
+        };
+        assert!(report.is_success());
+
+        report.checked.push(CheckedFile {
+            path: PathBuf::from("/etc/nginx.conf"),
+            validator: Validator::Nginx,
+            check: Check::Failed(String::from("unknown directive \"lsten\"\n")),
+        });
+        assert!(!report.is_success());
+        assert_eq!(
+            report.to_string(),
+            "skipped: /etc/systemd/system/a.service (systemd is not available in the sandbox)\n\
+             failed: /etc/nginx.conf (nginx)\n  unknown directive \"lsten\"\n\
+             2 files generated, 2 checked, 1 failed\n"
+        );
+    }
+}
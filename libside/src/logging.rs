@@ -0,0 +1,119 @@
+//! Logging is done through [`tracing`].
+//! Commands, package builds and requirement executions each open a span, so that log lines can be correlated to the package or requirement that produced them.
+//!
+//! Nothing is printed unless a subscriber is installed.
+//! [`init`] installs a subscriber that prints the same plain output that side has always printed, [`init_json`] emits one JSON object per event instead.
+//! The JSON output also has an event for every span that closes, with the time that was spent in it in `time.busy`, so that slow packages and requirements can be found.
+//! The log level can be changed with the `SIDE_LOG` environment variable, which accepts the same directives as `RUST_LOG`.
+use std::fmt;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, format::Writer, FmtContext, FormatEvent, FormatFields, MakeWriter},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter,
+};
+
+pub const LOG_ENV: &str = "SIDE_LOG";
+
+/// Formats events as bare messages, without timestamps, levels or span information.
+/// This matches the stdout format that was used before logging went through `tracing`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompatFormat;
+
+impl<S, N> FormatEvent<S, N> for CompatFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+fn filter() -> EnvFilter {
+    EnvFilter::try_from_env(LOG_ENV).unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// Installs a global subscriber that prints log messages to stdout in the plain compatibility format.
+/// Does nothing if a global subscriber has already been installed.
+pub fn init() {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter())
+        .with_writer(std::io::stdout)
+        .event_format(CompatFormat)
+        .try_init();
+}
+
+/// Installs a global subscriber that prints log messages to stdout as JSON, including the spans they occurred in.
+/// Does nothing if a global subscriber has already been installed.
+pub fn init_json() {
+    let _ = json_subscriber(std::io::stdout).try_init();
+}
+
+fn json_subscriber<W>(writer: W) -> impl Subscriber + Send + Sync
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt()
+        .with_env_filter(filter())
+        .with_writer(writer)
+        .json()
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_span_events(FmtSpan::CLOSE)
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::json_subscriber;
+    use serde_json::Value;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tracing::{info, info_span};
+
+    #[derive(Clone)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn json_events_include_spans_and_timing() {
+        let buffer = Buffer(Arc::new(Mutex::new(Vec::new())));
+        let writer = buffer.clone();
+        let subscriber = json_subscriber(move || writer.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _span =
+                info_span!("requirement", kind = "file", requirement = "/etc/motd").entered();
+            info!("  require: /etc/motd");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let events = output
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["fields"]["message"], "  require: /etc/motd");
+        assert_eq!(events[0]["span"]["kind"], "file");
+        assert_eq!(events[0]["span"]["requirement"], "/etc/motd");
+
+        assert_eq!(events[1]["fields"]["message"], "close");
+        assert!(events[1]["fields"]["time.busy"].is_string());
+    }
+}
@@ -0,0 +1,421 @@
+//! Notifications about the results of builds, applies and verifications, so that operators learn when an unattended apply failed or reverted itself.
+//!
+//! The `[notify]` section of `side.toml` lists where notifications are sent. A failure to send a notification is logged, and never fails the command itself.
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::PathBuf;
+use tracing::warn;
+
+/// How a command ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Succeeded,
+    Failed,
+
+    /// The apply failed, and the changes that it made were reverted.
+    Reverted,
+
+    /// The apply succeeded except for the subtrees that failed, which were reverted.
+    PartiallyApplied,
+}
+
+impl Outcome {
+    pub fn is_success(&self) -> bool {
+        *self == Outcome::Succeeded
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Outcome::Succeeded => "succeeded",
+            Outcome::Failed => "failed",
+            Outcome::Reverted => "reverted",
+            Outcome::PartiallyApplied => "partially applied",
+        })
+    }
+}
+
+/// The summary of a command that is sent to the notifiers. Webhooks and commands receive it as JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    /// The command that ran, such as `apply`.
+    pub command: String,
+    pub host: String,
+    pub base_dir: PathBuf,
+    pub outcome: Outcome,
+
+    /// The current install after the command, if it could be determined.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install: Option<u64>,
+
+    /// The error, or a short description of the result.
+    pub summary: String,
+
+    /// When the command finished, in seconds since the Unix epoch.
+    pub time: u64,
+}
+
+impl Notification {
+    pub fn new(command: &str, base_dir: PathBuf, outcome: Outcome, summary: String) -> Self {
+        Notification {
+            command: command.to_owned(),
+            host: crate::fleet::hostname(),
+            base_dir,
+            outcome,
+            install: None,
+            summary,
+            time: crate::agent::now(),
+        }
+    }
+
+    pub fn with_install(mut self, install: Option<u64>) -> Self {
+        self.install = install;
+        self
+    }
+
+    /// A single line that describes the notification, for the subject of an email.
+    pub fn subject(&self) -> String {
+        format!("side {} on {}: {}", self.command, self.host, self.outcome)
+    }
+}
+
+impl Display for Notification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Command  : side {}", self.command)?;
+        writeln!(f, "Host     : {}", self.host)?;
+        writeln!(f, "Base dir : {}", self.base_dir.display())?;
+        writeln!(f, "Outcome  : {}", self.outcome)?;
+        if let Some(install) = self.install {
+            writeln!(f, "Install  : {}", install)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{}", self.summary)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NotifyError<S: System> {
+    #[error("unable to execute {0}: {1}")]
+    FailedToStart(String, S::CommandError),
+
+    #[error("{0} failed: {1}")]
+    Unsuccessful(String, String),
+}
+
+/// Sends notifications to a single destination.
+pub trait Notifier {
+    fn notify<S: System>(
+        &self,
+        system: &S,
+        notification: &Notification,
+    ) -> Result<(), NotifyError<S>>;
+}
+
+fn run<S: System>(
+    system: &S,
+    program: &str,
+    args: &[&str],
+    input: &[u8],
+) -> Result<(), NotifyError<S>> {
+    let result = system
+        .execute_command_with_input(program, args, input)
+        .map_err(|e| NotifyError::FailedToStart(program.to_owned(), e))?;
+    if result.is_success() {
+        Ok(())
+    } else {
+        Err(NotifyError::Unsuccessful(
+            program.to_owned(),
+            result.stderr_as_str().trim().to_owned(),
+        ))
+    }
+}
+
+/// POSTs the notification as JSON to `url`, with `curl`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+}
+
+impl Notifier for Webhook {
+    fn notify<S: System>(
+        &self,
+        system: &S,
+        notification: &Notification,
+    ) -> Result<(), NotifyError<S>> {
+        let body = serde_json::to_vec(notification).unwrap();
+        run(
+            system,
+            "curl",
+            &[
+                "--silent",
+                "--show-error",
+                "--fail",
+                "--max-time",
+                "30",
+                "--header",
+                "Content-Type: application/json",
+                "--data-binary",
+                "@-",
+                &self.url,
+            ],
+            &body,
+        )
+    }
+}
+
+/// Sends the notification as a plain text email with `sendmail`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Email {
+    pub to: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+}
+
+/// Removes line breaks from a header value, so that it cannot add headers of its own.
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+impl Email {
+    /// The message that is passed to `sendmail -t`, which reads the recipients from the headers.
+    pub fn message(&self, notification: &Notification) -> String {
+        let mut message = format!("To: {}\n", header_value(&self.to.join(", ")));
+        if let Some(from) = &self.from {
+            message.push_str(&format!("From: {}\n", header_value(from)));
+        }
+
+        message.push_str(&format!(
+            "Subject: {}\nContent-Type: text/plain; charset=utf-8\n\n{}",
+            header_value(&notification.subject()),
+            notification
+        ));
+        message
+    }
+}
+
+impl Notifier for Email {
+    fn notify<S: System>(
+        &self,
+        system: &S,
+        notification: &Notification,
+    ) -> Result<(), NotifyError<S>> {
+        run(
+            system,
+            "sendmail",
+            &["-t", "-oi"],
+            self.message(notification).as_bytes(),
+        )
+    }
+}
+
+/// Runs a command with the notification as JSON on stdin, for destinations that side does not support itself.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandNotifier {
+    /// The program followed by its arguments.
+    pub command: Vec<String>,
+}
+
+impl Notifier for CommandNotifier {
+    fn notify<S: System>(
+        &self,
+        system: &S,
+        notification: &Notification,
+    ) -> Result<(), NotifyError<S>> {
+        let (program, args) = match self.command.split_first() {
+            Some(split) => split,
+            None => {
+                return Err(NotifyError::Unsuccessful(
+                    String::from("command notifier"),
+                    String::from("the command is empty"),
+                ))
+            }
+        };
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        run(
+            system,
+            program,
+            &args,
+            &serde_json::to_vec(notification).unwrap(),
+        )
+    }
+}
+
+/// Which outcomes are sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyOn {
+    /// Everything except success.
+    #[default]
+    Failure,
+    Always,
+}
+
+/// The `[notify]` section of `side.toml`.
+///
+/// ```toml
+/// [notify]
+/// on = "always"
+///
+/// [[notify.webhook]]
+/// url = "https://chat.example.com/hooks/side"
+///
+/// [[notify.email]]
+/// to = ["ops@example.com"]
+/// from = "side@example.com"
+///
+/// [[notify.command]]
+/// command = ["/usr/local/bin/page", "--team", "ops"]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifySettings {
+    #[serde(default)]
+    pub on: NotifyOn,
+
+    #[serde(default)]
+    pub webhook: Vec<Webhook>,
+
+    #[serde(default)]
+    pub email: Vec<Email>,
+
+    #[serde(default)]
+    pub command: Vec<CommandNotifier>,
+}
+
+impl NotifySettings {
+    /// Whether `notification` should be sent according to `on`.
+    pub fn wants(&self, notification: &Notification) -> bool {
+        self.on == NotifyOn::Always || !notification.outcome.is_success()
+    }
+
+    /// Sends `notification` to every configured notifier. Notifiers that fail are logged, and do not stop the others.
+    pub fn send<S: System>(&self, system: &S, notification: &Notification) {
+        if !self.wants(notification) {
+            return;
+        }
+
+        let results = self
+            .webhook
+            .iter()
+            .map(|n| n.notify(system, notification))
+            .chain(self.email.iter().map(|n| n.notify(system, notification)))
+            .chain(self.command.iter().map(|n| n.notify(system, notification)));
+        for result in results {
+            if let Err(e) = result {
+                warn!("Unable to send a notification: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CommandNotifier, Email, Notification, Notifier, NotifyOn, NotifySettings, Outcome, Webhook,
+    };
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+    use std::path::PathBuf;
+
+    fn notification(outcome: Outcome) -> Notification {
+        Notification {
+            command: String::from("apply"),
+            host: String::from("web1"),
+            base_dir: PathBuf::from("/srv"),
+            outcome,
+            install: Some(7),
+            summary: String::from("Unable to apply the build"),
+            time: 1700000000,
+        }
+    }
+
+    #[test]
+    pub fn parse_notify_settings() {
+        let settings: NotifySettings = toml::from_str(
+            "on = \"always\"\n[[webhook]]\nurl = \"https://example.com/hook\"\n[[email]]\nto = [\"ops@example.com\"]\n[[command]]\ncommand = [\"page\", \"--team\", \"ops\"]\n",
+        )
+        .unwrap();
+        assert_eq!(settings.on, NotifyOn::Always);
+        assert_eq!(
+            settings.webhook,
+            [Webhook {
+                url: String::from("https://example.com/hook")
+            }]
+        );
+        assert_eq!(
+            settings.email,
+            [Email {
+                to: vec![String::from("ops@example.com")],
+                from: None,
+            }]
+        );
+        assert_eq!(settings.command[0].command, ["page", "--team", "ops"]);
+
+        let settings: NotifySettings = toml::from_str("").unwrap();
+        assert_eq!(settings.on, NotifyOn::Failure);
+        assert!(!settings.wants(&notification(Outcome::Succeeded)));
+        assert!(settings.wants(&notification(Outcome::Reverted)));
+    }
+
+    #[test]
+    pub fn email_message() {
+        let email = Email {
+            to: vec![String::from("a@example.com"), String::from("b@example.com")],
+            from: Some(String::from("side@example.com\nBcc: evil@example.com")),
+        };
+        let mut n = notification(Outcome::Reverted);
+        n.host = String::from("web1\r\nBcc: evil@example.com");
+
+        assert_eq!(
+            email.message(&n),
+            "To: a@example.com, b@example.com\n\
+            From: side@example.com Bcc: evil@example.com\n\
+            Subject: side apply on web1  Bcc: evil@example.com: reverted\n\
+            Content-Type: text/plain; charset=utf-8\n\
+            \n\
+            Command  : side apply\n\
+            Host     : web1\r\nBcc: evil@example.com\n\
+            Base dir : /srv\n\
+            Outcome  : reverted\n\
+            Install  : 7\n\
+            \n\
+            Unable to apply the build\n"
+        );
+    }
+
+    #[test]
+    pub fn command_receives_json() {
+        let system = LocalSystem::new();
+        let dir = TempDir::new("notify");
+        let path = dir.join("notification.json");
+        let notifier = CommandNotifier {
+            command: vec![
+                String::from("sh"),
+                String::from("-c"),
+                format!("cat > {}", path.display()),
+            ],
+        };
+
+        let n = notification(Outcome::PartiallyApplied);
+        notifier.notify(&system, &n).unwrap();
+        let sent: Notification =
+            serde_json::from_slice(&system.file_contents(&path).unwrap()).unwrap();
+        assert_eq!(sent, n);
+        assert!(String::from_utf8(system.file_contents(&path).unwrap())
+            .unwrap()
+            .contains("\"outcome\":\"partially_applied\""));
+
+        let failing = CommandNotifier {
+            command: vec![String::from("false")],
+        };
+        assert!(failing.notify(&system, &n).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+//! Building OCI images instead of applying a build to the live system.
+//!
+//! The build is applied to a copy of a prepared root filesystem (for example one created with `debootstrap`), using a [`ChrootSystem`].
+//! The resulting tree is exported as a single-layer image in the OCI image layout.
+//! Requirements that need a running init system, such as starting services, cannot be applied in a chroot and will fail.
+use crate::builder::{fs::CreateDirectory, Builder};
+use crate::conflict::OverwritePolicy;
+use crate::graph::ApplyLimits;
+use crate::requirements::Supports;
+use crate::system::{ChrootSystem, CommandResult, System};
+use crate::{ApplyMode, Dirs, Interaction};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Where the result of a build should go.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum BuildTarget {
+    /// Apply the build to the system itself.
+    #[default]
+    Live,
+
+    /// Apply the build to a staged root filesystem and write it to the given path as an OCI image layout.
+    /// If the path ends in `.tar`, the image layout is written as a tarball instead.
+    Oci(PathBuf),
+}
+
+impl FromStr for BuildTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "live" => Ok(BuildTarget::Live),
+            Some(("oci", path)) if !path.is_empty() => Ok(BuildTarget::Oci(PathBuf::from(path))),
+            _ => Err(format!(
+                "invalid build target {:?}, expected 'live' or 'oci:<path>'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OciError<S: System> {
+    #[error("a root filesystem must be specified with --rootfs when building an OCI image")]
+    MissingRootfs,
+
+    #[error("unable to execute {}: {}", .0, .1)]
+    FailedToStart(&'static str, S::CommandError),
+
+    #[error("{} failed: {} {}", .0, .1, .2)]
+    Unsuccessful(&'static str, String, String),
+
+    #[error("unable to initialize the staged root filesystem: {}", .0)]
+    InitFailed(String),
+
+    #[error("unable to build into the staged root filesystem: {}", .0)]
+    BuildFailed(String),
+
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("unexpected output from {}: {:?}", .0, .1)]
+    UnexpectedOutput(&'static str, String),
+}
+
+fn run<S: System>(
+    system: &mut S,
+    command: &'static str,
+    args: &[&str],
+) -> Result<CommandResult, OciError<S>> {
+    let result = system
+        .execute_command(command, args)
+        .map_err(|e| OciError::FailedToStart(command, e))?;
+    result.successful().map_err(|(stdout, stderr)| {
+        OciError::Unsuccessful(command, stdout.into(), stderr.into())
+    })?;
+
+    Ok(result)
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().expect("paths must be valid UTF-8")
+}
+
+/// Builds the packages in `dirs` into a copy of `rootfs`, and writes the result to `output` as an OCI image.
+pub fn build_image<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    builder: B,
+    rootfs: &Path,
+    output: &Path,
+    overwrite: OverwritePolicy,
+) -> Result<(), OciError<S>>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    let staging = dirs.base.join("oci-staging");
+    let staging_str = path_str(&staging);
+    run(system, "rm", &["-rf", staging_str])?;
+    run(system, "mkdir", &["-p", staging_str])?;
+    run(
+        system,
+        "cp",
+        &["-a", &format!("{}/.", path_str(rootfs)), staging_str],
+    )?;
+
+    {
+        let mut chroot = ChrootSystem::new(system, &staging);
+        dirs.initialize::<B::Requirement, _>(&mut chroot)
+            .map_err(|e| OciError::InitFailed(e.to_string()))?;
+    }
+
+    // The packages and secrets are copied so that the image is built from the same sources as the live system.
+    for dir in [&dirs.packages, &dirs.secrets] {
+        let target = ChrootSystem::new(system, &staging).host_path(dir);
+        run(
+            system,
+            "cp",
+            &["-a", &format!("{}/.", path_str(dir)), path_str(&target)],
+        )?;
+    }
+
+    // The key-value store is copied as well, so that builders see the same facts as on the live system.
+    if system
+        .path_exists(&dirs.kv)
+        .map_err(|e| OciError::UnableToRead(dirs.kv.clone(), e))?
+    {
+        let target = ChrootSystem::new(system, &staging).host_path(&dirs.kv);
+        run(system, "cp", &["-a", path_str(&dirs.kv), path_str(&target)])?;
+    }
+
+    {
+        let mut chroot = ChrootSystem::new(system, &staging);
+        crate::build(
+            dirs,
+            &mut chroot,
+            &builder,
+            Interaction {
+                overwrite,
+                review: false,
+            },
+            &ApplyLimits::default(),
+            ApplyMode {
+                ignore_verification: true,
+                ..ApplyMode::default()
+            },
+        )
+        .map_err(|e| OciError::BuildFailed(e.to_string()))?;
+    }
+
+    export(system, &staging, output)?;
+    run(system, "rm", &["-rf", staging_str])?;
+
+    Ok(())
+}
+
+fn architecture<S: System>(system: &mut S) -> Result<&'static str, OciError<S>> {
+    let result = run(system, "uname", &["-m"])?;
+    Ok(match result.stdout_as_str().trim() {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "armv7l" => "arm",
+        "i686" | "i386" => "386",
+        "riscv64" => "riscv64",
+        "ppc64le" => "ppc64le",
+        "s390x" => "s390x",
+        other => return Err(OciError::UnexpectedOutput("uname", other.to_owned())),
+    })
+}
+
+/// Writes `contents` as a blob and returns its descriptor.
+fn write_blob<S: System>(
+    system: &mut S,
+    blobs: &Path,
+    media_type: &str,
+    contents: &[u8],
+) -> Result<serde_json::Value, OciError<S>> {
+    let digest = format!("{:x}", Sha256::digest(contents));
+    let path = blobs.join(&digest);
+    system
+        .put_file_contents(&path, contents)
+        .map_err(|e| OciError::UnableToWrite(path, e))?;
+
+    Ok(json!({
+        "mediaType": media_type,
+        "digest": format!("sha256:{}", digest),
+        "size": contents.len(),
+    }))
+}
+
+/// Exports `rootfs` as a single-layer OCI image.
+pub fn export<S: System>(system: &mut S, rootfs: &Path, output: &Path) -> Result<(), OciError<S>> {
+    let tarball = output.extension().map(|e| e == "tar").unwrap_or(false);
+    let layout = if tarball {
+        PathBuf::from(format!("{}.layout", output.display()))
+    } else {
+        output.to_path_buf()
+    };
+    let blobs = layout.join("blobs/sha256");
+
+    run(system, "rm", &["-rf", path_str(&layout)])?;
+    run(system, "mkdir", &["-p", path_str(&blobs)])?;
+
+    let layer = blobs.join("layer.tar");
+    run(
+        system,
+        "tar",
+        &[
+            "--numeric-owner",
+            "-C",
+            path_str(rootfs),
+            "-cf",
+            path_str(&layer),
+            ".",
+        ],
+    )?;
+
+    let result = run(system, "sha256sum", &[path_str(&layer)])?;
+    let layer_digest = result
+        .stdout_as_str()
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+    let result = run(system, "stat", &["-c", "%s", path_str(&layer)])?;
+    let layer_size = result
+        .stdout_as_str()
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| OciError::UnexpectedOutput("stat", result.stdout_as_str().to_owned()))?;
+    run(
+        system,
+        "mv",
+        &[path_str(&layer), path_str(&blobs.join(&layer_digest))],
+    )?;
+
+    let config = json!({
+        "architecture": architecture(system)?,
+        "os": "linux",
+        "config": {},
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": [format!("sha256:{}", layer_digest)],
+        },
+    });
+    let config = write_blob(
+        system,
+        &blobs,
+        "application/vnd.oci.image.config.v1+json",
+        config.to_string().as_bytes(),
+    )?;
+
+    let manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": config,
+        "layers": [{
+            "mediaType": "application/vnd.oci.image.layer.v1.tar",
+            "digest": format!("sha256:{}", layer_digest),
+            "size": layer_size,
+        }],
+    });
+    let manifest = write_blob(
+        system,
+        &blobs,
+        "application/vnd.oci.image.manifest.v1+json",
+        manifest.to_string().as_bytes(),
+    )?;
+
+    let index = json!({
+        "schemaVersion": 2,
+        "manifests": [manifest],
+    });
+    for (name, contents) in [
+        ("index.json", index.to_string()),
+        (
+            "oci-layout",
+            json!({ "imageLayoutVersion": "1.0.0" }).to_string(),
+        ),
+    ] {
+        let path = layout.join(name);
+        system
+            .put_file_contents(&path, contents.as_bytes())
+            .map_err(|e| OciError::UnableToWrite(path, e))?;
+    }
+
+    if tarball {
+        run(
+            system,
+            "tar",
+            &["-C", path_str(&layout), "-cf", path_str(output), "."],
+        )?;
+        run(system, "rm", &["-rf", path_str(&layout)])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BuildTarget;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn parse_build_target() {
+        assert_eq!("live".parse::<BuildTarget>().unwrap(), BuildTarget::Live);
+        assert_eq!(
+            "oci:/tmp/image.tar".parse::<BuildTarget>().unwrap(),
+            BuildTarget::Oci(PathBuf::from("/tmp/image.tar"))
+        );
+        assert!("oci:".parse::<BuildTarget>().is_err());
+        assert!("docker:foo".parse::<BuildTarget>().is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Upgrading the operating system to the next release of its distribution, for `side upgrade-os`.
+//!
+//! A release upgrade replaces many of the packages, configuration files and services that requirements manage, so it is guarded:
+//! the current install must verify before the upgrade starts, and all of its requirements are verified again afterwards.
+//! Requirements that held before the upgrade but not afterwards have been invalidated by it, and need to be fixed with `side verify --fix` or a new build.
+//! Every upgrade is recorded in `os-upgrades.json` in the base directory, with the release before and after and the requirements it invalidated.
+use crate::distro::{Distro, OsVersion};
+use crate::graph::NodeId;
+use crate::report::Category;
+use crate::requirements::Requirement;
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OsUpgradeError<S: System> {
+    #[error("unable to detect the release of the system: {}", .0)]
+    UnableToDetect(S::Error),
+
+    #[error("the system does not identify its distribution in /etc/os-release")]
+    UnknownRelease,
+
+    #[error("the distribution of the system is not supported")]
+    UnsupportedDistro,
+
+    #[error("a reboot is pending: {}", .0)]
+    RebootPending(String),
+
+    #[error("unable to execute the release upgrade: {}", .0)]
+    FailedToStart(S::CommandError),
+
+    #[error("the release upgrade failed: {} {}", .0, .1)]
+    Unsuccessful(String, String),
+
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("invalid upgrade history in {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, serde_json::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+}
+
+impl<S: System> From<(&str, &str)> for OsUpgradeError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        OsUpgradeError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+/// What an invalidated requirement manages, which hints at what the upgrade changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cause {
+    /// A file, directory or permissions that the upgrade replaced or moved.
+    Path,
+
+    /// A system package that the upgrade replaced or removed.
+    Package,
+
+    Other,
+}
+
+impl Cause {
+    fn of(category: Category) -> Cause {
+        match category {
+            Category::File | Category::Directory | Category::Permissions => Cause::Path,
+            Category::Package => Cause::Package,
+            _ => Cause::Other,
+        }
+    }
+}
+
+impl Display for Cause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Cause::Path => "path changed",
+            Cause::Package => "package changed",
+            Cause::Other => "changed",
+        })
+    }
+}
+
+/// A requirement that held before an upgrade, but not afterwards.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invalidated {
+    pub cause: Cause,
+    pub requirement: String,
+}
+
+impl Display for Invalidated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.cause, self.requirement)
+    }
+}
+
+/// A release upgrade of the system, as recorded in the upgrade history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OsUpgrade {
+    /// The install that was current during the upgrade.
+    pub install: u64,
+
+    /// When the upgrade was started, in seconds since the Unix epoch.
+    pub started: u64,
+
+    pub from: OsVersion,
+    pub to: OsVersion,
+
+    #[serde(default)]
+    pub invalidated: Vec<Invalidated>,
+}
+
+/// Detects the distribution and release of `system`.
+pub fn detect<S: System>(system: &S) -> Result<(Distro, OsVersion), OsUpgradeError<S>> {
+    let version = OsVersion::detect(system)
+        .map_err(OsUpgradeError::UnableToDetect)?
+        .ok_or(OsUpgradeError::UnknownRelease)?;
+    let distro = Distro::detect(system)
+        .map_err(OsUpgradeError::UnableToDetect)?
+        .ok_or(OsUpgradeError::UnsupportedDistro)?;
+
+    Ok((distro, version))
+}
+
+/// Returns the requirements in `invalid_after` that were not already invalid before the upgrade, according to `invalid_before`.
+pub fn invalidated<R: Requirement + Display>(
+    invalid_before: &BTreeSet<NodeId>,
+    invalid_after: &[&R],
+) -> Vec<Invalidated> {
+    invalid_after
+        .iter()
+        .filter(|requirement| !invalid_before.contains(&NodeId::of(**requirement)))
+        .map(|requirement| Invalidated {
+            cause: Cause::of(requirement.describe().category),
+            requirement: requirement.to_string(),
+        })
+        .collect()
+}
+
+/// Runs the release upgrade of `distro`, and logs its output line by line while it runs.
+pub fn upgrade<S: System>(system: &mut S, distro: Distro) -> Result<(), OsUpgradeError<S>> {
+    let (command, args) = distro.release_upgrade_command();
+    info!("Running {} {}", command, args.join(" "));
+
+    let mut lines = LineBuffer::default();
+    let result = system
+        .execute_command_streaming(command, &args, &mut |output| {
+            for line in lines.push(output) {
+                info!("{}", line);
+            }
+        })
+        .map_err(OsUpgradeError::FailedToStart)?;
+    if let Some(line) = lines.finish() {
+        info!("{}", line);
+    }
+
+    result.successful()?;
+
+    Ok(())
+}
+
+/// Splits streamed output into lines, which may be split across chunks.
+#[derive(Default)]
+struct LineBuffer {
+    partial: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Adds `output`, and returns the lines that are now complete.
+    fn push(&mut self, output: &[u8]) -> Vec<String> {
+        self.partial.extend_from_slice(output);
+        let mut lines = Vec::new();
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line = self.partial.drain(..=end).collect::<Vec<_>>();
+            lines.push(String::from_utf8_lossy(&line).trim_end().to_owned());
+        }
+
+        lines
+    }
+
+    /// Returns the last line if the output did not end with a newline.
+    fn finish(self) -> Option<String> {
+        if self.partial.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&self.partial).trim_end().to_owned())
+        }
+    }
+}
+
+/// The history of release upgrades in the base directory.
+pub struct OsUpgradeHistory<'p> {
+    path: &'p Path,
+}
+
+impl<'p> OsUpgradeHistory<'p> {
+    pub fn new(path: &'p Path) -> OsUpgradeHistory<'p> {
+        OsUpgradeHistory { path }
+    }
+
+    /// Loads all upgrades, oldest first. A history that does not exist yet is empty.
+    pub fn load<S: System>(&self, system: &S) -> Result<Vec<OsUpgrade>, OsUpgradeError<S>> {
+        let exists = system
+            .path_exists(self.path)
+            .map_err(|e| OsUpgradeError::UnableToRead(self.path.to_owned(), e))?;
+        if !exists {
+            return Ok(Vec::new());
+        }
+
+        let contents = system
+            .file_contents(self.path)
+            .map_err(|e| OsUpgradeError::UnableToRead(self.path.to_owned(), e))?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| OsUpgradeError::Invalid(self.path.to_owned(), e))
+    }
+
+    pub fn append<S: System>(
+        &self,
+        system: &S,
+        upgrade: OsUpgrade,
+    ) -> Result<(), OsUpgradeError<S>> {
+        let mut upgrades = self.load(system)?;
+        upgrades.push(upgrade);
+        system
+            .put_file_contents(self.path, &serde_json::to_vec_pretty(&upgrades).unwrap())
+            .map_err(|e| OsUpgradeError::UnableToWrite(self.path.to_owned(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{invalidated, Cause, Invalidated, LineBuffer, OsUpgrade, OsUpgradeHistory};
+    use crate::builder::apt::AptInstall;
+    use crate::builder::fs::CreateDirectory;
+    use crate::distro::OsVersion;
+    use crate::graph::NodeId;
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use std::collections::BTreeSet;
+
+    #[test]
+    pub fn split_streamed_lines() {
+        let mut lines = LineBuffer::default();
+
+        assert_eq!(lines.push(b"Reading pack"), Vec::<String>::new());
+        assert_eq!(
+            lines.push(b"age lists...\r\nDone\nFetch"),
+            vec!["Reading package lists...", "Done"]
+        );
+        assert_eq!(lines.finish(), Some(String::from("Fetch")));
+    }
+
+    #[test]
+    pub fn only_new_failures_are_invalidated() {
+        let dir = CreateDirectory::new("/etc/nginx".into());
+        let package = AptInstall::new("nginx");
+        let already_broken = CreateDirectory::new("/var/www".into());
+        let before = BTreeSet::from([NodeId::of(&already_broken)]);
+
+        assert_eq!(
+            invalidated(&before, &[&dir, &already_broken]),
+            vec![Invalidated {
+                cause: Cause::Path,
+                requirement: dir.to_string(),
+            }]
+        );
+        assert_eq!(invalidated(&before, &[&package])[0].cause, Cause::Package);
+    }
+
+    #[test]
+    pub fn record_upgrades() {
+        let dir = TempDir::new("os-upgrades");
+        let path = dir.join("os-upgrades.jsonl");
+        let history = OsUpgradeHistory::new(&path);
+        let upgrade = OsUpgrade {
+            install: 3,
+            started: 1700000000,
+            from: OsVersion::from_os_release("ID=ubuntu\nVERSION_ID=22.04\n").unwrap(),
+            to: OsVersion::from_os_release("ID=ubuntu\nVERSION_ID=24.04\n").unwrap(),
+            invalidated: Vec::new(),
+        };
+
+        assert!(history.load(&LocalSystem::new()).unwrap().is_empty());
+        history
+            .append(&LocalSystem::new(), upgrade.clone())
+            .unwrap();
+        history
+            .append(&LocalSystem::new(), upgrade.clone())
+            .unwrap();
+
+        assert_eq!(
+            history.load(&LocalSystem::new()).unwrap(),
+            vec![upgrade.clone(), upgrade]
+        );
+    }
+}
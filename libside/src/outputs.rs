@@ -0,0 +1,142 @@
+//! Named values that builders publish about a deployment, for `side output`.
+//!
+//! Outputs are facts that external tooling needs, such as site URLs, allocated uids or socket paths.
+//! They are declared with `Context::output` and saved with every install, so that they can be read without parsing the graph.
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+/// The outputs of an install. Names are prefixed with the name of the package that declared them, for example `www.url`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Outputs {
+    values: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OutputsError<S: System> {
+    #[error("unable to read outputs from {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("invalid outputs in {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, serde_json::Error),
+
+    #[error("unable to serialize outputs: {}", .0)]
+    UnableToSerialize(serde_json::Error),
+
+    #[error("unable to write outputs to {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+}
+
+impl Outputs {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.values.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.values
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Adds an output. Returns the previous value if an output with the same name already existed.
+    pub fn insert(&mut self, name: String, value: Value) -> Option<Value> {
+        self.values.insert(name, value)
+    }
+
+    pub fn extend(&mut self, other: Outputs) {
+        self.values.extend(other.values);
+    }
+
+    /// Loads the outputs from `path`. Installs that were created before outputs existed have no outputs.
+    pub fn load<S: System>(path: &Path, system: &S) -> Result<Outputs, OutputsError<S>> {
+        if !system
+            .path_exists(path)
+            .map_err(|e| OutputsError::UnableToRead(path.to_owned(), e))?
+        {
+            return Ok(Outputs::default());
+        }
+
+        let contents = system
+            .file_contents(path)
+            .map_err(|e| OutputsError::UnableToRead(path.to_owned(), e))?;
+        serde_json::from_slice(&contents).map_err(|e| OutputsError::Invalid(path.to_owned(), e))
+    }
+
+    pub fn save<S: System>(&self, path: &Path, system: &S) -> Result<(), OutputsError<S>> {
+        let contents =
+            serde_json::to_string_pretty(self).map_err(OutputsError::UnableToSerialize)?;
+        system
+            .put_file_contents(path, contents.as_bytes())
+            .map_err(|e| OutputsError::UnableToWrite(path.to_owned(), e))
+    }
+}
+
+/// Formats a value for humans and shell scripts: strings are printed without quotes, everything else as JSON.
+pub fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+impl Display for Outputs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, value) in self.iter() {
+            writeln!(f, "{} = {}", name, format_value(value))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_value, Outputs};
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use serde_json::json;
+
+    #[test]
+    pub fn serialize_deserialize_outputs() {
+        let mut outputs = Outputs::default();
+        outputs.insert(String::from("www.url"), json!("https://example.com"));
+        outputs.insert(String::from("www.uid"), json!(1001));
+        let json = r#"{"www.uid":1001,"www.url":"https://example.com"}"#;
+
+        assert_eq!(serde_json::to_string(&outputs).unwrap(), json);
+        assert_eq!(outputs, serde_json::from_str(json).unwrap());
+        assert_eq!(
+            outputs.to_string(),
+            "www.uid = 1001\nwww.url = https://example.com\n"
+        );
+    }
+
+    #[test]
+    pub fn format_values() {
+        assert_eq!(format_value(&json!("/run/php.sock")), "/run/php.sock");
+        assert_eq!(format_value(&json!(["a", "b"])), r#"["a","b"]"#);
+    }
+
+    #[test]
+    pub fn load_missing_and_saved_outputs() {
+        let dir = TempDir::new("outputs");
+        let path = dir.join("outputs.json");
+        let mut outputs = Outputs::default();
+        outputs.insert(String::from("www.url"), json!("https://example.com"));
+
+        let missing = Outputs::load(&path, &LocalSystem::new()).unwrap();
+        outputs.save(&path, &LocalSystem::new()).unwrap();
+        let loaded = Outputs::load(&path, &LocalSystem::new()).unwrap();
+
+        assert!(missing.is_empty());
+        assert_eq!(loaded, outputs);
+    }
+}
@@ -0,0 +1,320 @@
+//! Previewing a build in a throwaway sandbox, for `side preview`.
+//!
+//! The sandbox is seeded with the current install, after which the new build is applied to it and the result is verified.
+//! The system itself is never changed. Both sandboxes are created from the machine running `side`:
+//! the base directory is copied into an LXC instance, or into a copy of a prepared root filesystem that is used as a chroot.
+//! Requirements that need a running init system, such as starting services, fail in a chroot sandbox.
+use crate::builder::{fs::CreateDirectory, Builder};
+use crate::graph::{ApplyLimits, VerificationState};
+use crate::requirements::Supports;
+use crate::system::{ChrootSystem, CommandResult, System};
+use crate::testing::{LxcError, LxcInstance, LxcLauncher};
+use crate::{ApplyMode, Dirs, Interaction};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The stages of a preview, in the order in which they are run.
+pub const STAGES: [&str; 3] = ["seed", "build", "verify"];
+
+/// The throwaway system that a build is previewed in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sandbox {
+    /// A new LXC instance started from the given image.
+    Lxc(String),
+
+    /// A chroot in a copy of the given root filesystem.
+    Chroot(PathBuf),
+}
+
+impl FromStr for Sandbox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "lxc" => Ok(Sandbox::Lxc(LxcInstance::DEFAULT_IMAGE.to_owned())),
+            Some(("lxc", image)) if !image.is_empty() => Ok(Sandbox::Lxc(image.to_owned())),
+            Some(("chroot", path)) if !path.is_empty() => Ok(Sandbox::Chroot(PathBuf::from(path))),
+            _ => Err(format!(
+                "invalid sandbox {:?}, expected 'lxc', 'lxc:<image>' or 'chroot:<rootfs>'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PreviewError<S: System> {
+    #[error("unable to execute {}: {}", .0, .1)]
+    FailedToStart(&'static str, S::CommandError),
+
+    #[error("{} failed: {} {}", .0, .1, .2)]
+    Unsuccessful(&'static str, String, String),
+
+    #[error("unable to prepare the LXC instance: {}", .0)]
+    Lxc(LxcError),
+}
+
+/// The outcome of a single stage of a preview.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stage {
+    pub name: &'static str,
+    pub result: Result<(), String>,
+}
+
+/// The outcome of all stages that were run. Stages after the first failure are not run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PreviewReport {
+    stages: Vec<Stage>,
+}
+
+impl PreviewReport {
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    /// Returns true if all stages were run and succeeded.
+    pub fn is_success(&self) -> bool {
+        self.stages.len() == STAGES.len() && self.stages.iter().all(|s| s.result.is_ok())
+    }
+
+    fn record(&mut self, name: &'static str, result: Result<(), String>) -> bool {
+        let ok = result.is_ok();
+        self.stages.push(Stage { name, result });
+        ok
+    }
+}
+
+impl Display for PreviewReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for name in STAGES {
+            match self.stages.iter().find(|s| s.name == name) {
+                Some(Stage { result: Ok(()), .. }) => writeln!(f, "{}: ok", name)?,
+                Some(Stage {
+                    result: Err(err), ..
+                }) => writeln!(
+                    f,
+                    "{}: failed\n  {}",
+                    name,
+                    err.trim_end().replace('\n', "\n  ")
+                )?,
+                None => writeln!(f, "{}: skipped", name)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn run<S: System>(
+    system: &mut S,
+    command: &'static str,
+    args: &[&str],
+) -> Result<CommandResult, PreviewError<S>> {
+    let result = system
+        .execute_command(command, args)
+        .map_err(|e| PreviewError::FailedToStart(command, e))?;
+    result.successful().map_err(|(stdout, stderr)| {
+        PreviewError::Unsuccessful(command, stdout.into(), stderr.into())
+    })?;
+
+    Ok(result)
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().expect("paths must be valid UTF-8")
+}
+
+/// Work that is done in a sandbox that contains a copy of the base directory, see [`in_sandbox`].
+pub(crate) trait InSandbox {
+    type Output;
+
+    fn run<P: System>(self, dirs: &Dirs, sandbox: &mut P) -> Self::Output;
+}
+
+struct Stages<B>(B);
+
+impl<B: Builder> InSandbox for Stages<B>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    type Output = PreviewReport;
+
+    fn run<P: System>(self, dirs: &Dirs, sandbox: &mut P) -> PreviewReport {
+        run_stages(dirs, sandbox, self.0)
+    }
+}
+
+/// Previews the build of the packages in `dirs` in `sandbox`. The sandbox is removed afterwards.
+pub fn preview<S: System, B: Builder>(
+    dirs: &Dirs,
+    system: &mut S,
+    builder: B,
+    sandbox: &Sandbox,
+) -> Result<PreviewReport, PreviewError<S>>
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    in_sandbox(dirs, system, sandbox, Stages(builder))
+}
+
+/// Runs `task` in a new `sandbox` that contains a copy of the base directory. The sandbox is removed afterwards.
+pub(crate) fn in_sandbox<S: System, T: InSandbox>(
+    dirs: &Dirs,
+    system: &mut S,
+    sandbox: &Sandbox,
+    task: T,
+) -> Result<T::Output, PreviewError<S>> {
+    match sandbox {
+        Sandbox::Lxc(image) => {
+            let mut instance = LxcLauncher::new()
+                .try_start(image)
+                .map_err(PreviewError::Lxc)?;
+            let parent = dirs.base.parent().unwrap_or_else(|| Path::new("/"));
+            instance
+                .copy_dir_to_container(&dirs.base, parent)
+                .map_err(PreviewError::Lxc)?;
+
+            Ok(task.run(dirs, &mut instance))
+        }
+        Sandbox::Chroot(rootfs) => {
+            let staging = PathBuf::from(format!("{}.preview", dirs.base.display()));
+            let staging_str = path_str(&staging);
+            run(system, "rm", &["-rf", staging_str])?;
+            run(system, "mkdir", &["-p", staging_str])?;
+            run(
+                system,
+                "cp",
+                &["-a", &format!("{}/.", path_str(rootfs)), staging_str],
+            )?;
+
+            let target = ChrootSystem::new(system, &staging).host_path(&dirs.base);
+            run(system, "mkdir", &["-p", path_str(&target)])?;
+            run(
+                system,
+                "cp",
+                &[
+                    "-a",
+                    &format!("{}/.", path_str(&dirs.base)),
+                    path_str(&target),
+                ],
+            )?;
+
+            let output = task.run(dirs, &mut ChrootSystem::new(system, &staging));
+            run(system, "rm", &["-rf", staging_str])?;
+
+            Ok(output)
+        }
+    }
+}
+
+/// Seeds `sandbox` with the current install, applies a new build and verifies the result.
+/// `sandbox` must contain a copy of the base directory.
+fn run_stages<P: System, B: Builder>(dirs: &Dirs, sandbox: &mut P, builder: B) -> PreviewReport
+where
+    B::Requirement: Supports<CreateDirectory>,
+{
+    let mut report = PreviewReport::default();
+
+    // The sandbox starts out empty, so the current install is applied on top of the empty initial install.
+    let seeded = dirs
+        .current_install(sandbox)
+        .map_err(|e| e.to_string())
+        .and_then(|current| {
+            dirs.set_current_install(&dirs.get_install(0), sandbox)
+                .map_err(|e| e.to_string())?;
+            crate::apply_install::<P, B>(
+                dirs,
+                sandbox,
+                &builder,
+                current.version,
+                Interaction::default(),
+                &ApplyLimits::default(),
+                ApplyMode {
+                    ignore_verification: true,
+                    ..ApplyMode::default()
+                },
+            )
+            .map_err(|e| e.to_string())
+        });
+    if !report.record("seed", seeded) {
+        return report;
+    }
+
+    let built = crate::build(
+        dirs,
+        sandbox,
+        &builder,
+        Interaction::default(),
+        &ApplyLimits::default(),
+        ApplyMode::default(),
+    )
+    .map_err(|e| e.to_string());
+    if !report.record("build", built) {
+        return report;
+    }
+
+    let verified = dirs
+        .current_install(sandbox)
+        .map_err(|e| e.to_string())
+        .and_then(|current| {
+            let state = current
+                .load_install::<B::Requirement, P>(sandbox)
+                .map_err(|e| e.to_string())?;
+            match state.verify_system_state(sandbox) {
+                Ok(VerificationState::Ok) => Ok(()),
+                Ok(err @ VerificationState::Invalid { .. }) => Err(err.to_string()),
+                Err(()) => Err(String::from("unable to determine the verification order")),
+            }
+        });
+    report.record("verify", verified);
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PreviewReport, Sandbox, Stage};
+    use crate::testing::LxcInstance;
+    use std::path::PathBuf;
+
+    #[test]
+    pub fn parse_sandbox() {
+        assert_eq!(
+            "lxc".parse::<Sandbox>().unwrap(),
+            Sandbox::Lxc(LxcInstance::DEFAULT_IMAGE.to_owned())
+        );
+        assert_eq!(
+            "lxc:images:debian/12".parse::<Sandbox>().unwrap(),
+            Sandbox::Lxc("images:debian/12".to_owned())
+        );
+        assert_eq!(
+            "chroot:/srv/rootfs".parse::<Sandbox>().unwrap(),
+            Sandbox::Chroot(PathBuf::from("/srv/rootfs"))
+        );
+        assert!("chroot:".parse::<Sandbox>().is_err());
+        assert!("docker".parse::<Sandbox>().is_err());
+    }
+
+    #[test]
+    pub fn report_skips_stages_after_failure() {
+        let report = PreviewReport {
+            stages: vec![
+                Stage {
+                    name: "seed",
+                    result: Ok(()),
+                },
+                Stage {
+                    name: "build",
+                    result: Err(String::from("apt-get failed")),
+                },
+            ],
+        };
+
+        assert!(!report.is_success());
+        assert_eq!(
+            report.to_string(),
+            "seed: ok\nbuild: failed\n  apt-get failed\nverify: skipped\n"
+        );
+    }
+}
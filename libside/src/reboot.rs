@@ -0,0 +1,176 @@
+//! Coordinating reboots that are needed for changes to take effect, such as kernel upgrades.
+//!
+//! A `RequiresReboot` requirement writes a marker to the reboots directory that records the boot in which the change was made.
+//! The reboot is pending as long as the system has not been restarted since, which is determined by comparing the boot id of the kernel.
+use crate::system::System;
+use crate::Dirs;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Changes whenever the system boots.
+const BOOT_ID: &str = "/proc/sys/kernel/random/boot_id";
+
+/// How often to check whether the system has come back after a reboot.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum RebootError<S: System> {
+    #[error("unable to read the boot id: {}", .0)]
+    UnableToReadBootId(S::Error),
+
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("unable to execute command: {}", .0)]
+    FailedToStart(S::CommandError),
+
+    #[error("command failed: {} {}", .0, .1)]
+    Unsuccessful(String, String),
+
+    #[error("the system did not come back within {} seconds", .0.as_secs())]
+    TimedOut(Duration),
+}
+
+impl<S: System> From<(&str, &str)> for RebootError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        RebootError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+/// A change that has been made since the last boot, and needs a reboot to take effect.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PendingReboot {
+    pub reason: String,
+}
+
+pub fn boot_id<S: System>(system: &S) -> Result<String, RebootError<S>> {
+    let contents = system
+        .file_contents(Path::new(BOOT_ID))
+        .map_err(RebootError::UnableToReadBootId)?;
+    Ok(String::from_utf8_lossy(&contents).trim().to_owned())
+}
+
+/// Records that the change described by `reason` has been made during the current boot.
+pub fn write_marker<S: System>(
+    system: &mut S,
+    marker: &Path,
+    reason: &str,
+) -> Result<(), RebootError<S>> {
+    if let Some(dir) = marker.parent() {
+        system
+            .make_dir_all(dir)
+            .map_err(|e| RebootError::UnableToWrite(dir.to_owned(), e))?;
+    }
+
+    let contents = format!("{}\n{}\n", boot_id(system)?, reason);
+    system
+        .put_file_contents(marker, contents.as_bytes())
+        .map_err(|e| RebootError::UnableToWrite(marker.to_owned(), e))
+}
+
+/// Parses a marker into the boot id and the reason.
+fn parse_marker(contents: &str) -> Option<(&str, &str)> {
+    let (boot_id, reason) = contents.split_once('\n')?;
+    Some((boot_id.trim(), reason.trim()))
+}
+
+/// Returns the changes that still need a reboot to take effect.
+pub fn pending<S: System>(
+    dirs: &Dirs,
+    system: &mut S,
+) -> Result<Vec<PendingReboot>, RebootError<S>> {
+    let exists = system
+        .path_exists(&dirs.reboots)
+        .map_err(|e| RebootError::UnableToRead(dirs.reboots.clone(), e))?;
+    if !exists {
+        return Ok(Vec::new());
+    }
+
+    let current = boot_id(system)?;
+    let mut names = system
+        .read_dir(&dirs.reboots)
+        .map_err(|e| RebootError::UnableToRead(dirs.reboots.clone(), e))?;
+    names.sort();
+
+    let mut result = Vec::new();
+    for name in names {
+        let path = dirs.reboots.join(name);
+        let contents = system
+            .file_contents(&path)
+            .map_err(|e| RebootError::UnableToRead(path.clone(), e))?;
+        if let Some((boot_id, reason)) = parse_marker(&String::from_utf8_lossy(&contents)) {
+            if boot_id == current {
+                result.push(PendingReboot {
+                    reason: reason.to_owned(),
+                });
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Schedules a reboot in one minute and waits until the system has come back, by polling the boot id.
+/// Errors while the system is down are expected and ignored.
+///
+/// When `system` is the machine running `side` itself, this process is stopped by the reboot; verification should then be run after the system has come back.
+pub fn reboot_and_wait<S: System>(system: &mut S, timeout: Duration) -> Result<(), RebootError<S>> {
+    let before = boot_id(system)?;
+    let result = system
+        .execute_command("shutdown", &["-r", "+1", "Rebooting to apply changes"])
+        .map_err(RebootError::FailedToStart)?;
+    result.successful()?;
+
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        std::thread::sleep(POLL_INTERVAL);
+        match boot_id(system) {
+            Ok(id) if id != before => return Ok(()),
+            _ => {}
+        }
+    }
+
+    Err(RebootError::TimedOut(timeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{boot_id, parse_marker, pending, write_marker, PendingReboot};
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use crate::Dirs;
+
+    #[test]
+    pub fn parse_markers() {
+        assert_eq!(
+            parse_marker("1234-abcd\nkernel upgrade\n"),
+            Some(("1234-abcd", "kernel upgrade"))
+        );
+        assert_eq!(parse_marker("1234-abcd"), None);
+    }
+
+    #[test]
+    pub fn only_markers_from_the_current_boot_are_pending() {
+        let base = TempDir::new("reboot");
+        let dirs = Dirs::new(&base);
+        let mut system = LocalSystem::new();
+
+        let missing = pending(&dirs, &mut system).unwrap();
+        write_marker(&mut system, &dirs.reboots.join("a"), "kernel upgrade").unwrap();
+        std::fs::write(dirs.reboots.join("b"), "previous-boot\nsysctl\n").unwrap();
+        let found = pending(&dirs, &mut system).unwrap();
+
+        assert!(!boot_id(&system).unwrap().is_empty());
+        assert!(missing.is_empty());
+        assert_eq!(
+            found,
+            vec![PendingReboot {
+                reason: String::from("kernel upgrade")
+            }]
+        );
+    }
+}
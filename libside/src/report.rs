@@ -0,0 +1,504 @@
+//! An inventory of what libside manages on the host.
+//!
+//! The report is generated by walking the graph of an install and asking each requirement to summarize itself with [`Requirement::summarize`].
+//! The facts are then grouped into a [`Report`], which can be printed for humans or serialized as JSON.
+use crate::builder::fs::Sha3;
+use crate::conffile::ThreeWay;
+use crate::graph::Graph;
+use crate::requirements::Requirement;
+use crate::system::System;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+/// The systemd settings that are included in the report for each service.
+pub const SANDBOX_SETTINGS: &[&str] = &[
+    "User",
+    "Group",
+    "DynamicUser",
+    "RootDirectory",
+    "BindPaths",
+    "BindReadOnlyPaths",
+    "PrivateTmp",
+    "PrivateDevices",
+    "PrivateNetwork",
+    "PrivateUsers",
+    "ProtectHome",
+    "ProtectSystem",
+    "ProtectKernelLogs",
+    "ProtectKernelModules",
+    "ProtectKernelTunables",
+    "ProtectControlGroups",
+    "ProtectClock",
+    "ProtectHostname",
+    "NoNewPrivileges",
+    "CapabilityBoundingSet",
+    "RestrictAddressFamilies",
+    "RestrictNamespaces",
+    "SystemCallFilter",
+    "MemoryDenyWriteExecute",
+];
+
+/// The kind of thing a requirement manages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    File,
+    Directory,
+    Permissions,
+    Package,
+    User,
+    Group,
+    Service,
+    Database,
+    Device,
+    Limits,
+    Firewall,
+    Other,
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Category::File => "files",
+            Category::Directory => "directories",
+            Category::Permissions => "permissions",
+            Category::Package => "system packages",
+            Category::User => "users",
+            Category::Group => "groups",
+            Category::Service => "services",
+            Category::Database => "databases",
+            Category::Device => "devices",
+            Category::Limits => "limits",
+            Category::Firewall => "firewall",
+            Category::Other => "other",
+        })
+    }
+}
+
+/// A human-readable description of a requirement, as returned by [`Requirement::describe`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Description {
+    pub category: Category,
+
+    /// The path or name of the thing that is managed.
+    pub target: String,
+    pub summary: String,
+}
+
+impl Description {
+    pub fn new<T: Into<String>, U: Into<String>>(
+        category: Category,
+        target: T,
+        summary: U,
+    ) -> Description {
+        Description {
+            category,
+            target: target.into(),
+            summary: summary.into(),
+        }
+    }
+}
+
+/// Something a requirement manages, as reported by [`Requirement::summarize`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fact {
+    File(PathBuf),
+    Directory(PathBuf),
+    AptPackage(String),
+    User {
+        name: String,
+        system: bool,
+    },
+    Group(String),
+    ServiceRunning(String),
+    ServiceEnabled {
+        name: String,
+        enabled: bool,
+    },
+    /// A sandbox setting from the unit file or one of the drop-ins of a service.
+    ServiceSetting {
+        name: String,
+        key: String,
+        value: String,
+    },
+    Database(String),
+    DatabaseUser(String),
+    DatabaseGrant {
+        user: String,
+        database: String,
+        privileges: String,
+    },
+    /// A port that a configuration file appears to listen on.
+    Port {
+        port: u16,
+        source: PathBuf,
+    },
+    /// A managed file that was changed by a package upgrade.
+    Conffile(ThreeWay),
+}
+
+/// Returns the name of the unit that is configured by the file at `path`, if it is a systemd unit file or drop-in.
+fn unit_name(path: &Path) -> Option<String> {
+    let parent = path.parent()?;
+    if parent == Path::new("/etc/systemd/system") {
+        let name = path.file_name()?.to_str()?;
+        return name.contains('.').then(|| name.to_owned());
+    }
+
+    let dir = parent.file_name()?.to_str()?;
+    if parent.parent()? == Path::new("/etc/systemd/system") {
+        return dir.strip_suffix(".d").map(str::to_owned);
+    }
+
+    None
+}
+
+/// Summarizes the contents of a configuration file written to `path`.
+/// Unit files are scanned for sandbox settings, and all other files are scanned for `listen` and `port` directives.
+/// The directives are recognized with a simple heuristic, so the list of ports is not guaranteed to be complete.
+pub fn summarize_config(path: &Path, contents: &[u8]) -> Vec<Fact> {
+    let contents = String::from_utf8_lossy(contents);
+    let mut facts = Vec::new();
+    if let Some(name) = unit_name(path) {
+        for line in contents.lines() {
+            if let Some((key, value)) = line.trim().split_once('=') {
+                if SANDBOX_SETTINGS.contains(&key.trim()) {
+                    facts.push(Fact::ServiceSetting {
+                        name: name.clone(),
+                        key: key.trim().to_owned(),
+                        value: value.trim().to_owned(),
+                    });
+                }
+            }
+        }
+
+        return facts;
+    }
+
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line
+            .split(|c: char| c.is_whitespace() || c == '=' || c == ';')
+            .filter(|w| !w.is_empty());
+        let directive = match words.next() {
+            Some(word) => word.to_ascii_lowercase(),
+            None => continue,
+        };
+
+        if directive == "listen" || directive == "port" {
+            let port = words
+                .next()
+                .and_then(|w| w.rsplit(':').next())
+                .and_then(|w| w.parse::<u16>().ok());
+            if let Some(port) = port {
+                facts.push(Fact::Port {
+                    port,
+                    source: path.to_owned(),
+                });
+            }
+        }
+    }
+
+    facts
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageReport {
+    pub name: String,
+
+    /// A hash of the requirements of the package. It changes whenever a rebuild changes what the package deploys.
+    pub revision: String,
+    pub requirements: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ServiceReport {
+    pub running: bool,
+    pub enabled: Option<bool>,
+    pub sandbox: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserReport {
+    pub name: String,
+    pub system: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GrantReport {
+    pub user: String,
+    pub database: String,
+    pub privileges: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PortReport {
+    pub port: u16,
+    pub source: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub install: u64,
+    pub packages: Vec<PackageReport>,
+    pub services: BTreeMap<String, ServiceReport>,
+    pub users: Vec<UserReport>,
+    pub groups: Vec<String>,
+    pub apt_packages: Vec<String>,
+    pub databases: Vec<String>,
+    pub database_users: Vec<String>,
+    pub database_grants: Vec<GrantReport>,
+    pub ports: Vec<PortReport>,
+    pub files: Vec<PathBuf>,
+
+    /// Managed files that a package upgrade changed or shipped a new version of.
+    pub conffiles: Vec<ThreeWay>,
+    pub directories: Vec<PathBuf>,
+
+    /// The descriptions of all requirements, grouped by category.
+    pub requirements: BTreeMap<Category, Vec<Description>>,
+}
+
+impl Report {
+    /// Generates a report for `graph`, which is the graph of install `install`.
+    pub fn generate<R: Requirement, State: Default + Copy, S: System>(
+        install: u64,
+        graph: &Graph<R, State>,
+        system: &mut S,
+    ) -> Report {
+        let mut report = Report {
+            install,
+            ..Default::default()
+        };
+
+        let mut packages = BTreeMap::<&str, (Vec<u8>, usize)>::new();
+        for (requirement, package) in graph.requirements().zip(graph.packages()) {
+            if let Some(package) = package {
+                let (serialized, count) = packages.entry(package).or_default();
+                serialized.extend(serde_json::to_vec(requirement).unwrap());
+                *count += 1;
+            }
+
+            for fact in requirement.summarize(system) {
+                report.add(fact);
+            }
+
+            let description = requirement.describe();
+            report
+                .requirements
+                .entry(description.category)
+                .or_default()
+                .push(description);
+        }
+
+        report.packages = packages
+            .into_iter()
+            .map(|(name, (serialized, requirements))| PackageReport {
+                name: name.to_owned(),
+                revision: Sha3::hash(&serialized).to_string()[..12].to_owned(),
+                requirements,
+            })
+            .collect();
+
+        report
+    }
+
+    fn add(&mut self, fact: Fact) {
+        match fact {
+            Fact::File(path) => self.files.push(path),
+            Fact::Directory(path) => self.directories.push(path),
+            Fact::AptPackage(name) => self.apt_packages.push(name),
+            Fact::User { name, system } => self.users.push(UserReport { name, system }),
+            Fact::Group(name) => self.groups.push(name),
+            Fact::ServiceRunning(name) => self.services.entry(name).or_default().running = true,
+            Fact::ServiceEnabled { name, enabled } => {
+                self.services.entry(name).or_default().enabled = Some(enabled)
+            }
+            Fact::ServiceSetting { name, key, value } => {
+                self.services
+                    .entry(name)
+                    .or_default()
+                    .sandbox
+                    .insert(key, value);
+            }
+            Fact::Database(name) => self.databases.push(name),
+            Fact::DatabaseUser(name) => self.database_users.push(name),
+            Fact::DatabaseGrant {
+                user,
+                database,
+                privileges,
+            } => self.database_grants.push(GrantReport {
+                user,
+                database,
+                privileges,
+            }),
+            Fact::Port { port, source } => self.ports.push(PortReport { port, source }),
+            Fact::Conffile(three_way) => self.conffiles.push(three_way),
+        }
+    }
+}
+
+fn write_list<T: Display>(
+    f: &mut std::fmt::Formatter<'_>,
+    title: &str,
+    items: impl IntoIterator<Item = T>,
+) -> std::fmt::Result {
+    let items = items.into_iter().map(|i| i.to_string()).collect::<Vec<_>>();
+    if items.is_empty() {
+        writeln!(f, "{}: none", title)
+    } else {
+        writeln!(f, "{}: {}", title, items.join(", "))
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Install {}", self.install)?;
+
+        writeln!(f, "Packages:")?;
+        for package in self.packages.iter() {
+            writeln!(
+                f,
+                "  {} (revision {}, {} requirements)",
+                package.name, package.revision, package.requirements
+            )?;
+        }
+
+        writeln!(f, "Services:")?;
+        for (name, service) in self.services.iter() {
+            let enabled = match service.enabled {
+                Some(true) => ", enabled",
+                Some(false) => ", disabled",
+                None => "",
+            };
+            let running = if service.running {
+                "running"
+            } else {
+                "stopped"
+            };
+            writeln!(f, "  {}: {}{}", name, running, enabled)?;
+            for (key, value) in service.sandbox.iter() {
+                writeln!(f, "    {}={}", key, value)?;
+            }
+        }
+
+        write_list(
+            f,
+            "Users",
+            self.users.iter().map(|u| {
+                if u.system {
+                    format!("{} (system)", u.name)
+                } else {
+                    u.name.clone()
+                }
+            }),
+        )?;
+        write_list(f, "Groups", self.groups.iter())?;
+        write_list(f, "APT packages", self.apt_packages.iter())?;
+        write_list(f, "Databases", self.databases.iter())?;
+        write_list(f, "Database users", self.database_users.iter())?;
+        write_list(
+            f,
+            "Database grants",
+            self.database_grants
+                .iter()
+                .map(|g| format!("{} on {} to {}", g.privileges, g.database, g.user)),
+        )?;
+        write_list(
+            f,
+            "Ports",
+            self.ports
+                .iter()
+                .map(|p| format!("{} ({})", p.port, p.source.display())),
+        )?;
+        writeln!(
+            f,
+            "Files: {} files, {} directories",
+            self.files.len(),
+            self.directories.len()
+        )?;
+        if !self.conffiles.is_empty() {
+            writeln!(f, "Changed by package upgrades:")?;
+            for three_way in self.conffiles.iter() {
+                writeln!(f, "  {}", three_way)?;
+            }
+        }
+
+        writeln!(f, "Requirements:")?;
+        for (category, descriptions) in self.requirements.iter() {
+            writeln!(f, "  {}: {}", category, descriptions.len())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{summarize_config, Category, Description, Fact, Report};
+    use crate::builder::fs::CreateDirectory;
+    use crate::graph::{Graph, Pending};
+    use crate::system::LocalSystem;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    pub fn ports_from_config() {
+        let path = Path::new("/etc/nginx/sites-enabled/site");
+        let facts = summarize_config(
+            path,
+            b"server {\n    listen 80;\n    listen [::]:443 ssl;\n    # listen 8080;\n    root /var/www;\n}\n",
+        );
+
+        assert_eq!(
+            facts,
+            vec![
+                Fact::Port {
+                    port: 80,
+                    source: path.to_owned()
+                },
+                Fact::Port {
+                    port: 443,
+                    source: path.to_owned()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    pub fn sandbox_from_unit_file() {
+        let facts = summarize_config(
+            Path::new("/etc/systemd/system/app.service.d/sandbox.conf"),
+            b"[Service]\nPrivateTmp=true\nExecStart=/usr/bin/app\n",
+        );
+
+        assert_eq!(
+            facts,
+            vec![Fact::ServiceSetting {
+                name: "app.service".to_owned(),
+                key: "PrivateTmp".to_owned(),
+                value: "true".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    pub fn generate_report() {
+        let mut graph = Graph::<CreateDirectory, Pending>::new();
+        graph.add(CreateDirectory::new(PathBuf::from("/srv/app")), &[]);
+
+        let report = Report::generate(3, &graph, &mut LocalSystem::new());
+        assert_eq!(report.install, 3);
+        assert_eq!(report.directories, vec![PathBuf::from("/srv/app")]);
+        assert!(report.to_string().contains("0 files, 1 directories"));
+        assert_eq!(
+            report.requirements[&Category::Directory],
+            vec![Description::new(
+                Category::Directory,
+                "/srv/app",
+                "directory"
+            )]
+        );
+    }
+}
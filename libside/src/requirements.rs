@@ -1,3 +1,7 @@
+use crate::batch::Probe;
+use crate::conflict::Resolution;
+use crate::report::{Category, Description, Fact};
+use crate::retry::RetryPolicy;
 use crate::system::System;
 use serde::{
     de::{DeserializeOwned, MapAccess, Visitor},
@@ -8,6 +12,7 @@ use std::{
     error::Error,
     fmt::{Debug, Display},
     marker::PhantomData,
+    path::{Path, PathBuf},
 };
 
 pub mod __impl {
@@ -24,6 +29,7 @@ macro_rules! requirements {
     ($name:ident = $($ty:tt),+ $(,)*) => {
         concat_idents::concat_idents!(
             mod_name = __req, $name {
+                #[allow(non_snake_case)]
                 mod mod_name {
                     use $crate::requirements::__impl::*;
 
@@ -187,6 +193,120 @@ macro_rules! requirements {
                                 $(Self::$ty { val } => Requirement::verify(val, system)),*
                             }
                         }
+
+                        fn resolve_existing<S: $crate::system::System>(&self, system: &mut S) -> Option<$crate::conflict::Resolution> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::resolve_existing(val, system)),*
+                            }
+                        }
+
+                        fn retry_policy(&self) -> Option<$crate::retry::RetryPolicy> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::retry_policy(val)),*
+                            }
+                        }
+
+                        fn backup_existing<S: $crate::system::System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::backup_existing(val, system).map_err(ModifyErrorImpl::<S>::$ty)),*
+                            }
+                        }
+
+                        fn saved_original(&self) -> Option<(&std::path::Path, &std::path::Path)> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::saved_original(val)),*
+                            }
+                        }
+
+                        fn diff<S: $crate::system::System>(&self, system: &mut S) -> Option<String> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::diff(val, system)),*
+                            }
+                        }
+
+                        fn verification_failure<S: $crate::system::System>(&self, system: &mut S) -> $crate::requirements::VerificationFailure {
+                            match self {
+                                $(Self::$ty { val } => Requirement::verification_failure(val, system)),*
+                            }
+                        }
+
+                        fn contents_diff<S: $crate::system::System>(&self, system: &mut S) -> Option<String> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::contents_diff(val, system)),*
+                            }
+                        }
+
+                        fn verify_probes(&self) -> Vec<$crate::batch::Probe> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::verify_probes(val)),*
+                            }
+                        }
+
+                        fn to_shell<S: $crate::system::System>(&self, system: &mut S) -> Option<String> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::to_shell(val, system)),*
+                            }
+                        }
+
+                        fn summarize<S: $crate::system::System>(&self, system: &mut S) -> Vec<$crate::report::Fact> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::summarize(val, system)),*
+                            }
+                        }
+
+                        fn required_commands(&self) -> Vec<&'static str> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::required_commands(val)),*
+                            }
+                        }
+
+                        fn describe(&self) -> $crate::report::Description {
+                            match self {
+                                $(Self::$ty { val } => Requirement::describe(val)),*
+                            }
+                        }
+
+                        fn kind(&self) -> &'static str {
+                            match self {
+                                $(Self::$ty { val } => Requirement::kind(val)),*
+                            }
+                        }
+
+                        fn identity(&self) -> String {
+                            match self {
+                                $(Self::$ty { val } => Requirement::identity(val)),*
+                            }
+                        }
+
+                        fn preflight<S: $crate::system::System>(&self, system: &mut S) -> Vec<String> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::preflight(val, system)),*
+                            }
+                        }
+
+                        fn exclusive_resources(&self) -> Vec<$crate::requirements::Resource> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::exclusive_resources(val)),*
+                            }
+                        }
+
+                        fn backup<S: $crate::system::System>(&self, system: &mut S, to: &std::path::Path) -> Result<bool, $crate::requirements::BackupError<S>> {
+                            match self {
+                                $(Self::$ty { val } => Requirement::backup(val, system, to)),*
+                            }
+                        }
+                    
+                        fn documentation(&self) -> $crate::requirements::Documentation {
+                            match self {
+                                $(Self::$ty { val } => Requirement::documentation(val)),*
+                            }
+                        }
+
+                        fn kinds() -> Vec<$crate::requirements::Documentation> {
+                            let mut kinds = Vec::new();
+                            $(kinds.extend(<super::$ty as Requirement>::kinds());)*
+                            kinds
+                        }
                     
                         const NAME: &'static str = "<unused>";
                     }
@@ -308,6 +428,306 @@ pub trait Requirement:
     fn may_pre_exist(&self) -> bool;
 
     fn verify<S: System>(&self, system: &mut S) -> Result<bool, ()>;
+
+    /// Decides how a conflict with existing state is resolved, if the requirement can determine that without asking.
+    /// For example, existing state that is identical to the requirement can be adopted.
+    fn resolve_existing<S: System>(&self, _system: &mut S) -> Option<Resolution> {
+        None
+    }
+
+    /// Returns how often creating, modifying or deleting the requirement is attempted, if it can fail for transient reasons.
+    /// The `[retry]` section of the settings overrides this, see [`RetrySettings`](crate::retry::RetrySettings).
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        None
+    }
+
+    /// Saves existing state that the requirement is about to overwrite, so that `pre_existing_delete` can restore it.
+    fn backup_existing<S: System>(&self, _system: &mut S) -> Result<(), Self::ModifyError<S>> {
+        Ok(())
+    }
+
+    /// Returns the system file whose original the requirement saves before it overwrites or deletes it, and the path where the original is saved.
+    /// Later builds save the original of the same file to the same path, and retention keeps it while the current install refers to it.
+    fn saved_original(&self) -> Option<(&Path, &Path)> {
+        None
+    }
+
+    /// Describes how the state that currently exists on the system differs from the requirement, for example when it conflicts with existing state.
+    /// Returns `None` if the difference is unknown.
+    fn diff<S: System>(&self, _system: &mut S) -> Option<String> {
+        None
+    }
+
+    /// Describes why the requirement failed verification. Only called after `verify` returned false.
+    /// By default, the failure is described by [`Requirement::diff`].
+    fn verification_failure<S: System>(&self, system: &mut S) -> VerificationFailure {
+        match self.diff(system) {
+            Some(details) => VerificationFailure::Differs { details },
+            None => VerificationFailure::Unknown,
+        }
+    }
+
+    /// Returns a unified diff from the contents of the file that currently exists on the system to the contents that the requirement writes, for showing the changes of a plan.
+    /// Returns `None` if the requirement does not write a file, or if no file exists yet.
+    fn contents_diff<S: System>(&self, _system: &mut S) -> Option<String> {
+        None
+    }
+
+    /// Returns the read-only checks that `verify` performs, so that they can be prefetched in a single batch when many requirements are verified.
+    /// Checks that are not listed here still work, but need a separate round-trip to the system.
+    fn verify_probes(&self) -> Vec<Probe> {
+        Vec::new()
+    }
+
+    /// Returns shell commands that have the same effect as `create`, for use in a standalone bootstrap script.
+    /// Returns `None` if the requirement cannot be expressed as a shell script.
+    /// The system is only used to read local files that need to be embedded in the script.
+    fn to_shell<S: System>(&self, _system: &mut S) -> Option<String> {
+        None
+    }
+
+    /// Describes what this requirement manages, for the inventory generated by `side report`.
+    /// The system is only used to read local files, for example to find the ports that a configuration file listens on.
+    fn summarize<S: System>(&self, _system: &mut S) -> Vec<Fact> {
+        Vec::new()
+    }
+
+    /// Returns the external commands that must be available on the system to apply or verify the requirement, for `side doctor`.
+    fn required_commands(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    /// Describes the requirement for humans.
+    /// Unlike `Display`, which identifies the requirement in logs, the description is structured so that requirements can be grouped by category.
+    fn describe(&self) -> Description {
+        Description::new(Category::Other, Self::NAME, self.to_string())
+    }
+
+    /// The kind of the requirement. This is `NAME`, except for the types generated by `requirements!`, which return the kind of the requirement they contain.
+    fn kind(&self) -> &'static str {
+        Self::NAME
+    }
+
+    /// Returns the fields that identify the requirement across builds, such as the path of a file but not its contents.
+    /// Requirements of the same kind with the same identity are the same node in different versions of the graph, see `graph::NodeId`.
+    /// Defaults to the entire requirement, so that any change to the requirement gives it a new identity.
+    fn identity(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Checks, without changing the system, whether the requirement can be applied, such as whether a package is available.
+    /// Returns a description of every problem that would make `create` fail.
+    /// The commands in `required_commands` are checked separately.
+    fn preflight<S: System>(&self, _system: &mut S) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Returns the resources on the system that this requirement holds exclusively, such as the path it creates.
+    /// Two requirements that hold conflicting resources cannot exist at the same time,
+    /// so an apply that creates new requirements first still has to undo the old requirement before creating the new one.
+    fn exclusive_resources(&self) -> Vec<Resource> {
+        Vec::new()
+    }
+
+    /// Saves the data that undoing the requirement would destroy but that the requirement did not create, such as files that an application wrote to a directory.
+    /// Called before `delete` when the requirement is undone, with a directory that is unique to the requirement and the apply. The directory does not exist yet.
+    /// Returns true if a backup was written to the directory.
+    fn backup<S: System>(&self, _system: &mut S, _to: &Path) -> Result<bool, BackupError<S>> {
+        Ok(false)
+    }
+
+    /// Explains what creating, verifying and undoing a requirement of this kind does, for `side explain`.
+    const EXPLANATION: Explanation = Explanation::UNDOCUMENTED;
+
+    /// Where to read more about this kind of requirement, such as the manual of the command it runs.
+    const DOCS_URL: Option<&'static str> = None;
+
+    /// Common causes of failures of this kind of requirement.
+    /// A hint is shown when a requirement fails with an error that contains the pattern of the hint.
+    const FAILURE_HINTS: &'static [FailureHint] = &[];
+
+    /// The documentation of the kind of this requirement.
+    /// This is the documentation of `Self`, except for the types generated by `requirements!`, which return the documentation of the requirement they contain.
+    fn documentation(&self) -> Documentation {
+        Documentation::of::<Self>()
+    }
+
+    /// Returns the documentation of the kinds of requirements that this type can hold.
+    /// This is just the documentation of `Self`, except for the types generated by `requirements!`, which list every type they support.
+    fn kinds() -> Vec<Documentation> {
+        vec![Documentation::of::<Self>()]
+    }
+}
+
+/// The semantics of a kind of requirement, in plain words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Explanation {
+    /// What the requirement manages, in a single sentence.
+    pub summary: &'static str,
+
+    /// What applying the requirement does to the system.
+    pub create: &'static str,
+
+    /// What verification checks.
+    pub verify: &'static str,
+
+    /// What undoing the requirement does, or why it cannot be undone.
+    pub undo: &'static str,
+}
+
+impl Explanation {
+    pub const UNDOCUMENTED: Explanation = Explanation {
+        summary: "Not documented.",
+        create: "Not documented.",
+        verify: "Not documented.",
+        undo: "Not documented.",
+    };
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.summary)?;
+        writeln!(f)?;
+        writeln!(f, "Create: {}", self.create)?;
+        writeln!(f, "Verify: {}", self.verify)?;
+        write!(f, "Undo:   {}", self.undo)
+    }
+}
+
+/// A likely cause of a failure, and what to do about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailureHint {
+    /// A part of the error message, such as `is not unique`.
+    pub pattern: &'static str,
+    pub hint: &'static str,
+}
+
+/// Everything that is known about a kind of requirement, for `side explain` and the reports of failed requirements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Documentation {
+    pub kind: &'static str,
+    pub explanation: Explanation,
+    pub url: Option<&'static str>,
+    pub hints: &'static [FailureHint],
+}
+
+impl Documentation {
+    pub fn of<R: Requirement>() -> Documentation {
+        Documentation {
+            kind: R::NAME,
+            explanation: R::EXPLANATION,
+            url: R::DOCS_URL,
+            hints: R::FAILURE_HINTS,
+        }
+    }
+
+    /// Returns the hints whose pattern occurs in `error`.
+    pub fn hints_for<'e>(&self, error: &'e str) -> impl Iterator<Item = &'static str> + 'e {
+        self.hints
+            .iter()
+            .filter(move |hint| error.contains(hint.pattern))
+            .map(|hint| hint.hint)
+    }
+}
+
+impl Display for Documentation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.explanation)?;
+        if let Some(url) = self.url {
+            write!(f, "\n\nSee {}", url)?;
+        }
+
+        if !self.hints.is_empty() {
+            write!(f, "\n\nCommon failures:")?;
+            for hint in self.hints {
+                write!(f, "\n  {:?}: {}", hint.pattern, hint.hint)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError<S: System> {
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("unable to execute the backup command: {}", .0)]
+    FailedToStart(S::CommandError),
+
+    #[error("the backup command failed: {} {}", .0, .1)]
+    Unsuccessful(String, String),
+}
+
+impl<S: System> From<(&str, &str)> for BackupError<S> {
+    fn from(output: (&str, &str)) -> Self {
+        BackupError::Unsuccessful(output.0.to_string(), output.1.to_string())
+    }
+}
+
+/// Something on the system that only one requirement can hold at a time.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Resource {
+    /// A path, which can only be a single file, directory or symlink.
+    /// A path also conflicts with the paths inside it.
+    Path(PathBuf),
+
+    /// A numeric user id.
+    Uid(u32),
+
+    /// A numeric group id.
+    Gid(u32),
+}
+
+impl Resource {
+    pub fn conflicts_with(&self, other: &Resource) -> bool {
+        match (self, other) {
+            (Resource::Path(a), Resource::Path(b)) => a.starts_with(b) || b.starts_with(a),
+            (a, b) => a == b,
+        }
+    }
+}
+
+/// Why a requirement failed verification, see [`Requirement::verification_failure`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum VerificationFailure {
+    /// What the requirement created no longer exists, such as a deleted file.
+    Missing,
+
+    /// A file exists, but its contents have changed.
+    HashMismatch {
+        expected: String,
+        found: String,
+    },
+
+    /// The service is not running.
+    ServiceStopped,
+
+    /// The system differs from the requirement in another way, see [`Requirement::diff`].
+    Differs {
+        details: String,
+    },
+
+    Unknown,
+}
+
+impl Display for VerificationFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerificationFailure::Missing => write!(f, "missing"),
+            VerificationFailure::HashMismatch { expected, found } => {
+                write!(f, "checksum {}, expected {}", found, expected)
+            }
+            VerificationFailure::ServiceStopped => write!(f, "service stopped"),
+            VerificationFailure::Differs { details } => write!(f, "{}", details),
+            VerificationFailure::Unknown => write!(f, "reason unknown"),
+        }
+    }
 }
 
 pub trait Supports<R> {
@@ -322,10 +742,11 @@ impl<T> Supports<T> for T {
 
 #[cfg(test)]
 mod tests {
-    use super::Supports;
+    use super::{Documentation, Explanation, FailureHint, Resource, Supports};
     use crate::requirements::Requirement;
     use serde::{Deserialize, Serialize};
     use std::fmt::{Debug, Display};
+    use std::path::PathBuf;
 
     #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
     struct Foo {
@@ -362,6 +783,16 @@ mod tests {
 
     impl Requirement for Foo {
         const NAME: &'static str = "foo";
+        const EXPLANATION: Explanation = Explanation {
+            summary: "A number.",
+            create: "Nothing.",
+            verify: "Nothing.",
+            undo: "Nothing.",
+        };
+        const FAILURE_HINTS: &'static [FailureHint] = &[FailureHint {
+            pattern: "too large",
+            hint: "Use a smaller number.",
+        }];
 
         type CreateError<S: crate::system::System> = std::io::Error;
         type ModifyError<S: crate::system::System> = std::io::Error;
@@ -544,4 +975,37 @@ mod tests {
         let v: R = Supports::create_from(Baz { k: (5, 10, 15) });
         assert_eq!(v, R::create_from(Baz { k: (5, 10, 15) }));
     }
+
+    #[test]
+    pub fn documentation() {
+        requirements!(R = Foo, Bar);
+        let kinds = R::kinds();
+
+        assert_eq!(kinds.len(), 2);
+        assert_eq!(kinds[0], Documentation::of::<Foo>());
+        assert_eq!(kinds[0].explanation, Foo::EXPLANATION);
+        assert_eq!(kinds[1].kind, "bar");
+        assert_eq!(kinds[1].explanation, Explanation::UNDOCUMENTED);
+
+        let foo = R::create_from(Foo { x: 1 });
+        assert_eq!(foo.documentation().kind, "foo");
+        assert_eq!(
+            foo.documentation()
+                .hints_for("value 5000 is too large")
+                .collect::<Vec<_>>(),
+            vec!["Use a smaller number."]
+        );
+        assert_eq!(foo.documentation().hints_for("invalid").count(), 0);
+    }
+
+    #[test]
+    pub fn conflicting_resources() {
+        let dir = Resource::Path(PathBuf::from("/var/www/app"));
+        assert!(dir.conflicts_with(&Resource::Path(PathBuf::from("/var/www/app"))));
+        assert!(dir.conflicts_with(&Resource::Path(PathBuf::from("/var/www/app/index.html"))));
+        assert!(dir.conflicts_with(&Resource::Path(PathBuf::from("/var/www"))));
+        assert!(!dir.conflicts_with(&Resource::Path(PathBuf::from("/var/www/app2"))));
+        assert!(Resource::Uid(1000).conflicts_with(&Resource::Uid(1000)));
+        assert!(!Resource::Uid(1000).conflicts_with(&Resource::Gid(1000)));
+    }
 }
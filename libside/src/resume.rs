@@ -0,0 +1,283 @@
+//! Checkpoints of applies, so that an apply that was interrupted by a crash, a power loss or a dropped SSH connection can be resumed with `side resume`.
+//!
+//! While an apply runs, `progress.json` in the directory of the target install records which requirements it has undone and applied.
+//! The file starts with a snapshot of the progress, which is replaced atomically, followed by a [`ProgressEvent`] on a line of its own for every requirement since the snapshot.
+//! The file is removed once the apply has finished or has been reverted, so a file that remains belongs to an apply that was interrupted.
+//! A resumed apply skips the requirements that have been undone, and the requirements that have been applied and still exist according to [`Requirement::has_been_created`](crate::requirements::Requirement::has_been_created).
+use crate::graph::{ApplyStrategy, NodeId};
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ResumeError<S: System> {
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("invalid apply progress in {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, serde_json::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("unable to remove {}: {}", .0.display(), .1)]
+    UnableToRemove(PathBuf, S::Error),
+
+    #[error("there is no interrupted apply to resume")]
+    NothingToResume,
+
+    #[error("the apply of install {} was not interrupted", .0)]
+    NotInterrupted(u64),
+
+    #[error("the apply of install {} started from install {}, but install {} is current now; apply install {} again instead", .install, .from, .current, .install)]
+    CurrentChanged {
+        install: u64,
+        from: u64,
+        current: u64,
+    },
+}
+
+/// The requirements that an apply has undone and applied so far.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApplyProgress {
+    /// The install that was current when the apply started. The apply can only be resumed while it still is.
+    pub from: u64,
+
+    /// The install that is being applied.
+    pub to: u64,
+
+    /// Whether the apply is part of `side build`, whose database is only complete once the apply has finished.
+    pub build: bool,
+
+    pub strategy: ApplyStrategy,
+    pub isolate_failures: bool,
+
+    /// The checkpoint of a staged rollout up to which the install is applied, see [`rollout`](crate::rollout).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<String>,
+
+    /// The requirements of the previous install that have been undone.
+    #[serde(default)]
+    pub undone: BTreeSet<NodeId>,
+
+    /// The nodes of the target graph that have been applied.
+    #[serde(default)]
+    pub applied: BTreeSet<usize>,
+
+    /// The nodes of the target graph that already existed before the apply.
+    #[serde(default)]
+    pub pre_existing: BTreeSet<usize>,
+
+    /// The node of the target graph that was being applied, if any. It may have been applied partially.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started: Option<usize>,
+}
+
+/// A change to an [`ApplyProgress`], which is appended to the progress file instead of rewriting the entire file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// A requirement of the previous install has been undone.
+    Undone(NodeId),
+
+    /// A node of the target graph already existed before the apply.
+    PreExisting(usize),
+
+    /// A node of the target graph is being applied.
+    Started(usize),
+
+    /// A node of the target graph has been applied.
+    Applied(usize),
+}
+
+impl ApplyProgress {
+    pub fn new(from: u64, to: u64) -> ApplyProgress {
+        ApplyProgress {
+            from,
+            to,
+            build: false,
+            strategy: ApplyStrategy::default(),
+            isolate_failures: false,
+            until: None,
+            undone: BTreeSet::new(),
+            applied: BTreeSet::new(),
+            pre_existing: BTreeSet::new(),
+            started: None,
+        }
+    }
+
+    /// Loads the progress of an interrupted apply, if any.
+    pub fn load<S: System>(
+        path: &Path,
+        system: &S,
+    ) -> Result<Option<ApplyProgress>, ResumeError<S>> {
+        let exists = system
+            .path_exists(path)
+            .map_err(|e| ResumeError::UnableToRead(path.to_owned(), e))?;
+        if !exists {
+            return Ok(None);
+        }
+
+        let contents = system
+            .file_contents(path)
+            .map_err(|e| ResumeError::UnableToRead(path.to_owned(), e))?;
+        let invalid = |e| ResumeError::Invalid(path.to_owned(), e);
+        let mut lines = contents.split(|&byte| byte == b'\n');
+        let mut progress: ApplyProgress =
+            serde_json::from_slice(lines.next().unwrap_or_default()).map_err(invalid)?;
+        let complete = contents.ends_with(b"\n");
+        let mut lines = lines.filter(|line| !line.is_empty()).peekable();
+        while let Some(line) = lines.next() {
+            match serde_json::from_slice(line) {
+                Ok(event) => progress.apply(event),
+                // The last event may have been cut off by the interruption
+                Err(_) if !complete && lines.peek().is_none() => {}
+                Err(e) => return Err(invalid(e)),
+            }
+        }
+
+        Ok(Some(progress))
+    }
+
+    /// Replaces the progress file with a snapshot of the progress, without any events.
+    pub fn save<S: System>(&self, path: &Path, system: &mut S) -> Result<(), ResumeError<S>> {
+        let mut contents = serde_json::to_vec(self).unwrap();
+        contents.push(b'\n');
+        system
+            .replace_file_contents(path, &contents)
+            .map_err(|e| ResumeError::UnableToWrite(path.to_owned(), e))
+    }
+
+    /// Adds `events` to the progress file that was written by [`ApplyProgress::save`].
+    pub fn append<S: System>(
+        path: &Path,
+        events: &[ProgressEvent],
+        system: &mut S,
+    ) -> Result<(), ResumeError<S>> {
+        let mut contents = Vec::new();
+        for event in events.iter() {
+            serde_json::to_writer(&mut contents, event).unwrap();
+            contents.push(b'\n');
+        }
+
+        system
+            .append_file_contents(path, &contents)
+            .map_err(|e| ResumeError::UnableToWrite(path.to_owned(), e))
+    }
+
+    pub fn apply(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Undone(id) => {
+                self.undone.insert(id);
+            }
+            ProgressEvent::PreExisting(node) => {
+                self.pre_existing.insert(node);
+            }
+            ProgressEvent::Started(node) => self.started = Some(node),
+            ProgressEvent::Applied(node) => {
+                self.applied.insert(node);
+                self.started = None;
+            }
+        }
+    }
+
+    /// Removes the progress once the apply has finished or has been reverted.
+    pub fn remove<S: System>(path: &Path, system: &mut S) -> Result<(), ResumeError<S>> {
+        let exists = system
+            .path_exists(path)
+            .map_err(|e| ResumeError::UnableToRemove(path.to_owned(), e))?;
+        if exists {
+            system
+                .remove_file(path)
+                .map_err(|e| ResumeError::UnableToRemove(path.to_owned(), e))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for ApplyProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the apply of install {} over install {} was interrupted after {} undone and {} applied requirements",
+            self.to,
+            self.from,
+            self.undone.len(),
+            self.applied.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ApplyProgress, ProgressEvent};
+    use crate::graph::ApplyStrategy;
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+
+    #[test]
+    pub fn save_load_remove() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("progress");
+        let path = dir.join("progress.json");
+        assert_eq!(ApplyProgress::load(&path, &system).unwrap(), None);
+
+        let mut progress = ApplyProgress::new(3, 4);
+        progress.strategy = ApplyStrategy::CreateFirst;
+        progress.applied.extend([0, 1]);
+        progress.pre_existing.insert(1);
+        progress.started = Some(2);
+        progress.save(&path, &mut system).unwrap();
+        assert_eq!(
+            ApplyProgress::load(&path, &system).unwrap(),
+            Some(progress.clone())
+        );
+        assert_eq!(
+            progress.to_string(),
+            "the apply of install 4 over install 3 was interrupted after 0 undone and 2 applied requirements"
+        );
+
+        ApplyProgress::remove(&path, &mut system).unwrap();
+        ApplyProgress::remove(&path, &mut system).unwrap();
+        assert_eq!(ApplyProgress::load(&path, &system).unwrap(), None);
+    }
+
+    #[test]
+    pub fn append_events() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("progress-events");
+        let path = dir.join("progress.json");
+        ApplyProgress::new(3, 4).save(&path, &mut system).unwrap();
+        ApplyProgress::append(
+            &path,
+            &[ProgressEvent::PreExisting(0), ProgressEvent::Started(0)],
+            &mut system,
+        )
+        .unwrap();
+        ApplyProgress::append(&path, &[ProgressEvent::Applied(0)], &mut system).unwrap();
+        ApplyProgress::append(&path, &[ProgressEvent::Started(1)], &mut system).unwrap();
+
+        let mut expected = ApplyProgress::new(3, 4);
+        expected.applied.insert(0);
+        expected.pre_existing.insert(0);
+        expected.started = Some(1);
+        assert_eq!(
+            ApplyProgress::load(&path, &system).unwrap(),
+            Some(expected.clone())
+        );
+
+        // An event that was cut off by the interruption is ignored
+        system.append_file_contents(&path, br#"{"appl"#).unwrap();
+        assert_eq!(
+            ApplyProgress::load(&path, &system).unwrap(),
+            Some(expected.clone())
+        );
+
+        expected.save(&path, &mut system).unwrap();
+        assert_eq!(ApplyProgress::load(&path, &system).unwrap(), Some(expected));
+        assert!(!dir.join("progress.json.tmp").exists());
+    }
+}
@@ -0,0 +1,444 @@
+//! Removing old backups and installs, for `side maintain` and after every apply.
+//!
+//! Every apply adds a new install, and backups of the files and data that it overwrote, deleted or undid.
+//! The [`RetentionPolicy`] in the settings of the base directory limits the age and total size of these.
+//! Only the entries of installs older than the current install are removed: the current install, newer installs,
+//! and the backups that undoing the current install needs (such as patches, alternatives and the originals it still refers to) are always kept.
+//! Removing an old install means that it can no longer be applied again.
+use crate::system::{CommandResult, System};
+use crate::{Dirs, ORIGINALS, UNDONE};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const DAY: u64 = 24 * 60 * 60;
+const MIB: u64 = 1024 * 1024;
+
+/// How long backups and old installs are kept. Without limits, nothing is removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionPolicy {
+    /// Remove entries that were last modified more than this many days ago.
+    pub max_age_days: Option<u64>,
+
+    /// Remove the oldest entries until the remaining entries take up at most this many MiB.
+    pub max_size_mib: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age_days.is_none() && self.max_size_mib.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntryKind {
+    /// The directories of an install, with its database and generated files.
+    Install,
+
+    /// The system files that an install overwrote or deleted, see [`Dirs::originals_path`].
+    Originals,
+
+    /// The data that was backed up before requirements were undone, see [`Dirs::undone_path`].
+    Undone,
+}
+
+impl Display for EntryKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            EntryKind::Install => "install",
+            EntryKind::Originals => "originals of install",
+            EntryKind::Undone => "undone backups of install",
+        })
+    }
+}
+
+/// Directories that belong to a single install, and are removed together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub version: u64,
+    pub paths: Vec<PathBuf>,
+
+    /// The total size of the directories, in bytes.
+    pub size: u64,
+
+    /// The most recent modification time of the directories.
+    pub modified: SystemTime,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} ({:.1} MiB)",
+            self.kind,
+            self.version,
+            self.size as f64 / MIB as f64
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The entry is older than the maximum age in days.
+    Age(u64),
+
+    /// Keeping the entry would exceed the maximum size.
+    Size,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Removal {
+    pub entry: Entry,
+    pub reason: Reason,
+}
+
+impl Display for Removal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, ", self.entry)?;
+        match self.reason {
+            Reason::Age(days) => write!(f, "older than {} days", days)?,
+            Reason::Size => write!(f, "over the size limit")?,
+        }
+
+        write!(
+            f,
+            ": {}",
+            self.entry
+                .paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+/// The entries that a retention policy removes, and the entries that it keeps.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub removals: Vec<Removal>,
+    pub kept: Vec<Entry>,
+}
+
+impl Plan {
+    /// The total size of the entries that are removed, in bytes.
+    pub fn freed(&self) -> u64 {
+        self.removals.iter().map(|removal| removal.entry.size).sum()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RetentionError<S: System> {
+    #[error("unable to list {}: {}", .0.display(), .1)]
+    UnableToList(PathBuf, S::Error),
+
+    #[error("unable to execute {}: {}", .0, .1)]
+    FailedToStart(&'static str, S::CommandError),
+
+    #[error("{} failed: {}", .0, .1)]
+    Unsuccessful(&'static str, String),
+
+    #[error("unexpected output from {}: {:?}", .0, .1)]
+    UnexpectedOutput(&'static str, String),
+}
+
+fn run<S: System>(
+    system: &mut S,
+    command: &'static str,
+    args: &[&str],
+) -> Result<CommandResult, RetentionError<S>> {
+    let result = system
+        .execute_command(command, args)
+        .map_err(|e| RetentionError::FailedToStart(command, e))?;
+    result
+        .successful()
+        .map_err(|(_, stderr)| RetentionError::Unsuccessful(command, stderr.trim().to_owned()))?;
+
+    Ok(result)
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().expect("paths must be valid UTF-8")
+}
+
+/// The versions of the subdirectories of `dir` that are older than `current`.
+fn versions<S: System>(
+    system: &mut S,
+    dir: &Path,
+    current: u64,
+) -> Result<Vec<u64>, RetentionError<S>> {
+    let list_error = |e| RetentionError::UnableToList(dir.to_owned(), e);
+    if !system.path_exists(dir).map_err(list_error)? {
+        return Ok(Vec::new());
+    }
+
+    Ok(system
+        .read_dir(dir)
+        .map_err(list_error)?
+        .iter()
+        .flat_map(|name| name.parse::<u64>().ok())
+        .filter(|&version| version < current)
+        .collect())
+}
+
+fn entry<S: System>(
+    system: &mut S,
+    kind: EntryKind,
+    version: u64,
+    paths: Vec<PathBuf>,
+) -> Result<Entry, RetentionError<S>> {
+    let mut args = vec!["-s", "-k", "-c"];
+    args.extend(paths.iter().map(|path| path_str(path)));
+    let result = run(system, "du", &args)?;
+    let output = result.stdout_as_str();
+    let size = output
+        .lines()
+        .last()
+        .and_then(|total| total.split_whitespace().next())
+        .and_then(|kib| kib.parse::<u64>().ok())
+        .ok_or_else(|| RetentionError::UnexpectedOutput("du", output.to_owned()))?;
+
+    // Entries of which the modification time cannot be determined are treated as new, so that they are not removed by age.
+    let mut modified = None;
+    for path in paths.iter() {
+        let mtime = system
+            .file_mtime(path)
+            .map_err(|e| RetentionError::UnableToList(path.clone(), e))?;
+        modified = modified.max(Some(mtime.unwrap_or_else(SystemTime::now)));
+    }
+
+    Ok(Entry {
+        kind,
+        version,
+        paths,
+        size: size * 1024,
+        modified: modified.unwrap_or_else(SystemTime::now),
+    })
+}
+
+/// Lists the installs older than `current`, and their backups, oldest first.
+/// The originals of older installs that contain one of the paths in `in_use` are not listed, because the current install restores them when it is undone.
+pub fn entries<S: System>(
+    dirs: &Dirs,
+    system: &mut S,
+    current: u64,
+    in_use: &[PathBuf],
+) -> Result<Vec<Entry>, RetentionError<S>> {
+    let mut entries = Vec::new();
+    for version in versions(system, &dirs.installed, current)? {
+        let mut paths = vec![dirs.installed.join(version.to_string())];
+        let chroot = dirs.chroots.join(version.to_string());
+        if system
+            .path_exists(&chroot)
+            .map_err(|e| RetentionError::UnableToList(chroot.clone(), e))?
+        {
+            paths.push(chroot);
+        }
+
+        entries.push(entry(system, EntryKind::Install, version, paths)?);
+    }
+
+    for (kind, dir) in [
+        (EntryKind::Originals, dirs.backups.join(ORIGINALS)),
+        (EntryKind::Undone, dirs.backups.join(UNDONE)),
+    ] {
+        for version in versions(system, &dir, current)? {
+            let path = dir.join(version.to_string());
+            if kind == EntryKind::Originals && in_use.iter().any(|used| used.starts_with(&path)) {
+                continue;
+            }
+
+            let paths = vec![path];
+            entries.push(entry(system, kind, version, paths)?);
+        }
+    }
+
+    entries.sort_by_key(|entry| (entry.modified, entry.version));
+    Ok(entries)
+}
+
+/// Decides which of `entries` to remove under `policy`. The entries must be sorted oldest first, as returned by [`entries`].
+/// Entries that are too old are removed first. If the remaining entries are still too large, the oldest of them are removed as well.
+pub fn plan(entries: Vec<Entry>, policy: &RetentionPolicy, now: SystemTime) -> Plan {
+    let mut plan = Plan::default();
+    for entry in entries {
+        let age = now
+            .duration_since(entry.modified)
+            .unwrap_or_default()
+            .as_secs();
+        match policy.max_age_days {
+            Some(days) if age > days * DAY => plan.removals.push(Removal {
+                entry,
+                reason: Reason::Age(days),
+            }),
+            _ => plan.kept.push(entry),
+        }
+    }
+
+    if let Some(max) = policy.max_size_mib {
+        let mut size: u64 = plan.kept.iter().map(|entry| entry.size).sum();
+        let oldest = plan
+            .kept
+            .iter()
+            .take_while(|entry| {
+                let over = size > max * MIB;
+                size -= entry.size;
+                over
+            })
+            .count();
+        plan.removals
+            .extend(plan.kept.drain(..oldest).map(|entry| Removal {
+                entry,
+                reason: Reason::Size,
+            }));
+    }
+
+    plan
+}
+
+/// Removes the entries that `plan` removes.
+pub fn remove<S: System>(system: &mut S, plan: &Plan) -> Result<(), RetentionError<S>> {
+    for removal in plan.removals.iter() {
+        for path in removal.entry.paths.iter() {
+            run(system, "rm", &["-rf", path_str(path)])?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entries, plan, Entry, EntryKind, Reason, RetentionPolicy, DAY, MIB};
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+    use crate::Dirs;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn entry(version: u64, days_old: u64, mib: u64) -> Entry {
+        Entry {
+            kind: EntryKind::Install,
+            version,
+            paths: Vec::new(),
+            size: mib * MIB,
+            modified: UNIX_EPOCH + Duration::from_secs((100 - days_old) * DAY),
+        }
+    }
+
+    fn removed(policy: RetentionPolicy) -> Vec<(u64, Reason)> {
+        let now = UNIX_EPOCH + Duration::from_secs(100 * DAY);
+        let entries = vec![entry(1, 60, 10), entry(2, 20, 10), entry(3, 5, 10)];
+        plan(entries, &policy, now)
+            .removals
+            .iter()
+            .map(|removal| (removal.entry.version, removal.reason))
+            .collect()
+    }
+
+    #[test]
+    pub fn plan_removals() {
+        assert_eq!(removed(RetentionPolicy::default()), vec![]);
+        assert_eq!(
+            removed(RetentionPolicy {
+                max_age_days: Some(30),
+                max_size_mib: None,
+            }),
+            vec![(1, Reason::Age(30))]
+        );
+        assert_eq!(
+            removed(RetentionPolicy {
+                max_age_days: None,
+                max_size_mib: Some(15),
+            }),
+            vec![(1, Reason::Size), (2, Reason::Size)]
+        );
+        assert_eq!(
+            removed(RetentionPolicy {
+                max_age_days: Some(30),
+                max_size_mib: Some(10),
+            }),
+            vec![(1, Reason::Age(30)), (2, Reason::Size)]
+        );
+        assert_eq!(
+            removed(RetentionPolicy {
+                max_age_days: None,
+                max_size_mib: Some(30),
+            }),
+            vec![]
+        );
+    }
+
+    #[test]
+    pub fn list_entries() {
+        let base = TempDir::new("retention");
+        let dirs = Dirs::new(&base);
+        let mut system = LocalSystem::new();
+        for dir in [
+            "installed/1",
+            "installed/2",
+            "installed/3",
+            "chroots/1",
+            "backups/_originals/2",
+            "backups/_undone/3",
+        ] {
+            system.make_dir_all(&base.join(dir)).unwrap();
+        }
+        system
+            .put_file_contents(&base.join("installed/1/db"), &[0; 8192])
+            .unwrap();
+
+        let found = entries(&dirs, &mut system, 3, &[]).unwrap();
+        let plan = plan(
+            found.clone(),
+            &RetentionPolicy {
+                max_age_days: None,
+                max_size_mib: Some(0),
+            },
+            SystemTime::now(),
+        );
+        super::remove(&mut system, &plan).unwrap();
+        let left = system.read_dir(&base.join("installed")).unwrap();
+
+        let mut listed = found
+            .iter()
+            .map(|entry| (entry.version, entry.kind, entry.paths.len()))
+            .collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(
+            listed,
+            vec![
+                (1, EntryKind::Install, 2),
+                (2, EntryKind::Install, 1),
+                (2, EntryKind::Originals, 1),
+            ]
+        );
+        let install = found.iter().find(|entry| entry.version == 1).unwrap();
+        assert!(install.size >= 8192);
+        assert!(plan.kept.is_empty());
+        assert_eq!(left, vec!["3".to_string()]);
+    }
+
+    #[test]
+    pub fn keep_originals_in_use() {
+        let base = TempDir::new("retention-originals");
+        let dirs = Dirs::new(&base);
+        let mut system = LocalSystem::new();
+        for dir in ["backups/_originals/1/etc", "backups/_originals/2/etc"] {
+            system.make_dir_all(&base.join(dir)).unwrap();
+        }
+
+        let in_use = vec![dirs.originals_path(1).join("etc/motd")];
+        let found = entries(&dirs, &mut system, 3, &in_use).unwrap();
+
+        assert_eq!(
+            found
+                .iter()
+                .map(|entry| (entry.version, entry.kind))
+                .collect::<Vec<_>>(),
+            vec![(2, EntryKind::Originals)]
+        );
+    }
+}
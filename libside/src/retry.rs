@@ -0,0 +1,131 @@
+//! Retrying requirements that fail for transient reasons, such as apt waiting for the dpkg lock or a service that is restarted while it is still stopping.
+//!
+//! Without a retry, such a failure aborts the entire apply and reverts it.
+//! A requirement kind can declare a default [`RetryPolicy`] with [`Requirement::retry_policy`].
+//! The `[retry]` section of `side.toml` sets a policy for every requirement, and overrides it per requirement kind.
+use crate::requirements::Requirement;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// How often an operation on a requirement is attempted before the apply fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// The number of attempts, including the first. A value of 0 or 1 disables retries.
+    pub max_attempts: u32,
+
+    /// The delay before the second attempt, in milliseconds. The delay doubles with every attempt after that.
+    #[serde(default)]
+    pub backoff_ms: u64,
+}
+
+impl RetryPolicy {
+    /// Attempts the operation only once.
+    pub const NONE: RetryPolicy = RetryPolicy::new(1, 0);
+
+    pub const fn new(max_attempts: u32, backoff_ms: u64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            backoff_ms,
+        }
+    }
+
+    /// Returns true if the operation may be attempted again after `attempt` failed attempts.
+    pub fn allows(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    /// The delay after `attempt` failed attempts, before the next attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::NONE
+    }
+}
+
+/// The `[retry]` section of `side.toml`.
+///
+/// ```toml
+/// [retry]
+/// max_attempts = 2
+/// backoff_ms = 500
+///
+/// [retry.kinds.apt_package]
+/// max_attempts = 10
+/// backoff_ms = 2000
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetrySettings {
+    /// The number of attempts for requirements that do not declare a policy themselves.
+    pub max_attempts: Option<u32>,
+
+    /// The backoff for requirements that do not declare a policy themselves.
+    pub backoff_ms: Option<u64>,
+
+    /// Policies per requirement kind, by the name that `side explain` shows. These override the policy that the requirement declares.
+    #[serde(default)]
+    pub kinds: BTreeMap<String, RetryPolicy>,
+}
+
+impl RetrySettings {
+    /// The policy for `requirement`: the policy of its kind in the settings, then the policy that the requirement declares, then the global settings.
+    pub fn policy_for<R: Requirement>(&self, requirement: &R) -> RetryPolicy {
+        if let Some(policy) = self.kinds.get(requirement.kind()) {
+            return *policy;
+        }
+
+        if let Some(policy) = requirement.retry_policy() {
+            return policy;
+        }
+
+        RetryPolicy::new(
+            self.max_attempts.unwrap_or(RetryPolicy::NONE.max_attempts),
+            self.backoff_ms.unwrap_or(RetryPolicy::NONE.backoff_ms),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryPolicy, RetrySettings};
+    use crate::builder::apt::AptInstall;
+    use crate::builder::fs::CreateDirectory;
+    use crate::requirements::Requirement;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    pub fn backoff() {
+        let policy = RetryPolicy::new(4, 100);
+        assert!(policy.allows(3));
+        assert!(!policy.allows(4));
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+        assert!(!RetryPolicy::NONE.allows(1));
+    }
+
+    #[test]
+    pub fn policy_precedence() {
+        let dir = CreateDirectory::new(PathBuf::from("/srv/app"));
+        let apt = AptInstall::new("nginx");
+
+        let settings = RetrySettings::default();
+        assert_eq!(settings.policy_for(&dir), RetryPolicy::NONE);
+        assert_eq!(settings.policy_for(&apt), apt.retry_policy().unwrap());
+
+        let settings: RetrySettings = toml::from_str(
+            "max_attempts = 2\nbackoff_ms = 50\n[kinds.apt_package]\nmax_attempts = 10\n",
+        )
+        .unwrap();
+        assert_eq!(settings.policy_for(&dir), RetryPolicy::new(2, 50));
+        assert_eq!(settings.policy_for(&apt), RetryPolicy::new(10, 0));
+    }
+}
@@ -0,0 +1,795 @@
+//! Reviewing the changes of an apply, or the results of a verification, before acting on them.
+//!
+//! `side build --review` and `side apply --review` show every requirement of the apply grouped by package, and only continue once the changes are approved.
+//! `side verify --review` shows every requirement of the current install with its verification result. With `--fix`, approving the review fixes the install.
+//!
+//! With the `tui` feature, the review is an interactive view with a tree of packages and requirements, and a preview of the selected requirement.
+//! Without it, or when stdin or stdout is not a terminal, the changes are printed and approved by typing `yes`.
+use crate::graph::{Applied, ApplySequence, ChangeKind, Graph, VerificationState};
+use crate::requirements::Requirement;
+use crate::system::System;
+use std::fmt::Display;
+use std::io::BufRead;
+use std::path::Path;
+
+#[cfg(feature = "tui")]
+mod terminal;
+
+/// The packages of requirements that were not added by a package, such as the requirements of older installs.
+const UNKNOWN_PACKAGE: &str = "<unknown>";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Undo,
+    Create,
+    Update,
+    Unchanged,
+    Ok,
+    Corrupted,
+}
+
+impl Status {
+    /// Returns true if the operator should look at requirements with this status.
+    pub fn needs_attention(&self) -> bool {
+        !matches!(self, Status::Unchanged | Status::Ok)
+    }
+}
+
+impl From<ChangeKind> for Status {
+    fn from(kind: ChangeKind) -> Self {
+        match kind {
+            ChangeKind::Undo => Status::Undo,
+            ChangeKind::Create => Status::Create,
+            ChangeKind::Update => Status::Update,
+            ChangeKind::Unchanged => Status::Unchanged,
+        }
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Status::Undo => "undo",
+            Status::Create => "create",
+            Status::Update => "update",
+            Status::Unchanged => "unchanged",
+            Status::Ok => "ok",
+            Status::Corrupted => "corrupted",
+        })
+    }
+}
+
+/// A single requirement in a [`Review`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReviewItem {
+    pub package: String,
+    pub status: Status,
+    pub requirement: String,
+
+    /// The lines that are shown when the requirement is selected.
+    /// For changes, this is a diff of the requirement; for verification results, a description of the corruption.
+    pub preview: Vec<String>,
+}
+
+/// The requirements of an apply or a verification, and what approving them does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Review {
+    title: String,
+    items: Vec<ReviewItem>,
+
+    /// What approving the review does, such as `apply`. `None` if there is nothing to approve.
+    action: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Approve,
+    Abort,
+}
+
+impl Review {
+    /// Reviews the changes that running `sequence` would make.
+    pub fn plan<R: Requirement>(title: &str, sequence: &ApplySequence<R>) -> Review {
+        let items = sequence
+            .changes()
+            .into_iter()
+            .map(|change| {
+                let status = Status::from(change.kind);
+                let json = pretty(change.requirement);
+                let preview = match (status, change.previous) {
+                    (Status::Update, Some(previous)) => diff_lines(&pretty(previous), &json),
+                    (Status::Create, _) | (Status::Update, None) => prefix_lines("+ ", &json),
+                    (Status::Undo, _) => prefix_lines("- ", &json),
+                    _ => prefix_lines("  ", &json),
+                };
+
+                ReviewItem {
+                    package: change.package.unwrap_or(UNKNOWN_PACKAGE).to_owned(),
+                    status,
+                    requirement: change.requirement.to_string(),
+                    preview,
+                }
+            })
+            .collect();
+
+        Review {
+            title: title.to_owned(),
+            items,
+            action: Some(String::from("apply")),
+        }
+    }
+
+    /// Reviews the result of verifying `graph`. The corrupted requirements are compared with the system, to show how they differ.
+    /// If `fix` is set, approving the review fixes the corrupted requirements.
+    pub fn verification<R: Requirement, S: System>(
+        title: &str,
+        graph: &Graph<R, Applied>,
+        state: &VerificationState<R>,
+        system: &mut S,
+        fix: bool,
+    ) -> Review {
+        let invalid = match state {
+            VerificationState::Ok => &[][..],
+            VerificationState::Invalid { invalid, .. } => &invalid[..],
+        };
+        let items = graph
+            .requirements()
+            .zip(graph.packages())
+            .map(|(requirement, package)| {
+                let corrupted = invalid.iter().any(|r| std::ptr::eq(*r, requirement));
+                let mut preview = Vec::new();
+                if corrupted {
+                    preview.push(format!(
+                        "corrupted: {}",
+                        requirement
+                            .diff(system)
+                            .unwrap_or_else(|| String::from("the difference is unknown"))
+                    ));
+                    preview.push(String::new());
+                }
+
+                preview.extend(prefix_lines("  ", &pretty(requirement)));
+                ReviewItem {
+                    package: package.unwrap_or(UNKNOWN_PACKAGE).to_owned(),
+                    status: if corrupted {
+                        Status::Corrupted
+                    } else {
+                        Status::Ok
+                    },
+                    requirement: requirement.to_string(),
+                    preview,
+                }
+            })
+            .collect();
+
+        Review {
+            title: title.to_owned(),
+            items,
+            action: if fix && !invalid.is_empty() {
+                Some(String::from("fix"))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn items(&self) -> &[ReviewItem] {
+        &self.items
+    }
+
+    pub fn action(&self) -> Option<&str> {
+        self.action.as_deref()
+    }
+
+    /// Groups the items by package, in the order in which the packages first occur.
+    pub fn packages(&self) -> Vec<(&str, Vec<usize>)> {
+        let mut packages: Vec<(&str, Vec<usize>)> = Vec::new();
+        for (index, item) in self.items.iter().enumerate() {
+            match packages
+                .iter_mut()
+                .find(|(package, _)| *package == item.package)
+            {
+                Some((_, items)) => items.push(index),
+                None => packages.push((&item.package, vec![index])),
+            }
+        }
+
+        packages
+    }
+
+    /// Counts the items with each status, for example `2 create, 1 undo, 40 unchanged`.
+    pub fn summary(&self) -> String {
+        summarize(self.items.iter())
+    }
+}
+
+fn summarize<'a>(items: impl Iterator<Item = &'a ReviewItem>) -> String {
+    let mut counts: Vec<(Status, usize)> = Vec::new();
+    for item in items {
+        match counts.iter_mut().find(|(status, _)| *status == item.status) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((item.status, 1)),
+        }
+    }
+
+    counts.sort_by_key(|(status, _)| !status.needs_attention());
+    counts
+        .iter()
+        .map(|(status, count)| format!("{} {}", count, status))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Prints the requirements that need attention with their preview, grouped by package.
+impl Display for Review {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}: {}", self.title, self.summary())?;
+        for (package, items) in self.packages() {
+            let items = items
+                .iter()
+                .map(|&index| &self.items[index])
+                .filter(|item| item.status.needs_attention())
+                .collect::<Vec<_>>();
+            if items.is_empty() {
+                continue;
+            }
+
+            writeln!(f, "{}", package)?;
+            for item in items {
+                writeln!(f, "  {}: {}", item.status, item.requirement)?;
+                for line in item.preview.iter() {
+                    writeln!(f, "      {}", line)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A row in the tree of a review: either a package, or a requirement of an expanded package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Row {
+    Package(usize),
+    Item(usize, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Toggle,
+    PreviewUp,
+    PreviewDown,
+    Approve,
+    Abort,
+}
+
+/// The state of the interactive view of a review: which packages are expanded and which row is selected.
+/// Packages that contain requirements that need attention start out expanded.
+pub struct ReviewState<'a> {
+    review: &'a Review,
+    packages: Vec<(&'a str, Vec<usize>)>,
+    expanded: Vec<bool>,
+    cursor: usize,
+    preview_scroll: usize,
+}
+
+impl<'a> ReviewState<'a> {
+    pub fn new(review: &'a Review) -> Self {
+        let packages = review.packages();
+        let expanded = packages
+            .iter()
+            .map(|(_, items)| {
+                items
+                    .iter()
+                    .any(|&index| review.items[index].status.needs_attention())
+            })
+            .collect();
+
+        ReviewState {
+            review,
+            packages,
+            expanded,
+            cursor: 0,
+            preview_scroll: 0,
+        }
+    }
+
+    pub fn review(&self) -> &'a Review {
+        self.review
+    }
+
+    /// The rows of the tree, in the order in which they are shown.
+    pub fn rows(&self) -> Vec<Row> {
+        let mut rows = Vec::new();
+        for (package, (_, items)) in self.packages.iter().enumerate() {
+            rows.push(Row::Package(package));
+            if self.expanded[package] {
+                rows.extend(items.iter().map(|&item| Row::Item(package, item)));
+            }
+        }
+
+        rows
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn preview_scroll(&self) -> usize {
+        self.preview_scroll
+    }
+
+    pub fn is_expanded(&self, package: usize) -> bool {
+        self.expanded[package]
+    }
+
+    pub fn package_name(&self, package: usize) -> &'a str {
+        self.packages[package].0
+    }
+
+    /// Summarizes the items of `package`, like [`Review::summary`].
+    pub fn package_summary(&self, package: usize) -> String {
+        summarize(
+            self.packages[package]
+                .1
+                .iter()
+                .map(|&index| &self.review.items[index]),
+        )
+    }
+
+    /// The preview of the selected row.
+    pub fn preview(&self) -> Vec<String> {
+        match self.rows().get(self.cursor) {
+            Some(Row::Item(_, item)) => {
+                let item = &self.review.items[*item];
+                let mut lines = vec![
+                    format!("{}: {}", item.status, item.requirement),
+                    String::new(),
+                ];
+                lines.extend(item.preview.iter().cloned());
+                lines
+            }
+            Some(Row::Package(package)) => {
+                let mut lines = vec![
+                    self.package_name(*package).to_owned(),
+                    self.package_summary(*package),
+                    String::new(),
+                ];
+                lines.extend(
+                    self.packages[*package]
+                        .1
+                        .iter()
+                        .map(|&index| &self.review.items[index])
+                        .map(|item| format!("{:>9} {}", item.status, item.requirement)),
+                );
+                lines
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Handles a key press. `page` is the number of rows that are visible at once.
+    /// Returns the decision once the review is approved or aborted. Reviews without an action can only be aborted.
+    pub fn handle(&mut self, key: Key, page: usize) -> Option<Decision> {
+        let last = self.rows().len().saturating_sub(1);
+        let previous = self.cursor;
+        match key {
+            Key::Up => self.cursor = self.cursor.saturating_sub(1),
+            Key::Down => self.cursor = (self.cursor + 1).min(last),
+            Key::PageUp => self.cursor = self.cursor.saturating_sub(page.max(1)),
+            Key::PageDown => self.cursor = (self.cursor + page.max(1)).min(last),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = last,
+            Key::Toggle => {
+                let package = match self.rows()[self.cursor] {
+                    Row::Package(package) | Row::Item(package, _) => package,
+                };
+                self.expanded[package] = !self.expanded[package];
+                self.cursor = self
+                    .rows()
+                    .iter()
+                    .position(|row| *row == Row::Package(package))
+                    .unwrap_or(0);
+            }
+            Key::PreviewUp => self.preview_scroll = self.preview_scroll.saturating_sub(1),
+            Key::PreviewDown => {
+                self.preview_scroll =
+                    (self.preview_scroll + 1).min(self.preview().len().saturating_sub(1))
+            }
+            Key::Approve if self.review.action.is_some() => return Some(Decision::Approve),
+            Key::Approve => {}
+            Key::Abort => return Some(Decision::Abort),
+        }
+
+        if self.cursor != previous {
+            self.preview_scroll = 0;
+        }
+
+        None
+    }
+}
+
+/// Shows `review` to the operator, and returns whether it was approved.
+/// Reviews without an action are only shown, and are never approved.
+pub fn run(review: &Review) -> Decision {
+    #[cfg(feature = "tui")]
+    if terminal::is_available() {
+        match terminal::run(review) {
+            Ok(decision) => return decision,
+            Err(err) => tracing::warn!("Unable to show the interactive review: {}", err),
+        }
+    }
+
+    print!("{}", review);
+    match review.action() {
+        Some(action) => {
+            println!("Type 'yes' to {}, or anything else to abort", action);
+            let line = std::io::stdin()
+                .lock()
+                .lines()
+                .next()
+                .and_then(Result::ok)
+                .unwrap_or_default();
+            if line.trim() == "yes" {
+                Decision::Approve
+            } else {
+                Decision::Abort
+            }
+        }
+        None => Decision::Abort,
+    }
+}
+
+fn pretty<R: Requirement>(requirement: &R) -> String {
+    serde_json::to_string_pretty(requirement).unwrap()
+}
+
+fn prefix_lines(prefix: &str, text: &str) -> Vec<String> {
+    text.lines()
+        .map(|line| format!("{}{}", prefix, line))
+        .collect()
+}
+
+/// A line-based diff of `old` and `new`, with every line prefixed by `- `, `+ ` or two spaces.
+pub(crate) fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    compare_lines(&old, &new)
+        .into_iter()
+        .map(|(line, text)| format!("{}{}", line.prefix(), text))
+        .collect()
+}
+
+/// The number of unchanged lines that [`unified_diff`] shows around every change.
+const CONTEXT_LINES: usize = 3;
+
+/// The largest number of line pairs that [`unified_diff`] compares, which bounds the memory that it needs.
+const MAX_COMPARISONS: usize = 4_000_000;
+
+/// A unified diff from the `old` to the `new` contents of the file at `path`, in the format of `diff -u`.
+/// Binary contents, and changes too large to compare, are summarized in a single line instead.
+/// Returns an empty string if the contents are equal.
+pub(crate) fn unified_diff(path: &Path, old: &[u8], new: &[u8]) -> String {
+    if old == new {
+        return String::new();
+    }
+
+    let (Some(old_text), Some(new_text)) = (as_text(old), as_text(new)) else {
+        return format!(
+            "Binary file {} differs ({} bytes, will be {} bytes)\n",
+            path.display(),
+            old.len(),
+            new.len()
+        );
+    };
+
+    let old_lines = old_text.split_inclusive('\n').collect::<Vec<_>>();
+    let new_lines = new_text.split_inclusive('\n').collect::<Vec<_>>();
+
+    // Only the lines between the common prefix and suffix need to be compared
+    let prefix = old_lines
+        .iter()
+        .zip(new_lines.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_changed = &old_lines[prefix..old_lines.len() - suffix];
+    let new_changed = &new_lines[prefix..new_lines.len() - suffix];
+    if old_changed.len().saturating_mul(new_changed.len()) > MAX_COMPARISONS {
+        return format!(
+            "File {} has too many changes to show ({} lines, will be {} lines)\n",
+            path.display(),
+            old_lines.len(),
+            new_lines.len()
+        );
+    }
+
+    let mut lines = old_lines[..prefix]
+        .iter()
+        .map(|&text| (Line::Same, text))
+        .collect::<Vec<_>>();
+    lines.extend(compare_lines(old_changed, new_changed));
+    lines.extend(
+        old_lines[old_lines.len() - suffix..]
+            .iter()
+            .map(|&text| (Line::Same, text)),
+    );
+
+    let changes = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, (line, _))| *line != Line::Same)
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    let mut result = format!("--- {}\n+++ {}\n", path.display(), path.display());
+    let mut index = 0;
+    while index < changes.len() {
+        // Changes that are separated by at most twice the context are shown in the same hunk
+        let first = changes[index];
+        let mut last = first;
+        index += 1;
+        while index < changes.len() && changes[index] - last <= 2 * CONTEXT_LINES + 1 {
+            last = changes[index];
+            index += 1;
+        }
+
+        let start = first.saturating_sub(CONTEXT_LINES);
+        let end = (last + CONTEXT_LINES + 1).min(lines.len());
+        let count = |lines: &[(Line, &str)], side: Line| {
+            lines
+                .iter()
+                .filter(|(line, _)| *line == Line::Same || *line == side)
+                .count()
+        };
+        let (old_start, new_start) = (
+            count(&lines[..start], Line::Removed),
+            count(&lines[..start], Line::Added),
+        );
+        let (old_count, new_count) = (
+            count(&lines[start..end], Line::Removed),
+            count(&lines[start..end], Line::Added),
+        );
+        result.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old_start, old_count),
+            hunk_range(new_start, new_count)
+        ));
+
+        for (line, text) in lines[start..end].iter() {
+            result.push(line.marker());
+            match text.strip_suffix('\n') {
+                Some(text) => {
+                    result.push_str(text);
+                    result.push('\n');
+                }
+                None => {
+                    result.push_str(text);
+                    result.push_str("\n\\ No newline at end of file\n");
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// The range of lines of a hunk header. Like `diff -u`, the range of an empty hunk starts at the line before it.
+fn hunk_range(before: usize, count: usize) -> String {
+    match count {
+        0 => format!("{},0", before),
+        1 => format!("{}", before + 1),
+        _ => format!("{},{}", before + 1, count),
+    }
+}
+
+/// Returns `contents` as text, or `None` if it looks like binary data.
+fn as_text(contents: &[u8]) -> Option<&str> {
+    if contents.contains(&0) {
+        None
+    } else {
+        std::str::from_utf8(contents).ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line {
+    Same,
+    Removed,
+    Added,
+}
+
+impl Line {
+    fn prefix(&self) -> &'static str {
+        match self {
+            Line::Same => "  ",
+            Line::Removed => "- ",
+            Line::Added => "+ ",
+        }
+    }
+
+    fn marker(&self) -> char {
+        match self {
+            Line::Same => ' ',
+            Line::Removed => '-',
+            Line::Added => '+',
+        }
+    }
+}
+
+/// Compares `old` and `new` line by line, using their longest common subsequence.
+fn compare_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Line, &'a str)> {
+    // lengths[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut result = Vec::with_capacity(old.len().max(new.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            result.push((Line::Same, old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            result.push((Line::Removed, old[i]));
+            i += 1;
+        } else {
+            result.push((Line::Added, new[j]));
+            j += 1;
+        }
+    }
+
+    result
+}
+#[cfg(test)]
+mod tests {
+    use super::{
+        diff_lines, unified_diff, Decision, Key, Review, ReviewItem, ReviewState, Row, Status,
+    };
+    use std::path::Path;
+
+    fn item(package: &str, status: Status, requirement: &str) -> ReviewItem {
+        ReviewItem {
+            package: package.to_owned(),
+            status,
+            requirement: requirement.to_owned(),
+            preview: vec![format!("+ {}", requirement)],
+        }
+    }
+
+    fn review() -> Review {
+        Review {
+            title: String::from("Build"),
+            items: vec![
+                item("base", Status::Unchanged, "directory /srv"),
+                item("www", Status::Create, "file /etc/nginx/nginx.conf"),
+                item("base", Status::Unchanged, "directory /var/srv"),
+                item("www", Status::Unchanged, "apt package nginx"),
+            ],
+            action: Some(String::from("apply")),
+        }
+    }
+
+    #[test]
+    pub fn diff_requirement_lines() {
+        assert_eq!(
+            diff_lines(
+                "{\n  \"path\": \"/a\",\n  \"mode\": 420\n}",
+                "{\n  \"path\": \"/a\",\n  \"mode\": 384\n}"
+            ),
+            vec![
+                "  {",
+                "    \"path\": \"/a\",",
+                "-   \"mode\": 420",
+                "+   \"mode\": 384",
+                "  }"
+            ]
+        );
+    }
+
+    #[test]
+    pub fn unified_diff_hunks() {
+        let old = (1..=20)
+            .map(|n| format!("line {}\n", n))
+            .collect::<String>();
+        let new = old
+            .replace("line 2\n", "line two\n")
+            .replace("line 5\n", "")
+            .replace("line 18\n", "line 18\nline 18.5\n");
+        let path = Path::new("/etc/side.conf");
+
+        assert_eq!(unified_diff(path, old.as_bytes(), old.as_bytes()), "");
+        assert_eq!(
+            unified_diff(path, old.as_bytes(), new.as_bytes()),
+            "--- /etc/side.conf\n+++ /etc/side.conf\n\
+             @@ -1,8 +1,7 @@\n line 1\n-line 2\n+line two\n line 3\n line 4\n-line 5\n line 6\n line 7\n line 8\n\
+             @@ -16,5 +15,6 @@\n line 16\n line 17\n line 18\n+line 18.5\n line 19\n line 20\n"
+        );
+        assert_eq!(
+            unified_diff(path, b"a\nb", b"a\nc\n"),
+            "--- /etc/side.conf\n+++ /etc/side.conf\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+c\n"
+        );
+        assert_eq!(
+            unified_diff(path, b"a\n", b"\0\x01"),
+            "Binary file /etc/side.conf differs (2 bytes, will be 2 bytes)\n"
+        );
+    }
+
+    #[test]
+    pub fn group_and_summarize() {
+        let review = review();
+
+        assert_eq!(
+            review.packages(),
+            vec![("base", vec![0, 2]), ("www", vec![1, 3])]
+        );
+        assert_eq!(review.summary(), "1 create, 3 unchanged");
+        assert_eq!(
+            review.to_string(),
+            "Build: 1 create, 3 unchanged\nwww\n  create: file /etc/nginx/nginx.conf\n      + file /etc/nginx/nginx.conf\n"
+        );
+    }
+
+    #[test]
+    pub fn navigate_tree() {
+        let review = review();
+        let mut state = ReviewState::new(&review);
+
+        assert_eq!(
+            state.rows(),
+            vec![
+                Row::Package(0),
+                Row::Package(1),
+                Row::Item(1, 1),
+                Row::Item(1, 3)
+            ]
+        );
+
+        assert_eq!(state.handle(Key::Down, 10), None);
+        assert_eq!(state.handle(Key::Down, 10), None);
+        assert_eq!(state.preview()[0], "create: file /etc/nginx/nginx.conf");
+        assert_eq!(state.handle(Key::Toggle, 10), None);
+        assert_eq!(state.rows(), vec![Row::Package(0), Row::Package(1)]);
+        assert_eq!(state.cursor(), 1);
+
+        assert_eq!(state.handle(Key::Up, 10), None);
+        assert_eq!(state.handle(Key::Toggle, 10), None);
+        assert_eq!(state.handle(Key::End, 10), None);
+        assert_eq!(state.rows()[state.cursor()], Row::Package(1));
+        assert_eq!(state.handle(Key::Approve, 10), Some(Decision::Approve));
+    }
+
+    #[test]
+    pub fn reviews_without_action_cannot_be_approved() {
+        let mut review = review();
+        review.action = None;
+        let mut state = ReviewState::new(&review);
+
+        assert_eq!(state.handle(Key::Approve, 10), None);
+        assert_eq!(state.handle(Key::Abort, 10), Some(Decision::Abort));
+    }
+}
@@ -0,0 +1,208 @@
+//! The interactive view of a review, drawn with ANSI escape codes on a terminal in raw mode.
+//!
+//! The tree of packages and requirements is on the left, and the preview of the selected row on the right.
+//! The terminal is restored when the view is closed, also if drawing fails.
+use super::{Decision, Key, ReviewState, Row, Status};
+use crate::review::Review;
+use std::io::{Read, Write};
+
+const HELP: &str = "j/k move  space expand  J/K scroll preview  a approve  q abort";
+
+pub fn is_available() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+/// Puts the terminal in raw mode on the alternate screen, and restores it when dropped.
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    fn enter() -> std::io::Result<RawTerminal> {
+        let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let terminal = RawTerminal { original };
+        let mut stdout = std::io::stdout();
+        stdout.write_all(b"\x1b[?1049h\x1b[?25l")?;
+        stdout.flush()?;
+
+        Ok(terminal)
+    }
+
+    /// The number of columns and rows of the terminal.
+    fn size(&self) -> (usize, usize) {
+        let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0
+            && size.ws_col > 0
+            && size.ws_row > 0
+        {
+            (size.ws_col as usize, size.ws_row as usize)
+        } else {
+            (80, 24)
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(b"\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.original);
+        }
+    }
+}
+
+/// Shows `review` until it is approved or aborted.
+pub fn run(review: &Review) -> std::io::Result<Decision> {
+    let terminal = RawTerminal::enter()?;
+    let mut state = ReviewState::new(review);
+    let mut scroll = 0;
+    let mut stdin = std::io::stdin();
+    loop {
+        let (width, height) = terminal.size();
+        let page = height.saturating_sub(3).max(1);
+        if state.cursor() < scroll {
+            scroll = state.cursor();
+        } else if state.cursor() >= scroll + page {
+            scroll = state.cursor() + 1 - page;
+        }
+
+        let screen = draw(&state, scroll, width, height);
+        let mut stdout = std::io::stdout();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()?;
+
+        let mut buf = [0u8; 8];
+        let len = stdin.read(&mut buf)?;
+        if len == 0 {
+            return Ok(Decision::Abort);
+        }
+
+        if let Some(key) = parse_key(&buf[..len]) {
+            if let Some(decision) = state.handle(key, page) {
+                return Ok(decision);
+            }
+        }
+    }
+}
+
+fn parse_key(input: &[u8]) -> Option<Key> {
+    Some(match input {
+        b"k" | b"\x1b[A" | b"\x1bOA" => Key::Up,
+        b"j" | b"\x1b[B" | b"\x1bOB" => Key::Down,
+        b"\x1b[5~" => Key::PageUp,
+        b"\x1b[6~" => Key::PageDown,
+        b"g" | b"\x1b[H" | b"\x1b[1~" => Key::Home,
+        b"G" | b"\x1b[F" | b"\x1b[4~" => Key::End,
+        b" " | b"\r" | b"\n" => Key::Toggle,
+        b"K" => Key::PreviewUp,
+        b"J" => Key::PreviewDown,
+        b"a" => Key::Approve,
+        b"q" | b"\x1b" | b"\x03" => Key::Abort,
+        _ => return None,
+    })
+}
+
+fn color(status: Status) -> &'static str {
+    match status {
+        Status::Create => "\x1b[32m",
+        Status::Update => "\x1b[33m",
+        Status::Undo | Status::Corrupted => "\x1b[31m",
+        Status::Unchanged | Status::Ok => "\x1b[2m",
+    }
+}
+
+/// Pads or truncates `text` to exactly `width` characters.
+fn fit(text: &str, width: usize) -> String {
+    let mut result = text.chars().take(width).collect::<String>();
+    let len = result.chars().count();
+    result.push_str(&" ".repeat(width - len));
+    result
+}
+
+fn draw(state: &ReviewState, scroll: usize, width: usize, height: usize) -> String {
+    let review = state.review();
+    let tree_width = (width * 2 / 5).max(20).min(width.saturating_sub(10));
+    let preview_width = width.saturating_sub(tree_width + 3);
+    let body = height.saturating_sub(3);
+    let rows = state.rows();
+    let preview = state.preview();
+
+    let mut screen = String::from("\x1b[H\x1b[2J");
+    screen.push_str("\x1b[1m");
+    screen.push_str(&fit(
+        &format!("{}: {}", review.title(), review.summary()),
+        width,
+    ));
+    screen.push_str("\x1b[0m\r\n");
+
+    for line in 0..body {
+        let index = scroll + line;
+        let (tree, tree_color) = match rows.get(index) {
+            Some(Row::Package(package)) => (
+                format!(
+                    "{} {} ({})",
+                    if state.is_expanded(*package) {
+                        "▾"
+                    } else {
+                        "▸"
+                    },
+                    state.package_name(*package),
+                    state.package_summary(*package)
+                ),
+                "\x1b[1m",
+            ),
+            Some(Row::Item(_, item)) => {
+                let item = &review.items()[*item];
+                (
+                    format!("    {:<9} {}", item.status, item.requirement),
+                    color(item.status),
+                )
+            }
+            None => (String::new(), ""),
+        };
+
+        if index == state.cursor() {
+            screen.push_str("\x1b[7m");
+        }
+        screen.push_str(tree_color);
+        screen.push_str(&fit(&tree, tree_width));
+        screen.push_str("\x1b[0m │ ");
+
+        if let Some(text) = preview.get(state.preview_scroll() + line) {
+            let preview_color = match text.chars().next() {
+                Some('+') => "\x1b[32m",
+                Some('-') => "\x1b[31m",
+                _ => "",
+            };
+            screen.push_str(preview_color);
+            screen.push_str(&fit(text, preview_width));
+            screen.push_str("\x1b[0m");
+        }
+        screen.push_str("\r\n");
+    }
+
+    let help = match review.action() {
+        Some(action) => HELP.replace("approve", action),
+        None => HELP.replace("  a approve", ""),
+    };
+    screen.push_str("\x1b[2m");
+    screen.push_str(&fit(&help, width));
+    screen.push_str("\x1b[0m");
+
+    screen
+}
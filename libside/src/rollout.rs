@@ -0,0 +1,152 @@
+//! Staged rollouts, which apply an install only up to named checkpoints, for `side apply --until`.
+//!
+//! Builders mark checkpoints with [`Context::checkpoint`](crate::builder::Context::checkpoint).
+//! While a rollout is in progress, the current install stays the install that the rollout started from, and `rollout.json` in the base directory records which checkpoints of the new install have been applied.
+//! The requirements that hold on the system are then determined by [`Rollout::state`].
+//! The record is removed as soon as another install becomes current.
+use crate::graph::{Applied, Graph};
+use crate::requirements::Requirement;
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RolloutError<S: System> {
+    #[error("unable to read {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("invalid rollout in {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, serde_json::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+
+    #[error("install {} has no checkpoint named {:?}, it has: {}", .install, .name, .available.join(", "))]
+    UnknownCheckpoint {
+        install: u64,
+        name: String,
+        available: Vec<String>,
+    },
+
+    #[error("{}; apply install {} completely, or apply install {} to abandon the rollout first", .0, .0.to, .0.from)]
+    InProgress(Rollout),
+}
+
+/// An install that has been applied up to some of its checkpoints.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rollout {
+    /// The install that was current when the rollout started, and still is.
+    pub from: u64,
+
+    /// The install that is being rolled out.
+    pub to: u64,
+
+    /// The checkpoints of install `to` that have been applied, in the order in which they were applied.
+    pub checkpoints: Vec<String>,
+}
+
+impl Rollout {
+    /// Loads the rollout in progress, if any.
+    pub fn load<S: System>(path: &Path, system: &S) -> Result<Option<Rollout>, RolloutError<S>> {
+        let exists = system
+            .path_exists(path)
+            .map_err(|e| RolloutError::UnableToRead(path.to_owned(), e))?;
+        if !exists {
+            return Ok(None);
+        }
+
+        let contents = system
+            .file_contents(path)
+            .map_err(|e| RolloutError::UnableToRead(path.to_owned(), e))?;
+        serde_json::from_slice(&contents)
+            .map(Some)
+            .map_err(|e| RolloutError::Invalid(path.to_owned(), e))
+    }
+
+    pub fn save<S: System>(&self, path: &Path, system: &mut S) -> Result<(), RolloutError<S>> {
+        system
+            .put_file_contents(path, &serde_json::to_vec_pretty(self).unwrap())
+            .map_err(|e| RolloutError::UnableToWrite(path.to_owned(), e))
+    }
+
+    /// Returns the same rollout with `checkpoint` applied as well.
+    pub fn with_checkpoint(mut self, checkpoint: &str) -> Rollout {
+        if !self.checkpoints.iter().any(|c| c == checkpoint) {
+            self.checkpoints.push(checkpoint.to_owned());
+        }
+
+        self
+    }
+
+    /// The requirements that hold on the system during the rollout, given the graph of install `from` and the graph of install `to`.
+    pub fn state<R: Requirement, S: System>(
+        &self,
+        from: &Graph<R, Applied>,
+        to: &Graph<R, Applied>,
+    ) -> Result<Graph<R, Applied>, RolloutError<S>> {
+        to.up_to_checkpoints(from, &self.checkpoints)
+            .map_err(|name| RolloutError::UnknownCheckpoint {
+                install: self.to,
+                name,
+                available: to.checkpoints().map(str::to_owned).collect(),
+            })
+    }
+}
+
+impl Display for Rollout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "install {} is applied over install {} up to checkpoint {}",
+            self.to,
+            self.from,
+            self.checkpoints.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rollout, RolloutError};
+    use crate::builder::fs::CreateDirectory;
+    use crate::graph::{Applied, Graph};
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+
+    #[test]
+    pub fn save_and_extend() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("rollout");
+        let path = dir.join("rollout.json");
+        assert_eq!(Rollout::load(&path, &system).unwrap(), None);
+
+        let rollout = Rollout {
+            from: 3,
+            to: 4,
+            checkpoints: vec![String::from("infra")],
+        }
+        .with_checkpoint("sites")
+        .with_checkpoint("infra");
+        assert_eq!(rollout.checkpoints, vec!["infra", "sites"]);
+        assert_eq!(
+            rollout.to_string(),
+            "install 4 is applied over install 3 up to checkpoint infra, sites"
+        );
+
+        rollout.save(&path, &mut system).unwrap();
+        assert_eq!(
+            Rollout::load(&path, &system).unwrap(),
+            Some(rollout.clone())
+        );
+
+        let empty = Graph::<CreateDirectory, Applied>::new();
+        match rollout.state::<_, LocalSystem>(&empty, &empty) {
+            Err(RolloutError::UnknownCheckpoint { install, name, .. }) => {
+                assert_eq!(install, 4);
+                assert_eq!(name, "infra");
+            }
+            other => panic!("unexpected result: {:?}", other.map(|g| g.len())),
+        }
+    }
+}
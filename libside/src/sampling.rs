@@ -0,0 +1,149 @@
+//! Verifying part of an install, for quick periodic checks with `side verify --sample` and `side verify --changed-since`.
+//!
+//! A sample contains the same share of the requirements of every kind, so that kinds with few requirements are checked as well.
+//! It is chosen with a seeded random number generator: verifying with the same seed checks the same requirements again.
+use crate::graph::Graph;
+use crate::requirements::Requirement;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// The share of the requirements to verify, such as `10%`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleSize {
+    percent: f64,
+}
+
+impl SampleSize {
+    pub fn percent(&self) -> f64 {
+        self.percent
+    }
+
+    /// The number of requirements to verify out of `total`. At least one requirement is verified if there are any.
+    fn of(&self, total: usize) -> usize {
+        ((total as f64 * self.percent / 100.0).ceil() as usize).clamp(total.min(1), total)
+    }
+}
+
+impl FromStr for SampleSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_end_matches('%').parse::<f64>() {
+            Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(SampleSize { percent }),
+            _ => Err(format!(
+                "invalid sample size {:?}, expected a percentage between 0% and 100% such as '10%'",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for SampleSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}%", self.percent)
+    }
+}
+
+/// Selects `size` of the requirements of every kind at random, out of the requirements of `graph` for which `candidates` is true.
+/// Returns whether each requirement of `graph` was selected.
+pub fn stratified_sample<R: Requirement, State: Default + Copy>(
+    graph: &Graph<R, State>,
+    candidates: &[bool],
+    size: SampleSize,
+    seed: u64,
+) -> Vec<bool> {
+    let mut kinds = BTreeMap::<&'static str, Vec<usize>>::new();
+    for (index, requirement) in graph.requirements().enumerate() {
+        if candidates[index] {
+            kinds.entry(requirement.kind()).or_default().push(index);
+        }
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut selected = vec![false; graph.len()];
+    for indices in kinds.values_mut() {
+        let amount = size.of(indices.len());
+        let (sample, _) = indices.partial_shuffle(&mut rng, amount);
+        for &index in sample.iter() {
+            selected[index] = true;
+        }
+    }
+
+    selected
+}
+
+/// Returns whether each requirement of `new` was added or modified since `old`, i.e. whether `old` contains no identical requirement.
+pub fn changed_since<R: Requirement, A: Default + Copy, B: Default + Copy>(
+    old: &Graph<R, A>,
+    new: &Graph<R, B>,
+) -> Vec<bool> {
+    let old = old
+        .requirements()
+        .map(|r| serde_json::to_string(r).unwrap())
+        .collect::<HashSet<_>>();
+    new.requirements()
+        .map(|r| !old.contains(&serde_json::to_string(r).unwrap()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_since, stratified_sample, SampleSize};
+    use crate::builder::fs::{CreateDirectory, FileWithContents, Sha3};
+    use crate::graph::{Graph, Pending};
+    use crate::requirements::Requirement;
+    use std::path::PathBuf;
+
+    crate::requirements!(R = CreateDirectory, FileWithContents);
+
+    fn file(to: &str, contents: &[u8]) -> FileWithContents {
+        FileWithContents::new(
+            PathBuf::from("/srv/files/config").join(to.trim_start_matches('/')),
+            PathBuf::from(to),
+            Sha3::hash(contents),
+        )
+    }
+
+    #[test]
+    pub fn parse_sample_size() {
+        assert_eq!("10%".parse::<SampleSize>().unwrap().percent(), 10.0);
+        assert_eq!("2.5".parse::<SampleSize>().unwrap().percent(), 2.5);
+        assert!("0%".parse::<SampleSize>().is_err());
+        assert!("150%".parse::<SampleSize>().is_err());
+        assert!("ten".parse::<SampleSize>().is_err());
+    }
+
+    #[test]
+    pub fn sample_every_kind() {
+        let mut old = Graph::<R, Pending>::new();
+        let dir = old.add(CreateDirectory::new(PathBuf::from("/etc/app")), &[]);
+        for n in 0..20 {
+            old.add(file(&format!("/etc/app/{}.conf", n), b"a = 1\n"), &[dir]);
+        }
+
+        let all = vec![true; old.len()];
+        let size = "10%".parse().unwrap();
+        let sample = stratified_sample(&old, &all, size, 42);
+        assert!(sample[0], "the only directory must be sampled");
+        assert_eq!(sample.iter().filter(|&&s| s).count(), 3);
+        assert_eq!(stratified_sample(&old, &all, size, 42), sample);
+
+        let mut new = Graph::<R, Pending>::new();
+        let dir = new.add(CreateDirectory::new(PathBuf::from("/etc/app")), &[]);
+        new.add(file("/etc/app/0.conf", b"a = 2\n"), &[dir]);
+        new.add(file("/etc/app/1.conf", b"a = 1\n"), &[dir]);
+        new.add(file("/etc/app/new.conf", b"a = 1\n"), &[dir]);
+
+        let changed = changed_since(&old, &new);
+        assert_eq!(changed, vec![false, true, false, true]);
+
+        let sample = stratified_sample(&new, &changed, "100%".parse().unwrap(), 7);
+        assert_eq!(sample, changed);
+        assert!(new
+            .requirements()
+            .zip(sample)
+            .all(|(r, s)| !s || r.kind() == "file_with_contents"));
+    }
+}
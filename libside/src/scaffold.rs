@@ -0,0 +1,209 @@
+//! Scaffolding for new packages, for `side new-package`.
+//!
+//! The files of a new package come from [`crate::builder::Builder::package_template`], because only the builder knows its package configuration.
+//! Before anything is written, the generated `package.toml` is loaded as the configuration of the builder, so a template that does not match the configuration is never written.
+use crate::builder::{is_reserved_package_name, PackageConfig};
+use crate::system::System;
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+use std::path::{Component, Path, PathBuf};
+
+/// The kinds of packages that `side new-package` can create.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PackageTemplate {
+    /// A website served by nginx, optionally with PHP and a database
+    Www,
+
+    /// An executable that runs as a service
+    Binary,
+
+    /// Backups of the data of the other packages
+    Backup,
+}
+
+impl Display for PackageTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PackageTemplate::Www => "www",
+            PackageTemplate::Binary => "binary",
+            PackageTemplate::Backup => "backup",
+        })
+    }
+}
+
+/// A file of a new package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScaffoldFile {
+    /// The path of the file, relative to the package directory.
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+impl ScaffoldFile {
+    pub fn new(path: impl Into<PathBuf>, contents: impl Into<String>) -> ScaffoldFile {
+        ScaffoldFile {
+            path: path.into(),
+            contents: contents.into(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScaffoldError<S: System> {
+    #[error("{:?} is not a valid package name", .0)]
+    InvalidName(String),
+
+    #[error("{:?} is reserved and cannot be used as a package name", .0)]
+    ReservedName(String),
+
+    #[error("the builder has no {} template", .0)]
+    UnsupportedTemplate(PackageTemplate),
+
+    #[error("the template does not contain a package.toml")]
+    MissingConfig,
+
+    #[error("the template contains a file outside of the package: {}", .0.display())]
+    InvalidPath(PathBuf),
+
+    #[error("the package.toml of the template does not match the package configuration: {}", .0)]
+    InvalidConfig(toml::de::Error),
+
+    #[error("{} already exists", .0.display())]
+    AlreadyExists(PathBuf),
+
+    #[error("unable to check {}: {}", .0.display(), .1)]
+    UnableToCheck(PathBuf, S::Error),
+
+    #[error("unable to write {}: {}", .0.display(), .1)]
+    UnableToWrite(PathBuf, S::Error),
+}
+
+/// Checks that `name` can be used as the name of a package, which is also the name of its directory.
+pub fn check_name<S: System>(name: &str) -> Result<(), ScaffoldError<S>> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_');
+    if !valid {
+        Err(ScaffoldError::InvalidName(name.to_owned()))
+    } else if is_reserved_package_name(name) {
+        Err(ScaffoldError::ReservedName(name.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Writes `files` to a new package directory named `name` in `packages`, and returns the path of that directory.
+/// The `package.toml` in `files` must be a valid configuration for packages with configuration `C`.
+pub fn create_package<S: System, C: DeserializeOwned>(
+    system: &mut S,
+    packages: &Path,
+    name: &str,
+    files: &[ScaffoldFile],
+) -> Result<PathBuf, ScaffoldError<S>> {
+    check_name(name)?;
+
+    if let Some(file) = files.iter().find(|file| {
+        !file
+            .path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    }) {
+        return Err(ScaffoldError::InvalidPath(file.path.clone()));
+    }
+
+    let config = files
+        .iter()
+        .find(|file| file.path == Path::new("package.toml"))
+        .ok_or(ScaffoldError::MissingConfig)?;
+    toml::from_str::<PackageConfig<C>>(&config.contents).map_err(ScaffoldError::InvalidConfig)?;
+
+    let path = packages.join(name);
+    if system
+        .path_exists(&path)
+        .map_err(|e| ScaffoldError::UnableToCheck(path.clone(), e))?
+    {
+        return Err(ScaffoldError::AlreadyExists(path));
+    }
+
+    for file in files {
+        let target = path.join(&file.path);
+        let parent = target.parent().unwrap();
+        system
+            .make_dir_all(parent)
+            .map_err(|e| ScaffoldError::UnableToWrite(parent.to_owned(), e))?;
+        system
+            .put_file_contents(&target, file.contents.as_bytes())
+            .map_err(|e| ScaffoldError::UnableToWrite(target.clone(), e))?;
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_name, create_package, ScaffoldError, ScaffoldFile};
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Config {
+        #[serde(rename = "www")]
+        Www { hostname: String },
+    }
+
+    #[test]
+    pub fn package_names() {
+        assert!(check_name::<LocalSystem>("shell.test").is_ok());
+        assert!(check_name::<LocalSystem>("my-app_2").is_ok());
+        assert!(matches!(
+            check_name::<LocalSystem>("../etc"),
+            Err(ScaffoldError::InvalidName(_))
+        ));
+        assert!(matches!(
+            check_name::<LocalSystem>(""),
+            Err(ScaffoldError::InvalidName(_))
+        ));
+        assert!(matches!(
+            check_name::<LocalSystem>(crate::ORIGINALS),
+            Err(ScaffoldError::ReservedName(_))
+        ));
+    }
+
+    #[test]
+    pub fn scaffold_package() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("scaffold");
+        let packages = dir.join("packages");
+        let files = vec![
+            ScaffoldFile::new("package.toml", "[www]\nhostname = \"example.test\"\n"),
+            ScaffoldFile::new("www/index.html", "<h1>Hello</h1>\n"),
+        ];
+
+        let path =
+            create_package::<_, Config>(&mut system, &packages, "example.test", &files).unwrap();
+        assert_eq!(
+            system.file_contents(&path.join("www/index.html")).unwrap(),
+            b"<h1>Hello</h1>\n"
+        );
+        assert!(matches!(
+            create_package::<_, Config>(&mut system, &packages, "example.test", &files),
+            Err(ScaffoldError::AlreadyExists(_))
+        ));
+
+        let invalid = vec![ScaffoldFile::new("package.toml", "[binary]\n")];
+        assert!(matches!(
+            create_package::<_, Config>(&mut system, &packages, "other", &invalid),
+            Err(ScaffoldError::InvalidConfig(_))
+        ));
+        let outside = vec![ScaffoldFile::new("../package.toml", "")];
+        assert!(matches!(
+            create_package::<_, Config>(&mut system, &packages, "other", &outside),
+            Err(ScaffoldError::InvalidPath(_))
+        ));
+        assert!(!system.path_exists(&packages.join("other")).unwrap());
+    }
+}
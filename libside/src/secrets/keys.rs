@@ -3,7 +3,7 @@ use std::{
     io::{self, Write},
 };
 
-use super::Secret;
+use super::{Credential, Secret};
 use openssl::{bn::BigNumRef, rsa::Rsa};
 use serde::{Deserialize, Serialize};
 
@@ -43,6 +43,13 @@ impl<const BITS: u32> Secret for AsymmetricKey<BITS> {
     }
 }
 
+impl<const BITS: u32> Credential for AsymmetricKey<BITS> {
+    /// The private key, in PEM format.
+    fn credential_data(&self) -> Vec<u8> {
+        self.private.as_bytes().to_vec()
+    }
+}
+
 fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
     w.write_all(&v.to_be_bytes())?;
 
@@ -1,9 +1,10 @@
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
     collections::{HashMap, HashSet},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
+use crate::builder::credentials::decrypt;
 use crate::system::System;
 
 pub mod keys;
@@ -38,6 +39,10 @@ pub struct SecretData(Vec<u8>);
 pub struct Secrets {
     secrets: HashMap<InternalSecretId, SecretData>,
     new_secrets: HashSet<InternalSecretId>,
+    credentials: HashMap<SecretId, Vec<u8>>,
+
+    /// The credentials that are encrypted, with the paths of their encrypted files.
+    encrypted: HashMap<SecretId, PathBuf>,
 }
 
 pub trait Secret: Serialize + DeserializeOwned + Clone {
@@ -46,11 +51,25 @@ pub trait Secret: Serialize + DeserializeOwned + Clone {
     fn generate_new() -> Self;
 }
 
+/// A secret that can be delivered to a service as a systemd credential.
+/// The service reads [`Credential::credential_data`] from a file in `$CREDENTIALS_DIRECTORY`, so the secret never has to be written to the config tree.
+pub trait Credential: Secret {
+    /// The contents of the credential file.
+    fn credential_data(&self) -> Vec<u8>;
+}
+
+/// The path of the credential file of `id` in the credentials directory `path`.
+pub fn credential_path(path: &Path, id: &SecretId) -> PathBuf {
+    path.join(&id.package).join(&id.name)
+}
+
 impl Secrets {
     pub fn load<S: System>(path: &Path, system: &mut S) -> Result<Secrets, S::Error> {
         let mut result = Secrets {
             secrets: HashMap::new(),
             new_secrets: HashSet::new(),
+            credentials: HashMap::new(),
+            encrypted: HashMap::new(),
         };
 
         for package_dir in system.read_dir(path)? {
@@ -81,7 +100,7 @@ impl Secrets {
                         kind,
                     };
 
-                    println!("  secret loaded: {}", internal_id);
+                    tracing::debug!("  secret loaded: {}", internal_id);
 
                     result
                         .secrets
@@ -110,6 +129,48 @@ impl Secrets {
         Ok(())
     }
 
+    /// Writes the credential files of the credentials that were requested with [`Secrets::credential`] to the credentials directory `path`.
+    /// Only root can read the files. Files whose contents have not changed are not written again.
+    ///
+    /// The credential file of an encrypted credential is only written if its encrypted file does not contain the secret yet,
+    /// because the credential file is removed once it has been encrypted. A leftover credential file is removed.
+    pub fn save_credentials<S: System>(
+        &mut self,
+        path: &Path,
+        system: &mut S,
+    ) -> Result<(), S::Error> {
+        system.make_dir_all(path)?;
+        system.chmod(path, 0o700)?;
+
+        for (id, data) in self.credentials.iter() {
+            let file = credential_path(path, id);
+            if let Some(target) = self.encrypted.get(id) {
+                if system.path_exists(target)?
+                    && decrypt(system, &id.name, target).as_ref() == Some(data)
+                {
+                    if system.path_exists(&file)? {
+                        system.remove_file(&file)?;
+                    }
+
+                    continue;
+                }
+            }
+
+            if system.path_exists(&file)? && &system.file_contents(&file)? == data {
+                continue;
+            }
+
+            let dir = file.parent().unwrap();
+            system.make_dir_all(dir)?;
+            system.chmod(dir, 0o700)?;
+
+            system.put_file_contents(&file, data)?;
+            system.chmod(&file, 0o600)?;
+        }
+
+        Ok(())
+    }
+
     pub fn get_or_create<S: Secret + std::fmt::Debug>(
         &mut self,
         id: SecretId,
@@ -129,9 +190,69 @@ impl Secrets {
             );
             self.new_secrets.insert(internal_id.clone());
 
-            println!("  secret generated: {} = {:?}", internal_id, new_secret);
+            tracing::info!("  secret generated: {} = {:?}", internal_id, new_secret);
 
             Ok(new_secret)
         }
     }
+
+    /// Like [`Secrets::get_or_create`], but also writes the secret to a credential file when the secrets are saved.
+    /// Marks the credential `id` as encrypted into the file `target`, see [`Secrets::save_credentials`].
+    pub fn encrypt_credential(&mut self, id: SecretId, target: PathBuf) {
+        self.encrypted.insert(id, target);
+    }
+
+    pub fn credential<S: Credential + std::fmt::Debug>(
+        &mut self,
+        id: SecretId,
+    ) -> Result<S, serde_json::Error> {
+        let secret: S = self.get_or_create(id.clone())?;
+        self.credentials.insert(id, secret.credential_data());
+
+        Ok(secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        credential_path,
+        password::{Alphanumeric, Password},
+        SecretId, Secrets,
+    };
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    pub fn save_credentials() {
+        let mut system = LocalSystem::new();
+        let base = TempDir::new("credentials");
+        let secrets_path = base.join("secrets");
+        let credentials_path = base.join("credentials");
+        system.make_dir_all(&secrets_path).unwrap();
+
+        let mut secrets = Secrets::load(&secrets_path, &mut system).unwrap();
+        let id = SecretId::new("app".to_string(), "db-password".to_string());
+        let password: Password<16, Alphanumeric> = secrets.credential(id.clone()).unwrap();
+        secrets.save(&secrets_path, &mut system).unwrap();
+        secrets
+            .save_credentials(&credentials_path, &mut system)
+            .unwrap();
+
+        let file = credential_path(&credentials_path, &id);
+        assert_eq!(
+            system.file_contents(&file).unwrap(),
+            password.get().as_bytes()
+        );
+        assert_eq!(
+            std::fs::metadata(&file).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // The same secret is loaded again, and the credential file is kept
+        let mut secrets = Secrets::load(&secrets_path, &mut system).unwrap();
+        let loaded: Password<16, Alphanumeric> = secrets.credential(id).unwrap();
+        assert_eq!(loaded.get(), password.get());
+    }
 }
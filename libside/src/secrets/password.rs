@@ -2,7 +2,7 @@ use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
 
-use super::Secret;
+use super::{Credential, Secret};
 
 pub struct Alphanumeric;
 
@@ -87,6 +87,12 @@ impl<const LENGTH: usize, K: PasswordKind> Secret for Password<LENGTH, K> {
     }
 }
 
+impl<const LENGTH: usize, K: PasswordKind> Credential for Password<LENGTH, K> {
+    fn credential_data(&self) -> Vec<u8> {
+        self.pass.as_bytes().to_vec()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::secrets::{
@@ -0,0 +1,84 @@
+//! The settings of side itself, read from `side.toml` in the base directory.
+//!
+//! Unlike the configuration of packages, these settings apply to the base directory as a whole.
+//! A base directory without a `side.toml` uses the defaults.
+use crate::notify::NotifySettings;
+use crate::retention::RetentionPolicy;
+use crate::retry::RetrySettings;
+use crate::system::System;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The `[retention]` section, which limits how long backups and old installs are kept.
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+
+    /// The `[retry]` section, which retries requirements that fail for transient reasons.
+    #[serde(default)]
+    pub retry: RetrySettings,
+
+    /// The `[notify]` section, which sends the results of builds, applies and verifications to operators.
+    #[serde(default)]
+    pub notify: NotifySettings,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SettingsError<S: System> {
+    #[error("unable to read the settings from {}: {}", .0.display(), .1)]
+    UnableToRead(PathBuf, S::Error),
+
+    #[error("invalid settings in {}: {}", .0.display(), .1)]
+    Invalid(PathBuf, toml::de::Error),
+}
+
+impl Settings {
+    /// Loads the settings from `path`, or returns the defaults if it does not exist.
+    pub fn load<S: System>(path: &Path, system: &S) -> Result<Settings, SettingsError<S>> {
+        if !system
+            .path_exists(path)
+            .map_err(|e| SettingsError::UnableToRead(path.to_owned(), e))?
+        {
+            return Ok(Settings::default());
+        }
+
+        let contents = system
+            .file_contents(path)
+            .map_err(|e| SettingsError::UnableToRead(path.to_owned(), e))?;
+        toml::from_slice(&contents).map_err(|e| SettingsError::Invalid(path.to_owned(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Settings;
+    use crate::retention::RetentionPolicy;
+    use crate::retry::{RetryPolicy, RetrySettings};
+
+    #[test]
+    pub fn parse_settings() {
+        let settings: Settings =
+            toml::from_str("[retention]\nmax_age_days = 30\nmax_size_mib = 2048\n").unwrap();
+        assert_eq!(
+            settings.retention,
+            RetentionPolicy {
+                max_age_days: Some(30),
+                max_size_mib: Some(2048),
+            }
+        );
+
+        let settings: Settings = toml::from_str("").unwrap();
+        assert!(settings.retention.is_unlimited());
+        assert_eq!(settings.retry, RetrySettings::default());
+
+        let settings: Settings =
+            toml::from_str("[retry]\nmax_attempts = 3\n[retry.kinds.service_status]\nmax_attempts = 5\nbackoff_ms = 250\n").unwrap();
+        assert_eq!(settings.retry.max_attempts, Some(3));
+        assert_eq!(
+            settings.retry.kinds.get("service_status"),
+            Some(&RetryPolicy::new(5, 250))
+        );
+    }
+}
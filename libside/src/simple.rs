@@ -0,0 +1,339 @@
+//! Requirements that wrap shell commands, declared with [`simple_requirement!`](crate::simple_requirement).
+//!
+//! Many resources are managed by a command that creates them, a command that removes them, and a command that succeeds if they exist.
+//! The macro generates the struct and its entire [`Requirement`](crate::requirements::Requirement) implementation from those commands.
+//! Requirements that need to inspect the system in more detail, or that can be modified in place, should still implement the trait by hand.
+use crate::bootstrap;
+use crate::system::System;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SimpleError<S: System> {
+    #[error("unable to execute {}: {}", .0, .1)]
+    FailedToStart(String, S::CommandError),
+
+    #[error("{} failed: {} {}", .0, .1, .2)]
+    Unsuccessful(String, String, String),
+}
+
+/// Runs `command`, where the first element is the program, and fails if the command does not succeed.
+pub fn run<S: System>(system: &mut S, command: &[String]) -> Result<(), SimpleError<S>> {
+    let args = command[1..].iter().map(String::as_str).collect::<Vec<_>>();
+    let result = system
+        .execute_command(&command[0], &args)
+        .map_err(|e| SimpleError::FailedToStart(command[0].clone(), e))?;
+    result.successful().map_err(|(stdout, stderr)| {
+        SimpleError::Unsuccessful(command[0].clone(), stdout.to_owned(), stderr.to_owned())
+    })
+}
+
+/// Runs `command`, and returns whether it succeeded.
+pub fn succeeds<S: System>(system: &mut S, command: &[String]) -> Result<bool, SimpleError<S>> {
+    let args = command[1..].iter().map(String::as_str).collect::<Vec<_>>();
+    system
+        .execute_command(&command[0], &args)
+        .map(|result| result.is_success())
+        .map_err(|e| SimpleError::FailedToStart(command[0].clone(), e))
+}
+
+/// The identity of a requirement, given its key fields.
+pub fn identity<T: serde::Serialize>(key: &T) -> String {
+    serde_json::to_string(key).unwrap()
+}
+
+/// The line that runs `command` in a bootstrap script.
+pub fn to_shell(command: &[String]) -> String {
+    let args = command[1..].iter().map(String::as_str).collect::<Vec<_>>();
+    bootstrap::command(&command[0], &args)
+}
+
+/// Declares a requirement that is created, removed and checked with shell commands.
+///
+/// ```ignore
+/// libside::simple_requirement! {
+///     /// A port that is opened in the firewall.
+///     pub struct UfwAllow {
+///         port: u16,
+///         protocol: String,
+///     }
+///
+///     name: "ufw_allow",
+///     key: [port, protocol],
+///     summary: "A port that is opened in the firewall with ufw.",
+///     create: ["ufw", "allow", format!("{}/{}", port, protocol)],
+///     delete: ["ufw", "delete", "allow", format!("{}/{}", port, protocol)],
+///     exists: ["sh", "-c", format!("ufw status | grep -q '^{}/{} '", port, protocol)],
+/// }
+/// ```
+///
+/// The commands are lists of arguments, the first of which is the program.
+/// Arguments can be any expression that implements `Display`, and can refer to the fields of the struct by name.
+/// The program is also listed in [`Requirement::required_commands`](crate::requirements::Requirement::required_commands).
+///
+/// `exists` must succeed if the resource exists, and fail otherwise.
+/// Verification runs `verify` if it is given, and `exists` otherwise.
+/// Two requirements [affect](crate::requirements::Requirement::affects) each other if their `key` fields are equal, and the key fields are the identity of the requirement.
+/// A requirement that already exists is modified with `modify` if it is given.
+/// Otherwise, it is removed and created again if it does not verify, and left alone if it does.
+///
+/// The struct derives `Serialize` and `Deserialize`, so the crate that uses the macro must depend on `serde`.
+#[macro_export]
+macro_rules! simple_requirement {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field_vis:vis $field:ident : $field_ty:ty),* $(,)?
+        }
+
+        name: $kind:literal,
+        key: [$($key:ident),+ $(,)?],
+        summary: $summary:literal,
+        create: [$create:literal $(, $create_arg:expr)* $(,)?],
+        delete: [$delete:literal $(, $delete_arg:expr)* $(,)?],
+        exists: [$exists:literal $(, $exists_arg:expr)* $(,)?],
+        $(modify: [$modify:literal $(, $modify_arg:expr)* $(,)?],)?
+        verify: [$verify:literal $(, $verify_arg:expr)* $(,)?] $(,)?
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $field_ty),*
+        }
+
+        impl $name {
+            #[allow(unused_variables)]
+            fn __create_command(&self) -> Vec<String> {
+                let $name { $($field),* } = self;
+                vec![$create.to_string() $(, $create_arg.to_string())*]
+            }
+
+            #[allow(unused_variables)]
+            fn __delete_command(&self) -> Vec<String> {
+                let $name { $($field),* } = self;
+                vec![$delete.to_string() $(, $delete_arg.to_string())*]
+            }
+
+            #[allow(unused_variables)]
+            fn __exists_command(&self) -> Vec<String> {
+                let $name { $($field),* } = self;
+                vec![$exists.to_string() $(, $exists_arg.to_string())*]
+            }
+
+            #[allow(unused_variables)]
+            fn __modify_command(&self) -> Option<Vec<String>> {
+                let $name { $($field),* } = self;
+                None $(.or(Some(vec![$modify.to_string() $(, $modify_arg.to_string())*])))?
+            }
+
+            #[allow(unused_variables)]
+            fn __verify_command(&self) -> Vec<String> {
+                let $name { $($field),* } = self;
+                vec![$verify.to_string() $(, $verify_arg.to_string())*]
+            }
+        }
+
+        impl $crate::requirements::Requirement for $name {
+            const NAME: &'static str = $kind;
+            const EXPLANATION: $crate::requirements::Explanation = $crate::requirements::Explanation {
+                summary: $summary,
+                create: concat!("Runs `", $create, "`."),
+                verify: concat!("Runs `", $verify, "`, which succeeds if the requirement holds."),
+                undo: concat!("Runs `", $delete, "`."),
+            };
+
+            type CreateError<S: $crate::system::System> = $crate::simple::SimpleError<S>;
+            type ModifyError<S: $crate::system::System> = $crate::simple::SimpleError<S>;
+            type DeleteError<S: $crate::system::System> = $crate::simple::SimpleError<S>;
+            type HasBeenCreatedError<S: $crate::system::System> = $crate::simple::SimpleError<S>;
+
+            fn create<S: $crate::system::System>(&self, system: &mut S) -> Result<(), Self::CreateError<S>> {
+                $crate::simple::run(system, &self.__create_command())
+            }
+
+            fn modify<S: $crate::system::System>(&self, system: &mut S) -> Result<(), Self::ModifyError<S>> {
+                match self.__modify_command() {
+                    Some(command) => $crate::simple::run(system, &command),
+                    None if $crate::simple::succeeds(system, &self.__verify_command())? => Ok(()),
+                    None => {
+                        $crate::simple::run(system, &self.__delete_command())?;
+                        $crate::simple::run(system, &self.__create_command())
+                    }
+                }
+            }
+
+            fn delete<S: $crate::system::System>(&self, system: &mut S) -> Result<(), Self::DeleteError<S>> {
+                $crate::simple::run(system, &self.__delete_command())
+            }
+
+            fn has_been_created<S: $crate::system::System>(
+                &self,
+                system: &mut S,
+            ) -> Result<bool, Self::HasBeenCreatedError<S>> {
+                $crate::simple::succeeds(system, &self.__exists_command())
+            }
+
+            fn affects(&self, other: &Self) -> bool {
+                $(self.$key == other.$key)&&+
+            }
+
+            fn identity(&self) -> String {
+                $crate::simple::identity(&($(&self.$key,)+))
+            }
+
+            fn supports_modifications(&self) -> bool {
+                true
+            }
+
+            fn can_undo(&self) -> bool {
+                true
+            }
+
+            fn may_pre_exist(&self) -> bool {
+                false
+            }
+
+            fn verify<S: $crate::system::System>(&self, system: &mut S) -> Result<bool, ()> {
+                $crate::simple::succeeds(system, &self.__verify_command()).map_err(|_| ())
+            }
+
+            fn to_shell<S: $crate::system::System>(&self, _system: &mut S) -> Option<String> {
+                Some($crate::simple::to_shell(&self.__create_command()))
+            }
+
+            fn required_commands(&self) -> Vec<&'static str> {
+                let mut commands = vec![$create, $delete, $exists $(, $modify)?, $verify];
+                commands.sort_unstable();
+                commands.dedup();
+                commands
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}(", $kind)?;
+                let keys: Vec<String> = vec![$(format!("{:?}", self.$key)),+];
+                write!(f, "{})", keys.join(", "))
+            }
+        }
+    };
+
+    // Without `verify`, verification runs `exists`
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident { $($fields:tt)* }
+
+        name: $kind:literal,
+        key: [$($key:ident),+ $(,)?],
+        summary: $summary:literal,
+        create: [$create:literal $(, $create_arg:expr)* $(,)?],
+        delete: [$delete:literal $(, $delete_arg:expr)* $(,)?],
+        exists: [$exists:literal $(, $exists_arg:expr)* $(,)?],
+        $(modify: [$modify:literal $(, $modify_arg:expr)* $(,)?],)?
+    ) => {
+        $crate::simple_requirement! {
+            $(#[$meta])*
+            $vis struct $name { $($fields)* }
+
+            name: $kind,
+            key: [$($key),+],
+            summary: $summary,
+            create: [$create $(, $create_arg)*],
+            delete: [$delete $(, $delete_arg)*],
+            exists: [$exists $(, $exists_arg)*],
+            $(modify: [$modify $(, $modify_arg)*],)?
+            verify: [$exists $(, $exists_arg)*],
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::requirements::Requirement;
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use std::path::PathBuf;
+
+    crate::simple_requirement! {
+        /// A marker file, created with `touch`.
+        pub struct Marker {
+            path: PathBuf,
+            mode: String,
+        }
+
+        name: "marker",
+        key: [path],
+        summary: "A marker file.",
+        create: ["install", "-m", mode, "/dev/null", path.display()],
+        delete: ["rm", path.display()],
+        exists: ["test", "-e", path.display()],
+        verify: ["sh", "-c", format!("test \"$(stat -c %a {})\" = {}", path.display(), mode)],
+    }
+
+    #[test]
+    pub fn command_requirement() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("simple");
+        let path = dir.join("marker");
+        let marker = Marker {
+            path: path.clone(),
+            mode: String::from("600"),
+        };
+
+        assert_eq!(Marker::NAME, "marker");
+        assert_eq!(
+            marker.required_commands(),
+            vec!["install", "rm", "sh", "test"]
+        );
+        assert_eq!(marker.to_string(), format!("marker({:?})", path));
+        assert_eq!(marker.identity(), serde_json::to_string(&(&path,)).unwrap());
+
+        assert!(!marker.has_been_created(&mut system).unwrap());
+        marker.create(&mut system).unwrap();
+        assert!(marker.has_been_created(&mut system).unwrap());
+        assert!(marker.verify(&mut system).unwrap());
+
+        // A changed mode affects the same file, which is created again because it no longer verifies
+        let changed = Marker {
+            path: path.clone(),
+            mode: String::from("644"),
+        };
+        assert!(marker.affects(&changed));
+        assert!(!changed.verify(&mut system).unwrap());
+        changed.modify(&mut system).unwrap();
+        assert!(changed.verify(&mut system).unwrap());
+
+        changed.delete(&mut system).unwrap();
+        assert!(!changed.has_been_created(&mut system).unwrap());
+        assert!(changed.delete(&mut system).is_err());
+    }
+
+    crate::simple_requirement! {
+        pub struct Flag {
+            name: String,
+        }
+
+        name: "flag",
+        key: [name],
+        summary: "A flag.",
+        create: ["touch", name],
+        delete: ["rm", name],
+        exists: ["test", "-e", name],
+    }
+
+    crate::requirements!(R = Marker, Flag);
+
+    #[test]
+    pub fn verify_defaults_to_exists() {
+        let flag = Flag {
+            name: String::from("/nonexistent/flag"),
+        };
+        assert_eq!(flag.__verify_command(), flag.__exists_command());
+        assert_eq!(
+            Flag::EXPLANATION.verify,
+            "Runs `test`, which succeeds if the requirement holds."
+        );
+        assert!(!flag.verify(&mut LocalSystem::new()).unwrap());
+
+        let kinds = R::kinds().into_iter().map(|d| d.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec!["marker", "flag"]);
+    }
+}
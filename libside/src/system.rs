@@ -2,13 +2,18 @@ use std::{
     ffi::CString,
     fs,
     io::{self, Read, Write},
-    os::unix::prelude::PermissionsExt,
-    path::Path,
+    os::unix::prelude::{AsRawFd, PermissionsExt},
+    path::{Component, Path, PathBuf},
     process::{Command, Stdio},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use etc_passwd::Passwd;
 
+use crate::audit::Recorder;
+use crate::builder::fs::Sha3;
+use crate::transfer::TransferLimits;
+
 pub trait System: std::fmt::Debug {
     type Error: std::error::Error;
     type CommandError: std::error::Error;
@@ -21,6 +26,64 @@ pub trait System: std::fmt::Debug {
 
     fn put_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error>;
 
+    /// Adds `contents` to the end of a file, which is created if it does not exist. The existing contents are never rewritten.
+    /// Systems that cannot append to files rewrite the file with `put_file_contents`.
+    fn append_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        let mut existing = if self.path_exists(path)? {
+            self.file_contents(path)?
+        } else {
+            Vec::new()
+        };
+        existing.extend_from_slice(contents);
+        self.put_file_contents(path, &existing)
+    }
+
+    /// Replaces the contents of a file, which is created if it does not exist, such that a crash leaves either the old or the new contents.
+    /// Systems that cannot replace files atomically write the file with `put_file_contents`.
+    fn replace_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        self.put_file_contents(path, contents)
+    }
+
+    /// Computes the SHA3-256 hash of a file on the system itself, using `sha3sum` or `openssl`.
+    /// Falls back to transferring the file and hashing it locally if neither is available.
+    fn file_sha3(&self, path: &Path) -> Result<Sha3, Self::Error> {
+        if let Some(path) = path.to_str() {
+            let commands: [(&str, &[&str]); 2] = [
+                ("sha3sum", &["-a", "256", path]),
+                ("openssl", &["dgst", "-sha3-256", "-r", path]),
+            ];
+            for (command, args) in commands {
+                let hash = self
+                    .execute_command(command, args)
+                    .ok()
+                    .filter(|result| result.is_success())
+                    .and_then(|result| {
+                        let stdout = String::from_utf8_lossy(result.stdout()).into_owned();
+                        stdout.split_whitespace().next().and_then(Sha3::from_hex)
+                    });
+                if let Some(hash) = hash {
+                    return Ok(hash);
+                }
+            }
+        }
+
+        Ok(Sha3::hash(&self.file_contents(path)?))
+    }
+
+    /// Returns the size of a file in bytes. Falls back to transferring the file if `stat` is not available.
+    fn file_size(&self, path: &Path) -> Result<u64, Self::Error> {
+        if let Some(size) = stat(self, path, "%s") {
+            return Ok(size);
+        }
+
+        Ok(self.file_contents(path)?.len() as u64)
+    }
+
+    /// Returns the last modification time of a file, or `None` if it cannot be determined.
+    fn file_mtime(&self, path: &Path) -> Result<Option<SystemTime>, Self::Error> {
+        Ok(stat(self, path, "%Y").map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds)))
+    }
+
     fn execute_command(
         &self,
         path: &str,
@@ -34,8 +97,71 @@ pub trait System: std::fmt::Debug {
         input: &[u8],
     ) -> Result<CommandResult, Self::CommandError>;
 
+    /// Like `execute_command`, but calls `output` with the output of the command while it is running, for long-running commands such as release upgrades.
+    /// Systems that cannot stream output call `output` once with the entire output when the command has finished.
+    /// The returned result contains the entire output in both cases.
+    fn execute_command_streaming(
+        &self,
+        path: &str,
+        args: &[&str],
+        output: &mut dyn FnMut(&[u8]),
+    ) -> Result<CommandResult, Self::CommandError> {
+        let result = self.execute_command(path, args)?;
+        output(result.stdout());
+        output(result.stderr());
+
+        Ok(result)
+    }
+
+    /// Executes several commands and returns their results in the same order.
+    /// Systems where starting a command is expensive run all commands in a single round-trip, see `crate::batch`.
+    /// The commands do not receive any input.
+    fn execute_batch(
+        &self,
+        commands: &[(&str, &[&str])],
+    ) -> Result<Vec<CommandResult>, Self::CommandError> {
+        commands
+            .iter()
+            .map(|(path, args)| self.execute_command(path, args))
+            .collect()
+    }
+
+    /// Like `path_exists`, but checks several paths at once.
+    fn paths_exist(&self, paths: &[&Path]) -> Result<Vec<bool>, Self::Error> {
+        paths.iter().map(|path| self.path_exists(path)).collect()
+    }
+
     fn copy_file(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error>;
 
+    /// Like `copy_file`, but calls `progress` with the number of bytes of the file that have been copied so far.
+    /// Systems that cannot report intermediate progress never call `progress`.
+    fn copy_file_with_progress(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), Self::Error> {
+        let _ = progress;
+        self.copy_file(from, to)
+    }
+
+    /// Like `copy_file_with_progress`, but copies the file in chunks while staying below the bandwidth limit in `limits`.
+    /// A copy that was interrupted resumes after the last complete chunk, see `crate::transfer`.
+    /// Systems where copying is cheap, such as the local system, ignore the limits.
+    fn copy_file_resumable(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        limits: &TransferLimits,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), Self::Error> {
+        let _ = limits;
+        self.copy_file_with_progress(from, to, progress)
+    }
+
+    /// Creates a symbolic link at `link` that points to `target`.
+    fn symlink(&mut self, target: &Path, link: &Path) -> Result<(), Self::Error>;
+
     fn make_dir(&mut self, path: &Path) -> Result<(), Self::Error>;
 
     fn make_dir_all(&mut self, path: &Path) -> Result<(), Self::Error>;
@@ -53,10 +179,76 @@ pub trait System: std::fmt::Debug {
     fn get_user(&mut self, name: &str) -> Result<Option<()>, Self::Error>;
 
     fn chmod(&mut self, path: &Path, mode: u32) -> Result<(), Self::Error>;
+
+    /// The recorder that the commands executed on this system are reported to, see [`crate::audit`].
+    fn recorder(&self) -> Option<&Recorder> {
+        None
+    }
+
+    /// Attaches `recorder` to the system, or detaches the current recorder if `recorder` is `None`, and returns the recorder that was attached before.
+    /// Systems that wrap another system attach the recorder to that system. Systems that cannot record commands ignore it.
+    fn set_recorder(&mut self, recorder: Option<Recorder>) -> Option<Recorder> {
+        let _ = recorder;
+        None
+    }
+
+    /// Takes an exclusive advisory lock on `path`, which is created if it does not exist.
+    /// If another process holds the lock, waits until it is released when `wait` is set, and returns `None` otherwise.
+    /// Systems that cannot lock files return a lock that does not lock anything.
+    fn lock(&mut self, path: &Path, wait: bool) -> Result<Option<SystemLock>, Self::Error> {
+        let _ = (path, wait);
+        Ok(Some(SystemLock { file: None }))
+    }
 }
 
+/// An advisory lock taken with [`System::lock`], which is released when it is dropped.
 #[derive(Debug)]
-pub struct LocalSystem;
+pub struct SystemLock {
+    file: Option<fs::File>,
+}
+
+impl SystemLock {
+    /// Whether the lock actually excludes other processes.
+    pub fn is_held(&self) -> bool {
+        self.file.is_some()
+    }
+}
+
+/// Runs `stat` with the given format, which must print a single number.
+fn stat<S: System + ?Sized>(system: &S, path: &Path, format: &str) -> Option<u64> {
+    let result = system
+        .execute_command("stat", &["-c", format, path.to_str()?])
+        .ok()?;
+    if !result.is_success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(result.stdout()).trim().parse().ok()
+}
+
+/// The machine that side runs on, using `std::fs` and `std::process` directly.
+#[derive(Debug, Default)]
+pub struct LocalSystem {
+    recorder: Option<Recorder>,
+}
+
+impl LocalSystem {
+    pub fn new() -> LocalSystem {
+        LocalSystem::default()
+    }
+
+    fn command_executed(
+        &self,
+        path: &str,
+        args: &[&str],
+        result: &io::Result<CommandResult>,
+        started: Instant,
+    ) {
+        if let Some(recorder) = &self.recorder {
+            recorder.command_executed(path, args, result.as_ref().ok(), started);
+        }
+    }
+}
 
 impl System for LocalSystem {
     type Error = io::Error;
@@ -86,14 +278,78 @@ impl System for LocalSystem {
         Ok(fs::write(path, contents)?)
     }
 
+    fn append_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?
+            .write_all(contents)
+    }
+
+    /// Writes the contents to a temporary file in the same directory, flushes it to disk and renames it over `path`.
+    fn replace_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        let mut name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?
+            .to_owned();
+        name.push(".tmp");
+        let temporary = path.with_file_name(name);
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+
+        // The rename itself is only durable once the directory has been flushed as well
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => fs::File::open(parent)?.sync_all(),
+            _ => Ok(()),
+        }
+    }
+
+    fn file_sha3(&self, path: &Path) -> Result<Sha3, Self::Error> {
+        Sha3::hash_reader(fs::File::open(path)?)
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64, Self::Error> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn file_mtime(&self, path: &Path) -> Result<Option<SystemTime>, Self::Error> {
+        Ok(fs::metadata(path)?.modified().ok())
+    }
+
     fn execute_command(&self, path: &str, args: &[&str]) -> Result<CommandResult, Self::Error> {
-        let command = Command::new(path).args(args).output()?;
+        let started = Instant::now();
+        let result = Command::new(path)
+            .args(args)
+            .output()
+            .map(|command| CommandResult {
+                exit_code: command.status.code(),
+                stdout: command.stdout,
+                stderr: command.stderr,
+            });
+        self.command_executed(path, args, &result, started);
 
-        Ok(CommandResult {
-            exit_code: command.status.code(),
-            stdout: command.stdout,
-            stderr: command.stderr,
-        })
+        result
+    }
+
+    fn execute_command_streaming(
+        &self,
+        path: &str,
+        args: &[&str],
+        output: &mut dyn FnMut(&[u8]),
+    ) -> Result<CommandResult, Self::CommandError> {
+        let started = Instant::now();
+        let result = Command::new(path)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|child| stream_process_output(child, output));
+        self.command_executed(path, args, &result, started);
+
+        result
     }
 
     fn copy_file(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error> {
@@ -101,6 +357,36 @@ impl System for LocalSystem {
         Ok(())
     }
 
+    fn copy_file_with_progress(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), Self::Error> {
+        let mut source = fs::File::open(from)?;
+        let mut target = fs::File::create(to)?;
+        let mut buf = vec![0u8; 1 << 20];
+        let mut copied = 0u64;
+        loop {
+            let n = source.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+
+            target.write_all(&buf[..n])?;
+            copied += n as u64;
+            progress(copied);
+        }
+
+        fs::set_permissions(to, source.metadata()?.permissions())?;
+
+        Ok(())
+    }
+
+    fn symlink(&mut self, target: &Path, link: &Path) -> Result<(), Self::Error> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
     fn make_dir(&mut self, path: &Path) -> Result<(), Self::Error> {
         fs::create_dir(path)?;
         Ok(())
@@ -112,7 +398,7 @@ impl System for LocalSystem {
     }
 
     fn dir_is_empty(&mut self, path: &Path) -> Result<bool, Self::Error> {
-        Ok(fs::read_dir(path)?.count() != 0)
+        Ok(fs::read_dir(path)?.next().is_none())
     }
 
     fn remove_dir(&mut self, path: &Path) -> Result<(), Self::Error> {
@@ -126,7 +412,9 @@ impl System for LocalSystem {
     }
 
     fn get_user(&mut self, name: &str) -> Result<Option<()>, Self::Error> {
-        Ok(Passwd::from_name(CString::new(name).unwrap())?.map(|_| ()))
+        let name =
+            CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Passwd::from_name(name)?.map(|_| ()))
     }
 
     fn execute_command_with_input(
@@ -135,14 +423,17 @@ impl System for LocalSystem {
         args: &[&str],
         input: &[u8],
     ) -> Result<CommandResult, Self::CommandError> {
-        let child = Command::new(path)
+        let started = Instant::now();
+        let result = Command::new(path)
             .args(args)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .spawn()?;
+            .spawn()
+            .and_then(|child| handle_process_io(child, input));
+        self.command_executed(path, args, &result, started);
 
-        handle_process_io(child, input)
+        result
     }
 
     fn chmod(&mut self, path: &Path, mode: u32) -> Result<(), Self::Error> {
@@ -155,10 +446,287 @@ impl System for LocalSystem {
     }
 
     fn read_dir(&mut self, path: &Path) -> Result<Vec<String>, Self::Error> {
-        Ok(path
-            .read_dir()?
-            .map(|item| item.unwrap().file_name().to_str().unwrap().to_owned())
-            .collect())
+        path.read_dir()?
+            .map(|item| {
+                item?.file_name().into_string().map_err(|name| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("file name {:?} is not valid UTF-8", name),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    fn lock(&mut self, path: &Path, wait: bool) -> Result<Option<SystemLock>, Self::Error> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        let operation = if wait {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_EX | libc::LOCK_NB
+        };
+
+        // SAFETY: the file descriptor is valid for as long as `file` lives
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+            let e = io::Error::last_os_error();
+            return if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                Ok(None)
+            } else {
+                Err(e)
+            };
+        }
+
+        // The PID of the holder, for the error that other runs report
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+
+        Ok(Some(SystemLock { file: Some(file) }))
+    }
+
+    fn recorder(&self) -> Option<&Recorder> {
+        self.recorder.as_ref()
+    }
+
+    fn set_recorder(&mut self, recorder: Option<Recorder>) -> Option<Recorder> {
+        std::mem::replace(&mut self.recorder, recorder)
+    }
+}
+
+/// A system rooted in a directory of another system.
+/// All paths are interpreted relative to `root`, and commands are executed with `chroot`.
+/// This can be used to apply a build to a staged root filesystem instead of the live system.
+#[derive(Debug)]
+pub struct ChrootSystem<'s, S: System> {
+    root: PathBuf,
+    inner: &'s mut S,
+}
+
+impl<'s, S: System> ChrootSystem<'s, S> {
+    pub fn new<P: AsRef<Path>>(inner: &'s mut S, root: P) -> Self {
+        ChrootSystem {
+            root: root.as_ref().to_path_buf(),
+            inner,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the path in the inner system that corresponds to `path` in the chroot.
+    pub fn host_path(&self, path: &Path) -> PathBuf {
+        let mut result = self.root.clone();
+        for component in path.components() {
+            match component {
+                Component::Normal(c) => result.push(c),
+                Component::ParentDir => {
+                    if result != self.root {
+                        result.pop();
+                    }
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
+            }
+        }
+
+        result
+    }
+
+    fn chroot_args<'a>(&'a self, path: &'a str, args: &[&'a str]) -> Vec<&'a str> {
+        let mut result = vec![self.root.to_str().unwrap(), path];
+        result.extend(args);
+        result
+    }
+}
+
+impl<'s, S: System> System for ChrootSystem<'s, S> {
+    type Error = S::Error;
+    type CommandError = S::CommandError;
+
+    fn path_exists(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.inner.path_exists(&self.host_path(path))
+    }
+
+    fn path_is_dir(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.inner.path_is_dir(&self.host_path(path))
+    }
+
+    fn file_contents(&self, path: &Path) -> Result<Vec<u8>, Self::Error> {
+        self.inner.file_contents(&self.host_path(path))
+    }
+
+    fn put_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        self.inner
+            .put_file_contents(&self.host_path(path), contents)
+    }
+
+    fn append_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        self.inner
+            .append_file_contents(&self.host_path(path), contents)
+    }
+
+    fn replace_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        self.inner
+            .replace_file_contents(&self.host_path(path), contents)
+    }
+
+    fn file_sha3(&self, path: &Path) -> Result<Sha3, Self::Error> {
+        self.inner.file_sha3(&self.host_path(path))
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64, Self::Error> {
+        self.inner.file_size(&self.host_path(path))
+    }
+
+    fn file_mtime(&self, path: &Path) -> Result<Option<SystemTime>, Self::Error> {
+        self.inner.file_mtime(&self.host_path(path))
+    }
+
+    fn execute_command(
+        &self,
+        path: &str,
+        args: &[&str],
+    ) -> Result<CommandResult, Self::CommandError> {
+        self.inner
+            .execute_command("chroot", &self.chroot_args(path, args))
+    }
+
+    fn execute_command_with_input(
+        &self,
+        path: &str,
+        args: &[&str],
+        input: &[u8],
+    ) -> Result<CommandResult, Self::CommandError> {
+        self.inner
+            .execute_command_with_input("chroot", &self.chroot_args(path, args), input)
+    }
+
+    fn execute_command_streaming(
+        &self,
+        path: &str,
+        args: &[&str],
+        output: &mut dyn FnMut(&[u8]),
+    ) -> Result<CommandResult, Self::CommandError> {
+        self.inner
+            .execute_command_streaming("chroot", &self.chroot_args(path, args), output)
+    }
+
+    fn execute_batch(
+        &self,
+        commands: &[(&str, &[&str])],
+    ) -> Result<Vec<CommandResult>, Self::CommandError> {
+        let args = commands
+            .iter()
+            .map(|(path, args)| self.chroot_args(path, args))
+            .collect::<Vec<_>>();
+        let commands = args
+            .iter()
+            .map(|args| ("chroot", args.as_slice()))
+            .collect::<Vec<_>>();
+        self.inner.execute_batch(&commands)
+    }
+
+    fn paths_exist(&self, paths: &[&Path]) -> Result<Vec<bool>, Self::Error> {
+        let paths = paths
+            .iter()
+            .map(|path| self.host_path(path))
+            .collect::<Vec<_>>();
+        self.inner
+            .paths_exist(&paths.iter().map(PathBuf::as_path).collect::<Vec<_>>())
+    }
+
+    fn copy_file(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error> {
+        let (from, to) = (self.host_path(from), self.host_path(to));
+        self.inner.copy_file(&from, &to)
+    }
+
+    fn copy_file_with_progress(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), Self::Error> {
+        let (from, to) = (self.host_path(from), self.host_path(to));
+        self.inner.copy_file_with_progress(&from, &to, progress)
+    }
+
+    fn copy_file_resumable(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        limits: &TransferLimits,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), Self::Error> {
+        let (from, to) = (self.host_path(from), self.host_path(to));
+        self.inner.copy_file_resumable(&from, &to, limits, progress)
+    }
+
+    /// The target is not translated, because the link is resolved inside the chroot.
+    fn symlink(&mut self, target: &Path, link: &Path) -> Result<(), Self::Error> {
+        let link = self.host_path(link);
+        self.inner.symlink(target, &link)
+    }
+
+    fn make_dir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        let path = self.host_path(path);
+        self.inner.make_dir(&path)
+    }
+
+    fn make_dir_all(&mut self, path: &Path) -> Result<(), Self::Error> {
+        let path = self.host_path(path);
+        self.inner.make_dir_all(&path)
+    }
+
+    fn dir_is_empty(&mut self, path: &Path) -> Result<bool, Self::Error> {
+        let path = self.host_path(path);
+        self.inner.dir_is_empty(&path)
+    }
+
+    fn read_dir(&mut self, path: &Path) -> Result<Vec<String>, Self::Error> {
+        let path = self.host_path(path);
+        self.inner.read_dir(&path)
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        let path = self.host_path(path);
+        self.inner.remove_dir(&path)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<(), Self::Error> {
+        let path = self.host_path(path);
+        self.inner.remove_file(&path)
+    }
+
+    fn get_user(&mut self, name: &str) -> Result<Option<()>, Self::Error> {
+        let passwd = self.host_path(Path::new("/etc/passwd"));
+        let contents = self.inner.file_contents(&passwd)?;
+        let prefix = format!("{}:", name);
+
+        Ok(String::from_utf8_lossy(&contents)
+            .lines()
+            .any(|line| line.starts_with(&prefix))
+            .then_some(()))
+    }
+
+    fn chmod(&mut self, path: &Path, mode: u32) -> Result<(), Self::Error> {
+        let path = self.host_path(path);
+        self.inner.chmod(&path, mode)
+    }
+
+    fn lock(&mut self, path: &Path, wait: bool) -> Result<Option<SystemLock>, Self::Error> {
+        let path = self.host_path(path);
+        self.inner.lock(&path, wait)
+    }
+
+    fn recorder(&self) -> Option<&Recorder> {
+        self.inner.recorder()
+    }
+
+    fn set_recorder(&mut self, recorder: Option<Recorder>) -> Option<Recorder> {
+        self.inner.set_recorder(recorder)
     }
 }
 
@@ -181,9 +749,9 @@ pub(crate) fn handle_process_io(
                 if let Some(stdin) = &mut stdin_stream {
                     if to_write.len() > 0 {
                         let written = stdin.write(&to_write)?;
-                        println!(
+                        tracing::trace!(
                             "Written: [{}]",
-                            std::str::from_utf8(&to_write[..written]).unwrap()
+                            String::from_utf8_lossy(&to_write[..written])
                         );
                         to_write = &to_write[written..];
 
@@ -215,6 +783,61 @@ pub(crate) fn handle_process_io(
     })
 }
 
+/// Passes stdout and stderr to `output` as they are written. Stderr is read on a separate thread, so that neither pipe can fill up and block the process.
+fn stream_process_output(
+    mut child: std::process::Child,
+    output: &mut dyn FnMut(&[u8]),
+) -> Result<CommandResult, io::Error> {
+    let (sender, receiver) = std::sync::mpsc::channel::<Vec<u8>>();
+    let mut stderr_stream = child.stderr.take().unwrap();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match stderr_stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if sender.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut stdout_stream = child.stdout.take().unwrap();
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stdout_stream.read(&mut buf)?;
+        for chunk in receiver.try_iter() {
+            output(&chunk);
+            stderr.extend(chunk);
+        }
+
+        if n == 0 {
+            break;
+        }
+
+        output(&buf[..n]);
+        stdout.extend(&buf[..n]);
+    }
+
+    let status = child.wait()?;
+    let _ = stderr_thread.join();
+    for chunk in receiver.try_iter() {
+        output(&chunk);
+        stderr.extend(chunk);
+    }
+
+    Ok(CommandResult {
+        exit_code: status.code(),
+        stdout,
+        stderr,
+    })
+}
+
+#[derive(Clone)]
 pub struct CommandResult {
     stdout: Vec<u8>,
     stderr: Vec<u8>,
@@ -231,10 +854,23 @@ impl std::fmt::Debug for CommandResult {
 }
 
 impl CommandResult {
+    pub fn new(stdout: Vec<u8>, stderr: Vec<u8>, exit_code: Option<i32>) -> CommandResult {
+        CommandResult {
+            stdout,
+            stderr,
+            exit_code,
+        }
+    }
+
     pub fn is_success(&self) -> bool {
         self.exit_code == Some(0)
     }
 
+    /// The exit code of the command, or `None` if it was killed by a signal.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
     pub fn successful(&self) -> Result<(), (&str, &str)> {
         if self.is_success() {
             Ok(())
@@ -266,16 +902,140 @@ pub struct NeverError;
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
-    use crate::system::{LocalSystem, System};
+    use crate::builder::fs::Sha3;
+    use crate::system::{ChrootSystem, LocalSystem, System};
+    use crate::testing::TempDir;
+    use std::path::{Path, PathBuf};
 
     #[test]
     pub fn test_read_dir() {
-        assert_eq!(LocalSystem.read_dir(&PathBuf::from("test-data/empty-folder")).unwrap(), Vec::<String>::new());
-        let mut v = LocalSystem.read_dir(&PathBuf::from("test-data/folder-folder")).unwrap();
+        // Git does not keep empty directories
+        let empty = TempDir::new("empty-folder");
+
+        assert_eq!(
+            LocalSystem::new().read_dir(&empty).unwrap(),
+            Vec::<String>::new()
+        );
+        let mut v = LocalSystem::new()
+            .read_dir(&PathBuf::from("test-data/folder-folder"))
+            .unwrap();
         v.sort();
-        assert_eq!(v, vec![ String::from("a"), String::from("b"), String::from("c") ]);
+        assert_eq!(
+            v,
+            vec![String::from("a"), String::from("b"), String::from("c")]
+        );
+
+        assert!(LocalSystem::new()
+            .path_is_dir(&PathBuf::from("test-data/folder-folder/a"))
+            .unwrap());
+        assert!(LocalSystem::new().dir_is_empty(&empty).unwrap());
+        assert!(!LocalSystem::new()
+            .dir_is_empty(&PathBuf::from("test-data/folder-folder"))
+            .unwrap());
+    }
+
+    #[test]
+    pub fn get_user() {
+        assert_eq!(LocalSystem::new().get_user("root").unwrap(), Some(()));
+        assert_eq!(
+            LocalSystem::new().get_user("side-no-such-user").unwrap(),
+            None
+        );
+        assert!(LocalSystem::new().get_user("ro\0ot").is_err());
+    }
+
+    #[test]
+    pub fn chroot_host_path() {
+        let mut local = LocalSystem::new();
+        let sys = ChrootSystem::new(&mut local, "/staging");
+
+        assert_eq!(
+            sys.host_path(Path::new("/etc/passwd")),
+            PathBuf::from("/staging/etc/passwd")
+        );
+        assert_eq!(
+            sys.host_path(Path::new("/../../etc")),
+            PathBuf::from("/staging/etc")
+        );
+        assert_eq!(
+            sys.host_path(Path::new("/srv/a/../b")),
+            PathBuf::from("/staging/srv/b")
+        );
+    }
+
+    #[test]
+    pub fn copy_file_with_progress() {
+        let dir = TempDir::new("copy");
+        let contents = vec![7u8; 3 << 20];
+        std::fs::write(dir.join("asset"), &contents).unwrap();
 
-        assert!(LocalSystem.path_is_dir(&PathBuf::from("test-data/folder-folder/a")).unwrap());
+        let mut progress = Vec::new();
+        LocalSystem::new()
+            .copy_file_with_progress(&dir.join("asset"), &dir.join("copy"), &mut |n| {
+                progress.push(n)
+            })
+            .unwrap();
+        LocalSystem::new()
+            .symlink(&dir.join("asset"), &dir.join("link"))
+            .unwrap();
+        let copied = std::fs::read(dir.join("copy")).unwrap();
+        let linked = std::fs::read_link(dir.join("link")).unwrap();
+
+        assert_eq!(copied, contents);
+        assert_eq!(progress.last(), Some(&(3 << 20)));
+        assert!(progress.len() > 1);
+        assert_eq!(linked, dir.join("asset"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn file_metadata() {
+        let dir = TempDir::new("metadata");
+        let path = dir.join("file");
+        std::fs::write(&path, b"Hello World").unwrap();
+
+        let hash = LocalSystem::new().file_sha3(&path).unwrap();
+        let size = LocalSystem::new().file_size(&path).unwrap();
+        let mtime = LocalSystem::new().file_mtime(&path).unwrap();
+
+        assert_eq!(hash, Sha3::hash(b"Hello World"));
+        assert_eq!(size, 11);
+        assert!(mtime.is_some());
+    }
+
+    #[test]
+    pub fn stream_command_output() {
+        let mut streamed = Vec::new();
+        let result = LocalSystem::new()
+            .execute_command_streaming(
+                "sh",
+                &["-c", "echo out; echo err >&2; exit 3"],
+                &mut |output| streamed.extend_from_slice(output),
+            )
+            .unwrap();
+
+        assert_eq!(result.exit_code(), Some(3));
+        assert_eq!(result.stdout(), b"out\n");
+        assert_eq!(result.stderr(), b"err\n");
+        assert_eq!(streamed.len(), 8);
+    }
+
+    #[test]
+    pub fn lock_excludes_other_holders() {
+        let mut system = LocalSystem::new();
+        let dir = TempDir::new("lock");
+        let path = dir.join("lock");
+
+        let lock = system.lock(&path, false).unwrap().unwrap();
+        assert!(lock.is_held());
+        assert_eq!(
+            String::from_utf8(system.file_contents(&path).unwrap()).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        // flock locks belong to the open file, so a second lock in the same process is refused as well
+        assert!(system.lock(&path, false).unwrap().is_none());
+
+        drop(lock);
+        assert!(system.lock(&path, true).unwrap().is_some());
+    }
+}
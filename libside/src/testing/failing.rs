@@ -0,0 +1,253 @@
+//! A [`System`] that makes selected operations of another system fail, to test revert paths deterministically.
+//!
+//! Requirements that always fail can only fail as a whole. [`FailingSystem`] fails in the middle of a requirement instead,
+//! for example on the third file that is copied during an apply, or on the first time a specific command is executed.
+use crate::audit::Recorder;
+use crate::system::{CommandResult, System, SystemLock};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FailingError<E: std::error::Error> {
+    #[error("injected failure of {}", .0)]
+    Injected(&'static str),
+
+    #[error("{}", .0)]
+    Inner(E),
+}
+
+/// Wraps `inner` and injects failures into the operations that have been configured with [`FailingSystem::fail_copy`] and [`FailingSystem::fail_command`].
+/// All other operations are passed through to `inner`.
+#[derive(Debug)]
+pub struct FailingSystem<S> {
+    inner: S,
+    copies: Cell<usize>,
+    failing_copies: BTreeSet<usize>,
+    failing_commands: RefCell<HashMap<String, usize>>,
+}
+
+impl<S: System> FailingSystem<S> {
+    pub fn new(inner: S) -> Self {
+        FailingSystem {
+            inner,
+            copies: Cell::new(0),
+            failing_copies: BTreeSet::new(),
+            failing_commands: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Makes the `n`th call to `copy_file` fail, counting from 1.
+    /// The failing call does not copy anything.
+    pub fn fail_copy(mut self, n: usize) -> Self {
+        self.failing_copies.insert(n);
+        self
+    }
+
+    /// Makes the next `times` executions of `command` exit with status 1, without running it.
+    pub fn fail_command(self, command: &str, times: usize) -> Self {
+        *self
+            .failing_commands
+            .borrow_mut()
+            .entry(command.to_owned())
+            .or_default() += times;
+        self
+    }
+
+    /// The number of times `copy_file` has been called, including calls that failed.
+    pub fn copies(&self) -> usize {
+        self.copies.get()
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn command_fails(&self, command: &str) -> bool {
+        match self.failing_commands.borrow_mut().get_mut(command) {
+            Some(remaining) if *remaining > 0 => {
+                *remaining -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn failed_command(command: &str) -> CommandResult {
+        CommandResult::new(
+            Vec::new(),
+            format!("{}: injected failure\n", command).into_bytes(),
+            Some(1),
+        )
+    }
+}
+
+impl<S: System> System for FailingSystem<S> {
+    type Error = FailingError<S::Error>;
+    type CommandError = S::CommandError;
+
+    fn path_exists(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.inner.path_exists(path).map_err(FailingError::Inner)
+    }
+
+    fn path_is_dir(&self, path: &Path) -> Result<bool, Self::Error> {
+        self.inner.path_is_dir(path).map_err(FailingError::Inner)
+    }
+
+    fn file_contents(&self, path: &Path) -> Result<Vec<u8>, Self::Error> {
+        self.inner.file_contents(path).map_err(FailingError::Inner)
+    }
+
+    fn put_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        self.inner
+            .put_file_contents(path, contents)
+            .map_err(FailingError::Inner)
+    }
+
+    fn append_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        self.inner
+            .append_file_contents(path, contents)
+            .map_err(FailingError::Inner)
+    }
+
+    fn replace_file_contents(&self, path: &Path, contents: &[u8]) -> Result<(), Self::Error> {
+        self.inner
+            .replace_file_contents(path, contents)
+            .map_err(FailingError::Inner)
+    }
+
+    fn execute_command(
+        &self,
+        path: &str,
+        args: &[&str],
+    ) -> Result<CommandResult, Self::CommandError> {
+        if self.command_fails(path) {
+            return Ok(Self::failed_command(path));
+        }
+
+        self.inner.execute_command(path, args)
+    }
+
+    fn execute_command_with_input(
+        &self,
+        path: &str,
+        args: &[&str],
+        input: &[u8],
+    ) -> Result<CommandResult, Self::CommandError> {
+        if self.command_fails(path) {
+            return Ok(Self::failed_command(path));
+        }
+
+        self.inner.execute_command_with_input(path, args, input)
+    }
+
+    fn execute_command_streaming(
+        &self,
+        path: &str,
+        args: &[&str],
+        output: &mut dyn FnMut(&[u8]),
+    ) -> Result<CommandResult, Self::CommandError> {
+        if self.command_fails(path) {
+            return Ok(Self::failed_command(path));
+        }
+
+        self.inner.execute_command_streaming(path, args, output)
+    }
+
+    fn copy_file(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error> {
+        self.copies.set(self.copies.get() + 1);
+        if self.failing_copies.contains(&self.copies.get()) {
+            return Err(FailingError::Injected("copy_file"));
+        }
+
+        self.inner.copy_file(from, to).map_err(FailingError::Inner)
+    }
+
+    fn symlink(&mut self, target: &Path, link: &Path) -> Result<(), Self::Error> {
+        self.inner
+            .symlink(target, link)
+            .map_err(FailingError::Inner)
+    }
+
+    fn make_dir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.inner.make_dir(path).map_err(FailingError::Inner)
+    }
+
+    fn make_dir_all(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.inner.make_dir_all(path).map_err(FailingError::Inner)
+    }
+
+    fn read_dir(&mut self, path: &Path) -> Result<Vec<String>, Self::Error> {
+        self.inner.read_dir(path).map_err(FailingError::Inner)
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.inner.remove_dir(path).map_err(FailingError::Inner)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.inner.remove_file(path).map_err(FailingError::Inner)
+    }
+
+    fn get_user(&mut self, name: &str) -> Result<Option<()>, Self::Error> {
+        self.inner.get_user(name).map_err(FailingError::Inner)
+    }
+
+    fn chmod(&mut self, path: &Path, mode: u32) -> Result<(), Self::Error> {
+        self.inner.chmod(path, mode).map_err(FailingError::Inner)
+    }
+
+    fn lock(&mut self, path: &Path, wait: bool) -> Result<Option<SystemLock>, Self::Error> {
+        self.inner.lock(path, wait).map_err(FailingError::Inner)
+    }
+
+    fn recorder(&self) -> Option<&Recorder> {
+        self.inner.recorder()
+    }
+
+    fn set_recorder(&mut self, recorder: Option<Recorder>) -> Option<Recorder> {
+        self.inner.set_recorder(recorder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FailingError, FailingSystem};
+    use crate::system::{LocalSystem, System};
+    use crate::testing::TempDir;
+
+    #[test]
+    pub fn command_fails_once() {
+        let system = FailingSystem::new(LocalSystem::new()).fail_command("true", 1);
+
+        let first = system.execute_command("true", &[]).unwrap();
+        let second = system.execute_command("true", &[]).unwrap();
+        let other = system.execute_command("echo", &["ok"]).unwrap();
+
+        assert_eq!(first.exit_code(), Some(1));
+        assert!(second.is_success());
+        assert_eq!(other.stdout_as_str(), "ok\n");
+    }
+
+    #[test]
+    pub fn nth_copy_fails() {
+        let base = TempDir::new("failing");
+        std::fs::write(base.join("source"), "contents").unwrap();
+        let mut system = FailingSystem::new(LocalSystem::new()).fail_copy(2);
+
+        let first = system.copy_file(&base.join("source"), &base.join("first"));
+        let second = system.copy_file(&base.join("source"), &base.join("second"));
+        let third = system.copy_file(&base.join("source"), &base.join("third"));
+        let copied = ["first", "second", "third"].map(|name| base.join(name).exists());
+
+        assert!(first.is_ok());
+        assert!(matches!(second, Err(FailingError::Injected("copy_file"))));
+        assert!(third.is_ok());
+        assert_eq!(copied, [true, false, true]);
+        assert_eq!(system.copies(), 3);
+    }
+}
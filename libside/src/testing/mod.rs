@@ -1,29 +1,46 @@
+use crate::audit::Recorder;
 use crate::system::{handle_process_io, System};
+use crate::transfer::{TransferError, TransferLimits};
 use lazy_static::lazy_static;
 use rand::{distributions::Alphanumeric, prelude::*};
 use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     sync::Mutex,
+    time::Instant,
 };
+use tracing::{debug, info};
+
+pub mod failing;
 
 lazy_static! {
-    static ref LOCK: Mutex<()> = Mutex::new(());
+    static ref DEFAULT_LAUNCHER: LxcLauncher = LxcLauncher::new();
 }
 
-#[derive(Debug)]
-pub struct LxcInstance {
-    is_ready: bool,
-    name: String,
+/// Launches [`LxcInstance`]s.
+/// Launches through the same launcher are serialized, because launching multiple VMs at the same time somehow causes crashes.
+/// Processes that manage independent sets of containers can each use their own launcher.
+#[derive(Debug, Default)]
+pub struct LxcLauncher {
+    lock: Mutex<()>,
 }
 
-impl LxcInstance {
-    pub const DEFAULT_IMAGE: &'static str = Self::UBUNTU_FOCAL;
-    pub const UBUNTU_FOCAL: &'static str = "images:ubuntu/focal";
-    pub const UBUNTU_JAMMY: &'static str = "images:ubuntu/jammy";
+impl LxcLauncher {
+    pub fn new() -> LxcLauncher {
+        LxcLauncher {
+            lock: Mutex::new(()),
+        }
+    }
 
-    pub fn start(image: &str) -> LxcInstance {
+    pub fn start(&self, image: &str) -> LxcInstance {
+        self.try_start(image).unwrap()
+    }
+
+    /// Like [`LxcLauncher::start`], but returns an error instead of panicking if the instance cannot be started.
+    pub fn try_start(&self, image: &str) -> Result<LxcInstance, LxcError> {
         // Make sure we don't launch multiple VMs at the same time because that somehow causes crashes.
-        let guard = LOCK.lock().unwrap();
+        let guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
         let name = format!(
             "side-test-{}",
             rand::thread_rng()
@@ -41,43 +58,62 @@ impl LxcInstance {
             .arg("-p")
             .arg("default")
             .output()
-            .unwrap();
+            .map_err(|e| LxcError::LaunchFailed(e.to_string()))?;
         drop(guard);
 
-        assert!(
-            result.status.success(),
-            "lxc launch failed: {}{}",
-            std::str::from_utf8(&result.stdout).unwrap(),
-            std::str::from_utf8(&result.stderr).unwrap()
-        );
+        if !result.status.success() {
+            return Err(LxcError::LaunchFailed(format!(
+                "{}{}",
+                String::from_utf8_lossy(&result.stdout),
+                String::from_utf8_lossy(&result.stderr)
+            )));
+        }
 
-        println!("lxc instance started as {}", name);
+        info!("lxc instance started as {}", name);
         let mut inst = LxcInstance {
             name,
             is_ready: false,
+            recorder: None,
         };
-        inst.wait_until_ready();
+        inst.wait_until_ready()?;
+
+        info!("lxc instance {} is ready", inst.name);
+        Ok(inst)
+    }
+}
+
+#[derive(Debug)]
+pub struct LxcInstance {
+    is_ready: bool,
+    name: String,
+    recorder: Option<Recorder>,
+}
+
+impl LxcInstance {
+    pub const DEFAULT_IMAGE: &'static str = Self::UBUNTU_FOCAL;
+    pub const UBUNTU_FOCAL: &'static str = "images:ubuntu/focal";
+    pub const UBUNTU_JAMMY: &'static str = "images:ubuntu/jammy";
 
-        println!("Ready");
-        inst
+    /// Starts a new instance using the default launcher shared by the whole process.
+    pub fn start(image: &str) -> LxcInstance {
+        DEFAULT_LAUNCHER.start(image)
     }
 
-    fn wait_until_ready(&mut self) {
+    fn wait_until_ready(&mut self) -> Result<(), LxcError> {
         for _ in 0..100 {
             let result = Command::new("lxc")
                 .arg("exec")
                 .arg(&self.name)
                 .arg("true")
                 .output()
-                .unwrap();
+                .map_err(|e| LxcError::LaunchFailed(e.to_string()))?;
             if result.status.success() {
-                return;
+                self.is_ready = true;
+                return Ok(());
             }
         }
 
-        self.is_ready = true;
-
-        panic!("Container {} is not starting", self.name);
+        Err(LxcError::NotStarting(self.name.clone()))
     }
 
     pub fn copy_files_to_container(&mut self, host_source: &str, container_target: &str) {
@@ -94,6 +130,36 @@ impl LxcInstance {
             .output()
             .unwrap();
     }
+
+    /// Recursively copies the directory `host_source` into the directory `container_parent`, which is created if needed.
+    /// The copy keeps the name of the source directory.
+    pub fn copy_dir_to_container(
+        &mut self,
+        host_source: &Path,
+        container_parent: &Path,
+    ) -> Result<(), LxcError> {
+        let container_parent = container_parent
+            .to_str()
+            .and_then(|p| p.strip_prefix('/'))
+            .expect("container target path must be absolute");
+        self.make_dir_all(Path::new("/").join(container_parent).as_path())?;
+        let result = Command::new("lxc")
+            .arg("file")
+            .arg("push")
+            .arg("-r")
+            .arg(host_source)
+            .arg(format!("{}/{}", self.name, container_parent))
+            .output()
+            .map_err(|e| LxcError::CopyFailed(e.to_string()))?;
+
+        if result.status.success() {
+            Ok(())
+        } else {
+            Err(LxcError::CopyFailed(
+                String::from_utf8_lossy(&result.stderr).into_owned(),
+            ))
+        }
+    }
 }
 
 impl Drop for LxcInstance {
@@ -107,10 +173,73 @@ impl Drop for LxcInstance {
     }
 }
 
+/// A directory in the system's temporary directory that is removed when it is dropped, for tests that need real files.
+/// The name includes a random suffix, so that tests that run at the same time never share a directory.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates an empty directory whose name starts with `side-{name}-`.
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!(
+            "side-{}-{}-{}",
+            name,
+            std::process::id(),
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(7)
+                .map(char::from)
+                .collect::<String>()
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // Never panic in drop: the test may already be panicking, and its own failure is more useful.
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum LxcError {
     #[error("path does not exist")]
     PathDoesNotExist,
+
+    #[error("lxc launch failed: {0}")]
+    LaunchFailed(String),
+
+    #[error("container {0} is not starting")]
+    NotStarting(String),
+
+    #[error("copying files to the container failed: {0}")]
+    CopyFailed(String),
+
+    #[error("batched commands did not complete: {0}")]
+    BatchFailed(String),
 }
 
 impl System for LxcInstance {
@@ -133,7 +262,7 @@ impl System for LxcInstance {
 
     fn file_contents(&self, path: &std::path::Path) -> Result<Vec<u8>, Self::Error> {
         let path = path.as_os_str().to_str().unwrap();
-        let result = self.execute_command("/usr/bin/cat", &[path])?;
+        let result = self.execute_command("/bin/cat", &[path])?;
 
         if result.is_success() {
             Ok(result.stdout().to_vec())
@@ -156,7 +285,14 @@ impl System for LxcInstance {
         args: &[&str],
         input: &[u8],
     ) -> Result<crate::system::CommandResult, Self::CommandError> {
-        println!("Running command: {:?} {:?} {:?}", path, args, input);
+        // The input is not logged, because it can contain secrets
+        debug!(
+            "Running command: {:?} {:?} with {} bytes of input",
+            path,
+            args,
+            input.len()
+        );
+        let started = Instant::now();
         let child = Command::new("lxc")
             .arg("exec")
             .arg(&self.name)
@@ -168,7 +304,41 @@ impl System for LxcInstance {
             .stderr(Stdio::piped())
             .spawn()
             .unwrap();
-        Ok(handle_process_io(child, input).unwrap())
+        let result = handle_process_io(child, input).unwrap();
+        if let Some(recorder) = &self.recorder {
+            recorder.command_executed(path, args, Some(&result), started);
+        }
+
+        Ok(result)
+    }
+
+    /// Runs all commands in a single `lxc exec`.
+    fn execute_batch(
+        &self,
+        commands: &[(&str, &[&str])],
+    ) -> Result<Vec<crate::system::CommandResult>, Self::CommandError> {
+        let script = crate::batch::script(commands);
+        let result = self.execute_command_with_input("/bin/sh", &[], script.as_bytes())?;
+
+        crate::batch::parse_output(result.stdout(), commands.len())
+            .ok_or_else(|| LxcError::BatchFailed(result.stderr_as_str().to_owned()))
+    }
+
+    fn paths_exist(&self, paths: &[&std::path::Path]) -> Result<Vec<bool>, Self::Error> {
+        let args = paths
+            .iter()
+            .map(|path| ["-e", path.as_os_str().to_str().unwrap(), "]"])
+            .collect::<Vec<_>>();
+        let commands = args
+            .iter()
+            .map(|args| ("/usr/bin/[", &args[..]))
+            .collect::<Vec<_>>();
+
+        Ok(self
+            .execute_batch(&commands)?
+            .iter()
+            .map(|result| result.is_success())
+            .collect())
     }
 
     fn copy_file(
@@ -178,7 +348,35 @@ impl System for LxcInstance {
     ) -> Result<(), Self::Error> {
         let from = from.as_os_str().to_str().unwrap();
         let to = to.as_os_str().to_str().unwrap();
-        let result = self.execute_command("/usr/bin/cp", &[from, to])?;
+        let result = self.execute_command("/bin/cp", &[from, to])?;
+
+        assert!(result.is_success());
+
+        Ok(())
+    }
+
+    /// Copies the file in chunks with `dd`, so that large files can be copied without saturating the link to the container.
+    fn copy_file_resumable(
+        &mut self,
+        from: &std::path::Path,
+        to: &std::path::Path,
+        limits: &TransferLimits,
+        progress: &mut dyn FnMut(u64),
+    ) -> Result<(), Self::Error> {
+        crate::transfer::copy_in_chunks(self, from, to, limits, progress).map_err(|e| match e {
+            TransferError::Command(e) => e,
+            e => LxcError::CopyFailed(e.to_string()),
+        })
+    }
+
+    fn symlink(
+        &mut self,
+        target: &std::path::Path,
+        link: &std::path::Path,
+    ) -> Result<(), Self::Error> {
+        let target = target.as_os_str().to_str().unwrap();
+        let link = link.as_os_str().to_str().unwrap();
+        let result = self.execute_command("/bin/ln", &["-s", target, link])?;
 
         assert!(result.is_success());
 
@@ -187,7 +385,7 @@ impl System for LxcInstance {
 
     fn make_dir(&mut self, path: &std::path::Path) -> Result<(), Self::Error> {
         let path = path.as_os_str().to_str().unwrap();
-        let result = self.execute_command("/usr/bin/mkdir", &[path])?;
+        let result = self.execute_command("/bin/mkdir", &[path])?;
 
         assert!(result.is_success());
 
@@ -196,7 +394,7 @@ impl System for LxcInstance {
 
     fn remove_dir(&mut self, path: &std::path::Path) -> Result<(), Self::Error> {
         let path = path.as_os_str().to_str().unwrap();
-        let result = self.execute_command("/usr/bin/rmdir", &[path])?;
+        let result = self.execute_command("/bin/rmdir", &[path])?;
 
         assert!(result.is_success());
 
@@ -205,7 +403,7 @@ impl System for LxcInstance {
 
     fn remove_file(&mut self, path: &std::path::Path) -> Result<(), Self::Error> {
         let path = path.as_os_str().to_str().unwrap();
-        let result = self.execute_command("/usr/bin/rm", &[path])?;
+        let result = self.execute_command("/bin/rm", &[path])?;
 
         assert!(result.is_success());
 
@@ -221,7 +419,7 @@ impl System for LxcInstance {
     fn chmod(&mut self, path: &std::path::Path, mode: u32) -> Result<(), Self::Error> {
         let mode = format!("{:o}", mode);
         let path = path.as_os_str().to_str().unwrap();
-        let result = self.execute_command("/usr/bin/chmod", &[&mode, path])?;
+        let result = self.execute_command("/bin/chmod", &[&mode, path])?;
 
         assert!(result.is_success());
 
@@ -241,9 +439,25 @@ impl System for LxcInstance {
         Ok(())
     }
 
+    fn append_file_contents(
+        &self,
+        path: &std::path::Path,
+        contents: &[u8],
+    ) -> Result<(), Self::Error> {
+        let result = self.execute_command_with_input(
+            "/usr/bin/tee",
+            &["-a", path.to_str().unwrap()],
+            contents,
+        )?;
+
+        assert!(result.is_success());
+
+        Ok(())
+    }
+
     fn make_dir_all(&mut self, path: &std::path::Path) -> Result<(), Self::Error> {
         let path = path.as_os_str().to_str().unwrap();
-        let result = self.execute_command("/usr/bin/mkdir", &["-p", path])?;
+        let result = self.execute_command("/bin/mkdir", &["-p", path])?;
 
         assert!(result.is_success());
 
@@ -251,7 +465,7 @@ impl System for LxcInstance {
     }
 
     fn read_dir(&mut self, path: &std::path::Path) -> Result<Vec<String>, Self::Error> {
-        let result = self.execute_command("/usr/bin/ls", &[path.to_str().unwrap()])?;
+        let result = self.execute_command("/bin/ls", &[path.to_str().unwrap()])?;
         let output = result.stdout_as_str().trim();
         let data = if output == "" {
             Vec::new()
@@ -261,4 +475,12 @@ impl System for LxcInstance {
 
         Ok(data)
     }
+
+    fn recorder(&self) -> Option<&Recorder> {
+        self.recorder.as_ref()
+    }
+
+    fn set_recorder(&mut self, recorder: Option<Recorder>) -> Option<Recorder> {
+        std::mem::replace(&mut self.recorder, recorder)
+    }
 }
@@ -0,0 +1,257 @@
+//! Copying large files in chunks, on systems where copying is slow or shares a link with other traffic, such as remote systems.
+//!
+//! The copy is written to `<target>.side-partial`, next to a marker that identifies the version of the source file by its size and modification time.
+//! When a copy is interrupted, the next copy of the same source file resumes after the last complete chunk instead of starting from zero.
+//! The copy is moved into place once it is complete.
+use crate::system::System;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// The suffix of the file that a copy is written to until it is complete.
+pub const PARTIAL_SUFFIX: &str = ".side-partial";
+
+/// The suffix of the marker that identifies the source of a partial copy.
+const SOURCE_SUFFIX: &str = ".side-partial.source";
+
+const DEFAULT_CHUNK_SIZE: u64 = 8 << 20;
+
+/// Limits for copying files on systems that implement `System::copy_file_resumable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferLimits {
+    /// The number of bytes that are copied at a time. An interrupted copy resumes after the last complete chunk.
+    pub chunk_size: u64,
+
+    /// The maximum number of bytes per second, or `None` to copy as fast as possible.
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for TransferLimits {
+    fn default() -> Self {
+        TransferLimits {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            bandwidth: None,
+        }
+    }
+}
+
+impl TransferLimits {
+    /// How long to wait after `copied` bytes were copied in `elapsed`, to stay below the bandwidth limit.
+    pub fn delay(&self, copied: u64, elapsed: Duration) -> Duration {
+        match self.bandwidth {
+            Some(bandwidth) if bandwidth > 0 => {
+                Duration::from_secs_f64(copied as f64 / bandwidth as f64).saturating_sub(elapsed)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransferError<E: std::error::Error> {
+    #[error("{}", .0)]
+    Command(E),
+
+    #[error("{} failed: {}", .0, .1)]
+    Failed(&'static str, String),
+
+    #[error("unexpected output from stat for {}: {:?}", .0.display(), .1)]
+    InvalidStat(PathBuf, String),
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn path_str(path: &Path) -> &str {
+    path.to_str().expect("paths must be valid UTF-8")
+}
+
+fn run<S: System + ?Sized>(
+    system: &S,
+    command: &'static str,
+    args: &[&str],
+    input: Option<&[u8]>,
+) -> Result<String, TransferError<S::CommandError>> {
+    let result = match input {
+        Some(input) => system.execute_command_with_input(command, args, input),
+        None => system.execute_command(command, args),
+    }
+    .map_err(TransferError::Command)?;
+    result
+        .successful()
+        .map_err(|(_, stderr)| TransferError::Failed(command, stderr.trim().to_owned()))?;
+
+    Ok(result.stdout_as_str().to_owned())
+}
+
+/// Copies `from` to `to` on `system` in chunks, resuming a previous copy of the same file if there is one.
+/// Calls `progress` with the number of bytes of the file that have been copied so far, including the bytes of a resumed copy.
+pub fn copy_in_chunks<S: System + ?Sized>(
+    system: &S,
+    from: &Path,
+    to: &Path,
+    limits: &TransferLimits,
+    progress: &mut dyn FnMut(u64),
+) -> Result<(), TransferError<S::CommandError>> {
+    let partial = with_suffix(to, PARTIAL_SUFFIX);
+    let marker = with_suffix(to, SOURCE_SUFFIX);
+    let (from_str, partial_str, marker_str) =
+        (path_str(from), path_str(&partial), path_str(&marker));
+
+    let source = run(system, "stat", &["-c", "%s %Y", from_str], None)?;
+    let source = source.trim();
+    let size = source
+        .split_whitespace()
+        .next()
+        .and_then(|size| size.parse::<u64>().ok())
+        .ok_or_else(|| TransferError::InvalidStat(from.to_owned(), source.to_owned()))?;
+
+    let chunk_size = limits.chunk_size.max(1);
+    let previous = run(system, "cat", &[marker_str], None).ok();
+    let resumed = if previous.as_deref().map(str::trim) == Some(source) {
+        let copied = run(system, "stat", &["-c", "%s", partial_str], None)
+            .ok()
+            .and_then(|size| size.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        copied.min(size) / chunk_size
+    } else {
+        0
+    };
+
+    if resumed == 0 {
+        run(system, "rm", &["-f", partial_str], None)?;
+        run(system, "touch", &[partial_str], None)?;
+        run(
+            system,
+            "dd",
+            &[&format!("of={}", marker_str), "status=none"],
+            Some(source.as_bytes()),
+        )?;
+    } else {
+        tracing::info!(
+            "Resuming the copy of {} after {} bytes",
+            from.display(),
+            resumed * chunk_size
+        );
+    }
+
+    let bs = format!("bs={}", chunk_size);
+    let (input, output) = (format!("if={}", from_str), format!("of={}", partial_str));
+    let chunks = size.div_ceil(chunk_size);
+    let started = Instant::now();
+    for index in resumed..chunks {
+        let (skip, seek) = (format!("skip={}", index), format!("seek={}", index));
+        run(
+            system,
+            "dd",
+            &[
+                &input,
+                &output,
+                &bs,
+                &skip,
+                &seek,
+                "count=1",
+                "conv=notrunc",
+                "status=none",
+            ],
+            None,
+        )?;
+
+        let copied = ((index + 1) * chunk_size).min(size);
+        progress(copied);
+        std::thread::sleep(limits.delay(copied - resumed * chunk_size, started.elapsed()));
+    }
+
+    run(
+        system,
+        "chmod",
+        &[&format!("--reference={}", from_str), partial_str],
+        None,
+    )?;
+    run(system, "mv", &["-f", partial_str, path_str(to)], None)?;
+    run(system, "rm", &["-f", marker_str], None)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_in_chunks, with_suffix, TransferLimits, PARTIAL_SUFFIX};
+    use crate::system::LocalSystem;
+    use crate::testing::TempDir;
+    use std::time::Duration;
+
+    #[test]
+    pub fn bandwidth_delay() {
+        let limits = TransferLimits {
+            chunk_size: 1024,
+            bandwidth: Some(1000),
+        };
+
+        assert_eq!(
+            limits.delay(500, Duration::ZERO),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            limits.delay(2000, Duration::from_millis(1500)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(limits.delay(500, Duration::from_secs(1)), Duration::ZERO);
+        assert_eq!(
+            TransferLimits::default().delay(1 << 30, Duration::ZERO),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    pub fn resume_interrupted_copy() {
+        let dir = TempDir::new("transfer");
+        let contents = (0..10_000u32).map(|n| n as u8).collect::<Vec<_>>();
+        let (from, to) = (dir.join("asset"), dir.join("copy"));
+        std::fs::write(&from, &contents).unwrap();
+        let limits = TransferLimits {
+            chunk_size: 4096,
+            bandwidth: None,
+        };
+
+        let mut progress = Vec::new();
+        copy_in_chunks(&LocalSystem::new(), &from, &to, &limits, &mut |n| {
+            progress.push(n)
+        })
+        .unwrap();
+        std::fs::remove_file(&to).unwrap();
+
+        // Simulate a copy that was interrupted after the first chunk and part of the second.
+        let marker = std::fs::metadata(&from).unwrap();
+        std::fs::write(
+            with_suffix(&to, ".side-partial.source"),
+            format!(
+                "{} {}",
+                marker.len(),
+                marker
+                    .modified()
+                    .unwrap()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            ),
+        )
+        .unwrap();
+        std::fs::write(with_suffix(&to, PARTIAL_SUFFIX), &contents[..5000]).unwrap();
+
+        let mut resumed = Vec::new();
+        copy_in_chunks(&LocalSystem::new(), &from, &to, &limits, &mut |n| {
+            resumed.push(n)
+        })
+        .unwrap();
+        let copied = std::fs::read(&to).unwrap();
+        let leftovers = std::fs::read_dir(&dir).unwrap().count();
+
+        assert_eq!(progress, vec![4096, 8192, 10_000]);
+        assert_eq!(resumed, vec![8192, 10_000]);
+        assert_eq!(copied, contents);
+        assert_eq!(leftovers, 2);
+    }
+}
@@ -0,0 +1,181 @@
+//! Machine-readable results of `side verify`.
+//!
+//! With `--format json`, `side verify` prints a [`VerificationReport`] with the result of every requirement of the install:
+//! whether it verified, was invalid, or was skipped because it was not in the selected sample, and why an invalid requirement failed.
+use crate::graph::{Graph, VerificationState};
+use crate::requirements::{Requirement, VerificationFailure};
+use serde::Serialize;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerifyFormat {
+    /// Log lines for humans.
+    #[default]
+    Text,
+
+    /// A [`VerificationReport`] as JSON on stdout.
+    Json,
+}
+
+impl FromStr for VerifyFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(VerifyFormat::Text),
+            "json" => Ok(VerifyFormat::Json),
+            _ => Err(format!(
+                "invalid verification format {:?}, expected 'text' or 'json'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyStatus {
+    Ok,
+    Invalid,
+
+    /// The requirement was not verified, because it was not selected by `--sample` or `--changed-since`.
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VerifiedRequirement {
+    pub name: String,
+    pub kind: &'static str,
+    pub package: Option<String>,
+    pub status: VerifyStatus,
+
+    /// Why the requirement is invalid.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<VerificationFailure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VerificationReport {
+    pub install: u64,
+    pub ok: usize,
+    pub invalid: usize,
+    pub skipped: usize,
+    pub requirements: Vec<VerifiedRequirement>,
+}
+
+impl VerificationReport {
+    /// Collects the result of verifying the requirements of `graph` for which `selected` is true into a report.
+    /// `state` must be the result of verifying those requirements.
+    pub fn new<R: Requirement, State: Default + Copy>(
+        install: u64,
+        graph: &Graph<R, State>,
+        selected: &[bool],
+        state: &VerificationState<R>,
+    ) -> VerificationReport {
+        let (invalid, failures) = match state {
+            VerificationState::Ok => (&[][..], &[][..]),
+            VerificationState::Invalid { invalid, failures } => (&invalid[..], &failures[..]),
+        };
+
+        let requirements = graph
+            .requirements()
+            .zip(graph.packages())
+            .zip(selected)
+            .map(|((requirement, package), &selected)| {
+                let failure = invalid
+                    .iter()
+                    .position(|item| std::ptr::eq(*item, requirement))
+                    .map(|index| failures[index].clone());
+                VerifiedRequirement {
+                    name: requirement.to_string(),
+                    kind: requirement.kind(),
+                    package: package.map(str::to_owned),
+                    status: match (&failure, selected) {
+                        (Some(_), _) => VerifyStatus::Invalid,
+                        (None, true) => VerifyStatus::Ok,
+                        (None, false) => VerifyStatus::Skipped,
+                    },
+                    details: failure,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let count = |status: VerifyStatus| {
+            requirements
+                .iter()
+                .filter(|requirement| requirement.status == status)
+                .count()
+        };
+        VerificationReport {
+            install,
+            ok: count(VerifyStatus::Ok),
+            invalid: count(VerifyStatus::Invalid),
+            skipped: count(VerifyStatus::Skipped),
+            requirements,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VerificationReport, VerifyFormat, VerifyStatus};
+    use crate::builder::fs::{CreateDirectory, FileWithContents, Sha3};
+    use crate::graph::{Graph, Pending, VerificationState};
+    use crate::requirements::VerificationFailure;
+    use std::path::PathBuf;
+
+    crate::requirements!(Req = CreateDirectory, FileWithContents);
+
+    #[test]
+    pub fn parse_format() {
+        assert_eq!("json".parse(), Ok(VerifyFormat::Json));
+        assert_eq!("text".parse(), Ok(VerifyFormat::Text));
+        assert!("yaml".parse::<VerifyFormat>().is_err());
+    }
+
+    #[test]
+    pub fn report_per_requirement() {
+        let mut graph = Graph::<Req, Pending>::new();
+        graph.add(CreateDirectory::new(PathBuf::from("/srv")), &[]);
+        graph.add(
+            FileWithContents::new(
+                PathBuf::from("/srv/installed/1/config/0"),
+                PathBuf::from("/etc/side.conf"),
+                Sha3::hash(b"a"),
+            ),
+            &[],
+        );
+        graph.add(CreateDirectory::new(PathBuf::from("/var/srv")), &[]);
+        graph.assign_package(0..1, "base");
+        let requirements = graph.requirements().collect::<Vec<_>>();
+        let state = VerificationState::Invalid {
+            invalid: vec![requirements[1]],
+            failures: vec![VerificationFailure::Missing],
+        };
+        let report = VerificationReport::new(4, &graph, &[true, true, false], &state);
+
+        assert_eq!((report.ok, report.invalid, report.skipped), (1, 1, 1));
+        assert_eq!(
+            report
+                .requirements
+                .iter()
+                .map(|requirement| requirement.status)
+                .collect::<Vec<_>>(),
+            vec![
+                VerifyStatus::Ok,
+                VerifyStatus::Invalid,
+                VerifyStatus::Skipped
+            ]
+        );
+
+        let json = serde_json::to_value(&report.requirements[1]).unwrap();
+        assert_eq!(json["status"], "invalid");
+        assert_eq!(json["details"]["reason"], "missing");
+        assert_eq!(json["kind"], report.requirements[1].kind);
+        assert_eq!(report.requirements[0].package.as_deref(), Some("base"));
+        assert!(serde_json::to_value(&report.requirements[0])
+            .unwrap()
+            .get("details")
+            .is_none());
+    }
+}
@@ -0,0 +1,85 @@
+//! Detecting changes to the packages directory, for `side watch`.
+//!
+//! Changes are detected by periodically hashing all files in the packages directory through the [`System`].
+//! This works for every system, including remote ones, without requiring inotify support.
+//! Files that are excluded by default, such as editor swap files, are ignored.
+use crate::builder::fs::Sha3;
+use crate::builder::{ignore::FileFilter, scan_files};
+use crate::system::System;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The hashes of all files in a directory at a point in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    files: BTreeMap<PathBuf, Sha3>,
+}
+
+impl Snapshot {
+    pub fn take<S: System>(dir: &Path, system: &mut S) -> Result<Snapshot, S::Error> {
+        let mut files = BTreeMap::new();
+        for path in scan_files(dir, &FileFilter::default(), system)? {
+            if !system.path_is_dir(&path)? {
+                let hash = system.file_sha3(&path)?;
+                files.insert(path, hash);
+            }
+        }
+
+        Ok(Snapshot { files })
+    }
+
+    /// Returns the files that were added, removed or modified since `previous`.
+    pub fn changes<'a>(&'a self, previous: &'a Snapshot) -> Vec<&'a Path> {
+        let modified = self
+            .files
+            .iter()
+            .filter(|(path, hash)| previous.files.get(*path) != Some(hash))
+            .map(|(path, _)| path.as_path());
+        let removed = previous
+            .files
+            .keys()
+            .filter(|path| !self.files.contains_key(*path))
+            .map(PathBuf::as_path);
+
+        modified.chain(removed).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Snapshot;
+    use crate::builder::fs::Sha3;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    pub fn changes() {
+        let old = Snapshot {
+            files: [
+                (PathBuf::from("/p/a/package.toml"), Sha3::hash(b"a")),
+                (PathBuf::from("/p/a/site.conf"), Sha3::hash(b"old")),
+                (PathBuf::from("/p/b/package.toml"), Sha3::hash(b"b")),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        let new = Snapshot {
+            files: [
+                (PathBuf::from("/p/a/package.toml"), Sha3::hash(b"a")),
+                (PathBuf::from("/p/a/site.conf"), Sha3::hash(b"new")),
+                (PathBuf::from("/p/c/package.toml"), Sha3::hash(b"c")),
+            ]
+            .into_iter()
+            .collect(),
+        };
+
+        assert_eq!(
+            new.changes(&old),
+            vec![
+                Path::new("/p/a/site.conf"),
+                Path::new("/p/c/package.toml"),
+                Path::new("/p/b/package.toml"),
+            ]
+        );
+        assert!(new.changes(&new).is_empty());
+    }
+}
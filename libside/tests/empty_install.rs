@@ -1,7 +1,9 @@
 use libside::{
     builder::{fs::CreateDirectory, Builder},
+    oci::BuildTarget,
     requirements,
     testing::LxcInstance,
+    verification::VerifyFormat,
     Command, Dirs, SiDe,
 };
 
@@ -17,6 +19,7 @@ requirements!(R = CreateDirectory);
 impl Builder for EmptyBuilder {
     type PackageConfig = ();
     type Data = ();
+    type Prepared = ();
     type Requirement = R;
     type BuildError = EmptyError;
 
@@ -27,9 +30,17 @@ impl Builder for EmptyBuilder {
         Ok(())
     }
 
+    fn prepare_package(
+        &self,
+        _package: &libside::builder::Package<Self::PackageConfig>,
+    ) -> Result<Self::Prepared, Self::BuildError> {
+        Ok(())
+    }
+
     fn build_package(
         &self,
         _package: &libside::builder::Package<Self::PackageConfig>,
+        _prepared: Self::Prepared,
         _context: &mut libside::builder::Context<Self::Requirement>,
         _data: &mut Self::Data,
     ) -> Result<(), Self::BuildError> {
@@ -55,6 +66,16 @@ pub fn empty_install() {
         Command::Build {
             ignore_verification: false,
             ask_overwrite: false,
+            overwrite: None,
+            review: false,
+            target: BuildTarget::Live,
+            rootfs: None,
+            timeout: None,
+            node_timeout: None,
+            create_first: false,
+            isolate_failures: false,
+            bandwidth_limit: None,
+            chunk_size: 8192,
         },
         &dirs,
         &mut system,
@@ -66,6 +87,15 @@ pub fn empty_install() {
             target: 0,
             ignore_verification: false,
             ask_overwrite: false,
+            overwrite: None,
+            review: false,
+            allow_reboot: false,
+            timeout: None,
+            node_timeout: None,
+            create_first: false,
+            isolate_failures: false,
+            allow_downgrade: true,
+            until: None,
         },
         &dirs,
         &mut system,
@@ -77,6 +107,15 @@ pub fn empty_install() {
             target: 1,
             ignore_verification: false,
             ask_overwrite: false,
+            overwrite: None,
+            review: false,
+            allow_reboot: false,
+            timeout: None,
+            node_timeout: None,
+            create_first: false,
+            isolate_failures: false,
+            allow_downgrade: false,
+            until: None,
         },
         &dirs,
         &mut system,
@@ -84,7 +123,14 @@ pub fn empty_install() {
     )
     .unwrap();
     SiDe::run_command(
-        Command::Verify { fix: false },
+        Command::Verify {
+            fix: false,
+            review: false,
+            sample: None,
+            seed: None,
+            changed_since: None,
+            format: VerifyFormat::Text,
+        },
         &dirs,
         &mut system,
         EmptyBuilder,
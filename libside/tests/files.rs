@@ -5,10 +5,12 @@ use libside::{
         fs::{ConfigFileData, CreateDirectory, FileWithContents},
         Builder,
     },
+    oci::BuildTarget,
     requirements,
     system::System,
     testing::LxcInstance,
-    Command, Dirs, SiDe,
+    verification::VerifyFormat,
+    Command, Dirs, RunError, SiDe,
 };
 
 #[derive(Copy, Clone, Debug, thiserror::Error)]
@@ -23,6 +25,7 @@ requirements!(R = CreateDirectory, FileWithContents);
 impl Builder for EmptyBuilder {
     type PackageConfig = ();
     type Data = ();
+    type Prepared = ();
     type Requirement = R;
     type BuildError = EmptyError;
 
@@ -45,9 +48,17 @@ impl Builder for EmptyBuilder {
         Ok(())
     }
 
+    fn prepare_package(
+        &self,
+        _package: &libside::builder::Package<Self::PackageConfig>,
+    ) -> Result<Self::Prepared, Self::BuildError> {
+        Ok(())
+    }
+
     fn build_package(
         &self,
         _package: &libside::builder::Package<Self::PackageConfig>,
+        _prepared: Self::Prepared,
         _context: &mut libside::builder::Context<Self::Requirement>,
         _data: &mut Self::Data,
     ) -> Result<(), Self::BuildError> {
@@ -73,6 +84,16 @@ pub fn single_file_install() {
         Command::Build {
             ignore_verification: false,
             ask_overwrite: false,
+            overwrite: None,
+            review: false,
+            target: BuildTarget::Live,
+            rootfs: None,
+            timeout: None,
+            node_timeout: None,
+            create_first: false,
+            isolate_failures: false,
+            bandwidth_limit: None,
+            chunk_size: 8192,
         },
         &dirs,
         &mut system,
@@ -89,11 +110,41 @@ pub fn single_file_install() {
         String::from("Hello, world!").into_bytes()
     );
 
+    let refused = SiDe::run_command(
+        Command::Apply {
+            target: 0,
+            ignore_verification: false,
+            ask_overwrite: false,
+            overwrite: None,
+            review: false,
+            allow_reboot: false,
+            timeout: None,
+            node_timeout: None,
+            create_first: false,
+            isolate_failures: false,
+            allow_downgrade: false,
+            until: None,
+        },
+        &dirs,
+        &mut system,
+        EmptyBuilder,
+    );
+    assert!(matches!(refused, Err(RunError::DowngradeNotAllowed(1, 0))));
+
     SiDe::run_command(
         Command::Apply {
             target: 0,
             ignore_verification: false,
             ask_overwrite: false,
+            overwrite: None,
+            review: false,
+            allow_reboot: false,
+            timeout: None,
+            node_timeout: None,
+            create_first: false,
+            isolate_failures: false,
+            allow_downgrade: true,
+            until: None,
         },
         &dirs,
         &mut system,
@@ -112,6 +163,15 @@ pub fn single_file_install() {
             target: 1,
             ignore_verification: false,
             ask_overwrite: false,
+            overwrite: None,
+            review: false,
+            allow_reboot: false,
+            timeout: None,
+            node_timeout: None,
+            create_first: false,
+            isolate_failures: false,
+            allow_downgrade: false,
+            until: None,
         },
         &dirs,
         &mut system,
@@ -134,8 +194,31 @@ pub fn single_file_install() {
         ))
         .unwrap();
 
+    // Nothing changed since install 1, so the removed file is not verified
+    SiDe::run_command(
+        Command::Verify {
+            fix: false,
+            review: false,
+            sample: None,
+            seed: None,
+            changed_since: Some(1),
+            format: VerifyFormat::Text,
+        },
+        &dirs,
+        &mut system,
+        EmptyBuilder,
+    )
+    .unwrap();
+
     let result = SiDe::run_command(
-        Command::Verify { fix: false },
+        Command::Verify {
+            fix: false,
+            review: false,
+            sample: None,
+            seed: None,
+            changed_since: None,
+            format: VerifyFormat::Text,
+        },
         &dirs,
         &mut system,
         EmptyBuilder,
@@ -148,7 +231,14 @@ pub fn single_file_install() {
         .unwrap());
 
     SiDe::run_command(
-        Command::Verify { fix: true },
+        Command::Verify {
+            fix: true,
+            review: false,
+            sample: None,
+            seed: None,
+            changed_since: None,
+            format: VerifyFormat::Text,
+        },
         &dirs,
         &mut system,
         EmptyBuilder,